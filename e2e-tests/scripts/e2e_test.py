@@ -20,6 +20,7 @@ import shutil
 import subprocess
 import tempfile
 import logging
+from concurrent.futures import ThreadPoolExecutor
 from pathlib import Path
 from typing import Dict, List, Optional, Tuple
 from dataclasses import dataclass
@@ -523,6 +524,54 @@ fn process_data(input: &str) -> String {
                 str(e)
             )
     
+    def test_parallel_metadata_benchmark(self) -> TestResult:
+        """Compare stat storms run one at a time and from many threads"""
+        start_time = time.time()
+        test_dir = self.mount_point / "parallel_metadata_test"
+        
+        try:
+            test_dir.mkdir(exist_ok=True)
+            # Separate files for each run, so neither is answered from
+            # attributes the other cached
+            files = [test_dir / f"stat_{i}.txt" for i in range(400)]
+            for test_file in files:
+                test_file.write_text("x")
+            serial_files, parallel_files = files[:200], files[200:]
+            
+            serial_start = time.time()
+            for test_file in serial_files:
+                os.stat(test_file)
+            serial_time = time.time() - serial_start
+            
+            parallel_start = time.time()
+            with ThreadPoolExecutor(max_workers=16) as executor:
+                list(executor.map(os.stat, parallel_files))
+            parallel_time = time.time() - parallel_start
+            
+            shutil.rmtree(test_dir)
+            
+            return TestResult(
+                "parallel_metadata_benchmark",
+                True,
+                time.time() - start_time,
+                details={
+                    "files_per_run": len(serial_files),
+                    "serial_stat_time_ms": serial_time * 1000,
+                    "parallel_stat_time_ms": parallel_time * 1000,
+                    "speedup": serial_time / parallel_time if parallel_time > 0 else None
+                }
+            )
+            
+        except Exception as e:
+            if test_dir.exists():
+                shutil.rmtree(test_dir, ignore_errors=True)
+            return TestResult(
+                "parallel_metadata_benchmark",
+                False,
+                time.time() - start_time,
+                str(e)
+            )
+    
     def run_all_tests(self) -> Dict:
         """Run all end-to-end tests"""
         self.logger.info("Starting RemoteFS End-to-End Tests")
@@ -543,6 +592,7 @@ fn process_data(input: &str) -> String {
             self.test_code_modifications,
             self.test_file_search_operations,
             self.test_performance_benchmark,
+            self.test_parallel_metadata_benchmark,
         ]
         
         # Run each test
@@ -0,0 +1,63 @@
+//! Mount an agent's files with the system NFS client
+//!
+//! Serves the loopback agent's directory over NFS on 127.0.0.1 and prints
+//! the command that mounts it, then runs until Ctrl-C. Mounting needs root
+//! and an NFS client (`nfs-common` on Debian and Ubuntu; macOS has one). Run
+//! with:
+//!
+//! ```sh
+//! cargo run --example nfs_mount
+//! ```
+
+use remotefs_examples::{free_port, Loopback, Result};
+use remotefs_nfs::{mount, ExportConfig, NfsConfig, RemoteNfsServer};
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Quiet unless RUST_LOG asks for logs
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")))
+        .init();
+
+    println!("🗂️  RemoteFS NFS Mount Example");
+    println!("=============================");
+
+    let loopback = Loopback::start().await?;
+    std::fs::write(loopback.root().join("hello.txt"), "Hello over NFS!\n")?;
+
+    let mut config = NfsConfig {
+        agents: vec![loopback.relay_url().to_string()],
+        port: free_port()?,
+        exports: vec![ExportConfig {
+            name: "remotefs".to_string(),
+            agent: None,
+            remote_path: loopback.remote_path(""),
+            port: None,
+            bind_address: None,
+            selinux_context: None,
+            agent_exports: false,
+            umask: None,
+            symlinks: None,
+        }],
+        ..Default::default()
+    };
+    // The example has nothing to manage or recover
+    config.control.enabled = false;
+    config.recovery.enabled = false;
+
+    let mut server = RemoteNfsServer::new(config);
+    server.initialize(loopback.client().await?).await?;
+
+    // Clients of the loopback relay can only read, so mount read-only
+    for export in server.exports() {
+        println!("\n📁 Serving {} at {}", loopback.root().display(), mount::mount_source(export));
+        println!("   Mount it with:");
+        println!("   sudo mkdir -p /mnt/remotefs");
+        println!("   sudo mount -t nfs -o ro,{} {} /mnt/remotefs", mount::mount_options(export), mount::mount_source(export));
+    }
+    println!("\n⏹️  Press Ctrl-C to stop; unmount first with `sudo umount /mnt/remotefs`");
+
+    server.start().await?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Quickstart: embed an agent and read its files through a relay
+//!
+//! Starts a relay and an agent exporting a temporary directory in this
+//! process, then connects a client the same way it would connect to a relay
+//! on another host. Run with:
+//!
+//! ```sh
+//! cargo run --example quickstart
+//! ```
+
+use remotefs_examples::{Loopback, Result};
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Quiet unless RUST_LOG asks for logs
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")))
+        .init();
+
+    println!("🚀 RemoteFS Quickstart");
+    println!("======================");
+
+    // A relay and an agent, both on 127.0.0.1
+    println!("\n🔧 Starting a relay and an agent...");
+    let loopback = Loopback::start().await?;
+    println!("✅ Relay listening at {}", loopback.relay_url());
+    println!("✅ Agent {} exporting {}", loopback.agent_id(), loopback.root().display());
+
+    // Files the agent serves; clients of the loopback relay can only read them
+    std::fs::write(loopback.root().join("hello.txt"), "Hello from RemoteFS!\n")?;
+    std::fs::create_dir(loopback.root().join("notes"))?;
+    std::fs::write(loopback.root().join("notes").join("todo.md"), "- try the sync example\n")?;
+
+    println!("\n🔌 Connecting a client...");
+    let client = loopback.client().await?;
+    println!("✅ Connected");
+
+    println!("\n📁 Listing {}:", loopback.root().display());
+    for entry in client.list_directory(loopback.remote_path("")).await? {
+        let kind = if entry.metadata.is_dir { "dir " } else { "file" };
+        println!("   {} {:>6} bytes  {}", kind, entry.metadata.size, entry.name);
+    }
+
+    println!("\n📖 Reading hello.txt:");
+    let content = client.read_file(loopback.remote_path("hello.txt")).await?;
+    print!("   {}", String::from_utf8_lossy(&content));
+
+    let metadata = client.get_metadata(loopback.remote_path("notes/todo.md")).await?;
+    println!("\n📊 notes/todo.md: {} bytes, mode {:o}", metadata.size, metadata.permissions & 0o7777);
+
+    let stats = client.get_stats().await;
+    println!("\n📈 {} operations, {} bytes read", stats.operations_total, stats.bytes_read);
+
+    client.shutdown().await?;
+    println!("\n✅ Done");
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! In-process RemoteFS deployment for the examples
+//!
+//! `Loopback::start` runs a relay and an agent inside the calling process,
+//! both on 127.0.0.1, with the agent exporting a fresh temporary directory.
+//! The examples then connect clients to it exactly as they would connect to
+//! a relay elsewhere, so `cargo run --example quickstart` needs nothing
+//! running beforehand.
+//!
+//! Clients reach the agent through the relay's public export of that
+//! directory, the way anonymous clients do, so they can read, list and stat
+//! it but not change it. The examples change it through `Loopback::root`.
+
+use remotefs_agent::AgentServer;
+use remotefs_client::{AgentConfig, Client, ClientConfig};
+use remotefs_common::config::{AgentConfig as AgentServerConfig, PublicExport, RelayConfig};
+use remotefs_common::config_utils::{create_default_agent_config, create_default_relay_config};
+use remotefs_relay::{AuthManager, RelayServer};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::task::JoinHandle;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// How long `start` waits for the relay and agent to come up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A relay and an agent running in this process
+pub struct Loopback {
+    agent_id: String,
+    relay_url: String,
+    root: PathBuf,
+    // Remove the exported directory and the relay's storage on drop
+    _root_dir: TempDir,
+    _state_dir: TempDir,
+    relay: JoinHandle<()>,
+    agent: JoinHandle<()>,
+}
+
+impl Loopback {
+    /// Start a relay and an agent exporting a new temporary directory, and
+    /// wait until clients can reach the agent
+    pub async fn start() -> Result<Self> {
+        let root_dir = tempfile::tempdir()?;
+        let state_dir = tempfile::tempdir()?;
+        // Agents compare resolved paths, and the temporary directory may be behind a symlink
+        let root = root_dir.path().canonicalize()?;
+        let port = free_port()?;
+        let agent_id = "agent-loopback".to_string();
+        let relay_url = format!("ws://127.0.0.1:{}/ws", port);
+
+        let relay_config = relay_config(port, &agent_id, &root, state_dir.path());
+        let auth_manager = Arc::new(AuthManager::new(&relay_config));
+        let relay_server = RelayServer::new(relay_config, auth_manager)?;
+        let relay = tokio::spawn(async move {
+            if let Err(e) = relay_server.run().await {
+                tracing::error!("Loopback relay stopped: {}", e);
+            }
+        });
+        wait_for_port(port).await?;
+
+        let agent_server = AgentServer::new(agent_config(&agent_id, &relay_url, &root))?;
+        let agent = tokio::spawn(async move {
+            if let Err(e) = agent_server.run().await {
+                tracing::error!("Loopback agent stopped: {}", e);
+            }
+        });
+
+        let loopback = Self { agent_id, relay_url, root, _root_dir: root_dir, _state_dir: state_dir, relay, agent };
+        loopback.wait_for_agent().await?;
+        Ok(loopback)
+    }
+
+    /// Directory the agent exports; write to it directly to give clients
+    /// something to read
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path clients use for `relative` inside the exported directory
+    pub fn remote_path(&self, relative: &str) -> String {
+        self.root.join(relative).to_string_lossy().to_string()
+    }
+
+    /// ID the agent registered with the relay
+    pub fn agent_id(&self) -> &str {
+        &self.agent_id
+    }
+
+    /// WebSocket URL of the relay
+    pub fn relay_url(&self) -> &str {
+        &self.relay_url
+    }
+
+    /// Configuration for a client of the relay
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            agents: vec![AgentConfig {
+                id: "loopback-relay".to_string(),
+                url: self.relay_url.clone(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// A client connected to the relay
+    pub async fn client(&self) -> Result<Client> {
+        let client = Client::new(self.client_config())?;
+        client.initialize().await?;
+        Ok(client)
+    }
+
+    /// Wait until a client request reaches the agent, which registers with
+    /// the relay in the background
+    async fn wait_for_agent(&self) -> Result<()> {
+        let client = self.client().await?;
+        let root = self.remote_path("");
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            match client.get_metadata(&root).await {
+                Ok(_) => break,
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(format!("Agent did not come up: {}", e).into());
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        client.shutdown().await?;
+        Ok(())
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.agent.abort();
+        self.relay.abort();
+    }
+}
+
+fn relay_config(port: u16, agent_id: &str, root: &Path, state: &Path) -> RelayConfig {
+    let mut config = create_default_relay_config();
+    config.bind_address = "127.0.0.1".to_string();
+    config.port = port;
+    config.security.enable_tls = false;
+    config.security.enable_auth = false;
+    config.storage.temp_dir = state.join("temp");
+    config.public_exports = vec![PublicExport {
+        agent_id: agent_id.to_string(),
+        path: root.to_string_lossy().to_string(),
+        requests_per_minute: 60_000,
+        burst: 1000,
+    }];
+    config
+}
+
+fn agent_config(agent_id: &str, relay_url: &str, root: &Path) -> AgentServerConfig {
+    let mut config = create_default_agent_config();
+    config.agent_id = agent_id.to_string();
+    config.relay_url = relay_url.to_string();
+    config.access.allowed_paths = vec![root.to_string_lossy().to_string()];
+    config.access.denied_paths = vec![];
+    config.security.enable_tls = false;
+    config.security.enable_auth = false;
+    config
+}
+
+/// A port on 127.0.0.1 nothing listens on right now
+pub fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+async fn wait_for_port(port: u16) -> Result<()> {
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while TcpStream::connect_timeout(&address, Duration::from_millis(100)).is_err() {
+        if Instant::now() >= deadline {
+            return Err(format!("Relay did not start listening on {}", address).into());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
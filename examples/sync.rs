@@ -0,0 +1,105 @@
+//! Minimal sync tool: mirror a remote directory into a local one
+//!
+//! Walks the remote tree with `walk_directory` and copies every file whose
+//! size or modification time differs from the local copy, so a second run
+//! only transfers what changed in between. Files deleted remotely are left
+//! in place. Run with:
+//!
+//! ```sh
+//! cargo run --example sync [destination]
+//! ```
+
+use remotefs_client::Client;
+use remotefs_examples::{Loopback, Result};
+use std::path::Path;
+use std::time::SystemTime;
+use tracing_subscriber::EnvFilter;
+
+/// Files copied and skipped by one sync
+#[derive(Debug, Default)]
+struct SyncReport {
+    copied: usize,
+    unchanged: usize,
+    bytes: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Quiet unless RUST_LOG asks for logs
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")))
+        .init();
+
+    println!("🔄 RemoteFS Sync Example");
+    println!("========================");
+
+    let loopback = Loopback::start().await?;
+    let root = loopback.root();
+    std::fs::create_dir_all(root.join("photos/2024"))?;
+    std::fs::write(root.join("README.md"), "Synced with RemoteFS\n")?;
+    std::fs::write(root.join("photos/2024/beach.jpg"), vec![0xAB; 64 * 1024])?;
+    std::fs::write(root.join("photos/2024/hike.jpg"), vec![0xCD; 32 * 1024])?;
+
+    let destination = match std::env::args().nth(1) {
+        Some(destination) => destination.into(),
+        None => std::env::temp_dir().join("remotefs-sync-example"),
+    };
+    std::fs::create_dir_all(&destination)?;
+
+    let client = loopback.client().await?;
+    let source = loopback.remote_path("");
+    println!("\n📥 {} -> {}", source, destination.display());
+
+    let report = sync(&client, &source, &destination).await?;
+    println!("✅ First sync: {} copied, {} unchanged, {} bytes", report.copied, report.unchanged, report.bytes);
+
+    // Change one file on the agent's side; only it is copied again
+    std::fs::write(root.join("README.md"), "Synced with RemoteFS, twice\n")?;
+    let report = sync(&client, &source, &destination).await?;
+    println!("✅ Second sync: {} copied, {} unchanged, {} bytes", report.copied, report.unchanged, report.bytes);
+
+    client.shutdown().await?;
+    Ok(())
+}
+
+/// Copy the files under `source` on the agent into `destination`
+async fn sync(client: &Client, source: &str, destination: &Path) -> Result<SyncReport> {
+    let mut report = SyncReport::default();
+    let source = source.trim_end_matches('/');
+
+    // One request for the whole tree, its entries named relative to `source`
+    let mut pages = client.walk_directory(source, None, false).await?;
+    while let Some(page) = pages.next_page().await {
+        for entry in page? {
+            let remote_path = format!("{}/{}", source, entry.name);
+            let local_path = destination.join(&entry.name);
+
+            // Parents are walked before their entries
+            if entry.metadata.is_dir {
+                std::fs::create_dir_all(&local_path)?;
+                continue;
+            }
+            if !entry.metadata.is_file {
+                continue;
+            }
+
+            let modified = SystemTime::from(entry.metadata.modified);
+            let unchanged = std::fs::metadata(&local_path)
+                .is_ok_and(|local| local.len() == entry.metadata.size && local.modified().ok() == Some(modified));
+            if unchanged {
+                report.unchanged += 1;
+                continue;
+            }
+
+            let content = client.read_file(&remote_path).await?;
+            std::fs::write(&local_path, &content)?;
+            // Record the remote time so the next sync sees the copy as current
+            std::fs::File::options().write(true).open(&local_path)?.set_modified(modified)?;
+            println!("   copied {}", remote_path);
+            report.copied += 1;
+            report.bytes += content.len() as u64;
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,76 @@
+//! The loopback deployment the examples run against
+
+use remotefs_examples::Loopback;
+
+#[tokio::test]
+async fn test_client_reads_through_loopback() {
+    let loopback = Loopback::start().await.unwrap();
+    std::fs::write(loopback.root().join("hello.txt"), b"hello").unwrap();
+    std::fs::create_dir(loopback.root().join("docs")).unwrap();
+    std::fs::write(loopback.root().join("docs/readme.md"), b"docs").unwrap();
+
+    let client = loopback.client().await.unwrap();
+    let content = client.read_file(loopback.remote_path("hello.txt")).await.unwrap();
+    assert_eq!(&content[..], b"hello");
+
+    let mut names: Vec<_> = client.list_directory(loopback.remote_path("")).await.unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["docs", "hello.txt"]);
+
+    let mut walked = Vec::new();
+    let mut pages = client.walk_directory(loopback.remote_path(""), None, false).await.unwrap();
+    while let Some(page) = pages.next_page().await {
+        walked.extend(page.unwrap().into_iter().map(|entry| entry.name));
+    }
+    walked.sort();
+    assert_eq!(walked, ["docs", "docs/readme.md", "hello.txt"]);
+
+    // Clients of the loopback relay are anonymous, so they can only read
+    assert!(client.write_file(loopback.remote_path("new.txt"), b"no".to_vec().into()).await.is_err());
+    assert!(!loopback.root().join("new.txt").exists());
+
+    client.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_listing_resumes_after_name() {
+    let loopback = Loopback::start().await.unwrap();
+    for name in ["a.txt", "c.txt", "e.txt"] {
+        std::fs::write(loopback.root().join(name), b"x").unwrap();
+    }
+
+    let client = loopback.client().await.unwrap();
+    let list_after = |after: Option<&'static str>| {
+        let client = &client;
+        let path = loopback.remote_path("");
+        async move {
+            let mut names = Vec::new();
+            let mut pages = client.list_directory_pages_after(path, 2, after).await.unwrap();
+            while let Some(page) = pages.next_page().await {
+                names.extend(page.unwrap().into_iter().map(|entry| entry.name));
+            }
+            names
+        }
+    };
+    assert_eq!(list_after(None).await, ["a.txt", "c.txt", "e.txt"]);
+
+    // Changes behind the cursor are not seen, those ahead of it are
+    std::fs::write(loopback.root().join("b.txt"), b"x").unwrap();
+    std::fs::write(loopback.root().join("d.txt"), b"x").unwrap();
+    std::fs::remove_file(loopback.root().join("c.txt")).unwrap();
+    assert_eq!(list_after(Some("c.txt")).await, ["d.txt", "e.txt"]);
+
+    // A part at a time, each naming where the next starts
+    let (names, cursor) = client.list_directory_from(loopback.remote_path(""), None, 3).await.unwrap();
+    let names: Vec<_> = names.into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, ["a.txt", "b.txt", "d.txt"]);
+    assert_eq!(cursor.as_deref(), Some("d.txt"));
+    let (names, cursor) = client.list_directory_from(loopback.remote_path(""), cursor.as_deref(), 3).await.unwrap();
+    assert_eq!(names.len(), 1);
+    assert_eq!(cursor, None);
+
+    client.shutdown().await.unwrap();
+}
@@ -0,0 +1,22 @@
+#!/bin/sh
+# Write the Homebrew formula for a release to stdout
+#
+# Usage: generate-formula.sh <version> <source tarball URL>
+#
+# The tarball is downloaded to compute its checksum, so run this after the
+# release is published.
+set -e
+
+if [ $# -ne 2 ]; then
+    echo "Usage: $0 <version> <source tarball URL>" >&2
+    exit 2
+fi
+version=$1
+url=$2
+
+sha256=$(curl -fsSL "$url" | shasum -a 256 | cut -d' ' -f1)
+
+sed -e "s|@VERSION@|$version|" \
+    -e "s|@URL@|$url|" \
+    -e "s|@SHA256@|$sha256|" \
+    "$(dirname "$0")/remotefs.rb.in"
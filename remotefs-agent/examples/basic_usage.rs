@@ -72,7 +72,7 @@ async fn main() -> Result<()> {
     println!("   In production, the server would run indefinitely until stopped");
     
     // Create a timeout for the example
-    let server_task = tokio::spawn(async move {
+    let _server_task = tokio::spawn(async move {
         if let Err(e) = server.run().await {
             eprintln!("❌ Agent server error: {}", e);
         }
@@ -91,6 +91,7 @@ async fn main() -> Result<()> {
 }
 
 /// Helper function to demonstrate configuration customization
+#[allow(dead_code)]
 fn customize_config_for_development(mut config: AgentConfig) -> AgentConfig {
     // Development-friendly settings
     config.security.enable_tls = false;
@@ -108,6 +109,7 @@ fn customize_config_for_development(mut config: AgentConfig) -> AgentConfig {
 }
 
 /// Helper function to demonstrate configuration for production
+#[allow(dead_code)]
 fn customize_config_for_production(mut config: AgentConfig) -> AgentConfig {
     // Production security settings
     config.security.enable_tls = true;
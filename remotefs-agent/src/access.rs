@@ -1,56 +1,249 @@
 use remotefs_common::{
-    config::AccessConfig,
+    config::{AccessConfig, AccessRule, AccessVerb, RuleEffect, UnmatchedUserPolicy, UserAccessRule},
     error::{RemoteFsError, Result},
+    protocol::{CallerIdentity, Message, TransactionOp},
 };
-use crate::server::AccessControlStatistics;
+use crate::{mirror::MirrorState, server::AccessControlStatistics};
+use regex::Regex;
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, PoisonError},
 };
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// Access control manager that enforces security policies
 #[derive(Clone)]
 pub struct AccessControl {
-    config: AccessConfig,
     stats: Arc<RwLock<AccessControlStatistics>>,
+    /// Rules in effect; replaced when the configuration is reloaded and
+    /// re-resolved on SIGHUP, for every copy of this access control at once
+    policy: Arc<std::sync::RwLock<Policy>>,
+    /// Local user of a shared mount the checks are made for, if forwarded
+    caller: Option<CallerIdentity>,
+    /// Client the relay said the request is from
+    client: Option<String>,
+    /// Set on mirror agents, which refuse writes unless promoted
+    mirror: Option<Arc<MirrorState>>,
+    /// Trash directory at the top of each allowed path, which clients reach
+    /// only through deletes, restores and purges
+    trash: Option<PathBuf>,
+}
+
+/// An access configuration prepared for checking requests
+///
+/// Configured path lists are resolved to the directories they name: a
+/// configured path that is itself a symlink, such as `/tmp` on macOS, is
+/// matched by its target, since request paths are resolved the same way.
+struct Policy {
+    config: AccessConfig,
+    allowed_extensions: HashSet<String>,
+    denied_extensions: HashSet<String>,
     allowed_paths: HashSet<PathBuf>,
     read_only_paths: HashSet<PathBuf>,
     denied_paths: HashSet<PathBuf>,
-    allowed_extensions: HashSet<String>,
-    denied_extensions: HashSet<String>,
+    user_rules: Vec<UserRule>,
+    rules: Vec<PatternRule>,
+    /// Allowed and read-only paths as configured and as resolved; symlinks
+    /// up to and including them are trusted even without `follow_symlinks`
+    trusted_roots: Vec<PathBuf>,
+    /// Allowed and read-only paths as configured, with what they resolve to
+    roots: Vec<(PathBuf, PathBuf)>,
 }
 
-impl AccessControl {
-    /// Create a new access control manager
-    pub fn new(config: &AccessConfig) -> Self {
-        let allowed_paths: HashSet<PathBuf> = config.allowed_paths
-            .iter()
-            .map(|p| normalize_path(p))
-            .collect();
-            
-        let read_only_paths: HashSet<PathBuf> = config.read_only_paths
-            .iter()
-            .map(|p| normalize_path(p))
-            .collect();
-            
-        let denied_paths: HashSet<PathBuf> = config.denied_paths
-            .iter()
-            .map(|p| normalize_path(p))
-            .collect();
-            
-        let allowed_extensions: HashSet<String> = config.allowed_extensions
-            .iter()
-            .map(|ext| ext.to_lowercase())
+impl Policy {
+    fn resolve(config: &AccessConfig) -> Self {
+        let lowercase = |extensions: &[String]| extensions.iter().map(|ext| ext.to_lowercase()).collect();
+
+        let resolve = |kind: &str, paths: &[String]| -> HashSet<PathBuf> {
+            paths.iter().map(|path| resolve_root(kind, path)).collect()
+        };
+        
+        let allowed_paths = resolve("Allowed", &config.allowed_paths);
+        let read_only_paths = resolve("Read-only", &config.read_only_paths);
+        let denied_paths = resolve("Denied", &config.denied_paths);
+        
+        let roots: Vec<_> = config.allowed_paths.iter().chain(&config.read_only_paths)
+            .map(|path| (clean_path(Path::new(path)), normalize_path(path)))
             .collect();
-            
-        let denied_extensions: HashSet<String> = config.denied_extensions
-            .iter()
-            .map(|ext| ext.to_lowercase())
+        let trusted_roots = roots.iter()
+            .flat_map(|(configured, resolved)| [configured.clone(), resolved.clone()])
             .collect();
         
+        Self {
+            config: config.clone(),
+            allowed_extensions: lowercase(&config.allowed_extensions),
+            denied_extensions: lowercase(&config.denied_extensions),
+            allowed_paths,
+            read_only_paths,
+            denied_paths,
+            user_rules: config.user_rules.iter().map(UserRule::new).collect(),
+            rules: config.rules.iter()
+                .filter_map(|rule| PatternRule::new(rule)
+                    .inspect_err(|e| warn!("Ignoring access rule: {}", e))
+                    .ok())
+                .collect(),
+            trusted_roots,
+            roots,
+        }
+    }
+    
+    /// Whether symlinks lead `path`, which resolves to `resolved`, out of
+    /// every allowed or read-only path holding it
+    fn escapes(&self, path: &Path, resolved: &Path) -> bool {
+        let mut holding = self.roots.iter()
+            .filter(|(configured, root)| path.starts_with(configured) || path.starts_with(root))
+            .peekable();
+        holding.peek().is_some() && !holding.any(|(_, root)| resolved.starts_with(root))
+    }
+    
+    /// Whether `resolved` is the trash directory `trash` of a root, or in it
+    fn in_trash(&self, trash: &Path, resolved: &Path) -> bool {
+        self.roots.iter().any(|(_, root)| resolved.starts_with(root.join(trash)))
+    }
+    
+    /// Number of leading components of `path` covered by a trusted root
+    fn trusted_prefix(&self, path: &Path) -> usize {
+        self.trusted_roots.iter()
+            .filter(|root| path.starts_with(root))
+            .map(|root| root.components().count())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// `UserAccessRule` with normalized paths
+struct UserRule {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    allowed_paths: HashSet<PathBuf>,
+    read_only_paths: HashSet<PathBuf>,
+    denied_paths: HashSet<PathBuf>,
+    read_only: bool,
+}
+
+impl UserRule {
+    fn new(rule: &UserAccessRule) -> Self {
+        let normalize = |paths: &[String]| paths.iter().map(|p| normalize_path(p)).collect();
+        
+        Self {
+            uids: rule.uids.clone(),
+            gids: rule.gids.clone(),
+            allowed_paths: normalize(&rule.allowed_paths),
+            read_only_paths: normalize(&rule.read_only_paths),
+            denied_paths: normalize(&rule.denied_paths),
+            read_only: rule.read_only,
+        }
+    }
+    
+    fn matches(&self, caller: &CallerIdentity) -> bool {
+        self.uids.contains(&caller.uid) || self.gids.iter().any(|gid| caller.in_group(*gid))
+    }
+}
+
+/// `AccessRule` with its patterns compiled
+struct PatternRule {
+    effect: RuleEffect,
+    clients: Vec<Regex>,
+    paths: Vec<Regex>,
+    verbs: Vec<AccessVerb>,
+}
+
+impl PatternRule {
+    fn new(rule: &AccessRule) -> Result<Self> {
+        let compile = |patterns: &[String]| patterns.iter().map(|p| compile_pattern(p)).collect::<Result<Vec<_>>>();
+        
+        Ok(Self {
+            effect: rule.effect,
+            clients: compile(&rule.clients)?,
+            paths: compile(&rule.paths)?,
+            verbs: rule.verbs.clone(),
+        })
+    }
+    
+    /// Whether the rule covers `verb` on `path`, or on a directory above it,
+    /// for `client`; rules naming clients never match requests from none
+    fn matches(&self, client: Option<&str>, path: &Path, verb: AccessVerb) -> bool {
+        let client_matches = self.clients.is_empty()
+            || client.is_some_and(|client| self.clients.iter().any(|pattern| pattern.is_match(client)));
+        let verb_matches = self.verbs.is_empty() || self.verbs.contains(&verb);
+        
+        client_matches && verb_matches && path.ancestors()
+            .filter_map(|path| path.to_str())
+            .any(|path| self.paths.iter().any(|pattern| pattern.is_match(path)))
+    }
+}
+
+/// Check that every pattern of the configured access rules compiles
+pub fn validate_rules(config: &AccessConfig) -> Result<()> {
+    config.rules.iter().try_for_each(|rule| PatternRule::new(rule).map(|_| ()))
+}
+
+/// Compile a glob, or a regular expression written `regex:<pattern>`, to
+/// match whole strings
+///
+/// In globs `*` and `?` match within one path component, `**` matches
+/// across components, and `[...]` a character class, negated with `!`.
+fn compile_pattern(pattern: &str) -> Result<Regex> {
+    let source = match pattern.strip_prefix("regex:") {
+        Some(regex) => format!("^(?:{})$", regex),
+        None => glob_to_regex(pattern),
+    };
+    
+    Regex::new(&source).map_err(|e| RemoteFsError::Configuration(format!(
+        "Invalid access rule pattern '{}': {}",
+        pattern,
+        e
+    )))
+}
+
+/// Regular expression matching the same whole strings as `glob`
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        regex.push('\\');
+                    }
+                    regex.push(c);
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    
+    regex.push('$');
+    regex
+}
+
+impl AccessControl {
+    /// Create a new access control manager
+    pub fn new(config: &AccessConfig) -> Self {
         let stats = Arc::new(RwLock::new(AccessControlStatistics {
             allowed_requests: 0,
             denied_requests: 0,
@@ -59,52 +252,159 @@ impl AccessControl {
         }));
         
         Self {
-            config: config.clone(),
             stats,
-            allowed_paths,
-            read_only_paths,
-            denied_paths,
-            allowed_extensions,
-            denied_extensions,
+            policy: Arc::new(std::sync::RwLock::new(Policy::resolve(config))),
+            caller: None,
+            client: None,
+            mirror: None,
+            trash: None,
+        }
+    }
+    
+    /// Resolve the configured paths again, for paths whose symlinks changed
+    /// or that did not exist when the agent started
+    pub fn resolve_paths(&self) {
+        let config = self.config();
+        self.reload(&config);
+    }
+    
+    /// Apply a reloaded configuration to every request checked from now on
+    ///
+    /// Requests already past their checks finish under the rules they were
+    /// checked with, and open handles stay open.
+    pub fn reload(&self, config: &AccessConfig) {
+        let policy = Policy::resolve(config);
+        *self.policy.write().unwrap_or_else(PoisonError::into_inner) = policy;
+    }
+    
+    /// The configuration in effect
+    pub fn config(&self) -> AccessConfig {
+        self.policy().config.clone()
+    }
+    
+    fn policy(&self) -> std::sync::RwLockReadGuard<'_, Policy> {
+        self.policy.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    
+    /// The bits of a requested `mode` clients may set, per `allowed_mode`
+    pub fn permitted_mode(&self, mode: u32) -> u32 {
+        mode & self.policy().config.allowed_mode
+    }
+    
+    /// The innermost allowed or read-only path holding `path`, spelled the
+    /// way `path` spells it
+    pub fn root_of(&self, path: &str) -> Option<PathBuf> {
+        let path = clean_path(Path::new(path));
+        self.policy().roots.iter()
+            .flat_map(|(configured, resolved)| [configured, resolved])
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .cloned()
+    }
+    
+    /// Refuse writes unless `mirror` has been promoted with writes allowed
+    pub fn with_mirror(mut self, mirror: Arc<MirrorState>) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+    
+    /// Deny access to the trash directory named `directory` in every root
+    pub fn with_trash(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.trash = Some(directory.into());
+        self
+    }
+    
+    /// Access control that also applies the per-user rules for `caller`
+    ///
+    /// Statistics are shared with `self`.
+    pub fn for_caller(&self, caller: CallerIdentity) -> Self {
+        Self {
+            caller: Some(caller),
+            ..self.clone()
+        }
+    }
+    
+    /// Access control that also applies the pattern rules naming `client_id`
+    ///
+    /// Statistics are shared with `self`.
+    pub fn for_client(&self, client_id: String) -> Self {
+        Self {
+            client: Some(client_id),
+            ..self.clone()
         }
     }
     
     /// Check if read access is allowed for a path
     pub async fn check_read_access(&self, path: &str) -> Result<()> {
         let result = self.check_path_access(path, AccessType::Read).await;
-        self.update_stats(result.is_ok(), false, false).await;
+        self.update_stats(result.is_ok(), result.is_err(), false).await;
+        result
+    }
+    
+    /// Check if the entry at a path may be read, rather than what a symlink
+    /// there points to
+    pub async fn check_entry_access(&self, path: &str) -> Result<()> {
+        let result = self.check_path_access(path, AccessType::ReadEntry).await;
+        self.update_stats(result.is_ok(), result.is_err(), false).await;
         result
     }
     
     /// Check if write access is allowed for a path
     pub async fn check_write_access(&self, path: &str) -> Result<()> {
         let result = self.check_path_access(path, AccessType::Write).await;
-        self.update_stats(result.is_ok(), false, false).await;
+        self.update_stats(result.is_ok(), result.is_err(), false).await;
         result
     }
     
     /// Check if create access is allowed for a path
     pub async fn check_create_access(&self, path: &str) -> Result<()> {
         let result = self.check_path_access(path, AccessType::Create).await;
-        self.update_stats(result.is_ok(), false, false).await;
+        self.update_stats(result.is_ok(), result.is_err(), false).await;
         result
     }
     
     /// Check if delete access is allowed for a path
     pub async fn check_delete_access(&self, path: &str) -> Result<()> {
         let result = self.check_path_access(path, AccessType::Delete).await;
-        self.update_stats(result.is_ok(), false, false).await;
+        self.update_stats(result.is_ok(), result.is_err(), false).await;
         result
     }
     
+    /// Whether a path may be read, without counting towards the statistics
+    pub async fn is_readable(&self, path: &str) -> bool {
+        self.check_path_access(path, AccessType::Read).await.is_ok()
+    }
+    
+    /// Check every path a request would change, whichever handler it reaches
+    ///
+    /// Requests that only read pass; their handlers check them. A refusal
+    /// counts towards the statistics, a pass does not, since the handler
+    /// checks the same paths again.
+    pub async fn check_request(&self, message: &Message) -> Result<()> {
+        let (access_control, message) = match message {
+            Message::AsUser { identity, request } => (self.for_caller(identity.clone()), request.as_ref()),
+            message => (self.clone(), message),
+        };
+        
+        for (path, access_type) in changed_paths(message) {
+            if let Err(e) = access_control.check_path_access(path, access_type).await {
+                self.update_stats(false, true, false).await;
+                return Err(e);
+            }
+        }
+        
+        Ok(())
+    }
+    
     /// Check if a file size is within limits
     pub async fn check_file_size(&self, size: u64) -> Result<()> {
-        if size > self.config.max_file_size {
+        let max_file_size = self.policy().config.max_file_size;
+        if size > max_file_size {
             self.update_stats(false, false, true).await;
             return Err(RemoteFsError::Authorization(format!(
                 "File size {} exceeds maximum allowed size {}",
                 size,
-                self.config.max_file_size
+                max_file_size
             )));
         }
         
@@ -118,32 +418,71 @@ impl AccessControl {
     
     /// Check path access for a specific access type
     async fn check_path_access(&self, path: &str, access_type: AccessType) -> Result<()> {
-        let path_buf = normalize_path(path);
+        let policy = self.policy();
         
-        // Resolve symlinks if following is disabled
-        let resolved_path = if self.config.follow_symlinks {
-            path_buf.clone()
-        } else {
-            // Check if path contains symlinks
-            if self.contains_symlink(&path_buf)? {
+        // Check for symlinks before normalizing, since canonicalization resolves them
+        if !policy.config.follow_symlinks {
+            let cleaned = clean_path(Path::new(path));
+            // Reading an entry does not go through a symlink at its end
+            let checked = match access_type {
+                AccessType::ReadEntry => cleaned.parent().unwrap_or(&cleaned),
+                _ => &cleaned,
+            };
+            if contains_symlink(checked, policy.trusted_prefix(&cleaned))? {
                 return Err(RemoteFsError::Authorization(
                     "Symlinks are not allowed".to_string()
                 ));
             }
-            path_buf
+        }
+        
+        // A delete removes the entry itself, not what a symlink points to,
+        // and reading an entry reads only the entry; everything else is
+        // checked where symlinks lead
+        let resolved_path = match access_type {
+            AccessType::Delete | AccessType::ReadEntry => normalize_entry(path),
+            _ => normalize_path(path),
         };
+        if policy.config.deny_symlink_escapes && policy.escapes(&clean_path(Path::new(path)), &resolved_path) {
+            debug!("Access denied - symlink leads out of its root: {}", path);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "Symlink leads out of the allowed path: {}",
+                path
+            )));
+        }
         
         // Check denied paths first (highest priority)
-        if self.is_path_denied(&resolved_path) {
+        if matches_any(&policy.denied_paths, &resolved_path) {
             debug!("Access denied - path in denied list: {}", path);
-            return Err(RemoteFsError::Authorization(format!(
+            return Err(RemoteFsError::AccessDenied(format!(
                 "Access denied to path: {}",
                 path
             )));
         }
         
+        if self.trash.as_ref().is_some_and(|trash| policy.in_trash(trash, &resolved_path)) {
+            debug!("Access denied - path is in the trash: {}", path);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "Access denied to the trash: {}",
+                path
+            )));
+        }
+        
+        // Removing a directory removes everything below it as well
+        let is_delete = matches!(access_type, AccessType::Delete);
+        if is_delete && contains_any(&policy.denied_paths, &resolved_path) {
+            debug!("Delete denied - path contains a denied path: {}", path);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "Path contains a denied path: {}",
+                path
+            )));
+        }
+        
         // Check if path is in allowed paths
-        if !self.allowed_paths.is_empty() && !self.is_path_allowed(&resolved_path) {
+        // Read-only paths are implicitly allowed; write checks reject them later
+        if !policy.allowed_paths.is_empty()
+            && !matches_any(&policy.allowed_paths, &resolved_path)
+            && !matches_any(&policy.read_only_paths, &resolved_path)
+        {
             debug!("Access denied - path not in allowed list: {}", path);
             return Err(RemoteFsError::Authorization(format!(
                 "Path not in allowed list: {}",
@@ -151,23 +490,43 @@ impl AccessControl {
             )));
         }
         
+        let is_write = matches!(access_type, AccessType::Write | AccessType::Create | AccessType::Delete);
+        if is_write && self.mirror.as_ref().is_some_and(|mirror| !mirror.allows_writes()) {
+            debug!("Write access denied - agent is a read-only mirror: {}", path);
+            return Err(RemoteFsError::Authorization(
+                "Agent is a read-only mirror".to_string()
+            ));
+        }
+        
         // Check read-only restrictions for write operations
-        if matches!(access_type, AccessType::Write | AccessType::Delete) {
-            if self.is_path_read_only(&resolved_path) {
-                debug!("Write access denied - path is read-only: {}", path);
-                return Err(RemoteFsError::Authorization(format!(
-                    "Path is read-only: {}",
-                    path
-                )));
-            }
+        if is_write && matches_any(&policy.read_only_paths, &resolved_path) {
+            debug!("Write access denied - path is read-only: {}", path);
+            return Err(RemoteFsError::Authorization(format!(
+                "Path is read-only: {}",
+                path
+            )));
+        }
+        
+        if is_delete && contains_any(&policy.read_only_paths, &resolved_path) {
+            debug!("Delete denied - path contains a read-only path: {}", path);
+            return Err(RemoteFsError::Authorization(format!(
+                "Path contains a read-only path: {}",
+                path
+            )));
         }
         
+        if let Some(caller) = &self.caller {
+            self.check_caller_access(&policy, caller, &resolved_path, path, access_type)?;
+        }
+        
+        self.check_rules(&policy, &resolved_path, path, access_type)?;
+        
         // Check file extension restrictions
         if let Some(extension) = resolved_path.extension().and_then(|e| e.to_str()) {
             let ext_lower = extension.to_lowercase();
             
             // Check denied extensions
-            if !self.denied_extensions.is_empty() && self.denied_extensions.contains(&ext_lower) {
+            if !policy.denied_extensions.is_empty() && policy.denied_extensions.contains(&ext_lower) {
                 debug!("Access denied - file extension denied: {}", extension);
                 return Err(RemoteFsError::Authorization(format!(
                     "File extension '{}' is not allowed",
@@ -176,7 +535,7 @@ impl AccessControl {
             }
             
             // Check allowed extensions (if specified)
-            if !self.allowed_extensions.is_empty() && !self.allowed_extensions.contains(&ext_lower) {
+            if !policy.allowed_extensions.is_empty() && !policy.allowed_extensions.contains(&ext_lower) {
                 debug!("Access denied - file extension not allowed: {}", extension);
                 return Err(RemoteFsError::Authorization(format!(
                     "File extension '{}' is not in allowed list",
@@ -189,47 +548,117 @@ impl AccessControl {
         Ok(())
     }
     
-    /// Check if path is in denied list
-    fn is_path_denied(&self, path: &Path) -> bool {
-        self.denied_paths.iter().any(|denied| {
-            path.starts_with(denied) || path == denied
-        })
-    }
-    
-    /// Check if path is in allowed list
-    fn is_path_allowed(&self, path: &Path) -> bool {
-        self.allowed_paths.iter().any(|allowed| {
-            path.starts_with(allowed) || path == allowed
-        })
-    }
-    
-    /// Check if path is in read-only list
-    fn is_path_read_only(&self, path: &Path) -> bool {
-        self.read_only_paths.iter().any(|readonly| {
-            path.starts_with(readonly) || path == readonly
-        })
+    /// Apply the rule for a forwarded caller on top of the agent-wide rules
+    fn check_caller_access(
+        &self,
+        policy: &Policy,
+        caller: &CallerIdentity,
+        resolved_path: &Path,
+        path: &str,
+        access_type: AccessType,
+    ) -> Result<()> {
+        let is_write = matches!(access_type, AccessType::Write | AccessType::Create | AccessType::Delete);
+        let is_delete = matches!(access_type, AccessType::Delete);
+        
+        let rule = match policy.user_rules.iter().find(|rule| rule.matches(caller)) {
+            Some(rule) => rule,
+            None => {
+                return match policy.config.unmatched_users {
+                    UnmatchedUserPolicy::Allow => Ok(()),
+                    UnmatchedUserPolicy::ReadOnly if !is_write => Ok(()),
+                    UnmatchedUserPolicy::ReadOnly => {
+                        debug!("Write access denied - no rule for uid {}: {}", caller.uid, path);
+                        Err(RemoteFsError::Authorization(format!(
+                            "User {} has read-only access to: {}",
+                            caller.uid,
+                            path
+                        )))
+                    }
+                    UnmatchedUserPolicy::Deny => {
+                        debug!("Access denied - no rule for uid {}: {}", caller.uid, path);
+                        Err(RemoteFsError::AccessDenied(format!(
+                            "User {} has no access to: {}",
+                            caller.uid,
+                            path
+                        )))
+                    }
+                };
+            }
+        };
+        
+        if matches_any(&rule.denied_paths, resolved_path)
+            || (is_delete && contains_any(&rule.denied_paths, resolved_path))
+        {
+            debug!("Access denied - path denied for uid {}: {}", caller.uid, path);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "User {} has no access to: {}",
+                caller.uid,
+                path
+            )));
+        }
+        
+        let has_allowed = !rule.allowed_paths.is_empty() || !rule.read_only_paths.is_empty();
+        if has_allowed
+            && !matches_any(&rule.allowed_paths, resolved_path)
+            && !matches_any(&rule.read_only_paths, resolved_path)
+        {
+            debug!("Access denied - path not allowed for uid {}: {}", caller.uid, path);
+            return Err(RemoteFsError::Authorization(format!(
+                "Path not in allowed list for user {}: {}",
+                caller.uid,
+                path
+            )));
+        }
+        
+        let read_only = rule.read_only
+            || matches_any(&rule.read_only_paths, resolved_path)
+            || (is_delete && contains_any(&rule.read_only_paths, resolved_path));
+        if is_write && read_only {
+            debug!("Write access denied - read-only for uid {}: {}", caller.uid, path);
+            return Err(RemoteFsError::Authorization(format!(
+                "User {} has read-only access to: {}",
+                caller.uid,
+                path
+            )));
+        }
+        
+        Ok(())
     }
     
-    /// Check if path contains symlinks
-    fn contains_symlink(&self, path: &Path) -> Result<bool> {
-        let mut current = PathBuf::new();
+    /// Apply the pattern rules; a matching deny rule beats any allow rule
+    fn check_rules(
+        &self,
+        policy: &Policy,
+        resolved_path: &Path,
+        path: &str,
+        access_type: AccessType,
+    ) -> Result<()> {
+        let verb = match access_type {
+            AccessType::Read | AccessType::ReadEntry => AccessVerb::Read,
+            AccessType::Write | AccessType::Create => AccessVerb::Write,
+            AccessType::Delete => AccessVerb::Delete,
+        };
         
-        for component in path.components() {
-            current.push(component);
-            
-            if current.exists() {
-                let metadata = current.symlink_metadata()
-                    .map_err(|e| RemoteFsError::FileSystem(format!(
-                        "Failed to read symlink metadata: {}", e
-                    )))?;
-                
-                if metadata.file_type().is_symlink() {
-                    return Ok(true);
-                }
+        let mut effect = None;
+        for rule in policy.rules.iter().filter(|rule| rule.matches(self.client.as_deref(), resolved_path, verb)) {
+            effect = Some(rule.effect);
+            if rule.effect == RuleEffect::Deny {
+                break;
             }
         }
         
-        Ok(false)
+        if effect.unwrap_or(policy.config.unmatched_rules) == RuleEffect::Deny {
+            let client = self.client.as_deref().unwrap_or("unnamed client");
+            debug!("Access denied - {} by rule for {}: {}", access_type, client, path);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "{} access denied by rule for {}: {}",
+                access_type,
+                client,
+                path
+            )));
+        }
+        
+        Ok(())
     }
     
     /// Update access control statistics
@@ -252,10 +681,208 @@ impl AccessControl {
     }
 }
 
+/// Paths a request changes and the access each needs
+///
+/// Every message is listed so a new request has to be placed here. Batch
+/// creates are left to their handler, which checks each file so a refused
+/// file does not fail the rest of the batch, and the requests of a `Batch`
+/// are checked one by one as they are handled. Locks change no file; their
+/// handler checks the access the type of lock needs. Opens are checked by
+/// their handler against the flags they give, and a handle is only used for
+/// what it was opened for.
+fn changed_paths(message: &Message) -> Vec<(&str, AccessType)> {
+    match message {
+        Message::WriteFile { path, .. }
+        | Message::WriteFileChunk { path, .. }
+        | Message::WriteDelta { path, .. }
+        | Message::TruncateFile { path, .. }
+        | Message::SetMetadata { path, .. }
+        | Message::SetMetadataTree { path, .. }
+        | Message::SetXattr { path, .. }
+        | Message::RemoveXattr { path, .. } => vec![(path, AccessType::Write)],
+        
+        Message::CreateFile { path, .. }
+        | Message::CreateDirectory { path, .. } => vec![(path, AccessType::Create)],
+        Message::CreateSymlink { link_path, .. } => vec![(link_path, AccessType::Create)],
+        Message::RestoreFromTrash { path, .. } => vec![(path, AccessType::Create)],
+        // The link makes the file writable through another path
+        Message::CreateHardLink { existing_path, link_path, .. } => {
+            vec![(existing_path, AccessType::Write), (link_path, AccessType::Create)]
+        }
+        Message::CopyFile { destination, .. } => vec![(destination, AccessType::Create)],
+        
+        Message::DeleteFile { path, .. }
+        | Message::RemoveDirectory { path, .. }
+        | Message::PurgeTrash { path, .. } => vec![(path, AccessType::Delete)],
+        Message::Rename { from_path, to_path, .. } => {
+            vec![(from_path, AccessType::Delete), (to_path, AccessType::Create)]
+        }
+        
+        Message::Transaction { operations, .. } => operations.iter()
+            .flat_map(|operation| match operation {
+                TransactionOp::WriteFile { path, .. } => vec![(path.as_str(), AccessType::Write)],
+                TransactionOp::Rename { from_path, to_path } => {
+                    vec![(from_path.as_str(), AccessType::Delete), (to_path.as_str(), AccessType::Create)]
+                }
+                TransactionOp::DeleteFile { path }
+                | TransactionOp::RemoveDirectory { path } => vec![(path.as_str(), AccessType::Delete)],
+                TransactionOp::CreateDirectory { path } => vec![(path.as_str(), AccessType::Create)],
+            })
+            .collect(),
+        
+        // A command may change anything in the directory it runs in
+        Message::ExtendedOperation { working_dir, .. } => working_dir.iter()
+            .map(|working_dir| (working_dir.as_str(), AccessType::Write))
+            .collect(),
+        
+        Message::AsUser { request, .. }
+        | Message::FromClient { request, .. } => changed_paths(request),
+        
+        Message::BatchCreateFiles { .. }
+        | Message::Batch { .. }
+        | Message::ReadFile { .. }
+        | Message::ComputeChecksum { .. }
+        | Message::GetFileSignature { .. }
+        | Message::ReadSymlink { .. }
+        | Message::LockFile { .. }
+        | Message::UnlockFile { .. }
+        | Message::TestLock { .. }
+        | Message::OpenFile { .. }
+        | Message::ReadHandle { .. }
+        | Message::WriteHandle { .. }
+        | Message::CloseFile { .. }
+        | Message::ReadFileStream { .. }
+        | Message::ListDirectory { .. }
+        | Message::ListDirectoryPaged { .. }
+        | Message::WalkDirectory { .. }
+        | Message::SearchFiles { .. }
+        | Message::GetMetadata { .. }
+        | Message::GetXattr { .. }
+        | Message::ListXattr { .. }
+        | Message::PathExists { .. }
+        | Message::GetSpaceInfo { .. }
+        | Message::ListExports { .. }
+        | Message::GetChanges { .. }
+        | Message::Watch { .. }
+        | Message::Unwatch { .. }
+        | Message::CancelSetMetadataTree { .. }
+        | Message::ReadFileAsOf { .. }
+        | Message::ReadBackupEntry { .. } => Vec::new(),
+        
+        // Responses and messages that name no path on this agent
+        Message::AuthRequest { .. }
+        | Message::AuthResponse { .. }
+        | Message::ResumeSession { .. }
+        | Message::EstablishChannel { .. }
+        | Message::ChannelEstablished { .. }
+        | Message::ReadFileResponse { .. }
+        | Message::WriteFileResponse { .. }
+        | Message::ReadFileChunk { .. }
+        | Message::ReadFileAck { .. }
+        | Message::CreateFileResponse { .. }
+        | Message::DeleteFileResponse { .. }
+        | Message::TruncateFileResponse { .. }
+        | Message::ChecksumResponse { .. }
+        | Message::FileSignatureResponse { .. }
+        | Message::LockFileResponse { .. }
+        | Message::UnlockFileResponse { .. }
+        | Message::TestLockResponse { .. }
+        | Message::ReleaseLocks { .. }
+        | Message::OpenFileResponse { .. }
+        | Message::CloseFileResponse { .. }
+        | Message::ListDirectoryResponse { .. }
+        | Message::DirectoryPage { .. }
+        | Message::SearchResults { .. }
+        | Message::CreateDirectoryResponse { .. }
+        | Message::RemoveDirectoryResponse { .. }
+        | Message::GetMetadataResponse { .. }
+        | Message::SetMetadataResponse { .. }
+        | Message::SetMetadataTreeProgress { .. }
+        | Message::GetXattrResponse { .. }
+        | Message::SetXattrResponse { .. }
+        | Message::ListXattrResponse { .. }
+        | Message::RemoveXattrResponse { .. }
+        | Message::RenameResponse { .. }
+        | Message::CreateSymlinkResponse { .. }
+        | Message::CreateHardLinkResponse { .. }
+        | Message::ReadSymlinkResponse { .. }
+        | Message::CopyFileResponse { .. }
+        | Message::PathExistsResponse { .. }
+        | Message::GetSpaceInfoResponse { .. }
+        | Message::ListExportsResponse { .. }
+        | Message::GetChangesResponse { .. }
+        | Message::WatchResponse { .. }
+        | Message::FileChanged { .. }
+        | Message::DirectoryChanged { .. }
+        | Message::WatchEnded { .. }
+        | Message::ReadBackupEntryResponse { .. }
+        | Message::TransactionResponse { .. }
+        | Message::BatchCreateFilesResponse { .. }
+        | Message::RestoreFromTrashResponse { .. }
+        | Message::PurgeTrashResponse { .. }
+        | Message::BatchResponse { .. }
+        | Message::ExtendedOutput { .. }
+        | Message::Ping { .. }
+        | Message::Pong { .. }
+        | Message::ConnectionClose { .. }
+        | Message::Compressed { .. }
+        | Message::MirrorStatus { .. }
+        | Message::AgentHealth { .. }
+        | Message::Audit { .. }
+        | Message::Broadcast { .. }
+        | Message::GetRelayDirectory
+        | Message::RelayDirectoryResponse { .. }
+        | Message::ListAgents { .. }
+        | Message::ListAgentsResponse { .. }
+        | Message::GetMaintenance { .. }
+        | Message::MaintenanceStatus { .. }
+        | Message::Error { .. } => Vec::new(),
+    }
+}
+
+/// Whether `message` changes files, so takes a write slot rather than a
+/// read slot while it is handled
+pub(crate) fn is_write(message: &Message) -> bool {
+    matches!(message, Message::WriteHandle { .. } | Message::BatchCreateFiles { .. }) || !changed_paths(message).is_empty()
+}
+
+/// Whether `path` has a symlink below its first `trusted` components
+fn contains_symlink(path: &Path, trusted: usize) -> Result<bool> {
+    let mut current = PathBuf::new();
+    
+    for (depth, component) in path.components().enumerate() {
+        current.push(component);
+        
+        if depth >= trusted && current.exists() {
+            let metadata = current.symlink_metadata()
+                .map_err(|e| RemoteFsError::io("Failed to read symlink metadata", e))?;
+            
+            if metadata.file_type().is_symlink() {
+                return Ok(true);
+            }
+        }
+    }
+    
+    Ok(false)
+}
+
+/// Whether `path` is one of `paths` or below one of them
+fn matches_any(paths: &HashSet<PathBuf>, path: &Path) -> bool {
+    paths.iter().any(|p| path.starts_with(p))
+}
+
+/// Whether one of `paths` is `path` or below it
+fn contains_any(paths: &HashSet<PathBuf>, path: &Path) -> bool {
+    paths.iter().any(|p| p.starts_with(path))
+}
+
 /// Type of access being requested
 #[derive(Debug, Clone, Copy)]
 enum AccessType {
     Read,
+    /// Reading an entry itself, such as where a symlink points, without
+    /// following a symlink at its end
+    ReadEntry,
     Write,
     Create,
     Delete,
@@ -265,6 +892,7 @@ impl std::fmt::Display for AccessType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             AccessType::Read => write!(f, "read"),
+            AccessType::ReadEntry => write!(f, "read entry"),
             AccessType::Write => write!(f, "write"),
             AccessType::Create => write!(f, "create"),
             AccessType::Delete => write!(f, "delete"),
@@ -272,16 +900,67 @@ impl std::fmt::Display for AccessType {
     }
 }
 
+/// Symlinks followed in resolving a path before giving up, as the kernel does
+const MAX_SYMLINK_HOPS: usize = 40;
+
 /// Normalize a path by resolving it to an absolute path
+///
+/// For a path that does not exist yet, such as a file about to be created,
+/// its closest existing ancestor is resolved so it matches the same rules
+/// as its siblings. A dangling symlink is followed to where it points,
+/// since creating a file through it creates the file there.
 fn normalize_path(path: &str) -> PathBuf {
-    let path_buf = PathBuf::from(path);
+    let mut cleaned = clean_path(Path::new(path));
     
-    // Try to canonicalize if the path exists, otherwise just clean it up
-    if path_buf.exists() {
-        path_buf.canonicalize().unwrap_or_else(|_| clean_path(&path_buf))
-    } else {
-        clean_path(&path_buf)
+    for _ in 0..MAX_SYMLINK_HOPS {
+        let mut missing = Vec::new();
+        let mut existing = cleaned.as_path();
+        while existing.symlink_metadata().is_err() {
+            match (existing.file_name(), existing.parent()) {
+                (Some(name), Some(parent)) => {
+                    missing.push(name.to_os_string());
+                    existing = parent;
+                }
+                _ => return cleaned,
+            }
+        }
+        
+        if let Ok(mut resolved) = existing.canonicalize() {
+            resolved.extend(missing.iter().rev());
+            return resolved;
+        }
+        
+        // Only a dangling symlink exists but cannot be canonicalized
+        let (Ok(target), Some(Ok(parent))) = (existing.read_link(), existing.parent().map(Path::canonicalize)) else {
+            return cleaned;
+        };
+        let mut next = parent.join(target);
+        next.extend(missing.iter().rev());
+        cleaned = clean_path(&next);
+    }
+    
+    cleaned
+}
+
+/// Normalize a path without following a symlink at its end, for requests
+/// on the entry itself
+fn normalize_entry(path: &str) -> PathBuf {
+    let cleaned = clean_path(Path::new(path));
+    match (cleaned.parent(), cleaned.file_name()) {
+        (Some(parent), Some(name)) => normalize_path(&parent.to_string_lossy()).join(name),
+        _ => cleaned,
+    }
+}
+
+/// Resolve a configured path, saying when it is a symlink or missing
+fn resolve_root(kind: &str, path: &str) -> PathBuf {
+    let resolved = normalize_path(path);
+    if !Path::new(path).exists() {
+        warn!("{} path {} does not exist; send SIGHUP to resolve it again once it does", kind, path);
+    } else if resolved != clean_path(Path::new(path)) {
+        warn!("{} path {} resolves to {}; requests are matched against the latter", kind, path, resolved.display());
     }
+    resolved
 }
 
 /// Clean up a path without requiring it to exist
@@ -309,6 +988,8 @@ fn clean_path(path: &Path) -> PathBuf {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use remotefs_common::protocol::{ChecksumAlgorithm, MetadataUpdate, NewFile, XattrSetMode};
+    use uuid::Uuid;
     use std::fs;
     use tempfile::TempDir;
     
@@ -319,8 +1000,15 @@ mod tests {
             denied_paths: vec!["/etc".to_string(), "/root".to_string()],
             max_file_size: 1024 * 1024, // 1MB
             follow_symlinks: false,
+            deny_symlink_escapes: false,
             allowed_extensions: vec!["txt".to_string(), "md".to_string()],
             denied_extensions: vec!["exe".to_string(), "bat".to_string()],
+            user_rules: vec![],
+            unmatched_users: UnmatchedUserPolicy::Allow,
+            rules: vec![],
+            unmatched_rules: RuleEffect::Allow,
+            allowed_mode: 0o7777,
+            quotas: Vec::new(),
         }
     }
     
@@ -441,4 +1129,393 @@ mod tests {
         #[cfg(unix)]
         assert!(access_control.check_read_access(symlink_path.to_str().unwrap()).await.is_err());
     }
+    
+    #[tokio::test]
+    async fn test_symlinked_root() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("private");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("notes.txt"), "notes").unwrap();
+        let root = temp_dir.path().join("root");
+        std::os::unix::fs::symlink(&target, &root).unwrap();
+        
+        let mut config = create_test_access_config();
+        config.allowed_paths = vec![root.to_string_lossy().to_string()];
+        config.follow_symlinks = false;
+        let access_control = AccessControl::new(&config);
+        
+        // The configured root may be a symlink; paths below it may not
+        let path = |p: &Path| p.to_string_lossy().to_string();
+        assert!(access_control.check_read_access(&path(&root.join("notes.txt"))).await.is_ok());
+        assert!(access_control.check_read_access(&path(&target.join("notes.txt"))).await.is_ok());
+        assert!(access_control.check_create_access(&path(&root.join("new/file.txt"))).await.is_ok());
+        std::os::unix::fs::symlink(target.join("notes.txt"), target.join("link.txt")).unwrap();
+        assert!(access_control.check_read_access(&path(&root.join("link.txt"))).await.is_err());
+        
+        // A root created after startup matches once paths are resolved again
+        let later = temp_dir.path().join("later");
+        config.allowed_paths = vec![path(&later)];
+        let access_control = AccessControl::new(&config);
+        fs::create_dir(target.join("later")).unwrap();
+        std::os::unix::fs::symlink(target.join("later"), &later).unwrap();
+        fs::write(later.join("a.txt"), "a").unwrap();
+        assert!(access_control.check_read_access(&path(&later.join("a.txt"))).await.is_err());
+        access_control.resolve_paths();
+        assert!(access_control.check_read_access(&path(&later.join("a.txt"))).await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_symlink_escapes() {
+        let temp_dir = TempDir::new().unwrap();
+        let (share, other, outside) = (temp_dir.path().join("share"), temp_dir.path().join("other"), temp_dir.path().join("outside"));
+        for dir in [&share, &other, &outside] {
+            fs::create_dir(dir).unwrap();
+        }
+        fs::write(other.join("b.txt"), "b").unwrap();
+        std::os::unix::fs::symlink(outside.join("new.txt"), share.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(&other, share.join("to-other")).unwrap();
+        
+        let mut config = create_test_access_config();
+        config.allowed_paths = vec![share.to_string_lossy().to_string(), other.to_string_lossy().to_string()];
+        config.follow_symlinks = true;
+        let access_control = AccessControl::new(&config);
+        let path = |p: &Path| p.to_string_lossy().to_string();
+        
+        // Creating through a dangling symlink would create its target
+        assert!(access_control.check_create_access(&path(&share.join("dangling"))).await.is_err());
+        assert!(access_control.check_delete_access(&path(&share.join("dangling"))).await.is_ok());
+        
+        // Symlinks between allowed paths are followed unless escapes are denied
+        assert!(access_control.check_read_access(&path(&share.join("to-other/b.txt"))).await.is_ok());
+        config.deny_symlink_escapes = true;
+        access_control.reload(&config);
+        assert!(access_control.check_read_access(&path(&share.join("to-other/b.txt"))).await.is_err());
+        assert!(access_control.check_read_access(&path(&other.join("b.txt"))).await.is_ok());
+        assert!(access_control.check_delete_access(&path(&share.join("to-other"))).await.is_ok());
+    }
+    
+    #[tokio::test]
+    async fn test_trash_denied() {
+        let temp_dir = TempDir::new().unwrap();
+        let share = temp_dir.path().join("share");
+        fs::create_dir_all(share.join(".remotefs-trash/files")).unwrap();
+        std::os::unix::fs::symlink(share.join(".remotefs-trash"), share.join("bin")).unwrap();
+        
+        let mut config = create_test_access_config();
+        config.allowed_paths = vec![share.to_string_lossy().to_string()];
+        config.follow_symlinks = true;
+        let access_control = AccessControl::new(&config).with_trash(".remotefs-trash");
+        let path = |p: &Path| p.to_string_lossy().to_string();
+        
+        // Neither the trash nor anything in it is reachable, through symlinks or not
+        assert!(access_control.check_read_access(&path(&share.join(".remotefs-trash"))).await.is_err());
+        assert!(access_control.check_read_access(&path(&share.join(".remotefs-trash/files/x.txt"))).await.is_err());
+        assert!(access_control.check_create_access(&path(&share.join("bin/files/x.txt"))).await.is_err());
+        assert!(access_control.check_delete_access(&path(&share.join(".remotefs-trash"))).await.is_err());
+        assert!(access_control.check_read_access(&path(&share.join(".remotefs-trash-old.txt"))).await.is_ok());
+        assert!(access_control.check_delete_access(&path(&share.join("bin"))).await.is_ok());
+    }
+    
+    fn caller(uid: u32, groups: Vec<u32>) -> CallerIdentity {
+        CallerIdentity { uid, gid: uid, groups }
+    }
+    
+    #[tokio::test]
+    async fn test_user_rules() {
+        let mut config = create_test_access_config();
+        config.user_rules = vec![
+            UserAccessRule {
+                uids: vec![1001],
+                allowed_paths: vec!["/home/user/shared".to_string()],
+                denied_paths: vec!["/home/user/shared/private".to_string()],
+                ..Default::default()
+            },
+            UserAccessRule {
+                gids: vec![100],
+                read_only: true,
+                ..Default::default()
+            },
+        ];
+        let access_control = AccessControl::new(&config);
+        
+        // Without a forwarded caller only the agent-wide rules apply
+        assert!(access_control.check_write_access("/tmp/notes.txt").await.is_ok());
+        
+        let alice = access_control.for_caller(caller(1001, vec![]));
+        assert!(alice.check_write_access("/home/user/shared/notes.txt").await.is_ok());
+        assert!(alice.check_read_access("/tmp/notes.txt").await.is_err());
+        assert!(alice.check_read_access("/home/user/shared/private/notes.txt").await.is_err());
+        
+        // Agent-wide rules still apply on top of the user's rule
+        assert!(alice.check_read_access("/home/user/shared/setup.exe").await.is_err());
+        
+        let staff = access_control.for_caller(caller(1002, vec![100]));
+        assert!(staff.check_read_access("/tmp/notes.txt").await.is_ok());
+        assert!(staff.check_write_access("/tmp/notes.txt").await.is_err());
+    }
+    
+    #[test]
+    fn test_patterns() {
+        let matches = |pattern: &str, value: &str| compile_pattern(pattern).unwrap().is_match(value);
+        
+        assert!(matches("/tmp/*.txt", "/tmp/notes.txt"));
+        assert!(!matches("/tmp/*.txt", "/tmp/drafts/notes.txt"));
+        assert!(matches("/tmp/**/*.txt", "/tmp/notes.txt"));
+        assert!(matches("/tmp/**/*.txt", "/tmp/drafts/2024/notes.txt"));
+        assert!(matches("/tmp/**", "/tmp/drafts/notes.txt"));
+        assert!(matches("/tmp/note?.[tm][!a]t", "/tmp/notes.txt"));
+        assert!(!matches("/tmp/note?.[tm][!x]t", "/tmp/notes.txt"));
+        assert!(matches("/tmp/a+b (1).txt", "/tmp/a+b (1).txt"));
+        assert!(matches("laptop-*", "laptop-alice"));
+        assert!(matches(r"regex:/tmp/\d+\.log", "/tmp/2024.log"));
+        assert!(!matches(r"regex:/tmp/\d+", "/tmp/2024.log"));
+        
+        let mut config = create_test_access_config();
+        config.rules = vec![AccessRule { paths: vec!["regex:(".to_string()], ..Default::default() }];
+        assert!(validate_rules(&config).is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_pattern_rules() {
+        let mut config = create_test_access_config();
+        config.allowed_extensions = vec![];
+        config.rules = vec![
+            AccessRule {
+                effect: RuleEffect::Deny,
+                paths: vec!["/tmp/**/.git".to_string()],
+                verbs: vec![AccessVerb::Write, AccessVerb::Delete],
+                ..Default::default()
+            },
+            AccessRule {
+                effect: RuleEffect::Allow,
+                clients: vec!["build-*".to_string()],
+                paths: vec!["/tmp/**".to_string()],
+                ..Default::default()
+            },
+            AccessRule {
+                effect: RuleEffect::Allow,
+                paths: vec!["/tmp/public/**".to_string()],
+                verbs: vec![AccessVerb::Read],
+                ..Default::default()
+            },
+        ];
+        
+        // Without rules for them, requests fall back to `unmatched_rules`
+        let access_control = AccessControl::new(&config);
+        assert!(access_control.check_write_access("/tmp/notes.txt").await.is_ok());
+        assert!(access_control.check_write_access("/tmp/repo/.git/config").await.is_err());
+        assert!(access_control.check_read_access("/tmp/repo/.git/config").await.is_ok());
+        
+        config.unmatched_rules = RuleEffect::Deny;
+        let access_control = AccessControl::new(&config);
+        assert!(access_control.check_read_access("/tmp/notes.txt").await.is_err());
+        assert!(access_control.check_read_access("/tmp/public/notes.txt").await.is_ok());
+        assert!(access_control.check_write_access("/tmp/public/notes.txt").await.is_err());
+        
+        let builder = access_control.for_client("build-1".to_string());
+        assert!(builder.check_write_access("/tmp/notes.txt").await.is_ok());
+        assert!(builder.check_delete_access("/tmp/notes.txt").await.is_ok());
+        // Deny wins over the client's allow rule
+        assert!(builder.check_delete_access("/tmp/repo/.git").await.is_err());
+        assert!(access_control.for_client("laptop".to_string()).check_write_access("/tmp/notes.txt").await.is_err());
+        
+        // Path lists still apply to clients the rules allow
+        assert!(builder.check_read_access("/etc/passwd").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let mut config = create_test_access_config();
+        let access_control = AccessControl::new(&config);
+        let client = access_control.for_client("laptop".to_string());
+        assert!(client.check_read_access("/srv/notes.txt").await.is_err());
+
+        // Copies made for callers and clients see the reloaded rules too
+        config.allowed_paths.push("/srv".to_string());
+        config.max_file_size = 1024;
+        access_control.reload(&config);
+        assert!(client.check_read_access("/srv/notes.txt").await.is_ok());
+        assert!(client.check_file_size(1025).await.is_err());
+        assert_eq!(access_control.config().allowed_paths, config.allowed_paths);
+    }
+    
+    #[tokio::test]
+    async fn test_unmatched_user_policy() {
+        let mut config = create_test_access_config();
+        config.user_rules = vec![UserAccessRule { uids: vec![1001], ..Default::default() }];
+        
+        let access_control = AccessControl::new(&config);
+        let stranger = access_control.for_caller(caller(2000, vec![]));
+        assert!(stranger.check_write_access("/tmp/notes.txt").await.is_ok());
+        
+        config.unmatched_users = UnmatchedUserPolicy::ReadOnly;
+        let stranger = AccessControl::new(&config).for_caller(caller(2000, vec![]));
+        assert!(stranger.check_read_access("/tmp/notes.txt").await.is_ok());
+        assert!(stranger.check_delete_access("/tmp/notes.txt").await.is_err());
+        
+        config.unmatched_users = UnmatchedUserPolicy::Deny;
+        let access_control = AccessControl::new(&config);
+        let stranger = access_control.for_caller(caller(2000, vec![]));
+        assert!(stranger.check_read_access("/tmp/notes.txt").await.is_err());
+        let alice = access_control.for_caller(caller(1001, vec![]));
+        assert!(alice.check_write_access("/tmp/notes.txt").await.is_ok());
+        
+        // Requests naming no user are held to the agent-wide rules only
+        assert!(access_control.check_write_access("/tmp/notes.txt").await.is_ok());
+        
+        // Checks made for a caller count towards the shared statistics
+        assert_eq!(access_control.get_statistics().await.denied_requests, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_read_only_request_matrix() {
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        let read_only = shared.join("readonly");
+        fs::create_dir_all(&read_only).unwrap();
+        fs::write(read_only.join("notes.txt"), "notes").unwrap();
+        
+        let path = |p: &Path| p.to_string_lossy().to_string();
+        let mut config = create_test_access_config();
+        config.allowed_paths = vec![path(&shared)];
+        config.read_only_paths = vec![path(&read_only)];
+        config.allowed_extensions = vec![];
+        let access_control = AccessControl::new(&config);
+        
+        let file = path(&read_only.join("notes.txt"));
+        let directory = path(&read_only);
+        let writable = path(&shared.join("notes.txt"));
+        let id = Uuid::new_v4;
+        
+        // Every request naming a path, aimed at the read-only directory
+        let matrix = vec![
+            (Message::ReadFile { request_id: id(), path: file.clone(), offset: 0, length: 5 }, false),
+            (Message::WriteFile { request_id: id(), path: file.clone(), offset: 0, data: vec![1], sync: false }, true),
+            (Message::ReadFileStream { request_id: id(), path: file.clone(), offset: 0, length: None, chunk_size: 2 }, false),
+            (Message::WriteFileChunk {
+                request_id: id(),
+                path: file.clone(),
+                sequence: 0,
+                offset: 0,
+                data: vec![1],
+                last: true,
+                sync: false,
+            }, true),
+            (Message::CreateFile { request_id: id(), path: path(&read_only.join("new.txt")), mode: 0o644, exclusive: true }, true),
+            (Message::DeleteFile { request_id: id(), path: file.clone() }, true),
+            (Message::ComputeChecksum {
+                request_id: id(),
+                path: file.clone(),
+                algorithm: ChecksumAlgorithm::Sha256,
+                offset: 0,
+                length: None,
+            }, false),
+            (Message::GetFileSignature { request_id: id(), path: file.clone(), block_size: 4096 }, false),
+            (Message::WriteDelta {
+                request_id: id(),
+                path: file.clone(),
+                block_size: 4096,
+                ops: vec![],
+                checksum: vec![],
+                sync: false,
+            }, true),
+            (Message::TruncateFile { request_id: id(), path: file.clone(), size: 0 }, true),
+            (Message::ListDirectory { request_id: id(), path: directory.clone() }, false),
+            (Message::ListDirectoryPaged { request_id: id(), path: directory.clone(), page_size: 10, after: None, max_entries: None }, false),
+            (Message::WalkDirectory { request_id: id(), path: directory.clone(), max_depth: None, follow_symlinks: false }, false),
+            (Message::SearchFiles {
+                request_id: id(),
+                root: directory.clone(),
+                name_glob: None,
+                content_regex: Some("text".to_string()),
+                max_results: None,
+            }, false),
+            (Message::CreateDirectory { request_id: id(), path: path(&read_only.join("new")), mode: 0o755 }, true),
+            (Message::RemoveDirectory { request_id: id(), path: directory.clone(), recursive: true }, true),
+            (Message::GetMetadata { request_id: id(), path: file.clone(), follow_symlinks: true }, false),
+            (Message::SetMetadata { request_id: id(), path: file.clone(), update: MetadataUpdate::default() }, true),
+            (Message::SetMetadataTree { request_id: id(), path: directory.clone(), update: MetadataUpdate::default() }, true),
+            (Message::GetXattr { request_id: id(), path: file.clone(), name: "user.tag".to_string() }, false),
+            (Message::SetXattr {
+                request_id: id(),
+                path: file.clone(),
+                name: "user.tag".to_string(),
+                value: vec![1],
+                mode: XattrSetMode::Upsert,
+            }, true),
+            (Message::ListXattr { request_id: id(), path: file.clone() }, false),
+            (Message::RemoveXattr { request_id: id(), path: file.clone(), name: "user.tag".to_string() }, true),
+            (Message::Rename { request_id: id(), from_path: file.clone(), to_path: writable.clone(), no_replace: false }, true),
+            (Message::Rename { request_id: id(), from_path: writable.clone(), to_path: file.clone(), no_replace: false }, true),
+            (Message::CreateSymlink { request_id: id(), link_path: path(&read_only.join("link.txt")), target_path: writable.clone() }, true),
+            (Message::CreateHardLink { request_id: id(), existing_path: file.clone(), link_path: writable.clone() }, true),
+            (Message::CreateHardLink { request_id: id(), existing_path: writable.clone(), link_path: path(&read_only.join("link.txt")) }, true),
+            (Message::ReadSymlink { request_id: id(), path: file.clone() }, false),
+            (Message::CopyFile { request_id: id(), source: file.clone(), destination: writable.clone(), offset: 0, length: None }, false),
+            (Message::CopyFile { request_id: id(), source: writable.clone(), destination: file.clone(), offset: 0, length: Some(1) }, true),
+            (Message::PathExists { request_id: id(), path: file.clone() }, false),
+            (Message::GetSpaceInfo { request_id: id(), path: directory.clone() }, false),
+            (Message::Watch { request_id: id(), path: directory.clone(), recursive: true }, false),
+            (Message::ReadFileAsOf { request_id: id(), path: file.clone(), offset: 0, length: 5, as_of: chrono::Utc::now() }, false),
+            (Message::ReadBackupEntry { request_id: id(), path: file.clone() }, false),
+            (Message::Transaction {
+                request_id: id(),
+                operations: vec![
+                    TransactionOp::WriteFile { path: writable.clone(), data: vec![1] },
+                    TransactionOp::DeleteFile { path: file.clone() },
+                ],
+            }, true),
+            (Message::Transaction {
+                request_id: id(),
+                operations: vec![TransactionOp::CreateDirectory { path: path(&read_only.join("new")) }],
+            }, true),
+            (Message::ExtendedOperation {
+                request_id: id(),
+                name: "build".to_string(),
+                arguments: vec![],
+                working_dir: Some(directory.clone()),
+            }, true),
+            // Checked file by file by its handler instead
+            (Message::BatchCreateFiles {
+                request_id: id(),
+                files: vec![NewFile { path: path(&read_only.join("new.txt")), data: vec![1], mode: None }],
+                overwrite: false,
+            }, false),
+        ];
+        
+        for (request, refused) in matrix {
+            let result = access_control.check_request(&request).await;
+            assert_eq!(result.is_err(), refused, "{} {:?}", request.message_type(), result);
+        }
+        
+        // Removing or moving a directory holding a read-only one is refused
+        let remove_shared = Message::RemoveDirectory { request_id: id(), path: path(&shared), recursive: true };
+        assert!(access_control.check_request(&remove_shared).await.is_err());
+        let moved = path(&temp_dir.path().join("moved"));
+        let move_shared = Message::Rename { request_id: id(), from_path: path(&shared), to_path: moved, no_replace: false };
+        assert!(access_control.check_request(&move_shared).await.is_err());
+        let delete_writable = Message::DeleteFile { request_id: id(), path: writable };
+        assert!(access_control.check_request(&delete_writable).await.is_ok());
+        
+        // The same holds for a caller's read-only paths
+        config.read_only_paths = vec![];
+        config.user_rules = vec![UserAccessRule {
+            uids: vec![1001],
+            read_only_paths: vec![path(&read_only)],
+            allowed_paths: vec![path(&shared)],
+            ..Default::default()
+        }];
+        let access_control = AccessControl::new(&config);
+        let as_user = |request: Message| Message::AsUser { identity: caller(1001, vec![]), request: Box::new(request) };
+        let remove_shared = Message::RemoveDirectory { request_id: id(), path: path(&shared), recursive: true };
+        assert!(access_control.check_request(&remove_shared).await.is_ok());
+        assert!(access_control.check_request(&as_user(remove_shared)).await.is_err());
+        let set_metadata = Message::SetMetadata { request_id: id(), path: file.clone(), update: MetadataUpdate::default() };
+        assert!(access_control.check_request(&as_user(set_metadata)).await.is_err());
+        assert!(access_control.check_request(&as_user(Message::ReadFile { request_id: id(), path: file, offset: 0, length: 5 })).await.is_ok());
+        
+        // Only refusals are counted; handlers count the requests they check
+        let statistics = access_control.get_statistics().await;
+        assert_eq!((statistics.allowed_requests, statistics.denied_requests), (0, 2));
+    }
 }
@@ -0,0 +1,196 @@
+//! Hooks for files offloaded to cold storage
+//!
+//! An external archiver (e.g. one moving data to S3 Glacier) marks each file
+//! it has offloaded by creating `<file><marker_suffix>` next to the stub it
+//! leaves behind, and removes the marker once the content is back. The agent
+//! reports marked files as offline and, instead of serving the stub, answers
+//! reads with a `FileOffline` error and starts the configured recall command.
+//! Readers retry until the file is online again.
+
+use remotefs_common::config::ArchiveConfig;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{process::Command, sync::Mutex};
+use tracing::{info, warn};
+
+/// Where a recall of an offline file stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecallState {
+    /// Recall requested; the file comes back when the archiver removes its marker
+    Recalling { started_at: DateTime<Utc> },
+    /// The recall command failed; the next read tries again
+    Failed { error: String },
+    /// No recall command is configured, so recall is left to the archiver
+    Unavailable,
+}
+
+impl RecallState {
+    /// Short name used in error details
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecallState::Recalling { .. } => "in_progress",
+            RecallState::Failed { .. } => "failed",
+            RecallState::Unavailable => "unavailable",
+        }
+    }
+}
+
+/// Detects offline files and recalls them on demand
+pub struct ArchiveHooks {
+    config: ArchiveConfig,
+    recalls: Mutex<HashMap<PathBuf, RecallState>>,
+}
+
+impl ArchiveHooks {
+    pub fn new(config: ArchiveConfig) -> Self {
+        Self {
+            config,
+            recalls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Hooks described by `config`, or `None` if they are disabled
+    pub fn from_config(config: &ArchiveConfig) -> Option<Self> {
+        config.enabled.then(|| Self::new(config.clone()))
+    }
+
+    /// Marker file the archiver creates for `path`
+    pub fn marker_path(&self, path: &Path) -> PathBuf {
+        let mut marker = OsString::from(path.as_os_str());
+        marker.push(&self.config.marker_suffix);
+        PathBuf::from(marker)
+    }
+
+    /// Whether `path` is an archiver marker rather than user data
+    pub fn is_marker(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.len() > self.config.marker_suffix.len()
+                && name.ends_with(&self.config.marker_suffix))
+    }
+
+    /// Whether the content of `path` is in cold storage
+    pub fn is_offline(&self, path: &Path) -> bool {
+        self.marker_path(path).exists()
+    }
+
+    /// Request a recall of `path`, starting the recall command unless one is
+    /// already running for it
+    pub async fn recall(self: &Arc<Self>, path: &Path) -> RecallState {
+        let mut recalls = self.recalls.lock().await;
+        match recalls.get(path) {
+            Some(state @ RecallState::Recalling { .. }) => return state.clone(),
+            // Reported once, then retried by the next read
+            Some(RecallState::Failed { .. }) => return recalls.remove(path).unwrap_or(RecallState::Unavailable),
+            _ => {}
+        }
+
+        if self.config.recall_command.is_empty() {
+            return RecallState::Unavailable;
+        }
+
+        let state = RecallState::Recalling { started_at: Utc::now() };
+        recalls.insert(path.to_path_buf(), state.clone());
+        drop(recalls);
+
+        info!("Recalling offline file {}", path.display());
+        let hooks = Arc::clone(self);
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            if let Err(error) = hooks.run_recall_command(&path).await {
+                warn!("Recall of {} failed: {}", path.display(), error);
+                hooks.recalls.lock().await.insert(path, RecallState::Failed { error });
+            }
+        });
+
+        state
+    }
+
+    /// Forget the recall of a file that is online again
+    pub async fn recalled(&self, path: &Path) {
+        let mut recalls = self.recalls.lock().await;
+        if recalls.remove(path).is_some() {
+            info!("Offline file {} is back online", path.display());
+        }
+    }
+
+    async fn run_recall_command(&self, path: &Path) -> Result<(), String> {
+        let (program, args) = self.config.recall_command
+            .split_first()
+            .ok_or_else(|| "no recall command configured".to_string())?;
+
+        let output = Command::new(program)
+            .args(args)
+            .arg(path)
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(Duration::from_secs(self.config.recall_timeout_secs), output)
+            .await
+            .map_err(|_| format!("recall command timed out after {}s", self.config.recall_timeout_secs))?
+            .map_err(|e| format!("failed to run {}: {}", program, e))?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "recall command exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn hooks(recall_command: &[&str]) -> Arc<ArchiveHooks> {
+        Arc::new(ArchiveHooks::new(ArchiveConfig {
+            enabled: true,
+            recall_command: recall_command.iter().map(|s| s.to_string()).collect(),
+            ..ArchiveConfig::default()
+        }))
+    }
+
+    #[test]
+    fn test_markers() {
+        let hooks = hooks(&[]);
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("report.pdf");
+        std::fs::write(&file, b"stub").unwrap();
+        assert!(!hooks.is_offline(&file));
+
+        std::fs::write(hooks.marker_path(&file), b"").unwrap();
+        assert!(hooks.is_offline(&file));
+        assert!(hooks.is_marker(&temp_dir.path().join("report.pdf.offline")));
+        assert!(!hooks.is_marker(&file));
+        assert!(!hooks.is_marker(Path::new("/data/.offline")));
+    }
+
+    #[tokio::test]
+    async fn test_recall_states() {
+        let path = Path::new("/data/report.pdf");
+        assert_eq!(hooks(&[]).recall(path).await, RecallState::Unavailable);
+
+        let hooks = hooks(&["false"]);
+        assert!(matches!(hooks.recall(path).await, RecallState::Recalling { .. }));
+
+        // The failure is reported once, then the recall starts over
+        for _ in 0..50 {
+            if matches!(hooks.recalls.lock().await.get(path), Some(RecallState::Failed { .. })) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(matches!(hooks.recall(path).await, RecallState::Failed { .. }));
+        assert!(matches!(hooks.recall(path).await, RecallState::Recalling { .. }));
+    }
+}
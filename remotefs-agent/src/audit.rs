@@ -0,0 +1,336 @@
+//! Audit log of the requests the agent handles
+//!
+//! With `logging.enable_access_log`, every filesystem request the agent
+//! answers, refused ones included, is written as a line of JSON to
+//! `logging.access_log_file`: when it arrived, the client the relay named
+//! and the uid of a shared mount's caller, the operation and its paths, the
+//! file data read or written, whether it succeeded or the error code it
+//! failed with, and how long it took. Requests in a batch are recorded one
+//! by one.
+//!
+//! The file is rotated once it passes `logging.max_file_size` MB, keeping
+//! `logging.max_files` files in all as `<file>.1`, `<file>.2` and so on.
+//! Records are written on a thread of their own, so a slow disk does not
+//! hold up requests. With `logging.forward_access_log` they are also sent
+//! to the relay, which logs them under the agent's ID.
+
+use chrono::Utc;
+use remotefs_common::{
+    config::LoggingConfig,
+    error::Result,
+    protocol::{AuditRecord, Message},
+};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Instant;
+use tracing::warn;
+
+use crate::rate::request_cost;
+
+/// Who made a request, as far as the agent knows
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    /// Client the relay named for the request
+    pub client_id: Option<String>,
+    /// User a shared mount made the request for
+    pub uid: Option<u32>,
+}
+
+/// The audit log file and the thread writing it
+pub struct AuditLog {
+    /// Closed on drop, which ends the writer
+    records: Option<mpsc::Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+    forward: bool,
+}
+
+impl AuditLog {
+    /// Audit log described by `config`, or `None` if access logging is off
+    pub fn from_config(config: &LoggingConfig) -> Result<Option<Self>> {
+        if !config.enable_access_log {
+            return Ok(None);
+        }
+        let path = config.access_log_file.clone().unwrap_or_else(default_audit_path);
+        let max_bytes = (config.max_file_size as u64).saturating_mul(1024 * 1024);
+        Self::open(&path, max_bytes, config.max_files, config.forward_access_log).map(Some)
+    }
+
+    /// Append to the log at `path`, rotating it once it passes `max_bytes`
+    /// and keeping `max_files` files in all
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize, forward: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut writer = Writer {
+            file: append(path)?,
+            written: fs::metadata(path)?.len(),
+            path: path.to_path_buf(),
+            max_bytes: max_bytes.max(1),
+            max_files: max_files.max(1),
+        };
+
+        let (records, received) = mpsc::channel::<AuditRecord>();
+        let thread = std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for record in received {
+                    if let Err(e) = writer.write(&record) {
+                        warn!("Failed to write audit record to {}: {}", writer.path.display(), e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            records: Some(records),
+            writer: Some(thread),
+            forward,
+        })
+    }
+
+    /// Whether records also go to the relay
+    pub fn forwards(&self) -> bool {
+        self.forward
+    }
+
+    /// Queue `record` to be written
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(records) = &self.records {
+            let _ = records.send(record);
+        }
+    }
+}
+
+impl Drop for AuditLog {
+    /// Write the records still queued before the log closes
+    fn drop(&mut self) {
+        self.records.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// A request being handled, recorded once it is answered
+#[derive(Debug)]
+pub struct PendingAudit {
+    started: Instant,
+    record: AuditRecord,
+}
+
+impl PendingAudit {
+    /// Start recording `request` from `caller`; messages that are not
+    /// requests are not recorded, nor are batches, whose requests are
+    pub fn begin(request: &Message, caller: &Caller) -> Option<Self> {
+        if matches!(request, Message::Batch { .. }) {
+            return None;
+        }
+        let request_id = request.request_id()?;
+        Some(Self {
+            started: Instant::now(),
+            record: AuditRecord {
+                timestamp: Utc::now(),
+                request_id: Some(request_id),
+                client_id: caller.client_id.clone(),
+                uid: caller.uid,
+                operation: request.message_type().to_string(),
+                paths: request.request_paths().into_iter().map(str::to_string).collect(),
+                bytes: request_cost(request).map_or(0, |(_, bytes)| bytes),
+                success: true,
+                error: None,
+                latency_us: 0,
+            },
+        })
+    }
+
+    /// The record of the request, answered with `response`; requests
+    /// answered by a stream of messages have no response of their own
+    pub fn finish(self, response: Option<&Message>) -> AuditRecord {
+        let mut record = self.record;
+        record.latency_us = self.started.elapsed().as_micros().min(u64::MAX as u128) as u64;
+        match response {
+            Some(Message::Error { code, .. }) => {
+                record.success = false;
+                record.error = Some(code.clone());
+            }
+            Some(Message::ReadFileResponse { success, bytes_read, .. }) => {
+                record.success = *success;
+                record.bytes += bytes_read;
+            }
+            Some(Message::ChecksumResponse { success, length, .. }) => {
+                record.success = *success;
+                record.bytes += length;
+            }
+            Some(response) => record.success = succeeded(response),
+            None => {}
+        }
+        record
+    }
+}
+
+/// Whether `response` reports success; responses without a `success` flag
+/// report failures as `Error` messages
+fn succeeded(response: &Message) -> bool {
+    match response {
+        Message::WriteFileResponse { success, .. }
+        | Message::CreateFileResponse { success, .. }
+        | Message::DeleteFileResponse { success, .. }
+        | Message::TruncateFileResponse { success, .. }
+        | Message::LockFileResponse { success, .. }
+        | Message::UnlockFileResponse { success, .. }
+        | Message::TestLockResponse { success, .. }
+        | Message::OpenFileResponse { success, .. }
+        | Message::CloseFileResponse { success, .. }
+        | Message::ListDirectoryResponse { success, .. }
+        | Message::CreateDirectoryResponse { success, .. }
+        | Message::RemoveDirectoryResponse { success, .. }
+        | Message::GetMetadataResponse { success, .. }
+        | Message::SetMetadataResponse { success, .. }
+        | Message::GetXattrResponse { success, .. }
+        | Message::SetXattrResponse { success, .. }
+        | Message::ListXattrResponse { success, .. }
+        | Message::RemoveXattrResponse { success, .. }
+        | Message::RenameResponse { success, .. }
+        | Message::CreateSymlinkResponse { success, .. }
+        | Message::CreateHardLinkResponse { success, .. }
+        | Message::FileSignatureResponse { success, .. }
+        | Message::ReadSymlinkResponse { success, .. }
+        | Message::CopyFileResponse { success, .. }
+        | Message::GetSpaceInfoResponse { success, .. }
+        | Message::GetChangesResponse { success, .. }
+        | Message::WatchResponse { success, .. }
+        | Message::ReadBackupEntryResponse { success, .. }
+        | Message::TransactionResponse { success, .. }
+        | Message::RestoreFromTrashResponse { success, .. }
+        | Message::PurgeTrashResponse { success, .. } => *success,
+        Message::ListExportsResponse { error, .. }
+        | Message::SetMetadataTreeProgress { error, .. } => error.is_none(),
+        _ => true,
+    }
+}
+
+/// Writes records and rotates the file
+struct Writer {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl Writer {
+    fn write(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.written > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `<file>.N` to `<file>.N+1`, dropping the oldest, and start a
+    /// new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.max_files > 1 {
+            let _ = fs::remove_file(numbered(self.max_files - 1));
+            for n in (1..self.max_files - 1).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Audit log used when `logging.access_log_file` is not set
+pub fn default_audit_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join(".remotefs")
+        .join("access.jsonl")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::protocol::ErrorCode;
+    use uuid::Uuid;
+
+    fn read(path: &Path) -> Vec<AuditRecord> {
+        fs::read_to_string(path).unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_records() {
+        let caller = Caller { client_id: Some("laptop".to_string()), uid: Some(501) };
+        let write = Message::WriteFile {
+            request_id: Uuid::new_v4(),
+            path: "/srv/a".to_string(),
+            offset: 0,
+            data: vec![0; 10],
+            sync: false,
+        };
+        let record = PendingAudit::begin(&write, &caller).unwrap().finish(Some(&Message::Error {
+            request_id: write.request_id(),
+            code: ErrorCode::QuotaExceeded,
+            message: String::new(),
+            details: None,
+            errno: None,
+        }));
+        assert_eq!((record.operation.as_str(), record.paths.as_slice(), record.bytes), ("WriteFile", &["/srv/a".to_string()][..], 10));
+        assert_eq!((record.client_id.as_deref(), record.uid, record.success), (Some("laptop"), Some(501), false));
+        assert!(matches!(record.error, Some(ErrorCode::QuotaExceeded)));
+
+        let read = Message::ReadFile { request_id: Uuid::new_v4(), path: "/srv/a".to_string(), offset: 0, length: 100 };
+        let record = PendingAudit::begin(&read, &Caller::default()).unwrap().finish(Some(&Message::ReadFileResponse {
+            request_id: Uuid::new_v4(),
+            success: true,
+            data: Some(vec![0; 42]),
+            bytes_read: 42,
+            error: None,
+        }));
+        assert_eq!((record.bytes, record.success), (42, true));
+        assert!(PendingAudit::begin(&Message::Ping { timestamp: Utc::now() }, &Caller::default()).is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.jsonl");
+        let request = Message::DeleteFile { request_id: Uuid::new_v4(), path: "/srv/a".to_string() };
+        let record = || PendingAudit::begin(&request, &Caller::default()).unwrap().finish(None);
+        let size = serde_json::to_vec(&record()).unwrap().len() as u64 + 1;
+
+        // Two records fit in a file, and three files are kept
+        let log = AuditLog::open(&path, size * 5 / 2, 3, false).unwrap();
+        for _ in 0..7 {
+            log.record(record());
+        }
+        drop(log);
+        assert_eq!(read(&path).len(), 1);
+        assert_eq!(read(&dir.path().join("access.jsonl.1")).len(), 2);
+        assert_eq!(read(&dir.path().join("access.jsonl.2")).len(), 2);
+        assert!(!dir.path().join("access.jsonl.3").exists());
+    }
+}
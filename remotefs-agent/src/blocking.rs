@@ -0,0 +1,112 @@
+//! Blocking filesystem calls, kept off the async runtime
+//!
+//! `std::fs` calls block the thread they run on; made straight from a
+//! handler, a slow disk stalls every other request scheduled on the same
+//! runtime thread. Handlers pass them to [`BlockingPool::run`] instead,
+//! which runs them on tokio's blocking threads with at most
+//! `performance.worker_threads` in flight. Further calls wait for a free
+//! worker without holding a thread, so a burst of large reads queues up
+//! rather than growing the blocking thread pool to hundreds of threads.
+//!
+//! With `performance.async_io` off, calls run in place on the calling
+//! thread, as a single-threaded agent on a fast local disk may prefer.
+
+use remotefs_common::{config::PerformanceConfig, error::RemoteFsError};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Bounded set of workers for blocking filesystem calls
+#[derive(Debug)]
+pub struct BlockingPool {
+    /// One permit per worker; `None` runs calls in place
+    workers: Option<Arc<Semaphore>>,
+    size: usize,
+}
+
+impl BlockingPool {
+    /// Pool of `worker_threads` workers, or none with `async_io` off
+    pub fn new(config: &PerformanceConfig) -> Self {
+        let size = config.worker_threads.max(1);
+        Self {
+            workers: config.async_io.then(|| Arc::new(Semaphore::new(size))),
+            size,
+        }
+    }
+
+    /// Run `call` on a worker once one is free
+    pub async fn run<T, F>(&self, call: F) -> Result<T, RemoteFsError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let Some(workers) = &self.workers else {
+            return Ok(call());
+        };
+
+        // The semaphore is never closed
+        let permit = Arc::clone(workers).acquire_owned().await
+            .map_err(|e| RemoteFsError::Internal(format!("Blocking pool closed: {}", e)))?;
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            call()
+        })
+            .await
+            .map_err(|e| RemoteFsError::Internal(format!("Blocking task failed: {}", e)))
+    }
+
+    /// Number of workers
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Workers running a call right now
+    pub fn busy(&self) -> usize {
+        self.workers.as_ref().map_or(0, |workers| self.size - workers.available_permits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_worker_bound() {
+        let pool = Arc::new(BlockingPool::new(&PerformanceConfig {
+            worker_threads: 2,
+            ..PerformanceConfig::default()
+        }));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let calls: Vec<_> = (0..8).map(|_| {
+            let (pool, running, most) = (Arc::clone(&pool), Arc::clone(&running), Arc::clone(&most));
+            tokio::spawn(async move {
+                pool.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                }).await
+            })
+        }).collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.busy(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_in_place() {
+        let pool = BlockingPool::new(&PerformanceConfig {
+            async_io: false,
+            ..PerformanceConfig::default()
+        });
+        let caller = std::thread::current().id();
+        assert!(pool.run(move || std::thread::current().id() == caller).await.unwrap());
+        assert_eq!(pool.busy(), 0);
+    }
+}
@@ -1,7 +1,7 @@
 use std::path::{Path, PathBuf};
 use std::fs;
 use remotefs_common::{
-    config::{AgentConfig, AccessConfig, SecurityConfig, NetworkConfig, LoggingConfig, PerformanceConfig},
+    config::{AgentConfig, RelayMode, AccessConfig, UnmatchedUserPolicy, RuleEffect, SecurityConfig, NetworkConfig, LoggingConfig, CrashConfig, PerformanceConfig, JournalConfig, ArchiveConfig, TrashConfig, MirrorConfig, ResourceLimitsConfig, RateLimitConfig, RemoteExecConfig, IdMapConfig},
     error::{RemoteFsError, Result},
 };
 use dirs;
@@ -14,6 +14,8 @@ pub fn create_default_agent_config() -> AgentConfig {
     AgentConfig {
         agent_id: format!("agent-{}", uuid::Uuid::new_v4()),
         relay_url: "ws://localhost:8080/ws".to_string(),
+        relay_urls: vec![],
+        relay_mode: RelayMode::default(),
         access: AccessConfig {
             allowed_paths: vec![
                 home_dir.join("Documents").to_string_lossy().to_string(),
@@ -28,6 +30,7 @@ pub fn create_default_agent_config() -> AgentConfig {
             ],
             max_file_size: 100 * 1024 * 1024, // 100MB
             follow_symlinks: false,
+            deny_symlink_escapes: false,
             allowed_extensions: vec![],
             denied_extensions: vec![
                 "exe".to_string(),
@@ -35,6 +38,12 @@ pub fn create_default_agent_config() -> AgentConfig {
                 "cmd".to_string(),
                 "scr".to_string(),
             ],
+            user_rules: vec![],
+            unmatched_users: UnmatchedUserPolicy::Allow,
+            rules: vec![],
+            unmatched_rules: RuleEffect::Allow,
+            allowed_mode: 0o7777,
+            quotas: Vec::new(),
         },
         security: SecurityConfig {
             key_file: config_dir.join("agent.key"),
@@ -54,6 +63,8 @@ pub fn create_default_agent_config() -> AgentConfig {
             max_files: 5,
             enable_access_log: true,
             access_log_file: Some(config_dir.join("access.log")),
+            forward_access_log: false,
+            crash: CrashConfig::default(),
         },
         performance: PerformanceConfig {
             worker_threads: num_cpus::get(),
@@ -63,6 +74,15 @@ pub fn create_default_agent_config() -> AgentConfig {
             enable_prefetch: true,
             prefetch_window: 8,
         },
+        journal: JournalConfig::default(),
+        archive: ArchiveConfig::default(),
+        trash: TrashConfig::default(),
+        mirror: MirrorConfig::default(),
+        limits: ResourceLimitsConfig::default(),
+        rate_limits: RateLimitConfig::default(),
+        remote_exec: RemoteExecConfig::default(),
+        id_map: IdMapConfig::default(),
+        local_socket: None,
     }
 }
 
@@ -148,6 +168,14 @@ pub fn validate_config(config: &AgentConfig) -> Result<()> {
         ));
     }
     
+    if let Some(url) = config.relay_urls.iter().find(|url| !url.starts_with("ws://") && !url.starts_with("wss://")) {
+        return Err(RemoteFsError::Configuration(
+            format!("Relay URL {} must start with ws:// or wss://", url)
+        ));
+    }
+    
+    crate::ids::validate(&config.id_map)?;
+    
     // Validate access configuration
     if config.access.allowed_paths.is_empty() {
         return Err(RemoteFsError::Configuration(
@@ -172,6 +200,13 @@ pub fn validate_config(config: &AgentConfig) -> Result<()> {
         ));
     }
     
+    if config.access.allowed_mode > 0o7777 {
+        return Err(RemoteFsError::Configuration(format!(
+            "Allowed mode {:o} has bits beyond 7777",
+            config.access.allowed_mode
+        )));
+    }
+    
     // Validate performance settings
     if config.performance.worker_threads == 0 {
         return Err(RemoteFsError::Configuration(
@@ -185,6 +220,16 @@ pub fn validate_config(config: &AgentConfig) -> Result<()> {
         ));
     }
     
+    // Validate request size limits
+    let limits = &config.limits;
+    if limits.max_listing_entries == 0 || limits.max_page_entries == 0
+        || limits.max_path_length == 0 || limits.max_path_depth == 0
+    {
+        return Err(RemoteFsError::Configuration(
+            "Listing, page and path limits must be greater than 0".to_string()
+        ));
+    }
+    
     // Validate logging level
     let valid_levels = ["trace", "debug", "info", "warn", "error"];
     if !valid_levels.contains(&config.logging.level.as_str()) {
@@ -211,11 +256,26 @@ pub fn merge_configs(base: &AgentConfig, overlay: &AgentConfig) -> AgentConfig {
         } else {
             overlay.relay_url.clone()
         },
+        relay_urls: if overlay.relay_urls.is_empty() {
+            base.relay_urls.clone()
+        } else {
+            overlay.relay_urls.clone()
+        },
+        relay_mode: overlay.relay_mode,
         access: merge_access_configs(&base.access, &overlay.access),
         security: merge_security_configs(&base.security, &overlay.security),
         network: overlay.network.clone(),
         logging: merge_logging_configs(&base.logging, &overlay.logging),
         performance: merge_performance_configs(&base.performance, &overlay.performance),
+        journal: overlay.journal.clone(),
+        archive: overlay.archive.clone(),
+        trash: overlay.trash.clone(),
+        mirror: overlay.mirror.clone(),
+        limits: overlay.limits.clone(),
+        rate_limits: overlay.rate_limits.clone(),
+        remote_exec: overlay.remote_exec.clone(),
+        id_map: overlay.id_map.clone(),
+        local_socket: overlay.local_socket.clone(),
     }
 }
 
@@ -238,6 +298,7 @@ fn merge_access_configs(base: &AccessConfig, overlay: &AccessConfig) -> AccessCo
         },
         max_file_size: overlay.max_file_size,
         follow_symlinks: overlay.follow_symlinks,
+        deny_symlink_escapes: overlay.deny_symlink_escapes,
         allowed_extensions: if overlay.allowed_extensions.is_empty() {
             base.allowed_extensions.clone()
         } else {
@@ -248,6 +309,24 @@ fn merge_access_configs(base: &AccessConfig, overlay: &AccessConfig) -> AccessCo
         } else {
             overlay.denied_extensions.clone()
         },
+        user_rules: if overlay.user_rules.is_empty() {
+            base.user_rules.clone()
+        } else {
+            overlay.user_rules.clone()
+        },
+        unmatched_users: overlay.unmatched_users,
+        rules: if overlay.rules.is_empty() {
+            base.rules.clone()
+        } else {
+            overlay.rules.clone()
+        },
+        unmatched_rules: overlay.unmatched_rules,
+        allowed_mode: overlay.allowed_mode,
+        quotas: if overlay.quotas.is_empty() {
+            base.quotas.clone()
+        } else {
+            overlay.quotas.clone()
+        },
     }
 }
 
@@ -284,6 +363,11 @@ fn merge_logging_configs(base: &LoggingConfig, overlay: &LoggingConfig) -> Loggi
         max_files: overlay.max_files,
         enable_access_log: overlay.enable_access_log,
         access_log_file: overlay.access_log_file.clone().or_else(|| base.access_log_file.clone()),
+        forward_access_log: overlay.forward_access_log,
+        crash: CrashConfig {
+            directory: overlay.crash.directory.clone().or_else(|| base.crash.directory.clone()),
+            ..overlay.crash.clone()
+        },
     }
 }
 
@@ -322,7 +406,10 @@ mod tests {
 
     #[test]
     fn test_validate_config() {
+        let temp_dir = TempDir::new().unwrap();
         let mut config = create_default_agent_config();
+        // The default allowed paths live under $HOME and may not exist here
+        config.access.allowed_paths = vec![temp_dir.path().to_string_lossy().to_string()];
         
         // Valid config should pass
         assert!(validate_config(&config).is_ok());
@@ -339,6 +426,12 @@ mod tests {
         
         config.relay_url = "ws://localhost:8080/ws".to_string();
         
+        // So should an invalid failover relay
+        config.relay_urls = vec!["localhost:8081".to_string()];
+        assert!(validate_config(&config).is_err());
+        
+        config.relay_urls.clear();
+        
         // Empty allowed paths should fail
         config.access.allowed_paths.clear();
         assert!(validate_config(&config).is_err());
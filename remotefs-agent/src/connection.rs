@@ -1,17 +1,21 @@
 use remotefs_common::{
-    protocol::{Message, NodeType, generate_request_id},
+    compression::{self, AutoDisable, CompressionStats},
+    token::{HeldToken, TokenClaims, TokenVerifier},
+    protocol::{AgentEvent, Capability, ErrorCode, Message, NodeType, PathReadiness, RequestId, MAX_BATCH_OPERATIONS},
     config::AgentConfig,
     error::{RemoteFsError, Result},
 };
 use crate::{
+    audit::{AuditLog, Caller, PendingAudit},
     filesystem::FilesystemHandler,
-    server::{ConnectionStatistics, PerformanceStatistics},
+    server::ConnectionStatistics,
 };
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tokio::sync::{broadcast, RwLock, mpsc};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message as WsMessage};
 use futures::{SinkExt, StreamExt};
-use tracing::{info, warn, error, debug};
+use tracing::{field, info, instrument, warn, error, debug, Span};
 use url::Url;
 
 /// Manages the WebSocket connection to the relay server
@@ -19,62 +23,109 @@ pub struct ConnectionManager {
     config: AgentConfig,
     agent_id: String,
     public_key: Vec<u8>,
-    relay_url: Url,
+    /// Relays to connect to, in the order they are tried
+    relay_urls: Vec<Url>,
+    /// Index in `relay_urls` of the relay in use or tried next
+    current_relay: AtomicUsize,
     stats: Arc<RwLock<ConnectionStatistics>>,
     start_time: std::time::SystemTime,
+    /// Ticket from the relay for skipping authentication on reconnect
+    resumption_ticket: RwLock<Option<Vec<u8>>>,
+    /// Latest self-test of the configured paths, reported to the relay
+    path_readiness: RwLock<Vec<PathReadiness>>,
+    /// Outgoing messages of the current connection, if connected
+    outgoing: RwLock<Option<mpsc::UnboundedSender<Message>>>,
+    /// Whether the relay of the current connection reads compressed messages
+    compress: AtomicBool,
+    /// Session token from the relay, timed from when it arrived
+    session_token: RwLock<Option<HeldToken>>,
+    /// Checks session tokens with the key the relay published
+    token_verifier: RwLock<Option<TokenVerifier>>,
+    /// Records the requests handled, if access logging is on
+    audit: Option<Arc<AuditLog>>,
 }
 
 impl ConnectionManager {
-    /// Create a new connection manager
+    /// Create a new connection manager for `relay_url`, failing over to
+    /// `relay_urls` in turn
     pub fn new(
         config: &AgentConfig,
         agent_id: String,
         public_key: Vec<u8>,
     ) -> Result<Self> {
-        let relay_url = Url::parse(&config.relay_url)
-            .map_err(|e| RemoteFsError::Configuration(format!("Invalid relay URL: {}", e)))?;
+        let relay_urls = relay_urls(config)?;
         
         let stats = Arc::new(RwLock::new(ConnectionStatistics {
             messages_sent: 0,
             messages_received: 0,
             reconnection_count: 0,
             last_heartbeat: None,
+            compression: CompressionStats::default(),
         }));
         
         Ok(Self {
             config: config.clone(),
             agent_id,
             public_key,
-            relay_url,
+            relay_urls,
+            current_relay: AtomicUsize::new(0),
             stats,
             start_time: std::time::SystemTime::now(),
+            resumption_ticket: RwLock::new(None),
+            path_readiness: RwLock::new(Vec::new()),
+            outgoing: RwLock::new(None),
+            compress: AtomicBool::new(false),
+            session_token: RwLock::new(None),
+            token_verifier: RwLock::new(None),
+            audit: None,
         })
     }
     
+    /// Record every request handled in `audit`
+    pub fn with_audit(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+    
     /// Connect to relay and serve filesystem operations
+    ///
+    /// A relay that cannot be reached, or stops answering, is left for the
+    /// next one in the list straight away. Once every relay has failed in a
+    /// row the agent backs off before going round again, and gives up after
+    /// `max_reconnect_attempts` rounds.
     pub async fn connect_and_serve(
         &self,
         filesystem_handler: Arc<FilesystemHandler>,
         mut shutdown_rx: broadcast::Receiver<()>,
     ) -> Result<()> {
-        let mut reconnect_attempts = 0;
+        let mut failures = 0;
         let max_attempts = self.config.network.max_reconnect_attempts;
         let base_delay = self.config.network.reconnect_backoff_base;
         
         loop {
-            match self.try_connect_and_serve(Arc::clone(&filesystem_handler), &mut shutdown_rx).await {
+            let mut served = false;
+            match self.try_connect_and_serve(Arc::clone(&filesystem_handler), &mut shutdown_rx, &mut served).await {
                 Ok(_) => {
                     info!("Connection closed normally");
                     break;
                 }
                 Err(e) => {
-                    error!("Connection error: {}", e);
+                    error!("Connection error on relay {}: {}", self.relay_url(), e);
+                    
+                    // A connection that was up starts the count afresh, from
+                    // the first relay
+                    if served {
+                        failures = 0;
+                        self.current_relay.store(0, Ordering::Relaxed);
+                    } else {
+                        self.current_relay.fetch_add(1, Ordering::Relaxed);
+                    }
+                    failures += 1;
                     
-                    reconnect_attempts += 1;
-                    if reconnect_attempts >= max_attempts {
+                    let Some(delay) = reconnect_delay(failures, self.relay_urls.len() as u32, max_attempts, base_delay) else {
                         error!("Max reconnection attempts ({}) reached, giving up", max_attempts);
                         return Err(e);
-                    }
+                    };
                     
                     // Update reconnection stats
                     {
@@ -82,9 +133,10 @@ impl ConnectionManager {
                         stats.reconnection_count += 1;
                     }
                     
-                    // Exponential backoff
-                    let delay = std::cmp::min(base_delay * (2_u64.pow(reconnect_attempts - 1)), 300);
-                    warn!("Reconnecting in {} seconds (attempt {}/{})", delay, reconnect_attempts, max_attempts);
+                    if delay == 0 {
+                        continue;
+                    }
+                    warn!("Reconnecting in {} seconds (attempt {}/{})", delay, failures / self.relay_urls.len() as u32, max_attempts);
                     
                     // Check for shutdown during delay
                     tokio::select! {
@@ -101,16 +153,26 @@ impl ConnectionManager {
         Ok(())
     }
     
+    /// The relay in use, or tried next
+    pub fn relay_url(&self) -> &Url {
+        &self.relay_urls[self.current_relay.load(Ordering::Relaxed) % self.relay_urls.len()]
+    }
+    
     /// Attempt a single connection and serve until disconnected
+    ///
+    /// Returns `Ok` only on shutdown; `served` is set once the relay has
+    /// accepted the agent.
     async fn try_connect_and_serve(
         &self,
         filesystem_handler: Arc<FilesystemHandler>,
         shutdown_rx: &mut broadcast::Receiver<()>,
+        served: &mut bool,
     ) -> Result<()> {
-        info!("Connecting to relay server: {}", self.relay_url);
+        let relay_url = self.relay_url().clone();
+        info!("Connecting to relay server: {}", relay_url);
         
         // Connect to WebSocket
-        let (ws_stream, _) = connect_async(&self.relay_url).await
+        let (ws_stream, _) = connect_async(&relay_url).await
             .map_err(|e| RemoteFsError::Connection(format!("Failed to connect to relay: {}", e)))?;
         
         info!("Connected to relay server");
@@ -120,66 +182,60 @@ impl ConnectionManager {
         // Create channels for internal communication
         let (message_tx, mut message_rx) = mpsc::unbounded_channel::<Message>();
         
-        // Send authentication message
-        let auth_message = Message::AuthRequest {
-            node_id: self.agent_id.clone(),
-            node_type: NodeType::Agent,
-            public_key: self.public_key.clone(),
-            capabilities: vec!["filesystem".to_string(), "read".to_string(), "write".to_string()],
-        };
-        
-        let auth_json = serde_json::to_string(&auth_message)
-            .map_err(|e| RemoteFsError::Protocol(format!("Failed to serialize auth message: {}", e)))?;
-        
-        ws_sender.send(WsMessage::Text(auth_json)).await
-            .map_err(|e| RemoteFsError::Network(format!("Failed to send auth message: {}", e)))?;
-        
-        // Update stats
-        {
-            let mut stats = self.stats.write().await;
-            stats.messages_sent += 1;
-        }
-        
-        // Wait for authentication response
-        if let Some(msg) = ws_receiver.next().await {
-            let msg = msg.map_err(|e| RemoteFsError::Network(format!("WebSocket error: {}", e)))?;
-            
-            match msg {
-                WsMessage::Text(text) => {
-                    let response: Message = serde_json::from_str(&text)
-                        .map_err(|e| RemoteFsError::Protocol(format!("Invalid auth response: {}", e)))?;
-                    
-                    if let Message::AuthResponse { success, error, .. } = response {
-                        if success {
-                            info!("Authentication successful");
-                        } else {
-                            let error_msg = error.unwrap_or_else(|| "Unknown auth error".to_string());
-                            return Err(RemoteFsError::Authentication(format!("Auth failed: {}", error_msg)));
-                        }
-                    } else {
-                        return Err(RemoteFsError::Protocol("Expected auth response".to_string()));
-                    }
+        // Resume the previous session if the relay still honours its ticket
+        let ticket = self.resumption_ticket.write().await.take();
+        let mut authenticated = false;
+        if let Some(ticket) = ticket {
+            let resume_message = Message::ResumeSession {
+                node_id: self.agent_id.clone(),
+                ticket,
+            };
+            match self.exchange_auth(&mut ws_sender, &mut ws_receiver, resume_message).await {
+                Ok(()) => {
+                    info!("Resumed session with relay");
+                    authenticated = true;
                 }
-                _ => return Err(RemoteFsError::Protocol("Expected text auth response".to_string())),
-            }
-            
-            // Update stats
-            {
-                let mut stats = self.stats.write().await;
-                stats.messages_received += 1;
+                Err(RemoteFsError::Authentication(e)) => {
+                    debug!("Session resumption refused, authenticating: {}", e);
+                }
+                Err(e) => return Err(e),
             }
-        } else {
-            return Err(RemoteFsError::Connection("Connection closed during auth".to_string()));
         }
         
-        // Start message sender task
-        let sender_handle = {
+        if !authenticated {
+            // Send authentication message
+            let auth_message = Message::AuthRequest {
+                node_id: self.agent_id.clone(),
+                node_type: NodeType::Agent,
+                public_key: self.public_key.clone(),
+                capabilities: self.capabilities(),
+            };
+            self.exchange_auth(&mut ws_sender, &mut ws_receiver, auth_message).await?;
+            info!("Authentication successful");
+        }
+        *served = true;
+        
+        // Start message sender task; messages go in binary frames,
+        // compressed where worthwhile, if the relay reads compressed messages
+        let mut sender_handle = {
             let mut ws_sender = ws_sender;
             let stats = Arc::clone(&self.stats);
+            let compress = self.compress.load(Ordering::Relaxed);
             tokio::spawn(async move {
+                let mut heuristic = AutoDisable::default();
                 while let Some(message) = message_rx.recv().await {
-                    let result = match serde_json::to_string(&message) {
-                        Ok(json) => ws_sender.send(WsMessage::Text(json)).await,
+                    let frame = if compress {
+                        let mut stats = stats.write().await;
+                        compression::encode_message(&message, &mut heuristic, &mut stats.compression)
+                            .map(WsMessage::Binary)
+                            .map_err(|e| e.to_string())
+                    } else {
+                        serde_json::to_string(&message)
+                            .map(WsMessage::Text)
+                            .map_err(|e| e.to_string())
+                    };
+                    let result = match frame {
+                        Ok(frame) => ws_sender.send(frame).await,
                         Err(e) => {
                             error!("Failed to serialize message: {}", e);
                             continue;
@@ -195,10 +251,15 @@ impl ConnectionManager {
                     let mut stats = stats.write().await;
                     stats.messages_sent += 1;
                 }
+                let _ = ws_sender.close().await;
                 debug!("Message sender task ended");
             })
         };
         
+        // Tell the relay which configured paths can be served
+        let _ = message_tx.send(self.health_message().await);
+        *self.outgoing.write().await = Some(message_tx.clone());
+        
         // Start heartbeat task
         let heartbeat_handle = {
             let message_tx = message_tx.clone();
@@ -229,11 +290,18 @@ impl ConnectionManager {
             })
         };
         
-        // Message handling loop
+        // Message handling loop; the relay answers every heartbeat, so one
+        // that stays silent for three of them is taken to be gone
+        let heartbeat_interval = tokio::time::Duration::from_secs(self.config.network.heartbeat_interval.max(1));
+        let mut health_check = tokio::time::interval(heartbeat_interval);
+        let mut last_received = tokio::time::Instant::now();
+        let mut shutting_down = false;
+        let mut lost = None;
         loop {
             tokio::select! {
                 // Handle incoming messages
                 msg = ws_receiver.next() => {
+                    last_received = tokio::time::Instant::now();
                     match msg {
                         Some(Ok(WsMessage::Text(text))) => {
                             // Update stats
@@ -264,8 +332,8 @@ impl ConnectionManager {
                                 stats.messages_received += 1;
                             }
                             
-                            match bincode::deserialize::<Message>(&data) {
-                                Ok(message) => {
+                            match compression::decode_message(&data) {
+                                Ok((message, _)) => {
                                     if let Err(e) = self.handle_message(
                                         message,
                                         Arc::clone(&filesystem_handler),
@@ -284,48 +352,383 @@ impl ConnectionManager {
                         }
                         Some(Ok(WsMessage::Close(_))) => {
                             info!("Relay closed connection");
+                            lost = Some("Relay closed connection".to_string());
                             break;
                         }
                         Some(Err(e)) => {
                             error!("WebSocket error: {}", e);
+                            lost = Some(format!("WebSocket error: {}", e));
                             break;
                         }
                         None => {
                             warn!("WebSocket stream ended");
+                            lost = Some("WebSocket stream ended".to_string());
                             break;
                         }
                         _ => {} // Ignore other message types
                     }
                 }
                 
+                // Check the relay is still answering
+                _ = health_check.tick() => {
+                    if last_received.elapsed() > heartbeat_interval * 3 {
+                        warn!("Relay {} stopped answering heartbeats", relay_url);
+                        lost = Some("Relay stopped answering heartbeats".to_string());
+                        break;
+                    }
+                }
+                
                 // Handle shutdown signal
                 _ = shutdown_rx.recv() => {
                     info!("Shutdown signal received");
+                    shutting_down = true;
                     break;
                 }
             }
         }
         
-        // Clean up tasks
-        sender_handle.abort();
+        // Clean up tasks; the relay ended the clients' watches, locks and
+        // open files with the connection
+        *self.outgoing.write().await = None;
+        if shutting_down {
+            let flushed = filesystem_handler.sync_open_files().await;
+            debug!("Flushed {} files open for writing", flushed);
+        }
+        filesystem_handler.stop_watches();
+        filesystem_handler.release_all_locks();
+        filesystem_handler.close_all_files();
         heartbeat_handle.abort();
+        if shutting_down {
+            // Tell the relay the agent is leaving before the connection goes away
+            let _ = message_tx.send(Message::ConnectionClose { reason: "Agent shutting down".to_string() });
+            drop(message_tx);
+            let _ = tokio::time::timeout(tokio::time::Duration::from_secs(1), &mut sender_handle).await;
+        }
+        sender_handle.abort();
         
-        Ok(())
+        match lost {
+            Some(reason) => Err(RemoteFsError::Connection(reason)),
+            None => Ok(()),
+        }
+    }
+    
+    /// Announce `event` to every client the relay has seen use this agent
+    ///
+    /// Returns false if the agent is not connected to the relay.
+    pub async fn broadcast(&self, event: AgentEvent) -> bool {
+        match self.outgoing.read().await.as_ref() {
+            Some(outgoing) => outgoing.send(self.broadcast_message(event)).is_ok(),
+            None => false,
+        }
+    }
+    
+    fn broadcast_message(&self, event: AgentEvent) -> Message {
+        Message::Broadcast {
+            agent_id: self.agent_id.clone(),
+            event,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+    
+    /// Record a new self-test of the configured paths and report it to the
+    /// relay if connected
+    pub async fn report_path_readiness(&self, paths: Vec<PathReadiness>) {
+        *self.path_readiness.write().await = paths;
+        if let Some(outgoing) = self.outgoing.read().await.as_ref() {
+            let _ = outgoing.send(self.health_message().await);
+        }
+    }
+    
+    async fn health_message(&self) -> Message {
+        Message::AgentHealth {
+            paths: self.path_readiness.read().await.clone(),
+            timestamp: chrono::Utc::now(),
+        }
+    }
+    
+    /// What this agent announces to the relay
+    fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = vec![
+            Capability::Filesystem,
+            Capability::Read,
+            Capability::Write,
+            Capability::Streaming,
+            Capability::Xattr,
+            Capability::Watch,
+            Capability::Transactions,
+            Capability::Exports,
+            Capability::BatchCreate,
+            Capability::Batch,
+            Capability::Checksum,
+            Capability::Locks,
+            Capability::CreateMode,
+            Capability::Walk,
+            Capability::Search,
+            Capability::ListingCursors,
+            Capability::OpenFiles,
+            Capability::SpaceInfo,
+            Capability::ClientRules,
+            Capability::ChunkedTransfer,
+            Capability::HardLinks,
+            Capability::MetadataTree,
+            Capability::DeltaTransfer,
+            Capability::ReadSymlink,
+            Capability::CopyFile,
+            Capability::RenameNoReplace,
+        ];
+        if cfg!(feature = "remote-exec") && self.config.remote_exec.enabled {
+            capabilities.push(Capability::RemoteExec);
+        }
+        if self.config.network.compression {
+            capabilities.push(Capability::Compression);
+        }
+        if self.config.trash.enabled {
+            capabilities.push(Capability::Trash);
+        }
+        capabilities
+    }
+    
+    /// Send an authentication or resumption request and wait for the relay's
+    /// answer, keeping the resumption ticket it hands out
+    async fn exchange_auth<S, R>(&self, ws_sender: &mut S, ws_receiver: &mut R, request: Message) -> Result<()>
+    where
+        S: futures::Sink<WsMessage, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+        R: futures::Stream<Item = std::result::Result<WsMessage, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let auth_json = serde_json::to_string(&request)
+            .map_err(|e| RemoteFsError::Protocol(format!("Failed to serialize auth message: {}", e)))?;
+        
+        ws_sender.send(WsMessage::Text(auth_json)).await
+            .map_err(|e| RemoteFsError::Network(format!("Failed to send auth message: {}", e)))?;
+        
+        // Update stats
+        {
+            let mut stats = self.stats.write().await;
+            stats.messages_sent += 1;
+        }
+        
+        // Wait for authentication response
+        let Some(msg) = ws_receiver.next().await else {
+            return Err(RemoteFsError::Connection("Connection closed during auth".to_string()));
+        };
+        let msg = msg.map_err(|e| RemoteFsError::Network(format!("WebSocket error: {}", e)))?;
+        
+        // Update stats
+        {
+            let mut stats = self.stats.write().await;
+            stats.messages_received += 1;
+        }
+        
+        let WsMessage::Text(text) = msg else {
+            return Err(RemoteFsError::Protocol("Expected text auth response".to_string()));
+        };
+        let response: Message = serde_json::from_str(&text)
+            .map_err(|e| RemoteFsError::Protocol(format!("Invalid auth response: {}", e)))?;
+        
+        match response {
+            Message::AuthResponse { success: true, session_token, resumption_ticket, relay_info, .. } => {
+                *self.resumption_ticket.write().await = resumption_ticket;
+                *self.session_token.write().await = session_token.map(HeldToken::new);
+                *self.token_verifier.write().await = relay_info.as_ref()
+                    .and_then(|info| info.token_key.clone())
+                    .map(TokenVerifier::new);
+                let relay_compresses = relay_info.is_some_and(|info| {
+                    info.capabilities.iter().any(|capability| capability == Capability::Compression.as_str())
+                });
+                self.compress.store(self.config.network.compression && relay_compresses, Ordering::Relaxed);
+                Ok(())
+            }
+            Message::AuthResponse { error, .. } => {
+                let error_msg = error.unwrap_or_else(|| "Unknown auth error".to_string());
+                Err(RemoteFsError::Authentication(format!("Auth failed: {}", error_msg)))
+            }
+            _ => Err(RemoteFsError::Protocol("Expected auth response".to_string())),
+        }
     }
     
     /// Handle an incoming message from the relay
+    ///
+    /// Everything logged while handling a request names it, so a failure a
+    /// client reports can be found by its request ID.
+    #[instrument(name = "request", skip_all, fields(id = field::Empty))]
     async fn handle_message(
         &self,
         message: Message,
         filesystem_handler: Arc<FilesystemHandler>,
         response_tx: &mpsc::UnboundedSender<Message>,
     ) -> Result<()> {
+        if let Some(request_id) = message.request_id() {
+            Span::current().record("id", field::display(request_id));
+        }
         debug!("Handling message: {:?}", message.message_type());
         
+        // Requests the relay names a client for are checked against its rules
+        let mut caller = Caller::default();
+        let (message, filesystem_handler) = match message {
+            // The relay only names clients for their own requests, so lock
+            // releases and other notices of its own are never wrapped
+            Message::FromClient { request, .. } if !request.is_client_request() && !matches!(*request, Message::AsUser { .. }) => {
+                debug!("Refusing {} sent from a client", request.message_type());
+                return response_tx.send(refuse_wrapped(&request, "FromClient"))
+                    .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()));
+            }
+            Message::FromClient { client_id, request } => {
+                debug!("Request from client {}", client_id);
+                caller.client_id = Some(client_id.clone());
+                (*request, Arc::new(filesystem_handler.for_client(client_id)))
+            }
+            message => (message, filesystem_handler),
+        };
+        
+        // Requests from shared mounts are checked against the caller's rules
+        let (message, filesystem_handler) = match message {
+            // Only what a client could send itself is made on a user's behalf
+            Message::AsUser { request, .. } if !request.is_client_request() => {
+                debug!("Refusing {} sent on behalf of a user", request.message_type());
+                return response_tx.send(refuse_wrapped(&request, "AsUser"))
+                    .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()));
+            }
+            Message::AsUser { identity, request } => {
+                debug!("Request on behalf of uid {} gid {}", identity.uid, identity.gid);
+                caller.uid = Some(identity.uid);
+                (*request, Arc::new(filesystem_handler.for_caller(identity)))
+            }
+            message => (message, filesystem_handler),
+        };
+        let audit = self.begin_audit(&message, &caller);
+        
+        // Read-only and denied paths are enforced for every mutating request,
+        // including those without a handler of their own
+        if let Some(refusal) = filesystem_handler.check_request(&message).await {
+            self.finish_audit(audit, Some(&refusal), response_tx);
+            response_tx.send(refusal)
+                .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()))?;
+            return Ok(());
+        }
+        
+        let _permit = match filesystem_handler.admit(&message).await {
+            Ok(permit) => permit,
+            Err(refusal) => {
+                self.finish_audit(audit, Some(&refusal), response_tx);
+                response_tx.send(*refusal)
+                    .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()))?;
+                return Ok(());
+            }
+        };
         let response = match message {
+            Message::Batch { request_id, operations } => {
+                Some(self.handle_batch(request_id, operations, &filesystem_handler, &caller, response_tx).await)
+            }
+            message => self.respond(message, &filesystem_handler, response_tx).await,
+        };
+        self.finish_audit(audit, response.as_ref(), response_tx);
+        
+        // Send response if we have one
+        if let Some(response) = response {
+            response_tx.send(response)
+                .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()))?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Answer each request of a batch in order
+    ///
+    /// Every request is checked as if sent on its own, so a refused or
+    /// failed request does not keep the rest from being handled.
+    async fn handle_batch(
+        &self,
+        request_id: RequestId,
+        operations: Vec<Message>,
+        filesystem_handler: &Arc<FilesystemHandler>,
+        caller: &Caller,
+        response_tx: &mpsc::UnboundedSender<Message>,
+    ) -> Message {
+        if operations.len() > MAX_BATCH_OPERATIONS {
+            return Message::Error {
+                request_id: Some(request_id),
+                code: ErrorCode::MessageTooLarge,
+                message: format!(
+                    "Batch has {} requests; at most {} are allowed",
+                    operations.len(), MAX_BATCH_OPERATIONS
+                ),
+                details: None,
+                errno: None,
+            };
+        }
+        
+        let mut responses = Vec::with_capacity(operations.len());
+        for operation in operations {
+            if !operation.is_batchable() {
+                responses.push(Message::Error {
+                    request_id: operation.request_id(),
+                    code: ErrorCode::InvalidMessage,
+                    message: format!("{} cannot be sent in a batch", operation.message_type()),
+                    details: None,
+                    errno: None,
+                });
+                continue;
+            }
+            let audit = self.begin_audit(&operation, caller);
+            if let Some(refusal) = filesystem_handler.check_request(&operation).await {
+                self.finish_audit(audit, Some(&refusal), response_tx);
+                responses.push(refusal);
+                continue;
+            }
+            let _permit = match filesystem_handler.admit(&operation).await {
+                Ok(permit) => permit,
+                Err(refusal) => {
+                    self.finish_audit(audit, Some(&refusal), response_tx);
+                    responses.push(*refusal);
+                    continue;
+                }
+            };
+            
+            let operation_id = operation.request_id();
+            let response = self.respond(operation, filesystem_handler, response_tx).await;
+            let response = response.unwrap_or_else(|| Message::Error {
+                request_id: operation_id,
+                code: ErrorCode::InternalError,
+                message: "Request was not answered".to_string(),
+                details: None,
+                errno: None,
+            });
+            self.finish_audit(audit, Some(&response), response_tx);
+            responses.push(response);
+        }
+        
+        Message::BatchResponse { request_id, responses }
+    }
+    
+    /// Start the audit record of `request`, if access logging is on
+    fn begin_audit(&self, request: &Message, caller: &Caller) -> Option<PendingAudit> {
+        self.audit.as_ref().and_then(|_| PendingAudit::begin(request, caller))
+    }
+    
+    /// Write the audit record of a request answered with `response`, and
+    /// send it to the relay as well if the agent forwards its access log
+    fn finish_audit(&self, pending: Option<PendingAudit>, response: Option<&Message>, response_tx: &mpsc::UnboundedSender<Message>) {
+        let (Some(audit), Some(pending)) = (&self.audit, pending) else {
+            return;
+        };
+        let record = pending.finish(response);
+        if audit.forwards() {
+            let _ = response_tx.send(Message::Audit { record: record.clone() });
+        }
+        audit.record(record);
+    }
+    
+    /// Handle a single request, returning its response if it has one
+    async fn respond(
+        &self,
+        message: Message,
+        filesystem_handler: &Arc<FilesystemHandler>,
+        response_tx: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        match message {
             Message::Pong { .. } => {
                 debug!("Received pong from relay");
-                return Ok(());
+                None
             }
             
             // Filesystem operations
@@ -333,18 +736,128 @@ impl ConnectionManager {
                 filesystem_handler.handle_read_file(request_id, path, Some(offset), Some(length as u64)).await
             }
             
+            Message::ComputeChecksum { request_id, path, algorithm, offset, length } => {
+                filesystem_handler.handle_compute_checksum(request_id, path, algorithm, offset, length).await
+            }
+            
+            Message::GetFileSignature { request_id, path, block_size } => {
+                filesystem_handler.handle_get_file_signature(request_id, path, block_size).await
+            }
+            
+            Message::WriteDelta { request_id, path, block_size, ops, checksum, sync } => {
+                filesystem_handler.handle_write_delta(request_id, path, block_size, ops, checksum, sync).await
+            }
+            
+            Message::LockFile { request_id, path, lock } => {
+                filesystem_handler.handle_lock_file(request_id, path, lock).await
+            }
+            
+            Message::UnlockFile { request_id, path, owner, start, length } => {
+                filesystem_handler.handle_unlock_file(request_id, path, owner, start, length).await
+            }
+            
+            Message::TestLock { request_id, path, lock } => {
+                filesystem_handler.handle_test_lock(request_id, path, lock).await
+            }
+            
+            // Sent by the relay only, never inside an envelope
+            Message::ReleaseLocks { session } => {
+                filesystem_handler.handle_release_locks(&session);
+                None
+            }
+            
+            Message::OpenFile { request_id, path, flags, mode, session } => {
+                filesystem_handler.handle_open_file(request_id, path, flags, mode, session).await
+            }
+            
+            Message::ReadHandle { request_id, handle, offset, length } => {
+                filesystem_handler.handle_read_handle(request_id, handle, offset, length).await
+            }
+            
+            Message::WriteHandle { request_id, handle, offset, data, sync } => {
+                filesystem_handler.handle_write_handle(request_id, handle, offset, data, sync).await
+            }
+            
+            Message::CloseFile { request_id, handle } => {
+                filesystem_handler.handle_close_file(request_id, handle).await
+            }
+            
             Message::WriteFile { request_id, path, data, offset, sync } => {
                 filesystem_handler.handle_write_file(request_id, path, data, Some(offset), sync).await
             }
             
+            Message::CreateFile { request_id, path, mode, exclusive } => {
+                filesystem_handler.handle_create_file(request_id, path, mode, exclusive).await
+            }
+            
+            Message::ReadFileStream { request_id, path, offset, length, chunk_size } => {
+                filesystem_handler.handle_read_file_stream(request_id, path, offset, length, chunk_size, response_tx).await
+            }
+            
+            Message::ReadFileAck { stream_id, .. } => {
+                filesystem_handler.handle_read_file_ack(stream_id)
+            }
+            
+            Message::WriteFileChunk { request_id, path, sequence, offset, data, last, sync } => {
+                filesystem_handler.handle_write_file_chunk(request_id, path, sequence, offset, data, last, sync).await
+            }
+            
+            Message::TruncateFile { request_id, path, size } => {
+                filesystem_handler.handle_truncate_file(request_id, path, size).await
+            }
+            
             Message::ListDirectory { request_id, path } => {
                 filesystem_handler.handle_list_directory(request_id, path).await
             }
             
+            Message::ListDirectoryPaged { request_id, path, page_size, after, max_entries } => {
+                filesystem_handler.handle_list_directory_paged(request_id, path, page_size, after, max_entries, response_tx).await
+            }
+            
+            Message::WalkDirectory { request_id, path, max_depth, follow_symlinks } => {
+                filesystem_handler.handle_walk_directory(request_id, path, max_depth, follow_symlinks, response_tx).await
+            }
+            
+            Message::SearchFiles { request_id, root, name_glob, content_regex, max_results } => {
+                filesystem_handler.handle_search_files(request_id, root, name_glob, content_regex, max_results, response_tx).await
+            }
+            
             Message::GetMetadata { request_id, path, follow_symlinks } => {
                 filesystem_handler.handle_get_metadata(request_id, path, follow_symlinks).await
             }
             
+            Message::PathExists { request_id, path } => {
+                filesystem_handler.handle_path_exists(request_id, path).await
+            }
+            
+            Message::SetMetadata { request_id, path, update } => {
+                filesystem_handler.handle_set_metadata(request_id, path, update).await
+            }
+            
+            Message::SetMetadataTree { request_id, path, update } => {
+                filesystem_handler.handle_set_metadata_tree(request_id, path, update, response_tx).await
+            }
+            
+            Message::CancelSetMetadataTree { request_id } => {
+                filesystem_handler.handle_cancel_set_metadata_tree(request_id)
+            }
+            
+            Message::GetXattr { request_id, path, name } => {
+                filesystem_handler.handle_get_xattr(request_id, path, name).await
+            }
+            
+            Message::SetXattr { request_id, path, name, value, mode } => {
+                filesystem_handler.handle_set_xattr(request_id, path, name, value, mode).await
+            }
+            
+            Message::ListXattr { request_id, path } => {
+                filesystem_handler.handle_list_xattr(request_id, path).await
+            }
+            
+            Message::RemoveXattr { request_id, path, name } => {
+                filesystem_handler.handle_remove_xattr(request_id, path, name).await
+            }
+            
             Message::CreateDirectory { request_id, path, mode } => {
                 filesystem_handler.handle_create_directory(request_id, path, mode).await
             }
@@ -357,24 +870,99 @@ impl ConnectionManager {
                 filesystem_handler.handle_delete_directory(request_id, path, recursive).await
             }
             
-            Message::Rename { request_id, from_path, to_path } => {
-                filesystem_handler.handle_move_file(request_id, from_path, to_path).await
+            Message::Rename { request_id, from_path, to_path, no_replace } => {
+                filesystem_handler.handle_move_file(request_id, from_path, to_path, no_replace).await
+            }
+            
+            Message::CreateSymlink { request_id, link_path, target_path } => {
+                filesystem_handler.handle_create_symlink(request_id, link_path, target_path).await
+            }
+            
+            Message::CreateHardLink { request_id, existing_path, link_path } => {
+                filesystem_handler.handle_create_hard_link(request_id, existing_path, link_path).await
+            }
+            
+            Message::ReadSymlink { request_id, path } => {
+                filesystem_handler.handle_read_symlink(request_id, path).await
+            }
+            
+            Message::CopyFile { request_id, source, destination, offset, length } => {
+                filesystem_handler.handle_copy_file(request_id, source, destination, offset, length).await
+            }
+            
+            Message::GetChanges { request_id, since, limit } => {
+                filesystem_handler.handle_get_changes(request_id, since, limit).await
+            }
+            
+            Message::Watch { request_id, path, recursive } => {
+                filesystem_handler.handle_watch(request_id, path, recursive, response_tx).await
+            }
+            
+            Message::Unwatch { request_id } => {
+                filesystem_handler.handle_unwatch(request_id).await
+            }
+            
+            Message::ReadFileAsOf { request_id, path, offset, length, as_of } => {
+                filesystem_handler.handle_read_file_as_of(request_id, path, offset, length, as_of).await
+            }
+            
+            Message::ListExports { request_id, .. } => {
+                filesystem_handler.handle_list_exports(request_id).await
+            }
+            
+            Message::GetSpaceInfo { request_id, path } => {
+                filesystem_handler.handle_get_space_info(request_id, path).await
+            }
+            
+            Message::RestoreFromTrash { request_id, path } => {
+                filesystem_handler.handle_restore_from_trash(request_id, path).await
+            }
+            
+            Message::PurgeTrash { request_id, path } => {
+                filesystem_handler.handle_purge_trash(request_id, path).await
+            }
+            
+            Message::ReadBackupEntry { request_id, path } => {
+                filesystem_handler.handle_read_backup_entry(request_id, path).await
+            }
+            
+            Message::Transaction { request_id, operations } => {
+                filesystem_handler.handle_transaction(request_id, operations).await
+            }
+            
+            Message::BatchCreateFiles { request_id, files, overwrite } => {
+                filesystem_handler.handle_batch_create_files(request_id, files, overwrite).await
+            }
+            
+            Message::ExtendedOperation { request_id, name, arguments, working_dir } => {
+                filesystem_handler.handle_extended_operation(request_id, name, arguments, working_dir, response_tx).await
+            }
+            
+            // Sent by the relay only; clients cannot wrap it in `AsUser`
+            Message::MirrorStatus { primary, promoted, writes_allowed, .. } => {
+                filesystem_handler.handle_mirror_status(&primary, promoted, writes_allowed).await;
+                None
+            }
+            
+            // Requests this agent cannot serve are refused rather than left
+            // for the client to time out on
+            _ if !message.is_response() && message.request_id().is_some() => {
+                debug!("Refusing unsupported request: {:?}", message.message_type());
+                Some(Message::Error {
+                    request_id: message.request_id(),
+                    code: ErrorCode::NotImplemented,
+                    message: format!("{} is not supported by this agent", message.message_type()),
+                    details: None,
+                    errno: None,
+                })
             }
             
             // Other messages that don't require responses
             _ => {
                 debug!("Ignoring message type: {:?}", message.message_type());
-                return Ok(());
+                None
             }
-        };
-        
-        // Send response if we have one
-        if let Some(response) = response {
-            response_tx.send(response)
-                .map_err(|_| RemoteFsError::Internal("Failed to send response".to_string()))?;
         }
-        
-        Ok(())
     }
     
     /// Check if connected to relay
@@ -395,8 +983,328 @@ impl ConnectionManager {
             .as_secs()
     }
     
+    /// Check a session token issued by the relay without asking it, and
+    /// return what it says about its holder
+    pub async fn verify_token(&self, token: &str) -> Result<TokenClaims> {
+        match self.token_verifier.read().await.as_ref() {
+            Some(verifier) => verifier.verify(token),
+            None => Err(RemoteFsError::Authentication("The relay published no token key".to_string())),
+        }
+    }
+    
+    /// Time left on this agent's own session token, if the relay said
+    pub async fn session_token_remaining(&self) -> Option<std::time::Duration> {
+        self.session_token.read().await.as_ref()?.remaining()
+    }
+    
     /// Get connection statistics
     pub async fn get_statistics(&self) -> ConnectionStatistics {
         self.stats.read().await.clone()
     }
+    
+    /// Relay connection and message counts for crash reports, without
+    /// waiting for locks
+    pub fn snapshot(&self) -> String {
+        let connected = match self.outgoing.try_read() {
+            Ok(outgoing) => if outgoing.is_some() { "connected" } else { "disconnected" },
+            Err(_) => "connection state locked",
+        };
+        let counts = match self.stats.try_read() {
+            Ok(stats) => format!(
+                "{} messages sent, {} received, {} reconnections",
+                stats.messages_sent, stats.messages_received, stats.reconnection_count
+            ),
+            Err(_) => "statistics locked".to_string(),
+        };
+        let token = match self.session_token.try_read() {
+            Ok(token) => match token.as_ref().and_then(HeldToken::remaining) {
+                Some(remaining) => format!("session token valid for {}s", remaining.as_secs()),
+                None => "no session token lifetime".to_string(),
+            },
+            Err(_) => "session token locked".to_string(),
+        };
+        format!("{} to {}; {}; {}", connected, self.relay_url(), counts, token)
+    }
+}
+
+/// Relays named by `config`: `relay_url` followed by `relay_urls`, each once
+pub fn relay_urls(config: &AgentConfig) -> Result<Vec<Url>> {
+    let mut urls: Vec<Url> = Vec::new();
+    for url in std::iter::once(&config.relay_url).chain(&config.relay_urls) {
+        let url = Url::parse(url)
+            .map_err(|e| RemoteFsError::Configuration(format!("Invalid relay URL {}: {}", url, e)))?;
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    Ok(urls)
+}
+
+/// Seconds to wait before the next connection attempt after `failures`
+/// failed in a row across `relays` relays, or `None` once every relay has
+/// failed `max_attempts` times
+///
+/// The next relay is tried at once; the backoff doubles with each round
+/// through the list, up to five minutes.
+fn reconnect_delay(failures: u32, relays: u32, max_attempts: u32, base_delay: u64) -> Option<u64> {
+    let relays = relays.max(1);
+    if !failures.is_multiple_of(relays) {
+        return Some(0);
+    }
+    let round = failures / relays;
+    if round >= max_attempts {
+        return None;
+    }
+    Some(base_delay.saturating_mul(2_u64.saturating_pow(round - 1)).min(300))
+}
+
+/// Error answering `request`, which may not be sent inside `envelope`
+fn refuse_wrapped(request: &Message, envelope: &str) -> Message {
+    Message::Error {
+        request_id: request.request_id(),
+        code: ErrorCode::InvalidMessage,
+        message: format!("{} cannot be sent in {}", request.message_type(), envelope),
+        details: None,
+        errno: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessControl;
+    use crate::mirror::MirrorState;
+    use remotefs_common::config_utils;
+    use remotefs_common::protocol::{generate_request_id, CallerIdentity, FileLock, LockType, NodeType, RelayInfo};
+    use remotefs_common::token::TokenSigner;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_batch_answers_each_request() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), b"hello").unwrap();
+        let mut config = config_utils::create_default_agent_config();
+        config.access.allowed_paths = vec![dir.path().to_string_lossy().to_string()];
+        config.access.denied_paths = vec![];
+        
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let handler = Arc::new(FilesystemHandler::new(Arc::new(AccessControl::new(&config.access)), &config.performance));
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let request_id = generate_request_id();
+        let operations = vec![
+            Message::GetMetadata { request_id: generate_request_id(), path: path("a.txt"), follow_symlinks: false },
+            Message::GetMetadata { request_id: generate_request_id(), path: path("missing"), follow_symlinks: false },
+            Message::ReleaseLocks { session: "client".to_string() },
+            Message::CreateDirectory { request_id: generate_request_id(), path: path("new"), mode: 0o755 },
+            Message::GetMetadata { request_id: generate_request_id(), path: "/etc/passwd".to_string(), follow_symlinks: false },
+        ];
+        let ids: Vec<_> = operations.iter().map(Message::request_id).collect();
+        
+        manager.handle_message(Message::Batch { request_id, operations }, handler, &response_tx).await.unwrap();
+        let Some(Message::BatchResponse { request_id: answered, responses }) = response_rx.recv().await else {
+            panic!("Expected a batch response");
+        };
+        assert_eq!(answered, request_id);
+        assert_eq!(responses.iter().map(Message::request_id).collect::<Vec<_>>(), ids);
+        
+        // Each request succeeds or fails on its own, in order
+        assert!(matches!(&responses[0], Message::GetMetadataResponse { metadata: Some(metadata), .. } if metadata.size == 5));
+        assert!(matches!(&responses[1], Message::Error { code: ErrorCode::FileNotFound, .. }));
+        assert!(matches!(&responses[2], Message::Error { code: ErrorCode::InvalidMessage, .. }));
+        assert!(matches!(&responses[3], Message::CreateDirectoryResponse { success: true, .. }));
+        assert!(!matches!(&responses[4], Message::GetMetadataResponse { success: true, .. }));
+        assert!(dir.path().join("new").is_dir());
+        assert!(response_rx.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_refuses_unsupported_requests() {
+        let config = config_utils::create_default_agent_config();
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let handler = Arc::new(FilesystemHandler::new(Arc::new(AccessControl::new(&config.access)), &config.performance));
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        
+        // A request without a handler is answered, not left to time out
+        let request_id = generate_request_id();
+        manager.handle_message(Message::ListAgents { request_id }, handler.clone(), &response_tx).await.unwrap();
+        let response = response_rx.try_recv();
+        assert!(matches!(response, Ok(Message::Error { code: ErrorCode::NotImplemented, request_id: Some(id), .. }) if id == request_id), "{:?}", response);
+        
+        // Responses and notifications still get no answer
+        let response = Message::PathExistsResponse { request_id, exists: true, error: None };
+        manager.handle_message(response, handler, &response_tx).await.unwrap();
+        assert!(response_rx.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_as_user_wraps_only_client_requests() {
+        let config = config_utils::create_default_agent_config();
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let handler = Arc::new(FilesystemHandler::new(Arc::new(AccessControl::new(&config.access)), &config.performance));
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let as_user = |request| Message::AsUser {
+            identity: CallerIdentity { uid: 1000, gid: 1000, groups: vec![] },
+            request: Box::new(request),
+        };
+        
+        let request_id = generate_request_id();
+        let exists = Message::PathExists { request_id, path: "/tmp".to_string() };
+        manager.handle_message(as_user(exists.clone()), handler.clone(), &response_tx).await.unwrap();
+        let response = response_rx.try_recv();
+        assert!(matches!(response, Ok(Message::PathExistsResponse { .. })), "{:?}", response);
+        
+        // Envelopes, responses and the relay's own messages are refused
+        let refused = [
+            as_user(exists),
+            Message::FromClient { client_id: "laptop".to_string(), request: Box::new(Message::ListDirectory { request_id, path: "/tmp".to_string() }) },
+            Message::PathExistsResponse { request_id, exists: true, error: None },
+            Message::Pong { timestamp: chrono::Utc::now(), original_timestamp: chrono::Utc::now() },
+        ];
+        for request in refused {
+            let type_name = request.message_type();
+            manager.handle_message(as_user(request), handler.clone(), &response_tx).await.unwrap();
+            let response = response_rx.try_recv();
+            assert!(matches!(response, Ok(Message::Error { code: ErrorCode::InvalidMessage, .. })), "{}: {:?}", type_name, response);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_mirror_status_only_from_relay() {
+        let config = config_utils::create_default_agent_config();
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let mirror = Arc::new(MirrorState::new());
+        let handler = Arc::new(FilesystemHandler::new(Arc::new(AccessControl::new(&config.access)), &config.performance)
+            .with_mirror(Arc::clone(&mirror)));
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let status = Message::MirrorStatus {
+            primary: "primary".to_string(),
+            mirror: "agent".to_string(),
+            promoted: true,
+            writes_allowed: true,
+            timestamp: chrono::Utc::now(),
+        };
+        
+        // A client cannot promote the mirror by wrapping the relay's notice
+        let wrapped = Message::AsUser {
+            identity: CallerIdentity { uid: 1000, gid: 1000, groups: vec![] },
+            request: Box::new(status.clone()),
+        };
+        manager.handle_message(wrapped, handler.clone(), &response_tx).await.unwrap();
+        assert!(matches!(response_rx.try_recv(), Ok(Message::Error { code: ErrorCode::InvalidMessage, .. })));
+        assert!(!mirror.is_promoted());
+        
+        manager.handle_message(status, handler, &response_tx).await.unwrap();
+        assert!(mirror.is_promoted() && mirror.allows_writes());
+        assert!(response_rx.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_release_locks_only_from_relay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.sqlite");
+        std::fs::write(&path, b"data").unwrap();
+        let mut config = config_utils::create_default_agent_config();
+        config.access.allowed_paths = vec![dir.path().to_string_lossy().to_string()];
+        config.access.denied_paths = vec![];
+        
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let handler = Arc::new(FilesystemHandler::new(Arc::new(AccessControl::new(&config.access)), &config.performance));
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        
+        let mut lock = FileLock::new(LockType::Write, 0, 0, 7);
+        lock.owner.session = "client-1".to_string();
+        let request = Message::LockFile { request_id: generate_request_id(), path: path.to_string_lossy().to_string(), lock };
+        manager.handle_message(request, handler.clone(), &response_tx).await.unwrap();
+        assert!(matches!(response_rx.try_recv(), Ok(Message::LockFileResponse { success: true, .. })));
+        assert_eq!(handler.lock_count(), 1);
+        
+        // Another client cannot have the locks released by wrapping the
+        // relay's message
+        let release = Message::ReleaseLocks { session: "client-1".to_string() };
+        let wrapped = [
+            Message::AsUser {
+                identity: CallerIdentity { uid: 1000, gid: 1000, groups: vec![] },
+                request: Box::new(release.clone()),
+            },
+            Message::FromClient { client_id: "client-2".to_string(), request: Box::new(release.clone()) },
+        ];
+        for message in wrapped {
+            manager.handle_message(message, handler.clone(), &response_tx).await.unwrap();
+            assert!(matches!(response_rx.try_recv(), Ok(Message::Error { code: ErrorCode::InvalidMessage, .. })));
+            assert_eq!(handler.lock_count(), 1);
+        }
+        
+        manager.handle_message(release, handler.clone(), &response_tx).await.unwrap();
+        assert_eq!(handler.lock_count(), 0);
+        assert!(response_rx.try_recv().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_verifies_tokens_with_relay_key() {
+        let config = config_utils::create_default_agent_config();
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        let signer = TokenSigner::generate().unwrap();
+        let claims = |node_id: &str| TokenClaims {
+            token_id: Uuid::new_v4(),
+            relay_id: "relay".to_string(),
+            node_id: node_id.to_string(),
+            node_type: NodeType::Client,
+            scope: vec![Capability::Read],
+            issued_at: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+            ttl_secs: 600,
+        };
+        
+        let peer_token = signer.sign(&claims("client-peer")).unwrap();
+        assert!(manager.verify_token(&peer_token).await.is_err());
+        
+        let response = Message::AuthResponse {
+            success: true,
+            session_token: Some(signer.sign(&claims("agent")).unwrap()),
+            relay_info: Some(RelayInfo {
+                relay_id: "relay".to_string(),
+                capabilities: Vec::new(),
+                max_message_size: 1024,
+                heartbeat_interval: 30,
+                token_key: Some(signer.public_key()),
+            }),
+            error: None,
+            resumption_ticket: None,
+        };
+        let mut sink = futures::sink::drain().sink_map_err(|never| match never {});
+        let mut stream = futures::stream::iter(vec![Ok(WsMessage::Text(serde_json::to_string(&response).unwrap()))]);
+        manager.exchange_auth(&mut sink, &mut stream, Message::GetRelayDirectory).await.unwrap();
+        
+        // Tokens the relay issued to other nodes check out offline
+        let verified = manager.verify_token(&peer_token).await.unwrap();
+        assert_eq!(verified.node_id, "client-peer");
+        assert!(verified.allows(&Capability::Read));
+        let other_relay = TokenSigner::generate().unwrap().sign(&claims("client-peer")).unwrap();
+        assert!(manager.verify_token(&other_relay).await.is_err());
+        
+        let remaining = manager.session_token_remaining().await.unwrap();
+        assert!(remaining > std::time::Duration::from_secs(590));
+    }
+    
+    #[test]
+    fn test_relay_failover_order() {
+        let mut config = config_utils::create_default_agent_config();
+        config.relay_url = "ws://a:8080/ws".to_string();
+        config.relay_urls = vec!["ws://b:8080/ws".to_string(), "ws://a:8080/ws".to_string()];
+        let urls: Vec<_> = relay_urls(&config).unwrap().iter().map(Url::to_string).collect();
+        assert_eq!(urls, vec!["ws://a:8080/ws", "ws://b:8080/ws"]);
+        
+        // The next relay is tried at once, and each round through the list
+        // backs off twice as long
+        let delays: Vec<_> = (1..=7).map(|failures| reconnect_delay(failures, 2, 3, 5)).collect();
+        assert_eq!(delays, vec![Some(0), Some(5), Some(0), Some(10), Some(0), None, Some(0)]);
+        assert_eq!(reconnect_delay(1, 1, 5, 200), Some(200));
+        assert_eq!(reconnect_delay(2, 1, 5, 200), Some(300));
+        
+        let manager = ConnectionManager::new(&config, "agent".to_string(), Vec::new()).unwrap();
+        assert_eq!(manager.relay_url().as_str(), "ws://a:8080/ws");
+        manager.current_relay.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(manager.relay_url().as_str(), "ws://b:8080/ws");
+    }
 }
@@ -0,0 +1,233 @@
+//! Whitelisted commands run next to the data
+//!
+//! Some jobs, like `git fetch` in an exported repository, would otherwise
+//! mean round-tripping gigabytes through the relay just to run one command.
+//! Clients can instead ask the agent to run a command from its configured
+//! whitelist by name. Commands run without a shell; client arguments are
+//! only appended when the command allows it. Output is streamed back in
+//! chunks as it is produced, followed by a final message with the exit code.
+
+use remotefs_common::{
+    config::{ExecCommandConfig, RemoteExecConfig},
+    protocol::{Message, OutputStream},
+};
+use std::{path::Path, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    process::Command,
+    sync::mpsc,
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Largest output chunk sent in one message
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The whitelist of commands clients may run
+#[derive(Debug)]
+pub struct CommandRunner {
+    config: RemoteExecConfig,
+}
+
+impl CommandRunner {
+    pub fn new(config: RemoteExecConfig) -> Self {
+        Self { config }
+    }
+
+    /// Runner for `config`, or `None` if remote execution is disabled
+    pub fn from_config(config: &RemoteExecConfig) -> Option<Self> {
+        config.enabled.then(|| Self::new(config.clone()))
+    }
+
+    /// The whitelisted command called `name`
+    pub fn command(&self, name: &str) -> Option<&ExecCommandConfig> {
+        self.config.commands.iter().find(|command| command.name == name)
+    }
+}
+
+/// Run `command` in `working_dir`, sending its output to `output` and
+/// returning the final message
+pub async fn run(
+    request_id: Uuid,
+    command: &ExecCommandConfig,
+    arguments: &[String],
+    working_dir: &Path,
+    output: &mpsc::UnboundedSender<Message>,
+) -> Message {
+    let finished = |sequence: u32, exit_code: Option<i32>, error: Option<String>| Message::ExtendedOutput {
+        request_id,
+        sequence,
+        stream: OutputStream::Stdout,
+        data: Vec::new(),
+        last: true,
+        exit_code,
+        error,
+    };
+
+    let Some((program, fixed)) = command.command.split_first() else {
+        return finished(0, None, Some(format!("Command {} has no program configured", command.name)));
+    };
+
+    info!("Running {} in {} for request {}", command.name, working_dir.display(), request_id);
+    let spawned = Command::new(program)
+        .args(fixed)
+        .args(arguments)
+        .current_dir(working_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => return finished(0, None, Some(format!("Failed to run {}: {}", program, e))),
+    };
+
+    let (chunks_tx, mut chunks_rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(read_chunks(stdout, OutputStream::Stdout, chunks_tx.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(read_chunks(stderr, OutputStream::Stderr, chunks_tx));
+    }
+
+    let max_output = command.max_output_mb.saturating_mul(1024 * 1024);
+    let mut sent = 0u64;
+    let mut sequence = 0u32;
+    let forward = async {
+        while let Some((stream, mut data)) = chunks_rx.recv().await {
+            // Keep draining past the limit so the command is not blocked on a full pipe
+            let remaining = max_output.saturating_sub(sent) as usize;
+            if remaining == 0 {
+                continue;
+            }
+            data.truncate(remaining);
+            sent += data.len() as u64;
+
+            let chunk = Message::ExtendedOutput {
+                request_id,
+                sequence,
+                stream,
+                data,
+                last: false,
+                exit_code: None,
+                error: None,
+            };
+            sequence += 1;
+            if output.send(chunk).is_err() {
+                break;
+            }
+        }
+        child.wait().await
+    };
+
+    let timeout = Duration::from_secs(command.timeout_secs);
+    let status = tokio::time::timeout(timeout, forward).await;
+    let truncated = (sent >= max_output).then(|| format!("Output truncated after {} bytes", max_output));
+    match status {
+        Ok(Ok(status)) => {
+            info!("{} for request {} exited with {}", command.name, request_id, status);
+            finished(sequence, status.code(), truncated)
+        }
+        Ok(Err(e)) => finished(sequence, None, Some(format!("Failed to wait for {}: {}", program, e))),
+        Err(_) => {
+            // Dropping the child kills it
+            warn!("{} for request {} timed out after {}s", command.name, request_id, command.timeout_secs);
+            finished(sequence, None, Some(format!("Command timed out after {}s", command.timeout_secs)))
+        }
+    }
+}
+
+async fn read_chunks(
+    mut reader: impl AsyncRead + Unpin,
+    stream: OutputStream,
+    chunks: mpsc::UnboundedSender<(OutputStream, Vec<u8>)>,
+) {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => {
+                if chunks.send((stream, buf[..read].to_vec())).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn command(name: &str, command: &[&str]) -> ExecCommandConfig {
+        ExecCommandConfig {
+            name: name.to_string(),
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            working_dir: None,
+            allow_arguments: true,
+            timeout_secs: 10,
+            max_output_mb: 1,
+        }
+    }
+
+    async fn collect(command: &ExecCommandConfig, arguments: &[String], dir: &Path) -> (Vec<Message>, Message) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let last = run(Uuid::new_v4(), command, arguments, dir, &tx).await;
+        drop(tx);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        (chunks, last)
+    }
+
+    #[tokio::test]
+    async fn test_output_is_streamed() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join("marker"), b"").unwrap();
+        let ls = command("ls", &["ls"]);
+
+        let (chunks, last) = collect(&ls, &[], temp_dir.path()).await;
+        let stdout: Vec<u8> = chunks.iter()
+            .flat_map(|chunk| match chunk {
+                Message::ExtendedOutput { stream: OutputStream::Stdout, data, .. } => data.clone(),
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(stdout, b"marker\n");
+        assert!(matches!(last, Message::ExtendedOutput { last: true, exit_code: Some(0), error: None, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_arguments_are_not_interpreted() {
+        let temp_dir = TempDir::new().unwrap();
+        let ls = command("ls", &["ls"]);
+
+        let (chunks, last) = collect(&ls, &["; touch pwned".to_string()], temp_dir.path()).await;
+        assert!(chunks.iter().any(|chunk| matches!(chunk, Message::ExtendedOutput { stream: OutputStream::Stderr, .. })));
+        assert!(matches!(last, Message::ExtendedOutput { exit_code: Some(code), .. } if code != 0));
+        assert!(!temp_dir.path().join("pwned").exists());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_command() {
+        let temp_dir = TempDir::new().unwrap();
+        let sleep = ExecCommandConfig { timeout_secs: 0, ..command("sleep", &["sleep", "5"]) };
+
+        let (_, last) = collect(&sleep, &[], temp_dir.path()).await;
+        assert!(matches!(last, Message::ExtendedOutput { exit_code: None, error: Some(_), .. }));
+    }
+
+    #[test]
+    fn test_whitelist() {
+        let runner = CommandRunner::from_config(&RemoteExecConfig {
+            enabled: true,
+            commands: vec![command("fetch", &["git", "fetch"])],
+        }).unwrap();
+        assert!(runner.command("fetch").is_some());
+        assert!(runner.command("rm").is_none());
+        assert!(CommandRunner::from_config(&RemoteExecConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,114 @@
+//! Configured paths presented to clients as named exports
+//!
+//! Clients should not need to know where an agent keeps its data. Each
+//! allowed and read-only path is listed under the last component of its
+//! path, with a numeric suffix when two paths end the same way, along with
+//! the space on the filesystem holding it.
+
+use remotefs_common::{config::AccessConfig, protocol::{ExportInfo, SpaceInfo}};
+use std::{collections::HashSet, ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+/// Exports for every allowed and read-only path in `config`, in the same
+/// order as the self-test probes them, each named after the last component
+/// of its path and with the size and free space of the filesystem holding
+/// it, where that can be read
+pub fn list_exports(config: &AccessConfig) -> Vec<ExportInfo> {
+    let writable = config.allowed_paths.iter()
+        .filter(|path| !config.read_only_paths.contains(path))
+        .map(|path| (path, false));
+    let read_only = config.read_only_paths.iter().map(|path| (path, true));
+
+    let mut taken = HashSet::new();
+    writable.chain(read_only)
+        .map(|(path, read_only)| {
+            let space = space(Path::new(path)).ok();
+            ExportInfo {
+                name: export_name(path, &mut taken),
+                path: path.clone(),
+                read_only,
+                total_space: space.map(|space| space.total_space),
+                available_space: space.map(|space| space.available_space),
+            }
+        })
+        .collect()
+}
+
+/// Name for `path` that is not in `taken`, which it is added to
+fn export_name(path: &str, taken: &mut HashSet<String>) -> String {
+    let base = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "root".to_string());
+
+    let mut name = base.clone();
+    let mut suffix = 1;
+    while !taken.insert(name.clone()) {
+        suffix += 1;
+        name = format!("{}-{}", base, suffix);
+    }
+    name
+}
+
+/// Size and free space of the filesystem holding `path`
+pub fn space(path: &Path) -> std::io::Result<SpaceInfo> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is NUL-terminated and `stat` is only read after
+    // statvfs reported success
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)] // the field types differ by platform
+    let (blocks, free, available, block_size) =
+        (stat.f_blocks as u64, stat.f_bfree as u64, stat.f_bavail as u64, stat.f_frsize as u64);
+    // Filesystems that create inodes as needed report none
+    #[allow(clippy::unnecessary_cast)]
+    let files = (stat.f_files != 0).then_some((stat.f_files as u64, stat.f_favail as u64));
+    Ok(SpaceInfo {
+        total_space: blocks * block_size,
+        available_space: available * block_size,
+        used_space: blocks.saturating_sub(free) * block_size,
+        total_files: files.map(|(total, _)| total),
+        available_files: files.map(|(_, available)| available),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::config_utils;
+
+    #[test]
+    fn test_export_names() {
+        let mut config = config_utils::create_default_agent_config().access;
+        config.allowed_paths = vec![
+            "/srv/projects".to_string(),
+            "/home/alice/projects".to_string(),
+            "/".to_string(),
+            "/srv/media".to_string(),
+        ];
+        config.read_only_paths = vec!["/srv/media".to_string()];
+
+        let exports = list_exports(&config);
+        let names: Vec<(&str, bool)> = exports.iter().map(|export| (export.name.as_str(), export.read_only)).collect();
+        assert_eq!(names, vec![("projects", false), ("projects-2", false), ("root", false), ("media", true)]);
+
+        assert!(exports[2].total_space.is_some());
+        assert!(exports[2].available_space <= exports[2].total_space);
+    }
+
+    #[test]
+    fn test_space() {
+        let space = space(Path::new("/")).unwrap();
+        assert!(space.total_space > 0);
+        assert!(space.available_space <= space.total_space);
+        assert!(space.used_space <= space.total_space);
+
+        let missing = super::space(Path::new("/nonexistent/remotefs")).unwrap_err();
+        assert_eq!(missing.raw_os_error(), Some(libc::ENOENT));
+    }
+}
@@ -1,25 +1,66 @@
 use remotefs_common::{
-    protocol::{Message, FileMetadata, DirEntry},
+    checksum::{Checksum, Hasher},
+    delta::{self, DeltaOp, FileSignature},
+    protocol::{Message, ChecksumAlgorithm, FileLock, LockOwner, LockType, OpenFlags, FileMetadata, DirEntry, SearchMatch, MetadataUpdate, XattrSetMode, CallerIdentity, ChangeKind, ErrorCode, BackupEntry, TreeProgress, TransactionOp, NewFile, BatchFailure, OutputStream, MAX_BATCH_FILES, MAX_STREAM_CHUNK},
     error::RemoteFsError,
     config::{PerformanceConfig},
 };
 use crate::{
-    access::AccessControl,
-    server::{FilesystemStatistics, PerformanceStatistics},
+    access::{self, AccessControl},
+    archive::{ArchiveHooks, RecallState},
+    blocking::BlockingPool,
+    exports,
+    handles::{HandleTable, OpenHandle, MAX_HANDLES_PER_SESSION},
+    ids::IdMap,
+    jobs::{Job, JobTable},
+    journal::ChangeJournal,
+    limits::{over_limit, Exhausted, Operation, OperationPermit, ResourceLimits, ResourcePermit},
+    locks::LockTable,
+    mirror::MirrorState,
+    prefetch::{Prefetch, Prefetcher, ReadAhead, Version},
+    quota::{tree_size, QuotaChange, QuotaCharge, QuotaTable},
+    rate::{request_cost, RateLimiter},
+    search::{SearchQuery, MAX_SEARCH_RESULTS, SEARCH_PAGE_MATCHES, SEARCH_PARALLELISM},
+    streams::{StreamTable, StreamWindow, STREAM_ACK_TIMEOUT},
+    transaction::{Transaction, MAX_TRANSACTION_OPERATIONS},
+    trash::{Trash, TrashEntry},
+    watch::Watcher,
+    xattr,
+    server::{FilesystemStatistics, PerformanceStatistics, ResourceStatistics},
 };
 use std::{
-    collections::HashMap,
-    path::PathBuf,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH, Duration},
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{SystemTime, Duration},
     io::{Read, Write, Seek, SeekFrom},
     fs::{self, File, OpenOptions},
-    os::unix::fs::PermissionsExt,
+    os::unix::{ffi::OsStrExt, fs::{FileExt, MetadataExt, OpenOptionsExt, PermissionsExt}},
+    ffi::{CString, OsString},
 };
-use tokio::sync::RwLock;
-use tracing::{debug};
+use tokio::sync::{mpsc, RwLock};
+use futures::stream::{FuturesOrdered, StreamExt};
+#[cfg(feature = "remote-exec")]
+use {crate::exec::CommandRunner, remotefs_common::config::ExecCommandConfig};
+use tracing::{debug, warn, Instrument};
 use uuid::Uuid;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
+
+/// Most changes returned for one `GetChanges` request
+const MAX_CHANGES_PER_REQUEST: usize = 1000;
+
+/// Bytes read at a time when computing a checksum
+const CHECKSUM_BUFFER_SIZE: u64 = 1024 * 1024;
+
+/// Entries per page of a `WalkDirectory` stream
+const WALK_PAGE_ENTRIES: usize = 1000;
+
+/// Entries a walk reads from a directory at a time, so each open directory
+/// holds only this many besides its descriptor
+const WALK_READ_AHEAD: usize = 64;
+
+/// How often a `SetMetadataTree` reports its progress
+const TREE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Handles filesystem operations with access control and performance monitoring
 pub struct FilesystemHandler {
@@ -27,7 +68,35 @@ pub struct FilesystemHandler {
     stats: Arc<RwLock<FilesystemStatistics>>,
     performance_stats: Arc<RwLock<PerformanceStats>>,
     active_operations: Arc<RwLock<HashMap<Uuid, OperationInfo>>>,
+    #[allow(dead_code)]
     performance_config: PerformanceConfig,
+    /// Workers running blocking filesystem calls, shared by every handler
+    io: Arc<BlockingPool>,
+    /// Read-ahead budget of open files; `None` if prefetching is disabled
+    prefetcher: Option<Arc<Prefetcher>>,
+    journal: Option<Arc<ChangeJournal>>,
+    watcher: Arc<Watcher>,
+    locks: Arc<LockTable>,
+    handles: Arc<HandleTable>,
+    /// Windows of the files being streamed to readers
+    streams: Arc<StreamTable>,
+    /// Cancellation flags of the running `SetMetadataTree` requests
+    jobs: Arc<JobTable>,
+    archive: Option<Arc<ArchiveHooks>>,
+    mirror: Option<Arc<MirrorState>>,
+    limits: Option<Arc<ResourceLimits>>,
+    rate: Option<Arc<RateLimiter>>,
+    quotas: Option<Arc<QuotaTable>>,
+    /// Where deletes move entries; `None` if they unlink immediately
+    trash: Option<Arc<Trash>>,
+    /// Translation of owners and groups between clients and the host
+    ids: Arc<IdMap>,
+    /// Client the relay named for the requests of this handler
+    client_id: Option<String>,
+    /// Set on shutdown, after which new requests are refused
+    draining: Arc<AtomicBool>,
+    #[cfg(feature = "remote-exec")]
+    exec: Option<Arc<CommandRunner>>,
 }
 
 /// Internal performance statistics tracking
@@ -42,6 +111,7 @@ struct PerformanceStats {
 
 /// Information about an active operation
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 struct OperationInfo {
     operation_type: String,
     path: PathBuf,
@@ -60,6 +130,7 @@ impl FilesystemHandler {
             error_count: 0,
             bytes_read: 0,
             bytes_written: 0,
+            local_opens: 0,
         }));
         
         let performance_stats = Arc::new(RwLock::new(PerformanceStats {
@@ -76,7 +147,242 @@ impl FilesystemHandler {
             performance_stats,
             active_operations: Arc::new(RwLock::new(HashMap::new())),
             performance_config: performance_config.clone(),
+            io: Arc::new(BlockingPool::new(performance_config)),
+            prefetcher: Prefetcher::new(performance_config),
+            journal: None,
+            watcher: Arc::new(Watcher::new()),
+            locks: Arc::new(LockTable::new()),
+            handles: Arc::new(HandleTable::new()),
+            streams: Arc::new(StreamTable::new()),
+            jobs: Arc::new(JobTable::new()),
+            archive: None,
+            mirror: None,
+            limits: None,
+            rate: None,
+            quotas: None,
+            trash: None,
+            ids: Arc::new(IdMap::default()),
+            client_id: None,
+            draining: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "remote-exec")]
+            exec: None,
+        }
+    }
+    
+    /// Record changes made through this handler in `journal`
+    pub fn with_journal(mut self, journal: Arc<ChangeJournal>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+    
+    /// Report archived files as offline and recall them on read
+    pub fn with_archive(mut self, archive: Arc<ArchiveHooks>) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+    
+    /// Follow promotion announcements for this mirror agent; the access
+    /// control is expected to share the same state
+    pub fn with_mirror(mut self, mirror: Arc<MirrorState>) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+    
+    /// Refuse requests that would exceed the agent's memory or open file limits
+    pub fn with_limits(mut self, limits: Arc<ResourceLimits>) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+    
+    /// Refuse requests beyond the agent's request and bandwidth rates
+    pub fn with_rate_limits(mut self, rate: Arc<RateLimiter>) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+    
+    /// Refuse writes that would take a directory over its space quota
+    pub fn with_quotas(mut self, quotas: Arc<QuotaTable>) -> Self {
+        self.quotas = Some(quotas);
+        self
+    }
+    
+    /// Move deleted files and directories into `trash` instead of unlinking them
+    pub fn with_trash(mut self, trash: Arc<Trash>) -> Self {
+        self.trash = Some(trash);
+        self
+    }
+    
+    /// Translate owners and groups set and reported with `ids`
+    pub fn with_id_map(mut self, ids: Arc<IdMap>) -> Self {
+        self.ids = ids;
+        self
+    }
+    
+    /// Let clients run the whitelisted commands of `exec`
+    #[cfg(feature = "remote-exec")]
+    pub fn with_exec(mut self, exec: Arc<CommandRunner>) -> Self {
+        self.exec = Some(exec);
+        self
+    }
+    
+    /// Handler whose access checks also apply the per-user rules for `caller`;
+    /// statistics and active operations are shared with `self`
+    pub fn for_caller(&self, caller: CallerIdentity) -> Self {
+        self.with_access_control(Arc::new(self.access_control.for_caller(caller)))
+    }
+    
+    /// Handler whose access checks also apply the rules naming `client_id`;
+    /// statistics and active operations are shared with `self`
+    pub fn for_client(&self, client_id: String) -> Self {
+        let mut handler = self.with_access_control(Arc::new(self.access_control.for_client(client_id.clone())));
+        handler.client_id = Some(client_id);
+        handler
+    }
+    
+    /// Handler for the connection to another relay, with watches, locks and
+    /// open files of its own so that losing one relay does not end those
+    /// held through another; everything else is shared with `self`
+    pub fn for_relay(&self) -> Self {
+        let mut handler = self.with_access_control(Arc::clone(&self.access_control));
+        handler.watcher = Arc::new(Watcher::new());
+        handler.locks = Arc::new(LockTable::new());
+        handler.handles = Arc::new(HandleTable::new());
+        handler
+    }
+    
+    fn with_access_control(&self, access_control: Arc<AccessControl>) -> Self {
+        Self {
+            access_control,
+            stats: Arc::clone(&self.stats),
+            performance_stats: Arc::clone(&self.performance_stats),
+            active_operations: Arc::clone(&self.active_operations),
+            performance_config: self.performance_config.clone(),
+            io: Arc::clone(&self.io),
+            prefetcher: self.prefetcher.clone(),
+            journal: self.journal.clone(),
+            watcher: Arc::clone(&self.watcher),
+            locks: Arc::clone(&self.locks),
+            handles: Arc::clone(&self.handles),
+            streams: Arc::clone(&self.streams),
+            jobs: Arc::clone(&self.jobs),
+            archive: self.archive.clone(),
+            mirror: self.mirror.clone(),
+            limits: self.limits.clone(),
+            rate: self.rate.clone(),
+            quotas: self.quotas.clone(),
+            trash: self.trash.clone(),
+            ids: Arc::clone(&self.ids),
+            client_id: self.client_id.clone(),
+            draining: Arc::clone(&self.draining),
+            #[cfg(feature = "remote-exec")]
+            exec: self.exec.clone(),
+        }
+    }
+    
+    /// Open a file for a client on this host, which reads it through the
+    /// returned descriptor instead of `ReadFile`
+    ///
+    /// Applies the same checks as `ReadFile`. Offline files are refused
+    /// after their recall is started, so the client falls back to
+    /// `ReadFile` and gets its usual offline answer.
+    pub async fn open_for_local_read(&self, path: &str) -> Result<File, RemoteFsError> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        self.start_operation(operation_id, "local_open", path).await;
+        
+        let result = async {
+            self.access_control.check_read_access(path).await?;
+            
+            let path_buf = PathBuf::from(path);
+            match self.metadata(&path_buf).await {
+                Some(metadata) if metadata.is_file() => {}
+                Some(_) => return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path))),
+                None => return Err(RemoteFsError::NotFound(format!("File not found: {}", path))),
+            }
+            
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    archive.recall(&path_buf).await;
+                    return Err(RemoteFsError::Offline(format!("File is offline: {}", path)));
+                }
+                archive.recalled(&path_buf).await;
+            }
+            
+            let file = self.io.run(move || File::open(&path_buf)).await?
+                .map_err(|e| RemoteFsError::io("Failed to open file", e))?;
+            
+            let mut stats = self.stats.write().await;
+            stats.total_operations += 1;
+            stats.local_opens += 1;
+            Ok(file)
+        }.await;
+        
+        if result.is_err() {
+            self.record_error().await;
+        }
+        self.end_operation(operation_id, start_time).await;
+        result
+    }
+    
+    /// Take a read or write slot for `message` while it is handled, waiting
+    /// for one if all are taken
+    ///
+    /// Returns the error response to send instead when the queue of waiting
+    /// requests is full. Messages that are not requests, and batches, whose
+    /// requests each take a slot, take none.
+    pub async fn admit(&self, message: &Message) -> Result<Option<OperationPermit>, Box<Message>> {
+        let (Some(limits), Some(request_id)) = (&self.limits, message.request_id()) else {
+            return Ok(None);
+        };
+        if matches!(message, Message::Batch { .. }) {
+            return Ok(None);
+        }
+        let operation = if access::is_write(message) { Operation::Write } else { Operation::Read };
+        match limits.begin(operation).await {
+            Ok(permit) => Ok(Some(permit)),
+            Err(exhausted) => {
+                warn!("Refusing {}: agent is at its {} limit", message.message_type(), exhausted.as_str());
+                Err(Box::new(overloaded_response(request_id, exhausted)))
+            }
+        }
+    }
+    
+    /// Refusal for a request beyond the agent's request limits, or that
+    /// would change a path the caller may not change, which is then not
+    /// handled at all
+    pub async fn check_request(&self, message: &Message) -> Option<Message> {
+        if self.draining.load(Ordering::Acquire) && message.request_id().is_some() {
+            debug!("Refused {}: the agent is shutting down", message.message_type());
+            return Some(Message::Error {
+                request_id: message.request_id(),
+                code: ErrorCode::ServiceUnavailable,
+                message: "Agent is shutting down".to_string(),
+                details: None,
+                errno: None,
+            });
+        }
+        
+        if let Some(refusal) = self.limits.as_ref().and_then(|limits| limits.check_request(message)) {
+            debug!("Refused {}: beyond the request limits", message.message_type());
+            return Some(refusal);
+        }
+        
+        if let (Some(rate), Some((ops, bytes))) = (&self.rate, request_cost(message)) {
+            if let Err(throttled) = rate.admit(self.client_id.as_deref(), ops, bytes) {
+                debug!("Refused {}: over the {} rate", message.message_type(), throttled.rate.as_str());
+                return Some(throttled.response(message.request_id()));
+            }
         }
+        
+        let e = self.access_control.check_request(message).await.err()?;
+        debug!("Refused {}: {}", message.message_type(), e);
+        Some(Message::Error {
+            request_id: message.request_id(),
+            code: e.to_error_code(),
+            message: e.to_string(),
+            details: None,
+            errno: None,
+        })
     }
     
     /// Handle read file operation
@@ -100,38 +406,33 @@ impl FilesystemHandler {
             let path_buf = PathBuf::from(&path);
             
             // Check if path exists and is a file
-            if !path_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("File not found: {}", path)));
-            }
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
             
-            if !path_buf.is_file() {
+            if !metadata.is_file() {
                 return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
             }
             
-            // Open file for reading
-            let mut file = File::open(&path_buf)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to open file: {}", e)))?;
-            
-            // Seek to offset if specified
-            if let Some(offset) = offset {
-                file.seek(SeekFrom::Start(offset))
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to seek: {}", e)))?;
+            // Never hand out an archiver's stub
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
+                }
+                archive.recalled(&path_buf).await;
             }
             
-            // Read data
-            let data = if let Some(length) = length {
-                let mut buffer = vec![0u8; length as usize];
-                let bytes_read = file.read(&mut buffer)
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read file: {}", e)))?;
-                buffer.truncate(bytes_read);
-                buffer
-            } else {
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read file: {}", e)))?;
-                buffer
+            // Whole-file reads ask for u32::MAX bytes; only what exists is buffered
+            let remaining = metadata.len().saturating_sub(offset.unwrap_or(0));
+            let to_read = length.map_or(remaining, |length| length.min(remaining));
+            let _permit = match self.reserve(request_id, &path, to_read) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
             };
             
+            let data = self.io.run(move || read_range(&path_buf, offset.unwrap_or(0), to_read)).await??;
+            self.charge_read(data.len() as u64);
+            
             // Update statistics
             {
                 let mut stats = self.stats.write().await;
@@ -147,8 +448,8 @@ impl FilesystemHandler {
             Ok(Message::ReadFileResponse {
                 request_id,
                 success: true,
-                data: Some(data.clone()),
                 bytes_read: data.len() as u64,
+                data: Some(data),
                 error: None,
             })
         }.await;
@@ -160,103 +461,93 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::ReadFileResponse {
+                Some(coded_error_response(request_id, e, |error| Message::ReadFileResponse {
                     request_id,
                     success: false,
                     data: None,
                     bytes_read: 0,
-                    error: Some(e.to_string()),
-                })
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle write file operation
-    pub async fn handle_write_file(
+    /// Handle a checksum of a file, or of a range of it
+    ///
+    /// The file is read a buffer at a time on a blocking thread, so hashing
+    /// a large file holds neither its contents nor a runtime thread.
+    pub async fn handle_compute_checksum(
         &self,
         request_id: Uuid,
         path: String,
-        data: Vec<u8>,
-        offset: Option<u64>,
-        sync: bool,
+        algorithm: ChecksumAlgorithm,
+        offset: u64,
+        length: Option<u64>,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "write_file", &path).await;
+        self.start_operation(operation_id, "checksum", &path).await;
         
-        let result: Result<Message, RemoteFsError> = async {
-            // Check access permissions - for write operations, check write access
-            // If the file doesn't exist, we'll create it, so check create access too
+        let result = async {
+            // Check access permissions
+            self.access_control.check_read_access(&path).await?;
+            
             let path_buf = PathBuf::from(&path);
-            let file_exists = path_buf.exists();
             
-            if file_exists {
-                self.access_control.check_write_access(&path).await?;
-            } else {
-                self.access_control.check_create_access(&path).await?;
+            // Check if path exists and is a file
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+            
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
             }
             
-            // Check file size limit
-            let new_size = data.len() as u64 + offset.unwrap_or(0);
-            self.access_control.check_file_size(new_size).await?;
-            
-            // Create parent directory if it doesn't exist and file doesn't exist
-            if !file_exists {
-                if let Some(parent) = path_buf.parent() {
-                    if !parent.exists() {
-                        fs::create_dir_all(parent)
-                            .map_err(|e| RemoteFsError::FileSystem(format!("Failed to create parent directories: {}", e)))?;
-                    }
+            // A stub's digest would not match the file it stands in for
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
                 }
+                archive.recalled(&path_buf).await;
             }
             
-            // Open file for writing
-            let mut file = if !file_exists {
-                OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(offset.is_none())
-                    .open(&path_buf)
-            } else {
-                OpenOptions::new()
-                    .write(true)
-                    .open(&path_buf)
-            }.map_err(|e| RemoteFsError::FileSystem(format!("Failed to open file for writing: {}", e)))?;
-            
-            // Seek to offset if specified
-            if let Some(offset) = offset {
-                file.seek(SeekFrom::Start(offset))
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to seek: {}", e)))?;
-            }
+            let remaining = metadata.len().saturating_sub(offset);
+            let to_hash = length.map_or(remaining, |length| length.min(remaining));
             
-            // Write data
-            file.write_all(&data)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to write file: {}", e)))?;
+            // Only the read buffer is held, however much is hashed
+            let _permit = match self.limits.as_ref().map(|limits| limits.acquire(to_hash.min(CHECKSUM_BUFFER_SIZE))) {
+                Some(Ok(permit)) => Some(permit),
+                Some(Err(exhausted)) => {
+                    warn!("Refusing checksum of {}: agent is at its {} limit", path, exhausted.as_str());
+                    return Ok(overloaded_response(request_id, exhausted));
+                }
+                None => None,
+            };
             
-            // Sync to disk if requested
-            if sync {
-                file.sync_data()
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to sync file: {}", e)))?;
-            }
+            let checksum = self.io.run(move || hash_file(&path_buf, algorithm, offset, to_hash))
+                .await?
+                .map_err(|e| RemoteFsError::io("Failed to read file", e))?;
+            self.charge_read(checksum.length);
             
             // Update statistics
             {
                 let mut stats = self.stats.write().await;
-                stats.bytes_written += data.len() as u64;
+                stats.bytes_read += checksum.length;
                 stats.total_operations += 1;
             }
             
             {
                 let mut perf_stats = self.performance_stats.write().await;
-                perf_stats.bytes_written += data.len() as u64;
+                perf_stats.bytes_read += checksum.length;
             }
             
-            Ok(Message::WriteFileResponse {
+            Ok(Message::ChecksumResponse {
                 request_id,
                 success: true,
-                bytes_written: data.len() as u64,
+                digest: Some(checksum.digest),
+                length: checksum.length,
                 error: None,
             })
         }.await;
@@ -268,207 +559,251 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::WriteFileResponse {
+                Some(coded_error_response(request_id, e, |error| Message::ChecksumResponse {
                     request_id,
                     success: false,
-                    bytes_written: 0,
-                    error: Some(e.to_string()),
-                })
+                    digest: None,
+                    length: 0,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle list directory operation
-    pub async fn handle_list_directory(
-        &self,
+    /// Handle a streamed read of `length` bytes from `offset`, or of the
+    /// rest of the file
+    ///
+    /// The file is checked as `ReadFile` checks it before anything is sent.
+    /// Its chunks are then read and sent by a task of their own, paced by
+    /// the reader's acknowledgements, which this connection keeps handling
+    /// meanwhile.
+    pub async fn handle_read_file_stream(
+        self: &Arc<Self>,
         request_id: Uuid,
         path: String,
+        offset: u64,
+        length: Option<u64>,
+        chunk_size: u32,
+        chunks: &mpsc::UnboundedSender<Message>,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "list_directory", &path).await;
+        self.start_operation(operation_id, "read_file_stream", &path).await;
         
-        let result = async {
+        // The file to send and how much of it, or the answer instead
+        let result: Result<Result<(PathBuf, u64), Message>, RemoteFsError> = async {
             // Check access permissions
             self.access_control.check_read_access(&path).await?;
             
             let path_buf = PathBuf::from(&path);
             
-            // Check if path exists and is a directory
-            if !path_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("Directory not found: {}", path)));
-            }
+            // Check if path exists and is a file
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
             
-            if !path_buf.is_dir() {
-                return Err(RemoteFsError::InvalidPath(format!("Path is not a directory: {}", path)));
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
             }
             
-            // Read directory entries
-            let entries = fs::read_dir(&path_buf)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read directory: {}", e)))?;
-            
-            let mut dir_entries = Vec::new();
+            // Never hand out an archiver's stub
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(Err(offline_response(request_id, &path, &recall)));
+                }
+                archive.recalled(&path_buf).await;
+            }
             
-            for entry in entries {
-                let entry = entry
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read directory entry: {}", e)))?;
-                
-                let entry_path = entry.path();
-                let metadata = entry.metadata()
-                    .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read metadata: {}", e)))?;
+            let remaining = metadata.len().saturating_sub(offset);
+            Ok(Ok((path_buf, length.map_or(remaining, |length| length.min(remaining)))))
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        let (path_buf, to_read) = match result {
+            Ok(Ok(file)) => file,
+            Ok(Err(response)) => return Some(response),
+            Err(e) => {
+                self.record_error().await;
+                return Some(coded_error_response(request_id, e, |error| Message::ReadFileChunk {
+                    request_id,
+                    sequence: 0,
+                    offset,
+                    data: Vec::new(),
+                    last: true,
+                    error: Some(error),
+                }));
+            }
+        };
+        
+        // Each chunk has to fit in a response
+        let max_chunk = self.limits.as_ref().map_or(u64::MAX, |limits| limits.max_response()).max(1);
+        let chunk_size = (chunk_size.clamp(1, MAX_STREAM_CHUNK) as u64).min(max_chunk);
+        
+        let handler = Arc::clone(self);
+        let chunks = chunks.clone();
+        let window = self.streams.open(request_id);
+        tokio::spawn(async move {
+            let last = handler.send_file_chunks(request_id, path_buf, offset, to_read, chunk_size, &window, &chunks).await;
+            let _ = chunks.send(last);
+        }.in_current_span());
+        None
+    }
+    
+    /// Read and send the chunks of a streamed read but the last, which is
+    /// returned, waiting for room in the stream's window before each
+    #[allow(clippy::too_many_arguments)]
+    async fn send_file_chunks(
+        &self,
+        request_id: Uuid,
+        path: PathBuf,
+        offset: u64,
+        length: u64,
+        chunk_size: u64,
+        window: &StreamWindow,
+        chunks: &mpsc::UnboundedSender<Message>,
+    ) -> Message {
+        let mut sequence = 0;
+        let mut position = offset;
+        let result: Result<Message, RemoteFsError> = async {
+            let end = offset + length;
+            loop {
+                if !window.reserve(STREAM_ACK_TIMEOUT).await {
+                    return Err(RemoteFsError::Timeout(format!(
+                        "Reader of {} stopped acknowledging chunks", path.display()
+                    )));
+                }
                 
-                let file_name = entry_path
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("")
-                    .to_string();
+                let wanted = (end - position).min(chunk_size);
+                let read_path = path.clone();
+                let data = self.io.run(move || read_range(&read_path, position, wanted)).await??;
+                let read = data.len() as u64;
+                self.charge_read(read);
                 
-                // Create FileMetadata for this entry
-                let file_type = if metadata.is_dir() {
-                    remotefs_common::protocol::FileType::Directory
-                } else if metadata.is_symlink() {
-                    remotefs_common::protocol::FileType::Symlink
-                } else {
-                    remotefs_common::protocol::FileType::File
-                };
+                {
+                    let mut stats = self.stats.write().await;
+                    stats.bytes_read += read;
+                }
                 
-                let file_metadata = FileMetadata {
-                    size: metadata.len(),
-                    modified: metadata.modified()
-                        .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                        .unwrap_or_default(),
-                    created: metadata.created()
-                        .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                        .unwrap_or_default(),
-                    accessed: metadata.accessed()
-                        .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                        .unwrap_or_default(),
-                    permissions: metadata.permissions().mode(),
-                    uid: 0, // Default for cross-platform compatibility
-                    gid: 0, // Default for cross-platform compatibility 
-                    is_dir: metadata.is_dir(),
-                    is_file: metadata.is_file(),
-                    is_symlink: metadata.is_symlink(),
-                    file_type,
-                    symlink_target: if metadata.is_symlink() {
-                        entry_path.read_link().ok().and_then(|p| p.to_str().map(|s| s.to_string()))
-                    } else {
-                        None
-                    },
-                };
+                {
+                    let mut perf_stats = self.performance_stats.write().await;
+                    perf_stats.bytes_read += read;
+                }
                 
-                let dir_entry = DirEntry {
-                    name: file_name,
-                    metadata: file_metadata,
+                // A file that shrank since it was sized ends early
+                let chunk = Message::ReadFileChunk {
+                    request_id,
+                    sequence,
+                    offset: position,
+                    data,
+                    last: read < wanted || position + read >= end,
+                    error: None,
                 };
-                
-                dir_entries.push(dir_entry);
-            }
-            
-            // Update statistics
-            {
-                let mut stats = self.stats.write().await;
-                stats.total_operations += 1;
+                if chunk.ends_request() {
+                    return Ok(chunk);
+                }
+                chunks.send(chunk)
+                    .map_err(|_| RemoteFsError::Internal("Connection closed during streamed read".to_string()))?;
+                sequence += 1;
+                position += read;
             }
-            
-            Ok(Message::ListDirectoryResponse {
-                request_id,
-                success: true,
-                entries: Some(dir_entries),
-                error: None,
-            })
         }.await;
         
-        // End operation tracking
-        self.end_operation(operation_id, start_time).await;
-        
         match result {
-            Ok(response) => Some(response),
+            Ok(last) => {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+                last
+            }
             Err(e) => {
                 self.record_error().await;
-                Some(Message::ListDirectoryResponse {
+                coded_error_response(request_id, e, |error| Message::ReadFileChunk {
                     request_id,
-                    success: false,
-                    entries: None,
-                    error: Some(e.to_string()),
+                    sequence,
+                    offset: position,
+                    data: Vec::new(),
+                    last: true,
+                    error: Some(error),
                 })
             }
         }
     }
     
-    /// Handle get metadata operation
-    pub async fn handle_get_metadata(
+    /// Handle a reader's acknowledgement of a streamed chunk, which is not
+    /// answered
+    pub fn handle_read_file_ack(&self, stream_id: Uuid) -> Option<Message> {
+        if !self.streams.acknowledge(&stream_id) {
+            debug!("Acknowledgement for stream {}, which has ended", stream_id);
+        }
+        None
+    }
+    
+    /// Handle write file operation
+    pub async fn handle_write_file(
         &self,
         request_id: Uuid,
         path: String,
-        _follow_symlinks: bool,
+        data: Vec<u8>,
+        offset: Option<u64>,
+        sync: bool,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "get_metadata", &path).await;
+        self.start_operation(operation_id, "write_file", &path).await;
         
-        let result = async {
-            // Check access permissions
-            self.access_control.check_read_access(&path).await?;
-            
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions - for write operations, check write access
+            // If the file doesn't exist, we'll create it, so check create access too
             let path_buf = PathBuf::from(&path);
+            let existing = self.metadata(&path_buf).await;
+            let file_exists = existing.is_some();
             
-            // Check if path exists
-            if !path_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("Path not found: {}", path)));
+            if file_exists {
+                self.access_control.check_write_access(&path).await?;
+            } else {
+                self.access_control.check_create_access(&path).await?;
             }
             
-            // Get metadata
-            let metadata = path_buf.metadata()
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to read metadata: {}", e)))?;
+            // Check file size limit
+            let written = data.len() as u64;
+            self.access_control.check_file_size(written + offset.unwrap_or(0)).await?;
             
-            let file_type = if metadata.is_dir() {
-                remotefs_common::protocol::FileType::Directory
-            } else if metadata.is_symlink() {
-                remotefs_common::protocol::FileType::Symlink
-            } else {
-                remotefs_common::protocol::FileType::File
+            let _permit = match self.reserve(request_id, &path, written) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
             };
             
-            let file_metadata = FileMetadata {
-                size: metadata.len(),
-                modified: metadata.modified()
-                    .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                    .unwrap_or_default(),
-                created: metadata.created()
-                    .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                    .unwrap_or_default(),
-                accessed: metadata.accessed()
-                    .map(|st| DateTime::from_timestamp(st.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64, 0).unwrap_or_default())
-                    .unwrap_or_default(),
-                permissions: metadata.permissions().mode(),
-                uid: 0, // Default for cross-platform compatibility
-                gid: 0, // Default for cross-platform compatibility
-                is_dir: metadata.is_dir(),
-                is_file: metadata.is_file(),
-                is_symlink: metadata.is_symlink(),
-                file_type,
-                symlink_target: if metadata.is_symlink() {
-                    path_buf.read_link().ok().and_then(|p| p.to_str().map(|s| s.to_string()))
-                } else {
-                    None
-                },
-            };
+            let before = existing.map_or(0, |metadata| metadata.len());
+            let after = before.max(offset.unwrap_or(0) + written);
+            let charge = self.charge_quota(|change| change.resize(&path_buf, before, after))?;
+            
+            self.io.run(move || write_range(&path_buf, &data, offset, !file_exists, sync)).await??;
+            charge.commit();
             
             // Update statistics
             {
                 let mut stats = self.stats.write().await;
+                stats.bytes_written += written;
                 stats.total_operations += 1;
             }
             
-            Ok(Message::GetMetadataResponse {
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += written;
+            }
+            
+            let kind = if file_exists { ChangeKind::Modified } else { ChangeKind::Created };
+            self.record_change(kind, &path, false).await;
+            
+            Ok(Message::WriteFileResponse {
                 request_id,
                 success: true,
-                metadata: Some(file_metadata),
+                bytes_written: written,
                 error: None,
             })
         }.await;
@@ -480,178 +815,282 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::GetMetadataResponse {
+                Some(coded_error_response(request_id, e, |error| Message::WriteFileResponse {
                     request_id,
                     success: false,
-                    metadata: None,
-                    error: Some(e.to_string()),
-                })
+                    bytes_written: 0,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle create directory operation
-    pub async fn handle_create_directory(
+    /// Handle a request for the block signatures of a file
+    pub async fn handle_get_file_signature(
         &self,
         request_id: Uuid,
         path: String,
-        _mode: u32,
+        block_size: u32,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "create_directory", &path).await;
+        self.start_operation(operation_id, "file_signature", &path).await;
         
-        let result: Result<Message, RemoteFsError> = async {
-            // Check access permissions
-            self.access_control.check_create_access(&path).await?;
+        let result = async {
+            self.access_control.check_read_access(&path).await?;
+            check_block_size(block_size)?;
             
             let path_buf = PathBuf::from(&path);
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
+            }
             
-            // Create directory with specified mode
-            // For simplicity, we'll create directories recursively based on mode
-            let result = fs::create_dir_all(&path_buf);
+            // A stub's blocks would not match the file it stands in for
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
+                }
+                archive.recalled(&path_buf).await;
+            }
+            
+            // Only a block is held at a time, however large the file
+            let _permit = match self.limits.as_ref().map(|limits| limits.acquire(block_size as u64)) {
+                Some(Ok(permit)) => Some(permit),
+                Some(Err(exhausted)) => {
+                    warn!("Refusing signature of {}: agent is at its {} limit", path, exhausted.as_str());
+                    return Ok(overloaded_response(request_id, exhausted));
+                }
+                None => None,
+            };
             
-            result.map_err(|e| RemoteFsError::FileSystem(format!("Failed to create directory: {}", e)))?;
+            let signature = self.io.run(move || {
+                File::open(&path_buf).and_then(|file| FileSignature::from_reader(std::io::BufReader::new(file), block_size))
+            }).await?.map_err(|e| RemoteFsError::io("Failed to read file", e))?;
+            self.charge_read(signature.length);
             
-            // Update statistics
             {
                 let mut stats = self.stats.write().await;
+                stats.bytes_read += signature.length;
                 stats.total_operations += 1;
             }
             
-            Ok(Message::CreateDirectoryResponse {
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_read += signature.length;
+            }
+            
+            Ok(Message::FileSignatureResponse {
                 request_id,
                 success: true,
-                metadata: None,
+                signature: Some(signature),
                 error: None,
             })
         }.await;
         
-        // End operation tracking
         self.end_operation(operation_id, start_time).await;
         
         match result {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::CreateDirectoryResponse {
+                Some(coded_error_response(request_id, e, |error| Message::FileSignatureResponse {
                     request_id,
                     success: false,
-                    metadata: None,
-                    error: Some(e.to_string()),
-                })
+                    signature: None,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle delete file operation
-    pub async fn handle_delete_file(
+    /// Handle a delta write, rebuilding the file from blocks of its current
+    /// content and the literal data sent
+    ///
+    /// The new file is built next to the old one and renamed over it once
+    /// its digest matches, so a reader never sees it half built. Like any
+    /// replacement by rename, this gives the path a new inode, so other
+    /// hard links to the old file keep the old content.
+    pub async fn handle_write_delta(
         &self,
         request_id: Uuid,
         path: String,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+        checksum: Vec<u8>,
+        sync: bool,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "delete_file", &path).await;
+        self.start_operation(operation_id, "write_delta", &path).await;
         
-        let result = async {
-            // Check access permissions
-            self.access_control.check_delete_access(&path).await?;
+        let result: Result<Message, RemoteFsError> = async {
+            self.access_control.check_write_access(&path).await?;
+            check_block_size(block_size)?;
             
+            // The delta is against the file as it is, so it must be there
             let path_buf = PathBuf::from(&path);
-            
-            // Check if path exists and is a file
-            if !path_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("File not found: {}", path)));
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
             }
             
-            if !path_buf.is_file() {
-                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
+            if let Some(archive) = &self.archive {
+                if archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
+                }
+                archive.recalled(&path_buf).await;
             }
             
-            // Delete file
-            fs::remove_file(&path_buf)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to delete file: {}", e)))?;
+            let before = metadata.len();
+            let after: u64 = ops.iter()
+                .map(|op| match op {
+                    DeltaOp::Copy { block, count } => {
+                        let start = block.saturating_mul(block_size as u64).min(before);
+                        count.saturating_mul(block_size as u64).min(before - start)
+                    }
+                    DeltaOp::Data(data) => data.len() as u64,
+                })
+                .sum();
+            self.access_control.check_file_size(after).await?;
+            
+            let sent = delta::literal_len(&ops);
+            let _permit = match self.reserve(request_id, &path, sent) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let charge = self.charge_quota(|change| change.resize(&path_buf, before, after))?;
+            let temp_path = delta_temp_path(&path_buf, request_id);
+            let written = self.io.run(move || {
+                let result = rebuild_file(&path_buf, &temp_path, block_size, &ops, &checksum, sync);
+                if result.is_err() {
+                    let _ = fs::remove_file(&temp_path);
+                }
+                result
+            }).await??;
+            charge.commit();
             
-            // Update statistics
             {
                 let mut stats = self.stats.write().await;
+                stats.bytes_written += sent;
                 stats.total_operations += 1;
             }
             
-            Ok(Message::DeleteFileResponse {
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += sent;
+            }
+            
+            self.record_change(ChangeKind::Modified, &path, false).await;
+            
+            Ok(Message::WriteFileResponse {
                 request_id,
                 success: true,
+                bytes_written: written,
                 error: None,
             })
         }.await;
         
-        // End operation tracking
         self.end_operation(operation_id, start_time).await;
         
         match result {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::DeleteFileResponse {
+                Some(coded_error_response(request_id, e, |error| Message::WriteFileResponse {
                     request_id,
                     success: false,
-                    error: Some(e.to_string()),
-                })
+                    bytes_written: 0,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle delete directory operation
-    pub async fn handle_delete_directory(
+    /// Handle one chunk of an upload
+    ///
+    /// The first chunk creates the file or cuts it off at its offset, and
+    /// the last is synced if asked. Changes are recorded for those two
+    /// only, rather than for every chunk of a large file.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn handle_write_file_chunk(
         &self,
         request_id: Uuid,
         path: String,
-        recursive: bool,
+        sequence: u32,
+        offset: u64,
+        data: Vec<u8>,
+        last: bool,
+        sync: bool,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "delete_directory", &path).await;
+        self.start_operation(operation_id, "write_file_chunk", &path).await;
         
-        let result = async {
-            // Check access permissions
-            self.access_control.check_delete_access(&path).await?;
-            
+        let result: Result<Message, RemoteFsError> = async {
+            let first = sequence == 0;
             let path_buf = PathBuf::from(&path);
+            let existing = self.metadata(&path_buf).await;
+            let file_exists = existing.is_some();
             
-            // Check if path exists and is a directory
-            if !path_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("Directory not found: {}", path)));
+            if file_exists {
+                self.access_control.check_write_access(&path).await?;
+            } else if first {
+                self.access_control.check_create_access(&path).await?;
+            } else {
+                return Err(RemoteFsError::NotFound(format!(
+                    "File not found: {} (chunk {} of an upload whose first chunk did not create it)", path, sequence
+                )));
             }
             
-            if !path_buf.is_dir() {
-                return Err(RemoteFsError::InvalidPath(format!("Path is not a directory: {}", path)));
-            }
+            // Check file size limit
+            let written = data.len() as u64;
+            self.access_control.check_file_size(written + offset).await?;
             
-            // Delete directory
-            let result = if recursive {
-                fs::remove_dir_all(&path_buf)
-            } else {
-                fs::remove_dir(&path_buf)
+            let _permit = match self.reserve(request_id, &path, written) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
             };
             
-            result.map_err(|e| RemoteFsError::FileSystem(format!("Failed to delete directory: {}", e)))?;
+            let before = existing.map_or(0, |metadata| metadata.len());
+            let after = if first { offset + written } else { before.max(offset + written) };
+            let charge = self.charge_quota(|change| change.resize(&path_buf, before, after))?;
+            
+            self.io.run(move || write_chunk(&path_buf, &data, offset, first, last && sync)).await??;
+            charge.commit();
             
             // Update statistics
             {
                 let mut stats = self.stats.write().await;
+                stats.bytes_written += written;
                 stats.total_operations += 1;
             }
             
-            Ok(Message::RemoveDirectoryResponse {
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += written;
+            }
+            
+            if first || last {
+                let kind = if file_exists || !first { ChangeKind::Modified } else { ChangeKind::Created };
+                self.record_change(kind, &path, false).await;
+            }
+            
+            Ok(Message::WriteFileResponse {
                 request_id,
                 success: true,
+                bytes_written: written,
                 error: None,
             })
         }.await;
@@ -663,53 +1102,61 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::RemoveDirectoryResponse {
+                Some(coded_error_response(request_id, e, |error| Message::WriteFileResponse {
                     request_id,
                     success: false,
-                    error: Some(e.to_string()),
-                })
+                    bytes_written: 0,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle move file operation
-    pub async fn handle_move_file(
+    /// Handle list directory operation
+    pub async fn handle_list_directory(
         &self,
         request_id: Uuid,
-        source_path: String,
-        dest_path: String,
+        path: String,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "move_file", &source_path).await;
+        self.start_operation(operation_id, "list_directory", &path).await;
         
-        let result = async {
+        let result: Result<Message, RemoteFsError> = async {
             // Check access permissions
-            self.access_control.check_read_access(&source_path).await?;
-            self.access_control.check_create_access(&dest_path).await?;
-            self.access_control.check_delete_access(&source_path).await?;
-            
-            let source_buf = PathBuf::from(&source_path);
-            let dest_buf = PathBuf::from(&dest_path);
-            
-            // Check if source exists
-            if !source_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("Source not found: {}", source_path)));
-            }
+            self.access_control.check_read_access(&path).await?;
             
-            // Create destination directory if needed
-            if let Some(parent) = dest_buf.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)
-                        .map_err(|e| RemoteFsError::FileSystem(format!("Failed to create destination directories: {}", e)))?;
+            let listed_path = path.clone();
+            let archive = self.archive.clone();
+            let ids = Arc::clone(&self.ids);
+            let limits = self.limits.clone();
+            let listing = self.io.run(move || {
+                let mut dir_entries = Vec::new();
+                for entry in open_directory(&listed_path)? {
+                    if let Some(dir_entry) = dir_entry(archive.as_deref(), &ids, entry)? {
+                        dir_entries.push(dir_entry);
+                    }
+                    
+                    // Stop reading as soon as the listing is known to be too large
+                    if limits.as_ref().is_some_and(|limits| !limits.allows_listing(dir_entries.len())) {
+                        return Ok(None);
+                    }
                 }
-            }
+                Ok::<_, RemoteFsError>(Some(dir_entries))
+            }).await??;
+            
+            let Some(mut dir_entries) = listing else {
+                let max = self.limits.as_ref().map_or(0, |limits| limits.max_listing_entries());
+                return Ok(over_limit(
+                    Some(request_id), ErrorCode::MessageTooLarge, "listing_entries", max as u64,
+                    format!("{} has more than the agent's limit of {} entries; list it in pages", path, max),
+                ));
+            };
             
-            // Move file/directory
-            fs::rename(&source_buf, &dest_buf)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to move: {}", e)))?;
+            // Same order as paged listings
+            dir_entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
             
             // Update statistics
             {
@@ -717,9 +1164,10 @@ impl FilesystemHandler {
                 stats.total_operations += 1;
             }
             
-            Ok(Message::RenameResponse {
+            Ok(Message::ListDirectoryResponse {
                 request_id,
                 success: true,
+                entries: Some(dir_entries),
                 error: None,
             })
         }.await;
@@ -731,83 +1179,244 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::RenameResponse {
+                Some(coded_error_response(request_id, e, |error| Message::ListDirectoryResponse {
                     request_id,
                     success: false,
-                    error: Some(e.to_string()),
-                })
+                    entries: None,
+                    error: Some(error),
+                }))
             }
         }
     }
     
-    /// Handle copy file operation
-    pub async fn handle_copy_file(
+    /// Handle paged list directory operation
+    ///
+    /// Entries are listed in the byte order of their names, starting behind
+    /// `after` when it is set. Only the names are read up front; metadata is
+    /// read a page at a time and every full page is sent through `pages` as
+    /// soon as it is ready, with the last page returned like any other
+    /// response. Entries removed before their page is read are left out.
+    /// With `max_entries` the listing stops after that many entries, and its
+    /// last page names the entry to continue after if any remain.
+    pub async fn handle_list_directory_paged(
         &self,
         request_id: Uuid,
-        source_path: String,
-        dest_path: String,
+        path: String,
+        page_size: u32,
+        after: Option<String>,
+        max_entries: Option<u32>,
+        pages: &mpsc::UnboundedSender<Message>,
     ) -> Option<Message> {
         let operation_id = Uuid::new_v4();
         let start_time = SystemTime::now();
         
         // Track operation
-        self.start_operation(operation_id, "copy_file", &source_path).await;
+        self.start_operation(operation_id, "list_directory_paged", &path).await;
         
-        let result = async {
+        let page_size = page_size.max(1) as usize;
+        let max_entries = max_entries.map_or(usize::MAX, |max| max.max(1) as usize);
+        let mut sequence = 0;
+        let result: Result<Message, RemoteFsError> = async {
             // Check access permissions
-            self.access_control.check_read_access(&source_path).await?;
-            self.access_control.check_create_access(&dest_path).await?;
+            self.access_control.check_read_access(&path).await?;
             
-            let source_buf = PathBuf::from(&source_path);
-            let dest_buf = PathBuf::from(&dest_path);
+            let listed_path = path.clone();
+            let mut names = self.io.run(move || sorted_names(&listed_path, after.as_deref())).await??
+                .into_iter()
+                .peekable();
             
-            // Check if source exists and is a file
-            if !source_buf.exists() {
-                return Err(RemoteFsError::NotFound(format!("Source not found: {}", source_path)));
+            let mut page = Vec::with_capacity(page_size);
+            let mut listed = 0;
+            let mut last_name = None;
+            
+            while listed < max_entries {
+                // Read the metadata of as many entries as the page and the
+                // listing have room for at once
+                let wanted = (page_size - page.len()).min(max_entries - listed);
+                let batch: Vec<OsString> = names.by_ref().take(wanted).collect();
+                if batch.is_empty() {
+                    break;
+                }
+                
+                let directory = PathBuf::from(&path);
+                let archive = self.archive.clone();
+                let ids = Arc::clone(&self.ids);
+                let entries = self.io.run(move || {
+                    batch.into_iter()
+                        .map(|name| Ok((named_dir_entry(archive.as_deref(), &ids, &directory, &name)?, name)))
+                        .collect::<Result<Vec<_>, RemoteFsError>>()
+                }).await??;
+                
+                for (dir_entry, name) in entries {
+                    if let Some(dir_entry) = dir_entry {
+                        page.push(dir_entry);
+                        listed += 1;
+                    }
+                    last_name = Some(name);
+                }
+                
+                if page.len() == page_size {
+                    let full_page = Message::DirectoryPage {
+                        request_id,
+                        sequence,
+                        entries: std::mem::replace(&mut page, Vec::with_capacity(page_size)),
+                        last: false,
+                        error: None,
+                        cursor: None,
+                    };
+                    pages.send(full_page)
+                        .map_err(|_| RemoteFsError::Internal("Connection closed during listing".to_string()))?;
+                    sequence += 1;
+                }
             }
             
-            if !source_buf.is_file() {
-                return Err(RemoteFsError::InvalidPath(format!("Source is not a file: {}", source_path)));
+            // Entries remain past `max_entries`; continue after the last one read
+            let cursor = match names.peek() {
+                Some(_) => last_name.map(|name| name.to_string_lossy().to_string()),
+                None => None,
+            };
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
             }
             
-            // Check file size limit
-            let file_size = source_buf.metadata()
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to get source metadata: {}", e)))?
-                .len();
+            Ok(Message::DirectoryPage {
+                request_id,
+                sequence,
+                entries: page,
+                last: true,
+                error: None,
+                cursor,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::DirectoryPage {
+                    request_id,
+                    sequence,
+                    entries: Vec::new(),
+                    last: true,
+                    error: Some(error),
+                    cursor: None,
+                }))
+            }
+        }
+    }
+    
+    /// Handle a walk of the tree below a directory
+    ///
+    /// The tree is walked depth first and every full page is sent through
+    /// `pages` as soon as it is read. Only the directories between `path` and
+    /// the one being read are held open, so memory grows with the depth of
+    /// the tree, not its size. Subdirectories the caller may not read are
+    /// listed but not entered, and entries that vanish during the walk are
+    /// left out.
+    pub async fn handle_walk_directory(
+        &self,
+        request_id: Uuid,
+        path: String,
+        max_depth: Option<u32>,
+        follow_symlinks: bool,
+        pages: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "walk_directory", &path).await;
+        
+        let mut sequence = 0;
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_read_access(&path).await?;
             
-            self.access_control.check_file_size(file_size).await?;
+            let root = PathBuf::from(&path);
+            let walked_path = path.clone();
+            let (entries, id) = self.io.run(move || {
+                Ok::<_, RemoteFsError>((open_directory(&walked_path)?, directory_id(Path::new(&walked_path))?))
+            }).await??;
             
-            // Create destination directory if needed
-            if let Some(parent) = dest_buf.parent() {
-                if !parent.exists() {
-                    fs::create_dir_all(parent)
-                        .map_err(|e| RemoteFsError::FileSystem(format!("Failed to create destination directories: {}", e)))?;
-                }
+            // Paths deeper than the agent accepts in requests are not walked either
+            let depth_left = self.limits.as_ref()
+                .map_or(usize::MAX, |limits| limits.max_path_depth().saturating_sub(root.components().count()));
+            let max_depth = max_depth.map_or(usize::MAX, |depth| depth as usize).min(depth_left);
+            
+            let mut open = Vec::new();
+            if max_depth > 0 {
+                open.push(WalkLevel::new(entries, PathBuf::new(), id));
             }
             
-            // Copy file
-            fs::copy(&source_buf, &dest_buf)
-                .map_err(|e| RemoteFsError::FileSystem(format!("Failed to copy file: {}", e)))?;
+            let mut page = Vec::with_capacity(WALK_PAGE_ENTRIES);
+            
+            while let Some(level) = open.last_mut() {
+                let Some(entry) = level.read.pop_front() else {
+                    // Read the next few entries, or leave the exhausted directory
+                    let Some(entries) = level.entries.take() else {
+                        open.pop();
+                        continue;
+                    };
+                    let archive = self.archive.clone();
+                    let ids = Arc::clone(&self.ids);
+                    (level.entries, level.read) = self.io
+                        .run(move || read_ahead(entries, archive.as_deref(), &ids, follow_symlinks))
+                        .await?;
+                    continue;
+                };
+                let relative = level.relative.join(&entry.name);
+                
+                let mut dir_entry = match entry.dir_entry {
+                    Ok(Some(dir_entry)) => dir_entry,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!("Skipping entry during walk of {}: {}", path, e);
+                        continue;
+                    }
+                };
+                dir_entry.name = relative.to_string_lossy().to_string();
+                page.push(dir_entry);
+                
+                if entry.is_dir && open.len() < max_depth {
+                    if let Some(level) = self.enter_directory(entry.path, relative, &open).await {
+                        open.push(level);
+                    }
+                }
+                
+                if page.len() == WALK_PAGE_ENTRIES {
+                    let full_page = Message::DirectoryPage {
+                        request_id,
+                        sequence,
+                        entries: std::mem::replace(&mut page, Vec::with_capacity(WALK_PAGE_ENTRIES)),
+                        last: false,
+                        error: None,
+                        cursor: None,
+                    };
+                    pages.send(full_page)
+                        .map_err(|_| RemoteFsError::Internal("Connection closed during walk".to_string()))?;
+                    sequence += 1;
+                }
+            }
             
             // Update statistics
             {
                 let mut stats = self.stats.write().await;
-                stats.bytes_read += file_size;
-                stats.bytes_written += file_size;
                 stats.total_operations += 1;
             }
             
-            {
-                let mut perf_stats = self.performance_stats.write().await;
-                perf_stats.bytes_read += file_size;
-                perf_stats.bytes_written += file_size;
-            }
-            
-            Ok(Message::CreateFileResponse {
+            Ok(Message::DirectoryPage {
                 request_id,
-                success: true,
-                metadata: None,
+                sequence,
+                entries: page,
+                last: true,
                 error: None,
+                cursor: None,
             })
         }.await;
         
@@ -818,66 +1427,3174 @@ impl FilesystemHandler {
             Ok(response) => Some(response),
             Err(e) => {
                 self.record_error().await;
-                Some(Message::CreateFileResponse {
+                Some(coded_error_response(request_id, e, |error| Message::DirectoryPage {
                     request_id,
-                    success: false,
-                    metadata: None,
-                    error: Some(e.to_string()),
-                })
+                    sequence,
+                    entries: Vec::new(),
+                    last: true,
+                    error: Some(error),
+                    cursor: None,
+                }))
             }
         }
     }
     
-    /// Start tracking an operation
-    async fn start_operation(&self, operation_id: Uuid, operation_type: &str, path: &str) {
-        let operation_info = OperationInfo {
-            operation_type: operation_type.to_string(),
-            path: PathBuf::from(path),
-            start_time: SystemTime::now(),
-        };
-        
-        {
-            let mut active = self.active_operations.write().await;
-            active.insert(operation_id, operation_info);
+    /// Open a subdirectory found by a walk, or `None` if the caller may not
+    /// read it, it cannot be read or the walk is already inside it
+    async fn enter_directory(&self, path: PathBuf, relative: PathBuf, open: &[WalkLevel]) -> Option<WalkLevel> {
+        let path_str = path.to_string_lossy().to_string();
+        if let Err(e) = self.access_control.check_read_access(&path_str).await {
+            debug!("Not walking into {}: {}", path_str, e);
+            return None;
         }
         
-        {
-            let mut stats = self.stats.write().await;
-            stats.active_operations += 1;
-        }
+        let opened = self.io.run(move || {
+            let id = directory_id(&path)?;
+            let entries = fs::read_dir(&path)
+                .map_err(|e| RemoteFsError::io("Failed to read directory", e))?;
+            Ok::<_, RemoteFsError>((entries, id))
+        }).await.and_then(|opened| opened);
         
-        debug!("Started {} operation on {}", operation_type, path);
+        match opened {
+            Ok((_, id)) if open.iter().any(|level| level.id == id) => {
+                debug!("Not walking into {}: symlink loop", path_str);
+                None
+            }
+            Ok((entries, id)) => Some(WalkLevel::new(entries, relative, id)),
+            Err(e) => {
+                debug!("Not walking into {}: {}", path_str, e);
+                None
+            }
+        }
     }
     
-    /// End tracking an operation
-    async fn end_operation(&self, operation_id: Uuid, start_time: SystemTime) {
-        {
-            let mut active = self.active_operations.write().await;
-            active.remove(&operation_id);
-        }
+    /// Handle a search below `root`, sending full pages of matches as they
+    /// are found and returning the last
+    ///
+    /// The tree is walked like `WalkDirectory` walks it without following
+    /// symlinks. Files the caller may not read are left out, and files are
+    /// searched by content a few at a time, their matches kept in the order
+    /// the walk found the files.
+    pub async fn handle_search_files(
+        &self,
+        request_id: Uuid,
+        root: String,
+        name_glob: Option<String>,
+        content_regex: Option<String>,
+        max_results: Option<u32>,
+        pages: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
         
-        {
-            let mut stats = self.stats.write().await;
-            stats.active_operations = stats.active_operations.saturating_sub(1);
-        }
+        // Track operation
+        self.start_operation(operation_id, "search_files", &root).await;
         
-        // Record performance metrics
-        if let Ok(duration) = start_time.elapsed() {
-            let mut perf_stats = self.performance_stats.write().await;
-            perf_stats.response_times.push(duration);
-            perf_stats.operations_count += 1;
+        let mut sequence = 0;
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_read_access(&root).await?;
+            
+            let query = Arc::new(SearchQuery::new(name_glob.as_deref(), content_regex.as_deref())?);
+            let max_results = max_results.map_or(MAX_SEARCH_RESULTS, |max| max as usize).min(MAX_SEARCH_RESULTS);
+            
+            let root_path = PathBuf::from(&root);
+            let searched_path = root.clone();
+            let (entries, id) = self.io.run(move || {
+                Ok::<_, RemoteFsError>((open_directory(&searched_path)?, directory_id(Path::new(&searched_path))?))
+            }).await??;
+            
+            // Paths deeper than the agent accepts in requests are not searched either
+            let max_depth = self.limits.as_ref()
+                .map_or(usize::MAX, |limits| limits.max_path_depth().saturating_sub(root_path.components().count()));
+            
+            let mut open = Vec::new();
+            if max_depth > 0 {
+                open.push(WalkLevel::new(entries, PathBuf::new(), id));
+            }
+            
+            let mut page = Vec::new();
+            let mut found = 0;
+            let mut scans = FuturesOrdered::new();
+            while found < max_results {
+                let next = self.next_search_file(&mut open, max_depth, &query).await?;
+                let walked = next.is_none();
+                match next {
+                    Some((_, relative)) if !query.searches_content() => {
+                        page.push(SearchMatch { path: relative.to_string_lossy().to_string(), line_number: None, line: None });
+                        found += 1;
+                    }
+                    Some((path, relative)) => {
+                        let (query, left) = (Arc::clone(&query), max_results - found);
+                        scans.push_back(async move {
+                            (relative, self.io.run(move || query.scan(&path, left)).await)
+                        });
+                    }
+                    None if scans.is_empty() => break,
+                    None => {}
+                }
+                
+                // Take the matches of the oldest file once enough are being
+                // searched, or all are once the walk is done
+                if scans.len() >= SEARCH_PARALLELISM || walked {
+                    if let Some((relative, lines)) = scans.next().await {
+                        let lines = match lines? {
+                            Ok(lines) => lines,
+                            Err(e) => {
+                                debug!("Skipping {} during search of {}: {}", relative.display(), root, e);
+                                Vec::new()
+                            }
+                        };
+                        let path = relative.to_string_lossy().to_string();
+                        for (line_number, line) in lines.into_iter().take(max_results - found) {
+                            page.push(SearchMatch { path: path.clone(), line_number: Some(line_number), line: Some(line) });
+                            found += 1;
+                        }
+                    }
+                }
+                
+                while page.len() >= SEARCH_PAGE_MATCHES {
+                    let full_page = Message::SearchResults {
+                        request_id,
+                        sequence,
+                        matches: page.drain(..SEARCH_PAGE_MATCHES).collect(),
+                        last: false,
+                        truncated: false,
+                        error: None,
+                    };
+                    pages.send(full_page)
+                        .map_err(|_| RemoteFsError::Internal("Connection closed during search".to_string()))?;
+                    sequence += 1;
+                }
+            }
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::SearchResults {
+                request_id,
+                sequence,
+                matches: page,
+                last: true,
+                truncated: found >= max_results,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::SearchResults {
+                    request_id,
+                    sequence,
+                    matches: Vec::new(),
+                    last: true,
+                    truncated: false,
+                    error: Some(error),
+                }))
+            }
         }
     }
     
-    /// Record an error
-    async fn record_error(&self) {
-        let mut stats = self.stats.write().await;
-        stats.error_count += 1;
-    }
-    
-    /// Get filesystem statistics
-    pub async fn get_statistics(&self) -> FilesystemStatistics {
-        self.stats.read().await.clone()
+    /// Next file of a search's walk that is named as `query` asks and the
+    /// caller may read, with its path relative to the search root, or
+    /// `None` once the walk is done
+    async fn next_search_file(
+        &self,
+        open: &mut Vec<WalkLevel>,
+        max_depth: usize,
+        query: &SearchQuery,
+    ) -> Result<Option<(PathBuf, PathBuf)>, RemoteFsError> {
+        while let Some(level) = open.last_mut() {
+            let Some(entry) = level.read.pop_front() else {
+                // Read the next few entries, or leave the exhausted directory
+                let Some(entries) = level.entries.take() else {
+                    open.pop();
+                    continue;
+                };
+                let archive = self.archive.clone();
+                let ids = Arc::clone(&self.ids);
+                (level.entries, level.read) = self.io
+                    .run(move || read_ahead(entries, archive.as_deref(), &ids, false))
+                    .await?;
+                continue;
+            };
+            let relative = level.relative.join(&entry.name);
+            
+            let Ok(Some(dir_entry)) = entry.dir_entry else {
+                continue;
+            };
+            if entry.is_dir {
+                if open.len() < max_depth {
+                    if let Some(level) = self.enter_directory(entry.path, relative, open).await {
+                        open.push(level);
+                    }
+                }
+                continue;
+            }
+            
+            // Archived files would have to be recalled to be searched
+            let metadata = &dir_entry.metadata;
+            if !metadata.is_file || !query.matches_name(&relative) || (metadata.offline && query.searches_content()) {
+                continue;
+            }
+            if !self.access_control.is_readable(&entry.path.to_string_lossy()).await {
+                continue;
+            }
+            return Ok(Some((entry.path, relative)));
+        }
+        Ok(None)
+    }
+    
+    /// Handle get metadata operation
+    pub async fn handle_get_metadata(
+        &self,
+        request_id: Uuid,
+        path: String,
+        follow_symlinks: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "get_metadata", &path).await;
+        
+        let result = async {
+            // Without following, a symlink is checked and described itself,
+            // wherever it points
+            let path_buf = PathBuf::from(&path);
+            let metadata = if follow_symlinks {
+                self.access_control.check_read_access(&path).await?;
+                self.metadata(&path_buf).await
+            } else {
+                self.access_control.check_entry_access(&path).await?;
+                self.symlink_metadata(&path_buf).await
+            };
+            let metadata = metadata
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Path not found: {}", path)))?;
+            
+            let file_metadata = self.ids.to_remote(self.with_offline_flag(FileMetadata::from_fs(&metadata, &path_buf), &path_buf));
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::GetMetadataResponse {
+                request_id,
+                success: true,
+                metadata: Some(file_metadata),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::GetMetadataResponse {
+                    request_id,
+                    success: false,
+                    metadata: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle path existence check
+    pub async fn handle_path_exists(
+        &self,
+        request_id: Uuid,
+        path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "path_exists", &path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_read_access(&path).await?;
+            
+            let exists = self.metadata(&PathBuf::from(&path)).await.is_some();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::PathExistsResponse {
+                request_id,
+                exists,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::PathExistsResponse {
+                    request_id,
+                    exists: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle set metadata operation
+    ///
+    /// Only the fields present in `update` are applied, so changing the
+    /// modification time never resets mode bits or ownership.
+    pub async fn handle_set_metadata(
+        &self,
+        request_id: Uuid,
+        path: String,
+        update: MetadataUpdate,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "set_metadata", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_write_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            
+            // Check if path exists
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Path not found: {}", path)))?;
+            
+            let mode = update.permissions.map(|mode| self.access_control.permitted_mode(mode));
+            let update = self.ids.to_local(update);
+            self.io.run(move || set_metadata(&path_buf, mode, &update)).await??;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Modified, &path, metadata.is_dir()).await;
+            
+            Ok(Message::SetMetadataResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::SetMetadataResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a recursive metadata change of `path` and everything below it
+    ///
+    /// `path` itself is checked as `SetMetadata` checks it before anything
+    /// is sent. The tree is then changed by a task of its own, which reports
+    /// its progress about once a second and stops between entries when the
+    /// request is cancelled; this connection keeps handling requests
+    /// meanwhile.
+    pub async fn handle_set_metadata_tree(
+        self: &Arc<Self>,
+        request_id: Uuid,
+        path: String,
+        update: MetadataUpdate,
+        progress: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "set_metadata_tree", &path).await;
+        
+        let result: Result<bool, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_write_access(&path).await?;
+            
+            let metadata = self.metadata(Path::new(&path)).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Path not found: {}", path)))?;
+            Ok(metadata.is_dir())
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        let is_dir = match result {
+            Ok(is_dir) => is_dir,
+            Err(e) => {
+                self.record_error().await;
+                return Some(coded_error_response(request_id, e, |error| Message::SetMetadataTreeProgress {
+                    request_id,
+                    progress: TreeProgress::default(),
+                    last: true,
+                    error: Some(error),
+                }));
+            }
+        };
+        
+        let mode = update.permissions.map(|mode| self.access_control.permitted_mode(mode));
+        let update = Arc::new(self.ids.to_local(update));
+        let handler = Arc::clone(self);
+        let progress = progress.clone();
+        let job = self.jobs.start(request_id);
+        tokio::spawn(async move {
+            let last = handler.change_metadata_tree(request_id, PathBuf::from(path), is_dir, mode, update, &job, &progress).await;
+            let _ = progress.send(last);
+        }.in_current_span());
+        None
+    }
+    
+    /// Change the entries of a tree, depth first and each directory before
+    /// its entries, returning the last progress message
+    #[allow(clippy::too_many_arguments)]
+    async fn change_metadata_tree(
+        &self,
+        request_id: Uuid,
+        root: PathBuf,
+        root_is_dir: bool,
+        mode: Option<u32>,
+        update: Arc<MetadataUpdate>,
+        job: &Job,
+        progress: &mpsc::UnboundedSender<Message>,
+    ) -> Message {
+        let mut counts = TreeProgress::default();
+        let mut reported = tokio::time::Instant::now();
+        let mut pending = vec![(root, root_is_dir)];
+        
+        while let Some((path, is_dir)) = pending.pop() {
+            if job.is_cancelled() {
+                counts.cancelled = true;
+                break;
+            }
+            
+            let path_str = path.to_string_lossy().to_string();
+            // What the caller may not change is not entered either
+            if let Err(e) = self.access_control.check_write_access(&path_str).await {
+                counts.failed += 1;
+                counts.first_failure.get_or_insert_with(|| e.to_string());
+                continue;
+            }
+            
+            let (changed, entry_update) = (path.clone(), Arc::clone(&update));
+            match self.io.run(move || set_metadata(&changed, mode, &entry_update)).await {
+                Ok(Ok(())) => {
+                    counts.changed += 1;
+                    self.record_change(ChangeKind::Modified, &path_str, is_dir).await;
+                }
+                Ok(Err(e)) | Err(e) => {
+                    counts.failed += 1;
+                    counts.first_failure.get_or_insert_with(|| format!("{}: {}", path_str, e));
+                }
+            }
+            
+            if is_dir {
+                let listed = path.clone();
+                match self.io.run(move || list_tree_entries(&listed)).await {
+                    Ok(Ok(entries)) => pending.extend(entries.into_iter().rev()),
+                    Ok(Err(e)) | Err(e) => {
+                        counts.failed += 1;
+                        counts.first_failure.get_or_insert_with(|| format!("{}: {}", path_str, e));
+                    }
+                }
+            }
+            
+            if reported.elapsed() >= TREE_PROGRESS_INTERVAL {
+                reported = tokio::time::Instant::now();
+                let update = Message::SetMetadataTreeProgress {
+                    request_id,
+                    progress: counts.clone(),
+                    last: false,
+                    error: None,
+                };
+                // Nobody is left to report to
+                if progress.send(update).is_err() {
+                    counts.cancelled = true;
+                    break;
+                }
+            }
+        }
+        
+        {
+            let mut stats = self.stats.write().await;
+            stats.total_operations += 1;
+        }
+        
+        Message::SetMetadataTreeProgress {
+            request_id,
+            progress: counts,
+            last: true,
+            error: None,
+        }
+    }
+    
+    /// Handle the cancellation of a `SetMetadataTree`, which answers it with
+    /// its last progress message; nothing is sent if it has already ended
+    pub fn handle_cancel_set_metadata_tree(&self, request_id: Uuid) -> Option<Message> {
+        if !self.jobs.cancel(&request_id) {
+            debug!("Cancellation of metadata change {}, which has ended", request_id);
+        }
+        None
+    }
+    
+    /// Handle get xattr operation
+    pub async fn handle_get_xattr(
+        &self,
+        request_id: Uuid,
+        path: String,
+        name: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "get_xattr", &path).await;
+        
+        let result = async {
+            self.access_control.check_read_access(&path).await?;
+            
+            let (xattr_path, xattr_name) = (path.clone(), name.clone());
+            let value = self.io.run(move || {
+                let path_buf = existing_path(&xattr_path)?;
+                xattr::read(&path_buf, &xattr_name)
+                    .map_err(|e| xattr_error(e, &xattr_name, &xattr_path))
+            }).await??;
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::GetXattrResponse {
+                request_id,
+                success: true,
+                value,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::GetXattrResponse {
+                    request_id,
+                    success: false,
+                    value: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle set xattr operation
+    pub async fn handle_set_xattr(
+        &self,
+        request_id: Uuid,
+        path: String,
+        name: String,
+        value: Vec<u8>,
+        mode: XattrSetMode,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "set_xattr", &path).await;
+        
+        let result = async {
+            self.access_control.check_write_access(&path).await?;
+            
+            let (xattr_path, xattr_name) = (path.clone(), name.clone());
+            let is_dir = self.io.run(move || {
+                let path_buf = existing_path(&xattr_path)?;
+                xattr::write(&path_buf, &xattr_name, &value, mode)
+                    .map_err(|e| xattr_error(e, &xattr_name, &xattr_path))?;
+                Ok::<_, RemoteFsError>(path_buf.is_dir())
+            }).await??;
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Modified, &path, is_dir).await;
+            
+            Ok(Message::SetXattrResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::SetXattrResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle list xattr operation
+    pub async fn handle_list_xattr(
+        &self,
+        request_id: Uuid,
+        path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "list_xattr", &path).await;
+        
+        let result = async {
+            self.access_control.check_read_access(&path).await?;
+            
+            let xattr_path = path.clone();
+            let names = self.io.run(move || {
+                let path_buf = existing_path(&xattr_path)?;
+                xattr::list_names(&path_buf)
+                    .map_err(|e| RemoteFsError::io(&format!("Failed to list attributes of {}", xattr_path), e))
+            }).await??;
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::ListXattrResponse {
+                request_id,
+                success: true,
+                names: Some(names),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::ListXattrResponse {
+                    request_id,
+                    success: false,
+                    names: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle remove xattr operation
+    pub async fn handle_remove_xattr(
+        &self,
+        request_id: Uuid,
+        path: String,
+        name: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "remove_xattr", &path).await;
+        
+        let result = async {
+            self.access_control.check_write_access(&path).await?;
+            
+            let (xattr_path, xattr_name) = (path.clone(), name.clone());
+            let is_dir = self.io.run(move || {
+                let path_buf = existing_path(&xattr_path)?;
+                xattr::remove(&path_buf, &xattr_name)
+                    .map_err(|e| xattr_error(e, &xattr_name, &xattr_path))?;
+                Ok::<_, RemoteFsError>(path_buf.is_dir())
+            }).await??;
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Modified, &path, is_dir).await;
+            
+            Ok(Message::RemoveXattrResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::RemoveXattrResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a file create; the mode is applied to a created file as given,
+    /// whatever the agent's umask, less any bits `allowed_mode` drops
+    pub async fn handle_create_file(
+        &self,
+        request_id: Uuid,
+        path: String,
+        mode: u32,
+        exclusive: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "create_file", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            let path_buf = PathBuf::from(&path);
+            
+            // Opening an existing file for writing needs write access
+            if !exclusive && self.metadata(&path_buf).await.is_some() {
+                self.access_control.check_write_access(&path).await?;
+            } else {
+                self.access_control.check_create_access(&path).await?;
+            }
+            
+            let mode = self.access_control.permitted_mode(mode);
+            let created_path = path_buf.clone();
+            let (metadata, created) = self.io.run(move || create_file(&created_path, mode, exclusive)).await??;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            if created {
+                self.record_change(ChangeKind::Created, &path, false).await;
+            }
+            
+            Ok(Message::CreateFileResponse {
+                request_id,
+                success: true,
+                metadata: Some(self.ids.to_remote(FileMetadata::from_fs(&metadata, &path_buf))),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::CreateFileResponse {
+                    request_id,
+                    success: false,
+                    metadata: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle create directory operation
+    ///
+    /// The response carries the directory's metadata, with the mode it got.
+    pub async fn handle_create_directory(
+        &self,
+        request_id: Uuid,
+        path: String,
+        mode: u32,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "create_directory", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_create_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            let mode = self.access_control.permitted_mode(mode);
+            let created_path = path_buf.clone();
+            let (existed, metadata) = self.io.run(move || {
+                let existed = create_directory(&created_path, mode)
+                    .map_err(|e| RemoteFsError::io("Failed to create directory", e))?;
+                let metadata = fs::metadata(&created_path)
+                    .map_err(|e| RemoteFsError::io("Failed to read directory metadata", e))?;
+                Ok::<_, RemoteFsError>((existed, metadata))
+            }).await??;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            if !existed {
+                self.record_change(ChangeKind::Created, &path, true).await;
+            }
+            
+            Ok(Message::CreateDirectoryResponse {
+                request_id,
+                success: true,
+                metadata: Some(self.ids.to_remote(FileMetadata::from_fs(&metadata, &path_buf))),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::CreateDirectoryResponse {
+                    request_id,
+                    success: false,
+                    metadata: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle delete file operation
+    pub async fn handle_delete_file(
+        &self,
+        request_id: Uuid,
+        path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "delete_file", &path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_delete_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            
+            // Check if path exists and is a file
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+            
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
+            }
+            
+            // Delete file
+            self.remove_entry(path_buf, metadata.len(), "Failed to delete file", |path| fs::remove_file(path)).await?;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Deleted, &path, false).await;
+            
+            Ok(Message::DeleteFileResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::DeleteFileResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle truncate operation, cutting a file down or extending it
+    /// with zeros to `size` in place
+    pub async fn handle_truncate_file(
+        &self,
+        request_id: Uuid,
+        path: String,
+        size: u64,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "truncate_file", &path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_write_access(&path).await?;
+            self.access_control.check_file_size(size).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+            
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
+            }
+            
+            let charge = self.charge_quota(|change| change.resize(&path_buf, metadata.len(), size))?;
+            
+            self.io.run(move || {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path_buf)
+                    .and_then(|file| file.set_len(size))
+                    .map_err(|e| RemoteFsError::io("Failed to truncate file", e))
+            }).await??;
+            charge.commit();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Modified, &path, false).await;
+            
+            Ok(Message::TruncateFileResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::TruncateFileResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle delete directory operation
+    pub async fn handle_delete_directory(
+        &self,
+        request_id: Uuid,
+        path: String,
+        recursive: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "delete_directory", &path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_delete_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            
+            // Check if path exists and is a directory
+            let metadata = self.metadata(&path_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Directory not found: {}", path)))?;
+            
+            if !metadata.is_dir() {
+                return Err(not_a_directory(&path));
+            }
+            
+            // Without `recursive` only an empty directory goes to the trash
+            if !recursive && self.trash.is_some() {
+                let dir = path_buf.clone();
+                let empty = self.io.run(move || fs::read_dir(dir).map(|mut entries| entries.next().is_none())).await?
+                    .map_err(|e| RemoteFsError::io("Failed to delete directory", e))?;
+                if !empty {
+                    let e = std::io::Error::from_raw_os_error(libc::ENOTEMPTY);
+                    return Err(RemoteFsError::io("Failed to delete directory", e));
+                }
+            }
+            
+            // Delete directory
+            let size = if recursive { self.quota_size(&path_buf, &metadata, None).await? } else { 0 };
+            self.remove_entry(path_buf, size, "Failed to delete directory", move |path| if recursive {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_dir(path)
+            }).await?;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Deleted, &path, true).await;
+            
+            Ok(Message::RemoveDirectoryResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::RemoveDirectoryResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a request to put back the entry most recently deleted from a
+    /// path
+    pub async fn handle_restore_from_trash(&self, request_id: Uuid, path: String) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        self.start_operation(operation_id, "restore_from_trash", &path).await;
+        
+        let result = async {
+            self.access_control.check_create_access(&path).await?;
+            
+            let trash = self.trash.clone()
+                .ok_or_else(|| RemoteFsError::NotImplemented("This agent does not keep a trash".to_string()))?;
+            let path_buf = PathBuf::from(&path);
+            let not_found = || RemoteFsError::NotFound(format!("Nothing deleted from {} is in the trash", path));
+            let root = self.trash_root(&path_buf)
+                .filter(|root| trash.keeps(root, &path_buf))
+                .ok_or_else(not_found)?;
+            
+            let (lookup, dir, wanted) = (Arc::clone(&trash), trash.dir(&root), path_buf.clone());
+            let entry = self.io.run(move || lookup.latest(&root, &wanted)).await?
+                .map_err(|e| RemoteFsError::io("Failed to read the trash", e))?
+                .ok_or_else(not_found)?;
+            let metadata = self.metadata(&entry.file).await.ok_or_else(not_found)?;
+            
+            // Restoring counts against quotas the trash is outside of
+            let size = self.quota_size(&entry.file, &metadata, Some(&path_buf)).await?;
+            let charge = self.charge_quota(|change| change.moved(&dir, &path_buf, size))?;
+            self.io.run(move || trash.restore(&entry)).await?
+                .map_err(|e| RemoteFsError::io("Failed to restore from the trash", e))?;
+            charge.commit();
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Created, &path, metadata.is_dir()).await;
+            
+            Ok(Message::RestoreFromTrashResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::RestoreFromTrashResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a request to delete for good what was deleted from a path or
+    /// below it
+    pub async fn handle_purge_trash(&self, request_id: Uuid, path: String) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        self.start_operation(operation_id, "purge_trash", &path).await;
+        
+        let result = async {
+            self.access_control.check_delete_access(&path).await?;
+            
+            let trash = self.trash.clone()
+                .ok_or_else(|| RemoteFsError::NotImplemented("This agent does not keep a trash".to_string()))?;
+            let under = PathBuf::from(&path);
+            let purged = match self.trash_root(&under) {
+                Some(root) => self.purge_trash_entries(&trash, root, move |entry| entry.path.starts_with(&under)).await?,
+                None => 0,
+            };
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::PurgeTrashResponse {
+                request_id,
+                success: true,
+                purged,
+                error: None,
+            })
+        }.await;
+        
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::PurgeTrashResponse {
+                    request_id,
+                    success: false,
+                    purged: 0,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle move file operation
+    pub async fn handle_move_file(
+        &self,
+        request_id: Uuid,
+        source_path: String,
+        dest_path: String,
+        no_replace: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "move_file", &source_path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_read_access(&source_path).await?;
+            self.access_control.check_create_access(&dest_path).await?;
+            self.access_control.check_delete_access(&source_path).await?;
+            
+            let source_buf = PathBuf::from(&source_path);
+            let dest_buf = PathBuf::from(&dest_path);
+            
+            // Check if source exists
+            let metadata = self.metadata(&source_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Source not found: {}", source_path)))?;
+            
+            // A file at the destination is replaced, unless asked not to
+            let moved = self.quota_size(&source_buf, &metadata, Some(&dest_buf)).await?;
+            let replaced = self.metadata(&dest_buf).await.filter(|metadata| metadata.is_file()).map_or(0, |metadata| metadata.len());
+            let charge = self.charge_quota(|change| {
+                change.resize(&dest_buf, replaced, 0);
+                change.moved(&source_buf, &dest_buf, moved);
+            })?;
+            
+            self.io.run(move || {
+                // Create destination directory if needed
+                if let Some(parent) = dest_buf.parent().filter(|parent| !parent.exists()) {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| RemoteFsError::io("Failed to create destination directories", e))?;
+                }
+                
+                // Move file/directory
+                let result = if no_replace {
+                    rename_no_replace(&source_buf, &dest_buf)
+                } else {
+                    fs::rename(&source_buf, &dest_buf)
+                };
+                result.map_err(|e| RemoteFsError::io("Failed to move", e))
+            }).await??;
+            charge.commit();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            let kind = ChangeKind::Renamed { from: source_path.clone() };
+            self.record_change(kind, &dest_path, metadata.is_dir()).await;
+            
+            Ok(Message::RenameResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::RenameResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle symlink creation
+    ///
+    /// The target is stored as given; paths through the link are checked
+    /// where it leads, as they are for any other symlink.
+    pub async fn handle_create_symlink(
+        &self,
+        request_id: Uuid,
+        link_path: String,
+        target_path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "create_symlink", &link_path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_create_access(&link_path).await?;
+            
+            let link_buf = PathBuf::from(&link_path);
+            self.io.run(move || {
+                std::os::unix::fs::symlink(&target_path, &link_buf)
+                    .map_err(|e| RemoteFsError::io("Failed to create symlink", e))
+            }).await??;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Created, &link_path, false).await;
+            
+            Ok(Message::CreateSymlinkResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::CreateSymlinkResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle hard link creation
+    ///
+    /// The link gives access to the file's data under the rules of its own
+    /// path, so the caller needs read and write access to the file as well
+    /// as create access where the link goes. Missing parents are not created.
+    pub async fn handle_create_hard_link(
+        &self,
+        request_id: Uuid,
+        existing_path: String,
+        link_path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "create_hard_link", &link_path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_read_access(&existing_path).await?;
+            self.access_control.check_write_access(&existing_path).await?;
+            self.access_control.check_create_access(&link_path).await?;
+            
+            let existing_buf = PathBuf::from(&existing_path);
+            let link_buf = PathBuf::from(&link_path);
+            
+            let metadata = self.metadata(&existing_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("File not found: {}", existing_path)))?;
+            
+            // Quotas count the data once under every name it has
+            let charge = self.charge_quota(|change| change.resize(&link_buf, 0, metadata.len()))?;
+            
+            self.io.run(move || {
+                fs::hard_link(&existing_buf, &link_buf)
+                    .map_err(|e| RemoteFsError::io("Failed to create hard link", e))
+            }).await??;
+            charge.commit();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            self.record_change(ChangeKind::Created, &link_path, false).await;
+            
+            Ok(Message::CreateHardLinkResponse {
+                request_id,
+                success: true,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::CreateHardLinkResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a symlink read, answering with the target as stored in the
+    /// link rather than resolved; the link is checked as any read of its
+    /// path is
+    pub async fn handle_read_symlink(
+        &self,
+        request_id: Uuid,
+        path: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "read_symlink", &path).await;
+        
+        let result = async {
+            // Check access to the link itself, wherever it points
+            self.access_control.check_entry_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            let target = self.io.run(move || {
+                let metadata = fs::symlink_metadata(&path_buf)
+                    .map_err(|e| RemoteFsError::io("Failed to read symlink", e))?;
+                if !metadata.is_symlink() {
+                    return Err(RemoteFsError::InvalidPath(format!("Path is not a symlink: {}", path_buf.display())));
+                }
+                fs::read_link(&path_buf).map_err(|e| RemoteFsError::io("Failed to read symlink", e))
+            }).await??;
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::ReadSymlinkResponse {
+                request_id,
+                success: true,
+                target: Some(target.to_string_lossy().to_string()),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::ReadSymlinkResponse {
+                    request_id,
+                    success: false,
+                    target: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a transaction: apply every operation or, if one fails, none
+    ///
+    /// All access checks run before anything is changed, so a transaction
+    /// refused for permissions leaves no trace at all.
+    pub async fn handle_transaction(
+        &self,
+        request_id: Uuid,
+        operations: Vec<TransactionOp>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        let first_path = operations.first().map(transaction_path).unwrap_or_default().to_string();
+        
+        // Track operation
+        self.start_operation(operation_id, "transaction", &first_path).await;
+        
+        let result: Result<Message, (Option<u32>, RemoteFsError)> = async {
+            if operations.len() > MAX_TRANSACTION_OPERATIONS {
+                return Err((None, RemoteFsError::Protocol(format!(
+                    "Transaction has {} operations; at most {} are allowed",
+                    operations.len(), MAX_TRANSACTION_OPERATIONS
+                ))));
+            }
+            
+            for (index, operation) in operations.iter().enumerate() {
+                self.check_transaction_access(operation).await.map_err(|e| (Some(index as u32), e))?;
+            }
+            
+            let bytes: u64 = operations.iter()
+                .map(|operation| match operation {
+                    TransactionOp::WriteFile { data, .. } => data.len() as u64,
+                    _ => 0,
+                })
+                .sum();
+            let _permit = match self.reserve(request_id, &first_path, bytes) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let quotas = self.quotas.clone();
+            let (charge, changes) = self.io.run(move || {
+                let charge = match quotas {
+                    Some(quotas) => quotas.charge(quotas.transaction_change(&operations)).map_err(|e| (None, e))?,
+                    None => QuotaCharge::default(),
+                };
+                Ok((charge, apply_transaction(request_id, &operations)?))
+            })
+                .await
+                .map_err(|e| (None, e))??;
+            charge.commit();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_written += bytes;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += bytes;
+            }
+            
+            for (kind, path, is_dir) in changes {
+                self.record_change(kind, &path, is_dir).await;
+            }
+            
+            Ok(Message::TransactionResponse {
+                request_id,
+                success: true,
+                failed_operation: None,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err((failed_operation, e)) => {
+                self.record_error().await;
+                Some(Message::TransactionResponse {
+                    request_id,
+                    success: false,
+                    failed_operation,
+                    error: Some(e.to_string()),
+                })
+            }
+        }
+    }
+    
+    /// Check one transaction step with the same rules as the matching
+    /// single request
+    async fn check_transaction_access(&self, operation: &TransactionOp) -> Result<(), RemoteFsError> {
+        match operation {
+            TransactionOp::WriteFile { path, data } => {
+                if self.metadata(Path::new(path)).await.is_some() {
+                    self.access_control.check_write_access(path).await?;
+                } else {
+                    self.access_control.check_create_access(path).await?;
+                }
+                self.access_control.check_file_size(data.len() as u64).await
+            }
+            TransactionOp::Rename { from_path, to_path } => {
+                self.access_control.check_read_access(from_path).await?;
+                self.access_control.check_create_access(to_path).await?;
+                self.access_control.check_delete_access(from_path).await
+            }
+            TransactionOp::DeleteFile { path } | TransactionOp::RemoveDirectory { path } => {
+                self.access_control.check_delete_access(path).await
+            }
+            TransactionOp::CreateDirectory { path } => self.access_control.check_create_access(path).await,
+        }
+    }
+    
+    /// Handle a batch create: write many small files in one request
+    ///
+    /// Each file succeeds or fails on its own and failures are reported by
+    /// position. Access to every file is checked first; the permitted files
+    /// are then written on one blocking thread instead of a task each.
+    pub async fn handle_batch_create_files(
+        &self,
+        request_id: Uuid,
+        files: Vec<NewFile>,
+        overwrite: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        let first_path = files.first().map(|file| file.path.clone()).unwrap_or_default();
+        
+        // Track operation
+        self.start_operation(operation_id, "batch_create", &first_path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            if files.len() > MAX_BATCH_FILES {
+                return Err(RemoteFsError::Protocol(format!(
+                    "Batch has {} files; at most {} are allowed",
+                    files.len(), MAX_BATCH_FILES
+                )));
+            }
+            
+            let mut failures = Vec::new();
+            let mut permitted = Vec::with_capacity(files.len());
+            let mut charges = HashMap::new();
+            for (index, mut file) in files.into_iter().enumerate() {
+                file.mode = file.mode.map(|mode| self.access_control.permitted_mode(mode));
+                match self.check_batch_access(&file, overwrite).await {
+                    Ok(charge) => {
+                        charges.insert(index as u32, charge);
+                        permitted.push((index as u32, file));
+                    }
+                    Err(e) => failures.push(BatchFailure { index: index as u32, error: e.to_string() }),
+                }
+            }
+            
+            let bytes = permitted.iter().map(|(_, file)| file.data.len() as u64).sum();
+            let _permit = match self.reserve(request_id, &first_path, bytes) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let written = self.io.run(move || {
+                permitted.into_iter()
+                    .map(|(index, file)| {
+                        let result = create_batch_file(&file, overwrite);
+                        (index, file.path, file.data.len() as u64, result)
+                    })
+                    .collect::<Vec<_>>()
+            }).await?;
+            
+            let mut created = 0;
+            let mut bytes_written = 0;
+            for (index, path, len, result) in written {
+                match result {
+                    Ok(kind) => {
+                        created += 1;
+                        bytes_written += len;
+                        if let Some(charge) = charges.remove(&index) {
+                            charge.commit();
+                        }
+                        self.record_change(kind, &path, false).await;
+                    }
+                    Err(e) => failures.push(BatchFailure { index, error: format!("Failed to create {}: {}", path, e) }),
+                }
+            }
+            failures.sort_by_key(|failure| failure.index);
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_written += bytes_written;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += bytes_written;
+            }
+            
+            Ok(Message::BatchCreateFilesResponse {
+                request_id,
+                created,
+                failures,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::BatchCreateFilesResponse {
+                    request_id,
+                    created: 0,
+                    failures: Vec::new(),
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Check one file of a batch with the same rules as a single write, and
+    /// count it against the quotas
+    async fn check_batch_access(&self, file: &NewFile, overwrite: bool) -> Result<QuotaCharge, RemoteFsError> {
+        let path = Path::new(&file.path);
+        let existing = self.metadata(path).await;
+        if existing.is_some() {
+            if !overwrite {
+                return Err(RemoteFsError::AlreadyExists(file.path.clone()));
+            }
+            self.access_control.check_write_access(&file.path).await?;
+        } else {
+            self.access_control.check_create_access(&file.path).await?;
+        }
+        self.access_control.check_file_size(file.data.len() as u64).await?;
+        
+        let replaced = existing.map_or(0, |metadata| metadata.len());
+        self.charge_quota(|change| change.resize(path, replaced, file.data.len() as u64))
+    }
+    
+    /// Run a whitelisted command, streaming its output to `output`
+    ///
+    /// The command runs in its configured directory, or else in
+    /// `working_dir`, which the caller needs write access to. It runs in the
+    /// background so other requests on the connection are not held up;
+    /// refusals are answered straight away.
+    #[cfg_attr(not(feature = "remote-exec"), allow(unused_variables))]
+    pub async fn handle_extended_operation(
+        &self,
+        request_id: Uuid,
+        name: String,
+        arguments: Vec<String>,
+        working_dir: Option<String>,
+        output: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "extended_operation", working_dir.as_deref().unwrap_or_default()).await;
+        
+        let result = self.prepare_extended_operation(&name, &arguments, working_dir).await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        let error = match result {
+            #[cfg(feature = "remote-exec")]
+            Ok((command, working_dir)) => {
+                let stats = Arc::clone(&self.stats);
+                let output = output.clone();
+                tokio::spawn(async move {
+                    let last = crate::exec::run(request_id, &command, &arguments, &working_dir, &output).await;
+                    {
+                        let mut stats = stats.write().await;
+                        stats.total_operations += 1;
+                        if !matches!(last, Message::ExtendedOutput { exit_code: Some(0), error: None, .. }) {
+                            stats.error_count += 1;
+                        }
+                    }
+                    let _ = output.send(last);
+                }.in_current_span());
+                return None;
+            }
+            #[cfg(not(feature = "remote-exec"))]
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+        
+        self.record_error().await;
+        Some(Message::ExtendedOutput {
+            request_id,
+            sequence: 0,
+            stream: OutputStream::Stdout,
+            data: Vec::new(),
+            last: true,
+            exit_code: None,
+            error: Some(error.to_string()),
+        })
+    }
+    
+    /// The whitelisted command to run and the directory to run it in
+    #[cfg(feature = "remote-exec")]
+    async fn prepare_extended_operation(
+        &self,
+        name: &str,
+        arguments: &[String],
+        working_dir: Option<String>,
+    ) -> Result<(ExecCommandConfig, PathBuf), RemoteFsError> {
+        let command = self.exec.as_ref()
+            .ok_or_else(|| RemoteFsError::AccessDenied("Remote execution is disabled on this agent".to_string()))?
+            .command(name)
+            .ok_or_else(|| RemoteFsError::AccessDenied(format!("{} is not a whitelisted command", name)))?;
+        
+        if !arguments.is_empty() && !command.allow_arguments {
+            return Err(RemoteFsError::AccessDenied(format!("{} does not accept arguments", name)));
+        }
+        
+        let working_dir = match (&command.working_dir, working_dir) {
+            (Some(_), Some(_)) => {
+                return Err(RemoteFsError::AccessDenied(format!("{} always runs in its configured directory", name)));
+            }
+            (Some(configured), None) => configured.clone(),
+            (None, Some(requested)) => {
+                self.access_control.check_write_access(&requested).await?;
+                if !self.metadata(Path::new(&requested)).await.is_some_and(|metadata| metadata.is_dir()) {
+                    return Err(RemoteFsError::NotFound(format!("Directory not found: {}", requested)));
+                }
+                PathBuf::from(requested)
+            }
+            (None, None) => {
+                return Err(RemoteFsError::Protocol(format!("{} needs a working directory", name)));
+            }
+        };
+        
+        Ok((command.clone(), working_dir))
+    }
+    
+    #[cfg(not(feature = "remote-exec"))]
+    async fn prepare_extended_operation(
+        &self,
+        _name: &str,
+        _arguments: &[String],
+        _working_dir: Option<String>,
+    ) -> Result<std::convert::Infallible, RemoteFsError> {
+        Err(RemoteFsError::AccessDenied("This agent was built without remote execution support".to_string()))
+    }
+    
+    /// Handle a copy made on the agent
+    ///
+    /// With `offset` 0 and no `length` the whole source replaces the
+    /// destination. Otherwise the range is written into the destination at
+    /// the same offset, creating it if missing.
+    pub async fn handle_copy_file(
+        &self,
+        request_id: Uuid,
+        source_path: String,
+        dest_path: String,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "copy_file", &source_path).await;
+        
+        let result = async {
+            // Check access permissions
+            self.access_control.check_read_access(&source_path).await?;
+            let source_buf = PathBuf::from(&source_path);
+            let dest_buf = PathBuf::from(&dest_path);
+            
+            // An existing destination is overwritten, or written over in part
+            let existing = self.metadata(&dest_buf).await;
+            if existing.is_some() {
+                self.access_control.check_write_access(&dest_path).await?;
+            } else {
+                self.access_control.check_create_access(&dest_path).await?;
+            }
+            
+            // Check if source exists and is a file
+            let metadata = self.metadata(&source_buf).await
+                .ok_or_else(|| RemoteFsError::NotFound(format!("Source not found: {}", source_path)))?;
+            
+            if !metadata.is_file() {
+                return Err(RemoteFsError::InvalidPath(format!("Source is not a file: {}", source_path)));
+            }
+            
+            // Size the destination will have, for the file size limit and quotas
+            let whole = offset == 0 && length.is_none();
+            let source_size = metadata.len();
+            let end = length.map_or(source_size, |length| offset.saturating_add(length).min(source_size));
+            let replaced = existing.map_or(0, |metadata| metadata.len());
+            let file_size = if whole {
+                source_size
+            } else if end > offset {
+                replaced.max(end)
+            } else {
+                replaced
+            };
+            self.access_control.check_file_size(file_size).await?;
+            let charge = self.charge_quota(|change| change.resize(&dest_buf, replaced, file_size))?;
+            
+            let target = dest_buf.clone();
+            let (copied, dest_existed, dest_metadata) = self.io.run(move || {
+                // Create destination directory if needed
+                if let Some(parent) = target.parent().filter(|parent| !parent.exists()) {
+                    fs::create_dir_all(parent)
+                        .map_err(|e| RemoteFsError::io("Failed to create destination directories", e))?;
+                }
+                
+                // Copying a file onto itself would truncate it first
+                let dest_existed = match fs::metadata(&target) {
+                    Ok(existing) => {
+                        let source = fs::metadata(&source_buf)
+                            .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+                        if (existing.dev(), existing.ino()) == (source.dev(), source.ino()) {
+                            return Err(RemoteFsError::InvalidPath(format!(
+                                "Source and destination are the same file: {}", target.display()
+                            )));
+                        }
+                        true
+                    }
+                    Err(_) => false,
+                };
+                
+                let copied = if whole {
+                    fs::copy(&source_buf, &target)
+                        .map_err(|e| RemoteFsError::io("Failed to copy file", e))?
+                } else {
+                    let mut source = File::open(&source_buf)
+                        .map_err(|e| RemoteFsError::io("Failed to open source", e))?;
+                    let mut dest = OpenOptions::new().write(true).create(true).truncate(false).open(&target)
+                        .map_err(|e| RemoteFsError::io("Failed to open destination", e))?;
+                    source.seek(SeekFrom::Start(offset))
+                        .and_then(|_| dest.seek(SeekFrom::Start(offset)))
+                        .and_then(|_| std::io::copy(&mut source.take(end.saturating_sub(offset)), &mut dest))
+                        .map_err(|e| RemoteFsError::io("Failed to copy file", e))?
+                };
+                let dest_metadata = fs::metadata(&target)
+                    .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+                Ok::<_, RemoteFsError>((copied, dest_existed, dest_metadata))
+            }).await??;
+            charge.commit();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_read += copied;
+                stats.bytes_written += copied;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_read += copied;
+                perf_stats.bytes_written += copied;
+            }
+            
+            let kind = if dest_existed { ChangeKind::Modified } else { ChangeKind::Created };
+            self.record_change(kind, &dest_path, false).await;
+            
+            Ok(Message::CopyFileResponse {
+                request_id,
+                success: true,
+                copied,
+                metadata: Some(self.ids.to_remote(FileMetadata::from_fs(&dest_metadata, &dest_buf))),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::CopyFileResponse {
+                    request_id,
+                    success: false,
+                    copied: 0,
+                    metadata: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a read of a file as it was at `as_of`
+    ///
+    /// No file contents are retained, so this is served from the current
+    /// file when the change journal shows it (and its ancestors) unchanged
+    /// since `as_of`, and their times agree, and refused otherwise.
+    pub async fn handle_read_file_as_of(
+        &self,
+        request_id: Uuid,
+        path: String,
+        offset: u64,
+        length: u32,
+        as_of: DateTime<Utc>,
+    ) -> Option<Message> {
+        let unchanged: Result<(), RemoteFsError> = async {
+            // Checked first so refusals do not reveal history of hidden paths
+            if !self.access_control.is_readable(&path).await {
+                return Err(RemoteFsError::AccessDenied(format!("Read access denied: {}", path)));
+            }
+            
+            let journal = self.journal.as_ref().ok_or_else(|| {
+                RemoteFsError::NotImplemented("Change journal is not enabled".to_string())
+            })?;
+            
+            match journal.unchanged_since(&path, as_of).await {
+                Some(true) => {}
+                Some(false) => return Err(RemoteFsError::NotFound(format!(
+                    "No version of {} is retained as of {}", path, as_of
+                ))),
+                None => return Err(RemoteFsError::NotFound(format!(
+                    "Change journal does not reach back to {}", as_of
+                ))),
+            }
+            
+            // The journal only sees changes made through the agent; those
+            // made on the host show in the times of the file or above it
+            let path_buf = PathBuf::from(&path);
+            let root = self.access_control.root_of(&path).unwrap_or_else(|| path_buf.clone());
+            if self.io.run(move || changed_since(&path_buf, &root, as_of)).await? {
+                return Err(RemoteFsError::NotFound(format!(
+                    "{} has changed outside the agent since {}", path, as_of
+                )));
+            }
+            Ok(())
+        }.await;
+        
+        match unchanged {
+            Ok(()) => self.handle_read_file(request_id, path, Some(offset), Some(length as u64)).await,
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::ReadFileResponse {
+                    request_id,
+                    success: false,
+                    data: None,
+                    bytes_read: 0,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a read of a file's content, metadata and extended attributes
+    ///
+    /// Symlinks are not followed: their target is part of the metadata.
+    /// Ownership is reported as the real uid and gid so backups can restore it.
+    pub async fn handle_read_backup_entry(&self, request_id: Uuid, path: String) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "read_backup_entry", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            // Check access permissions
+            self.access_control.check_read_access(&path).await?;
+            
+            let path_buf = PathBuf::from(&path);
+            let (stat_path, xattr_path) = (path_buf.clone(), path_buf.clone());
+            let metadata = self.io.run(move || fs::symlink_metadata(&stat_path)).await?.map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => RemoteFsError::NotFound(format!("Path not found: {}", path)),
+                _ => RemoteFsError::io("Failed to read metadata", e),
+            })?;
+            
+            // Never hand out an archiver's stub
+            if let Some(archive) = &self.archive {
+                if metadata.is_file() && archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
+                }
+            }
+            
+            let mut file_metadata = FileMetadata::from_fs(&metadata, &path_buf);
+            file_metadata.uid = metadata.uid();
+            file_metadata.gid = metadata.gid();
+            
+            let xattrs = self.io.run(move || xattr::read_all(&xattr_path)).await?
+                .map_err(|e| RemoteFsError::io("Failed to read extended attributes", e))?;
+            
+            let size = if metadata.is_file() { metadata.len() } else { 0 };
+            let _permit = match self.reserve(request_id, &path, size) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let data = if metadata.is_file() {
+                self.io.run(move || fs::read(&path_buf)).await?
+                    .map_err(|e| RemoteFsError::io("Failed to read file", e))?
+            } else {
+                Vec::new()
+            };
+            self.charge_read(data.len() as u64);
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_read += data.len() as u64;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_read += data.len() as u64;
+            }
+            
+            Ok(Message::ReadBackupEntryResponse {
+                request_id,
+                success: true,
+                entry: Some(BackupEntry {
+                    path: path.clone(),
+                    metadata: file_metadata,
+                    xattrs,
+                    data,
+                }),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::ReadBackupEntryResponse {
+                    request_id,
+                    success: false,
+                    entry: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle change journal query
+    ///
+    /// Changes to paths the caller may not read are left out, but still
+    /// advance the cursor.
+    pub async fn handle_get_changes(
+        &self,
+        request_id: Uuid,
+        since: u64,
+        limit: Option<u32>,
+    ) -> Option<Message> {
+        let Some(journal) = &self.journal else {
+            return Some(Message::GetChangesResponse {
+                request_id,
+                success: false,
+                changes: None,
+                error: Some(RemoteFsError::NotImplemented("Change journal is not enabled".to_string()).to_string()),
+            });
+        };
+        
+        let limit = limit.map(|l| l as usize).unwrap_or(MAX_CHANGES_PER_REQUEST).min(MAX_CHANGES_PER_REQUEST);
+        let mut changes = journal.changes_since(since, limit).await;
+        let mut visible = Vec::with_capacity(changes.changes.len());
+        for change in changes.changes {
+            if self.access_control.is_readable(&change.path).await {
+                visible.push(change);
+            }
+        }
+        changes.changes = visible;
+        
+        Some(Message::GetChangesResponse {
+            request_id,
+            success: true,
+            changes: Some(changes),
+            error: None,
+        })
+    }
+    
+    /// Handle a watch request
+    ///
+    /// The `WatchResponse` and the changes that follow are sent through
+    /// `notifications`, the connection the request came in on.
+    pub async fn handle_watch(
+        &self,
+        request_id: Uuid,
+        path: String,
+        recursive: bool,
+        notifications: &mpsc::UnboundedSender<Message>,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "watch", &path).await;
+        
+        let result = async {
+            self.access_control.check_read_access(&path).await?;
+            let path_buf = existing_path(&path)?;
+            
+            self.watcher.watch(
+                request_id,
+                path_buf,
+                recursive,
+                Arc::clone(&self.access_control),
+                notifications.clone(),
+            ).await?;
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok::<(), RemoteFsError>(())
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(()) => None,
+            Err(e) => {
+                self.record_error().await;
+                Some(Message::Error {
+                    request_id: Some(request_id),
+                    code: e.to_error_code(),
+                    message: e.to_string(),
+                    details: None,
+                    errno: None,
+                })
+            }
+        }
+    }
+    
+    /// Handle the end of a watch; its client is told it ended even if it
+    /// had already
+    pub async fn handle_unwatch(&self, request_id: Uuid) -> Option<Message> {
+        if self.watcher.unwatch(request_id) {
+            debug!("Stopped watch {}", request_id);
+        }
+        Some(Message::WatchEnded { request_id, error: None })
+    }
+    
+    /// Stop every watch, after the connection their changes went to closed
+    pub fn stop_watches(&self) {
+        self.watcher.stop_all();
+    }
+    
+    /// Number of watches running
+    pub fn watch_count(&self) -> usize {
+        self.watcher.watch_count()
+    }
+    
+    /// Handle a lock request, which is answered at once whether or not the
+    /// lock was taken
+    pub async fn handle_lock_file(&self, request_id: Uuid, path: String, lock: FileLock) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "lock", &path).await;
+        
+        let result = async {
+            let path_buf = self.lockable_path(&path, lock.lock_type).await?;
+            let conflict = self.locks.lock(&path_buf, lock).err();
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok::<_, RemoteFsError>(conflict)
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(conflict) => Some(Message::LockFileResponse {
+                request_id,
+                success: true,
+                conflict,
+                error: None,
+            }),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::LockFileResponse {
+                    request_id,
+                    success: false,
+                    conflict: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle an unlock request; releasing bytes that were not locked is
+    /// not an error
+    pub async fn handle_unlock_file(
+        &self,
+        request_id: Uuid,
+        path: String,
+        owner: LockOwner,
+        start: u64,
+        length: u64,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "unlock", &path).await;
+        
+        let result = async {
+            let path_buf = self.lockable_path(&path, LockType::Read).await?;
+            let end = match length {
+                0 => None,
+                length => start.checked_add(length),
+            };
+            self.locks.unlock(&path_buf, &owner, start, end);
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok::<(), RemoteFsError>(())
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(()) => Some(Message::UnlockFileResponse {
+                request_id,
+                success: true,
+                error: None,
+            }),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::UnlockFileResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a lock test
+    pub async fn handle_test_lock(&self, request_id: Uuid, path: String, lock: FileLock) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "test_lock", &path).await;
+        
+        let result = async {
+            let path_buf = self.lockable_path(&path, lock.lock_type).await?;
+            let conflict = self.locks.test(&path_buf, &lock);
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok::<_, RemoteFsError>(conflict)
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(conflict) => Some(Message::TestLockResponse {
+                request_id,
+                success: true,
+                conflict,
+                error: None,
+            }),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::TestLockResponse {
+                    request_id,
+                    success: false,
+                    conflict: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Release the locks and close the files of a client the relay reports
+    /// gone
+    pub fn handle_release_locks(&self, session: &str) {
+        let released = self.locks.release_session(session);
+        if released > 0 {
+            debug!("Released {} locks of {}", released, session);
+        }
+        let closed = self.handles.release_session(session);
+        if closed > 0 {
+            debug!("Closed {} files of {}", closed, session);
+        }
+    }
+    
+    /// Release every lock, after the connection of their clients closed
+    pub fn release_all_locks(&self) {
+        self.locks.clear();
+    }
+    
+    /// Number of locks held
+    pub fn lock_count(&self) -> usize {
+        self.locks.lock_count()
+    }
+    
+    /// Handle an open request, keeping the file open under the request's id
+    ///
+    /// Access is checked for what the flags open the file for. The mode is
+    /// applied to a created file as given, as with `CreateFile`.
+    pub async fn handle_open_file(
+        &self,
+        request_id: Uuid,
+        path: String,
+        flags: OpenFlags,
+        mode: u32,
+        session: String,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        // Track operation
+        self.start_operation(operation_id, "open_file", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            let path_buf = PathBuf::from(&path);
+            let existing = self.metadata(&path_buf).await;
+            let existed = existing.is_some();
+            
+            if flags.create && (!existed || flags.exclusive) {
+                self.access_control.check_create_access(&path).await?;
+            } else if flags.writes() {
+                self.access_control.check_write_access(&path).await?;
+            }
+            if flags.read || !flags.writes() {
+                self.access_control.check_read_access(&path).await?;
+            }
+            
+            // Never hand out an archiver's stub
+            if let Some(archive) = &self.archive {
+                if existed && archive.is_offline(&path_buf) {
+                    let recall = archive.recall(&path_buf).await;
+                    return Ok(offline_response(request_id, &path, &recall));
+                }
+            }
+            
+            let mode = self.access_control.permitted_mode(mode);
+            let truncated = existing.filter(|_| flags.truncate && flags.write).map_or(0, |metadata| metadata.len());
+            let charge = self.charge_quota(|change| change.resize(&path_buf, truncated, 0))?;
+            let opened_path = path_buf.clone();
+            let (file, created, metadata) = self.io.run(move || {
+                let (file, created) = open_file(&opened_path, flags, mode)?;
+                let metadata = file.metadata()
+                    .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+                Ok::<_, RemoteFsError>((file, created, metadata))
+            }).await??;
+            charge.commit();
+            if metadata.is_dir() {
+                return Err(RemoteFsError::Os {
+                    code: ErrorCode::IsADirectory,
+                    errno: Some(libc::EISDIR),
+                    message: format!("Path is a directory: {}", path),
+                });
+            }
+            
+            let read_ahead = match &self.prefetcher {
+                Some(prefetcher) if flags.read => ReadAhead::new(Arc::clone(prefetcher)),
+                _ => ReadAhead::disabled(),
+            };
+            let open = OpenHandle {
+                session,
+                path: path.clone(),
+                file,
+                readable: flags.read,
+                writable: flags.write || flags.append,
+                append: flags.append,
+                read_ahead,
+            };
+            if !self.handles.insert(request_id, open) {
+                return Err(RemoteFsError::Os {
+                    code: ErrorCode::InternalError,
+                    errno: Some(libc::EMFILE),
+                    message: format!("Too many open files; a client may hold {} open", MAX_HANDLES_PER_SESSION),
+                });
+            }
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            if created {
+                self.record_change(ChangeKind::Created, &path, false).await;
+            } else if flags.truncate && flags.write {
+                self.record_change(ChangeKind::Modified, &path, false).await;
+            }
+            
+            Ok(Message::OpenFileResponse {
+                request_id,
+                success: true,
+                metadata: Some(self.ids.to_remote(FileMetadata::from_fs(&metadata, &path_buf))),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::OpenFileResponse {
+                    request_id,
+                    success: false,
+                    metadata: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a read through an open handle
+    pub async fn handle_read_handle(
+        &self,
+        request_id: Uuid,
+        handle: Uuid,
+        offset: u64,
+        length: u32,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        let open = self.handles.get(&handle);
+        
+        // Track operation
+        let path = open.as_ref().map_or_else(String::new, |open| open.path.clone());
+        self.start_operation(operation_id, "read_handle", &path).await;
+        
+        let result = async {
+            let open = open.filter(|open| open.readable).ok_or_else(|| bad_handle(handle, "reading"))?;
+            
+            let sized = Arc::clone(&open);
+            let metadata = self.io.run(move || sized.file.metadata()).await?
+                .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+            let to_read = (length as u64).min(metadata.len().saturating_sub(offset));
+            let _permit = match self.reserve(request_id, &open.path, to_read) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let data = match open.read_ahead.take(offset, to_read, Version::of(&metadata)) {
+                Some(data) => data,
+                None => {
+                    let reader = Arc::clone(&open);
+                    self.io.run(move || read_at(&reader.file, offset, to_read))
+                        .await?
+                        .map_err(|e| RemoteFsError::io("Failed to read file", e))?
+                }
+            };
+            
+            if let Some(prefetch) = open.read_ahead.record(offset, length as u64, data.len() as u64) {
+                self.prefetch(open, prefetch);
+            }
+            self.charge_read(data.len() as u64);
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_read += data.len() as u64;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_read += data.len() as u64;
+            }
+            
+            Ok(Message::ReadFileResponse {
+                request_id,
+                success: true,
+                bytes_read: data.len() as u64,
+                data: Some(data),
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::ReadFileResponse {
+                    request_id,
+                    success: false,
+                    data: None,
+                    bytes_read: 0,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a write through an open handle; a handle opened to append
+    /// writes at the end of the file, wherever that is by then
+    pub async fn handle_write_handle(
+        &self,
+        request_id: Uuid,
+        handle: Uuid,
+        offset: u64,
+        data: Vec<u8>,
+        sync: bool,
+    ) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        let open = self.handles.get(&handle);
+        
+        // Track operation
+        let path = open.as_ref().map_or_else(String::new, |open| open.path.clone());
+        self.start_operation(operation_id, "write_handle", &path).await;
+        
+        let result: Result<Message, RemoteFsError> = async {
+            let open = open.filter(|open| open.writable).ok_or_else(|| bad_handle(handle, "writing"))?;
+            
+            // Check file size limit
+            let path_buf = PathBuf::from(&open.path);
+            let len = if open.append || self.quotas.as_ref().is_some_and(|quotas| quotas.covers(&path_buf)) {
+                let sized = Arc::clone(&open);
+                self.io.run(move || sized.file.metadata().map(|metadata| metadata.len()).unwrap_or(0)).await?
+            } else {
+                0
+            };
+            let end = if open.append { len } else { offset };
+            self.access_control.check_file_size(end + data.len() as u64).await?;
+            
+            let _permit = match self.reserve(request_id, &open.path, data.len() as u64) {
+                Ok(permit) => permit,
+                Err(refusal) => return Ok(*refusal),
+            };
+            
+            let written = data.len() as u64;
+            let charge = self.charge_quota(|change| change.resize(&path_buf, len, len.max(end + written)))?;
+            let writer = Arc::clone(&open);
+            self.io.run(move || {
+                if writer.append {
+                    (&writer.file).write_all(&data)?;
+                } else {
+                    writer.file.write_all_at(&data, offset)?;
+                }
+                if sync {
+                    writer.file.sync_data()?;
+                }
+                Ok::<(), std::io::Error>(())
+            })
+                .await?
+                .map_err(|e| RemoteFsError::io("Failed to write file", e))?;
+            charge.commit();
+            open.read_ahead.invalidate();
+            
+            // Update statistics
+            {
+                let mut stats = self.stats.write().await;
+                stats.bytes_written += written;
+                stats.total_operations += 1;
+            }
+            
+            {
+                let mut perf_stats = self.performance_stats.write().await;
+                perf_stats.bytes_written += written;
+            }
+            
+            self.record_change(ChangeKind::Modified, &open.path, false).await;
+            
+            Ok(Message::WriteFileResponse {
+                request_id,
+                success: true,
+                bytes_written: written,
+                error: None,
+            })
+        }.await;
+        
+        // End operation tracking
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::WriteFileResponse {
+                    request_id,
+                    success: false,
+                    bytes_written: 0,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a close; the file stays open until reads and writes already
+    /// using the handle are done
+    pub async fn handle_close_file(&self, request_id: Uuid, handle: Uuid) -> Option<Message> {
+        match self.handles.remove(&handle) {
+            Some(_) => {
+                self.stats.write().await.total_operations += 1;
+                Some(Message::CloseFileResponse {
+                    request_id,
+                    success: true,
+                    error: None,
+                })
+            }
+            None => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, bad_handle(handle, "closing"), |error| Message::CloseFileResponse {
+                    request_id,
+                    success: false,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Close every file, after the connection of their clients closed
+    pub fn close_all_files(&self) {
+        self.handles.clear();
+    }
+    
+    /// Refuse new requests from now on, for a shutdown; handlers derived
+    /// from this one refuse them too
+    pub fn stop_accepting(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+    
+    /// Wait up to `timeout` for the operations in progress to finish,
+    /// returning how many are still running
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let active = self.active_operations.read().await.len();
+            if active == 0 || tokio::time::Instant::now() >= deadline {
+                return active;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    
+    /// Flush the files held open for writing to disk, returning how many
+    /// were flushed
+    pub async fn sync_open_files(&self) -> usize {
+        let writable = self.handles.writable();
+        let synced = self.io.run(move || {
+            writable.iter()
+                .filter(|open| match open.file.sync_all() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Failed to flush {}: {}", open.path, e);
+                        false
+                    }
+                })
+                .count()
+        }).await;
+        synced.unwrap_or_else(|e| {
+            warn!("Failed to flush open files: {}", e);
+            0
+        })
+    }
+    
+    /// Number of files held open
+    pub fn handle_count(&self) -> usize {
+        self.handles.handle_count()
+    }
+    
+    /// Read the blocks of `prefetch` in the background, for later reads
+    /// through `open` to find in memory
+    fn prefetch(&self, open: Arc<OpenHandle>, prefetch: Prefetch) {
+        let io = Arc::clone(&self.io);
+        tokio::spawn(async move {
+            let reader = Arc::clone(&open);
+            let read = io.run(move || {
+                let version = Version::of(&reader.file.metadata()?);
+                let mut blocks = Vec::with_capacity(prefetch.count as usize);
+                for offset in prefetch.offsets() {
+                    // Let go of the file once the reader moved away or closed it
+                    if !reader.read_ahead.wants(&prefetch) {
+                        break;
+                    }
+                    let data = read_at(&reader.file, offset, prefetch.block)?;
+                    let last = (data.len() as u64) < prefetch.block;
+                    if !data.is_empty() {
+                        blocks.push((offset, data));
+                    }
+                    if last {
+                        break;
+                    }
+                }
+                Ok::<_, std::io::Error>((version, blocks))
+            }).await;
+            
+            match read {
+                Ok(Ok((version, blocks))) => open.read_ahead.fill(&prefetch, version, blocks),
+                Ok(Err(e)) => debug!("Read-ahead of {} failed: {}", open.path, e),
+                Err(e) => debug!("Read-ahead of {} failed: {}", open.path, e),
+            }
+        }.in_current_span());
+    }
+    
+    /// Path a lock of `lock_type` is kept under, once the caller is found
+    /// to have the access it needs; every name of a file shares its locks
+    async fn lockable_path(&self, path: &str, lock_type: LockType) -> Result<PathBuf, RemoteFsError> {
+        match lock_type {
+            LockType::Read => self.access_control.check_read_access(path).await?,
+            LockType::Write => self.access_control.check_write_access(path).await?,
+        }
+        
+        let canonical = path.to_string();
+        let (path_buf, is_dir) = self.io.run(move || {
+            fs::canonicalize(&canonical).map(|path_buf| {
+                let is_dir = path_buf.is_dir();
+                (path_buf, is_dir)
+            })
+        }).await?
+            .map_err(|_| RemoteFsError::NotFound(format!("File not found: {}", path)))?;
+        if is_dir {
+            return Err(RemoteFsError::InvalidPath(format!("Path is not a file: {}", path)));
+        }
+        Ok(path_buf)
+    }
+    
+    /// Handle an export listing, leaving out exports the caller cannot read
+    pub async fn handle_list_exports(&self, request_id: Uuid) -> Option<Message> {
+        let config = self.access_control.config();
+        let exports = match self.io.run(move || exports::list_exports(&config)).await {
+            Ok(exports) => exports,
+            Err(e) => {
+                return Some(Message::ListExportsResponse {
+                    request_id,
+                    exports: Vec::new(),
+                    error: Some(format!("Failed to list exports: {}", e)),
+                });
+            }
+        };
+        
+        let mut visible = Vec::with_capacity(exports.len());
+        for mut export in exports {
+            if self.access_control.is_readable(&export.path).await {
+                // An export under a quota has the space its quota leaves
+                if let Some((used, max)) = self.quotas.as_ref().and_then(|quotas| quotas.space(Path::new(&export.path))) {
+                    export.total_space = Some(max);
+                    export.available_space = Some(export.available_space.unwrap_or(u64::MAX).min(max.saturating_sub(used)));
+                }
+                visible.push(export);
+            }
+        }
+        
+        Some(Message::ListExportsResponse {
+            request_id,
+            exports: visible,
+            error: None,
+        })
+    }
+    
+    /// Handle a request for the size and free space of the filesystem
+    /// holding a path
+    pub async fn handle_get_space_info(&self, request_id: Uuid, path: String) -> Option<Message> {
+        let operation_id = Uuid::new_v4();
+        let start_time = SystemTime::now();
+        
+        self.start_operation(operation_id, "get_space_info", &path).await;
+        
+        let result = async {
+            self.access_control.check_read_access(&path).await?;
+            
+            let space_path = path.clone();
+            let mut space = self.io.run(move || {
+                let path_buf = existing_path(&space_path)?;
+                exports::space(&path_buf)
+                    .map_err(|e| RemoteFsError::io(&format!("Failed to get space of {}", space_path), e))
+            }).await??;
+            
+            // A path under a quota has the space its quota leaves
+            if let Some((used, max)) = self.quotas.as_ref().and_then(|quotas| quotas.space(Path::new(&path))) {
+                space.total_space = max;
+                space.used_space = used;
+                space.available_space = space.available_space.min(max.saturating_sub(used));
+            }
+            
+            {
+                let mut stats = self.stats.write().await;
+                stats.total_operations += 1;
+            }
+            
+            Ok(Message::GetSpaceInfoResponse {
+                request_id,
+                success: true,
+                total_space: Some(space.total_space),
+                available_space: Some(space.available_space),
+                used_space: Some(space.used_space),
+                total_files: space.total_files,
+                available_files: space.available_files,
+                error: None,
+            })
+        }.await;
+        
+        self.end_operation(operation_id, start_time).await;
+        
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                self.record_error().await;
+                Some(coded_error_response(request_id, e, |error| Message::GetSpaceInfoResponse {
+                    request_id,
+                    success: false,
+                    total_space: None,
+                    available_space: None,
+                    used_space: None,
+                    total_files: None,
+                    available_files: None,
+                    error: Some(error),
+                }))
+            }
+        }
+    }
+    
+    /// Handle a promotion or demotion announced by the relay
+    pub async fn handle_mirror_status(&self, primary: &str, promoted: bool, writes_allowed: bool) {
+        match &self.mirror {
+            Some(mirror) => mirror.set_promoted(primary, promoted, writes_allowed).await,
+            None => warn!("Ignoring mirror status for primary {}: not configured as a mirror", primary),
+        }
+    }
+    
+    /// Resource usage against the configured limits
+    pub fn get_resource_statistics(&self) -> ResourceStatistics {
+        let mut statistics: ResourceStatistics = self.limits.as_ref().map(|limits| limits.statistics()).unwrap_or_default();
+        statistics.throttled_requests = self.rate.as_ref().map_or(0, |rate| rate.throttled());
+        statistics
+    }
+    
+    /// Count `build`'s change against the space quotas, refusing it if it
+    /// would take a directory over its quota; the charge is taken back
+    /// unless committed once the change is made
+    fn charge_quota(&self, build: impl FnOnce(&mut QuotaChange)) -> Result<QuotaCharge, RemoteFsError> {
+        let Some(quotas) = &self.quotas else {
+            return Ok(QuotaCharge::default());
+        };
+        let mut change = quotas.change();
+        build(&mut change);
+        quotas.charge(change)
+    }
+    
+    /// Delete `path`, holding `size` bytes as quotas count them, with
+    /// `remove`, or move it into the trash if the agent keeps one for it
+    async fn remove_entry<F>(&self, path: PathBuf, size: u64, context: &str, remove: F) -> Result<(), RemoteFsError>
+    where
+        F: FnOnce(&Path) -> std::io::Result<()> + Send + 'static,
+    {
+        let trash = self.trash.clone()
+            .zip(self.trash_root(&path))
+            .filter(|(trash, root)| trash.keeps(root, &path));
+        let (charge, result) = match trash {
+            // Moving into the trash only frees quotas the trash is outside of
+            Some((trash, root)) => {
+                let charge = self.charge_quota(|change| change.moved(&path, &trash.dir(&root), size))?;
+                (charge, self.io.run(move || trash.put(&root, &path).map(drop)).await?)
+            }
+            None => {
+                let charge = self.charge_quota(|change| change.resize(&path, size, 0))?;
+                (charge, self.io.run(move || remove(&path)).await?)
+            }
+        };
+        result.map_err(|e| RemoteFsError::io(context, e))?;
+        charge.commit();
+        Ok(())
+    }
+    
+    /// The allowed path holding `path` whose trash keeps what is deleted
+    /// from it: the innermost, when allowed paths nest
+    fn trash_root(&self, path: &Path) -> Option<PathBuf> {
+        self.access_control.config().allowed_paths.into_iter()
+            .map(PathBuf::from)
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+    }
+    
+    /// Delete for good the entries in the trash of `root` that `pick`
+    /// chooses, returning how many were purged
+    async fn purge_trash_entries<F>(&self, trash: &Arc<Trash>, root: PathBuf, pick: F) -> Result<u32, RemoteFsError>
+    where
+        F: Fn(&TrashEntry) -> bool + Send + 'static,
+    {
+        let (trash, quotas) = (Arc::clone(trash), self.quotas.clone());
+        let purged = self.io.run(move || -> std::io::Result<u32> {
+            let mut purged = 0;
+            for entry in trash.entries(&root)?.into_iter().filter(|entry| pick(entry)) {
+                let size = match &quotas {
+                    Some(quotas) if quotas.covers(&entry.file) => tree_size(&entry.file),
+                    _ => 0,
+                };
+                trash.remove(&entry)?;
+                if let Some(quotas) = &quotas {
+                    let mut change = quotas.change();
+                    change.resize(&entry.file, size, 0);
+                    // Freeing space is never refused
+                    if let Ok(charge) = quotas.charge(change) {
+                        charge.commit();
+                    }
+                }
+                purged += 1;
+            }
+            Ok(purged)
+        }).await?;
+        purged.map_err(|e| RemoteFsError::io("Failed to purge the trash", e))
+    }
+    
+    /// Purge the entries that have been in the trash for longer than its
+    /// retention, returning how many were purged
+    pub async fn purge_expired_trash(&self) -> u32 {
+        let Some(trash) = &self.trash else {
+            return 0;
+        };
+        let Some(expiry) = chrono::Duration::from_std(trash.retention()).ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention)) else {
+            return 0;
+        };
+        
+        let mut purged = 0;
+        for root in self.access_control.config().allowed_paths {
+            match self.purge_trash_entries(trash, PathBuf::from(&root), move |entry| entry.deleted_at < expiry).await {
+                Ok(count) => purged += count,
+                Err(e) => warn!("Failed to purge the trash of {}: {}", root, e),
+            }
+        }
+        purged
+    }
+    
+    /// Bytes of file data at `path`, whose metadata is `metadata`, as
+    /// quotas count them; a directory is only measured when a quota holds it
+    /// or `to`, where it is being moved
+    async fn quota_size(&self, path: &Path, metadata: &fs::Metadata, to: Option<&Path>) -> Result<u64, RemoteFsError> {
+        if !metadata.is_dir() {
+            return Ok(if metadata.is_file() { metadata.len() } else { 0 });
+        }
+        match &self.quotas {
+            Some(quotas) if quotas.covers(path) || to.is_some_and(|to| quotas.covers(to)) => {
+                let path = path.to_path_buf();
+                self.io.run(move || tree_size(&path)).await
+            }
+            _ => Ok(0),
+        }
+    }
+    
+    /// Count `bytes` read for this handler's client against its byte rate
+    fn charge_read(&self, bytes: u64) {
+        if let Some(rate) = &self.rate {
+            rate.charge(self.client_id.as_deref(), bytes);
+        }
+    }
+    
+    /// Operations in progress and the resources they hold, for crash
+    /// reports, without waiting for locks
+    pub fn snapshot(&self) -> String {
+        let resources = self.get_resource_statistics();
+        let mut lines = vec![format!(
+            "{} files open and {} bytes buffered by in-flight requests, {} requests shed",
+            resources.open_files, resources.buffered_bytes, resources.shed_requests
+        )];
+        match self.active_operations.try_read() {
+            Ok(active) => lines.extend(active.values().map(|operation| format!(
+                "{} {} for {}ms",
+                operation.operation_type,
+                operation.path.display(),
+                operation.start_time.elapsed().unwrap_or_default().as_millis()
+            ))),
+            Err(_) => lines.push("operations unavailable, the table is locked".to_string()),
+        }
+        lines.join("\n")
+    }
+    
+    /// Reserve memory and an open file for a request handling `bytes` of data
+    ///
+    /// Returns the error response to send instead when the request is too
+    /// large or the agent is at its limits.
+    fn reserve(&self, request_id: Uuid, path: &str, bytes: u64) -> Result<Option<ResourcePermit>, Box<Message>> {
+        let Some(limits) = &self.limits else {
+            return Ok(None);
+        };
+        
+        if !limits.allows_response(bytes) {
+            return Err(Box::new(over_limit(
+                Some(request_id), ErrorCode::MessageTooLarge, "response_bytes", limits.max_response(),
+                format!(
+                    "Reading {} bytes of {} exceeds the agent's {} byte response limit; read it in ranges",
+                    bytes, path, limits.max_response()
+                ),
+            )));
+        }
+        
+        match limits.acquire(bytes) {
+            Ok(permit) => Ok(Some(permit)),
+            Err(exhausted) => {
+                warn!("Refusing request for {}: agent is at its {} limit", path, exhausted.as_str());
+                Err(Box::new(overloaded_response(request_id, exhausted)))
+            }
+        }
+    }
+    
+    /// Flag files whose content the archiver has offloaded
+    fn with_offline_flag(&self, metadata: FileMetadata, path: &Path) -> FileMetadata {
+        flag_offline(self.archive.as_deref(), metadata, path)
+    }
+    
+    /// Metadata of `path`, following symlinks, or `None` if it cannot be read
+    async fn metadata(&self, path: &Path) -> Option<fs::Metadata> {
+        let path = path.to_path_buf();
+        self.io.run(move || fs::metadata(path).ok()).await.ok().flatten()
+    }
+    
+    /// Metadata of `path` itself, not following a symlink there
+    async fn symlink_metadata(&self, path: &Path) -> Option<fs::Metadata> {
+        let path = path.to_path_buf();
+        self.io.run(move || fs::symlink_metadata(path).ok()).await.ok().flatten()
+    }
+    
+    /// Add a change to the journal, if one is configured
+    async fn record_change(&self, kind: ChangeKind, path: &str, is_dir: bool) {
+        if let Some(journal) = &self.journal {
+            journal.record(kind.clone(), path, is_dir).await;
+        }
+        self.watcher.record(kind, path, is_dir).await;
+    }
+    
+    /// Start tracking an operation
+    async fn start_operation(&self, operation_id: Uuid, operation_type: &str, path: &str) {
+        let operation_info = OperationInfo {
+            operation_type: operation_type.to_string(),
+            path: PathBuf::from(path),
+            start_time: SystemTime::now(),
+        };
+        
+        {
+            let mut active = self.active_operations.write().await;
+            active.insert(operation_id, operation_info);
+        }
+        
+        {
+            let mut stats = self.stats.write().await;
+            stats.active_operations += 1;
+        }
+        
+        debug!("Started {} operation on {}", operation_type, path);
+    }
+    
+    /// End tracking an operation
+    async fn end_operation(&self, operation_id: Uuid, start_time: SystemTime) {
+        {
+            let mut active = self.active_operations.write().await;
+            active.remove(&operation_id);
+        }
+        
+        {
+            let mut stats = self.stats.write().await;
+            stats.active_operations = stats.active_operations.saturating_sub(1);
+        }
+        
+        // Record performance metrics
+        if let Ok(duration) = start_time.elapsed() {
+            let mut perf_stats = self.performance_stats.write().await;
+            perf_stats.response_times.push(duration);
+            perf_stats.operations_count += 1;
+        }
+    }
+    
+    /// Record an error
+    async fn record_error(&self) {
+        let mut stats = self.stats.write().await;
+        // Failed operations never reach the success-path counter
+        stats.total_operations += 1;
+        stats.error_count += 1;
+    }
+    
+    /// Get filesystem statistics
+    pub async fn get_statistics(&self) -> FilesystemStatistics {
+        self.stats.read().await.clone()
     }
     
     /// Get performance statistics
@@ -908,6 +4625,8 @@ impl FilesystemHandler {
             bytes_read: stats.bytes_read,
             bytes_written: stats.bytes_written,
             operations_per_second: ops_per_second,
+            prefetch_hits: self.prefetcher.as_ref().map_or(0, |prefetcher| prefetcher.hits()),
+            prefetched_bytes: self.prefetcher.as_ref().map_or(0, |prefetcher| prefetcher.cached_bytes()),
         }
     }
     
@@ -930,3 +4649,700 @@ impl FilesystemHandler {
         perf_stats.last_cleanup = SystemTime::now();
     }
 }
+
+/// Checksum of `length` bytes of a file from `offset`, or fewer if the file
+/// has shrunk since it was sized
+fn hash_file(path: &Path, algorithm: ChecksumAlgorithm, offset: u64, length: u64) -> std::io::Result<Checksum> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut file = file.take(length);
+    
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; length.min(CHECKSUM_BUFFER_SIZE) as usize];
+    let mut hashed = 0;
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        hashed += read as u64;
+    }
+    
+    Ok(Checksum {
+        algorithm,
+        digest: hasher.finalize(),
+        length: hashed,
+    })
+}
+
+/// Up to `length` bytes of a file from `offset`, or fewer if the file has
+/// shrunk since it was sized
+fn read_range(path: &Path, offset: u64, length: u64) -> Result<Vec<u8>, RemoteFsError> {
+    let mut file = File::open(path)
+        .map_err(|e| RemoteFsError::io("Failed to open file", e))?;
+    
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| RemoteFsError::io("Failed to seek", e))?;
+    }
+    
+    // The file may have grown since it was sized
+    let mut data = Vec::with_capacity(length as usize);
+    file.take(length).read_to_end(&mut data)
+        .map_err(|e| RemoteFsError::io("Failed to read file", e))?;
+    Ok(data)
+}
+
+/// Write `data` to a file at `offset`, or over its start if `offset` is
+/// unset; with `create` the file and any missing parents are created first,
+/// and the file is truncated unless written at an offset
+fn write_range(path: &Path, data: &[u8], offset: Option<u64>, create: bool, sync: bool) -> Result<(), RemoteFsError> {
+    if create {
+        if let Some(parent) = path.parent().filter(|parent| !parent.exists()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| RemoteFsError::io("Failed to create parent directories", e))?;
+        }
+    }
+    
+    let mut file = OpenOptions::new()
+        .create(create)
+        .write(true)
+        .truncate(create && offset.is_none())
+        .open(path)
+        .map_err(|e| RemoteFsError::io("Failed to open file for writing", e))?;
+    
+    if let Some(offset) = offset {
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| RemoteFsError::io("Failed to seek", e))?;
+    }
+    
+    file.write_all(data)
+        .map_err(|e| RemoteFsError::io("Failed to write file", e))?;
+    
+    // Sync to disk if requested
+    if sync {
+        file.sync_data()
+            .map_err(|e| RemoteFsError::io("Failed to sync file", e))?;
+    }
+    Ok(())
+}
+
+/// Refuse block sizes a signature or delta may not use
+fn check_block_size(block_size: u32) -> Result<(), RemoteFsError> {
+    if (delta::MIN_BLOCK_SIZE..=delta::MAX_BLOCK_SIZE).contains(&block_size) {
+        Ok(())
+    } else {
+        Err(RemoteFsError::Protocol(format!(
+            "Block size {} is outside {}..={}", block_size, delta::MIN_BLOCK_SIZE, delta::MAX_BLOCK_SIZE
+        )))
+    }
+}
+
+/// Where a delta write builds the new version of `path`
+fn delta_temp_path(path: &Path, request_id: Uuid) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.remotefs-delta", name, request_id.simple()))
+}
+
+/// Writes to a file and hashes what is written
+struct HashingWriter {
+    file: File,
+    hasher: Hasher,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.file.write(data)?;
+        self.hasher.update(&data[..written]);
+        Ok(written)
+    }
+    
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Build the new version of `path` at `temp_path` from `ops`, and rename it
+/// over `path` if its BLAKE3 digest is `checksum`, keeping the old file's
+/// mode and, where the agent may, its owner; returns the new length
+fn rebuild_file(
+    path: &Path,
+    temp_path: &Path,
+    block_size: u32,
+    ops: &[DeltaOp],
+    checksum: &[u8],
+    sync: bool,
+) -> Result<u64, RemoteFsError> {
+    let mut basis = File::open(path)
+        .map_err(|e| RemoteFsError::io("Failed to open file", e))?;
+    let metadata = basis.metadata()
+        .map_err(|e| RemoteFsError::io("Failed to read file metadata", e))?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(metadata.mode() & 0o7777)
+        .open(temp_path)
+        .map_err(|e| RemoteFsError::io("Failed to create file", e))?;
+    
+    let mut out = std::io::BufWriter::new(HashingWriter { file, hasher: Hasher::new(ChecksumAlgorithm::Blake3) });
+    let written = delta::apply(&mut basis, metadata.len(), block_size, ops, &mut out)
+        .map_err(|e| RemoteFsError::io("Failed to rebuild file", e))?;
+    let out = out.into_inner()
+        .map_err(|e| RemoteFsError::io("Failed to write file", e.into_error()))?;
+    
+    if out.hasher.finalize() != checksum {
+        return Err(RemoteFsError::FileSystem(format!(
+            "Rebuilt {} does not match the data sent; it changed since its signature was taken", path.display()
+        )));
+    }
+    
+    // The mode given on creation is masked by the umask
+    out.file.set_permissions(metadata.permissions())
+        .map_err(|e| RemoteFsError::io("Failed to set permissions", e))?;
+    if let Err(e) = std::os::unix::fs::fchown(&out.file, Some(metadata.uid()), Some(metadata.gid())) {
+        debug!("Rebuilt {} keeps the agent's ownership: {}", path.display(), e);
+    }
+    if sync {
+        out.file.sync_data()
+            .map_err(|e| RemoteFsError::io("Failed to sync file", e))?;
+    }
+    
+    fs::rename(temp_path, path)
+        .map_err(|e| RemoteFsError::io("Failed to replace file", e))?;
+    Ok(written)
+}
+
+/// Apply the fields present in `update` to `path`, with `mode` in place of
+/// the requested permissions
+fn set_metadata(path: &Path, mode: Option<u32>, update: &MetadataUpdate) -> Result<(), RemoteFsError> {
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| RemoteFsError::io("Failed to set permissions", e))?;
+    }
+    
+    if update.uid.is_some() || update.gid.is_some() {
+        std::os::unix::fs::chown(path, update.uid, update.gid)
+            .map_err(|e| RemoteFsError::io("Failed to set owner", e))?;
+    }
+    
+    if update.accessed.is_some() || update.modified.is_some() {
+        set_times(path, update.accessed, update.modified)
+            .map_err(|e| RemoteFsError::io("Failed to set times", e))?;
+    }
+    Ok(())
+}
+
+/// Set the times of a file by path, leaving those not given as they are;
+/// unlike setting them through an open file this needs no permission to
+/// read or write it, so it works on a file just made mode 000
+fn set_times(path: &Path, accessed: Option<DateTime<Utc>>, modified: Option<DateTime<Utc>>) -> std::io::Result<()> {
+    let timespec = |time: Option<DateTime<Utc>>| match time {
+        Some(time) => libc::timespec {
+            tv_sec: time.timestamp() as libc::time_t,
+            tv_nsec: time.timestamp_subsec_nanos() as libc::c_long,
+        },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    };
+    let times = [timespec(accessed), timespec(modified)];
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is NUL-terminated and `times` holds the two entries
+    // utimensat reads
+    if unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Rename `from` to `to`, failing with `AlreadyExists` instead of replacing
+/// anything at `to`, even something created there at the same time
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn rename_no_replace(from: &Path, to: &Path) -> std::io::Result<()> {
+    let c_path = |path: &Path| CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+    let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+    // SAFETY: both paths are NUL-terminated
+    #[cfg(target_os = "linux")]
+    let result = unsafe {
+        libc::renameat2(libc::AT_FDCWD, c_from.as_ptr(), libc::AT_FDCWD, c_to.as_ptr(), libc::RENAME_NOREPLACE)
+    };
+    // SAFETY: both paths are NUL-terminated
+    #[cfg(target_os = "macos")]
+    let result = unsafe { libc::renamex_np(c_from.as_ptr(), c_to.as_ptr(), libc::RENAME_EXCL) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Rename `from` to `to` unless something is at `to`; without a system call
+/// that checks both at once, an entry created in between is still replaced
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn rename_no_replace(from: &Path, to: &Path) -> std::io::Result<()> {
+    if to.symlink_metadata().is_ok() {
+        return Err(std::io::ErrorKind::AlreadyExists.into());
+    }
+    fs::rename(from, to)
+}
+
+/// Whether `path`, or a directory between it and `root`, was modified or
+/// had its inode changed after `as_of`; a path that cannot be read counts
+/// as changed
+fn changed_since(path: &Path, root: &Path, as_of: DateTime<Utc>) -> bool {
+    path.ancestors()
+        .take_while(|ancestor| ancestor.starts_with(root))
+        .any(|ancestor| match fs::metadata(ancestor) {
+            Ok(metadata) => [
+                (metadata.mtime(), metadata.mtime_nsec()),
+                (metadata.ctime(), metadata.ctime_nsec()),
+            ]
+            .into_iter()
+            .any(|(secs, nsecs)| DateTime::from_timestamp(secs, nsecs as u32).is_none_or(|time| time > as_of)),
+            Err(_) => true,
+        })
+}
+
+/// Entries of a directory being changed by `SetMetadataTree`, sorted by
+/// name, with whether each is a directory; symlinks are left out
+fn list_tree_entries(path: &Path) -> Result<Vec<(PathBuf, bool)>, RemoteFsError> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).map_err(|e| RemoteFsError::io("Failed to read directory", e))? {
+        let entry = entry.map_err(|e| RemoteFsError::io("Failed to read directory entry", e))?;
+        let file_type = entry.file_type().map_err(|e| RemoteFsError::io("Failed to read entry type", e))?;
+        if !file_type.is_symlink() {
+            entries.push((entry.path(), file_type.is_dir()));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+/// Write one chunk of an upload at `offset`; the first chunk creates the
+/// file and any missing parents, or cuts the file off at `offset`
+fn write_chunk(path: &Path, data: &[u8], offset: u64, first: bool, sync: bool) -> Result<(), RemoteFsError> {
+    if first {
+        if let Some(parent) = path.parent().filter(|parent| !parent.exists()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| RemoteFsError::io("Failed to create parent directories", e))?;
+        }
+    }
+    
+    let mut file = OpenOptions::new()
+        .create(first)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| RemoteFsError::io("Failed to open file for writing", e))?;
+    
+    if first {
+        file.set_len(offset)
+            .map_err(|e| RemoteFsError::io("Failed to truncate file", e))?;
+    }
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|e| RemoteFsError::io("Failed to seek", e))?;
+    file.write_all(data)
+        .map_err(|e| RemoteFsError::io("Failed to write file", e))?;
+    
+    if sync {
+        file.sync_data()
+            .map_err(|e| RemoteFsError::io("Failed to sync file", e))?;
+    }
+    Ok(())
+}
+
+/// A directory a walk is reading
+struct WalkLevel {
+    /// `None` once every entry has been read
+    entries: Option<fs::ReadDir>,
+    /// Entries read but not yet listed
+    read: VecDeque<WalkEntry>,
+    /// Path relative to the walked directory
+    relative: PathBuf,
+    id: (u64, u64),
+}
+
+impl WalkLevel {
+    fn new(entries: fs::ReadDir, relative: PathBuf, id: (u64, u64)) -> Self {
+        Self { entries: Some(entries), read: VecDeque::new(), relative, id }
+    }
+}
+
+/// An entry of a directory a walk is reading
+struct WalkEntry {
+    path: PathBuf,
+    name: OsString,
+    dir_entry: Result<Option<DirEntry>, RemoteFsError>,
+    /// Whether the walk may descend into it
+    is_dir: bool,
+}
+
+/// Read the next `WALK_READ_AHEAD` entries of a directory being walked,
+/// handing the directory back unless it has no more
+fn read_ahead(mut entries: fs::ReadDir, archive: Option<&ArchiveHooks>, ids: &IdMap, follow_symlinks: bool) -> (Option<fs::ReadDir>, VecDeque<WalkEntry>) {
+    let mut read = VecDeque::with_capacity(WALK_READ_AHEAD);
+    while read.len() < WALK_READ_AHEAD {
+        let Some(entry) = entries.next() else {
+            return (None, read);
+        };
+        let (path, name) = match &entry {
+            Ok(entry) => (entry.path(), entry.file_name()),
+            Err(_) => (PathBuf::new(), OsString::new()),
+        };
+        let dir_entry = dir_entry(archive, ids, entry);
+        let is_dir = match &dir_entry {
+            Ok(Some(dir_entry)) if dir_entry.metadata.is_symlink => follow_symlinks && path.is_dir(),
+            Ok(Some(dir_entry)) => dir_entry.metadata.is_dir,
+            _ => false,
+        };
+        read.push_back(WalkEntry { path, name, dir_entry, is_dir });
+    }
+    (Some(entries), read)
+}
+
+/// Listing entry for a directory entry, or `None` for entries that are
+/// hidden from clients
+fn dir_entry(archive: Option<&ArchiveHooks>, ids: &IdMap, entry: std::io::Result<fs::DirEntry>) -> Result<Option<DirEntry>, RemoteFsError> {
+    let entry = entry
+        .map_err(|e| RemoteFsError::io("Failed to read directory entry", e))?;
+    
+    let entry_path = entry.path();
+    if archive.is_some_and(|archive| archive.is_marker(&entry_path)) {
+        return Ok(None);
+    }
+    
+    let metadata = entry.metadata()
+        .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+    
+    let file_name = entry_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    
+    Ok(Some(DirEntry {
+        name: file_name,
+        metadata: ids.to_remote(flag_offline(archive, FileMetadata::from_fs(&metadata, &entry_path), &entry_path)),
+    }))
+}
+
+/// Entry `name` of the directory `directory`, or `None` if it no longer
+/// exists
+fn named_dir_entry(archive: Option<&ArchiveHooks>, ids: &IdMap, directory: &Path, name: &OsString) -> Result<Option<DirEntry>, RemoteFsError> {
+    let entry_path = directory.join(name);
+    if archive.is_some_and(|archive| archive.is_marker(&entry_path)) {
+        return Ok(None);
+    }
+    
+    let metadata = match fs::symlink_metadata(&entry_path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(RemoteFsError::io("Failed to read metadata", e)),
+    };
+    
+    Ok(Some(DirEntry {
+        name: name.to_str().unwrap_or("").to_string(),
+        metadata: ids.to_remote(flag_offline(archive, FileMetadata::from_fs(&metadata, &entry_path), &entry_path)),
+    }))
+}
+
+/// Flag files whose content the archiver has offloaded
+fn flag_offline(archive: Option<&ArchiveHooks>, mut metadata: FileMetadata, path: &Path) -> FileMetadata {
+    metadata.offline = metadata.is_file && archive.is_some_and(|archive| archive.is_offline(path));
+    metadata
+}
+
+/// Device and inode of a directory, following symlinks
+fn directory_id(path: &Path) -> Result<(u64, u64), RemoteFsError> {
+    let metadata = fs::metadata(path)
+        .map_err(|e| RemoteFsError::io("Failed to read metadata", e))?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+/// Start reading a directory, checking that it exists and is one
+fn open_directory(path: &str) -> Result<fs::ReadDir, RemoteFsError> {
+    let path_buf = PathBuf::from(path);
+    
+    if !path_buf.exists() {
+        return Err(RemoteFsError::NotFound(format!("Directory not found: {}", path)));
+    }
+    
+    if !path_buf.is_dir() {
+        return Err(not_a_directory(path));
+    }
+    
+    fs::read_dir(&path_buf)
+        .map_err(|e| RemoteFsError::io("Failed to read directory", e))
+}
+
+/// Names in a directory that sort after `after`, in byte order
+fn sorted_names(path: &str, after: Option<&str>) -> Result<Vec<OsString>, RemoteFsError> {
+    let mut names = Vec::new();
+    for entry in open_directory(path)? {
+        let entry = entry
+            .map_err(|e| RemoteFsError::io("Failed to read directory entry", e))?;
+        let name = entry.file_name();
+        if after.is_none_or(|after| name.as_bytes() > after.as_bytes()) {
+            names.push(name);
+        }
+    }
+    names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    Ok(names)
+}
+
+/// A change to record in the journal: its kind, path and whether the path
+/// is a directory
+type Change = (ChangeKind, String, bool);
+
+/// Apply every operation of a transaction, or roll back those applied if
+/// one fails, returning the changes made
+fn apply_transaction(request_id: Uuid, operations: &[TransactionOp]) -> Result<Vec<Change>, (Option<u32>, RemoteFsError)> {
+    let mut transaction = Transaction::new();
+    let mut changes = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.iter().enumerate() {
+        let change = transaction_change(operation);
+        if let Err(e) = transaction.apply(operation) {
+            if let Err(rollback) = transaction.rollback() {
+                warn!("Transaction {} was only partly rolled back: {}", request_id, rollback);
+            }
+            return Err((Some(index as u32), RemoteFsError::FileSystem(format!(
+                "Operation {} ({} {}) failed: {}",
+                index, operation.name(), transaction_path(operation), e
+            ))));
+        }
+        changes.push(change);
+    }
+    transaction.commit();
+    Ok(changes)
+}
+
+/// Path a transaction step applies to
+fn transaction_path(operation: &TransactionOp) -> &str {
+    match operation {
+        TransactionOp::WriteFile { path, .. }
+        | TransactionOp::DeleteFile { path }
+        | TransactionOp::CreateDirectory { path }
+        | TransactionOp::RemoveDirectory { path } => path,
+        TransactionOp::Rename { to_path, .. } => to_path,
+    }
+}
+
+/// Open or create an empty file, returning its metadata and whether it was
+/// created
+///
+/// The mode is set with `fchmod` once the file exists, as the agent's umask
+/// would mask the mode passed to `open`.
+fn create_file(path: &Path, mode: u32, exclusive: bool) -> std::io::Result<(fs::Metadata, bool)> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    let (file, created) = if exclusive {
+        (options.create_new(true).open(path)?, true)
+    } else {
+        match options.open(path) {
+            Ok(file) => (file, false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (options.create(true).open(path)?, true),
+            Err(e) => return Err(e),
+        }
+    };
+    
+    if created {
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok((file.metadata()?, created))
+}
+
+/// Open a file for `OpenFile`, returning it and whether it was created
+///
+/// A created file gets `mode` as given, whatever the agent's umask.
+fn open_file(path: &Path, flags: OpenFlags, mode: u32) -> std::io::Result<(File, bool)> {
+    let mut options = OpenOptions::new();
+    options.read(flags.read).write(flags.write).append(flags.append).truncate(flags.truncate);
+    let (file, created) = if flags.create && flags.exclusive {
+        (options.create_new(true).open(path)?, true)
+    } else {
+        match options.open(path) {
+            Ok(file) => (file, false),
+            Err(e) if flags.create && e.kind() == std::io::ErrorKind::NotFound => {
+                (options.create(true).open(path)?, true)
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    
+    if created {
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    Ok((file, created))
+}
+
+/// Read up to `length` bytes from `offset`, fewer if the file ends first
+fn read_at(file: &File, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; length as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        match file.read_at(&mut data[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
+
+/// Create a directory and any missing parents, returning whether it existed
+///
+/// Only the directory itself gets `mode`, set after it is created so the
+/// agent's umask does not mask it.
+fn create_directory(path: &Path, mode: u32) -> std::io::Result<bool> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::create_dir(path) {
+        Ok(()) => {
+            File::open(path)?.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+            Ok(false)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && path.is_dir() => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write one file of a batch, creating its parent directories
+///
+/// Without `overwrite` the file must not exist, even if it appeared after
+/// access was checked. A created file gets its `mode` as given, whatever
+/// the agent's umask; an overwritten one keeps its own.
+fn create_batch_file(file: &NewFile, overwrite: bool) -> std::io::Result<ChangeKind> {
+    let path = Path::new(&file.path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    
+    let existed = overwrite && path.exists();
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut output = options.open(path)?;
+    if let Some(mode) = file.mode.filter(|_| !existed) {
+        output.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    output.write_all(&file.data)?;
+    
+    Ok(if existed { ChangeKind::Modified } else { ChangeKind::Created })
+}
+
+/// Journal entry for a transaction step, worked out before it is applied
+fn transaction_change(operation: &TransactionOp) -> Change {
+    match operation {
+        TransactionOp::WriteFile { path, .. } => {
+            let kind = if Path::new(path).exists() { ChangeKind::Modified } else { ChangeKind::Created };
+            (kind, path.clone(), false)
+        }
+        TransactionOp::Rename { from_path, to_path } => {
+            let is_dir = Path::new(from_path).is_dir();
+            (ChangeKind::Renamed { from: from_path.clone() }, to_path.clone(), is_dir)
+        }
+        TransactionOp::DeleteFile { path } => (ChangeKind::Deleted, path.clone(), false),
+        TransactionOp::CreateDirectory { path } => (ChangeKind::Created, path.clone(), true),
+        TransactionOp::RemoveDirectory { path } => (ChangeKind::Deleted, path.clone(), true),
+    }
+}
+
+/// `FileOffline` error for a read of an archived file, with the recall state
+/// in the details so clients can tell a pending recall from a failed one
+fn offline_response(request_id: Uuid, path: &str, recall: &RecallState) -> Message {
+    let mut details = HashMap::from([("recall".to_string(), recall.as_str().to_string())]);
+    let message = match recall {
+        RecallState::Recalling { started_at } => {
+            details.insert("recall_started_at".to_string(), started_at.to_rfc3339());
+            format!("{} is in cold storage; recall in progress since {}", path, started_at)
+        }
+        RecallState::Failed { error } => format!("{} is in cold storage; recall failed: {}", path, error),
+        RecallState::Unavailable => format!("{} is in cold storage", path),
+    };
+    
+    Message::Error {
+        request_id: Some(request_id),
+        code: ErrorCode::FileOffline,
+        message,
+        details: Some(details),
+        errno: None,
+    }
+}
+
+/// Error for a handle that is not open, or not open for `use_`
+fn bad_handle(handle: Uuid, use_: &str) -> RemoteFsError {
+    RemoteFsError::Os {
+        code: ErrorCode::InternalError,
+        errno: Some(libc::EBADF),
+        message: format!("File handle {} is not open for {}", handle, use_),
+    }
+}
+
+/// Error for a directory request on something else
+fn not_a_directory(path: &str) -> RemoteFsError {
+    RemoteFsError::Os {
+        code: ErrorCode::NotADirectory,
+        errno: Some(libc::ENOTDIR),
+        message: format!("Path is not a directory: {}", path),
+    }
+}
+
+/// `path`, if it exists; a dangling symlink exists
+fn existing_path(path: &str) -> Result<PathBuf, RemoteFsError> {
+    let path_buf = PathBuf::from(path);
+    if path_buf.symlink_metadata().is_err() {
+        return Err(RemoteFsError::NotFound(format!("Path not found: {}", path)));
+    }
+    Ok(path_buf)
+}
+
+fn xattr_error(e: std::io::Error, name: &str, path: &str) -> RemoteFsError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => RemoteFsError::NotFound(format!("No attribute {} on {}", name, path)),
+        std::io::ErrorKind::AlreadyExists => RemoteFsError::AlreadyExists(format!("Attribute {} exists on {}", name, path)),
+        std::io::ErrorKind::PermissionDenied => RemoteFsError::PermissionDenied(format!("Attribute {} of {}: {}", name, path, e)),
+        _ if e.kind() == std::io::ErrorKind::Unsupported || e.raw_os_error() == Some(libc::ENOTSUP) => {
+            RemoteFsError::NotImplemented(format!("{} does not support extended attributes", path))
+        }
+        _ => RemoteFsError::io(&format!("Attribute {} of {}", name, path), e),
+    }
+}
+
+/// Answer to a failed request: an `Error` with the code and OS error number
+/// for failures clients can tell apart, such as a missing path or a full
+/// disk, and the request's own failed response that `failed` builds for
+/// failures without either
+fn coded_error_response(request_id: Uuid, e: RemoteFsError, failed: impl FnOnce(String) -> Message) -> Message {
+    match (e.to_error_code(), e.errno()) {
+        (ErrorCode::InternalError, None) => failed(e.to_string()),
+        (code, errno) => Message::Error {
+            request_id: Some(request_id),
+            code,
+            message: e.to_string(),
+            details: None,
+            errno,
+        },
+    }
+}
+
+/// Retriable `ServiceUnavailable` error for a request refused at the agent's
+/// resource limits, naming the exhausted resource in the details
+fn overloaded_response(request_id: Uuid, exhausted: Exhausted) -> Message {
+    Message::Error {
+        request_id: Some(request_id),
+        code: ErrorCode::ServiceUnavailable,
+        message: format!("Agent is at its {} limit; retry later", exhausted.as_str().replace('_', " ")),
+        details: Some(HashMap::from([("resource".to_string(), exhausted.as_str().to_string())])),
+        errno: None,
+    }
+}
+
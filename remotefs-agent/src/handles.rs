@@ -0,0 +1,148 @@
+//! Files held open between `OpenFile` and `CloseFile`
+//!
+//! A handle keeps its file open on the agent, so reads and writes through it
+//! reach the same file after it is renamed or removed, as a descriptor does.
+//! Access is checked when the file is opened, for what it is opened for.
+//! Handles are qualified by the client session the relay names and closed
+//! when the relay reports it gone or the relay connection ends.
+
+use crate::prefetch::ReadAhead;
+use remotefs_common::protocol::RequestId;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Most files one client session may hold open
+pub const MAX_HANDLES_PER_SESSION: usize = 1024;
+
+/// A file held open for a client
+pub struct OpenHandle {
+    /// Client the handle belongs to
+    pub session: String,
+    /// Path the file was opened by; it may since have been renamed or removed
+    pub path: String,
+    pub file: File,
+    pub readable: bool,
+    pub writable: bool,
+    pub append: bool,
+    /// Blocks read ahead of a sequential reader
+    pub read_ahead: ReadAhead,
+}
+
+/// Files held open, by handle
+#[derive(Default)]
+pub struct HandleTable {
+    handles: Mutex<HashMap<RequestId, Arc<OpenHandle>>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep `file` open under `handle`, unless the handle is taken or its
+    /// session holds `MAX_HANDLES_PER_SESSION` files already
+    pub fn insert(&self, handle: RequestId, file: OpenHandle) -> bool {
+        let mut handles = lock_handles(&self.handles);
+        let held = handles.values().filter(|open| open.session == file.session).count();
+        if held >= MAX_HANDLES_PER_SESSION || handles.contains_key(&handle) {
+            return false;
+        }
+        handles.insert(handle, Arc::new(file));
+        true
+    }
+
+    /// The file open under `handle`
+    pub fn get(&self, handle: &RequestId) -> Option<Arc<OpenHandle>> {
+        lock_handles(&self.handles).get(handle).cloned()
+    }
+
+    /// Stop keeping the file open under `handle`; it is closed once the
+    /// requests using it are done, and nothing more is read ahead
+    pub fn remove(&self, handle: &RequestId) -> Option<Arc<OpenHandle>> {
+        let open = lock_handles(&self.handles).remove(handle)?;
+        open.read_ahead.close();
+        Some(open)
+    }
+
+    /// Close every file of a client session, returning how many there were
+    pub fn release_session(&self, session: &str) -> usize {
+        let mut handles = lock_handles(&self.handles);
+        let before = handles.len();
+        handles.retain(|_, open| {
+            let kept = open.session != session;
+            if !kept {
+                open.read_ahead.close();
+            }
+            kept
+        });
+        before - handles.len()
+    }
+
+    /// Close every file, after the connection of their sessions closed
+    pub fn clear(&self) {
+        for (_, open) in lock_handles(&self.handles).drain() {
+            open.read_ahead.close();
+        }
+    }
+
+    /// Files held open for writing
+    pub fn writable(&self) -> Vec<Arc<OpenHandle>> {
+        lock_handles(&self.handles).values().filter(|open| open.writable).cloned().collect()
+    }
+
+    /// Number of files held open
+    pub fn handle_count(&self) -> usize {
+        lock_handles(&self.handles).len()
+    }
+}
+
+fn lock_handles<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn open(session: &str) -> OpenHandle {
+        OpenHandle {
+            session: session.to_string(),
+            path: "/dev/null".to_string(),
+            file: File::open("/dev/null").unwrap(),
+            readable: true,
+            writable: false,
+            append: false,
+            read_ahead: ReadAhead::disabled(),
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let table = HandleTable::new();
+        let first = Uuid::new_v4();
+        assert!(table.insert(first, open("a")));
+        assert!(!table.insert(first, open("b")));
+        for _ in 1..MAX_HANDLES_PER_SESSION {
+            assert!(table.insert(Uuid::new_v4(), open("a")));
+        }
+
+        // One session at its limit does not keep another from opening files
+        assert!(!table.insert(Uuid::new_v4(), open("a")));
+        let other = Uuid::new_v4();
+        assert!(table.insert(other, open("b")));
+
+        // A removed handle stays usable by whoever still holds it
+        let removed = table.remove(&first).unwrap();
+        assert!(table.get(&first).is_none());
+        assert_eq!(removed.session, "a");
+        assert!(table.insert(Uuid::new_v4(), open("a")));
+
+        assert_eq!(table.release_session("a"), MAX_HANDLES_PER_SESSION);
+        assert_eq!(table.handle_count(), 1);
+        assert!(table.get(&other).is_some());
+        table.clear();
+        assert_eq!(table.handle_count(), 0);
+    }
+}
@@ -0,0 +1,121 @@
+//! Translation between the user and group IDs of clients and of the host
+//!
+//! Clients and agents rarely share a user database, so `id_map` says which
+//! host uid or gid each client uid or gid stands for. Ownership a client
+//! sets with `SetMetadata` is translated to host IDs before the `chown`, and
+//! the owner and group in metadata sent to clients are translated back. IDs
+//! without a mapping pass through unchanged.
+//!
+//! Backup entries keep the host's IDs, so that a restore on the same host
+//! brings back the real owner.
+
+use remotefs_common::{
+    config::{IdMapConfig, IdMapping},
+    error::{RemoteFsError, Result},
+    protocol::{FileMetadata, MetadataUpdate},
+};
+use std::collections::HashMap;
+
+/// The configured uid and gid mappings, both ways
+#[derive(Debug, Clone, Default)]
+pub struct IdMap {
+    uids: Mapping,
+    gids: Mapping,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Mapping {
+    to_local: HashMap<u32, u32>,
+    to_remote: HashMap<u32, u32>,
+}
+
+impl Mapping {
+    fn new(mappings: &[IdMapping]) -> Self {
+        Self {
+            to_local: mappings.iter().map(|mapping| (mapping.remote, mapping.local)).collect(),
+            to_remote: mappings.iter().map(|mapping| (mapping.local, mapping.remote)).collect(),
+        }
+    }
+
+    fn local(&self, remote: u32) -> u32 {
+        self.to_local.get(&remote).copied().unwrap_or(remote)
+    }
+
+    fn remote(&self, local: u32) -> u32 {
+        self.to_remote.get(&local).copied().unwrap_or(local)
+    }
+}
+
+impl IdMap {
+    pub fn new(config: &IdMapConfig) -> Self {
+        Self {
+            uids: Mapping::new(&config.uids),
+            gids: Mapping::new(&config.gids),
+        }
+    }
+
+    /// `update` with the owner and group it sets in host IDs
+    pub fn to_local(&self, mut update: MetadataUpdate) -> MetadataUpdate {
+        update.uid = update.uid.map(|uid| self.uids.local(uid));
+        update.gid = update.gid.map(|gid| self.gids.local(gid));
+        update
+    }
+
+    /// `metadata` with its owner and group in client IDs
+    pub fn to_remote(&self, mut metadata: FileMetadata) -> FileMetadata {
+        metadata.uid = self.uids.remote(metadata.uid);
+        metadata.gid = self.gids.remote(metadata.gid);
+        metadata
+    }
+}
+
+/// Check that no ID is mapped twice, on either side
+pub fn validate(config: &IdMapConfig) -> Result<()> {
+    for (kind, mappings) in [("uid", &config.uids), ("gid", &config.gids)] {
+        for (i, mapping) in mappings.iter().enumerate() {
+            let earlier = &mappings[..i];
+            if earlier.iter().any(|other| other.remote == mapping.remote) {
+                return Err(RemoteFsError::Configuration(format!(
+                    "Client {} {} is mapped more than once", kind, mapping.remote
+                )));
+            }
+            if earlier.iter().any(|other| other.local == mapping.local) {
+                return Err(RemoteFsError::Configuration(format!(
+                    "Host {} {} is mapped to more than once", kind, mapping.local
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_translation() {
+        let ids = IdMap::new(&IdMapConfig {
+            uids: vec![IdMapping { remote: 1000, local: 501 }],
+            gids: vec![IdMapping { remote: 100, local: 20 }],
+        });
+
+        let update = ids.to_local(MetadataUpdate { uid: Some(1000), gid: Some(5), ..MetadataUpdate::default() });
+        assert_eq!((update.uid, update.gid), (Some(501), Some(5)));
+        assert!(ids.to_local(MetadataUpdate::default()).is_empty());
+
+        let mut metadata = FileMetadata::synthetic_directory(0o755, Utc::now());
+        (metadata.uid, metadata.gid) = (501, 20);
+        let metadata = ids.to_remote(metadata);
+        assert_eq!((metadata.uid, metadata.gid), (1000, 100));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = |uids| IdMapConfig { uids, gids: Vec::new() };
+        assert!(validate(&config(vec![IdMapping { remote: 1000, local: 501 }, IdMapping { remote: 1001, local: 502 }])).is_ok());
+        assert!(validate(&config(vec![IdMapping { remote: 1000, local: 501 }, IdMapping { remote: 1000, local: 502 }])).is_err());
+        assert!(validate(&config(vec![IdMapping { remote: 1000, local: 501 }, IdMapping { remote: 1001, local: 501 }])).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Cancellation of long-running requests
+//!
+//! A `SetMetadataTree` may take minutes on a large tree, and runs on a task
+//! of its own so the connection keeps handling other requests meanwhile,
+//! among them the `CancelSetMetadataTree` that stops it. Each running job
+//! has a flag here, which it checks between entries.
+
+use remotefs_common::protocol::RequestId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Cancellation flags of the running jobs, by request
+#[derive(Default)]
+pub struct JobTable {
+    jobs: Mutex<HashMap<RequestId, Arc<AtomicBool>>>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start job `request_id`; it is forgotten when the returned guard is
+    /// dropped
+    pub fn start(self: &Arc<Self>, request_id: RequestId) -> Job {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        lock_jobs(&self.jobs).insert(request_id, Arc::clone(&cancelled));
+        Job { table: Arc::clone(self), request_id, cancelled }
+    }
+
+    /// Ask job `request_id` to stop; `false` if it is not running
+    pub fn cancel(&self, request_id: &RequestId) -> bool {
+        match lock_jobs(&self.jobs).get(request_id) {
+            Some(cancelled) => {
+                cancelled.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Jobs running
+    pub fn len(&self) -> usize {
+        lock_jobs(&self.jobs).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A running job
+pub struct Job {
+    table: Arc<JobTable>,
+    request_id: RequestId,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    /// Whether the job was asked to stop
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        lock_jobs(&self.table.jobs).remove(&self.request_id);
+    }
+}
+
+fn lock_jobs<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_cancel() {
+        let table = Arc::new(JobTable::new());
+        let request_id = Uuid::new_v4();
+        let job = table.start(request_id);
+        assert!(!job.is_cancelled());
+
+        assert!(table.cancel(&request_id));
+        assert!(job.is_cancelled());
+
+        drop(job);
+        assert!(table.is_empty());
+        assert!(!table.cancel(&request_id));
+    }
+}
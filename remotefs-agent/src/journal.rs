@@ -0,0 +1,378 @@
+//! Change journal for incremental sync
+//!
+//! Records the creates, modifications, deletes and renames made through the
+//! agent under consecutive sequence numbers, so sync clients can ask for the
+//! changes after a cursor instead of re-walking directory trees. Entries are
+//! appended to a JSON-lines file and reloaded when the agent restarts.
+
+use remotefs_common::{
+    config::JournalConfig,
+    error::{RemoteFsError, Result},
+    protocol::{ChangeKind, ChangeRecord, ChangeSet},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Sequence-numbered record of changes made through the agent
+pub struct ChangeJournal {
+    path: Option<PathBuf>,
+    max_entries: usize,
+    /// When this process started recording
+    opened_at: DateTime<Utc>,
+    state: Mutex<JournalState>,
+}
+
+struct JournalState {
+    records: VecDeque<ChangeRecord>,
+    last_sequence: u64,
+    file: Option<File>,
+    /// Lines in the journal file, including ones no longer retained
+    file_lines: usize,
+}
+
+impl ChangeJournal {
+    /// Journal that is not persisted
+    pub fn in_memory(max_entries: usize) -> Self {
+        Self {
+            path: None,
+            max_entries: max_entries.max(1),
+            opened_at: Utc::now(),
+            state: Mutex::new(JournalState {
+                records: VecDeque::new(),
+                last_sequence: 0,
+                file: None,
+                file_lines: 0,
+            }),
+        }
+    }
+
+    /// Open the journal at `path`, loading the changes recorded before
+    pub fn open(path: &Path, max_entries: usize) -> Result<Self> {
+        let max_entries = max_entries.max(1);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut records = VecDeque::new();
+        let mut file_lines = 0;
+        let mut skipped = 0;
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                file_lines += 1;
+                match serde_json::from_str::<ChangeRecord>(&line) {
+                    Ok(record) => {
+                        if records.len() == max_entries {
+                            records.pop_front();
+                        }
+                        records.push_back(record);
+                    }
+                    // Most likely the tail of a write interrupted by a crash
+                    Err(e) => {
+                        warn!("Skipping unreadable journal entry in {}: {}", path.display(), e);
+                        skipped += 1;
+                    }
+                }
+            }
+        }
+
+        // Rewrite a damaged file so new entries do not continue a torn line
+        if skipped > 0 {
+            rewrite(path, &records)?;
+            file_lines = records.len();
+        }
+
+        let last_sequence = records.back().map(|r| r.sequence).unwrap_or(0);
+        info!(
+            "Loaded change journal {} ({} entries, last sequence {})",
+            path.display(), records.len(), last_sequence
+        );
+
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            max_entries,
+            opened_at: Utc::now(),
+            state: Mutex::new(JournalState {
+                records,
+                last_sequence,
+                file: None,
+                file_lines,
+            }),
+        })
+    }
+
+    /// Open the journal described by `config`, or `None` if it is disabled
+    pub fn from_config(config: &JournalConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let path = config.path.clone().unwrap_or_else(default_journal_path);
+        Self::open(&path, config.max_entries).map(Some)
+    }
+
+    /// Record a change and return its sequence number
+    ///
+    /// Failing to persist the entry is logged but does not fail the change.
+    pub async fn record(&self, kind: ChangeKind, path: &str, is_dir: bool) -> u64 {
+        let mut state = self.state.lock().await;
+        state.last_sequence += 1;
+        let record = ChangeRecord {
+            sequence: state.last_sequence,
+            timestamp: Utc::now(),
+            kind,
+            path: path.to_string(),
+            is_dir,
+        };
+
+        if let Err(e) = self.append(&mut state, &record) {
+            warn!("Failed to persist change journal entry {}: {}", record.sequence, e);
+        }
+
+        if state.records.len() == self.max_entries {
+            state.records.pop_front();
+        }
+        let sequence = record.sequence;
+        state.records.push_back(record);
+
+        // Rewrite the file once it holds twice the retained entries
+        if state.file_lines > self.max_entries * 2 {
+            if let Err(e) = self.compact(&mut state) {
+                warn!("Failed to compact change journal: {}", e);
+            }
+        }
+
+        sequence
+    }
+
+    /// Changes recorded after `since`, at most `limit` of them
+    ///
+    /// A cursor older than the retained history (or newer than the journal,
+    /// e.g. after the journal file was removed) yields `reset` so the client
+    /// knows to rescan.
+    pub async fn changes_since(&self, since: u64, limit: usize) -> ChangeSet {
+        let state = self.state.lock().await;
+        let first_retained = state.last_sequence + 1 - state.records.len() as u64;
+
+        if since > state.last_sequence || since + 1 < first_retained {
+            return ChangeSet {
+                changes: Vec::new(),
+                next_cursor: state.last_sequence,
+                reset: true,
+            };
+        }
+
+        let changes: Vec<ChangeRecord> = state.records
+            .iter()
+            .skip((since + 1 - first_retained) as usize)
+            .take(limit)
+            .cloned()
+            .collect();
+        let next_cursor = changes.last().map(|r| r.sequence).unwrap_or(since);
+
+        ChangeSet {
+            changes,
+            next_cursor,
+            reset: false,
+        }
+    }
+
+    /// Whether `path` is known to be unchanged since `as_of`
+    ///
+    /// Changes to the path itself and to any of its ancestors count. Returns
+    /// `None` when the journal does not reach back to `as_of`: before this
+    /// process started recording (changes made while the agent was stopped
+    /// are not journaled), or before the oldest retained entry once older
+    /// ones have been dropped.
+    pub async fn unchanged_since(&self, path: &str, as_of: DateTime<Utc>) -> Option<bool> {
+        let state = self.state.lock().await;
+        let covered_since = match state.records.front() {
+            Some(first) if first.sequence > 1 => first.timestamp.max(self.opened_at),
+            _ => self.opened_at,
+        };
+        if as_of < covered_since {
+            return None;
+        }
+
+        let path = Path::new(path);
+        let affects = |changed: &str| path.starts_with(changed);
+        let changed = state.records
+            .iter()
+            .rev()
+            .take_while(|r| r.timestamp > as_of)
+            .any(|r| match &r.kind {
+                ChangeKind::Renamed { from } => affects(from) || affects(&r.path),
+                _ => affects(&r.path),
+            });
+        Some(!changed)
+    }
+
+    /// Sequence number of the most recent change
+    pub async fn last_sequence(&self) -> u64 {
+        self.state.lock().await.last_sequence
+    }
+
+    fn append(&self, state: &mut JournalState, record: &ChangeRecord) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if state.file.is_none() {
+            state.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        if let Some(file) = state.file.as_mut() {
+            writeln!(file, "{}", encode(record)?)?;
+        }
+        state.file_lines += 1;
+        Ok(())
+    }
+
+    fn compact(&self, state: &mut JournalState) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        rewrite(path, &state.records)?;
+        state.file = None;
+        state.file_lines = state.records.len();
+        Ok(())
+    }
+}
+
+/// Atomically replace the journal file with `records`
+fn rewrite(path: &Path, records: &VecDeque<ChangeRecord>) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut temp = File::create(&temp_path)?;
+        for record in records {
+            writeln!(temp, "{}", encode(record)?)?;
+        }
+        temp.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn encode(record: &ChangeRecord) -> Result<String> {
+    serde_json::to_string(record)
+        .map_err(|e| RemoteFsError::Internal(format!("Failed to encode journal entry: {}", e)))
+}
+
+/// Default journal location next to the agent's other state
+pub fn default_journal_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join(".remotefs")
+        .join("journal.jsonl")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_changes_since_cursor() {
+        let journal = ChangeJournal::in_memory(100);
+        journal.record(ChangeKind::Created, "/data/a.txt", false).await;
+        journal.record(ChangeKind::Modified, "/data/a.txt", false).await;
+        journal.record(ChangeKind::Renamed { from: "/data/a.txt".to_string() }, "/data/b.txt", false).await;
+
+        let all = journal.changes_since(0, 100).await;
+        assert!(!all.reset);
+        assert_eq!(all.changes.len(), 3);
+        assert_eq!(all.next_cursor, 3);
+
+        let page = journal.changes_since(1, 1).await;
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(page.changes[0].kind, ChangeKind::Modified);
+        assert_eq!(page.next_cursor, 2);
+
+        let none = journal.changes_since(3, 100).await;
+        assert!(none.changes.is_empty());
+        assert_eq!(none.next_cursor, 3);
+        assert!(!none.reset);
+    }
+
+    #[tokio::test]
+    async fn test_reset_when_history_is_dropped() {
+        let journal = ChangeJournal::in_memory(2);
+        for i in 0..5 {
+            journal.record(ChangeKind::Created, &format!("/data/{}", i), false).await;
+        }
+
+        // Sequences 1-3 are gone
+        let stale = journal.changes_since(1, 100).await;
+        assert!(stale.reset);
+        assert_eq!(stale.next_cursor, 5);
+
+        let current = journal.changes_since(3, 100).await;
+        assert!(!current.reset);
+        assert_eq!(current.changes.iter().map(|r| r.sequence).collect::<Vec<_>>(), vec![4, 5]);
+
+        // A cursor from a journal that no longer exists
+        assert!(journal.changes_since(42, 100).await.reset);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_since() {
+        let journal = ChangeJournal::in_memory(2);
+        let before = Utc::now();
+        assert_eq!(journal.unchanged_since("/data/a.txt", before - chrono::Duration::hours(1)).await, None);
+
+        journal.record(ChangeKind::Created, "/data/a.txt", false).await;
+        let after_create = Utc::now();
+        journal.record(ChangeKind::Renamed { from: "/data/dir".to_string() }, "/data/moved", true).await;
+
+        assert_eq!(journal.unchanged_since("/data/a.txt", before).await, Some(false));
+        assert_eq!(journal.unchanged_since("/data/a.txt", after_create).await, Some(true));
+        // Renaming an ancestor changes everything beneath it
+        assert_eq!(journal.unchanged_since("/data/dir/b.txt", after_create).await, Some(false));
+        assert_eq!(journal.unchanged_since("/data/moved/b.txt", after_create).await, Some(false));
+        // Sibling with a common prefix
+        assert_eq!(journal.unchanged_since("/data/directory/b.txt", after_create).await, Some(true));
+
+        // Once the create is dropped, history before the oldest entry is unknown
+        journal.record(ChangeKind::Modified, "/data/c.txt", false).await;
+        assert_eq!(journal.unchanged_since("/data/a.txt", before).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_persisted_across_restarts() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("journal.jsonl");
+
+        {
+            let journal = ChangeJournal::open(&path, 3).unwrap();
+            for i in 0..10 {
+                journal.record(ChangeKind::Created, &format!("/data/{}", i), false).await;
+            }
+            journal.record(ChangeKind::Deleted, "/data/dir", true).await;
+        }
+
+        // Compaction keeps the file bounded
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 7, "journal file has {} lines", lines);
+
+        // A torn final write is skipped on load
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"sequence\":").unwrap();
+
+        let journal = ChangeJournal::open(&path, 3).unwrap();
+        assert_eq!(journal.last_sequence().await, 11);
+
+        let changes = journal.changes_since(8, 100).await;
+        assert!(!changes.reset);
+        assert_eq!(changes.changes.len(), 3);
+        assert_eq!(changes.changes[2].kind, ChangeKind::Deleted);
+        assert!(changes.changes[2].is_dir);
+
+        assert_eq!(journal.record(ChangeKind::Created, "/data/new", false).await, 12);
+    }
+}
@@ -4,14 +4,43 @@
 //! allowing secure access to local file systems through a relay server.
 
 pub mod access;
+pub mod archive;
+pub mod audit;
+pub mod blocking;
+pub mod cli;
 pub mod filesystem;
 pub mod connection;
 pub mod server;
 pub mod config_utils;
+#[cfg(feature = "remote-exec")]
+pub mod exec;
+pub mod exports;
+pub mod handles;
+pub mod ids;
+pub mod jobs;
+pub mod journal;
+pub mod limits;
+pub mod local;
+pub mod locks;
+pub mod mirror;
+pub mod prefetch;
+pub mod quota;
+pub mod rate;
+pub mod reload;
+pub mod search;
+pub mod selftest;
+pub mod streams;
+pub mod transaction;
+pub mod trash;
+pub mod watch;
+pub mod xattr;
 
 // Re-export commonly used types
 pub use access::AccessControl;
+pub use archive::ArchiveHooks;
 pub use filesystem::FilesystemHandler;
+pub use journal::ChangeJournal;
+pub use mirror::{MirrorState, Replicator};
 pub use server::AgentServer;
 pub use config_utils::{create_default_agent_config, load_config_from_file, save_config_to_file};
 
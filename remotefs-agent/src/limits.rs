@@ -0,0 +1,471 @@
+//! Guardrails on memory and file descriptors used by in-flight requests
+//!
+//! Each request that reads or writes file data reserves the bytes it will
+//! hold in memory and one open file before touching the disk, and releases
+//! them when its permit is dropped. A request that would go over either
+//! limit is refused so the client can retry later, rather than the agent
+//! running out of memory when many large reads arrive at once.
+//!
+//! Requests also take a slot while they are handled, from a pool for those
+//! that change files and one for those that only read, so a burst of
+//! requests cannot pile up unbounded blocking work. A request finding every
+//! slot of its kind taken waits its turn, unless `max_queued_operations`
+//! requests already wait, in which case it is refused like one over the
+//! memory limit.
+//!
+//! Requests are also checked for sizes no sane client asks for: reads and
+//! listings too large for one response, oversized pages, and overlong or
+//! overly deep paths. Their refusals name the limit and its value in the
+//! error's `limit` and `max` details, so a client can adapt the request.
+
+use crate::server::ResourceStatistics;
+use remotefs_common::{
+    config::ResourceLimitsConfig,
+    protocol::{ErrorCode, Message, RequestId},
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, PoisonError, RwLock,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The resource a refused request would have exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exhausted {
+    Memory,
+    OpenFiles,
+    ConcurrentReads,
+    ConcurrentWrites,
+}
+
+impl Exhausted {
+    /// Short name used in error details
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Exhausted::Memory => "memory",
+            Exhausted::OpenFiles => "open_files",
+            Exhausted::ConcurrentReads => "concurrent_reads",
+            Exhausted::ConcurrentWrites => "concurrent_writes",
+        }
+    }
+}
+
+/// Which pool of slots a request takes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// Shared budget of buffered bytes, open files and request slots
+#[derive(Debug)]
+pub struct ResourceLimits {
+    /// Replaced when the configuration is reloaded
+    maxima: RwLock<Maxima>,
+    /// Replaced when a reload changes their size
+    slots: RwLock<Slots>,
+    buffered: AtomicU64,
+    open_files: AtomicUsize,
+    queued: AtomicUsize,
+    shed_requests: AtomicU64,
+    oversized_responses: AtomicU64,
+}
+
+/// The configured limits, in the units they are checked in
+#[derive(Debug, Clone, Copy)]
+struct Maxima {
+    buffered: u64,
+    open_files: usize,
+    response: u64,
+    listing_entries: usize,
+    page_entries: u32,
+    path_length: usize,
+    path_depth: usize,
+    queued: usize,
+}
+
+impl Maxima {
+    fn new(config: &ResourceLimitsConfig) -> Self {
+        Self {
+            buffered: config.max_buffer_mb.saturating_mul(1024 * 1024),
+            open_files: config.max_open_files.max(1),
+            response: config.max_response_mb.saturating_mul(1024 * 1024),
+            listing_entries: config.max_listing_entries,
+            page_entries: config.max_page_entries,
+            path_length: config.max_path_length,
+            path_depth: config.max_path_depth,
+            queued: config.max_queued_operations,
+        }
+    }
+}
+
+/// Slots for requests that read and for those that write
+#[derive(Debug)]
+struct Slots {
+    reads: Arc<Semaphore>,
+    writes: Arc<Semaphore>,
+    sizes: (usize, usize),
+}
+
+impl Slots {
+    fn new(config: &ResourceLimitsConfig) -> Self {
+        let sizes = (config.max_concurrent_reads.max(1), config.max_concurrent_writes.max(1));
+        Self {
+            reads: Arc::new(Semaphore::new(sizes.0)),
+            writes: Arc::new(Semaphore::new(sizes.1)),
+            sizes,
+        }
+    }
+}
+
+impl ResourceLimits {
+    pub fn new(config: &ResourceLimitsConfig) -> Self {
+        Self {
+            maxima: RwLock::new(Maxima::new(config)),
+            slots: RwLock::new(Slots::new(config)),
+            buffered: AtomicU64::new(0),
+            open_files: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            shed_requests: AtomicU64::new(0),
+            oversized_responses: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply reloaded limits; requests holding resources keep them, and
+    /// new requests are measured against the new limits
+    pub fn reconfigure(&self, config: &ResourceLimitsConfig) {
+        *self.maxima.write().unwrap_or_else(PoisonError::into_inner) = Maxima::new(config);
+        let slots = Slots::new(config);
+        let mut current = self.slots.write().unwrap_or_else(PoisonError::into_inner);
+        if current.sizes != slots.sizes {
+            *current = slots;
+        }
+    }
+
+    fn maxima(&self) -> Maxima {
+        *self.maxima.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Largest response a single read may produce, in bytes
+    pub fn max_response(&self) -> u64 {
+        self.maxima().response
+    }
+
+    /// Whether a response of `bytes` is within the limit; counts refusals
+    pub fn allows_response(&self, bytes: u64) -> bool {
+        if bytes > self.maxima().response {
+            self.oversized_responses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Entries a whole-directory listing may return
+    pub fn max_listing_entries(&self) -> usize {
+        self.maxima().listing_entries
+    }
+
+    /// Whether a listing of `entries` is within the limit; counts refusals
+    pub fn allows_listing(&self, entries: usize) -> bool {
+        if entries > self.maxima().listing_entries {
+            self.oversized_responses.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Most components a path may have
+    pub fn max_path_depth(&self) -> usize {
+        self.maxima().path_depth
+    }
+
+    /// Refusal for a request whose paths or page size are beyond the limits
+    pub fn check_request(&self, message: &Message) -> Option<Message> {
+        let request_id = message.request_id();
+        let maxima = self.maxima();
+        for path in message.request_paths() {
+            if path.len() > maxima.path_length {
+                return Some(over_limit(
+                    request_id, ErrorCode::InvalidMessage, "path_length", maxima.path_length as u64,
+                    format!("Path of {} bytes exceeds the agent's {} byte limit", path.len(), maxima.path_length),
+                ));
+            }
+            let depth = Path::new(path).components().count();
+            if depth > maxima.path_depth {
+                return Some(over_limit(
+                    request_id, ErrorCode::InvalidMessage, "path_depth", maxima.path_depth as u64,
+                    format!("Path with {} components exceeds the agent's limit of {}", depth, maxima.path_depth),
+                ));
+            }
+        }
+
+        let page_size = match message {
+            Message::ListDirectoryPaged { page_size, .. } => *page_size,
+            Message::AsUser { request, .. } => return self.check_request(request),
+            _ => return None,
+        };
+        (page_size > maxima.page_entries).then(|| over_limit(
+            request_id, ErrorCode::InvalidMessage, "page_entries", maxima.page_entries as u64,
+            format!("Pages of {} entries exceed the agent's limit of {}", page_size, maxima.page_entries),
+        ))
+    }
+
+    /// Reserve `bytes` of buffer memory and one open file
+    ///
+    /// A request larger than the whole memory budget is still let through
+    /// when nothing else is buffered, so it can never be refused forever.
+    pub fn acquire(self: &Arc<Self>, bytes: u64) -> Result<ResourcePermit, Exhausted> {
+        let maxima = self.maxima();
+        let files = self.open_files.fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+            (open < maxima.open_files).then_some(open + 1)
+        });
+        if files.is_err() {
+            self.shed_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(Exhausted::OpenFiles);
+        }
+
+        let buffered = self.buffered.fetch_update(Ordering::AcqRel, Ordering::Acquire, |buffered| {
+            (buffered == 0 || buffered.saturating_add(bytes) <= maxima.buffered).then(|| buffered + bytes)
+        });
+        if buffered.is_err() {
+            self.open_files.fetch_sub(1, Ordering::AcqRel);
+            self.shed_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(Exhausted::Memory);
+        }
+
+        Ok(ResourcePermit { limits: Arc::clone(self), bytes })
+    }
+
+    /// Take a slot for an `operation`, waiting for one to be free if all are
+    /// taken, or refuse it if the queue is full as well
+    pub async fn begin(&self, operation: Operation) -> Result<OperationPermit, Exhausted> {
+        let (slots, exhausted) = {
+            let slots = self.slots.read().unwrap_or_else(PoisonError::into_inner);
+            match operation {
+                Operation::Read => (Arc::clone(&slots.reads), Exhausted::ConcurrentReads),
+                Operation::Write => (Arc::clone(&slots.writes), Exhausted::ConcurrentWrites),
+            }
+        };
+        if let Ok(slot) = Arc::clone(&slots).try_acquire_owned() {
+            return Ok(OperationPermit { _slot: slot });
+        }
+
+        let max_queued = self.maxima().queued;
+        let queued = self.queued.fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+            (queued < max_queued).then_some(queued + 1)
+        });
+        if queued.is_err() {
+            self.shed_requests.fetch_add(1, Ordering::Relaxed);
+            return Err(exhausted);
+        }
+        let _queued = Queued(&self.queued);
+        // The semaphores are never closed
+        slots.acquire_owned().await
+            .map(|slot| OperationPermit { _slot: slot })
+            .map_err(|_| exhausted)
+    }
+
+    /// Current usage and refusal counts
+    pub fn statistics(&self) -> ResourceStatistics {
+        ResourceStatistics {
+            buffered_bytes: self.buffered.load(Ordering::Relaxed),
+            open_files: self.open_files.load(Ordering::Relaxed),
+            shed_requests: self.shed_requests.load(Ordering::Relaxed),
+            oversized_responses: self.oversized_responses.load(Ordering::Relaxed),
+            queued_requests: self.queued.load(Ordering::Relaxed),
+            ..ResourceStatistics::default()
+        }
+    }
+}
+
+/// Error answering a request beyond one of the limits, naming the limit and
+/// its value in the details
+pub fn over_limit(request_id: Option<RequestId>, code: ErrorCode, limit: &str, max: u64, message: String) -> Message {
+    Message::Error {
+        request_id,
+        code,
+        message,
+        details: Some(HashMap::from([
+            ("limit".to_string(), limit.to_string()),
+            ("max".to_string(), max.to_string()),
+        ])),
+        errno: None,
+    }
+}
+
+/// Resources held by one request, released on drop
+#[derive(Debug)]
+pub struct ResourcePermit {
+    limits: Arc<ResourceLimits>,
+    bytes: u64,
+}
+
+impl Drop for ResourcePermit {
+    fn drop(&mut self) {
+        self.limits.buffered.fetch_sub(self.bytes, Ordering::AcqRel);
+        self.limits.open_files.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A request's slot, freed on drop
+#[derive(Debug)]
+pub struct OperationPermit {
+    _slot: OwnedSemaphorePermit,
+}
+
+/// A place in the queue, left on drop, including when the waiting request
+/// is abandoned
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_buffer_mb: u64, max_open_files: usize) -> Arc<ResourceLimits> {
+        Arc::new(ResourceLimits::new(&ResourceLimitsConfig {
+            max_buffer_mb,
+            max_open_files,
+            max_response_mb: 1,
+            ..ResourceLimitsConfig::default()
+        }))
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let limits = limits(2, 10);
+        let mb = 1024 * 1024;
+
+        let first = limits.acquire(mb).unwrap();
+        let second = limits.acquire(mb).unwrap();
+        assert_eq!(limits.acquire(1).unwrap_err(), Exhausted::Memory);
+        assert_eq!(limits.statistics().buffered_bytes, 2 * mb);
+
+        drop(first);
+        let third = limits.acquire(mb).unwrap();
+        drop(second);
+        drop(third);
+        assert_eq!(limits.statistics().buffered_bytes, 0);
+        assert_eq!(limits.statistics().open_files, 0);
+        assert_eq!(limits.statistics().shed_requests, 1);
+
+        // Larger than the whole budget, but alone
+        let huge = limits.acquire(10 * mb).unwrap();
+        assert_eq!(limits.acquire(1).unwrap_err(), Exhausted::Memory);
+        drop(huge);
+    }
+
+    #[test]
+    fn test_open_file_limit() {
+        let limits = limits(512, 2);
+        let permits = [limits.acquire(0).unwrap(), limits.acquire(0).unwrap()];
+        assert_eq!(limits.acquire(0).unwrap_err(), Exhausted::OpenFiles);
+        // Refusing for files does not leak buffer reservations
+        assert_eq!(limits.acquire(100).unwrap_err(), Exhausted::OpenFiles);
+        assert_eq!(limits.statistics().buffered_bytes, 0);
+
+        // Reloaded limits apply to the next request
+        limits.reconfigure(&ResourceLimitsConfig { max_open_files: 3, ..ResourceLimitsConfig::default() });
+        let third = limits.acquire(0).unwrap();
+        assert_eq!(limits.acquire(0).unwrap_err(), Exhausted::OpenFiles);
+
+        drop(permits);
+        drop(third);
+        assert!(limits.acquire(0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let limits = ResourceLimits::new(&ResourceLimitsConfig {
+            max_concurrent_reads: 1,
+            max_concurrent_writes: 1,
+            max_queued_operations: 1,
+            ..ResourceLimitsConfig::default()
+        });
+
+        // Reads and writes have slots of their own
+        let read = limits.begin(Operation::Read).await.unwrap();
+        let write = limits.begin(Operation::Write).await.unwrap();
+
+        // One request may wait, and gets the slot once it is freed
+        let limits = Arc::new(limits);
+        let waiting = tokio::spawn({
+            let limits = Arc::clone(&limits);
+            async move { limits.begin(Operation::Read).await.map(drop) }
+        });
+        while limits.statistics().queued_requests == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limits.begin(Operation::Write).await.unwrap_err(), Exhausted::ConcurrentWrites);
+        assert_eq!(limits.statistics().shed_requests, 1);
+
+        drop(read);
+        waiting.await.unwrap().unwrap();
+        assert_eq!(limits.statistics().queued_requests, 0);
+
+        // Reloading with more slots applies to the next request
+        limits.reconfigure(&ResourceLimitsConfig { max_concurrent_writes: 2, ..ResourceLimitsConfig::default() });
+        let _second = limits.begin(Operation::Write).await.unwrap();
+        drop(write);
+    }
+
+    #[test]
+    fn test_response_limit() {
+        let limits = limits(512, 2);
+        assert!(limits.allows_response(1024 * 1024));
+        assert!(!limits.allows_response(1024 * 1024 + 1));
+        assert_eq!(limits.statistics().oversized_responses, 1);
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let limits = ResourceLimits::new(&ResourceLimitsConfig {
+            max_page_entries: 100,
+            max_path_length: 64,
+            max_path_depth: 4,
+            ..ResourceLimitsConfig::default()
+        });
+        let read = |path: &str| Message::ReadFile {
+            request_id: uuid::Uuid::new_v4(),
+            path: path.to_string(),
+            offset: 0,
+            length: 1,
+        };
+        let limit_of = |refusal: Option<Message>| match refusal {
+            Some(Message::Error { code: ErrorCode::InvalidMessage, details: Some(details), .. }) => {
+                (details["limit"].clone(), details["max"].clone())
+            }
+            other => panic!("Unexpected refusal: {:?}", other),
+        };
+
+        assert!(limits.check_request(&read("/data/a/b")).is_none());
+        assert_eq!(limit_of(limits.check_request(&read(&format!("/{}", "x".repeat(64))))), ("path_length".to_string(), "64".to_string()));
+        assert_eq!(limit_of(limits.check_request(&read("/data/a/b/c"))), ("path_depth".to_string(), "4".to_string()));
+
+        // Every path of a request counts
+        let rename = Message::Rename {
+            request_id: uuid::Uuid::new_v4(),
+            from_path: "/data/a".to_string(),
+            to_path: "/data/a/b/c/d".to_string(),
+            no_replace: false,
+        };
+        assert!(limits.check_request(&rename).is_some());
+
+        let page = |page_size| Message::ListDirectoryPaged {
+            request_id: uuid::Uuid::new_v4(),
+            path: "/data".to_string(),
+            page_size,
+            after: None,
+            max_entries: None,
+        };
+        assert!(limits.check_request(&page(100)).is_none());
+        assert_eq!(limit_of(limits.check_request(&page(101))), ("page_entries".to_string(), "100".to_string()));
+    }
+}
@@ -0,0 +1,219 @@
+//! Descriptor passing for clients on the agent's host
+//!
+//! When the client runs next to the agent, copying file data through the
+//! protocol is pure overhead. Such clients open files through a Unix socket
+//! instead: the agent checks each request like a `ReadFile` and answers with
+//! an `O_RDONLY` descriptor passed with `SCM_RIGHTS`, which the client reads
+//! directly.
+
+use crate::filesystem::FilesystemHandler;
+use remotefs_common::{
+    activation,
+    error::{RemoteFsError, Result},
+    protocol::{CallerIdentity, LocalOpenRequest, LocalOpenResponse},
+};
+use std::{
+    fs::Permissions,
+    io,
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::Interest,
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+use tracing::{debug, info, warn};
+
+/// Longest request line accepted
+const MAX_REQUEST_LINE: usize = 64 * 1024;
+
+/// Unix socket on which local clients open files
+pub struct LocalSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    /// Whether the socket was passed by systemd, which then removes it
+    passed: bool,
+}
+
+impl LocalSocket {
+    /// Listen on `path`, replacing a socket left behind by an earlier run
+    ///
+    /// The socket is only accessible to the agent's user.
+    pub fn bind(path: &Path) -> Result<Self> {
+        if let Some(socket) = Self::passed(path)? {
+            return Ok(socket);
+        }
+        
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+            use std::os::unix::fs::FileTypeExt;
+            metadata.file_type().is_socket()
+        }) {
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path).map_err(|e| RemoteFsError::Configuration(
+            format!("Failed to listen on local socket {}: {}", path.display(), e)
+        ))?;
+        std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+        Ok(Self { listener, path: path.to_path_buf(), passed: false })
+    }
+    
+    /// The Unix socket systemd bound, if the agent was socket activated;
+    /// `path` only names it in logs, as the socket unit decides where it is
+    fn passed(path: &Path) -> Result<Option<Self>> {
+        let Some(listener) = activation::take_unix_listener()? else {
+            return Ok(None);
+        };
+        let listener = UnixListener::from_std(listener)?;
+        let path = listener.local_addr().ok()
+            .and_then(|address| address.as_pathname().map(Path::to_path_buf))
+            .unwrap_or_else(|| path.to_path_buf());
+        Ok(Some(Self { listener, path, passed: true }))
+    }
+
+    /// Serve local clients until shutdown
+    pub async fn serve(self, filesystem_handler: Arc<FilesystemHandler>, mut shutdown_rx: broadcast::Receiver<()>) {
+        info!("Serving local clients on {}{}", self.path.display(), if self.passed { " (passed by systemd)" } else { "" });
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let handler = Arc::clone(&filesystem_handler);
+                        tokio::spawn(async move {
+                            if let Err(e) = serve_client(stream, handler).await {
+                                debug!("Local client disconnected: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Failed to accept local client: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    }
+                },
+                _ = shutdown_rx.recv() => break,
+            }
+        }
+    }
+}
+
+impl Drop for LocalSocket {
+    fn drop(&mut self) {
+        if !self.passed {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Answer one client's requests until it disconnects
+///
+/// Requests are checked for the connecting process's user, as for requests
+/// a shared mount makes on behalf of a local user.
+async fn serve_client(stream: UnixStream, filesystem_handler: Arc<FilesystemHandler>) -> io::Result<()> {
+    let credentials = stream.peer_cred()?;
+    let handler = filesystem_handler.for_caller(CallerIdentity {
+        uid: credentials.uid(),
+        gid: credentials.gid(),
+        groups: Vec::new(),
+    });
+
+    let mut buffer = Vec::new();
+    while let Some(line) = read_line(&stream, &mut buffer).await? {
+        let (response, file) = match serde_json::from_slice::<LocalOpenRequest>(&line) {
+            Ok(request) => match handler.open_for_local_read(&request.path).await {
+                Ok(file) => (LocalOpenResponse { error: None }, Some(file)),
+                Err(e) => (LocalOpenResponse { error: Some((e.to_error_code(), e.to_string())) }, None),
+            },
+            Err(e) => {
+                let error = RemoteFsError::Protocol(format!("Invalid local request: {}", e));
+                (LocalOpenResponse { error: Some((error.to_error_code(), error.to_string())) }, None)
+            }
+        };
+
+        let mut data = serde_json::to_vec(&response).map_err(io::Error::other)?;
+        data.push(b'\n');
+        let fd = file.as_ref().map(AsRawFd::as_raw_fd);
+        let mut sent = loop {
+            stream.writable().await?;
+            match stream.try_io(Interest::WRITABLE, || send_with_fd(stream.as_raw_fd(), &data, fd)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => break result?,
+            }
+        };
+        // The descriptor went with the first byte; send whatever did not fit
+        while sent < data.len() {
+            stream.writable().await?;
+            match stream.try_write(&data[sent..]) {
+                Ok(written) => sent += written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        // Our copy of the descriptor closes here; the client keeps its own
+    }
+    Ok(())
+}
+
+/// Next newline-terminated line from `stream`, or `None` at end of stream
+async fn read_line(stream: &UnixStream, buffer: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    loop {
+        if let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line = buffer.drain(..=end).collect();
+            return Ok(Some(line));
+        }
+        if buffer.len() > MAX_REQUEST_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+        }
+
+        stream.readable().await?;
+        match stream.try_read_buf(buffer) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Send `data` on `socket` with `fd` attached, returning how much was sent
+fn send_with_fd(socket: RawFd, data: &[u8], fd: Option<RawFd>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // u64 elements keep the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: a zeroed msghdr is a valid empty message
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+
+    if let Some(fd) = fd {
+        // SAFETY: the control buffer is large enough and aligned for one
+        // cmsghdr carrying a single descriptor
+        unsafe {
+            let space = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as usize;
+            debug_assert!(space <= std::mem::size_of_val(&control));
+            message.msg_control = control.as_mut_ptr().cast();
+            message.msg_controllen = space as _;
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(header).cast::<RawFd>(), fd);
+        }
+    }
+
+    // SAFETY: `message` points at `iov` and `control`, both alive for the call
+    let sent = unsafe { libc::sendmsg(socket, &message, 0) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
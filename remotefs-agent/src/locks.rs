@@ -0,0 +1,212 @@
+//! Advisory byte-range locks for `LockFile`, `UnlockFile` and `TestLock`
+//!
+//! Locks follow POSIX record locks: read locks are shared, a write lock
+//! excludes every other owner's locks on the same bytes, and a lock taken
+//! over an owner's own locks replaces them, splitting or merging ranges as
+//! `fcntl` does. Owners are qualified by the client session the relay names,
+//! and a session's locks are released when the relay reports it gone.
+//!
+//! Locks are only kept by the agent. Programs on the host do not see them,
+//! and they are lost if the agent restarts, as NFS locks are when a server
+//! reboots without lock recovery.
+
+use remotefs_common::protocol::{FileLock, LockOwner};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+/// Locks held on each file
+#[derive(Default)]
+pub struct LockTable {
+    files: Mutex<HashMap<PathBuf, Vec<FileLock>>>,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take `lock` on `path`, or return a lock of another owner in its way
+    pub fn lock(&self, path: &Path, lock: FileLock) -> Result<(), FileLock> {
+        let mut files = lock_files(&self.files);
+        let conflict = files.get(path).and_then(|locks| locks.iter().find(|held| held.conflicts_with(&lock)));
+        if let Some(conflict) = conflict {
+            return Err(conflict.clone());
+        }
+
+        let locks = files.entry(path.to_path_buf()).or_default();
+        remove_range(locks, &lock.owner, lock.start, lock.end());
+
+        // Join the owner's locks of the same type that touch the new one
+        let mut start = lock.start;
+        let mut end = lock.end();
+        locks.retain(|held| {
+            let touches = held.owner == lock.owner
+                && held.lock_type == lock.lock_type
+                && (held.end() == Some(start) || end == Some(held.start));
+            if touches {
+                start = start.min(held.start);
+                end = end.zip(held.end()).map(|(end, held_end)| end.max(held_end));
+            }
+            !touches
+        });
+        locks.push(range(&lock, start, end));
+        Ok(())
+    }
+
+    /// Release the locks of `owner` on the bytes from `start` up to `end`,
+    /// or up to the end of the file if `end` is `None`
+    pub fn unlock(&self, path: &Path, owner: &LockOwner, start: u64, end: Option<u64>) {
+        let mut files = lock_files(&self.files);
+        if let Some(locks) = files.get_mut(path) {
+            remove_range(locks, owner, start, end);
+            if locks.is_empty() {
+                files.remove(path);
+            }
+        }
+    }
+
+    /// A lock of another owner that would keep `lock` from being taken
+    pub fn test(&self, path: &Path, lock: &FileLock) -> Option<FileLock> {
+        lock_files(&self.files)
+            .get(path)?
+            .iter()
+            .find(|held| held.conflicts_with(lock))
+            .cloned()
+    }
+
+    /// Release every lock of a client session, returning how many there were
+    pub fn release_session(&self, session: &str) -> usize {
+        let mut files = lock_files(&self.files);
+        let mut released = 0;
+        files.retain(|_, locks| {
+            let before = locks.len();
+            locks.retain(|held| held.owner.session != session);
+            released += before - locks.len();
+            !locks.is_empty()
+        });
+        released
+    }
+
+    /// Release every lock, after the connection of their sessions closed
+    pub fn clear(&self) {
+        lock_files(&self.files).clear();
+    }
+
+    /// Number of locks held
+    pub fn lock_count(&self) -> usize {
+        lock_files(&self.files).values().map(Vec::len).sum()
+    }
+}
+
+/// Remove the bytes from `start` up to `end` from the locks of `owner`,
+/// keeping what is left of them on either side
+fn remove_range(locks: &mut Vec<FileLock>, owner: &LockOwner, start: u64, end: Option<u64>) {
+    let mut kept = Vec::with_capacity(locks.len());
+    for held in locks.drain(..) {
+        if held.owner != *owner || !held.overlaps(start, end) {
+            kept.push(held);
+            continue;
+        }
+        if held.start < start {
+            kept.push(range(&held, held.start, Some(start)));
+        }
+        if let Some(end) = end {
+            if held.end().is_none_or(|held_end| held_end > end) {
+                kept.push(range(&held, end, held.end()));
+            }
+        }
+    }
+    *locks = kept;
+}
+
+/// A lock like `lock` on the bytes from `start` up to `end`
+fn range(lock: &FileLock, start: u64, end: Option<u64>) -> FileLock {
+    FileLock {
+        lock_type: lock.lock_type,
+        start,
+        length: end.map_or(0, |end| end - start),
+        owner: lock.owner.clone(),
+    }
+}
+
+fn lock_files<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::protocol::LockType;
+
+    fn lock(session: &str, owner: u64, lock_type: LockType, start: u64, length: u64) -> FileLock {
+        FileLock {
+            lock_type,
+            start,
+            length,
+            owner: LockOwner { session: session.to_string(), owner },
+        }
+    }
+
+    fn held(table: &LockTable, path: &Path) -> Vec<(LockType, u64, u64)> {
+        let mut locks: Vec<_> = lock_files(&table.files)
+            .get(path)
+            .map(|locks| locks.iter().map(|held| (held.lock_type, held.start, held.length)).collect())
+            .unwrap_or_default();
+        locks.sort_by_key(|&(_, start, _)| start);
+        locks
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let table = LockTable::new();
+        let path = Path::new("/data/db.sqlite");
+
+        // Read locks are shared, write locks are not
+        table.lock(path, lock("a", 1, LockType::Read, 0, 100)).unwrap();
+        table.lock(path, lock("b", 1, LockType::Read, 50, 100)).unwrap();
+        let conflict = table.lock(path, lock("b", 1, LockType::Write, 0, 10)).unwrap_err();
+        assert_eq!(conflict.owner.session, "a");
+
+        // The same owner number in another session is another owner
+        assert!(table.test(path, &lock("b", 1, LockType::Write, 200, 0)).is_none());
+        table.lock(path, lock("a", 2, LockType::Write, 200, 0)).unwrap();
+        assert!(table.test(path, &lock("a", 1, LockType::Read, 1_000_000, 1)).is_some());
+        assert!(table.test(path, &lock("a", 2, LockType::Read, 1_000_000, 1)).is_none());
+
+        assert!(table.test(Path::new("/data/other"), &lock("b", 1, LockType::Write, 0, 0)).is_none());
+        assert_eq!(table.release_session("a"), 2);
+        assert_eq!(table.lock_count(), 1);
+        table.lock(path, lock("c", 1, LockType::Write, 0, 50)).unwrap();
+    }
+
+    #[test]
+    fn test_ranges_split_and_merge() {
+        let table = LockTable::new();
+        let path = Path::new("/data/repo/index");
+
+        table.lock(path, lock("a", 1, LockType::Write, 0, 100)).unwrap();
+        table.unlock(path, &LockOwner { session: "a".to_string(), owner: 1 }, 40, Some(60));
+        assert_eq!(held(&table, path), vec![(LockType::Write, 0, 40), (LockType::Write, 60, 40)]);
+
+        // Relocking the gap joins the pieces again
+        table.lock(path, lock("a", 1, LockType::Write, 40, 20)).unwrap();
+        assert_eq!(held(&table, path), vec![(LockType::Write, 0, 100)]);
+
+        // A read lock in the middle downgrades only those bytes
+        table.lock(path, lock("a", 1, LockType::Read, 10, 10)).unwrap();
+        assert_eq!(
+            held(&table, path),
+            vec![(LockType::Write, 0, 10), (LockType::Read, 10, 10), (LockType::Write, 20, 80)]
+        );
+
+        // Up to the end of the file
+        table.lock(path, lock("a", 1, LockType::Write, 50, 0)).unwrap();
+        assert_eq!(
+            held(&table, path),
+            vec![(LockType::Write, 0, 10), (LockType::Read, 10, 10), (LockType::Write, 20, 0)]
+        );
+        table.unlock(path, &LockOwner { session: "a".to_string(), owner: 1 }, 0, None);
+        assert_eq!(table.lock_count(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+//! Read-only mirror of another agent
+//!
+//! A mirror agent follows its primary's change journal over an ordinary client
+//! connection and applies each change at the same path locally. Replication
+//! is asynchronous, so the mirror trails the primary by up to a poll interval,
+//! and by however long the primary was unreachable before it disappeared.
+//! The mirror refuses writes until the relay promotes it with writes allowed.
+//! On startup, or when the primary's journal no longer reaches back to the
+//! mirror's cursor, the whole tree is copied again.
+
+use remotefs_client::{AgentConfig as ClientAgentConfig, Client, ClientConfig, ClientError, ClientResult};
+use remotefs_common::{
+    config::MirrorConfig,
+    error::{RemoteFsError, Result},
+    protocol::{ChangeKind, ChangeRecord},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashSet,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{debug, info, warn};
+
+/// Changes fetched per journal poll
+const CHANGES_PER_POLL: u32 = 500;
+
+/// Promotion state of a mirror agent, as last announced by the relay
+#[derive(Debug, Default)]
+pub struct MirrorState {
+    promoted: AtomicBool,
+    writes_allowed: AtomicBool,
+    last_synced: RwLock<Option<DateTime<Utc>>>,
+}
+
+impl MirrorState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the relay currently routes requests to this mirror
+    pub fn is_promoted(&self) -> bool {
+        self.promoted.load(Ordering::Relaxed)
+    }
+
+    /// Whether clients may modify the mirror's files
+    pub fn allows_writes(&self) -> bool {
+        self.is_promoted() && self.writes_allowed.load(Ordering::Relaxed)
+    }
+
+    /// Apply a `MirrorStatus` announcement from the relay
+    pub async fn set_promoted(&self, primary: &str, promoted: bool, writes_allowed: bool) {
+        self.writes_allowed.store(promoted && writes_allowed, Ordering::Relaxed);
+        if self.promoted.swap(promoted, Ordering::Relaxed) == promoted {
+            return;
+        }
+
+        if promoted {
+            let last_synced = self.last_synced().await
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_else(|| "never".to_string());
+            warn!(
+                "Promoted to serve {} for primary {}; last replicated {}, later changes on the primary are missing",
+                if writes_allowed { "reads and writes" } else { "reads" },
+                primary,
+                last_synced
+            );
+        } else {
+            info!("Primary {} is back, demoted to read-only mirror", primary);
+        }
+    }
+
+    /// When the mirror last caught up with the primary's journal
+    pub async fn last_synced(&self) -> Option<DateTime<Utc>> {
+        *self.last_synced.read().await
+    }
+
+    async fn mark_synced(&self) {
+        *self.last_synced.write().await = Some(Utc::now());
+    }
+}
+
+/// Copies changes from the primary into the local tree
+pub struct Replicator {
+    client: Client,
+    root: PathBuf,
+    state: Arc<MirrorState>,
+    poll_interval: Duration,
+    /// Journal position applied so far; `None` until the first full copy
+    cursor: Option<u64>,
+}
+
+impl Replicator {
+    pub fn new(config: &MirrorConfig, state: Arc<MirrorState>) -> Result<Self> {
+        let client = Client::new(ClientConfig {
+            agents: vec![ClientAgentConfig {
+                id: config.primary_id.clone(),
+                url: config.primary_url.clone(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..ClientConfig::default()
+        })
+        .map_err(|e| RemoteFsError::Configuration(format!("Invalid mirror configuration: {}", e)))?;
+
+        Ok(Self {
+            client,
+            root: config.root.clone(),
+            state,
+            poll_interval: Duration::from_secs(config.poll_interval_secs.max(1)),
+            cursor: None,
+        })
+    }
+
+    /// Replicate until shutdown; paused while the mirror is promoted
+    pub async fn run(mut self, mut shutdown_rx: broadcast::Receiver<()>) {
+        let mut interval = tokio::time::interval(self.poll_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut connected = false;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_rx.recv() => break,
+            }
+
+            if self.state.is_promoted() {
+                continue;
+            }
+
+            if !connected {
+                match self.client.initialize().await {
+                    Ok(()) => connected = true,
+                    Err(e) => {
+                        debug!("Primary not reachable: {}", e);
+                        continue;
+                    }
+                }
+            }
+
+            match self.sync_once().await {
+                Ok(0) => {}
+                Ok(applied) => debug!("Replicated {} changes from primary", applied),
+                Err(e) => warn!("Replication from primary failed: {}", e),
+            }
+        }
+    }
+
+    /// Apply the primary's pending changes, returning how many were applied
+    pub async fn sync_once(&mut self) -> ClientResult<usize> {
+        let Some(mut cursor) = self.cursor else {
+            self.resync().await?;
+            return Ok(0);
+        };
+
+        let mut applied = 0;
+        loop {
+            let changes = self.client.get_changes(cursor, Some(CHANGES_PER_POLL)).await?;
+            if changes.reset {
+                info!("Primary journal no longer covers cursor {}, copying the whole tree", cursor);
+                self.resync().await?;
+                return Ok(applied);
+            }
+
+            for change in changes.changes.iter().filter(|c| Path::new(&c.path).starts_with(&self.root)) {
+                self.apply(change).await?;
+                applied += 1;
+            }
+            cursor = changes.next_cursor;
+            self.cursor = Some(cursor);
+
+            if changes.changes.len() < CHANGES_PER_POLL as usize {
+                break;
+            }
+        }
+
+        self.state.mark_synced().await;
+        Ok(applied)
+    }
+
+    /// Copy the whole tree and continue from the primary's current position
+    async fn resync(&mut self) -> ClientResult<()> {
+        // A cursor past the end of the journal yields the latest position;
+        // changes made during the copy are applied again afterwards
+        let start = self.client.get_changes(u64::MAX, Some(0)).await?.next_cursor;
+
+        let mut pending = vec![self.root.clone()];
+        while let Some(dir) = pending.pop() {
+            fs::create_dir_all(&dir)?;
+            let entries = self.client.list_directory(&dir).await?;
+            let names: HashSet<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+
+            for entry in &entries {
+                let path = dir.join(&entry.name);
+                if entry.metadata.is_dir {
+                    pending.push(path);
+                } else if needs_copy(&path, entry.metadata.size, entry.metadata.modified) {
+                    self.fetch(&path).await?;
+                }
+            }
+
+            // Remove what no longer exists on the primary
+            for local in fs::read_dir(&dir)? {
+                let local = local?;
+                if local.file_name().to_str().is_some_and(|name| !names.contains(name)) {
+                    remove_path(&local.path())?;
+                }
+            }
+        }
+
+        self.cursor = Some(start);
+        self.state.mark_synced().await;
+        info!("Copied {} from primary", self.root.display());
+        Ok(())
+    }
+
+    async fn apply(&self, change: &ChangeRecord) -> ClientResult<()> {
+        let path = Path::new(&change.path);
+        match &change.kind {
+            ChangeKind::Created | ChangeKind::Modified if change.is_dir => fs::create_dir_all(path)?,
+            ChangeKind::Created | ChangeKind::Modified => self.fetch(path).await?,
+            ChangeKind::Deleted => remove_path(path)?,
+            ChangeKind::Renamed { from } => {
+                let from = Path::new(from);
+                if from.starts_with(&self.root) && from.exists() {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    fs::rename(from, path)?;
+                } else if !change.is_dir {
+                    self.fetch(path).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Download a file from the primary, replacing the local copy atomically
+    async fn fetch(&self, path: &Path) -> ClientResult<()> {
+        let data = match self.client.read_file(path).await {
+            Ok(data) => data,
+            // Deleted again since; a later change removes the local copy
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(_)) => {
+                debug!("Skipping {}: {}", path.display(), e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_path = temp_path(path);
+        fs::write(&temp_path, &data)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Whether the local copy of a file is missing or older than the primary's
+fn needs_copy(path: &Path, size: u64, modified: DateTime<Utc>) -> bool {
+    match fs::metadata(path) {
+        Ok(local) => {
+            let local_modified = local.modified().map(DateTime::<Utc>::from).ok();
+            local.len() != size || local_modified.is_none_or(|m| m < modified)
+        }
+        Err(_) => true,
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file");
+    path.with_file_name(format!(".{}.remotefs-mirror", name))
+}
+
+fn remove_path(path: &Path) -> io::Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_promotion_state() {
+        let state = MirrorState::new();
+        assert!(!state.is_promoted());
+        assert!(!state.allows_writes());
+
+        state.set_promoted("primary", true, false).await;
+        assert!(state.is_promoted());
+        assert!(!state.allows_writes());
+
+        state.set_promoted("primary", true, true).await;
+        assert!(state.allows_writes());
+
+        state.set_promoted("primary", false, true).await;
+        assert!(!state.is_promoted());
+        assert!(!state.allows_writes());
+    }
+
+    #[test]
+    fn test_needs_copy() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.txt");
+        assert!(needs_copy(&path, 3, Utc::now()));
+
+        fs::write(&path, b"abc").unwrap();
+        let long_ago = DateTime::from_timestamp(1_000_000_000, 0).unwrap();
+        assert!(!needs_copy(&path, 3, long_ago));
+        assert!(needs_copy(&path, 4, long_ago));
+        assert!(needs_copy(&path, 3, Utc::now() + chrono::Duration::hours(1)));
+
+        remove_path(&path).unwrap();
+        remove_path(&path).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,387 @@
+//! Read-ahead for files read sequentially through a handle
+//!
+//! A client streaming a file asks for it a block at a time, and on slow or
+//! remote storage every block pays the disk's latency before its response
+//! can leave. Once a handle's reads follow on from each other, the agent
+//! reads the next `prefetch_window` blocks of the same size in the
+//! background, so later reads are answered from memory.
+//!
+//! Blocks read ahead are only served while the file's size and modification
+//! time are those it had when they were read; a write through the handle,
+//! or a read elsewhere in the file, drops them. All handles share a budget
+//! of `fs_cache_size` MiB, and blocks that do not fit are not kept. Closing
+//! a handle frees its blocks at once, and reads ahead still running for it
+//! keep nothing.
+
+use remotefs_common::config::PerformanceConfig;
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError,
+};
+use std::time::SystemTime;
+
+/// Memory budget and counters shared by the read-ahead of every handle
+#[derive(Debug)]
+pub struct Prefetcher {
+    /// Blocks kept ahead of a sequential reader
+    window: u64,
+    budget: u64,
+    cached: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Prefetcher {
+    /// Prefetcher for `config`, or `None` if prefetching is disabled
+    pub fn new(config: &PerformanceConfig) -> Option<Arc<Self>> {
+        (config.enable_prefetch && config.prefetch_window > 0).then(|| Arc::new(Self {
+            window: config.prefetch_window as u64,
+            budget: (config.fs_cache_size as u64).saturating_mul(1024 * 1024),
+            cached: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        }))
+    }
+
+    /// Bytes read ahead and not yet served
+    pub fn cached_bytes(&self) -> u64 {
+        self.cached.load(Ordering::Relaxed)
+    }
+
+    /// Reads answered from blocks read ahead
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self, bytes: u64) -> bool {
+        self.cached
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |cached| {
+                (cached.saturating_add(bytes) <= self.budget).then_some(cached + bytes)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: u64) {
+        self.cached.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+/// Size and modification time of a file, which a write changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Version {
+    pub fn of(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// Blocks to read ahead of a reader
+#[derive(Debug, Clone, Copy)]
+pub struct Prefetch {
+    /// Offset of the first block
+    pub offset: u64,
+    /// Size of each block
+    pub block: u64,
+    pub count: u64,
+    /// Reads since which the blocks are still wanted
+    generation: u64,
+}
+
+impl Prefetch {
+    /// Offsets of the blocks, in order
+    pub fn offsets(&self) -> impl Iterator<Item = u64> {
+        let (offset, block) = (self.offset, self.block);
+        (0..self.count).map(move |index| offset + index * block)
+    }
+}
+
+/// Read-ahead state of one open file
+#[derive(Debug, Default)]
+pub struct ReadAhead {
+    prefetcher: Option<Arc<Prefetcher>>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Where the last read ended
+    last_end: Option<u64>,
+    /// End of the blocks read or being read ahead
+    ahead: u64,
+    /// Blocks read ahead, by offset
+    blocks: BTreeMap<u64, Vec<u8>>,
+    /// Bytes of `blocks`, reserved from the prefetcher
+    held: u64,
+    /// Version of the file `blocks` were read from
+    version: Option<Version>,
+    /// Bumped whenever the blocks are dropped, so reads ahead started
+    /// before are not kept
+    generation: u64,
+    /// Set once the handle is closed; nothing is read ahead or kept after
+    closed: bool,
+}
+
+impl ReadAhead {
+    /// Read-ahead drawing on `prefetcher`
+    pub fn new(prefetcher: Arc<Prefetcher>) -> Self {
+        Self {
+            prefetcher: Some(prefetcher),
+            state: Mutex::default(),
+        }
+    }
+
+    /// No read-ahead, for files not opened for reading or agents that do
+    /// not prefetch
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// `length` bytes at `offset` if they were read ahead from the file as
+    /// it is at `version`
+    pub fn take(&self, offset: u64, length: u64, version: Version) -> Option<Vec<u8>> {
+        let prefetcher = self.prefetcher.as_ref()?;
+        let mut state = self.state();
+        if state.blocks.is_empty() || length == 0 {
+            return None;
+        }
+        if state.version != Some(version) {
+            state.reset(prefetcher);
+            return None;
+        }
+
+        // Blocks behind the reader are not asked for again
+        let ahead = state.blocks.split_off(&offset);
+        let behind: u64 = std::mem::replace(&mut state.blocks, ahead).values().map(|block| block.len() as u64).sum();
+        state.release(prefetcher, behind);
+
+        let mut data = state.blocks.remove(&offset)?;
+        let block = data.len() as u64;
+        if block < length {
+            state.release(prefetcher, block);
+            return None;
+        }
+        if block > length {
+            let rest = data.split_off(length as usize);
+            state.blocks.insert(offset + length, rest);
+        }
+        state.release(prefetcher, length);
+        prefetcher.hits.fetch_add(1, Ordering::Relaxed);
+        Some(data)
+    }
+
+    /// Note a read of `length` bytes at `offset` that returned `read`
+    /// bytes, returning the blocks to read ahead if the reader is reading
+    /// sequentially and has used up half of the window
+    pub fn record(&self, offset: u64, length: u64, read: u64) -> Option<Prefetch> {
+        let prefetcher = self.prefetcher.as_ref()?;
+        let mut state = self.state();
+        if state.closed {
+            return None;
+        }
+        let sequential = state.last_end == Some(offset);
+        let next = offset + read;
+        state.last_end = Some(next);
+
+        if !sequential {
+            state.reset(prefetcher);
+            return None;
+        }
+        // Nothing lies beyond the end of the file
+        if length == 0 || read < length {
+            return None;
+        }
+
+        let target = next + prefetcher.window * length;
+        let start = state.ahead.max(next);
+        if target.saturating_sub(start) < (prefetcher.window / 2).max(1) * length {
+            return None;
+        }
+        state.ahead = target;
+        Some(Prefetch {
+            offset: start,
+            block: length,
+            count: (target - start) / length,
+            generation: state.generation,
+        })
+    }
+
+    /// Whether the blocks of `prefetch` are still wanted, so a read ahead
+    /// can stop early once the reader moved elsewhere or the handle closed
+    pub fn wants(&self, prefetch: &Prefetch) -> bool {
+        let state = self.state();
+        !state.closed && state.generation == prefetch.generation
+    }
+
+    /// Keep the blocks read for `prefetch` from the file at `version`,
+    /// unless the reader has moved elsewhere or closed the handle since
+    pub fn fill(&self, prefetch: &Prefetch, version: Version, blocks: Vec<(u64, Vec<u8>)>) {
+        let Some(prefetcher) = &self.prefetcher else { return };
+        let mut state = self.state();
+        if state.closed || state.generation != prefetch.generation {
+            return;
+        }
+        if state.version != Some(version) {
+            let held = state.held;
+            state.release(prefetcher, held);
+            state.blocks.clear();
+            state.version = Some(version);
+        }
+
+        for (offset, data) in blocks {
+            let bytes = data.len() as u64;
+            if !prefetcher.reserve(bytes) {
+                break;
+            }
+            state.held += bytes;
+            if let Some(replaced) = state.blocks.insert(offset, data) {
+                state.release(prefetcher, replaced.len() as u64);
+            }
+        }
+    }
+
+    /// Drop the blocks read ahead, after a write through the handle
+    pub fn invalidate(&self) {
+        if let Some(prefetcher) = &self.prefetcher {
+            self.state().reset(prefetcher);
+        }
+    }
+
+    /// Drop the blocks read ahead and keep none read from now on, as the
+    /// handle is closed while reads ahead may still be running
+    pub fn close(&self) {
+        let mut state = self.state();
+        state.closed = true;
+        if let Some(prefetcher) = &self.prefetcher {
+            state.reset(prefetcher);
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl State {
+    fn reset(&mut self, prefetcher: &Prefetcher) {
+        prefetcher.release(self.held);
+        self.held = 0;
+        self.blocks.clear();
+        self.ahead = 0;
+        self.version = None;
+        self.generation += 1;
+    }
+
+    fn release(&mut self, prefetcher: &Prefetcher, bytes: u64) {
+        self.held -= bytes;
+        prefetcher.release(bytes);
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        if let Some(prefetcher) = &self.prefetcher {
+            prefetcher.release(self.state().held);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefetcher(window: usize, fs_cache_size: usize) -> Arc<Prefetcher> {
+        Prefetcher::new(&PerformanceConfig {
+            prefetch_window: window,
+            fs_cache_size,
+            ..PerformanceConfig::default()
+        })
+        .unwrap()
+    }
+
+    fn version(len: u64) -> Version {
+        Version { len, modified: None }
+    }
+
+    fn blocks(prefetch: &Prefetch) -> Vec<(u64, Vec<u8>)> {
+        prefetch.offsets().map(|offset| (offset, vec![offset as u8; prefetch.block as usize])).collect()
+    }
+
+    #[test]
+    fn test_sequential_reads() {
+        let prefetcher = prefetcher(4, 1);
+        let read_ahead = ReadAhead::new(Arc::clone(&prefetcher));
+
+        // One read is not yet a pattern
+        assert!(read_ahead.record(0, 10, 10).is_none());
+        let prefetch = read_ahead.record(10, 10, 10).unwrap();
+        assert_eq!(prefetch.offsets().collect::<Vec<_>>(), [20, 30, 40, 50]);
+        read_ahead.fill(&prefetch, version(100), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), 40);
+
+        // Served while the file is unchanged, topped up at half the window
+        assert_eq!(read_ahead.take(20, 10, version(100)).unwrap(), vec![20; 10]);
+        assert!(read_ahead.record(20, 10, 10).is_none());
+        assert_eq!(read_ahead.take(30, 10, version(100)).unwrap(), vec![30; 10]);
+        let prefetch = read_ahead.record(30, 10, 10).unwrap();
+        assert_eq!(prefetch.offsets().collect::<Vec<_>>(), [60, 70]);
+        assert_eq!(prefetcher.hits(), 2);
+        assert_eq!(prefetcher.cached_bytes(), 20);
+
+        // A changed file is read again
+        assert!(read_ahead.take(40, 10, version(101)).is_none());
+        assert_eq!(prefetcher.cached_bytes(), 0);
+
+        // Blocks read for a reader that moved away are not kept
+        read_ahead.record(0, 10, 10);
+        read_ahead.fill(&prefetch, version(100), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), 0);
+    }
+
+    #[test]
+    fn test_budget() {
+        let prefetcher = prefetcher(4, 1);
+        let mb = 1024 * 1024;
+        let first = ReadAhead::new(Arc::clone(&prefetcher));
+        first.record(0, mb / 2, mb / 2);
+        let prefetch = first.record(mb / 2, mb / 2, mb / 2).unwrap();
+        first.fill(&prefetch, version(8 * mb), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), mb);
+
+        // The budget is shared, and freed when a handle closes
+        let second = ReadAhead::new(Arc::clone(&prefetcher));
+        second.record(0, 1, 1);
+        let prefetch = second.record(1, 1, 1).unwrap();
+        second.fill(&prefetch, version(100), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), mb);
+        drop(first);
+        assert_eq!(prefetcher.cached_bytes(), 0);
+
+        second.invalidate();
+        assert!(Prefetcher::new(&PerformanceConfig { enable_prefetch: false, ..PerformanceConfig::default() }).is_none());
+    }
+
+    #[test]
+    fn test_close() {
+        let prefetcher = prefetcher(4, 1);
+        let read_ahead = ReadAhead::new(Arc::clone(&prefetcher));
+        read_ahead.record(0, 10, 10);
+        let prefetch = read_ahead.record(10, 10, 10).unwrap();
+        read_ahead.fill(&prefetch, version(100), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), 40);
+
+        // Blocks held are freed, and reads ahead still running are not kept
+        read_ahead.close();
+        assert_eq!(prefetcher.cached_bytes(), 0);
+        assert!(!read_ahead.wants(&prefetch));
+        read_ahead.fill(&prefetch, version(100), blocks(&prefetch));
+        assert_eq!(prefetcher.cached_bytes(), 0);
+        assert!(read_ahead.record(20, 10, 10).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+//! Space quotas on directories
+//!
+//! `access.quotas` caps the file data under a directory. The agent measures
+//! each directory when it starts, or when a reload adds its quota, and from
+//! then on counts every change it makes below it: writes and copies by how
+//! much they grow a file, deletes and truncations by what they free, and
+//! moves by the size of what moved in or out. A change that would take a
+//! directory over its quota is refused with `QuotaExceeded` before anything
+//! is written; changes that free space are never refused. Files changed on
+//! the host without going through the agent are only counted again at the
+//! next measurement.
+//!
+//! A change is counted before it is made, so concurrent writes cannot
+//! overshoot together, and taken back if it fails.
+//!
+//! Measuring directories, whether for a new table, a reload or a
+//! transaction, walks the disk and is done on the handler's I/O pool.
+
+use remotefs_common::{
+    config::PathQuota,
+    error::RemoteFsError,
+    protocol::{ErrorCode, TransactionOp},
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, PoisonError, RwLock,
+};
+
+/// The quotas in force and what each directory uses
+#[derive(Debug, Default)]
+pub struct QuotaTable {
+    /// Replaced when the configuration is reloaded
+    quotas: RwLock<Vec<Arc<Quota>>>,
+}
+
+#[derive(Debug)]
+struct Quota {
+    path: PathBuf,
+    max: AtomicU64,
+    used: AtomicU64,
+}
+
+impl QuotaTable {
+    /// Table for `quotas`, measuring their directories
+    pub fn new(quotas: &[PathQuota]) -> Self {
+        let table = Self::default();
+        table.reconfigure(quotas);
+        table
+    }
+
+    /// Apply reloaded quotas; directories that already had one keep their
+    /// usage, and those new to the table are measured
+    pub fn reconfigure(&self, quotas: &[PathQuota]) {
+        let current = self.quotas();
+        let quotas = quotas.iter()
+            .map(|config| {
+                let path = PathBuf::from(&config.path);
+                match current.iter().find(|quota| quota.path == path) {
+                    Some(quota) => {
+                        quota.max.store(config.max_bytes, Ordering::Release);
+                        Arc::clone(quota)
+                    }
+                    None => Arc::new(Quota {
+                        used: AtomicU64::new(tree_size(&path)),
+                        max: AtomicU64::new(config.max_bytes),
+                        path,
+                    }),
+                }
+            })
+            .collect();
+        *self.quotas.write().unwrap_or_else(PoisonError::into_inner) = quotas;
+    }
+
+    fn quotas(&self) -> Vec<Arc<Quota>> {
+        self.quotas.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Whether any quota holds `path`
+    pub fn covers(&self, path: &Path) -> bool {
+        self.quotas().iter().any(|quota| path.starts_with(&quota.path))
+    }
+
+    /// Bytes used and allowed under the quota holding `path` with the least
+    /// space left, if any holds it
+    pub fn space(&self, path: &Path) -> Option<(u64, u64)> {
+        self.quotas().iter()
+            .filter(|quota| path.starts_with(&quota.path))
+            .map(|quota| (quota.used.load(Ordering::Acquire), quota.max.load(Ordering::Acquire)))
+            .min_by_key(|&(used, max)| max.saturating_sub(used))
+    }
+
+    /// A change to count against the quotas in force
+    pub fn change(&self) -> QuotaChange {
+        let quotas = self.quotas();
+        QuotaChange {
+            deltas: vec![0; quotas.len()],
+            quotas,
+        }
+    }
+
+    /// Count `change`, or refuse it if it would take a directory over its
+    /// quota; dropping the returned charge without committing it takes the
+    /// change back
+    pub fn charge(&self, change: QuotaChange) -> Result<QuotaCharge, RemoteFsError> {
+        let mut charge = QuotaCharge::default();
+        for (quota, delta) in change.quotas.into_iter().zip(change.deltas) {
+            if delta > 0 {
+                let max = quota.max.load(Ordering::Acquire);
+                let grown = quota.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                    used.checked_add(delta as u64).filter(|&used| used <= max)
+                });
+                if grown.is_err() {
+                    // The charge made so far is taken back as it drops
+                    return Err(quota_exceeded(&quota.path, max));
+                }
+            } else if delta < 0 {
+                quota.release(delta.unsigned_abs());
+            } else {
+                continue;
+            }
+            charge.applied.push((quota, delta));
+        }
+        Ok(charge)
+    }
+
+    /// The change applying `operations` in order would make, sizing entries
+    /// as they are on disk and as earlier operations leave them
+    pub fn transaction_change(&self, operations: &[TransactionOp]) -> QuotaChange {
+        let mut change = self.change();
+        let mut sizes: HashMap<&Path, u64> = HashMap::new();
+        let size = |sizes: &HashMap<&Path, u64>, path: &Path| {
+            sizes.get(path).copied().unwrap_or_else(|| if self.covers(path) { tree_size(path) } else { 0 })
+        };
+        for operation in operations {
+            match operation {
+                TransactionOp::WriteFile { path, data } => {
+                    let path = Path::new(path);
+                    change.resize(path, size(&sizes, path), data.len() as u64);
+                    sizes.insert(path, data.len() as u64);
+                }
+                TransactionOp::Rename { from_path, to_path } => {
+                    let (from, to) = (Path::new(from_path), Path::new(to_path));
+                    let moved = size(&sizes, from);
+                    change.resize(to, size(&sizes, to), 0);
+                    change.moved(from, to, moved);
+                    sizes.insert(from, 0);
+                    sizes.insert(to, moved);
+                }
+                TransactionOp::DeleteFile { path } => {
+                    let path = Path::new(path);
+                    change.resize(path, size(&sizes, path), 0);
+                    sizes.insert(path, 0);
+                }
+                TransactionOp::CreateDirectory { .. } | TransactionOp::RemoveDirectory { .. } => {}
+            }
+        }
+        change
+    }
+}
+
+impl Quota {
+    fn release(&self, bytes: u64) {
+        // Files changed behind the agent's back can make the count too low
+        let _ = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| Some(used.saturating_sub(bytes)));
+    }
+}
+
+/// How a change moves the usage of each quota
+#[derive(Debug)]
+pub struct QuotaChange {
+    quotas: Vec<Arc<Quota>>,
+    deltas: Vec<i64>,
+}
+
+impl QuotaChange {
+    /// The entry at `path` goes from `before` to `after` bytes
+    pub fn resize(&mut self, path: &Path, before: u64, after: u64) {
+        let delta = after as i64 - before as i64;
+        for (quota, total) in self.quotas.iter().zip(&mut self.deltas) {
+            if path.starts_with(&quota.path) {
+                *total += delta;
+            }
+        }
+    }
+
+    /// `bytes` move from `from` to `to`, which only counts for quotas
+    /// holding one but not the other
+    pub fn moved(&mut self, from: &Path, to: &Path, bytes: u64) {
+        self.resize(from, bytes, 0);
+        self.resize(to, 0, bytes);
+    }
+}
+
+/// A counted change, taken back on drop unless committed
+#[derive(Debug, Default)]
+pub struct QuotaCharge {
+    applied: Vec<(Arc<Quota>, i64)>,
+}
+
+impl QuotaCharge {
+    /// Keep the change, once it has been made
+    pub fn commit(mut self) {
+        self.applied.clear();
+    }
+}
+
+impl Drop for QuotaCharge {
+    fn drop(&mut self) {
+        for (quota, delta) in self.applied.drain(..) {
+            if delta > 0 {
+                quota.release(delta as u64);
+            } else {
+                quota.used.fetch_add(delta.unsigned_abs(), Ordering::AcqRel);
+            }
+        }
+    }
+}
+
+/// `QuotaExceeded` error for a change refused under the quota on `path`
+fn quota_exceeded(path: &Path, max: u64) -> RemoteFsError {
+    RemoteFsError::Os {
+        code: ErrorCode::QuotaExceeded,
+        errno: Some(libc::EDQUOT),
+        message: format!("Quota of {} bytes on {} exceeded", max, path.display()),
+    }
+}
+
+/// Bytes of file data at `path`: a file's size, or the sizes of the files
+/// under a directory; symlinks are not followed
+pub fn tree_size(path: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let Ok(metadata) = fs::symlink_metadata(&path) else { continue };
+        if metadata.is_file() {
+            total += metadata.len();
+        } else if metadata.is_dir() {
+            if let Ok(entries) = fs::read_dir(&path) {
+                pending.extend(entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()));
+            }
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(path: &Path, max_bytes: u64) -> PathQuota {
+        PathQuota { path: path.to_string_lossy().to_string(), max_bytes }
+    }
+
+    #[test]
+    fn test_charges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (outer, inner) = (dir.path().join("outer"), dir.path().join("outer/inner"));
+        fs::create_dir_all(&inner).unwrap();
+        fs::write(inner.join("existing"), [0; 40]).unwrap();
+        let table = QuotaTable::new(&[quota(&outer, 100), quota(&inner, 50)]);
+        assert_eq!(table.space(&inner.join("file")), Some((40, 50)));
+
+        // Nested quotas both apply, and the tighter one refuses
+        let mut change = table.change();
+        change.resize(&inner.join("file"), 0, 20);
+        let e = table.charge(change).unwrap_err();
+        assert!(matches!(e.to_error_code(), ErrorCode::QuotaExceeded));
+        assert_eq!(e.errno(), Some(libc::EDQUOT));
+        assert_eq!(table.space(&outer), Some((40, 100)));
+
+        // Uncommitted charges are taken back
+        let mut change = table.change();
+        change.resize(&outer.join("file"), 0, 30);
+        drop(table.charge(change).unwrap());
+        assert_eq!(table.space(&outer), Some((40, 100)));
+
+        // Moving out of the inner directory frees its quota only
+        let mut change = table.change();
+        change.moved(&inner.join("existing"), &outer.join("existing"), 40);
+        table.charge(change).unwrap().commit();
+        assert_eq!(table.space(&inner), Some((0, 50)));
+        assert_eq!(table.space(&outer), Some((40, 100)));
+        assert_eq!(table.space(dir.path()), None);
+    }
+
+    #[test]
+    fn test_transaction_change() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("target"), [0; 60]).unwrap();
+        let table = QuotaTable::new(&[quota(dir.path(), 100)]);
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+
+        // Writing a temporary file and renaming it over the target only
+        // counts the difference
+        let change = table.transaction_change(&[
+            TransactionOp::WriteFile { path: path("target.tmp"), data: vec![0; 80] },
+            TransactionOp::Rename { from_path: path("target.tmp"), to_path: path("target") },
+        ]);
+        table.charge(change).unwrap().commit();
+        assert_eq!(table.space(dir.path()), Some((80, 100)));
+
+        // Reloading keeps the count of directories that had a quota
+        table.reconfigure(&[quota(dir.path(), 200)]);
+        assert_eq!(table.space(dir.path()), Some((80, 200)));
+    }
+}
@@ -0,0 +1,335 @@
+//! Request and bandwidth rates, per client and for the agent as a whole
+//!
+//! Every request takes a token from its client's request bucket and from
+//! the agent's, and file data takes one per byte from the byte buckets:
+//! written data when the request arrives, read data once it has been read.
+//! Buckets refill continuously at their rate and hold one second's worth.
+//!
+//! A request is admitted while its buckets each hold a token, and may then
+//! take more than is left, so a large read or write is never refused
+//! outright; the requests after it wait until the debt is paid off. Requests
+//! are refused rather than delayed because a connection handles its
+//! requests one at a time, and one client's backlog would hold up the
+//! others. The refusal says in `retry_after_ms` how long to back off.
+//!
+//! Requests the relay does not name a client for only count against the
+//! global rates.
+
+use remotefs_common::{
+    config::{RateLimit, RateLimitConfig},
+    delta,
+    protocol::{ErrorCode, Message, RequestId, TransactionOp},
+};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, MutexGuard, PoisonError, RwLock,
+};
+use std::time::{Duration, Instant};
+
+/// Clients tracked before those with full buckets are forgotten
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// The rate a refused request would have exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    Ops,
+    Bytes,
+}
+
+impl Rate {
+    /// Name of the setting, used in error details
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rate::Ops => "ops_per_sec",
+            Rate::Bytes => "bytes_per_sec",
+        }
+    }
+}
+
+/// Why a request was not admitted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttled {
+    pub rate: Rate,
+    /// The configured rate
+    pub max: u64,
+    /// Client whose rate it is; `None` for the global rate
+    pub client: Option<String>,
+    /// Time until the request would be admitted
+    pub retry_after: Duration,
+}
+
+impl Throttled {
+    /// Retriable `ServiceUnavailable` error naming the rate, its value and
+    /// when to retry in the details
+    pub fn response(&self, request_id: Option<RequestId>) -> Message {
+        let unit = match self.rate {
+            Rate::Ops => "requests",
+            Rate::Bytes => "bytes",
+        };
+        let whose = match &self.client {
+            Some(client) => format!("Client {} is over its", client),
+            None => "Agent is over its total".to_string(),
+        };
+        let retry_after_ms = self.retry_after.as_millis().max(1);
+        Message::Error {
+            request_id,
+            code: ErrorCode::ServiceUnavailable,
+            message: format!("{} rate of {} {} per second; retry in {} ms", whose, self.max, unit, retry_after_ms),
+            details: Some(HashMap::from([
+                ("limit".to_string(), self.rate.as_str().to_string()),
+                ("max".to_string(), self.max.to_string()),
+                ("retry_after_ms".to_string(), retry_after_ms.to_string()),
+            ])),
+            errno: None,
+        }
+    }
+}
+
+/// Token buckets of the agent and of each client
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Replaced when the configuration is reloaded
+    config: RwLock<RateLimitConfig>,
+    global: Mutex<Buckets>,
+    clients: Mutex<HashMap<String, Buckets>>,
+    throttled: AtomicU64,
+}
+
+/// A request bucket and a byte bucket
+#[derive(Debug)]
+struct Buckets {
+    limit: RateLimit,
+    ops: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            ops: limit.ops_per_sec as f64,
+            bytes: limit.bytes_per_sec as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        let (ops, bytes) = (self.limit.ops_per_sec as f64, self.limit.bytes_per_sec as f64);
+        self.ops = (self.ops + elapsed * ops).min(ops);
+        self.bytes = (self.bytes + elapsed * bytes).min(bytes);
+    }
+
+    /// The rate whose bucket is empty or in debt, and how long until it has
+    /// a token again
+    fn over(&self) -> Option<(Rate, u64, Duration)> {
+        [(Rate::Ops, self.limit.ops_per_sec, self.ops), (Rate::Bytes, self.limit.bytes_per_sec, self.bytes)]
+            .into_iter()
+            .filter(|&(_, max, tokens)| max > 0 && tokens < 1.0)
+            .map(|(rate, max, tokens)| (rate, max, Duration::from_secs_f64((1.0 - tokens) / max as f64)))
+            .max_by_key(|&(_, _, wait)| wait)
+    }
+
+    fn take(&mut self, ops: u64, bytes: u64) {
+        if self.limit.ops_per_sec > 0 {
+            self.ops -= ops as f64;
+        }
+        if self.limit.bytes_per_sec > 0 {
+            self.bytes -= bytes as f64;
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.ops >= self.limit.ops_per_sec as f64 && self.bytes >= self.limit.bytes_per_sec as f64
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config.clone()),
+            global: Mutex::new(Buckets::new(config.global, Instant::now())),
+            clients: Mutex::new(HashMap::new()),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Apply reloaded rates; every bucket starts again full
+    pub fn reconfigure(&self, config: &RateLimitConfig) {
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = config.clone();
+        *self.global() = Buckets::new(config.global, Instant::now());
+        self.clients().clear();
+    }
+
+    /// Rates applying to `client` on its own
+    fn client_limit(&self, client: &str) -> RateLimit {
+        let config = self.config.read().unwrap_or_else(PoisonError::into_inner);
+        config.clients.get(client).copied().unwrap_or(config.per_client)
+    }
+
+    /// Admit a request of `client` for `ops` requests and `bytes` of data,
+    /// or say which rate it is over
+    pub fn admit(&self, client: Option<&str>, ops: u64, bytes: u64) -> Result<(), Throttled> {
+        let now = Instant::now();
+        let mut global = self.global();
+        global.refill(now);
+        let mut clients = self.clients();
+        let mut buckets = client.and_then(|client| self.buckets(&mut clients, client, now));
+
+        let over = match &mut buckets {
+            Some((client, buckets)) => {
+                buckets.refill(now);
+                buckets.over().map(|over| (over, Some(client.to_string())))
+            }
+            None => None,
+        };
+        if let Some(((rate, max, retry_after), client)) = over.or_else(|| global.over().map(|over| (over, None))) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return Err(Throttled { rate, max, client, retry_after });
+        }
+
+        global.take(ops, bytes);
+        if let Some((_, buckets)) = buckets {
+            buckets.take(ops, bytes);
+        }
+        Ok(())
+    }
+
+    /// Take `bytes` read for a request of `client` that was admitted
+    pub fn charge(&self, client: Option<&str>, bytes: u64) {
+        let now = Instant::now();
+        let mut global = self.global();
+        global.refill(now);
+        global.take(0, bytes);
+
+        let mut clients = self.clients();
+        if let Some((_, buckets)) = client.and_then(|client| self.buckets(&mut clients, client, now)) {
+            buckets.refill(now);
+            buckets.take(0, bytes);
+        }
+    }
+
+    /// Requests refused for going over a rate
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Buckets of `client`, or `None` if it has no rates of its own
+    fn buckets<'a, 'c>(
+        &self,
+        clients: &'a mut HashMap<String, Buckets>,
+        client: &'c str,
+        now: Instant,
+    ) -> Option<(&'c str, &'a mut Buckets)> {
+        let limit = self.client_limit(client);
+        if limit == RateLimit::default() {
+            return None;
+        }
+        if !clients.contains_key(client) && clients.len() >= MAX_TRACKED_CLIENTS {
+            // A client with full buckets is in the same state as a new one
+            clients.retain(|_, buckets| {
+                buckets.refill(now);
+                !buckets.is_full()
+            });
+        }
+        let buckets = clients.entry(client.to_string()).or_insert_with(|| Buckets::new(limit, now));
+        Some((client, buckets))
+    }
+
+    fn global(&self) -> MutexGuard<'_, Buckets> {
+        self.global.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn clients(&self) -> MutexGuard<'_, HashMap<String, Buckets>> {
+        self.clients.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Requests and bytes of file data `message` is charged on arrival, or
+/// `None` if it is not charged at all
+///
+/// Messages that are not requests, such as heartbeats, are free, and a
+/// batch is charged for the requests in it, each as it is handled.
+pub fn request_cost(message: &Message) -> Option<(u64, u64)> {
+    let bytes = match message {
+        Message::AsUser { request, .. } | Message::FromClient { request, .. } => return request_cost(request),
+        Message::Batch { .. } => return None,
+        _ if message.request_id().is_none() => return None,
+        Message::WriteFile { data, .. }
+        | Message::WriteHandle { data, .. }
+        | Message::WriteFileChunk { data, .. } => data.len(),
+        Message::WriteDelta { ops, .. } => delta::literal_len(ops) as usize,
+        Message::BatchCreateFiles { files, .. } => files.iter().map(|file| file.data.len()).sum(),
+        Message::Transaction { operations, .. } => operations.iter()
+            .map(|operation| match operation {
+                TransactionOp::WriteFile { data, .. } => data.len(),
+                _ => 0,
+            })
+            .sum(),
+        _ => 0,
+    };
+    Some((1, bytes as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(ops_per_sec: u64, bytes_per_sec: u64) -> RateLimit {
+        RateLimit { ops_per_sec, bytes_per_sec }
+    }
+
+    #[test]
+    fn test_client_rates() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            per_client: limit(2, 0),
+            clients: HashMap::from([("bulk".to_string(), limit(0, 100))]),
+            ..RateLimitConfig::default()
+        });
+
+        // Each client has a bucket of its own
+        assert!(limiter.admit(Some("a"), 1, 0).is_ok());
+        assert!(limiter.admit(Some("a"), 1, 0).is_ok());
+        let throttled = limiter.admit(Some("a"), 1, 0).unwrap_err();
+        assert_eq!((throttled.rate, throttled.max, throttled.client.as_deref()), (Rate::Ops, 2, Some("a")));
+        assert!(throttled.retry_after > Duration::ZERO && throttled.retry_after <= Duration::from_millis(500));
+        assert!(limiter.admit(Some("b"), 1, 0).is_ok());
+        assert!(limiter.admit(None, 1, 0).is_ok());
+
+        // A large write is let through, then its debt is paid off
+        assert!(limiter.admit(Some("bulk"), 1, 1000).is_ok());
+        let throttled = limiter.admit(Some("bulk"), 1, 0).unwrap_err();
+        assert_eq!(throttled.rate, Rate::Bytes);
+        assert!(throttled.retry_after > Duration::from_secs(9));
+        assert_eq!(limiter.throttled(), 2);
+
+        let response = throttled.response(None);
+        let Message::Error { code: ErrorCode::ServiceUnavailable, details: Some(details), .. } = response else {
+            panic!("not a retriable error: {:?}", response)
+        };
+        assert_eq!(details["limit"], "bytes_per_sec");
+        assert_eq!(details["max"], "100");
+    }
+
+    #[test]
+    fn test_global_rate() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            global: limit(0, 1000),
+            ..RateLimitConfig::default()
+        });
+
+        // Reads are charged once they are done
+        assert!(limiter.admit(Some("a"), 1, 0).is_ok());
+        limiter.charge(Some("a"), 1000);
+        let throttled = limiter.admit(Some("b"), 1, 0).unwrap_err();
+        assert_eq!((throttled.rate, throttled.client), (Rate::Bytes, None));
+
+        limiter.reconfigure(&RateLimitConfig::default());
+        assert!(limiter.admit(Some("b"), 1, u64::MAX).is_ok());
+        assert!(limiter.admit(Some("b"), 1, 0).is_ok());
+        assert_eq!(request_cost(&Message::ConnectionClose { reason: String::new() }), None);
+    }
+}
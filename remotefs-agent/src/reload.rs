@@ -0,0 +1,137 @@
+//! Applying a changed configuration file to a running agent
+//!
+//! On SIGHUP the agent reads its configuration again. Access rules, resource
+//! limits, rate limits and the log level take effect from the next request
+//! on, without dropping the relay connection, client sessions or open
+//! handles. Settings only read at startup, such as the relay URL, are
+//! reported as needing a restart. A file that fails to load or validate
+//! changes nothing.
+
+use remotefs_common::{config::AgentConfig, error::Result};
+use serde::Serialize;
+use tracing::warn;
+
+type Loader = Box<dyn Fn() -> Result<AgentConfig> + Send + Sync>;
+type LogLevelSetter = Box<dyn Fn(&str) -> Result<()> + Send + Sync>;
+
+/// Where a running agent gets its configuration again
+pub struct ConfigReloader {
+    load: Loader,
+    set_log_level: Option<LogLevelSetter>,
+}
+
+impl ConfigReloader {
+    /// Reload with `load`, which reads and validates the configuration the
+    /// way the agent did at startup
+    pub fn new(load: impl Fn() -> Result<AgentConfig> + Send + Sync + 'static) -> Self {
+        Self {
+            load: Box::new(load),
+            set_log_level: None,
+        }
+    }
+
+    /// Apply reloaded log levels with `set_log_level`; without it, the level
+    /// needs a restart like other logging settings
+    pub fn with_log_level(mut self, set_log_level: impl Fn(&str) -> Result<()> + Send + Sync + 'static) -> Self {
+        self.set_log_level = Some(Box::new(set_log_level));
+        self
+    }
+
+    /// Read the configuration again
+    pub fn load(&self) -> Result<AgentConfig> {
+        (self.load)()
+    }
+
+    /// Apply the log level of a reloaded configuration, if it changed
+    pub fn apply_log_level(&self, running: &AgentConfig, reloaded: &AgentConfig) {
+        if running.logging.level == reloaded.logging.level {
+            return;
+        }
+        match &self.set_log_level {
+            Some(set_log_level) => {
+                if let Err(e) = set_log_level(&reloaded.logging.level) {
+                    warn!("Keeping log level {}: {}", running.logging.level, e);
+                }
+            }
+            None => warn!("Log level changes to {} after a restart", reloaded.logging.level),
+        }
+    }
+}
+
+/// Sections that differ between the running and the reloaded configuration
+/// but are only read at startup
+pub fn restart_required(running: &AgentConfig, reloaded: &AgentConfig) -> Vec<&'static str> {
+    // The log level is applied separately; other logging settings are not
+    let logging = |config: &AgentConfig| {
+        let mut logging = config.logging.clone();
+        logging.level.clear();
+        logging
+    };
+
+    let mut sections = Vec::new();
+    let mut compare = |name, differs: bool| {
+        if differs {
+            sections.push(name);
+        }
+    };
+    compare("agent_id", running.agent_id != reloaded.agent_id);
+    compare("relay_url", running.relay_url != reloaded.relay_url);
+    compare("relay_urls", running.relay_urls != reloaded.relay_urls);
+    compare("relay_mode", running.relay_mode != reloaded.relay_mode);
+    compare("security", differs(&running.security, &reloaded.security));
+    compare("network", differs(&running.network, &reloaded.network));
+    compare("logging", differs(&logging(running), &logging(reloaded)));
+    compare("journal", differs(&running.journal, &reloaded.journal));
+    compare("archive", differs(&running.archive, &reloaded.archive));
+    compare("trash", differs(&running.trash, &reloaded.trash));
+    compare("mirror", differs(&running.mirror, &reloaded.mirror));
+    compare("remote_exec", differs(&running.remote_exec, &reloaded.remote_exec));
+    compare("id_map", differs(&running.id_map, &reloaded.id_map));
+    compare("local_socket", running.local_socket != reloaded.local_socket);
+    sections
+}
+
+/// Configuration sections do not implement `PartialEq`, but serialize
+fn differs<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::config_utils::create_default_agent_config;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_restart_required() {
+        let running = create_default_agent_config();
+        let mut reloaded = running.clone();
+        reloaded.access.allowed_paths.push("/srv".to_string());
+        reloaded.limits.max_open_files += 1;
+        reloaded.logging.level = "trace".to_string();
+        assert!(restart_required(&running, &reloaded).is_empty());
+
+        reloaded.relay_url = "ws://elsewhere:8080".to_string();
+        reloaded.logging.format = "json".to_string();
+        assert_eq!(restart_required(&running, &reloaded), vec!["relay_url", "logging"]);
+    }
+
+    #[test]
+    fn test_apply_log_level() {
+        let level = Arc::new(Mutex::new(String::new()));
+        let set = Arc::clone(&level);
+        let reloader = ConfigReloader::new(|| Ok(create_default_agent_config()))
+            .with_log_level(move |new| {
+                *set.lock().unwrap() = new.to_string();
+                Ok(())
+            });
+
+        let running = reloader.load().unwrap();
+        reloader.apply_log_level(&running, &running);
+        assert!(level.lock().unwrap().is_empty());
+
+        let reloaded = AgentConfig { logging: remotefs_common::config::LoggingConfig { level: "debug".to_string(), ..running.logging.clone() }, ..running.clone() };
+        reloader.apply_log_level(&running, &reloaded);
+        assert_eq!(*level.lock().unwrap(), "debug");
+    }
+}
@@ -0,0 +1,179 @@
+//! File name and content search
+//!
+//! `SearchFiles` walks a tree on the agent the way `WalkDirectory` does,
+//! matching file names against a glob and, if the request has a regular
+//! expression, the lines of those files against it, so that finding or
+//! grepping a remote tree does not fetch every file. Up to
+//! `SEARCH_PARALLELISM` files are read at once, on the agent's filesystem
+//! workers.
+//!
+//! Only files the caller may read are reported or read, and directories it
+//! may not read are not entered. Files whose first block holds a NUL byte
+//! are taken to be binary and their content is not searched, nor is that of
+//! archived files, which would have to be recalled first.
+
+use crate::access::glob_to_regex;
+use regex::{bytes, Regex};
+use remotefs_common::error::RemoteFsError;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Most matches one search returns
+pub const MAX_SEARCH_RESULTS: usize = 10_000;
+
+/// Matches per page of a `SearchFiles` stream
+pub const SEARCH_PAGE_MATCHES: usize = 500;
+
+/// Files whose content is searched at once
+pub const SEARCH_PARALLELISM: usize = 8;
+
+/// Longest line reported in a match, in bytes
+const MAX_REPORTED_LINE: usize = 1024;
+
+/// Longest part of a line matched against the expression, in bytes; the
+/// rest of longer lines is skipped so one huge line cannot exhaust memory
+const MAX_SCANNED_LINE: usize = 64 * 1024;
+
+/// Compiled size limit of a content expression
+const MAX_REGEX_SIZE: usize = 1024 * 1024;
+
+/// What a search looks for
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    name: Option<Regex>,
+    /// Whether `name` applies to paths relative to the root rather than names
+    name_is_path: bool,
+    content: Option<bytes::Regex>,
+}
+
+impl SearchQuery {
+    /// Query for files named like `name_glob` with lines matching
+    /// `content_regex`; patterns that do not compile are refused
+    pub fn new(name_glob: Option<&str>, content_regex: Option<&str>) -> Result<Self, RemoteFsError> {
+        let name = name_glob
+            .map(|glob| Regex::new(&glob_to_regex(glob)).map_err(|e| {
+                RemoteFsError::Protocol(format!("Invalid name pattern '{}': {}", glob, e))
+            }))
+            .transpose()?;
+        let content = content_regex
+            .map(|regex| bytes::RegexBuilder::new(regex).size_limit(MAX_REGEX_SIZE).build().map_err(|e| {
+                RemoteFsError::Protocol(format!("Invalid content pattern '{}': {}", regex, e))
+            }))
+            .transpose()?;
+        Ok(Self {
+            name,
+            name_is_path: name_glob.is_some_and(|glob| glob.contains('/')),
+            content,
+        })
+    }
+
+    /// Whether the file `relative` to the search root is named as asked
+    pub fn matches_name(&self, relative: &Path) -> bool {
+        let Some(name) = &self.name else {
+            return true;
+        };
+        let subject = if self.name_is_path { Some(relative.as_os_str()) } else { relative.file_name() };
+        subject.and_then(|subject| subject.to_str()).is_some_and(|subject| name.is_match(subject))
+    }
+
+    /// Whether files are searched by content, rather than found by name
+    pub fn searches_content(&self) -> bool {
+        self.content.is_some()
+    }
+
+    /// Numbers and text of the first `max` lines of the file at `path`
+    /// matching the content expression; none for binary files
+    pub fn scan(&self, path: &Path, max: usize) -> io::Result<Vec<(u64, String)>> {
+        let Some(content) = &self.content else {
+            return Ok(Vec::new());
+        };
+        let mut reader = BufReader::new(File::open(path)?);
+        if reader.fill_buf()?.contains(&0) {
+            return Ok(Vec::new());
+        }
+
+        let mut matches = Vec::new();
+        let mut line = Vec::new();
+        let mut number = 0;
+        while matches.len() < max && read_line(&mut reader, &mut line)? {
+            number += 1;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if content.is_match(&line) {
+                let shown = &line[..line.len().min(MAX_REPORTED_LINE)];
+                matches.push((number, String::from_utf8_lossy(shown).into_owned()));
+            }
+        }
+        Ok(matches)
+    }
+}
+
+/// Read the next line into `line` without its newline, keeping at most
+/// `MAX_SCANNED_LINE` bytes of it; `false` at the end of the file
+fn read_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> io::Result<bool> {
+    line.clear();
+    let mut read_any = false;
+    loop {
+        let buffer = reader.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(read_any);
+        }
+        read_any = true;
+        let newline = buffer.iter().position(|&byte| byte == b'\n');
+        let end = newline.unwrap_or(buffer.len());
+        let room = MAX_SCANNED_LINE.saturating_sub(line.len());
+        line.extend_from_slice(&buffer[..end.min(room)]);
+        match newline {
+            Some(newline) => {
+                reader.consume(newline + 1);
+                return Ok(true);
+            }
+            None => {
+                let consumed = buffer.len();
+                reader.consume(consumed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_names() {
+        let query = SearchQuery::new(Some("*.rs"), None).unwrap();
+        assert!(query.matches_name(Path::new("src/main.rs")));
+        assert!(!query.matches_name(Path::new("src/main.rs.bak")));
+        assert!(!query.searches_content());
+
+        // A glob with a slash is matched against the whole relative path
+        let query = SearchQuery::new(Some("src/**/*.rs"), None).unwrap();
+        assert!(query.matches_name(Path::new("src/a/b.rs")));
+        assert!(!query.matches_name(Path::new("tests/b.rs")));
+
+        let e = SearchQuery::new(None, Some("(unclosed")).unwrap_err();
+        assert!(matches!(e, RemoteFsError::Protocol(_)));
+    }
+
+    #[test]
+    fn test_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("notes.txt");
+        fs::write(&text, format!("alpha\r\nbeta\n{}\nalphabet", "x".repeat(2 * MAX_SCANNED_LINE))).unwrap();
+        let query = SearchQuery::new(None, Some("^alpha")).unwrap();
+        assert_eq!(query.scan(&text, 10).unwrap(), vec![(1, "alpha".to_string()), (4, "alphabet".to_string())]);
+        assert_eq!(query.scan(&text, 1).unwrap().len(), 1);
+
+        // Long lines are reported cut off
+        let query = SearchQuery::new(None, Some("x+")).unwrap();
+        assert_eq!(query.scan(&text, 10).unwrap()[0].1.len(), MAX_REPORTED_LINE);
+
+        let binary = dir.path().join("image.bin");
+        fs::write(&binary, b"alpha\0beta").unwrap();
+        assert!(SearchQuery::new(None, Some("alpha")).unwrap().scan(&binary, 10).unwrap().is_empty());
+    }
+}
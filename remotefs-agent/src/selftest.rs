@@ -0,0 +1,219 @@
+//! Startup self-test of the configured paths
+//!
+//! Each allowed and read-only path is probed the way clients will use it:
+//! listed, and unless it is read-only, a file is created, written, read back
+//! and deleted under a temporary name. A path that fails shows up in the
+//! agent's log and in the health report sent to the relay, instead of as an
+//! EIO on the first client request.
+
+use crate::exports;
+use remotefs_common::{config::AccessConfig, protocol::PathReadiness};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
+use tracing::{info, warn};
+
+/// Written to and read back from each writable path
+const PROBE_DATA: &[u8] = b"remotefs self-test";
+
+/// Probe every allowed and read-only path in `config`, writable paths
+/// first, returning the readiness of each in that order
+pub fn probe_paths(config: &AccessConfig) -> Vec<PathReadiness> {
+    let writable = config.allowed_paths.iter()
+        .filter(|path| !config.read_only_paths.contains(path))
+        .map(|path| (path, false));
+    let read_only = config.read_only_paths.iter().map(|path| (path, true));
+
+    writable.chain(read_only)
+        .map(|(path, read_only)| probe_path(path, read_only, config))
+        .collect()
+}
+
+/// Probe a single configured path
+pub fn probe_path(path: &str, read_only: bool, config: &AccessConfig) -> PathReadiness {
+    let mut readiness = PathReadiness {
+        path: path.to_string(),
+        read_only,
+        ..Default::default()
+    };
+    let root = Path::new(path);
+
+    // Configured roots are trusted even without `follow_symlinks`, but one
+    // that dangles serves nothing
+    if let Ok(target) = fs::read_link(root) {
+        readiness.symlink_target = Some(target.to_string_lossy().to_string());
+    }
+
+    match fs::metadata(root) {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            readiness.problems.push("not a directory".to_string());
+            return readiness;
+        }
+        Err(e) if readiness.symlink_target.is_some() => {
+            readiness.problems.push(format!("symlink target is unreachable: {}", e));
+            return readiness;
+        }
+        Err(e) => {
+            readiness.problems.push(format!("cannot access: {}", e));
+            return readiness;
+        }
+    }
+
+    match fs::read_dir(root) {
+        Ok(_) => readiness.readable = true,
+        Err(e) => readiness.problems.push(format!("cannot list: {}", e)),
+    }
+
+    if !read_only {
+        match probe_write(root) {
+            Ok(()) => readiness.writable = true,
+            Err(e) => readiness.problems.push(e),
+        }
+    }
+
+    readiness.available_space = exports::space(root).ok().map(|space| space.available_space);
+    if let Some(available) = readiness.available_space {
+        if !read_only && config.max_file_size > available {
+            readiness.warnings.push(format!(
+                "max_file_size ({} bytes) exceeds the {} bytes available",
+                config.max_file_size, available
+            ));
+        }
+    }
+
+    readiness
+}
+
+/// Create, write, read back and delete a file under a temporary name
+fn probe_write(root: &Path) -> std::result::Result<(), String> {
+    let probe = root.join(format!(".remotefs-probe-{}", uuid::Uuid::new_v4()));
+
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format!("cannot create files: {}", e))
+        .and_then(|mut file| file.write_all(PROBE_DATA).map_err(|e| format!("cannot write files: {}", e)));
+
+    let read_back = written.and_then(|()| {
+        let mut data = Vec::new();
+        fs::File::open(&probe)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .map_err(|e| format!("cannot read back written files: {}", e))?;
+        if data == PROBE_DATA {
+            Ok(())
+        } else {
+            Err("written data reads back differently".to_string())
+        }
+    });
+
+    // Clean up even after a failed write, if the file was created at all
+    let removed = match fs::remove_file(&probe) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("cannot delete files: {}", e)),
+    };
+
+    read_back.and(removed)
+}
+
+/// Log the probe results as one line per path
+pub fn log_readiness(paths: &[PathReadiness]) {
+    if paths.is_empty() {
+        warn!("No allowed or read-only paths are configured; clients can reach nothing");
+        return;
+    }
+
+    info!("Path readiness:");
+    for path in paths {
+        let access = match (path.readable, path.writable, path.read_only) {
+            (true, true, _) => "read-write",
+            (true, false, true) => "read-only",
+            (true, false, false) => "read",
+            (false, ..) => "none",
+        };
+        let space = path.available_space
+            .map(|bytes| format!("{} MB free", bytes / (1024 * 1024)))
+            .unwrap_or_else(|| "free space unknown".to_string());
+        let target = path.symlink_target.as_ref()
+            .map(|target| format!(" -> {}", target))
+            .unwrap_or_default();
+
+        if path.is_ready() {
+            info!("  {}{}: ready, {}, {}", path.path, target, access, space);
+        } else {
+            warn!("  {}{}: NOT READY ({}), {}", path.path, target, path.problems.join("; "), access);
+        }
+        for warning in &path.warnings {
+            warn!("  {}: {}", path.path, warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::config_utils;
+
+    fn config_for(dir: &Path) -> AccessConfig {
+        let mut config = config_utils::create_default_agent_config().access;
+        config.allowed_paths = vec![dir.to_string_lossy().to_string()];
+        config
+    }
+
+    #[test]
+    fn test_writable_path_is_ready() {
+        let dir = tempfile::tempdir().unwrap();
+        let readiness = probe_paths(&config_for(dir.path()));
+
+        assert_eq!(readiness.len(), 1);
+        assert!(readiness[0].is_ready(), "{:?}", readiness[0].problems);
+        assert!(readiness[0].readable && readiness[0].writable);
+        assert!(readiness[0].available_space.is_some());
+
+        // The probe file is gone again
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_read_only_path_is_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config_for(dir.path());
+        config.read_only_paths = config.allowed_paths.clone();
+
+        let readiness = probe_paths(&config);
+        assert_eq!(readiness.len(), 1);
+        assert!(readiness[0].read_only && readiness[0].readable && !readiness[0].writable);
+        assert!(readiness[0].is_ready());
+    }
+
+    #[test]
+    fn test_missing_and_dangling_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let dangling = dir.path().join("dangling");
+        std::os::unix::fs::symlink(&missing, &dangling).unwrap();
+
+        let config = config_for(dir.path());
+        let readiness = probe_path(&missing.to_string_lossy(), false, &config);
+        assert!(!readiness.is_ready());
+
+        let readiness = probe_path(&dangling.to_string_lossy(), false, &config);
+        assert!(!readiness.is_ready());
+        assert_eq!(readiness.symlink_target.as_deref(), Some(&*missing.to_string_lossy()));
+    }
+
+    #[test]
+    fn test_max_file_size_beyond_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config_for(dir.path());
+        config.max_file_size = u64::MAX;
+
+        let readiness = probe_paths(&config);
+        assert!(readiness[0].is_ready());
+        assert_eq!(readiness[0].warnings.len(), 1);
+    }
+}
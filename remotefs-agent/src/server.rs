@@ -1,29 +1,52 @@
 use remotefs_common::{
-    protocol::{Message, NodeType, generate_request_id},
-    config::AgentConfig,
-    error::{RemoteFsError, Result},
+    compression::CompressionStats,
+    config::{AccessConfig, AgentConfig, RelayMode},
+    crash,
+    error::Result,
     crypto::{generate_keypair},
+    protocol::AgentEvent,
 };
 use crate::{
-    connection::ConnectionManager,
+    connection::{self, ConnectionManager},
     filesystem::FilesystemHandler,
     access::AccessControl,
+    archive::ArchiveHooks,
+    audit::AuditLog,
+    ids::IdMap,
+    journal::ChangeJournal,
+    limits::ResourceLimits,
+    quota::QuotaTable,
+    rate::RateLimiter,
+    trash::Trash,
+    local::LocalSocket,
+    mirror::{MirrorState, Replicator},
+    reload::{self, ConfigReloader},
+    selftest,
 };
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
 use tracing::{info, warn, error, debug};
-use uuid::Uuid;
 
 /// Main agent server that connects to relay and handles filesystem operations
 pub struct AgentServer {
     config: AgentConfig,
-    connection_manager: Arc<ConnectionManager>,
+    /// One connection manager, or one per relay with `relay_mode = "all"`
+    connection_managers: Vec<Arc<ConnectionManager>>,
     filesystem_handler: Arc<FilesystemHandler>,
     access_control: Arc<AccessControl>,
+    limits: Arc<ResourceLimits>,
+    rate_limits: Arc<RateLimiter>,
+    quotas: Arc<QuotaTable>,
+    mirror: Option<Arc<MirrorState>>,
+    /// Reads the configuration again on SIGHUP
+    reloader: Option<Arc<ConfigReloader>>,
     shutdown_tx: broadcast::Sender<()>,
     shutdown_rx: broadcast::Receiver<()>,
     agent_id: String,
+    #[allow(dead_code)]
     public_key: Vec<u8>,
+    #[allow(dead_code)]
     private_key: Vec<u8>,
 }
 
@@ -35,28 +58,88 @@ impl AgentServer {
         // Generate agent keys for authentication
         let (private_key, public_key) = generate_keypair();
         
+        // Mirror agents refuse writes until the relay promotes them
+        let mirror = config.mirror.enabled.then(|| Arc::new(MirrorState::new()));
+        
         // Create access control with the agent configuration
-        let access_control = Arc::new(AccessControl::new(&config.access));
+        let mut access_control = AccessControl::new(&config.access);
+        if let Some(mirror) = &mirror {
+            access_control = access_control.with_mirror(Arc::clone(mirror));
+        }
+        if config.trash.enabled {
+            access_control = access_control.with_trash(&config.trash.directory);
+        }
+        let access_control = Arc::new(access_control);
         
         // Create filesystem handler with access control
-        let filesystem_handler = Arc::new(FilesystemHandler::new(
+        let mut filesystem_handler = FilesystemHandler::new(
             Arc::clone(&access_control),
             &config.performance,
-        ));
+        );
+        if let Some(journal) = ChangeJournal::from_config(&config.journal)? {
+            filesystem_handler = filesystem_handler.with_journal(Arc::new(journal));
+        }
+        if let Some(archive) = ArchiveHooks::from_config(&config.archive) {
+            filesystem_handler = filesystem_handler.with_archive(Arc::new(archive));
+        }
+        if let Some(trash) = Trash::from_config(&config.trash) {
+            filesystem_handler = filesystem_handler.with_trash(Arc::new(trash));
+        }
+        #[cfg(feature = "remote-exec")]
+        if let Some(exec) = crate::exec::CommandRunner::from_config(&config.remote_exec) {
+            filesystem_handler = filesystem_handler.with_exec(Arc::new(exec));
+        }
+        #[cfg(not(feature = "remote-exec"))]
+        if config.remote_exec.enabled {
+            warn!("remote_exec is enabled but this agent was built without the remote-exec feature");
+        }
+        if let Some(mirror) = &mirror {
+            filesystem_handler = filesystem_handler.with_mirror(Arc::clone(mirror));
+        }
+        let limits = Arc::new(ResourceLimits::new(&config.limits));
+        let rate_limits = Arc::new(RateLimiter::new(&config.rate_limits));
+        let quotas = Arc::new(QuotaTable::new(&config.access.quotas));
+        let filesystem_handler = filesystem_handler
+            .with_id_map(Arc::new(IdMap::new(&config.id_map)))
+            .with_limits(Arc::clone(&limits))
+            .with_rate_limits(Arc::clone(&rate_limits))
+            .with_quotas(Arc::clone(&quotas));
+        let filesystem_handler = Arc::new(filesystem_handler);
         
-        // Create connection manager
-        let connection_manager = Arc::new(ConnectionManager::new(
-            &config,
-            config.agent_id.clone(),
-            public_key.to_vec(),
-        )?);
+        // Create connection managers: one failing over between the relays,
+        // or one registered on each
+        let relay_configs = match config.relay_mode {
+            RelayMode::Failover => vec![config.clone()],
+            RelayMode::All => connection::relay_urls(&config)?
+                .into_iter()
+                .map(|url| AgentConfig { relay_url: url.to_string(), relay_urls: Vec::new(), ..config.clone() })
+                .collect(),
+        };
+        let audit = AuditLog::from_config(&config.logging)?.map(Arc::new);
+        let mut connection_managers = Vec::new();
+        for relay_config in &relay_configs {
+            let mut connection_manager = ConnectionManager::new(
+                relay_config,
+                config.agent_id.clone(),
+                public_key.to_vec(),
+            )?;
+            if let Some(audit) = &audit {
+                connection_manager = connection_manager.with_audit(Arc::clone(audit));
+            }
+            connection_managers.push(Arc::new(connection_manager));
+        }
         
         Ok(Self {
             agent_id: config.agent_id.clone(),
             config,
-            connection_manager,
+            connection_managers,
             filesystem_handler,
             access_control,
+            limits,
+            rate_limits,
+            quotas,
+            mirror,
+            reloader: None,
             shutdown_tx,
             shutdown_rx,
             public_key: public_key.to_vec(),
@@ -64,24 +147,68 @@ impl AgentServer {
         })
     }
     
+    /// Read the configuration again with `reloader` on SIGHUP, applying
+    /// what can change while running
+    pub fn with_reloader(mut self, reloader: ConfigReloader) -> Self {
+        self.reloader = Some(Arc::new(reloader));
+        self
+    }
+    
     /// Start the agent server
     pub async fn run(&self) -> Result<()> {
         info!("Starting RemoteFS Agent: {}", self.agent_id);
-        info!("Connecting to relay: {}", self.config.relay_url);
+        for connection_manager in &self.connection_managers {
+            info!("Connecting to relay: {}", connection_manager.relay_url());
+        }
+        
+        // Hand descriptors to clients on this host instead of copying data
+        if let Some(path) = &self.config.local_socket {
+            let socket = LocalSocket::bind(path)?;
+            tokio::spawn(socket.serve(Arc::clone(&self.filesystem_handler), self.shutdown_rx.resubscribe()));
+        }
+        
+        // Report the relay connection and operations in progress if the agent panics
+        let connection_managers = self.connection_managers.clone();
+        crash::add_snapshot("connection", move || {
+            connection_managers.iter().map(|manager| manager.snapshot()).collect::<Vec<_>>().join("\n")
+        });
+        let filesystem_handler = Arc::clone(&self.filesystem_handler);
+        crash::add_snapshot("operations", move || filesystem_handler.snapshot());
+        
+        // Catch unusable paths before a client runs into them
+        probe_and_report(self.config.access.clone(), &self.connection_managers).await;
         
-        // Start connection to relay server
-        let connection_handle = {
-            let conn_mgr = Arc::clone(&self.connection_manager);
-            let fs_handler = Arc::clone(&self.filesystem_handler);
-            let mut shutdown_rx = self.shutdown_rx.resubscribe();
+        // Start connections to the relay servers; each relay has its own
+        // watches, locks and open files, so losing one leaves the others'
+        let mut connection_handle = {
+            let mut connections = tokio::task::JoinSet::new();
+            for (index, conn_mgr) in self.connection_managers.iter().enumerate() {
+                let conn_mgr = Arc::clone(conn_mgr);
+                let fs_handler = match index {
+                    0 => Arc::clone(&self.filesystem_handler),
+                    _ => Arc::new(self.filesystem_handler.for_relay()),
+                };
+                let shutdown_rx = self.shutdown_rx.resubscribe();
+                connections.spawn(async move {
+                    if let Err(e) = conn_mgr.connect_and_serve(fs_handler, shutdown_rx).await {
+                        error!("Connection manager error on relay {}: {}", conn_mgr.relay_url(), e);
+                    }
+                });
+            }
             
+            // The agent carries on while any relay connection does
             tokio::spawn(async move {
-                if let Err(e) = conn_mgr.connect_and_serve(fs_handler, shutdown_rx).await {
-                    error!("Connection manager error: {}", e);
-                }
+                while connections.join_next().await.is_some() {}
             })
         };
         
+        // Replicate from the primary if this agent is a mirror
+        if let Some(mirror) = &self.mirror {
+            info!("Mirroring {} from primary {}", self.config.mirror.root.display(), self.config.mirror.primary_id);
+            let replicator = Replicator::new(&self.config.mirror, Arc::clone(mirror))?;
+            tokio::spawn(replicator.run(self.shutdown_rx.resubscribe()));
+        }
+        
         // Start health monitoring
         let health_handle = self.start_health_monitoring();
         
@@ -91,15 +218,30 @@ impl AgentServer {
         // Start access log cleanup if enabled
         let cleanup_handle = self.start_cleanup_tasks();
         
+        // Purge what has been in the trash for longer than its retention
+        if let Some(trash) = Trash::from_config(&self.config.trash) {
+            self.start_trash_purge(trash.purge_interval());
+        }
+        
+        // Reload the configuration, or at least re-resolve its paths, on SIGHUP
+        self.start_reload_handler();
+        
+        let mut terminate = signal(SignalKind::terminate())?;
+        
         info!("RemoteFS Agent started and ready to serve filesystem operations");
         
         // Wait for shutdown signal
+        let mut connected = true;
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 info!("Received shutdown signal");
             }
-            _ = connection_handle => {
+            _ = terminate.recv() => {
+                info!("Received SIGTERM");
+            }
+            _ = &mut connection_handle => {
                 warn!("Connection manager ended unexpectedly");
+                connected = false;
             }
             _ = health_handle => {
                 warn!("Health monitoring ended unexpectedly");
@@ -113,17 +255,101 @@ impl AgentServer {
         }
         
         info!("Shutting down RemoteFS Agent");
+        self.drain().await;
         let _ = self.shutdown_tx.send(());
         
-        // Give tasks a moment to shut down gracefully
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        // Let the connections flush open files and say goodbye to the relays
+        if connected && tokio::time::timeout(tokio::time::Duration::from_secs(5), connection_handle).await.is_err() {
+            warn!("Relay connections did not close in time");
+        }
         
         Ok(())
     }
     
+    /// Refuse new requests and wait, up to `network.shutdown_timeout`, for
+    /// those in progress to finish
+    async fn drain(&self) {
+        self.filesystem_handler.stop_accepting();
+        self.broadcast(AgentEvent::ShuttingDown).await;
+        
+        let timeout = std::time::Duration::from_secs(self.config.network.shutdown_timeout);
+        let remaining = self.filesystem_handler.drain(timeout).await;
+        if remaining > 0 {
+            warn!("Shutting down with {} operations still in progress after {}s", remaining, timeout.as_secs());
+        } else {
+            debug!("All operations finished");
+        }
+    }
+    
+    /// Reload the configuration whenever SIGHUP arrives, or without a
+    /// reloader resolve the configured access paths again, for paths created
+    /// or re-pointed after the agent started
+    fn start_reload_handler(&self) {
+        let access_control = Arc::clone(&self.access_control);
+        let limits = Arc::clone(&self.limits);
+        let rate_limits = Arc::clone(&self.rate_limits);
+        let quotas = Arc::clone(&self.quotas);
+        let connection_managers = self.connection_managers.clone();
+        let reloader = self.reloader.clone();
+        // Startup-only settings keep their values from startup until a restart
+        let started = self.config.clone();
+        let mut running = self.config.clone();
+        let mut shutdown_rx = self.shutdown_rx.resubscribe();
+        
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP, the configuration will not be reloaded: {}", e);
+                return;
+            }
+        };
+        
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => {
+                        let reloaded = match &reloader {
+                            Some(reloader) => {
+                                info!("Received SIGHUP, reloading configuration");
+                                reloader.load()
+                                    .inspect_err(|e| error!("Keeping the running configuration: {}", e))
+                                    .ok()
+                            }
+                            None => None,
+                        };
+                        match (&reloader, reloaded) {
+                            (Some(reloader), Some(reloaded)) => {
+                                access_control.reload(&reloaded.access);
+                                limits.reconfigure(&reloaded.limits);
+                                rate_limits.reconfigure(&reloaded.rate_limits);
+                                // Directories new to a quota are measured
+                                let (quotas, configured) = (Arc::clone(&quotas), reloaded.access.quotas.clone());
+                                if let Err(e) = tokio::task::spawn_blocking(move || quotas.reconfigure(&configured)).await {
+                                    error!("Failed to apply the reloaded quotas: {}", e);
+                                }
+                                reloader.apply_log_level(&running, &reloaded);
+                                for section in reload::restart_required(&started, &reloaded) {
+                                    warn!("Changes to {} take effect after a restart", section);
+                                }
+                                info!("Configuration reloaded");
+                                running = reloaded;
+                            }
+                            _ => {
+                                info!("Resolving configured paths");
+                                access_control.resolve_paths();
+                            }
+                        }
+                        probe_and_report(access_control.config(), &connection_managers).await;
+                    }
+                    _ = shutdown_rx.recv() => break,
+                }
+            }
+        });
+    }
+    
     /// Start health monitoring background task
     fn start_health_monitoring(&self) -> tokio::task::JoinHandle<()> {
-        let connection_manager = Arc::clone(&self.connection_manager);
+        let connection_managers = self.connection_managers.clone();
         let filesystem_handler = Arc::clone(&self.filesystem_handler);
         let mut shutdown_rx = self.shutdown_rx.resubscribe();
         
@@ -136,8 +362,10 @@ impl AgentServer {
                 tokio::select! {
                     _ = interval.tick() => {
                         // Check connection health
-                        if !connection_manager.is_connected().await {
-                            warn!("Connection to relay is not healthy");
+                        for connection_manager in &connection_managers {
+                            if !connection_manager.is_connected().await {
+                                warn!("Connection to relay {} is not healthy", connection_manager.relay_url());
+                            }
                         }
                         
                         // Check filesystem handler health
@@ -191,6 +419,14 @@ impl AgentServer {
                         info!("  Average response time: {:.2}ms", perf_stats.avg_response_time_ms);
                         info!("  Data transferred: {} bytes read, {} bytes written", 
                             perf_stats.bytes_read, perf_stats.bytes_written);
+                        info!("  Read-ahead: {} reads served, {} bytes cached",
+                            perf_stats.prefetch_hits, perf_stats.prefetched_bytes);
+                        
+                        let resource_stats = filesystem_handler.get_resource_statistics();
+                        info!("  Resources: {} bytes buffered, {} files open, {} requests queued, {} requests shed, {} oversized reads refused, {} requests throttled",
+                            resource_stats.buffered_bytes, resource_stats.open_files, resource_stats.queued_requests,
+                            resource_stats.shed_requests, resource_stats.oversized_responses,
+                            resource_stats.throttled_requests);
                     }
                     _ = shutdown_rx.recv() => {
                         debug!("Performance monitoring shutting down");
@@ -232,16 +468,79 @@ impl AgentServer {
         })
     }
     
+    /// Purge expired trash entries every `period`
+    fn start_trash_purge(&self, period: std::time::Duration) {
+        let filesystem_handler = Arc::clone(&self.filesystem_handler);
+        let mut shutdown_rx = self.shutdown_rx.resubscribe();
+        
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        let purged = filesystem_handler.purge_expired_trash().await;
+                        if purged > 0 {
+                            info!("Purged {} expired trash entries", purged);
+                        }
+                    }
+                    _ = shutdown_rx.recv() => {
+                        debug!("Trash purge shutting down");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+    
+    /// Announce `event` to the clients using this agent, such as an
+    /// upcoming maintenance window
+    ///
+    /// Returns false if the agent is not connected to any relay.
+    pub async fn broadcast(&self, event: AgentEvent) -> bool {
+        let mut sent = false;
+        for connection_manager in &self.connection_managers {
+            sent |= connection_manager.broadcast(event.clone()).await;
+        }
+        sent
+    }
+    
+    /// Whether the agent is connected to at least one relay
+    async fn is_connected(&self) -> bool {
+        for connection_manager in &self.connection_managers {
+            if connection_manager.is_connected().await {
+                return true;
+            }
+        }
+        false
+    }
+    
     /// Get agent status information
     pub async fn get_status(&self) -> AgentStatus {
         AgentStatus {
             agent_id: self.agent_id.clone(),
-            connected: self.connection_manager.is_connected().await,
-            uptime_seconds: self.connection_manager.get_uptime().await,
+            connected: self.is_connected().await,
+            uptime_seconds: self.connection_managers[0].get_uptime().await,
             filesystem_stats: self.filesystem_handler.get_statistics().await,
-            connection_stats: self.connection_manager.get_statistics().await,
+            connection_stats: self.connection_managers[0].get_statistics().await,
             access_control_stats: self.access_control.get_statistics().await,
+            resource_stats: self.filesystem_handler.get_resource_statistics(),
+        }
+    }
+}
+
+/// Self-test the configured paths, log the results and report them to the relays
+async fn probe_and_report(access: AccessConfig, connection_managers: &[Arc<ConnectionManager>]) {
+    let paths = match tokio::task::spawn_blocking(move || selftest::probe_paths(&access)).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!("Path self-test failed: {}", e);
+            return;
         }
+    };
+    selftest::log_readiness(&paths);
+    for connection_manager in connection_managers {
+        connection_manager.report_path_readiness(paths.clone()).await;
     }
 }
 
@@ -254,6 +553,7 @@ pub struct AgentStatus {
     pub filesystem_stats: FilesystemStatistics,
     pub connection_stats: ConnectionStatistics,
     pub access_control_stats: AccessControlStatistics,
+    pub resource_stats: ResourceStatistics,
 }
 
 /// Filesystem operation statistics
@@ -264,6 +564,8 @@ pub struct FilesystemStatistics {
     pub error_count: u64,
     pub bytes_read: u64,
     pub bytes_written: u64,
+    /// Files handed to local clients as descriptors
+    pub local_opens: u64,
 }
 
 /// Connection statistics
@@ -273,6 +575,8 @@ pub struct ConnectionStatistics {
     pub messages_received: u64,
     pub reconnection_count: u32,
     pub last_heartbeat: Option<std::time::SystemTime>,
+    /// Compression of the messages sent to the relay
+    pub compression: CompressionStats,
 }
 
 /// Access control statistics
@@ -284,6 +588,23 @@ pub struct AccessControlStatistics {
     pub size_violations: u64,
 }
 
+/// Resource usage against the configured limits
+#[derive(Debug, Clone, Default)]
+pub struct ResourceStatistics {
+    /// Bytes of file data held by in-flight requests
+    pub buffered_bytes: u64,
+    /// Files open for in-flight requests
+    pub open_files: usize,
+    /// Requests refused because a limit was reached
+    pub shed_requests: u64,
+    /// Reads refused for exceeding the response size limit
+    pub oversized_responses: u64,
+    /// Requests refused for going over a request or bandwidth rate
+    pub throttled_requests: u64,
+    /// Requests waiting for a read or write slot
+    pub queued_requests: usize,
+}
+
 /// Performance statistics
 #[derive(Debug, Clone)]
 pub struct PerformanceStatistics {
@@ -291,4 +612,8 @@ pub struct PerformanceStatistics {
     pub bytes_read: u64,
     pub bytes_written: u64,
     pub operations_per_second: f64,
+    /// Handle reads answered from blocks read ahead
+    pub prefetch_hits: u64,
+    /// Bytes read ahead and not yet served
+    pub prefetched_bytes: u64,
 }
@@ -0,0 +1,117 @@
+//! Pacing of files streamed to clients
+//!
+//! A `ReadFileStream` is sent as `ReadFileChunk` messages, but the agent's
+//! connection queues messages without bound, so a disk faster than the
+//! network would otherwise fill memory with a whole file. Each stream may
+//! instead only have `STREAM_WINDOW` chunks that its reader has not
+//! acknowledged with `ReadFileAck`. A reader that stops acknowledging for
+//! `STREAM_ACK_TIMEOUT` ends the stream.
+
+use remotefs_common::protocol::RequestId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Chunks a stream may send ahead of its reader's acknowledgements
+pub const STREAM_WINDOW: usize = 8;
+
+/// How long a stream waits for its reader to acknowledge a chunk
+pub const STREAM_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Room left in each stream's window, by stream
+#[derive(Default)]
+pub struct StreamTable {
+    windows: Mutex<HashMap<RequestId, Arc<Semaphore>>>,
+}
+
+impl StreamTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start pacing stream `stream_id`; it is forgotten when the returned
+    /// window is dropped
+    pub fn open(self: &Arc<Self>, stream_id: RequestId) -> StreamWindow {
+        let room = Arc::new(Semaphore::new(STREAM_WINDOW));
+        lock_windows(&self.windows).insert(stream_id, Arc::clone(&room));
+        StreamWindow { table: Arc::clone(self), stream_id, room }
+    }
+
+    /// Make room for another chunk of `stream_id`; `false` if the stream
+    /// has ended
+    pub fn acknowledge(&self, stream_id: &RequestId) -> bool {
+        match lock_windows(&self.windows).get(stream_id) {
+            Some(room) => {
+                room.add_permits(1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Streams being sent
+    pub fn len(&self) -> usize {
+        lock_windows(&self.windows).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The window of one stream
+pub struct StreamWindow {
+    table: Arc<StreamTable>,
+    stream_id: RequestId,
+    room: Arc<Semaphore>,
+}
+
+impl StreamWindow {
+    /// Wait for room to send a chunk; `false` if the reader did not
+    /// acknowledge one within `timeout`
+    pub async fn reserve(&self, timeout: Duration) -> bool {
+        match tokio::time::timeout(timeout, self.room.acquire()).await {
+            Ok(Ok(permit)) => {
+                permit.forget();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Drop for StreamWindow {
+    fn drop(&mut self) {
+        lock_windows(&self.table.windows).remove(&self.stream_id);
+    }
+}
+
+fn lock_windows<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_window() {
+        let table = Arc::new(StreamTable::new());
+        let stream_id = Uuid::new_v4();
+        let window = table.open(stream_id);
+
+        // A full window waits for an acknowledgement
+        for _ in 0..STREAM_WINDOW {
+            assert!(window.reserve(Duration::from_millis(10)).await);
+        }
+        assert!(!window.reserve(Duration::from_millis(10)).await);
+        assert!(table.acknowledge(&stream_id));
+        assert!(window.reserve(Duration::from_millis(10)).await);
+
+        drop(window);
+        assert!(table.is_empty());
+        assert!(!table.acknowledge(&stream_id));
+    }
+}
@@ -0,0 +1,286 @@
+//! All-or-nothing application of multi-step updates
+//!
+//! The operations of a transaction are applied in order while an undo log
+//! records how to reverse each one. Anything an operation would overwrite or
+//! remove is first renamed aside within its own directory, so putting it back
+//! cannot fail for lack of space, and is only deleted once every operation
+//! succeeded. When an operation fails, the log is replayed backwards.
+//!
+//! Transactions are all-or-nothing with respect to failed operations, not
+//! isolated from concurrent requests: other clients may see the intermediate
+//! states. If the agent dies mid-transaction, the renamed-aside originals are
+//! left next to their paths under a `.remotefs-txn-` prefix.
+
+use remotefs_common::protocol::TransactionOp;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Most operations accepted in one transaction
+pub const MAX_TRANSACTION_OPERATIONS: usize = 64;
+
+/// Prefix of the temporary and renamed-aside files of a transaction
+const TEMP_PREFIX: &str = ".remotefs-txn-";
+
+/// How to reverse one applied step
+#[derive(Debug)]
+enum Undo {
+    /// A file that did not exist before
+    RemoveFile(PathBuf),
+    /// A directory that did not exist before
+    RemoveDirectory(PathBuf),
+    /// Put a renamed-aside original back in place
+    Restore { aside: PathBuf, original: PathBuf },
+    /// Rename `from` back to `to`
+    Rename { from: PathBuf, to: PathBuf },
+}
+
+/// Operations applied so far, with how to undo them
+#[derive(Debug)]
+pub struct Transaction {
+    id: Uuid,
+    temp_files: usize,
+    undo: Vec<Undo>,
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            temp_files: 0,
+            undo: Vec::new(),
+        }
+    }
+
+    /// Apply one operation, leaving everything as it was if it fails
+    pub fn apply(&mut self, operation: &TransactionOp) -> io::Result<()> {
+        match operation {
+            TransactionOp::WriteFile { path, data } => self.write_file(Path::new(path), data),
+            TransactionOp::Rename { from_path, to_path } => self.rename(Path::new(from_path), Path::new(to_path)),
+            TransactionOp::DeleteFile { path } => {
+                let path = Path::new(path);
+                if fs::symlink_metadata(path)?.is_dir() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is a directory"));
+                }
+                self.move_aside(path)
+            }
+            TransactionOp::CreateDirectory { path } => {
+                fs::create_dir(path)?;
+                self.undo.push(Undo::RemoveDirectory(PathBuf::from(path)));
+                Ok(())
+            }
+            TransactionOp::RemoveDirectory { path } => {
+                let path = Path::new(path);
+                if fs::read_dir(path)?.next().is_some() {
+                    return Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, "directory is not empty"));
+                }
+                self.move_aside(path)
+            }
+        }
+    }
+
+    /// Keep every applied operation and drop the renamed-aside originals
+    pub fn commit(self) {
+        for undo in self.undo {
+            if let Undo::Restore { aside, .. } = undo {
+                let removed = if aside.is_dir() { fs::remove_dir(&aside) } else { fs::remove_file(&aside) };
+                if let Err(e) = removed {
+                    warn!("Failed to remove {} after transaction: {}", aside.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Reverse every applied operation, newest first
+    ///
+    /// Keeps going past failures so as much as possible is restored, and
+    /// returns the first error.
+    pub fn rollback(self) -> io::Result<()> {
+        let mut first_error = None;
+        for undo in self.undo.into_iter().rev() {
+            let result = match &undo {
+                Undo::RemoveFile(path) => fs::remove_file(path),
+                Undo::RemoveDirectory(path) => fs::remove_dir(path),
+                Undo::Restore { aside, original } => fs::rename(aside, original),
+                Undo::Rename { from, to } => fs::rename(from, to),
+            };
+            if let Err(e) = result {
+                warn!("Failed to roll back {:?}: {}", undo, e);
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Write the new content next to the file, then swap it in
+    fn write_file(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let temp = self.temp_path(path)?;
+        let result = self.write_via(&temp, path, data);
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    fn write_via(&mut self, temp: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create_new(temp)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+
+        let existed = match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is a directory"));
+            }
+            Ok(metadata) => {
+                // The new file keeps the permissions of the one it replaces
+                fs::set_permissions(temp, metadata.permissions())?;
+                true
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+
+        self.swap_in(temp, path, existed)?;
+        if !existed {
+            self.undo.push(Undo::RemoveFile(path.to_path_buf()));
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::symlink_metadata(from)?;
+        let existed = match fs::symlink_metadata(to) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "destination is a directory"));
+            }
+            Ok(_) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+
+        self.swap_in(from, to, existed)?;
+        self.undo.push(Undo::Rename { from: to.to_path_buf(), to: from.to_path_buf() });
+        Ok(())
+    }
+
+    /// Rename `from` to `to`, first moving aside what is at `to` if it exists
+    fn swap_in(&mut self, from: &Path, to: &Path, existed: bool) -> io::Result<()> {
+        if existed {
+            self.move_aside(to)?;
+        }
+        if let Err(e) = fs::rename(from, to) {
+            if existed {
+                if let Some(Undo::Restore { aside, original }) = self.undo.pop() {
+                    fs::rename(aside, original)?;
+                }
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Rename `path` to a temporary name in the same directory
+    fn move_aside(&mut self, path: &Path) -> io::Result<()> {
+        let aside = self.temp_path(path)?;
+        fs::rename(path, &aside)?;
+        self.undo.push(Undo::Restore { aside, original: path.to_path_buf() });
+        Ok(())
+    }
+
+    fn temp_path(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let parent = path.parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no parent directory"))?;
+        self.temp_files += 1;
+        Ok(parent.join(format!("{}{}-{}", TEMP_PREFIX, self.id.simple(), self.temp_files)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_string_lossy().into_owned()
+    }
+
+    fn listing(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_commit_replaces_atomically() {
+        let dir = TempDir::new().unwrap();
+        fs::write(path(&dir, "config"), b"old").unwrap();
+
+        let mut transaction = Transaction::new();
+        for operation in [
+            TransactionOp::WriteFile { path: path(&dir, "config.new"), data: b"new".to_vec() },
+            TransactionOp::Rename { from_path: path(&dir, "config.new"), to_path: path(&dir, "config") },
+            TransactionOp::CreateDirectory { path: path(&dir, "cache") },
+        ] {
+            transaction.apply(&operation).unwrap();
+        }
+        transaction.commit();
+
+        assert_eq!(fs::read(path(&dir, "config")).unwrap(), b"new");
+        assert_eq!(listing(&dir), vec!["cache", "config"]);
+    }
+
+    #[test]
+    fn test_rollback_restores_everything() {
+        let dir = TempDir::new().unwrap();
+        fs::write(path(&dir, "a"), b"a").unwrap();
+        fs::write(path(&dir, "b"), b"b").unwrap();
+        fs::create_dir(path(&dir, "empty")).unwrap();
+
+        let mut transaction = Transaction::new();
+        for operation in [
+            TransactionOp::WriteFile { path: path(&dir, "a"), data: b"changed".to_vec() },
+            TransactionOp::WriteFile { path: path(&dir, "c"), data: b"c".to_vec() },
+            TransactionOp::Rename { from_path: path(&dir, "c"), to_path: path(&dir, "b") },
+            TransactionOp::RemoveDirectory { path: path(&dir, "empty") },
+            TransactionOp::DeleteFile { path: path(&dir, "a") },
+        ] {
+            transaction.apply(&operation).unwrap();
+        }
+        assert!(transaction.apply(&TransactionOp::DeleteFile { path: path(&dir, "missing") }).is_err());
+        transaction.rollback().unwrap();
+
+        assert_eq!(listing(&dir), vec!["a", "b", "empty"]);
+        assert_eq!(fs::read(path(&dir, "a")).unwrap(), b"a");
+        assert_eq!(fs::read(path(&dir, "b")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn test_failed_operation_leaves_no_trace() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(path(&dir, "full")).unwrap();
+        fs::write(path(&dir, "full/file"), b"x").unwrap();
+
+        let mut transaction = Transaction::new();
+        assert!(transaction.apply(&TransactionOp::RemoveDirectory { path: path(&dir, "full") }).is_err());
+        assert!(transaction.apply(&TransactionOp::WriteFile { path: path(&dir, "full"), data: vec![] }).is_err());
+        assert!(transaction.apply(&TransactionOp::Rename {
+            from_path: path(&dir, "missing"),
+            to_path: path(&dir, "full"),
+        }).is_err());
+        transaction.rollback().unwrap();
+
+        assert_eq!(listing(&dir), vec!["full"]);
+    }
+}
@@ -0,0 +1,238 @@
+//! Trash for deleted files and directories
+//!
+//! With `trash.enabled`, `DeleteFile` and `RemoveDirectory` move what they
+//! delete into a trash directory at the top of the allowed path holding it,
+//! `.remotefs-trash` by default, instead of unlinking it. Each entry is kept
+//! in `files/` under an ID, with a record in `info/` of the path it was
+//! deleted from and when, so that `RestoreFromTrash` can put the most recent
+//! deletion of a path back. Entries are purged for good once they are older
+//! than `retention_secs`, or when a client asks with `PurgeTrash`.
+//!
+//! Access control keeps clients out of the trash directory itself. Deleting
+//! a whole allowed path removes it for good. Files replaced by writes,
+//! renames or transactions are not kept.
+//!
+//! Everything here blocks on the filesystem, so the handler calls it on its
+//! I/O pool rather than on the async runtime.
+
+use chrono::{DateTime, Utc};
+use remotefs_common::config::TrashConfig;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Where deleted entries wait to be restored or purged
+#[derive(Debug, Clone)]
+pub struct Trash {
+    /// Name of the trash directory in each allowed path
+    directory: String,
+    retention: Duration,
+    purge_interval: Duration,
+}
+
+/// An entry in the trash
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    /// Where the entry is kept
+    pub file: PathBuf,
+    /// Its record in `info/`
+    info: PathBuf,
+    /// Path it was deleted from
+    pub path: PathBuf,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// Record kept in `info/` for each entry
+#[derive(Debug, Serialize, Deserialize)]
+struct TrashInfo {
+    path: PathBuf,
+    deleted_at: DateTime<Utc>,
+}
+
+impl Trash {
+    /// Trash for `config`, or `None` if deletes unlink immediately
+    pub fn from_config(config: &TrashConfig) -> Option<Self> {
+        config.enabled.then(|| Self {
+            directory: config.directory.clone(),
+            retention: Duration::from_secs(config.retention_secs),
+            purge_interval: Duration::from_secs(config.purge_interval_secs.max(1)),
+        })
+    }
+
+    /// How long entries are kept
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Time between purges of expired entries
+    pub fn purge_interval(&self) -> Duration {
+        self.purge_interval
+    }
+
+    /// Trash directory of the allowed path `root`
+    pub fn dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.directory)
+    }
+
+    /// Whether deleting `path`, under the allowed path `root`, moves it into
+    /// the trash rather than removing it for good
+    pub fn keeps(&self, root: &Path, path: &Path) -> bool {
+        path != root && path.starts_with(root) && !path.starts_with(self.dir(root))
+    }
+
+    /// Move `path` into the trash of `root`, returning where it is kept
+    pub fn put(&self, root: &Path, path: &Path) -> io::Result<PathBuf> {
+        let dir = self.dir(root);
+        let (files, infos) = (dir.join("files"), dir.join("info"));
+        fs::create_dir_all(&files)?;
+        fs::create_dir_all(&infos)?;
+
+        let id = Uuid::new_v4().simple().to_string();
+        let (file, info) = (files.join(&id), infos.join(format!("{}.json", id)));
+        let record = TrashInfo {
+            path: path.to_path_buf(),
+            deleted_at: Utc::now(),
+        };
+        fs::write(&info, serde_json::to_vec(&record)?)?;
+        if let Err(e) = fs::rename(path, &file) {
+            let _ = fs::remove_file(&info);
+            return Err(e);
+        }
+        Ok(file)
+    }
+
+    /// Entries in the trash of `root`; those whose record cannot be read
+    /// are skipped
+    pub fn entries(&self, root: &Path) -> io::Result<Vec<TrashEntry>> {
+        let dir = self.dir(root);
+        let infos = match fs::read_dir(dir.join("info")) {
+            Ok(infos) => infos,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        for info in infos {
+            let info = info?.path();
+            let Some(id) = info.file_stem().filter(|_| info.extension().is_some_and(|ext| ext == "json")) else {
+                continue;
+            };
+            // Only IDs `put` could have made name a file, never one elsewhere
+            if !id.to_str().is_some_and(is_entry_id) {
+                continue;
+            }
+            let Ok(record) = fs::read(&info).and_then(|data| Ok(serde_json::from_slice::<TrashInfo>(&data)?)) else {
+                continue;
+            };
+            entries.push(TrashEntry {
+                file: dir.join("files").join(id),
+                info,
+                path: record.path,
+                deleted_at: record.deleted_at,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// The most recent deletion of `path` in the trash of `root`
+    pub fn latest(&self, root: &Path, path: &Path) -> io::Result<Option<TrashEntry>> {
+        Ok(self.entries(root)?
+            .into_iter()
+            .filter(|entry| entry.path == path)
+            .max_by_key(|entry| entry.deleted_at))
+    }
+
+    /// Move `entry` back to the path it was deleted from, which must not
+    /// exist
+    pub fn restore(&self, entry: &TrashEntry) -> io::Result<()> {
+        if fs::symlink_metadata(&entry.path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists", entry.path.display()),
+            ));
+        }
+        fs::rename(&entry.file, &entry.path)?;
+        fs::remove_file(&entry.info)
+    }
+
+    /// Delete `entry` for good
+    pub fn remove(&self, entry: &TrashEntry) -> io::Result<()> {
+        let removed = match fs::symlink_metadata(&entry.file) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&entry.file),
+            Ok(_) => fs::remove_file(&entry.file),
+            Err(e) => Err(e),
+        };
+        match removed {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => fs::remove_file(&entry.info),
+        }
+    }
+}
+
+/// Whether `id` is a UUID in the simple form `put` names entries with
+fn is_entry_id(id: &str) -> bool {
+    Uuid::try_parse(id).is_ok_and(|uuid| uuid.simple().to_string() == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trash() -> Trash {
+        Trash::from_config(&TrashConfig {
+            enabled: true,
+            ..TrashConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_put_and_restore() {
+        let root = tempfile::tempdir().unwrap();
+        let trash = trash();
+        let path = root.path().join("file.txt");
+
+        // Each deletion of a path is kept, and the latest comes back
+        fs::write(&path, "first").unwrap();
+        trash.put(root.path(), &path).unwrap();
+        fs::write(&path, "second").unwrap();
+        let kept = trash.put(root.path(), &path).unwrap();
+        assert!(!path.exists());
+        assert!(kept.starts_with(trash.dir(root.path())));
+
+        let entry = trash.latest(root.path(), &path).unwrap().unwrap();
+        assert_eq!(entry.file, kept);
+        trash.restore(&entry).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+
+        // A restore never replaces what is there now
+        let entry = trash.latest(root.path(), &path).unwrap().unwrap();
+        let e = trash.restore(&entry).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        trash.remove(&entry).unwrap();
+        assert!(trash.entries(root.path()).unwrap().is_empty());
+
+        // Records whose IDs are not UUIDs are ignored
+        let record = serde_json::to_vec(&TrashInfo { path: path.clone(), deleted_at: Utc::now() }).unwrap();
+        let info = trash.dir(root.path()).join("info");
+        fs::create_dir_all(&info).unwrap();
+        fs::write(info.join("..json"), &record).unwrap();
+        fs::write(info.join("file.txt.json"), &record).unwrap();
+        fs::write(info.join(format!("{}.json", Uuid::new_v4().hyphenated())), &record).unwrap();
+        assert!(trash.entries(root.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_keeps() {
+        let trash = trash();
+        let root = Path::new("/srv/share");
+        assert!(trash.keeps(root, &root.join("dir/file")));
+        assert!(!trash.keeps(root, root));
+        assert!(!trash.keeps(root, &root.join(".remotefs-trash/files/abc")));
+        assert!(!trash.keeps(root, Path::new("/srv/other")));
+        assert!(Trash::from_config(&TrashConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,591 @@
+//! Change notifications for `Watch` requests
+//!
+//! A client watching a path is sent `FileChanged` for every file or
+//! directory created, modified, deleted or renamed under it, and
+//! `DirectoryChanged` for every watched directory whose entries changed,
+//! until it sends `Unwatch` or its connection ends. Changes are only reported
+//! for paths the client may read.
+//!
+//! On Linux the watcher uses inotify, so changes made on the host itself are
+//! reported as well as those made through RemoteFS. inotify watches one
+//! directory at a time: a recursive watch adds one for every directory
+//! beneath its root, including directories created later, and each counts
+//! against the host's `fs.inotify.max_user_watches`. Elsewhere, or if inotify
+//! cannot be used, only the changes the agent makes for its clients are
+//! reported.
+//!
+//! If inotify's queue overflows, changes were lost and every watch ends with
+//! an error; clients watch again and re-read what they had cached.
+
+use crate::access::AccessControl;
+use remotefs_common::{
+    error::{RemoteFsError, Result},
+    protocol::{ChangeKind, Message, RequestId},
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Watches an agent runs at once, over all its clients
+pub const MAX_WATCHES: usize = 256;
+
+/// A change to report to the watches that cover it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+#[derive(Clone)]
+struct Subscription {
+    path: PathBuf,
+    recursive: bool,
+    access_control: Arc<AccessControl>,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Subscription {
+    /// Whether changes to `path` are reported
+    fn covers(&self, path: &Path) -> bool {
+        if self.recursive {
+            path.starts_with(&self.path)
+        } else {
+            path == self.path || path.parent() == Some(&self.path)
+        }
+    }
+
+    /// Whether the entries of directory `dir` are watched
+    fn lists(&self, dir: &Path) -> bool {
+        dir == self.path || (self.recursive && dir.starts_with(&self.path))
+    }
+
+    /// Messages reporting `change` to the watch `request_id`
+    fn notifications(&self, request_id: RequestId, change: &Change) -> Vec<Message> {
+        let mut messages = Vec::new();
+        let from = match &change.kind {
+            ChangeKind::Renamed { from } => Some(PathBuf::from(from)),
+            _ => None,
+        };
+
+        // A rename across the watch's edge is a create or a delete to it
+        match &from {
+            Some(from) if self.covers(&change.path) && !self.covers(from) => {
+                messages.push(file_changed(request_id, &change.path, ChangeKind::Created, change.is_dir));
+            }
+            Some(from) if !self.covers(&change.path) && self.covers(from) => {
+                messages.push(file_changed(request_id, from, ChangeKind::Deleted, change.is_dir));
+            }
+            _ if self.covers(&change.path) => {
+                messages.push(file_changed(request_id, &change.path, change.kind.clone(), change.is_dir));
+            }
+            _ => {}
+        }
+
+        if !matches!(change.kind, ChangeKind::Modified) {
+            let mut dirs: Vec<&Path> = change.path.parent().into_iter().collect();
+            if let Some(parent) = from.as_deref().and_then(Path::parent) {
+                if !dirs.contains(&parent) {
+                    dirs.push(parent);
+                }
+            }
+            messages.extend(dirs.into_iter()
+                .filter(|dir| self.lists(dir))
+                .map(|dir| Message::DirectoryChanged { request_id, path: dir.to_string_lossy().to_string() }));
+        }
+        messages
+    }
+
+    /// Whether `change` removed the watched path itself
+    fn ended_by(&self, change: &Change) -> bool {
+        match &change.kind {
+            ChangeKind::Deleted => change.path == self.path,
+            ChangeKind::Renamed { from } => Path::new(from) == self.path,
+            _ => false,
+        }
+    }
+
+    /// Whether the client may see the path a notification names
+    async fn may_see(&self, message: &Message) -> bool {
+        match message {
+            Message::FileChanged { path, .. } | Message::DirectoryChanged { path, .. } => {
+                self.access_control.is_readable(path).await
+            }
+            _ => true,
+        }
+    }
+}
+
+fn file_changed(request_id: RequestId, path: &Path, kind: ChangeKind, is_dir: bool) -> Message {
+    Message::FileChanged { request_id, path: path.to_string_lossy().to_string(), kind, is_dir }
+}
+
+type Subscriptions = Arc<Mutex<HashMap<RequestId, Subscription>>>;
+
+/// Runs the watches of an agent's clients
+pub struct Watcher {
+    subscriptions: Subscriptions,
+    /// Set up with the first watch; `None` if inotify is not available
+    #[cfg(target_os = "linux")]
+    inotify: tokio::sync::OnceCell<Option<Arc<inotify::Inotify>>>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Self {
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(target_os = "linux")]
+            inotify: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    /// Start reporting changes under `path` to `sender` as the watch
+    /// `request_id`, after a `WatchResponse`
+    pub async fn watch(
+        &self,
+        request_id: RequestId,
+        path: PathBuf,
+        recursive: bool,
+        access_control: Arc<AccessControl>,
+        sender: mpsc::UnboundedSender<Message>,
+    ) -> Result<()> {
+        if lock(&self.subscriptions).len() >= MAX_WATCHES {
+            return Err(RemoteFsError::ServiceUnavailable(format!(
+                "Agent is running its limit of {} watches; retry later", MAX_WATCHES
+            )));
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(inotify) = self.inotify().await {
+            let added = if recursive && path.is_dir() {
+                inotify.add_tree(&path).map(drop)
+            } else {
+                inotify.add(&path)
+            };
+            added.map_err(|e| RemoteFsError::io(&format!("Failed to watch {}", path.display()), e))?;
+        }
+
+        // Sent under the lock, so no change is reported before it
+        let mut subscriptions = lock(&self.subscriptions);
+        let _ = sender.send(Message::WatchResponse { request_id, success: true, error: None });
+        debug!("Watching {} for {}", path.display(), request_id);
+        subscriptions.insert(request_id, Subscription { path, recursive, access_control, sender });
+        Ok(())
+    }
+
+    /// Stop the watch `request_id`, returning whether it was running
+    pub fn unwatch(&self, request_id: RequestId) -> bool {
+        let removed = lock(&self.subscriptions).remove(&request_id).is_some();
+        self.release();
+        removed
+    }
+
+    /// Stop every watch without telling their clients, whose connection is gone
+    pub fn stop_all(&self) {
+        lock(&self.subscriptions).clear();
+        self.release();
+    }
+
+    /// Number of watches running
+    pub fn watch_count(&self) -> usize {
+        lock(&self.subscriptions).len()
+    }
+
+    /// Report a change the agent made itself, unless inotify reports it
+    pub async fn record(&self, kind: ChangeKind, path: &str, is_dir: bool) {
+        #[cfg(target_os = "linux")]
+        if matches!(self.inotify.get(), Some(Some(_))) {
+            return;
+        }
+        deliver(&self.subscriptions, vec![Change { kind, path: PathBuf::from(path), is_dir }]).await;
+    }
+
+    /// Stop watching directories no watch needs any more
+    fn release(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(Some(inotify)) = self.inotify.get() {
+            release(inotify, &self.subscriptions);
+        }
+    }
+
+    /// The inotify instance, set up and read from the first time it is needed
+    #[cfg(target_os = "linux")]
+    async fn inotify(&self) -> Option<Arc<inotify::Inotify>> {
+        self.inotify.get_or_init(|| async {
+            match inotify::Inotify::new() {
+                Ok(inotify) => {
+                    let inotify = Arc::new(inotify);
+                    tokio::spawn(read_changes(Arc::clone(&inotify), Arc::clone(&self.subscriptions)));
+                    Some(inotify)
+                }
+                Err(e) => {
+                    tracing::warn!("inotify is not available, only changes made through RemoteFS are reported: {}", e);
+                    None
+                }
+            }
+        }).await.clone()
+    }
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Report `changes` to the watches covering them, ending those whose path
+/// went away
+async fn deliver(subscriptions: &Subscriptions, changes: Vec<Change>) {
+    for change in changes {
+        let watches: Vec<(RequestId, Subscription)> = lock(subscriptions).iter()
+            .map(|(request_id, subscription)| (*request_id, subscription.clone()))
+            .collect();
+
+        for (request_id, subscription) in watches {
+            let mut delivered = true;
+            for message in subscription.notifications(request_id, &change) {
+                if subscription.may_see(&message).await {
+                    delivered &= subscription.sender.send(message).is_ok();
+                }
+            }
+
+            if !delivered {
+                debug!("Watch {} has no connection, stopping it", request_id);
+                lock(subscriptions).remove(&request_id);
+            } else if subscription.ended_by(&change) {
+                let error = Some(format!("{} was removed", subscription.path.display()));
+                let _ = subscription.sender.send(Message::WatchEnded { request_id, error });
+                lock(subscriptions).remove(&request_id);
+            }
+        }
+    }
+}
+
+/// End every watch with `error`
+#[cfg(target_os = "linux")]
+fn end_all(subscriptions: &Subscriptions, error: &str) {
+    for (request_id, subscription) in lock(subscriptions).drain() {
+        let _ = subscription.sender.send(Message::WatchEnded { request_id, error: Some(error.to_string()) });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn release(inotify: &inotify::Inotify, subscriptions: &Subscriptions) {
+    let subscriptions = lock(subscriptions);
+    inotify.retain(|path| subscriptions.values().any(|subscription| subscription.lists(path)));
+}
+
+/// Turn inotify's events into changes for the watches, until inotify fails
+#[cfg(target_os = "linux")]
+async fn read_changes(inotify: Arc<inotify::Inotify>, subscriptions: Subscriptions) {
+    loop {
+        let events = match inotify.read().await {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::error!("Failed to read inotify events, ending all watches: {}", e);
+                end_all(&subscriptions, &format!("Agent stopped watching: {}", e));
+                inotify.retain(|_| false);
+                return;
+            }
+        };
+
+        let batch = inotify.changes(events, |dir| {
+            lock(&subscriptions).values().any(|subscription| subscription.lists(dir))
+        });
+        if batch.overflowed {
+            tracing::warn!("inotify queue overflowed, ending all watches");
+            end_all(&subscriptions, "Too many changes at once, some were lost");
+            inotify.retain(|_| false);
+            continue;
+        }
+
+        deliver(&subscriptions, batch.changes).await;
+        release(&inotify, &subscriptions);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::{lock, Change};
+    use remotefs_common::protocol::ChangeKind;
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use tokio::io::unix::AsyncFd;
+    use tracing::debug;
+
+    const EVENTS: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    /// An event as read from inotify
+    pub struct Event {
+        wd: i32,
+        mask: u32,
+        cookie: u32,
+        /// Entry of the watched directory the event is about
+        name: Option<OsString>,
+    }
+
+    /// Changes made from one read of events
+    pub struct Batch {
+        pub changes: Vec<Change>,
+        /// Events were dropped because the queue was full
+        pub overflowed: bool,
+    }
+
+    #[derive(Default)]
+    struct Watched {
+        /// Path and whether it is a directory, by watch descriptor
+        paths: HashMap<i32, (PathBuf, bool)>,
+        descriptors: HashMap<PathBuf, i32>,
+    }
+
+    impl Watched {
+        fn insert(&mut self, wd: i32, path: PathBuf, is_dir: bool) {
+            self.descriptors.insert(path.clone(), wd);
+            self.paths.insert(wd, (path, is_dir));
+        }
+
+        fn remove(&mut self, wd: i32) {
+            if let Some((path, _)) = self.paths.remove(&wd) {
+                if self.descriptors.get(&path) == Some(&wd) {
+                    self.descriptors.remove(&path);
+                }
+            }
+        }
+    }
+
+    pub struct Inotify {
+        fd: AsyncFd<OwnedFd>,
+        watched: Mutex<Watched>,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: plain system call; the descriptor is owned below
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a new descriptor nothing else owns
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Self { fd: AsyncFd::new(fd)?, watched: Mutex::new(Watched::default()) })
+        }
+
+        /// Watch `path`, a directory or a file
+        pub fn add(&self, path: &Path) -> io::Result<()> {
+            let is_dir = path.symlink_metadata()?.is_dir();
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: `name` is a NUL-terminated path that outlives the call
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), name.as_ptr(), EVENTS | libc::IN_DONT_FOLLOW) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            lock(&self.watched).insert(wd, path.to_path_buf(), is_dir);
+            Ok(())
+        }
+
+        /// Watch directory `root` and every directory beneath it, returning
+        /// the entries found beneath it
+        ///
+        /// Subdirectories that cannot be watched are skipped.
+        pub fn add_tree(&self, root: &Path) -> io::Result<Vec<(PathBuf, bool)>> {
+            self.add(root)?;
+            let mut found = Vec::new();
+            let mut pending = vec![root.to_path_buf()];
+            while let Some(dir) = pending.pop() {
+                let Ok(entries) = std::fs::read_dir(&dir) else { continue };
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                    if is_dir {
+                        match self.add(&path) {
+                            Ok(()) => pending.push(path.clone()),
+                            Err(e) => debug!("Not watching {}: {}", path.display(), e),
+                        }
+                    }
+                    found.push((path, is_dir));
+                }
+            }
+            Ok(found)
+        }
+
+        /// Stop watching the paths `keep` rejects
+        pub fn retain(&self, keep: impl Fn(&Path) -> bool) {
+            let mut watched = lock(&self.watched);
+            let unneeded: Vec<i32> = watched.paths.iter()
+                .filter(|(_, (path, _))| !keep(path))
+                .map(|(wd, _)| *wd)
+                .collect();
+            for wd in unneeded {
+                // SAFETY: plain system call on a descriptor this instance owns
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+                watched.remove(wd);
+            }
+        }
+
+        /// Wait for events and read them
+        pub async fn read(&self) -> io::Result<Vec<Event>> {
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let mut ready = self.fd.readable().await?;
+                let read = ready.try_io(|fd| {
+                    // SAFETY: the buffer is valid for writes of its length
+                    let read = unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                    if read < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(read as usize)
+                    }
+                });
+                if let Ok(read) = read {
+                    return Ok(parse(&buffer[..read?]));
+                }
+            }
+        }
+
+        /// Changes described by `events`, watching the directories created
+        /// or moved in where `recursive` says their subdirectories are watched
+        pub fn changes(&self, events: Vec<Event>, recursive: impl Fn(&Path) -> bool) -> Batch {
+            let mut changes: Vec<Change> = Vec::new();
+            let mut overflowed = false;
+            // Changes of entries moved away, by cookie, until their other half arrives
+            let mut moved_from: HashMap<u32, usize> = HashMap::new();
+
+            for event in events {
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    overflowed = true;
+                    continue;
+                }
+                let Some((watched_path, watched_dir)) = lock(&self.watched).paths.get(&event.wd).cloned() else {
+                    continue;
+                };
+                if event.mask & libc::IN_IGNORED != 0 {
+                    lock(&self.watched).remove(event.wd);
+                    continue;
+                }
+
+                let Some(name) = event.name else {
+                    // The watched path itself; its directory's watch reports it if there is one
+                    let parent_watched = watched_path.parent()
+                        .is_some_and(|parent| lock(&self.watched).descriptors.contains_key(parent));
+                    if parent_watched {
+                        continue;
+                    }
+                    if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                        changes.push(Change { kind: ChangeKind::Deleted, path: watched_path, is_dir: watched_dir });
+                    } else if event.mask & (libc::IN_MODIFY | libc::IN_ATTRIB) != 0 {
+                        push_modified(&mut changes, watched_path, watched_dir);
+                    }
+                    continue;
+                };
+
+                let path = watched_path.join(name);
+                let is_dir = event.mask & libc::IN_ISDIR != 0;
+                if event.mask & libc::IN_CREATE != 0 {
+                    changes.push(Change { kind: ChangeKind::Created, path: path.clone(), is_dir });
+                    if is_dir && recursive(&path) {
+                        self.watch_new_tree(&path, &mut changes);
+                    }
+                } else if event.mask & libc::IN_DELETE != 0 {
+                    changes.push(Change { kind: ChangeKind::Deleted, path, is_dir });
+                } else if event.mask & (libc::IN_MODIFY | libc::IN_ATTRIB) != 0 {
+                    push_modified(&mut changes, path, is_dir);
+                } else if event.mask & libc::IN_MOVED_FROM != 0 {
+                    moved_from.insert(event.cookie, changes.len());
+                    changes.push(Change { kind: ChangeKind::Deleted, path, is_dir });
+                } else if event.mask & libc::IN_MOVED_TO != 0 {
+                    match moved_from.remove(&event.cookie) {
+                        Some(index) => {
+                            let from = std::mem::replace(&mut changes[index].path, path.clone());
+                            if is_dir {
+                                self.rename_tree(&from, &path);
+                            }
+                            changes[index].kind = ChangeKind::Renamed { from: from.to_string_lossy().to_string() };
+                        }
+                        None => changes.push(Change { kind: ChangeKind::Created, path: path.clone(), is_dir }),
+                    }
+                    if is_dir && recursive(&path) {
+                        self.watch_new_tree(&path, &mut changes);
+                    }
+                }
+            }
+
+            // Moved out of every watched directory
+            for index in moved_from.into_values() {
+                let gone = &changes[index].path;
+                self.retain(|path| !path.starts_with(gone));
+            }
+
+            Batch { changes, overflowed }
+        }
+
+        /// Watch a directory that appeared, reporting what it already holds
+        fn watch_new_tree(&self, dir: &Path, changes: &mut Vec<Change>) {
+            if lock(&self.watched).descriptors.contains_key(dir) {
+                return;
+            }
+            match self.add_tree(dir) {
+                Ok(found) => changes.extend(found.into_iter()
+                    .map(|(path, is_dir)| Change { kind: ChangeKind::Created, path, is_dir })),
+                Err(e) => debug!("Not watching {}: {}", dir.display(), e),
+            }
+        }
+
+        /// Follow a watched tree to where it was moved
+        fn rename_tree(&self, from: &Path, to: &Path) {
+            let mut watched = lock(&self.watched);
+            let moved: Vec<(i32, PathBuf, bool)> = watched.paths.iter()
+                .filter_map(|(wd, (path, is_dir))| {
+                    let rest = path.strip_prefix(from).ok()?;
+                    Some((*wd, to.join(rest), *is_dir))
+                })
+                .collect();
+            for (wd, path, is_dir) in moved {
+                watched.remove(wd);
+                watched.insert(wd, path, is_dir);
+            }
+        }
+    }
+
+    /// Record a modification, once per batch of events for the same path
+    fn push_modified(changes: &mut Vec<Change>, path: PathBuf, is_dir: bool) {
+        let change = Change { kind: ChangeKind::Modified, path, is_dir };
+        if changes.last() != Some(&change) {
+            changes.push(change);
+        }
+    }
+
+    fn parse(buffer: &[u8]) -> Vec<Event> {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + header <= buffer.len() {
+            // SAFETY: the kernel wrote a whole event header at `offset`
+            let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+            let end = (offset + header + event.len as usize).min(buffer.len());
+            let name = buffer[offset + header..end].split(|byte| *byte == 0).next()
+                .filter(|name| !name.is_empty())
+                .map(|name| OsStr::from_bytes(name).to_os_string());
+            events.push(Event { wd: event.wd, mask: event.mask, cookie: event.cookie, name });
+            offset = end;
+        }
+        events
+    }
+}
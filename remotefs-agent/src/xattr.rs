@@ -0,0 +1,247 @@
+//! Extended attributes, without following symlinks
+//!
+//! Linux and macOS expose the same calls with different signatures; other
+//! platforms report no attributes and refuse to set any. Filesystems without
+//! xattr support report none as well rather than failing.
+
+use remotefs_common::protocol::XattrSetMode;
+use std::{
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// All extended attributes of `path` by name
+pub fn read_all(path: &Path) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    let mut xattrs = BTreeMap::new();
+    for name in list_names(path)? {
+        // Removed between listing and reading if missing
+        if let Some(value) = read(path, &name)? {
+            xattrs.insert(name, value);
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Names of the extended attributes of `path`
+pub fn list_names(path: &Path) -> io::Result<Vec<String>> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let names = match sized(|buf| list(&c_path, buf)) {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    Ok(names
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+/// Value of the attribute `name` of `path`, or `None` if it has none
+pub fn read(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_name = c_string(name.as_bytes())?;
+    match sized(|buf| get(&c_path, &c_name, buf)) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if is_missing(&e) || is_unsupported(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Set the attribute `name` of `path` to `value`
+///
+/// Fails with `AlreadyExists` or `NotFound` when `mode` does not allow
+/// creating or replacing the attribute.
+pub fn write(path: &Path, name: &str, value: &[u8], mode: XattrSetMode) -> io::Result<()> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_name = c_string(name.as_bytes())?;
+    let flags = match mode {
+        XattrSetMode::Upsert => 0,
+        XattrSetMode::Create => libc::XATTR_CREATE,
+        XattrSetMode::Replace => libc::XATTR_REPLACE,
+    };
+    set(&c_path, &c_name, value, flags)
+}
+
+/// Remove the attribute `name` of `path`, failing with `NotFound` if it has
+/// none
+pub fn remove(path: &Path, name: &str) -> io::Result<()> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_name = c_string(name.as_bytes())?;
+    unset(&c_path, &c_name)
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+/// Turn the result of a set or remove call into an error whose kind tells a
+/// missing attribute from other failures
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn check(result: i32) -> io::Result<()> {
+    if result == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if is_missing(&e) {
+        Err(io::Error::new(io::ErrorKind::NotFound, "no such attribute"))
+    } else {
+        Err(e)
+    }
+}
+
+/// Call a size-query style function: once to learn the size, then again to
+/// fill a buffer of that size, retrying if the value grew in between
+fn sized(call: impl Fn(&mut [u8]) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(&mut []);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let read = call(&mut buf);
+        if read < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+        buf.truncate(read as usize);
+        return Ok(buf);
+    }
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+fn is_missing(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(NO_ATTR)
+}
+
+#[cfg(target_os = "linux")]
+const NO_ATTR: i32 = libc::ENODATA;
+
+#[cfg(not(target_os = "linux"))]
+const NO_ATTR: i32 = libc::ENOATTR;
+
+#[cfg(target_os = "linux")]
+fn list(path: &CStr, buf: &mut [u8]) -> isize {
+    // SAFETY: `path` is NUL-terminated and `buf` is valid for its length
+    unsafe { libc::llistxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) }
+}
+
+#[cfg(target_os = "linux")]
+fn get(path: &CStr, name: &CStr, buf: &mut [u8]) -> isize {
+    // SAFETY: `path` and `name` are NUL-terminated and `buf` is valid for its length
+    unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) }
+}
+
+#[cfg(target_os = "linux")]
+fn set(path: &CStr, name: &CStr, value: &[u8], flags: i32) -> io::Result<()> {
+    // SAFETY: `path` and `name` are NUL-terminated and `value` is valid for its length
+    check(unsafe { libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), flags) })
+}
+
+#[cfg(target_os = "linux")]
+fn unset(path: &CStr, name: &CStr) -> io::Result<()> {
+    // SAFETY: `path` and `name` are NUL-terminated
+    check(unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) })
+}
+
+#[cfg(target_os = "macos")]
+fn list(path: &CStr, buf: &mut [u8]) -> isize {
+    // SAFETY: `path` is NUL-terminated and `buf` is valid for its length
+    unsafe { libc::listxattr(path.as_ptr(), buf.as_mut_ptr().cast(), buf.len(), libc::XATTR_NOFOLLOW) }
+}
+
+#[cfg(target_os = "macos")]
+fn get(path: &CStr, name: &CStr, buf: &mut [u8]) -> isize {
+    // SAFETY: `path` and `name` are NUL-terminated and `buf` is valid for its length
+    unsafe {
+        libc::getxattr(path.as_ptr(), name.as_ptr(), buf.as_mut_ptr().cast(), buf.len(), 0, libc::XATTR_NOFOLLOW)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set(path: &CStr, name: &CStr, value: &[u8], flags: i32) -> io::Result<()> {
+    // SAFETY: `path` and `name` are NUL-terminated and `value` is valid for its length
+    check(unsafe {
+        libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0, flags | libc::XATTR_NOFOLLOW)
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn unset(path: &CStr, name: &CStr) -> io::Result<()> {
+    // SAFETY: `path` and `name` are NUL-terminated
+    check(unsafe { libc::removexattr(path.as_ptr(), name.as_ptr(), libc::XATTR_NOFOLLOW) })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list(_path: &CStr, _buf: &mut [u8]) -> isize {
+    // No attributes to report
+    0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn get(_path: &CStr, _name: &CStr, _buf: &mut [u8]) -> isize {
+    0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set(_path: &CStr, _name: &CStr, _value: &[u8], _flags: i32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn unset(_path: &CStr, _name: &CStr) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_read_all() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.txt");
+        std::fs::write(&path, b"abc").unwrap();
+
+        // tmpfs and most local filesystems support user attributes, but a
+        // fresh file has none
+        let xattrs = read_all(&path).unwrap();
+        assert!(xattrs.keys().all(|name| !name.starts_with("user.")));
+
+        assert!(read_all(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_read_user_attribute() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("a.txt");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let value = b"backup";
+        // SAFETY: both strings are NUL-terminated and `value` is valid for its length
+        let set = unsafe {
+            libc::setxattr(c_path.as_ptr(), c"user.remotefs.test".as_ptr(), value.as_ptr().cast(), value.len(), 0)
+        };
+        if set != 0 {
+            // The filesystem under the temp dir has no user attributes
+            return;
+        }
+
+        let xattrs = read_all(&path).unwrap();
+        assert_eq!(xattrs.get("user.remotefs.test").map(Vec::as_slice), Some(&value[..]));
+    }
+}
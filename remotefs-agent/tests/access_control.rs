@@ -1,5 +1,3 @@
-use std::path::Path;
-use tokio;
 
 mod common;
 use common::*;
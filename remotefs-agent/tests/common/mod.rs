@@ -1,11 +1,11 @@
-use std::path::{Path, PathBuf};
+// Shared helpers; not every test crate uses all of them
+#![allow(dead_code)]
+
+use std::path::Path;
 use std::fs;
 use std::sync::Arc;
 use tempfile::TempDir;
-use remotefs_common::{
-    config::{AgentConfig, AccessConfig, SecurityConfig, NetworkConfig, LoggingConfig, PerformanceConfig},
-    defaults,
-};
+use remotefs_common::config::{AgentConfig, RelayMode, AccessConfig, UnmatchedUserPolicy, RuleEffect, SecurityConfig, NetworkConfig, LoggingConfig, CrashConfig, PerformanceConfig, JournalConfig, ArchiveConfig, TrashConfig, MirrorConfig, ResourceLimitsConfig, RateLimitConfig, RemoteExecConfig, IdMapConfig};
 use remotefs_agent::access::AccessControl;
 
 /// Create a temporary directory for tests
@@ -52,6 +52,8 @@ pub fn create_test_config(temp_dir: &Path) -> AgentConfig {
     AgentConfig {
         agent_id: "test-agent".to_string(),
         relay_url: "ws://localhost:8080/ws".to_string(),
+        relay_urls: vec![],
+        relay_mode: RelayMode::default(),
         access: AccessConfig {
             allowed_paths: vec![
                 temp_dir.join("allowed").to_string_lossy().to_string(),
@@ -65,8 +67,15 @@ pub fn create_test_config(temp_dir: &Path) -> AgentConfig {
             ],
             max_file_size: 1024 * 1024, // 1MB
             follow_symlinks: true,
+            deny_symlink_escapes: false,
             allowed_extensions: vec![],
             denied_extensions: vec!["exe".to_string(), "bat".to_string()],
+            user_rules: vec![],
+            unmatched_users: UnmatchedUserPolicy::Allow,
+            rules: vec![],
+            unmatched_rules: RuleEffect::Allow,
+            allowed_mode: 0o7777,
+            quotas: Vec::new(),
         },
         security: SecurityConfig {
             key_file: temp_dir.join("agent.key"),
@@ -86,6 +95,8 @@ pub fn create_test_config(temp_dir: &Path) -> AgentConfig {
             max_files: 5,
             enable_access_log: false,
             access_log_file: None,
+            forward_access_log: false,
+            crash: CrashConfig::default(),
         },
         performance: PerformanceConfig {
             worker_threads: 2,
@@ -95,6 +106,15 @@ pub fn create_test_config(temp_dir: &Path) -> AgentConfig {
             enable_prefetch: false,
             prefetch_window: 4,
         },
+        journal: JournalConfig::default(),
+        archive: ArchiveConfig::default(),
+        trash: TrashConfig::default(),
+        mirror: MirrorConfig::default(),
+        limits: ResourceLimitsConfig::default(),
+        rate_limits: RateLimitConfig::default(),
+        remote_exec: RemoteExecConfig::default(),
+        id_map: IdMapConfig::default(),
+        local_socket: None,
     }
 }
 
@@ -116,7 +136,7 @@ pub fn assert_path_not_exists<P: AsRef<Path>>(path: P) {
 /// Assert that a file contains specific content
 pub fn assert_file_content<P: AsRef<Path>>(path: P, expected_content: &str) {
     let actual_content = fs::read_to_string(path.as_ref())
-        .expect(&format!("Failed to read file: {}", path.as_ref().display()));
+        .unwrap_or_else(|_| panic!("Failed to read file: {}", path.as_ref().display()));
     assert_eq!(actual_content, expected_content, "File content mismatch");
 }
 
@@ -124,7 +144,7 @@ pub fn assert_file_content<P: AsRef<Path>>(path: P, expected_content: &str) {
 pub fn assert_directory_contains<P: AsRef<Path>>(dir_path: P, expected_entries: &[&str]) {
     let dir_path = dir_path.as_ref();
     let entries: Vec<String> = fs::read_dir(dir_path)
-        .expect(&format!("Failed to read directory: {}", dir_path.display()))
+        .unwrap_or_else(|_| panic!("Failed to read directory: {}", dir_path.display()))
         .map(|entry| {
             entry.expect("Failed to read directory entry")
                 .file_name()
@@ -1,11 +1,17 @@
 use std::sync::Arc;
 use uuid::Uuid;
-use tokio;
 
 mod common;
 use common::*;
-use remotefs_agent::filesystem::FilesystemHandler;
-use remotefs_common::config::PerformanceConfig;
+use remotefs_agent::{
+    access::AccessControl, archive::ArchiveHooks, filesystem::FilesystemHandler, ids::IdMap, journal::ChangeJournal,
+    limits::ResourceLimits, mirror::MirrorState, quota::QuotaTable, rate::RateLimiter, trash::Trash,
+};
+use remotefs_common::checksum::Checksum;
+use remotefs_common::delta;
+use remotefs_common::config::{ArchiveConfig, IdMapConfig, IdMapping, PathQuota, RateLimit, RateLimitConfig, ResourceLimitsConfig, TrashConfig};
+use remotefs_common::protocol::{ChangeKind, ChecksumAlgorithm, ErrorCode, FileLock, FileMetadata, FileType, LockOwner, LockType, Message, MetadataUpdate, OpenFlags, NewFile, TransactionOp, XattrSetMode};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 
 #[tokio::test]
 async fn test_filesystem_handler_creation() {
@@ -38,7 +44,7 @@ async fn test_read_file_success() {
     let result = filesystem_handler.handle_read_file(request_id, file_path, None, None).await;
     
     assert!(result.is_some(), "Should return a response");
-    let response = result.unwrap();
+    let _response = result.unwrap();
     
     // Check if it's a ReadFileResponse (would need to match on the actual message type)
     // For now, we just verify we got a response
@@ -301,6 +307,226 @@ async fn test_get_metadata_directory() {
     assert_eq!(stats.total_operations, 1);
 }
 
+#[tokio::test]
+async fn test_get_metadata_finder_attributes() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let hidden_path = temp_dir.path().join("allowed/.hidden");
+    std::fs::write(&hidden_path, b"secret").unwrap();
+    
+    let result = filesystem_handler
+        .handle_get_metadata(Uuid::new_v4(), hidden_path.to_string_lossy().to_string(), true)
+        .await;
+    let metadata = match result {
+        Some(Message::GetMetadataResponse { metadata: Some(metadata), .. }) => metadata,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    
+    assert!(metadata.hidden);
+    // Birth time is never later than the contents, and ctime is reported
+    assert!(metadata.created <= metadata.modified);
+    assert!(metadata.changed >= metadata.modified);
+    assert!(metadata.changed.timestamp() > 0);
+    
+    let result = filesystem_handler
+        .handle_get_metadata(Uuid::new_v4(), temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string(), true)
+        .await;
+    assert!(matches!(
+        result,
+        Some(Message::GetMetadataResponse { metadata: Some(FileMetadata { hidden: false, .. }), .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_set_metadata_partial_update() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let file_path = temp_dir.path().join("allowed/test.txt");
+    let path_str = file_path.to_string_lossy().to_string();
+    
+    // Set permissions only
+    let update = MetadataUpdate { permissions: Some(0o600), ..Default::default() };
+    let result = filesystem_handler.handle_set_metadata(Uuid::new_v4(), path_str.clone(), update).await;
+    assert!(matches!(result, Some(Message::SetMetadataResponse { success: true, .. })));
+    assert_eq!(std::fs::metadata(&file_path).unwrap().permissions().mode() & 0o777, 0o600);
+    
+    // Setting mtime alone must not touch the mode bits
+    let mtime = chrono::DateTime::from_timestamp(1_000_000_000, 0).unwrap();
+    let update = MetadataUpdate { modified: Some(mtime), ..Default::default() };
+    let result = filesystem_handler.handle_set_metadata(Uuid::new_v4(), path_str, update).await;
+    assert!(matches!(result, Some(Message::SetMetadataResponse { success: true, .. })));
+    
+    let metadata = std::fs::metadata(&file_path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert_eq!(chrono::DateTime::<chrono::Utc>::from(metadata.modified().unwrap()), mtime);
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.total_operations, 2);
+    assert_eq!(stats.error_count, 0);
+}
+
+#[tokio::test]
+async fn test_set_metadata_times_of_unreadable_file() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let file_path = temp_dir.path().join("allowed/test.txt");
+    
+    // Times are set after the mode, so they must not need the file opened
+    let mtime = chrono::DateTime::from_timestamp(1_000_000_000, 500).unwrap();
+    let update = MetadataUpdate { permissions: Some(0o000), modified: Some(mtime), ..Default::default() };
+    let result = filesystem_handler.handle_set_metadata(Uuid::new_v4(), file_path.to_string_lossy().to_string(), update).await;
+    assert!(matches!(result, Some(Message::SetMetadataResponse { success: true, .. })), "{:?}", result);
+    
+    let metadata = std::fs::metadata(&file_path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0);
+    assert_eq!(chrono::DateTime::<chrono::Utc>::from(metadata.modified().unwrap()), mtime);
+    std::fs::set_permissions(&file_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+}
+
+#[tokio::test]
+async fn test_set_metadata_tree() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = Arc::new(FilesystemHandler::new(access_control, &config.performance));
+    
+    let tree = temp_dir.path().join("allowed/tree");
+    std::fs::create_dir_all(tree.join("sub/deeper")).unwrap();
+    create_test_file(tree.join("a.txt"), "a");
+    create_test_file(tree.join("sub/b.txt"), "b");
+    create_test_file(tree.join("sub/deeper/c.txt"), "c");
+    let outside = temp_dir.path().join("allowed/test.txt");
+    std::os::unix::fs::symlink(&outside, tree.join("sub/link")).unwrap();
+    let outside_mode = std::fs::metadata(&outside).unwrap().permissions().mode() & 0o7777;
+    
+    let (progress_tx, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+    let request_id = Uuid::new_v4();
+    let update = MetadataUpdate { permissions: Some(0o750), ..Default::default() };
+    let tree_path = tree.to_string_lossy().to_string();
+    let response = filesystem_handler.handle_set_metadata_tree(request_id, tree_path, update.clone(), &progress_tx).await;
+    assert!(response.is_none(), "{:?}", response);
+    
+    let progress = loop {
+        match progress_rx.recv().await {
+            Some(Message::SetMetadataTreeProgress { progress, last: true, error: None, .. }) => break progress,
+            Some(Message::SetMetadataTreeProgress { last: false, .. }) => continue,
+            other => panic!("Unexpected progress: {:?}", other),
+        }
+    };
+    assert_eq!((progress.changed, progress.failed, progress.cancelled), (6, 0, false));
+    for path in ["", "a.txt", "sub", "sub/b.txt", "sub/deeper", "sub/deeper/c.txt"] {
+        assert_eq!(std::fs::metadata(tree.join(path)).unwrap().permissions().mode() & 0o7777, 0o750, "{}", path);
+    }
+    // Links are not followed
+    assert_eq!(std::fs::metadata(&outside).unwrap().permissions().mode() & 0o7777, outside_mode);
+    
+    // The job is gone once it has ended
+    assert!(filesystem_handler.handle_cancel_set_metadata_tree(request_id).is_none());
+    
+    // Read-only trees are refused before anything is changed
+    let readonly = temp_dir.path().join("readonly").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_set_metadata_tree(Uuid::new_v4(), readonly, update, &progress_tx).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_set_metadata_readonly_path() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let readonly_path = temp_dir.path().join("readonly/readonly.txt").to_string_lossy().to_string();
+    
+    let update = MetadataUpdate { permissions: Some(0o777), ..Default::default() };
+    let result = filesystem_handler.handle_set_metadata(Uuid::new_v4(), readonly_path, update).await;
+    assert!(matches!(result, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", result);
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.error_count, 1);
+}
+
+#[tokio::test]
+async fn test_owner_id_mapping() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    // The client's 4242 is whoever runs the test on this host
+    let file_path = temp_dir.path().join("allowed/test.txt");
+    let owner = std::fs::metadata(&file_path).unwrap();
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_id_map(Arc::new(IdMap::new(&IdMapConfig {
+            uids: vec![IdMapping { remote: 4242, local: owner.uid() }],
+            gids: vec![IdMapping { remote: 4343, local: owner.gid() }],
+        })));
+    let path_str = file_path.to_string_lossy().to_string();
+    
+    let result = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path_str.clone(), true).await;
+    let Some(Message::GetMetadataResponse { metadata: Some(metadata), .. }) = result else {
+        panic!("Unexpected response: {:?}", result)
+    };
+    assert_eq!((metadata.uid, metadata.gid), (4242, 4343));
+    
+    let result = filesystem_handler.handle_list_directory(Uuid::new_v4(), temp_dir.path().join("allowed").to_string_lossy().to_string()).await;
+    let Some(Message::ListDirectoryResponse { entries: Some(entries), .. }) = result else {
+        panic!("Unexpected response: {:?}", result)
+    };
+    assert!(entries.iter().all(|entry| entry.metadata.uid == 4242));
+    
+    // Changing the owner to the client's 4242 is a chown to the host user
+    let update = MetadataUpdate { uid: Some(4242), gid: Some(4343), ..Default::default() };
+    let result = filesystem_handler.handle_set_metadata(Uuid::new_v4(), path_str, update).await;
+    assert!(matches!(result, Some(Message::SetMetadataResponse { success: true, .. })), "{:?}", result);
+    assert_eq!(std::fs::metadata(&file_path).unwrap().uid(), owner.uid());
+}
+
+#[tokio::test]
+async fn test_errors_carry_codes() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |name: &str| temp_dir.path().join(name).to_string_lossy().to_string();
+    
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/nonexistent.txt"), None, None).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })));
+    
+    let response = filesystem_handler.handle_list_directory(Uuid::new_v4(), path("allowed/test.txt")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::NotADirectory, .. })));
+    
+    // The OS error number travels with the code
+    let response = filesystem_handler.handle_delete_directory(Uuid::new_v4(), path("allowed/subdir1"), false).await;
+    match response {
+        Some(Message::Error { code: ErrorCode::DirectoryNotEmpty, errno, .. }) => assert_eq!(errno, Some(libc::ENOTEMPTY)),
+        other => panic!("Unexpected response: {:?}", other),
+    }
+}
+
 #[tokio::test]
 async fn test_create_directory_success() {
     setup_test_logging();
@@ -310,71 +536,1162 @@ async fn test_create_directory_success() {
     let access_control = create_test_access_control(&config.access);
     
     let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
-    let request_id = Uuid::new_v4();
-    let new_dir_path = temp_dir.path().join("allowed/new_directory").to_string_lossy().to_string();
-    
-    let result = filesystem_handler.handle_create_directory(request_id, new_dir_path.clone(), 0o755).await;
-    
-    assert!(result.is_some(), "Should return a response");
-    
-    // Verify directory was created
-    assert_path_exists(&new_dir_path);
-    assert!(std::path::Path::new(&new_dir_path).is_dir());
+    let request_id = Uuid::new_v4();
+    let new_dir_path = temp_dir.path().join("allowed/new_directory").to_string_lossy().to_string();
+    
+    let result = filesystem_handler.handle_create_directory(request_id, new_dir_path.clone(), 0o755).await;
+    
+    assert!(result.is_some(), "Should return a response");
+    
+    // Verify directory was created
+    assert_path_exists(&new_dir_path);
+    assert!(std::path::Path::new(&new_dir_path).is_dir());
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.total_operations, 1);
+}
+
+#[tokio::test]
+async fn test_create_directory_recursive() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let request_id = Uuid::new_v4();
+    let nested_dir_path = temp_dir.path().join("allowed/deep/nested/directory").to_string_lossy().to_string();
+    
+    let result = filesystem_handler.handle_create_directory(request_id, nested_dir_path.clone(), 0o755).await;
+    
+    assert!(result.is_some(), "Should return a response");
+    
+    // Verify nested directories were created
+    assert_path_exists(&nested_dir_path);
+    assert!(std::path::Path::new(&nested_dir_path).is_dir());
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.total_operations, 1);
+}
+
+#[tokio::test]
+async fn test_delete_file_success() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    // Create a file to delete
+    let file_to_delete = temp_dir.path().join("allowed/delete_me.txt");
+    create_test_file(&file_to_delete, "delete me");
+    assert_path_exists(&file_to_delete);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let request_id = Uuid::new_v4();
+    let file_path = file_to_delete.to_string_lossy().to_string();
+    
+    let result = filesystem_handler.handle_delete_file(request_id, file_path).await;
+    
+    assert!(result.is_some(), "Should return a response");
+    
+    // Verify file was deleted
+    assert_path_not_exists(&file_to_delete);
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.total_operations, 1);
+}
+
+#[tokio::test]
+async fn test_create_hard_link() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    
+    let existing = temp_dir.path().join("allowed/test.txt");
+    let link = temp_dir.path().join("allowed/link.txt");
+    let path = |path: &std::path::Path| path.to_string_lossy().to_string();
+    
+    let response = filesystem_handler.handle_create_hard_link(Uuid::new_v4(), path(&existing), path(&link)).await;
+    assert!(matches!(response, Some(Message::CreateHardLinkResponse { success: true, .. })), "{:?}", response);
+    assert_eq!(std::fs::metadata(&existing).unwrap().ino(), std::fs::metadata(&link).unwrap().ino());
+    
+    // Both names report the second link
+    let response = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path(&link), true).await;
+    assert!(matches!(response, Some(Message::GetMetadataResponse { metadata: Some(FileMetadata { nlink: 2, .. }), .. })), "{:?}", response);
+    
+    // An existing name is not replaced
+    let response = filesystem_handler.handle_create_hard_link(Uuid::new_v4(), path(&existing), path(&link)).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })), "{:?}", response);
+    
+    // A link would make a read-only file writable through an allowed path
+    let readonly = temp_dir.path().join("readonly/readonly.txt");
+    let escape = temp_dir.path().join("allowed/escape.txt");
+    let response = filesystem_handler.handle_create_hard_link(Uuid::new_v4(), path(&readonly), path(&escape)).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert_path_not_exists(&escape);
+}
+
+#[tokio::test]
+async fn test_read_symlink() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    // The target is returned as stored, even when it does not exist
+    std::os::unix::fs::symlink("test.txt", path("allowed/link")).unwrap();
+    std::os::unix::fs::symlink("missing.txt", path("allowed/dangling")).unwrap();
+    for (link, target) in [("allowed/link", "test.txt"), ("allowed/dangling", "missing.txt")] {
+        let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path(link)).await;
+        assert!(matches!(&response, Some(Message::ReadSymlinkResponse { success: true, target: Some(t), .. }) if t == target), "{:?}", response);
+    }
+    
+    let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path("allowed/test.txt")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidPath, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path("allowed/missing")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+    
+    // Only the link is checked, not where it leads, even when escapes are
+    // denied or symlinks are not followed
+    std::os::unix::fs::symlink("/etc/passwd", path("allowed/passwd")).unwrap();
+    std::os::unix::fs::symlink(path("allowed/subdir1"), path("allowed/linked-dir")).unwrap();
+    std::os::unix::fs::symlink("nested.txt", path("allowed/subdir1/nested-link")).unwrap();
+    for (deny_symlink_escapes, follow_symlinks) in [(false, true), (true, true), (false, false)] {
+        let mut access = config.access.clone();
+        access.deny_symlink_escapes = deny_symlink_escapes;
+        access.follow_symlinks = follow_symlinks;
+        let filesystem_handler = FilesystemHandler::new(create_test_access_control(&access), &config.performance);
+        let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path("allowed/passwd")).await;
+        assert!(matches!(&response, Some(Message::ReadSymlinkResponse { success: true, target: Some(t), .. }) if t == "/etc/passwd"), "{:?}", response);
+        
+        // Symlinks on the way to the link are still refused
+        let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path("allowed/linked-dir/nested-link")).await;
+        assert_eq!(matches!(response, Some(Message::ReadSymlinkResponse { success: true, .. })), follow_symlinks, "{:?}", response);
+    }
+    
+    // Links in denied paths stay out of reach
+    std::os::unix::fs::symlink("secret.txt", path("denied/link")).unwrap();
+    let response = filesystem_handler.handle_read_symlink(Uuid::new_v4(), path("denied/link")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_get_metadata_of_symlink() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    std::os::unix::fs::symlink("test.txt", path("allowed/link")).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", path("allowed/passwd")).unwrap();
+    
+    // What a mount asks for on a directory cache miss: the link itself,
+    // wherever it points and however symlinks are checked
+    for (deny_symlink_escapes, follow_symlinks) in [(false, true), (true, true), (false, false)] {
+        let mut access = config.access.clone();
+        access.deny_symlink_escapes = deny_symlink_escapes;
+        access.follow_symlinks = follow_symlinks;
+        let filesystem_handler = FilesystemHandler::new(create_test_access_control(&access), &config.performance);
+        for (link, target) in [("allowed/link", "test.txt"), ("allowed/passwd", "/etc/passwd")] {
+            let response = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path(link), false).await;
+            let Some(Message::GetMetadataResponse { metadata: Some(metadata), .. }) = response else {
+                panic!("Expected the link's metadata: {:?}", response);
+            };
+            assert!(metadata.is_symlink && matches!(metadata.file_type, FileType::Symlink));
+            assert_eq!(metadata.symlink_target.as_deref(), Some(target));
+        }
+    }
+    
+    // Following describes the target, which must be reachable
+    let filesystem_handler = FilesystemHandler::new(create_test_access_control(&config.access), &config.performance);
+    let response = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path("allowed/link"), true).await;
+    assert!(matches!(response, Some(Message::GetMetadataResponse { metadata: Some(FileMetadata { is_file: true, size: 12, .. }), .. })), "{:?}", response);
+    let response = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path("allowed/passwd"), true).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_truncate_file() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    // Files are cut down or extended with zeros in place, keeping their
+    // mode and owner
+    std::fs::set_permissions(path("allowed/test.txt"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    let before = std::fs::metadata(path("allowed/test.txt")).unwrap();
+    let response = filesystem_handler.handle_truncate_file(Uuid::new_v4(), path("allowed/test.txt"), 4).await;
+    assert!(matches!(response, Some(Message::TruncateFileResponse { success: true, .. })), "{:?}", response);
+    assert_file_content(temp_dir.path().join("allowed/test.txt"), "test");
+    let response = filesystem_handler.handle_truncate_file(Uuid::new_v4(), path("allowed/test.txt"), 6).await;
+    assert!(matches!(response, Some(Message::TruncateFileResponse { success: true, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(path("allowed/test.txt")).unwrap(), b"test\0\0");
+    let after = std::fs::metadata(path("allowed/test.txt")).unwrap();
+    assert_eq!(after.mode() & 0o777, 0o640);
+    assert_eq!((after.ino(), after.uid(), after.gid()), (before.ino(), before.uid(), before.gid()));
+    
+    let response = filesystem_handler.handle_truncate_file(Uuid::new_v4(), path("allowed/missing.txt"), 0).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_truncate_file(Uuid::new_v4(), path("allowed/subdir1"), 0).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidPath, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_truncate_file(Uuid::new_v4(), path("readonly/readonly.txt"), 0).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+    assert_file_content(temp_dir.path().join("readonly/readonly.txt"), "readonly content");
+}
+
+#[tokio::test]
+async fn test_create_symlink() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    // The target is stored as given, even when it does not exist
+    for (link, target) in [("allowed/link", "test.txt"), ("allowed/dangling", "missing.txt")] {
+        let response = filesystem_handler.handle_create_symlink(Uuid::new_v4(), path(link), target.to_string()).await;
+        assert!(matches!(response, Some(Message::CreateSymlinkResponse { success: true, .. })), "{:?}", response);
+        assert_eq!(std::fs::read_link(path(link)).unwrap(), std::path::Path::new(target));
+    }
+    
+    let response = filesystem_handler.handle_create_symlink(Uuid::new_v4(), path("allowed/link"), "test.txt".to_string()).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_create_symlink(Uuid::new_v4(), path("readonly/link"), "readonly.txt".to_string()).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+    assert!(std::fs::symlink_metadata(path("readonly/link")).is_err());
+}
+
+#[tokio::test]
+async fn test_path_exists() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    for (p, expected) in [("allowed/test.txt", true), ("allowed/subdir1", true), ("allowed/missing.txt", false)] {
+        let response = filesystem_handler.handle_path_exists(Uuid::new_v4(), path(p)).await;
+        assert!(matches!(response, Some(Message::PathExistsResponse { exists, error: None, .. }) if exists == expected), "{}: {:?}", p, response);
+    }
+    
+    // Denied paths are not even confirmed to exist
+    let response = filesystem_handler.handle_path_exists(Uuid::new_v4(), path("denied/secret.txt")).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_write_delta() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    
+    let file = temp_dir.path().join("allowed/disk.txt");
+    let old: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(&file, &old).unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let path = file.to_string_lossy().to_string();
+    
+    let response = filesystem_handler.handle_get_file_signature(Uuid::new_v4(), path.clone(), 1024).await;
+    let signature = match response {
+        Some(Message::FileSignatureResponse { success: true, signature: Some(signature), .. }) => signature,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    assert_eq!((signature.length, signature.blocks.len()), (old.len() as u64, 64));
+    
+    let mut new = old.clone();
+    new[10_000..10_004].copy_from_slice(b"edit");
+    new.extend_from_slice(b"appended");
+    let ops = delta::diff(&signature, &new);
+    assert!(delta::literal_len(&ops) < 2048);
+    let checksum = Checksum::of(ChecksumAlgorithm::Blake3, &new).digest;
+    
+    // A digest that does not match leaves the file alone
+    let response = filesystem_handler.handle_write_delta(Uuid::new_v4(), path.clone(), 1024, ops.clone(), vec![0; 32], false).await;
+    assert!(matches!(response, Some(Message::WriteFileResponse { success: false, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(&file).unwrap(), old);
+    let leftovers = std::fs::read_dir(temp_dir.path().join("allowed")).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().ends_with(".remotefs-delta"))
+        .count();
+    assert_eq!(leftovers, 0);
+    
+    let response = filesystem_handler.handle_write_delta(Uuid::new_v4(), path.clone(), 1024, ops, checksum, true).await;
+    assert!(matches!(response, Some(Message::WriteFileResponse { success: true, bytes_written, .. }) if bytes_written == new.len() as u64), "{:?}", response);
+    assert_eq!(std::fs::read(&file).unwrap(), new);
+    assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o777, 0o640);
+    
+    // Blocks too small to be worth a signature are refused
+    let response = filesystem_handler.handle_get_file_signature(Uuid::new_v4(), path, 16).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidMessage, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_copy_file() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    std::fs::write(path("allowed/source.txt"), b"0123456789").unwrap();
+    
+    // The whole file replaces the destination
+    std::fs::write(path("allowed/copy.txt"), b"a much longer old file").unwrap();
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("allowed/copy.txt"), 0, None).await;
+    assert!(matches!(response, Some(Message::CopyFileResponse { success: true, copied: 10, metadata: Some(ref metadata), .. }) if metadata.size == 10), "{:?}", response);
+    assert_eq!(std::fs::read(path("allowed/copy.txt")).unwrap(), b"0123456789");
+    
+    // A range lands at the same offset, and stops at the end of the source
+    std::fs::write(path("allowed/range.txt"), b"abcdefghijkl").unwrap();
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("allowed/range.txt"), 8, Some(100)).await;
+    assert!(matches!(response, Some(Message::CopyFileResponse { success: true, copied: 2, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(path("allowed/range.txt")).unwrap(), b"abcdefgh89kl");
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("allowed/new.txt"), 2, Some(3)).await;
+    assert!(matches!(response, Some(Message::CopyFileResponse { success: true, copied: 3, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(path("allowed/new.txt")).unwrap(), b"\x00\x00234");
+    
+    // A file is never copied onto itself
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("allowed/source.txt"), 0, None).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidPath, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(path("allowed/source.txt")).unwrap(), b"0123456789");
+    
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("readonly/copy.txt"), 0, None).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+    assert!(!temp_dir.path().join("readonly/copy.txt").exists());
+    
+    // Nor is an existing file that may not be written
+    let original = std::fs::read(path("readonly/readonly.txt")).unwrap();
+    let response = filesystem_handler.handle_copy_file(Uuid::new_v4(), path("allowed/source.txt"), path("readonly/readonly.txt"), 0, Some(4)).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert_eq!(std::fs::read(path("readonly/readonly.txt")).unwrap(), original);
+}
+
+#[tokio::test]
+async fn test_rename_no_replace() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    std::fs::write(path("allowed/other.txt"), "other").unwrap();
+    
+    // Neither files nor directories are replaced, and both stay as they were
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/test.txt"), path("allowed/other.txt"), true).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })), "{:?}", response);
+    assert_file_content(temp_dir.path().join("allowed/test.txt"), "test content");
+    assert_file_content(temp_dir.path().join("allowed/other.txt"), "other");
+    std::fs::create_dir(path("allowed/empty")).unwrap();
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/empty"), path("allowed/subdir1"), true).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })), "{:?}", response);
+    assert_path_exists(temp_dir.path().join("allowed/subdir1/nested.txt"));
+    
+    // A free destination is renamed to as usual
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/other.txt"), path("allowed/new/other.txt"), true).await;
+    assert!(matches!(response, Some(Message::RenameResponse { success: true, .. })), "{:?}", response);
+    assert_file_content(temp_dir.path().join("allowed/new/other.txt"), "other");
+    
+    // Without the flag the destination is replaced
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/test.txt"), path("allowed/new/other.txt"), false).await;
+    assert!(matches!(response, Some(Message::RenameResponse { success: true, .. })), "{:?}", response);
+    assert_file_content(temp_dir.path().join("allowed/new/other.txt"), "test content");
+    assert_path_not_exists(temp_dir.path().join("allowed/test.txt"));
+}
+
+#[tokio::test]
+async fn test_change_journal_records_changes() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let journal = Arc::new(ChangeJournal::in_memory(100));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_journal(Arc::clone(&journal));
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/new.txt"), b"one".to_vec(), None, false).await;
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/new.txt"), b"two".to_vec(), Some(0), false).await;
+    filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/new.txt"), path("allowed/moved.txt"), false).await;
+    filesystem_handler.handle_delete_file(Uuid::new_v4(), path("allowed/moved.txt")).await;
+    // Rejected operations are not recorded
+    filesystem_handler.handle_delete_file(Uuid::new_v4(), path("readonly/readonly.txt")).await;
+    
+    let response = filesystem_handler.handle_get_changes(Uuid::new_v4(), 0, None).await.unwrap();
+    let changes = match response {
+        Message::GetChangesResponse { success: true, changes: Some(changes), .. } => changes,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    
+    let kinds: Vec<ChangeKind> = changes.changes.iter().map(|c| c.kind.clone()).collect();
+    assert_eq!(kinds, vec![
+        ChangeKind::Created,
+        ChangeKind::Modified,
+        ChangeKind::Renamed { from: path("allowed/new.txt") },
+        ChangeKind::Deleted,
+    ]);
+    assert_eq!(changes.changes[2].path, path("allowed/moved.txt"));
+    assert_eq!(changes.next_cursor, 4);
+    assert!(!changes.reset);
+    
+    // Polling from the returned cursor yields nothing new
+    let response = filesystem_handler.handle_get_changes(Uuid::new_v4(), 4, None).await.unwrap();
+    assert!(matches!(response, Message::GetChangesResponse { changes: Some(c), .. } if c.changes.is_empty()));
+}
+
+#[tokio::test]
+async fn test_get_changes_without_journal() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let response = filesystem_handler.handle_get_changes(Uuid::new_v4(), 0, None).await.unwrap();
+    assert!(matches!(response, Message::GetChangesResponse { success: false, .. }));
+}
+
+#[tokio::test]
+async fn test_read_file_as_of() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_journal(Arc::new(ChangeJournal::in_memory(100)));
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/a.txt"), b"first".to_vec(), None, false).await;
+    let after_write = chrono::Utc::now();
+    
+    // Unchanged since, so the current contents are the contents as of then
+    let response = filesystem_handler
+        .handle_read_file_as_of(Uuid::new_v4(), path("allowed/a.txt"), 0, u32::MAX, after_write)
+        .await
+        .unwrap();
+    assert!(matches!(response, Message::ReadFileResponse { success: true, data: Some(ref d), .. } if d == b"first"));
+    
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/a.txt"), b"second".to_vec(), Some(0), false).await;
+    let response = filesystem_handler
+        .handle_read_file_as_of(Uuid::new_v4(), path("allowed/a.txt"), 0, u32::MAX, after_write)
+        .await
+        .unwrap();
+    assert!(matches!(response, Message::Error { code: ErrorCode::FileNotFound, .. }), "{:?}", response);
+    
+    // Nor is a file changed on the host, which the journal never sees;
+    // file times are taken from a coarse clock, so leave a gap
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/b.txt"), b"agent".to_vec(), None, false).await;
+    let after_agent_write = chrono::Utc::now();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    std::fs::write(path("allowed/b.txt"), b"host").unwrap();
+    let response = filesystem_handler
+        .handle_read_file_as_of(Uuid::new_v4(), path("allowed/b.txt"), 0, u32::MAX, after_agent_write)
+        .await
+        .unwrap();
+    assert!(matches!(response, Message::Error { code: ErrorCode::FileNotFound, .. }), "{:?}", response);
+    
+    // Before the journal started
+    let response = filesystem_handler
+        .handle_read_file_as_of(Uuid::new_v4(), path("allowed/test.txt"), 0, u32::MAX, after_write - chrono::Duration::days(1))
+        .await
+        .unwrap();
+    assert!(matches!(response, Message::Error { code: ErrorCode::FileNotFound, .. }), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_read_backup_entry() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    std::fs::set_permissions(path("allowed/test.txt"), std::fs::Permissions::from_mode(0o640)).unwrap();
+    
+    let response = filesystem_handler.handle_read_backup_entry(Uuid::new_v4(), path("allowed/test.txt")).await.unwrap();
+    let entry = match response {
+        Message::ReadBackupEntryResponse { success: true, entry: Some(entry), .. } => entry,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    assert_eq!(entry.data, b"test content");
+    assert_eq!(entry.metadata.permissions & 0o777, 0o640);
+    // Real ownership, unlike GetMetadata
+    let owner = std::os::unix::fs::MetadataExt::uid(&std::fs::metadata(path("allowed/test.txt")).unwrap());
+    assert_eq!(entry.metadata.uid, owner);
+    
+    // Symlinks are captured as links
+    std::os::unix::fs::symlink("test.txt", path("allowed/link")).unwrap();
+    let response = filesystem_handler.handle_read_backup_entry(Uuid::new_v4(), path("allowed/link")).await.unwrap();
+    let entry = match response {
+        Message::ReadBackupEntryResponse { success: true, entry: Some(entry), .. } => entry,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    assert!(entry.metadata.is_symlink);
+    assert_eq!(entry.metadata.symlink_target.as_deref(), Some("test.txt"));
+    assert!(entry.data.is_empty());
+    
+    let response = filesystem_handler.handle_read_backup_entry(Uuid::new_v4(), path("denied/secret.txt")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_read_backup_entry(Uuid::new_v4(), path("allowed/missing.txt")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_list_directory_paged() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let dir = temp_dir.path().join("allowed/many");
+    std::fs::create_dir_all(&dir).unwrap();
+    for i in 0..7 {
+        std::fs::write(dir.join(format!("file{}.txt", i)), b"x").unwrap();
+    }
+    
+    let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+    let last = filesystem_handler
+        .handle_list_directory_paged(Uuid::new_v4(), dir.to_string_lossy().to_string(), 3, None, None, &pages_tx)
+        .await
+        .unwrap();
+    
+    let mut names = Vec::new();
+    let mut sequences = Vec::new();
+    while let Ok(Message::DirectoryPage { sequence, entries, last: false, error: None, .. }) = pages_rx.try_recv() {
+        assert_eq!(entries.len(), 3);
+        sequences.push(sequence);
+        names.extend(entries.into_iter().map(|entry| entry.name));
+    }
+    match last {
+        Message::DirectoryPage { sequence, entries, last: true, error: None, .. } => {
+            assert_eq!(entries.len(), 1);
+            sequences.push(sequence);
+            names.extend(entries.into_iter().map(|entry| entry.name));
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    assert_eq!(sequences, vec![0, 1, 2]);
+    names.sort();
+    assert_eq!(names.len(), 7);
+    assert_eq!(names[0], "file0.txt");
+    
+    // Errors end the stream with a single message
+    let denied = temp_dir.path().join("denied").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_list_directory_paged(Uuid::new_v4(), denied, 3, None, None, &pages_tx).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert!(pages_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_list_directory_paged_cursor() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let dir = temp_dir.path().join("allowed/cursor");
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in ["f", "B", "d", "b"] {
+        std::fs::write(dir.join(name), b"x").unwrap();
+    }
+    
+    let list_part = |after: Option<&str>, max_entries: Option<u32>| {
+        let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+        let filesystem_handler = &filesystem_handler;
+        let dir = dir.to_string_lossy().to_string();
+        let after = after.map(str::to_string);
+        async move {
+            let last = filesystem_handler
+                .handle_list_directory_paged(Uuid::new_v4(), dir, 2, after, max_entries, &pages_tx)
+                .await
+                .unwrap();
+            let mut pages = Vec::new();
+            while let Ok(page) = pages_rx.try_recv() {
+                pages.push(page);
+            }
+            pages.push(last);
+            
+            let mut names = Vec::new();
+            let mut next = None;
+            for page in pages {
+                match page {
+                    Message::DirectoryPage { entries, error: None, cursor, .. } => {
+                        names.extend(entries.into_iter().map(|entry| entry.name));
+                        next = cursor;
+                    }
+                    other => panic!("Unexpected response: {:?}", other),
+                }
+            }
+            (names, next)
+        }
+    };
+    let list = |after| async move { list_part(after, None).await.0 };
+    
+    // Sorted by the bytes of the names, across pages
+    assert_eq!(list(None).await, ["B", "b", "d", "f"]);
+    assert_eq!(list(Some("b")).await, ["d", "f"]);
+    
+    // Listings stopped early say where to continue
+    assert_eq!(list_part(None, Some(3)).await, (vec!["B".to_string(), "b".to_string(), "d".to_string()], Some("d".to_string())));
+    assert_eq!(list_part(Some("d"), Some(3)).await, (vec!["f".to_string()], None));
+    assert_eq!(list_part(Some("b"), Some(2)).await, (vec!["d".to_string(), "f".to_string()], None));
+    
+    // Resuming after changes: entries before the cursor are skipped, later
+    // ones included, and the cursor itself need not exist any more
+    std::fs::write(dir.join("a"), b"x").unwrap();
+    std::fs::write(dir.join("e"), b"x").unwrap();
+    std::fs::remove_file(dir.join("d")).unwrap();
+    std::fs::remove_file(dir.join("f")).unwrap();
+    assert_eq!(list(Some("d")).await, ["e"]);
+    assert!(list(Some("z")).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_walk_directory() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let tree = temp_dir.path().join("allowed/tree");
+    std::fs::create_dir_all(tree.join("a/b")).unwrap();
+    std::fs::write(tree.join("a/b/c.txt"), b"c").unwrap();
+    std::fs::write(tree.join("top.txt"), b"top").unwrap();
+    std::os::unix::fs::symlink(tree.join("a"), tree.join("a/loop")).unwrap();
+    std::os::unix::fs::symlink(temp_dir.path().join("denied"), tree.join("secret")).unwrap();
+    
+    let walk = |max_depth, follow_symlinks| {
+        let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+        let filesystem_handler = &filesystem_handler;
+        let tree = tree.to_string_lossy().to_string();
+        async move {
+            let last = filesystem_handler
+                .handle_walk_directory(Uuid::new_v4(), tree, max_depth, follow_symlinks, &pages_tx)
+                .await
+                .unwrap();
+            let mut pages = Vec::new();
+            while let Ok(page) = pages_rx.try_recv() {
+                pages.push(page);
+            }
+            pages.push(last);
+            
+            let mut names = Vec::new();
+            for (index, page) in pages.into_iter().enumerate() {
+                match page {
+                    Message::DirectoryPage { sequence, entries, error: None, .. } => {
+                        assert_eq!(sequence as usize, index);
+                        names.extend(entries.into_iter().map(|entry| entry.name));
+                    }
+                    other => panic!("Unexpected response: {:?}", other),
+                }
+            }
+            names.sort();
+            names
+        }
+    };
+    
+    // Links are listed, but the loop is never entered and the denied
+    // directory not even when following them
+    let everything = ["a", "a/b", "a/b/c.txt", "a/loop", "secret", "top.txt"];
+    assert_eq!(walk(None, false).await, everything);
+    assert_eq!(walk(None, true).await, everything);
+    assert_eq!(walk(Some(1), false).await, ["a", "secret", "top.txt"]);
+    assert_eq!(walk(Some(2), false).await, ["a", "a/b", "a/loop", "secret", "top.txt"]);
+    
+    // Large trees arrive in several pages
+    std::fs::create_dir(tree.join("a/many")).unwrap();
+    for i in 0..1000 {
+        std::fs::write(tree.join(format!("a/many/{}.txt", i)), b"x").unwrap();
+    }
+    let names = walk(None, false).await;
+    assert_eq!(names.len(), everything.len() + 1001);
+    assert!(names.contains(&"a/many/999.txt".to_string()));
+    
+    // Errors end the stream with a single message
+    let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+    let denied = temp_dir.path().join("denied").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_walk_directory(Uuid::new_v4(), denied, None, false, &pages_tx).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert!(pages_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_search_files() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let allowed = temp_dir.path().join("allowed");
+    std::os::unix::fs::symlink(temp_dir.path().join("denied"), allowed.join("secret")).unwrap();
+    std::fs::write(allowed.join("subdir2/binary.dat"), b"fake\0content").unwrap();
+    
+    let search = |name_glob: Option<&str>, content_regex: Option<&str>, max_results| {
+        let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+        let filesystem_handler = &filesystem_handler;
+        let root = allowed.to_string_lossy().to_string();
+        let (name_glob, content_regex) = (name_glob.map(str::to_string), content_regex.map(str::to_string));
+        async move {
+            let last = filesystem_handler
+                .handle_search_files(Uuid::new_v4(), root, name_glob, content_regex, max_results, &pages_tx)
+                .await
+                .unwrap();
+            let mut pages = Vec::new();
+            while let Ok(page) = pages_rx.try_recv() {
+                pages.push(page);
+            }
+            pages.push(last);
+            
+            let (mut found, mut truncated) = (Vec::new(), false);
+            for (index, page) in pages.into_iter().enumerate() {
+                match page {
+                    Message::SearchResults { sequence, matches, truncated: stopped, error: None, .. } => {
+                        assert_eq!(sequence as usize, index);
+                        found.extend(matches.into_iter().map(|found| match found.line_number {
+                            Some(line_number) => format!("{}:{}:{}", found.path, line_number, found.line.unwrap()),
+                            None => found.path,
+                        }));
+                        truncated = stopped;
+                    }
+                    other => panic!("Unexpected response: {:?}", other),
+                }
+            }
+            found.sort();
+            (found, truncated)
+        }
+    };
+    
+    // Denied files and the linked denied directory are left out
+    assert_eq!(search(Some("*.txt"), None, None).await, (vec!["subdir1/nested.txt".to_string(), "test.txt".to_string()], false));
+    assert_eq!(search(Some("subdir1/*"), None, None).await.0, ["subdir1/nested.txt"]);
+    assert_eq!(search(None, Some("^fake"), None).await.0, ["image.jpg:1:fake jpg"]);
+    assert_eq!(search(None, Some("content"), None).await.0, [
+        "document.pdf:1:pdf content",
+        "subdir1/nested.txt:1:nested content",
+        "test.txt:1:test content",
+    ]);
+    
+    // The search stops at its limit
+    let (found, truncated) = search(None, Some("content"), Some(2)).await;
+    assert_eq!((found.len(), truncated), (2, true));
+    
+    // Many matches arrive in several pages
+    std::fs::create_dir(allowed.join("many")).unwrap();
+    for i in 0..1000 {
+        std::fs::write(allowed.join(format!("many/{}.log", i)), b"x").unwrap();
+    }
+    assert_eq!(search(Some("*.log"), None, None).await.0.len(), 1000);
+    
+    // Bad patterns and denied roots end the stream with a single message
+    let (pages_tx, mut pages_rx) = tokio::sync::mpsc::unbounded_channel();
+    let root = allowed.to_string_lossy().to_string();
+    let response = filesystem_handler
+        .handle_search_files(Uuid::new_v4(), root, None, Some("(unclosed".to_string()), None, &pages_tx)
+        .await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidMessage, .. })), "{:?}", response);
+    let denied = temp_dir.path().join("denied").to_string_lossy().to_string();
+    let response = filesystem_handler
+        .handle_search_files(Uuid::new_v4(), denied, Some("*".to_string()), None, None, &pages_tx)
+        .await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert!(pages_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_read_file_stream() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = Arc::new(FilesystemHandler::new(access_control, &config.performance));
+    let file_path = temp_dir.path().join("allowed/stream.bin");
+    let content: Vec<u8> = (0..40).collect();
+    std::fs::write(&file_path, &content).unwrap();
+    let file_path = file_path.to_string_lossy().to_string();
+    
+    // Ten chunks of four bytes, of which only a window's worth are sent
+    // before the reader acknowledges them
+    let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+    let request_id = Uuid::new_v4();
+    let response = filesystem_handler.handle_read_file_stream(request_id, file_path.clone(), 0, None, 4, &chunks_tx).await;
+    assert!(response.is_none(), "{:?}", response);
+    
+    let mut received = Vec::new();
+    let mut unacknowledged = 0;
+    loop {
+        let chunk = tokio::time::timeout(std::time::Duration::from_millis(200), chunks_rx.recv()).await;
+        let Ok(Some(Message::ReadFileChunk { sequence, data, last, error: None, .. })) = chunk else {
+            // Stalled on a full window
+            assert_eq!(unacknowledged, remotefs_agent::streams::STREAM_WINDOW);
+            for _ in 0..unacknowledged {
+                filesystem_handler.handle_read_file_ack(request_id);
+            }
+            unacknowledged = 0;
+            continue;
+        };
+        assert_eq!(sequence as usize, received.len() / 4);
+        received.extend(data);
+        unacknowledged += 1;
+        if last {
+            break;
+        }
+    }
+    assert_eq!(received, content);
+    
+    // A range of the file, in one chunk
+    let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+    filesystem_handler.handle_read_file_stream(Uuid::new_v4(), file_path, 10, Some(5), 1024, &chunks_tx).await;
+    match chunks_rx.recv().await {
+        Some(Message::ReadFileChunk { sequence: 0, offset: 10, data, last: true, error: None, .. }) => assert_eq!(data, &content[10..15]),
+        other => panic!("Unexpected chunk: {:?}", other),
+    }
+    assert!(filesystem_handler.handle_read_file_ack(request_id).is_none());
+    
+    // Missing files are refused before the stream starts
+    let missing = temp_dir.path().join("allowed/missing.bin").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_read_file_stream(Uuid::new_v4(), missing, 0, None, 4, &chunks_tx).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_write_file_chunks() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let file_path = temp_dir.path().join("allowed/test.txt");
+    let path = file_path.to_string_lossy().to_string();
+    let write = |sequence, offset, data: &[u8], last| {
+        filesystem_handler.handle_write_file_chunk(Uuid::new_v4(), path.clone(), sequence, offset, data.to_vec(), last, last)
+    };
+    
+    // The first chunk replaces the existing content, and later ones may
+    // arrive in any order
+    assert!(matches!(write(0, 0, b"hello", false).await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert_file_content(&file_path, "hello");
+    assert!(matches!(write(2, 11, b"!", false).await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert!(matches!(write(1, 5, b" world", true).await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert_file_content(&file_path, "hello world!");
     
     let stats = filesystem_handler.get_statistics().await;
-    assert_eq!(stats.total_operations, 1);
+    assert_eq!(stats.bytes_written, 12);
+    
+    // Only the first chunk creates a file
+    let missing = temp_dir.path().join("allowed/new/upload.bin").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_write_file_chunk(Uuid::new_v4(), missing.clone(), 1, 4, b"data".to_vec(), true, false).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_write_file_chunk(Uuid::new_v4(), missing.clone(), 0, 0, Vec::new(), true, false).await;
+    assert!(matches!(response, Some(Message::WriteFileResponse { success: true, .. })), "{:?}", response);
+    assert_file_content(&missing, "");
+    
+    // Read-only paths are refused
+    let readonly = temp_dir.path().join("readonly/readonly.txt").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_write_file_chunk(Uuid::new_v4(), readonly, 0, 0, b"x".to_vec(), true, false).await;
+    assert!(!matches!(response, Some(Message::WriteFileResponse { success: true, .. })), "{:?}", response);
 }
 
 #[tokio::test]
-async fn test_create_directory_recursive() {
+async fn test_resource_limits() {
     setup_test_logging();
     let temp_dir = create_temp_dir();
     create_test_directory_structure(temp_dir.path());
     let config = create_test_config(temp_dir.path());
     let access_control = create_test_access_control(&config.access);
     
-    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
-    let request_id = Uuid::new_v4();
-    let nested_dir_path = temp_dir.path().join("allowed/deep/nested/directory").to_string_lossy().to_string();
-    
-    let result = filesystem_handler.handle_create_directory(request_id, nested_dir_path.clone(), 0o755).await;
-    
-    assert!(result.is_some(), "Should return a response");
+    let limits = Arc::new(ResourceLimits::new(&ResourceLimitsConfig {
+        max_buffer_mb: 1,
+        max_open_files: 1,
+        max_response_mb: 1,
+        max_listing_entries: 3,
+        ..ResourceLimitsConfig::default()
+    }));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_limits(Arc::clone(&limits));
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    std::fs::write(path("allowed/large.bin"), vec![7u8; 2 * 1024 * 1024]).unwrap();
+    
+    // Too large to answer at once, but fine in ranges
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/large.bin"), None, None).await;
+    match response {
+        Some(Message::Error { code: ErrorCode::MessageTooLarge, details: Some(details), .. }) => {
+            assert_eq!(details.get("limit").map(String::as_str), Some("response_bytes"));
+            assert_eq!(details.get("max").map(String::as_str), Some("1048576"));
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/large.bin"), Some(0), Some(65536)).await;
+    assert!(matches!(response, Some(Message::ReadFileResponse { success: true, bytes_read: 65536, .. })));
+    
+    // Whole-file reads of small files only reserve what they read
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/test.txt"), Some(0), Some(u32::MAX as u64)).await;
+    assert!(matches!(response, Some(Message::ReadFileResponse { success: true, .. })));
+    
+    // Directories with more entries than a listing may hold must be paged
+    let response = filesystem_handler.handle_list_directory(Uuid::new_v4(), path("allowed")).await;
+    match response {
+        Some(Message::Error { code: ErrorCode::MessageTooLarge, details: Some(details), .. }) => {
+            assert_eq!(details.get("limit").map(String::as_str), Some("listing_entries"));
+            assert_eq!(details.get("max").map(String::as_str), Some("3"));
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    let response = filesystem_handler.handle_list_directory(Uuid::new_v4(), path("allowed/subdir1")).await;
+    assert!(matches!(response, Some(Message::ListDirectoryResponse { success: true, .. })));
+    
+    // Shed with a retriable error while another request holds the only file slot
+    let held = limits.acquire(0).unwrap();
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/test.txt"), None, None).await;
+    match response {
+        Some(Message::Error { code: ErrorCode::ServiceUnavailable, details: Some(details), .. }) => {
+            assert_eq!(details.get("resource").map(String::as_str), Some("open_files"));
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
+    let response = filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/new.txt"), b"data".to_vec(), None, false).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::ServiceUnavailable, .. })));
+    drop(held);
+    
+    let stats = filesystem_handler.get_resource_statistics();
+    assert_eq!(stats.open_files, 0);
+    assert_eq!(stats.buffered_bytes, 0);
+    assert_eq!(stats.shed_requests, 2);
+    assert_eq!(stats.oversized_responses, 2);
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
     
-    // Verify nested directories were created
-    assert_path_exists(&nested_dir_path);
-    assert!(std::path::Path::new(&nested_dir_path).is_dir());
+    let rate_limits = Arc::new(RateLimiter::new(&RateLimitConfig {
+        per_client: RateLimit { ops_per_sec: 0, bytes_per_sec: 10 },
+        ..RateLimitConfig::default()
+    }));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_rate_limits(rate_limits);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let read = |path: String| Message::ReadFile { request_id: Uuid::new_v4(), path, offset: 0, length: u32::MAX };
+    
+    // A read is admitted, then counted against the client's byte rate
+    let greedy = filesystem_handler.for_client("greedy".to_string());
+    assert!(greedy.check_request(&read(path("allowed/test.txt"))).await.is_none());
+    let response = greedy.handle_read_file(Uuid::new_v4(), path("allowed/test.txt"), None, None).await;
+    assert!(matches!(response, Some(Message::ReadFileResponse { success: true, .. })));
+    match greedy.check_request(&read(path("allowed/test.txt"))).await {
+        Some(Message::Error { code: ErrorCode::ServiceUnavailable, details: Some(details), .. }) => {
+            assert_eq!(details.get("limit").map(String::as_str), Some("bytes_per_sec"));
+            assert_eq!(details.get("max").map(String::as_str), Some("10"));
+            assert!(details.get("retry_after_ms").unwrap().parse::<u64>().unwrap() > 0);
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
     
-    let stats = filesystem_handler.get_statistics().await;
-    assert_eq!(stats.total_operations, 1);
+    // Other clients, and heartbeats, are not held back
+    let other = filesystem_handler.for_client("other".to_string());
+    assert!(other.check_request(&read(path("allowed/test.txt"))).await.is_none());
+    let ping = Message::Ping { timestamp: chrono::Utc::now() };
+    assert!(greedy.check_request(&ping).await.is_none());
+    assert_eq!(filesystem_handler.get_resource_statistics().throttled_requests, 1);
 }
 
 #[tokio::test]
-async fn test_delete_file_success() {
+async fn test_quotas() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    // The 76 bytes of files already there count
+    let quotas = Arc::new(QuotaTable::new(&[PathQuota { path: path("allowed"), max_bytes: 200 }]));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_quotas(quotas);
+    let write = |name: &str, size: usize| filesystem_handler.handle_write_file(Uuid::new_v4(), path(name), vec![1; size], None, false);
+    
+    assert!(matches!(write("allowed/a.bin", 100).await, Some(Message::WriteFileResponse { success: true, .. })));
+    let response = write("allowed/b.bin", 30).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::QuotaExceeded, errno: Some(libc::EDQUOT), .. })), "{:?}", response);
+    assert!(!temp_dir.path().join("allowed/b.bin").exists());
+    
+    // Rewriting a file in place takes no more space; paths outside the quota are not limited
+    assert!(matches!(write("allowed/a.bin", 100).await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert!(matches!(write("temp/big.bin", 1000).await, Some(Message::WriteFileResponse { success: true, .. })));
+    
+    // Deletes free space, and moves in count
+    filesystem_handler.handle_delete_file(Uuid::new_v4(), path("allowed/a.bin")).await;
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("temp/big.bin"), path("allowed/big.bin"), false).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::QuotaExceeded, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_move_file(Uuid::new_v4(), path("temp/temp.txt"), path("allowed/temp.txt"), false).await;
+    assert!(matches!(response, Some(Message::RenameResponse { success: true, .. })));
+    
+    let Some(Message::GetSpaceInfoResponse { total_space, used_space, available_space, .. }) =
+        filesystem_handler.handle_get_space_info(Uuid::new_v4(), path("allowed/subdir1")).await else {
+        panic!("no space info");
+    };
+    assert_eq!((total_space, used_space, available_space), (Some(200), Some(88), Some(112)));
+}
+
+#[tokio::test]
+async fn test_trash() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = Arc::new(AccessControl::new(&config.access).with_trash(".remotefs-trash"));
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    let trash = Trash::from_config(&TrashConfig { enabled: true, ..TrashConfig::default() }).unwrap();
+    let quotas = Arc::new(QuotaTable::new(&[PathQuota { path: path("allowed/subdir1"), max_bytes: 100 }]));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_trash(Arc::new(trash))
+        .with_quotas(Arc::clone(&quotas));
+    
+    // Deleted files go to the trash of their allowed path, freeing quotas it is outside of
+    let response = filesystem_handler.handle_delete_file(Uuid::new_v4(), path("allowed/subdir1/nested.txt")).await;
+    assert!(matches!(response, Some(Message::DeleteFileResponse { success: true, .. })), "{:?}", response);
+    assert!(!temp_dir.path().join("allowed/subdir1/nested.txt").exists());
+    assert!(temp_dir.path().join("allowed/.remotefs-trash/files").read_dir().unwrap().next().is_some());
+    assert_eq!(quotas.space(&temp_dir.path().join("allowed/subdir1")), Some((0, 100)));
+    
+    let response = filesystem_handler.handle_restore_from_trash(Uuid::new_v4(), path("allowed/subdir1/nested.txt")).await;
+    assert!(matches!(response, Some(Message::RestoreFromTrashResponse { success: true, .. })), "{:?}", response);
+    assert_eq!(std::fs::read_to_string(path("allowed/subdir1/nested.txt")).unwrap(), "nested content");
+    assert_eq!(quotas.space(&temp_dir.path().join("allowed/subdir1")), Some((14, 100)));
+    
+    // Nothing left to restore
+    let response = filesystem_handler.handle_restore_from_trash(Uuid::new_v4(), path("allowed/subdir1/nested.txt")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })), "{:?}", response);
+    
+    // Directories go too, and purging removes them for good
+    let response = filesystem_handler.handle_delete_directory(Uuid::new_v4(), path("allowed/subdir2"), false).await;
+    assert!(matches!(response, Some(Message::RemoveDirectoryResponse { success: true, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_delete_directory(Uuid::new_v4(), path("allowed/subdir1"), false).await;
+    assert!(matches!(response, Some(Message::Error { .. })), "{:?}", response);
+    filesystem_handler.handle_delete_directory(Uuid::new_v4(), path("allowed/subdir1"), true).await;
+    filesystem_handler.handle_delete_file(Uuid::new_v4(), path("allowed/test.txt")).await;
+    
+    let response = filesystem_handler.handle_purge_trash(Uuid::new_v4(), path("allowed/subdir1")).await;
+    assert!(matches!(response, Some(Message::PurgeTrashResponse { success: true, purged: 1, .. })), "{:?}", response);
+    assert_eq!(filesystem_handler.purge_expired_trash().await, 0);
+    let response = filesystem_handler.handle_restore_from_trash(Uuid::new_v4(), path("allowed/test.txt")).await;
+    assert!(matches!(response, Some(Message::RestoreFromTrashResponse { success: true, .. })), "{:?}", response);
+    
+    // The trash itself is out of reach
+    let response = filesystem_handler.handle_delete_directory(Uuid::new_v4(), path("allowed/.remotefs-trash"), true).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    let response = filesystem_handler.handle_list_directory(Uuid::new_v4(), path("allowed/.remotefs-trash/files")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    assert!(temp_dir.path().join("allowed/.remotefs-trash").exists());
+}
+
+#[tokio::test]
+async fn test_offline_files() {
     setup_test_logging();
     let temp_dir = create_temp_dir();
     create_test_directory_structure(temp_dir.path());
     let config = create_test_config(temp_dir.path());
     let access_control = create_test_access_control(&config.access);
     
-    // Create a file to delete
-    let file_to_delete = temp_dir.path().join("allowed/delete_me.txt");
-    create_test_file(&file_to_delete, "delete me");
-    assert_path_exists(&file_to_delete);
+    let archive = ArchiveConfig { enabled: true, ..ArchiveConfig::default() };
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_archive(Arc::new(ArchiveHooks::new(archive)));
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    std::fs::write(path("allowed/test.txt.offline"), b"").unwrap();
+    
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/test.txt"), None, None).await.unwrap();
+    match response {
+        Message::Error { code: ErrorCode::FileOffline, details: Some(details), .. } => {
+            assert_eq!(details.get("recall").map(String::as_str), Some("unavailable"));
+        }
+        other => panic!("Unexpected response: {:?}", other),
+    }
     
-    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
-    let request_id = Uuid::new_v4();
-    let file_path = file_to_delete.to_string_lossy().to_string();
+    let response = filesystem_handler.handle_get_metadata(Uuid::new_v4(), path("allowed/test.txt"), true).await;
+    assert!(matches!(response, Some(Message::GetMetadataResponse { metadata: Some(FileMetadata { offline: true, .. }), .. })));
+    
+    // Markers are not listed
+    let response = filesystem_handler.handle_list_directory(Uuid::new_v4(), path("allowed")).await.unwrap();
+    let entries = match response {
+        Message::ListDirectoryResponse { entries: Some(entries), .. } => entries,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    assert!(entries.iter().all(|e| !e.name.ends_with(".offline")));
+    assert!(entries.iter().any(|e| e.name == "test.txt" && e.metadata.offline));
+    
+    // Back online once the archiver removes the marker
+    std::fs::remove_file(path("allowed/test.txt.offline")).unwrap();
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), path("allowed/test.txt"), None, None).await.unwrap();
+    assert!(matches!(response, Message::ReadFileResponse { success: true, .. }));
+}
+
+#[tokio::test]
+async fn test_mirror_refuses_writes_until_promoted() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
     
-    let result = filesystem_handler.handle_delete_file(request_id, file_path).await;
+    let mirror = Arc::new(MirrorState::new());
+    let access_control = Arc::new(AccessControl::new(&config.access).with_mirror(Arc::clone(&mirror)));
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance)
+        .with_mirror(Arc::clone(&mirror));
+    let path = temp_dir.path().join("allowed/new.txt").to_string_lossy().to_string();
+    let write = || filesystem_handler.handle_write_file(Uuid::new_v4(), path.clone(), b"data".to_vec(), None, false);
     
-    assert!(result.is_some(), "Should return a response");
+    assert!(matches!(write().await, Some(Message::Error { code: ErrorCode::AccessDenied, .. })));
+    let response = filesystem_handler.handle_read_file(Uuid::new_v4(), temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string(), None, None).await;
+    assert!(matches!(response, Some(Message::ReadFileResponse { success: true, .. })));
     
-    // Verify file was deleted
-    assert_path_not_exists(&file_to_delete);
+    // Promoted for reads only
+    filesystem_handler.handle_mirror_status("primary", true, false).await;
+    assert!(matches!(write().await, Some(Message::Error { code: ErrorCode::AccessDenied, .. })));
     
-    let stats = filesystem_handler.get_statistics().await;
-    assert_eq!(stats.total_operations, 1);
+    filesystem_handler.handle_mirror_status("primary", true, true).await;
+    assert!(matches!(write().await, Some(Message::WriteFileResponse { success: true, .. })));
+    
+    filesystem_handler.handle_mirror_status("primary", false, false).await;
+    assert!(matches!(write().await, Some(Message::Error { code: ErrorCode::AccessDenied, .. })));
 }
 
 #[tokio::test]
@@ -414,7 +1731,7 @@ async fn test_concurrent_filesystem_operations() {
     let file_path = temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string();
     
     // Run multiple concurrent read operations
-    let tasks: Vec<_> = (0..5).map(|i| {
+    let tasks: Vec<_> = (0..5).map(|_i| {
         let handler = filesystem_handler.clone();
         let path = file_path.clone();
         tokio::spawn(async move {
@@ -447,7 +1764,7 @@ async fn test_filesystem_performance_stats() {
     let file_path = temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string();
     
     // Perform several operations
-    for i in 0..3 {
+    for _i in 0..3 {
         let request_id = Uuid::new_v4();
         filesystem_handler.handle_read_file(request_id, file_path.clone(), None, None).await;
     }
@@ -478,3 +1795,686 @@ async fn test_filesystem_cleanup_operations() {
     filesystem_handler.cleanup_old_metrics().await;
     // Should complete without error
 }
+
+#[tokio::test]
+async fn test_transaction() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    
+    // Replace a file through a temporary and drop its lock file
+    std::fs::write(path("allowed/app.lock"), b"").unwrap();
+    let operations = vec![
+        TransactionOp::WriteFile { path: path("allowed/test.txt.tmp"), data: b"updated".to_vec() },
+        TransactionOp::Rename { from_path: path("allowed/test.txt.tmp"), to_path: path("allowed/test.txt") },
+        TransactionOp::DeleteFile { path: path("allowed/app.lock") },
+    ];
+    let response = filesystem_handler.handle_transaction(Uuid::new_v4(), operations).await;
+    assert!(matches!(response, Some(Message::TransactionResponse { success: true, .. })));
+    assert_eq!(std::fs::read(path("allowed/test.txt")).unwrap(), b"updated");
+    assert!(!std::path::Path::new(&path("allowed/app.lock")).exists());
+    
+    // A failing step undoes the ones before it
+    let operations = vec![
+        TransactionOp::WriteFile { path: path("allowed/test.txt"), data: b"lost".to_vec() },
+        TransactionOp::CreateDirectory { path: path("allowed/new") },
+        TransactionOp::DeleteFile { path: path("allowed/missing.txt") },
+    ];
+    let response = filesystem_handler.handle_transaction(Uuid::new_v4(), operations).await;
+    assert!(matches!(response, Some(Message::TransactionResponse { success: false, failed_operation: Some(2), .. })));
+    assert_eq!(std::fs::read(path("allowed/test.txt")).unwrap(), b"updated");
+    assert!(!std::path::Path::new(&path("allowed/new")).exists());
+    
+    // Access is checked before anything is changed
+    let operations = vec![
+        TransactionOp::WriteFile { path: path("allowed/test.txt"), data: b"lost".to_vec() },
+        TransactionOp::DeleteFile { path: path("denied/secret.txt") },
+    ];
+    let response = filesystem_handler.handle_transaction(Uuid::new_v4(), operations).await;
+    assert!(matches!(response, Some(Message::TransactionResponse { success: false, failed_operation: Some(1), .. })));
+    assert_eq!(std::fs::read(path("allowed/test.txt")).unwrap(), b"updated");
+    
+    let names: Vec<_> = std::fs::read_dir(path("allowed")).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(names.iter().all(|name| !name.starts_with(".remotefs-txn-")), "left behind: {:?}", names);
+}
+
+#[tokio::test]
+async fn test_xattrs() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let file = temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string();
+    let name = "user.remotefs.tag".to_string();
+    
+    let set = |mode| filesystem_handler.handle_set_xattr(Uuid::new_v4(), file.clone(), name.clone(), b"red".to_vec(), mode);
+    match set(XattrSetMode::Create).await {
+        Some(Message::SetXattrResponse { success: true, .. }) => {}
+        // The filesystem under the temp dir has no user attributes
+        Some(Message::Error { code: ErrorCode::NotImplemented, .. }) => return,
+        response => panic!("Unexpected response: {:?}", response),
+    }
+    assert!(matches!(
+        set(XattrSetMode::Create).await,
+        Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })
+    ));
+    
+    let response = filesystem_handler.handle_get_xattr(Uuid::new_v4(), file.clone(), name.clone()).await;
+    assert!(matches!(response, Some(Message::GetXattrResponse { value: Some(ref value), .. }) if value == b"red"));
+    let response = filesystem_handler.handle_list_xattr(Uuid::new_v4(), file.clone()).await;
+    assert!(matches!(response, Some(Message::ListXattrResponse { names: Some(ref names), .. }) if names.contains(&name)));
+    
+    let response = filesystem_handler.handle_remove_xattr(Uuid::new_v4(), file.clone(), name.clone()).await;
+    assert!(matches!(response, Some(Message::RemoveXattrResponse { success: true, .. })));
+    let response = filesystem_handler.handle_get_xattr(Uuid::new_v4(), file.clone(), name.clone()).await;
+    assert!(matches!(response, Some(Message::GetXattrResponse { success: true, value: None, .. })));
+    assert!(matches!(
+        set(XattrSetMode::Replace).await,
+        Some(Message::Error { code: ErrorCode::FileNotFound, .. })
+    ));
+    
+    // Attributes of denied paths are neither read nor written
+    let denied = temp_dir.path().join("denied/secret.txt").to_string_lossy().to_string();
+    let response = filesystem_handler.handle_set_xattr(Uuid::new_v4(), denied, name, b"red".to_vec(), XattrSetMode::Upsert).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+}
+
+#[tokio::test]
+async fn test_batch_create_files() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let file = |p: &str, data: &[u8]| NewFile { path: path(p), data: data.to_vec(), mode: None };
+    
+    let files = vec![
+        file("allowed/pkg/index.js", b"module.exports = 1;"),
+        NewFile { mode: Some(0o600), ..file("allowed/pkg/lib/util.js", b"") },
+        file("allowed/test.txt", b"replaced"),
+        file("denied/pkg.json", b"{}"),
+    ];
+    let response = filesystem_handler.handle_batch_create_files(Uuid::new_v4(), files, false).await;
+    let Some(Message::BatchCreateFilesResponse { created, failures, error: None, .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    // Existing and denied files fail on their own
+    assert_eq!(created, 2);
+    assert_eq!(failures.iter().map(|failure| failure.index).collect::<Vec<_>>(), [2, 3]);
+    assert_eq!(std::fs::read(path("allowed/pkg/index.js")).unwrap(), b"module.exports = 1;");
+    let mode = std::fs::metadata(path("allowed/pkg/lib/util.js")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_ne!(std::fs::read(path("allowed/test.txt")).unwrap(), b"replaced");
+    
+    let files = vec![file("allowed/test.txt", b"replaced")];
+    let response = filesystem_handler.handle_batch_create_files(Uuid::new_v4(), files, true).await;
+    assert!(matches!(
+        response,
+        Some(Message::BatchCreateFilesResponse { created: 1, ref failures, .. }) if failures.is_empty()
+    ));
+    assert_eq!(std::fs::read(path("allowed/test.txt")).unwrap(), b"replaced");
+    
+    let files = (0..=remotefs_common::protocol::MAX_BATCH_FILES)
+        .map(|n| file(&format!("allowed/many/{}.txt", n), b""))
+        .collect();
+    let response = filesystem_handler.handle_batch_create_files(Uuid::new_v4(), files, false).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::InvalidMessage, .. })), "{:?}", response);
+    assert!(!std::path::Path::new(&path("allowed/many")).exists());
+}
+
+#[tokio::test]
+async fn test_compute_checksum() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let data: Vec<u8> = (0..3 * 1024 * 1024 + 5).map(|i| (i % 251) as u8).collect();
+    std::fs::write(path("allowed/large.bin"), &data).unwrap();
+    
+    for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] {
+        let response = filesystem_handler
+            .handle_compute_checksum(Uuid::new_v4(), path("allowed/large.bin"), algorithm, 0, None)
+            .await;
+        let Some(Message::ChecksumResponse { success: true, digest: Some(digest), length, .. }) = response else {
+            panic!("Unexpected response: {:?}", response);
+        };
+        assert_eq!(Checksum { algorithm, digest, length }, Checksum::of(algorithm, &data));
+    }
+    
+    // A range past the end of the file is cut short
+    let response = filesystem_handler
+        .handle_compute_checksum(Uuid::new_v4(), path("allowed/large.bin"), ChecksumAlgorithm::Sha256, 1000, Some(10_000_000))
+        .await;
+    let Some(Message::ChecksumResponse { success: true, digest: Some(digest), length, .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(length, data.len() as u64 - 1000);
+    assert_eq!(digest, Checksum::of(ChecksumAlgorithm::Sha256, &data[1000..]).digest);
+    
+    let response = filesystem_handler
+        .handle_compute_checksum(Uuid::new_v4(), path("allowed/large.bin"), ChecksumAlgorithm::Blake3, 10, Some(0))
+        .await;
+    assert!(matches!(
+        response,
+        Some(Message::ChecksumResponse { success: true, digest: Some(ref digest), length: 0, .. })
+            if *digest == Checksum::of(ChecksumAlgorithm::Blake3, b"").digest
+    ));
+    
+    for refused in ["allowed/missing.txt", "allowed/subdir1", "denied/secret.txt"] {
+        let response = filesystem_handler
+            .handle_compute_checksum(Uuid::new_v4(), path(refused), ChecksumAlgorithm::Sha256, 0, None)
+            .await;
+        assert!(matches!(response, Some(Message::Error { .. })), "{}", refused);
+    }
+}
+
+#[tokio::test]
+async fn test_created_modes_ignore_agent_umask() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let mode = |p: &str| std::fs::metadata(path(p)).unwrap().permissions().mode() & 0o7777;
+    
+    // Group and other write bits survive the usual 022 umask
+    let response = filesystem_handler.handle_create_file(Uuid::new_v4(), path("allowed/shared.txt"), 0o664, true).await;
+    let Some(Message::CreateFileResponse { success: true, metadata: Some(metadata), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(metadata.permissions & 0o7777, 0o664);
+    assert_eq!(mode("allowed/shared.txt"), 0o664);
+    
+    let response = filesystem_handler.handle_create_file(Uuid::new_v4(), path("allowed/shared.txt"), 0o600, true).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })));
+    
+    // An existing file keeps its mode and contents
+    let response = filesystem_handler.handle_create_file(Uuid::new_v4(), path("allowed/test.txt"), 0o600, false).await;
+    assert!(matches!(response, Some(Message::CreateFileResponse { success: true, .. })));
+    assert_eq!(std::fs::read_to_string(path("allowed/test.txt")).unwrap(), "test content");
+    assert_ne!(mode("allowed/test.txt"), 0o600);
+    
+    let response = filesystem_handler.handle_create_directory(Uuid::new_v4(), path("allowed/team/inbox"), 0o2775).await;
+    let Some(Message::CreateDirectoryResponse { success: true, metadata: Some(metadata), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert!(metadata.is_dir);
+    assert_eq!(metadata.permissions & 0o7777, 0o2775);
+    assert_eq!(mode("allowed/team/inbox"), 0o2775);
+    
+    let response = filesystem_handler.handle_create_file(Uuid::new_v4(), path("readonly/new.txt"), 0o644, false).await;
+    assert!(!matches!(response, Some(Message::CreateFileResponse { success: true, .. })));
+}
+
+#[tokio::test]
+async fn test_allowed_mode_masks_requested_modes() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let mut config = create_test_config(temp_dir.path());
+    config.access.allowed_mode = 0o775;
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let mode = |p: &str| std::fs::metadata(path(p)).unwrap().permissions().mode() & 0o7777;
+    
+    // Setuid and world-writable bits are dropped, and the response says so
+    let response = filesystem_handler.handle_create_file(Uuid::new_v4(), path("allowed/tool"), 0o4777, true).await;
+    let Some(Message::CreateFileResponse { success: true, metadata: Some(metadata), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(metadata.permissions & 0o7777, 0o775);
+    assert_eq!(mode("allowed/tool"), 0o775);
+    
+    let response = filesystem_handler.handle_create_directory(Uuid::new_v4(), path("allowed/drop"), 0o1777).await;
+    let Some(Message::CreateDirectoryResponse { success: true, metadata: Some(metadata), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(metadata.permissions & 0o7777, 0o775);
+    
+    // Batches get the same modes as single creates, even under a umask that
+    // would drop the group and other bits
+    let previous = unsafe { libc::umask(0o077) };
+    let files = vec![NewFile { path: path("allowed/batch/tool"), data: Vec::new(), mode: Some(0o4777) }];
+    let response = filesystem_handler.handle_batch_create_files(Uuid::new_v4(), files, false).await;
+    let single = filesystem_handler.handle_create_file(Uuid::new_v4(), path("allowed/batch/single"), 0o4777, true).await;
+    unsafe { libc::umask(previous) };
+    assert!(matches!(response, Some(Message::BatchCreateFilesResponse { created: 1, .. })), "{:?}", response);
+    assert!(matches!(single, Some(Message::CreateFileResponse { success: true, .. })), "{:?}", single);
+    assert_eq!(mode("allowed/batch/tool"), 0o775);
+    assert_eq!(mode("allowed/batch/single"), 0o775);
+    
+    // The same applies to a chmod
+    let update = MetadataUpdate { permissions: Some(0o6755), ..Default::default() };
+    let response = filesystem_handler.handle_set_metadata(Uuid::new_v4(), path("allowed/test.txt"), update).await;
+    assert!(matches!(response, Some(Message::SetMetadataResponse { success: true, .. })));
+    assert_eq!(mode("allowed/test.txt"), 0o755);
+}
+
+#[tokio::test]
+async fn test_file_locks() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let lock = |session: &str, lock_type, start, length| {
+        let mut lock = FileLock::new(lock_type, start, length, 1);
+        lock.owner.session = session.to_string();
+        lock
+    };
+    
+    let response = filesystem_handler
+        .handle_lock_file(Uuid::new_v4(), path("allowed/test.txt"), lock("client-1", LockType::Write, 0, 100))
+        .await;
+    assert!(matches!(response, Some(Message::LockFileResponse { success: true, conflict: None, .. })));
+    
+    // Another name for the file finds the same lock
+    let response = filesystem_handler
+        .handle_lock_file(Uuid::new_v4(), path("allowed/subdir1/../test.txt"), lock("client-2", LockType::Read, 50, 1))
+        .await;
+    let Some(Message::LockFileResponse { success: true, conflict: Some(conflict), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(conflict, lock("client-1", LockType::Write, 0, 100));
+    
+    let response = filesystem_handler
+        .handle_test_lock(Uuid::new_v4(), path("allowed/test.txt"), lock("client-2", LockType::Read, 100, 0))
+        .await;
+    assert!(matches!(response, Some(Message::TestLockResponse { success: true, conflict: None, .. })));
+    
+    let owner = LockOwner { session: "client-1".to_string(), owner: 1 };
+    let response = filesystem_handler
+        .handle_unlock_file(Uuid::new_v4(), path("allowed/test.txt"), owner, 0, 50)
+        .await;
+    assert!(matches!(response, Some(Message::UnlockFileResponse { success: true, .. })));
+    assert_eq!(filesystem_handler.lock_count(), 1);
+    filesystem_handler.handle_release_locks("client-1");
+    assert_eq!(filesystem_handler.lock_count(), 0);
+    
+    // Write locks need write access, as opening a file for writing would
+    let response = filesystem_handler
+        .handle_lock_file(Uuid::new_v4(), path("readonly/readonly.txt"), lock("client-1", LockType::Read, 0, 0))
+        .await;
+    assert!(matches!(response, Some(Message::LockFileResponse { success: true, conflict: None, .. })));
+    for (refused, lock_type) in [
+        ("readonly/readonly.txt", LockType::Write),
+        ("denied/secret.txt", LockType::Read),
+        ("allowed/missing.txt", LockType::Read),
+    ] {
+        let response = filesystem_handler
+            .handle_lock_file(Uuid::new_v4(), path(refused), lock("client-1", lock_type, 0, 0))
+            .await;
+        assert!(matches!(response, Some(Message::Error { .. })), "{}", refused);
+    }
+}
+
+#[tokio::test]
+async fn test_open_files() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let read = |handle, offset, length| filesystem_handler.handle_read_handle(Uuid::new_v4(), handle, offset, length);
+    let write = |handle, data: &[u8]| filesystem_handler.handle_write_handle(Uuid::new_v4(), handle, 0, data.to_vec(), false);
+    let data = |response: Option<Message>| match response {
+        Some(Message::ReadFileResponse { success: true, data: Some(data), .. }) => data,
+        other => panic!("Unexpected response: {:?}", other),
+    };
+    
+    // O_EXCL refuses an existing file and creates a missing one
+    let exclusive = OpenFlags { write: true, create: true, exclusive: true, ..OpenFlags::default() };
+    let response = filesystem_handler
+        .handle_open_file(Uuid::new_v4(), path("allowed/test.txt"), exclusive, 0o644, "client-1".to_string())
+        .await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::PathAlreadyExists, .. })), "{:?}", response);
+    let log = Uuid::new_v4();
+    let append = OpenFlags { append: true, ..exclusive };
+    let response = filesystem_handler
+        .handle_open_file(log, path("allowed/log.txt"), append, 0o600, "client-1".to_string())
+        .await;
+    let Some(Message::OpenFileResponse { success: true, metadata: Some(metadata), .. }) = response else {
+        panic!("Unexpected response: {:?}", response);
+    };
+    assert_eq!(metadata.permissions & 0o777, 0o600);
+    
+    // Appends land at the end, whatever the offset
+    assert!(matches!(write(log, b"one ").await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert!(matches!(write(log, b"two").await, Some(Message::WriteFileResponse { success: true, .. })));
+    assert_file_content(temp_dir.path().join("allowed/log.txt"), "one two");
+    
+    // A handle reads the file it opened after it is removed
+    let reader = Uuid::new_v4();
+    let response = filesystem_handler
+        .handle_open_file(reader, path("allowed/test.txt"), OpenFlags::read_only(), 0, "client-2".to_string())
+        .await;
+    assert!(matches!(response, Some(Message::OpenFileResponse { success: true, .. })));
+    std::fs::remove_file(temp_dir.path().join("allowed/test.txt")).unwrap();
+    assert_eq!(data(read(reader, 5, 100).await), b"content");
+    assert_eq!(data(read(reader, 100, 10).await), b"");
+    
+    // A handle is only used for what it was opened for
+    let response = write(reader, b"x").await;
+    assert!(matches!(response, Some(Message::Error { errno: Some(libc::EBADF), .. })), "{:?}", response);
+    let response = read(log, 0, 3).await;
+    assert!(matches!(response, Some(Message::Error { errno: Some(libc::EBADF), .. })), "{:?}", response);
+    
+    // Opening for writing needs write access
+    let writable = OpenFlags { read: true, write: true, ..OpenFlags::default() };
+    let response = filesystem_handler
+        .handle_open_file(Uuid::new_v4(), path("readonly/readonly.txt"), writable, 0, "client-1".to_string())
+        .await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })), "{:?}", response);
+    
+    // Closing a handle, or the session that holds it, closes the file
+    assert_eq!(filesystem_handler.handle_count(), 2);
+    let response = filesystem_handler.handle_close_file(Uuid::new_v4(), log).await;
+    assert!(matches!(response, Some(Message::CloseFileResponse { success: true, .. })));
+    let response = filesystem_handler.handle_close_file(Uuid::new_v4(), log).await;
+    assert!(matches!(response, Some(Message::Error { errno: Some(libc::EBADF), .. })), "{:?}", response);
+    filesystem_handler.handle_release_locks("client-2");
+    assert_eq!(filesystem_handler.handle_count(), 0);
+}
+
+#[tokio::test]
+async fn test_shutdown_drain() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |p: &str| temp_dir.path().join(p).to_string_lossy().to_string();
+    let writable = OpenFlags { write: true, create: true, ..OpenFlags::default() };
+    let response = filesystem_handler
+        .handle_open_file(Uuid::new_v4(), path("allowed/open.txt"), writable, 0o644, "client-1".to_string())
+        .await;
+    assert!(matches!(response, Some(Message::OpenFileResponse { success: true, .. })), "{:?}", response);
+    
+    // Once draining, requests from any handler are refused as retriable
+    filesystem_handler.stop_accepting();
+    let request = Message::ReadFile { request_id: Uuid::new_v4(), path: path("allowed/test.txt"), offset: 0, length: 10 };
+    let response = filesystem_handler.for_client("client-2".to_string()).check_request(&request).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::ServiceUnavailable, .. })), "{:?}", response);
+    
+    // Nothing is in progress, and the open file is flushed
+    assert_eq!(filesystem_handler.drain(std::time::Duration::from_secs(5)).await, 0);
+    assert_eq!(filesystem_handler.sync_open_files().await, 1);
+}
+
+#[tokio::test]
+async fn test_read_ahead() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let mut config = create_test_config(temp_dir.path());
+    config.performance.enable_prefetch = true;
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let file_path = temp_dir.path().join("allowed/stream.bin");
+    let content: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file_path, &content).unwrap();
+    
+    let handle = Uuid::new_v4();
+    let response = filesystem_handler
+        .handle_open_file(handle, file_path.to_string_lossy().to_string(), OpenFlags::read_only(), 0, "client-1".to_string())
+        .await;
+    assert!(matches!(response, Some(Message::OpenFileResponse { success: true, .. })));
+    let handler = &filesystem_handler;
+    let read = |offset: u64| async move {
+        match handler.handle_read_handle(Uuid::new_v4(), handle, offset, 4096).await {
+            Some(Message::ReadFileResponse { success: true, data: Some(data), .. }) => data,
+            other => panic!("Unexpected response: {:?}", other),
+        }
+    };
+    
+    // Sequential reads are answered from blocks read ahead, with the same data
+    let mut offset = 0;
+    for _ in 0..2 {
+        assert_eq!(read(offset).await, content[offset as usize..offset as usize + 4096]);
+        offset += 4096;
+    }
+    for _ in 0..50 {
+        if filesystem_handler.get_performance_stats().await.prefetched_bytes > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    for _ in 0..4 {
+        assert_eq!(read(offset).await, content[offset as usize..offset as usize + 4096]);
+        offset += 4096;
+    }
+    assert!(filesystem_handler.get_performance_stats().await.prefetch_hits > 0);
+    
+    // A file changed since is read again
+    let mut changed = vec![0xff; 128 * 1024];
+    changed[..offset as usize].copy_from_slice(&content[..offset as usize]);
+    std::fs::write(&file_path, &changed).unwrap();
+    assert_eq!(read(offset).await, vec![0xff; 4096]);
+    
+    filesystem_handler.handle_close_file(Uuid::new_v4(), handle).await;
+    assert_eq!(filesystem_handler.get_performance_stats().await.prefetched_bytes, 0);
+}
+
+#[tokio::test]
+async fn test_extended_operation_refused_without_whitelist() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let (output_tx, mut output_rx) = tokio::sync::mpsc::unbounded_channel();
+    let working_dir = temp_dir.path().join("allowed").to_string_lossy().to_string();
+    
+    let response = filesystem_handler.handle_extended_operation(
+        Uuid::new_v4(), "fetch".to_string(), vec![], Some(working_dir), &output_tx,
+    ).await;
+    assert!(matches!(response, Some(Message::ExtendedOutput { last: true, exit_code: None, error: Some(_), .. })));
+    assert!(output_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_local_socket_passes_descriptors() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = Arc::new(FilesystemHandler::new(access_control, &config.performance));
+    
+    let socket_path = temp_dir.path().join("agent.sock");
+    let socket = remotefs_agent::local::LocalSocket::bind(&socket_path).unwrap();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::broadcast::channel(1);
+    let server = tokio::spawn(socket.serve(Arc::clone(&filesystem_handler), shutdown_rx));
+    
+    let local = remotefs_client::LocalFiles::new(&socket_path);
+    let allowed = temp_dir.path().join("allowed/test.txt").to_string_lossy().to_string();
+    assert_eq!(local.read(&allowed, 0, None).await.unwrap(), b"test content");
+    assert_eq!(local.read(&allowed, 5, Some(3)).await.unwrap(), b"con");
+    
+    // Refusals come back as errors on the same connection
+    let denied = temp_dir.path().join("denied/secret.txt").to_string_lossy().to_string();
+    assert!(matches!(
+        local.read(&denied, 0, None).await,
+        Err(remotefs_client::ClientError::RemoteFs(_))
+    ));
+    assert_eq!(local.read(&allowed, 0, Some(4)).await.unwrap(), b"test");
+    
+    let stats = filesystem_handler.get_statistics().await;
+    assert_eq!(stats.local_opens, 3);
+    assert_eq!(stats.error_count, 1);
+    
+    let _ = shutdown_tx.send(());
+    server.await.unwrap();
+    assert!(!socket_path.exists());
+}
+
+#[tokio::test]
+async fn test_list_exports() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    
+    let request_id = Uuid::new_v4();
+    let Some(Message::ListExportsResponse { request_id: id, exports, error: None }) =
+        filesystem_handler.handle_list_exports(request_id).await
+    else {
+        panic!("expected a list of exports");
+    };
+    assert_eq!(id, request_id);
+    
+    let listed: Vec<(&str, &str, bool)> = exports.iter()
+        .map(|export| (export.name.as_str(), export.path.as_str(), export.read_only))
+        .collect();
+    let path = |name: &str| temp_dir.path().join(name).to_string_lossy().to_string();
+    let (allowed, temp, readonly) = (path("allowed"), path("temp"), path("readonly"));
+    assert_eq!(listed, vec![
+        ("allowed", allowed.as_str(), false),
+        ("temp", temp.as_str(), false),
+        ("readonly", readonly.as_str(), true),
+    ]);
+    assert!(exports.iter().all(|export| export.available_space <= export.total_space));
+}
+
+#[tokio::test]
+async fn test_get_space_info() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |name: &str| temp_dir.path().join(name).to_string_lossy().to_string();
+    
+    let request_id = Uuid::new_v4();
+    let Some(Message::GetSpaceInfoResponse {
+        request_id: id,
+        success: true,
+        total_space: Some(total),
+        available_space: Some(available),
+        used_space: Some(used),
+        ..
+    }) = filesystem_handler.handle_get_space_info(request_id, path("allowed/subdir1")).await
+    else {
+        panic!("expected the space of the filesystem");
+    };
+    assert_eq!(id, request_id);
+    assert!(total > 0 && available <= total && used <= total);
+    
+    let response = filesystem_handler.handle_get_space_info(Uuid::new_v4(), path("allowed/nonexistent")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })));
+    
+    let response = filesystem_handler.handle_get_space_info(Uuid::new_v4(), path("denied")).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::AccessDenied, .. })));
+}
+
+/// Next change notification for a watch, skipping modifications
+async fn next_change(notifications: &mut tokio::sync::mpsc::UnboundedReceiver<Message>) -> Message {
+    loop {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), notifications.recv()).await
+            .expect("No change reported")
+            .expect("Watch channel closed");
+        if !matches!(message, Message::FileChanged { kind: ChangeKind::Modified, .. }) {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_watch() {
+    setup_test_logging();
+    let temp_dir = create_temp_dir();
+    create_test_directory_structure(temp_dir.path());
+    let config = create_test_config(temp_dir.path());
+    let access_control = create_test_access_control(&config.access);
+    
+    let filesystem_handler = FilesystemHandler::new(access_control, &config.performance);
+    let path = |name: &str| temp_dir.path().join(name).to_string_lossy().to_string();
+    let (tx, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+    let request_id = Uuid::new_v4();
+    
+    assert!(filesystem_handler.handle_watch(request_id, path("allowed"), true, &tx).await.is_none());
+    assert!(matches!(notifications.recv().await, Some(Message::WatchResponse { success: true, .. })));
+    assert_eq!(filesystem_handler.watch_count(), 1);
+    
+    filesystem_handler.handle_write_file(Uuid::new_v4(), path("allowed/subdir1/new.txt"), b"new".to_vec(), None, false).await;
+    match next_change(&mut notifications).await {
+        Message::FileChanged { request_id: id, path: changed, kind: ChangeKind::Created, is_dir: false } => {
+            assert_eq!(id, request_id);
+            assert_eq!(changed, path("allowed/subdir1/new.txt"));
+        }
+        message => panic!("Unexpected notification: {:?}", message),
+    }
+    assert!(matches!(
+        next_change(&mut notifications).await,
+        Message::DirectoryChanged { path: ref dir, .. } if *dir == path("allowed/subdir1")
+    ));
+    
+    filesystem_handler.handle_move_file(Uuid::new_v4(), path("allowed/subdir1/new.txt"), path("allowed/renamed.txt"), false).await;
+    match next_change(&mut notifications).await {
+        Message::FileChanged { path: changed, kind: ChangeKind::Renamed { from }, .. } => {
+            assert_eq!(changed, path("allowed/renamed.txt"));
+            assert_eq!(from, path("allowed/subdir1/new.txt"));
+        }
+        message => panic!("Unexpected notification: {:?}", message),
+    }
+    
+    // Changes made on the host, not through the agent, are seen by inotify
+    #[cfg(target_os = "linux")]
+    {
+        while tokio::time::timeout(std::time::Duration::from_millis(200), notifications.recv()).await.is_ok() {}
+        std::fs::create_dir(temp_dir.path().join("allowed/made-locally")).unwrap();
+        assert!(matches!(
+            next_change(&mut notifications).await,
+            Message::FileChanged { kind: ChangeKind::Created, is_dir: true, .. }
+        ));
+    }
+    
+    assert!(matches!(
+        filesystem_handler.handle_unwatch(request_id).await,
+        Some(Message::WatchEnded { error: None, .. })
+    ));
+    assert_eq!(filesystem_handler.watch_count(), 0);
+    
+    // Missing and denied paths cannot be watched
+    let response = filesystem_handler.handle_watch(Uuid::new_v4(), path("allowed/missing"), false, &tx).await;
+    assert!(matches!(response, Some(Message::Error { code: ErrorCode::FileNotFound, .. })));
+    let response = filesystem_handler.handle_watch(Uuid::new_v4(), path("denied"), false, &tx).await;
+    assert!(matches!(response, Some(Message::Error { .. })));
+    assert_eq!(filesystem_handler.watch_count(), 0);
+}
@@ -1,7 +0,0 @@
-// Integration tests for RemoteFS Agent
-
-mod common;
-mod access_control;
-mod configuration;
-mod filesystem;
-mod server;
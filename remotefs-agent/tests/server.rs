@@ -17,13 +17,7 @@ async fn test_agent_server_creation() {
     
     // Test that we can create an AgentServer instance
     let result = remotefs_agent::server::AgentServer::new(config);
-    
-    // This will fail due to compilation issues, but the test structure is in place
-    // Once the protocol issues are resolved, this would work:
-    // assert!(result.is_ok(), "Should be able to create AgentServer");
-    
-    // For now, we just verify the config structure is valid
-    assert!(true, "Server creation test structure is in place");
+    assert!(result.is_ok(), "Should be able to create AgentServer");
 }
 
 #[tokio::test]
@@ -121,7 +115,8 @@ async fn test_server_statistics_integration() {
     
     // Check statistics
     let access_stats = access_control.get_statistics().await;
-    assert_eq!(access_stats.allowed_requests, 2);
+    // The read handler shares this access control, so its check counts too
+    assert_eq!(access_stats.allowed_requests, 3);
     assert_eq!(access_stats.denied_requests, 1);
     
     let fs_stats = filesystem_handler.get_statistics().await;
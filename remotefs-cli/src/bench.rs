@@ -0,0 +1,104 @@
+//! `remotefs bench`: how fast an agent answers, measured through a client
+//!
+//! Works in a scratch directory under the given remote path: creates and
+//! stats many small files, lists them, then writes and reads back one large
+//! file. The scratch directory is removed afterwards.
+
+use anyhow::Result;
+use bytes::Bytes;
+use clap::Args;
+use remotefs_client::{ClientConfig, RemoteFsClient};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Args)]
+pub struct BenchArgs {
+    /// Client configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Writable remote directory to work in
+    #[arg(default_value = "/tmp")]
+    pub path: String,
+    /// Small files to create and stat
+    #[arg(long, default_value_t = 200)]
+    pub files: usize,
+    /// Size of the large file written and read back, in MiB
+    #[arg(long, default_value_t = 64)]
+    pub size_mb: usize,
+}
+
+/// Size of each small file
+const SMALL_FILE_SIZE: usize = 4096;
+
+pub async fn run(args: BenchArgs) -> Result<()> {
+    let config = match &args.config {
+        Some(path) => ClientConfig::from_file(path)?,
+        None => ClientConfig::default(),
+    };
+    let client = RemoteFsClient::new(config)?;
+    client.initialize().await?;
+
+    let scratch = format!("{}/remotefs-bench-{}", args.path.trim_end_matches('/'), uuid::Uuid::new_v4());
+    client.create_directory(&scratch).await?;
+    println!("Benchmarking in {}", scratch);
+
+    let result = measure(&client, &scratch, &args).await;
+
+    // Best effort: a failed measurement should still leave nothing behind
+    if let Err(e) = client.delete_directory(&scratch).await {
+        eprintln!("Could not remove {}: {}", scratch, e);
+    }
+    result
+}
+
+async fn measure(client: &RemoteFsClient, scratch: &str, args: &BenchArgs) -> Result<()> {
+    let small = Bytes::from(vec![0x5a; SMALL_FILE_SIZE]);
+    let paths: Vec<String> = (0..args.files).map(|i| format!("{}/small-{:05}", scratch, i)).collect();
+
+    let started = Instant::now();
+    for path in &paths {
+        client.write_file(path, small.clone()).await?;
+    }
+    report_ops("create", paths.len(), started.elapsed());
+
+    let started = Instant::now();
+    for path in &paths {
+        client.get_metadata(path).await?;
+    }
+    report_ops("stat", paths.len(), started.elapsed());
+
+    let started = Instant::now();
+    let entries = client.list_directory(scratch).await?;
+    println!("{:>8}: {} entries in {:.1} ms", "list", entries.len(), started.elapsed().as_secs_f64() * 1000.0);
+
+    let large_path = format!("{}/large", scratch);
+    let large = Bytes::from(vec![0xa5; args.size_mb * 1024 * 1024]);
+
+    let started = Instant::now();
+    client.write_file(&large_path, large.clone()).await?;
+    report_throughput("write", large.len(), started.elapsed());
+
+    let started = Instant::now();
+    let read = client.read_file(&large_path).await?;
+    report_throughput("read", read.len(), started.elapsed());
+    anyhow::ensure!(read == large, "Read back {} bytes that differ from those written", read.len());
+
+    Ok(())
+}
+
+fn report_ops(name: &str, count: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{:>8}: {} files in {:.2} s, {:.0} ops/s",
+        name, count, seconds, count as f64 / seconds.max(f64::EPSILON)
+    );
+}
+
+fn report_throughput(name: &str, bytes: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:>8}: {:.0} MiB in {:.2} s, {:.1} MiB/s",
+        name, mib, seconds, mib / seconds.max(f64::EPSILON)
+    );
+}
@@ -0,0 +1,185 @@
+//! `remotefs doctor`: checks a setup before its users find what is wrong
+//!
+//! Every configuration given is loaded and validated, the agent's paths are
+//! probed as its startup self-test does, servers are checked for listening on
+//! their ports, and with a client configuration the agents are connected to.
+
+use clap::Args;
+use remotefs_client::{ClientConfig, RemoteFsClient};
+use remotefs_common::config::{load_agent_config, load_relay_config};
+use remotefs_nfs::NfsConfig;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long connecting to a port or to the agents may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Agent configuration to check, with the paths it serves
+    #[arg(long, value_name = "FILE")]
+    pub agent_config: Option<PathBuf>,
+    /// Relay configuration to check
+    #[arg(long, value_name = "FILE")]
+    pub relay_config: Option<PathBuf>,
+    /// NFS configuration to check, with its exports' servers
+    #[arg(long, value_name = "FILE")]
+    pub nfs_config: Option<PathBuf>,
+    /// Client configuration whose agents to connect to
+    #[arg(long, value_name = "FILE")]
+    pub client_config: Option<PathBuf>,
+}
+
+/// Tally of the checks run, printed as they complete
+#[derive(Default)]
+struct Report {
+    failures: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn pass(&mut self, message: impl std::fmt::Display) {
+        println!("  ✓ {}", message);
+    }
+
+    fn fail(&mut self, message: impl std::fmt::Display) {
+        self.failures += 1;
+        println!("  ✗ {}", message);
+    }
+
+    fn warn(&mut self, message: impl std::fmt::Display) {
+        self.warnings += 1;
+        println!("  ! {}", message);
+    }
+}
+
+/// Run every check `args` asks for, returning whether all of them passed
+pub async fn run(args: DoctorArgs) -> bool {
+    let mut report = Report::default();
+    let mut checked = false;
+
+    if let Some(path) = &args.agent_config {
+        checked = true;
+        check_agent(&mut report, path).await;
+    }
+    if let Some(path) = &args.relay_config {
+        checked = true;
+        check_relay(&mut report, path);
+    }
+    if let Some(path) = &args.nfs_config {
+        checked = true;
+        check_nfs(&mut report, path);
+    }
+    if let Some(path) = &args.client_config {
+        checked = true;
+        check_client(&mut report, path).await;
+    }
+
+    if !checked {
+        println!("Nothing to check; pass --agent-config, --relay-config, --nfs-config or --client-config");
+        return false;
+    }
+
+    println!();
+    if report.failures == 0 {
+        println!("All checks passed ({} warnings)", report.warnings);
+    } else {
+        println!("{} checks failed ({} warnings)", report.failures, report.warnings);
+    }
+    report.failures == 0
+}
+
+async fn check_agent(report: &mut Report, path: &PathBuf) {
+    println!("Agent ({})", path.display());
+    let config = match load_agent_config(path) {
+        Ok(config) => config,
+        Err(e) => return report.fail(e),
+    };
+    match remotefs_agent::cli::validate_agent_config(&config) {
+        Ok(()) => report.pass(format!("configuration of agent {} is valid", config.agent_id)),
+        Err(e) => report.fail(e),
+    }
+
+    let access = config.access.clone();
+    let probes = match tokio::task::spawn_blocking(move || remotefs_agent::selftest::probe_paths(&access)).await {
+        Ok(probes) => probes,
+        Err(e) => return report.fail(format!("path self-test did not finish: {}", e)),
+    };
+    for probe in probes {
+        if probe.is_ready() {
+            let mode = if probe.read_only { "readable" } else { "readable and writable" };
+            report.pass(format!("{} is {}", probe.path, mode));
+        } else {
+            report.fail(format!("{}: {}", probe.path, probe.problems.join("; ")));
+        }
+        for warning in &probe.warnings {
+            report.warn(format!("{}: {}", probe.path, warning));
+        }
+    }
+}
+
+fn check_relay(report: &mut Report, path: &PathBuf) {
+    println!("Relay ({})", path.display());
+    match load_relay_config(path) {
+        Ok(config) => {
+            report.pass("configuration is valid");
+            let address = format!("{}:{}", config.bind_address, config.port);
+            if is_listening(&address) {
+                report.pass(format!("relay is listening on {}", address));
+            } else {
+                report.warn(format!("nothing is listening on {}; is the relay running?", address));
+            }
+        }
+        Err(e) => report.fail(e),
+    }
+}
+
+fn check_nfs(report: &mut Report, path: &PathBuf) {
+    println!("NFS ({})", path.display());
+    let config = match NfsConfig::from_file(path).and_then(|config| config.validate().map(|()| config)) {
+        Ok(config) => config,
+        Err(e) => return report.fail(e),
+    };
+    report.pass("configuration is valid");
+    for export in config.resolved_exports() {
+        let address = export.listen_address();
+        if is_listening(&address) {
+            report.pass(format!("export {} is served on {}", export.mount_path(), address));
+        } else {
+            report.warn(format!("export {} is not served on {}; is the server running?", export.mount_path(), address));
+        }
+    }
+}
+
+async fn check_client(report: &mut Report, path: &PathBuf) {
+    println!("Client ({})", path.display());
+    let client = match ClientConfig::from_file(path).map_err(|e| e.to_string())
+        .and_then(|config| RemoteFsClient::new(config).map_err(|e| e.to_string()))
+    {
+        Ok(client) => client,
+        Err(e) => return report.fail(e),
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, client.initialize()).await {
+        Ok(Ok(())) => report.pass("connected to the agents"),
+        Ok(Err(e)) => return report.fail(format!("cannot connect: {}", e)),
+        Err(_) => return report.fail(format!("cannot connect within {:?}", CONNECT_TIMEOUT)),
+    }
+    match client.list_exports(None).await {
+        Ok(exports) if exports.is_empty() => report.warn("the agents export no paths"),
+        Ok(exports) => {
+            for export in exports {
+                let mode = if export.read_only { "read-only" } else { "read-write" };
+                report.pass(format!("export {} ({}) is {}", export.name, export.path, mode));
+            }
+        }
+        Err(e) => report.fail(format!("cannot list exports: {}", e)),
+    }
+}
+
+fn is_listening(address: &str) -> bool {
+    use std::net::ToSocketAddrs;
+    address.to_socket_addrs().ok()
+        .and_then(|mut addresses| addresses.next())
+        .is_some_and(|address| TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).is_ok())
+}
@@ -0,0 +1,188 @@
+//! `remotefs`: every RemoteFS component behind one command
+//!
+//! Each component's subcommand takes the same arguments as its own binary,
+//! so `remotefs agent --config agent.toml` runs what
+//! `remotefs-agent --config agent.toml` does. `cp` and `ls` are shortcuts for
+//! the client's commands, and `doctor` and `bench` look at a whole setup.
+
+// `ClientError` wraps tungstenite errors by value, which trips this lint everywhere
+#![allow(clippy::result_large_err)]
+
+mod bench;
+mod doctor;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use remotefs_client::cli::{self as client, CliArgs};
+use remotefs_nfs::mount_helper;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "remotefs")]
+#[command(about = "RemoteFS - remote directories served by agents, through relays, to clients and mounts")]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Serve local directories to clients (remotefs-agent)
+    Agent(remotefs_agent::cli::Cli),
+    /// Connect clients to agents (remotefs-relay)
+    Relay(remotefs_relay::cli::Cli),
+    /// Serve agents over NFS and mount them, on macOS or Linux (remotefs-nfs)
+    #[command(visible_alias = "nfs")]
+    Macos(remotefs_nfs::cli::Cli),
+    /// Mount an agent directory, as mount(8) does for fstab entries of type remotefs
+    ///
+    /// Takes mount(8)'s helper arguments: <ws://agent/path> <dir> [-sfnv] [-o options]
+    Mount {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "ARGS")]
+        args: Vec<String>,
+    },
+    /// Run file operations against agents (remotefs-client)
+    Client(CliArgs),
+    /// Copy files to, from or between agents; remote paths start with ':'
+    Cp {
+        /// Client configuration file
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Upload directories and their contents
+        #[arg(short, long)]
+        recursive: bool,
+        /// Replace remote files that already exist
+        #[arg(long)]
+        overwrite: bool,
+        source: String,
+        destination: String,
+    },
+    /// List a remote directory
+    Ls {
+        /// Client configuration file
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Check configurations, paths, servers and connections
+    Doctor(doctor::DoctorArgs),
+    /// Measure metadata and transfer speed against an agent
+    Bench(bench::BenchArgs),
+}
+
+#[tokio::main]
+async fn main() {
+    // Symlinked to /sbin/mount.remotefs, this binary acts as the mount(8) helper
+    let invoked_as = std::env::args().next().unwrap_or_default();
+    if Path::new(&invoked_as).file_name().is_some_and(|name| name == mount_helper::HELPER_NAME) {
+        mount(std::env::args().skip(1).collect());
+    }
+
+    if let Err(e) = run(Cli::parse()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Commands::Agent(args) => remotefs_agent::cli::run(args).await?,
+        Commands::Relay(args) => remotefs_relay::cli::run(args).await?,
+        Commands::Macos(args) => args.run().await?,
+        Commands::Mount { args } => mount(args),
+        Commands::Client(args) => client::run(args).await?,
+        Commands::Cp { config, recursive, overwrite, source, destination } => {
+            let command = copy_command(&source, &destination, recursive, overwrite)?;
+            client::run(client_args(config, command)).await?
+        }
+        Commands::Ls { config, path } => {
+            client::run(client_args(config, client::Commands::List { path })).await?
+        }
+        Commands::Doctor(args) => {
+            if !doctor::run(args).await {
+                std::process::exit(1);
+            }
+        }
+        Commands::Bench(args) => bench::run(args).await?,
+    }
+    Ok(())
+}
+
+/// Run the mount helper and exit with the status mount(8) expects
+fn mount(args: Vec<String>) -> ! {
+    let result = mount_helper::MountRequest::parse(args)
+        .and_then(|request| mount_helper::run(&request));
+    if let Err(e) = result {
+        eprintln!("{}: {}", mount_helper::HELPER_NAME, e);
+        // mount(8) reserves 32 for mount failures
+        std::process::exit(32);
+    }
+    std::process::exit(0);
+}
+
+fn client_args(config: Option<PathBuf>, command: client::Commands) -> CliArgs {
+    CliArgs { config, verbose: false, record: None, command }
+}
+
+/// The client command copying `source` to `destination`, where remote paths
+/// are written `:/path`
+fn copy_command(source: &str, destination: &str, recursive: bool, overwrite: bool) -> Result<client::Commands> {
+    let command = match (source.strip_prefix(':'), destination.strip_prefix(':')) {
+        (None, Some(remote)) => client::Commands::Put {
+            local: PathBuf::from(source),
+            remote: remote.to_string(),
+            recursive,
+            overwrite,
+        },
+        (Some(remote), None) if !recursive => client::Commands::Read {
+            path: remote.to_string(),
+            output: Some(PathBuf::from(destination)),
+        },
+        (Some(_), None) => anyhow::bail!("Only uploads copy directories; download files one at a time"),
+        (Some(source), Some(destination)) => client::Commands::Copy {
+            source: source.to_string(),
+            destination: destination.to_string(),
+        },
+        (None, None) => anyhow::bail!("One of the paths must be remote, written as :/path"),
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        // Components take the arguments of their own binaries
+        let cli = Cli::try_parse_from(["remotefs", "macos", "--port", "2050", "config", "show"]).unwrap();
+        assert!(matches!(cli.command, Commands::Macos(args) if args.port == Some(2050)));
+        let cli = Cli::try_parse_from(["remotefs", "relay", "--config", "relay.toml"]).unwrap();
+        assert!(matches!(cli.command, Commands::Relay(args) if args.config == Some(PathBuf::from("relay.toml"))));
+        let cli = Cli::try_parse_from(["remotefs", "mount", "ws://files:8080/srv", "/mnt/srv", "-o", "port=2050"]).unwrap();
+        assert!(matches!(cli.command, Commands::Mount { args } if args.len() == 4));
+    }
+
+    #[test]
+    fn test_copy_command() {
+        assert!(matches!(
+            copy_command("notes.txt", ":/srv/notes.txt", false, true).unwrap(),
+            client::Commands::Put { remote, overwrite: true, .. } if remote == "/srv/notes.txt"
+        ));
+        assert!(matches!(
+            copy_command(":/srv/notes.txt", "notes.txt", false, false).unwrap(),
+            client::Commands::Read { path, output: Some(_) } if path == "/srv/notes.txt"
+        ));
+        assert!(matches!(
+            copy_command(":/srv/a", ":/srv/b", false, false).unwrap(),
+            client::Commands::Copy { source, destination } if source == "/srv/a" && destination == "/srv/b"
+        ));
+        assert!(copy_command(":/srv/dir", "dir", true, false).is_err());
+        assert!(copy_command("a", "b", false, false).is_err());
+    }
+}
@@ -8,7 +8,6 @@
 
 use remotefs_client::*;
 use bytes::Bytes;
-use tokio;
 use tracing::{info, error};
 
 #[tokio::main]
@@ -45,6 +44,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             enable_failover: true,
             read_buffer_size: 8192,
             write_buffer_size: 8192,
+            max_fallback_entries: 100_000,
         },
         connection: ConnectionConfig {
             connect_timeout_ms: 10000,
@@ -58,9 +58,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 max_delay_ms: 30000,
                 backoff_multiplier: 2.0,
             },
+            local_socket: None,
+            simulate: None,
         },
         auth: None,
         logging: LoggingConfig::default(),
+        discovery: None,
+        path_rewrites: Vec::new(),
     };
 
     // Create and initialize the client
@@ -141,22 +145,26 @@ async fn perform_filesystem_operations(client: &RemoteFsClient) -> Result<(), Cl
 }
 
 // Example with error handling
+#[allow(dead_code)]
 async fn example_with_error_handling(client: &RemoteFsClient) {
     match client.read_file("/nonexistent/file.txt").await {
         Ok(content) => {
             info!("File content: {} bytes", content.len());
         }
-        Err(ClientError::RemoteFs(remote_error)) => {
-            error!("Remote filesystem error: {}", remote_error);
-        }
-        Err(ClientError::Connection(msg)) => {
-            error!("Connection error: {}", msg);
-        }
-        Err(ClientError::Timeout { seconds }) => {
-            error!("Operation timed out after {} seconds", seconds);
-        }
-        Err(e) => {
-            error!("Other error: {}", e);
-        }
+        // The error names the request it answers; match on its cause
+        Err(e) => match e.cause() {
+            ClientError::RemoteFs(remote_error) => {
+                error!("Remote filesystem error: {} (request {:?})", remote_error, e.request_id());
+            }
+            ClientError::Connection(msg) => {
+                error!("Connection error: {}", msg);
+            }
+            ClientError::Timeout { seconds } => {
+                error!("Operation timed out after {} seconds (request {:?})", seconds, e.request_id());
+            }
+            _ => {
+                error!("Other error: {}", e);
+            }
+        },
     }
 }
@@ -1,11 +1,18 @@
-use crate::client::RemoteFsClient;
-use crate::config::ClientConfig;
+//! Command line interface of the client, run by `remotefs-client` and by
+//! `remotefs client`
+
+use crate::{ChangeKind, ChecksumAlgorithm, ClientConfig, ClientError, NewFile, RemoteFsClient, RemoteFsError, ReplayAgent, WatchEvent};
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use bytes::Bytes;
 
+/// Files up to this size are uploaded by `put` in batches
+const SMALL_FILE_LIMIT: u64 = 1024 * 1024;
+
 #[derive(Parser)]
 #[command(name = "remotefs-client")]
 #[command(about = "RemoteFS client for interacting with remote filesystem agents")]
@@ -18,6 +25,10 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub verbose: bool,
     
+    /// Record the messages exchanged with agents to this file
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -43,6 +54,19 @@ pub enum Commands {
         #[arg(short, long)]
         data: Option<String>,
     },
+    /// Upload a local file, or a directory tree with --recursive
+    Put {
+        /// Local file or directory to upload
+        local: PathBuf,
+        /// Remote path to upload to
+        remote: String,
+        /// Upload directories and their contents
+        #[arg(short, long)]
+        recursive: bool,
+        /// Replace remote files that already exist
+        #[arg(long)]
+        overwrite: bool,
+    },
     /// List directory contents
     List {
         /// Directory path to list
@@ -56,6 +80,14 @@ pub enum Commands {
         #[arg(short, long)]
         follow_symlinks: bool,
     },
+    /// Print a file's checksum, computed by the agent
+    Checksum {
+        /// File to checksum
+        path: String,
+        /// Digest to compute: sha256 or blake3
+        #[arg(short, long, default_value = "sha256")]
+        algorithm: ChecksumAlgorithm,
+    },
     /// Create a directory
     Mkdir {
         /// Directory path to create
@@ -80,6 +112,9 @@ pub enum Commands {
         source: String,
         /// Destination path
         destination: String,
+        /// Fail instead of replacing an existing destination
+        #[arg(short, long)]
+        no_clobber: bool,
     },
     /// Copy a file
     Copy {
@@ -88,19 +123,75 @@ pub enum Commands {
         /// Destination path
         destination: String,
     },
+    /// Find files by name and content, searched on the agent
+    Search {
+        /// Directory to search below
+        root: String,
+        /// Glob file names must match, or paths below the root if it holds a '/'
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Regular expression lines of the files must match
+        #[arg(short, long)]
+        content: Option<String>,
+        /// Stop after this many matches
+        #[arg(short, long)]
+        max_results: Option<u32>,
+    },
+    /// Print changes under a path as they happen, until interrupted
+    Watch {
+        /// File or directory to watch
+        path: String,
+        /// Include changes in subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// List the agents connected to the relay
+    Agents,
+    /// List the paths an agent exports
+    Exports {
+        /// Agent to ask (any agent if not given)
+        agent: Option<String>,
+    },
     /// Show client statistics
     Stats,
     /// Show connection status
     Status,
+    /// Serve a recording as a mock agent, answering requests as recorded
+    Replay {
+        /// Recording made with --record
+        recording: PathBuf,
+        /// Address to accept client connections on
+        #[arg(long, default_value = "127.0.0.1:9001")]
+        listen: String,
+    },
 }
 
+/// Run the command `args` names
 pub async fn run(args: CliArgs) -> Result<()> {
+    // Initialize tracing
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    
     // Load configuration
-    let config = if let Some(config_path) = args.config {
+    let mut config = if let Some(config_path) = args.config {
         ClientConfig::from_file(config_path)?
     } else {
         ClientConfig::default()
     };
+    if args.record.is_some() {
+        config.logging.record_file = args.record;
+    }
+    
+    // Stands in for the agents rather than connecting to them
+    if let Commands::Replay { recording, listen } = args.command {
+        let agent = std::sync::Arc::new(ReplayAgent::load(&recording)?);
+        let listener = tokio::net::TcpListener::bind(&listen).await?;
+        println!("Replaying {} on ws://{}", recording.display(), listener.local_addr()?);
+        agent.serve(listener).await?;
+        return Ok(());
+    }
     
     // Create and initialize client
     let client = RemoteFsClient::new(config)?;
@@ -111,34 +202,72 @@ pub async fn run(args: CliArgs) -> Result<()> {
     // Execute command
     match args.command {
         Commands::Read { path, output } => {
-            let data = client.read_file(&path).await?;
-            
             if let Some(ref output_path) = output {
-                tokio::fs::write(output_path, data).await?;
+                // Streamed, so that large files need not fit in memory
+                let mut file = tokio::fs::File::create(output_path).await?;
+                client.read_file_to(&path, &mut file).await?;
                 info!("File written to {:?}", output);
             } else {
                 // Print to stdout
+                let data = client.read_file(&path).await?;
                 print!("{}", String::from_utf8_lossy(&data));
             }
         }
         
         Commands::Write { path, input, data } => {
-            let content = if let Some(input_path) = input {
-                tokio::fs::read(input_path).await?
+            // Files and stdin are streamed, so that they need not fit in memory
+            if let Some(input_path) = input {
+                client.write_file_from(&path, &mut tokio::fs::File::open(input_path).await?, true).await?;
             } else if let Some(data_str) = data {
-                data_str.into_bytes()
+                client.write_file(&path, Bytes::from(data_str.into_bytes())).await?;
             } else {
-                // Read from stdin
-                use tokio::io::{AsyncReadExt, stdin};
-                let mut buffer = Vec::new();
-                stdin().read_to_end(&mut buffer).await?;
-                buffer
-            };
-            
-            client.write_file(&path, Bytes::from(content)).await?;
+                client.write_file_from(&path, &mut tokio::io::stdin(), true).await?;
+            }
             info!("File written successfully");
         }
         
+        Commands::Put { local, remote, recursive, overwrite } => {
+            let metadata = tokio::fs::metadata(&local).await?;
+            if metadata.is_dir() && !recursive {
+                anyhow::bail!("{} is a directory; use --recursive to upload it", local.display());
+            }
+            
+            let mut directories = Vec::new();
+            let mut files = Vec::new();
+            collect_local_tree(&local, &remote, &mut directories, &mut files)?;
+            
+            // Parents first, so that files can be written into them
+            for directory in &directories {
+                match client.create_directory(directory).await {
+                    Err(e) if matches!(e.cause(), ClientError::RemoteFs(RemoteFsError::AlreadyExists(_))) => {}
+                    result => result?,
+                }
+            }
+            
+            // Small files go in batches, saving a round trip per file
+            let (small, large): (Vec<_>, Vec<_>) = files.into_iter().partition(|(_, _, len)| *len <= SMALL_FILE_LIMIT);
+            let mut batch = Vec::with_capacity(small.len());
+            for (local_path, remote_path, _) in small {
+                let mode = std::fs::metadata(&local_path)?.permissions().mode() & 0o777;
+                batch.push(NewFile { path: remote_path, data: tokio::fs::read(&local_path).await?, mode: Some(mode) });
+            }
+            let paths: Vec<String> = batch.iter().map(|file| file.path.clone()).collect();
+            let failures = client.batch_create_files(batch, overwrite).await?;
+            for failure in &failures {
+                eprintln!("{}: {}", paths[failure.index as usize], failure.error);
+            }
+            
+            for (local_path, remote_path, _) in &large {
+                client.write_file_from(remote_path, &mut tokio::fs::File::open(local_path).await?, true).await?;
+            }
+            
+            let uploaded = paths.len() - failures.len() + large.len();
+            println!("Uploaded {} files and {} directories", uploaded, directories.len());
+            if !failures.is_empty() {
+                anyhow::bail!("{} files were not uploaded", failures.len());
+            }
+        }
+        
         Commands::List { path } => {
             let entries = client.list_directory(&path).await?;
             
@@ -159,6 +288,12 @@ pub async fn run(args: CliArgs) -> Result<()> {
             println!("Created: {:?}", metadata.created);
         }
         
+        Commands::Checksum { path, algorithm } => {
+            // Same layout as sha256sum and b3sum, so their --check can read it
+            let checksum = client.checksum(&path, algorithm).await?;
+            println!("{}  {}", checksum, path);
+        }
+        
         Commands::Mkdir { path, mode } => {
             let mode_value = u32::from_str_radix(&mode, 8)
                 .map_err(|_| anyhow::anyhow!("Invalid mode: {}", mode))?;
@@ -177,8 +312,8 @@ pub async fn run(args: CliArgs) -> Result<()> {
             info!("Directory deleted successfully");
         }
         
-        Commands::Move { source, destination } => {
-            client.move_path(&source, &destination).await?;
+        Commands::Move { source, destination, no_clobber } => {
+            client.move_path_with_options(&source, &destination, no_clobber).await?;
             info!("File moved successfully");
         }
         
@@ -187,6 +322,56 @@ pub async fn run(args: CliArgs) -> Result<()> {
             info!("File copied successfully");
         }
         
+        Commands::Search { root, name, content, max_results } => {
+            let mut matches = client.search_files(&root, name.as_deref(), content.as_deref(), max_results).await?;
+            while let Some(page) = matches.next_page().await {
+                for found in page? {
+                    match (found.line_number, found.line) {
+                        (Some(line_number), Some(line)) => println!("{}:{}:{}", found.path, line_number, line),
+                        _ => println!("{}", found.path),
+                    }
+                }
+            }
+            if matches.truncated() {
+                eprintln!("Search stopped at its result limit");
+            }
+        }
+        
+        Commands::Watch { path, recursive } => {
+            let mut changes = client.watch(&path, recursive).await?;
+            while let Some(event) = changes.next_event().await {
+                match event? {
+                    WatchEvent::FileChanged { path, kind: ChangeKind::Renamed { from }, .. } => {
+                        println!("renamed\t{} -> {}", from, path);
+                    }
+                    WatchEvent::FileChanged { path, kind, .. } => {
+                        println!("{}\t{}", format!("{:?}", kind).to_lowercase(), path);
+                    }
+                    WatchEvent::DirectoryChanged { path } => println!("listing\t{}", path),
+                }
+            }
+        }
+        
+        Commands::Agents => {
+            for agent in client.list_agents().await? {
+                let ready = agent.paths.iter().filter(|path| path.is_ready()).count();
+                let capabilities: Vec<&str> = agent.capabilities.iter().map(|capability| capability.as_str()).collect();
+                println!("{}", agent.agent_id);
+                println!("  Capabilities: {}", capabilities.join(", "));
+                println!("  Paths ready: {}/{}", ready, agent.paths.len());
+            }
+        }
+        
+        Commands::Exports { agent } => {
+            for export in client.list_exports(agent.as_deref()).await? {
+                let access = if export.read_only { "read-only" } else { "read-write" };
+                match export.available_space {
+                    Some(available) => println!("{}\t{}\t{}\t{} MB free", export.name, export.path, access, available / (1024 * 1024)),
+                    None => println!("{}\t{}\t{}", export.name, export.path, access),
+                }
+            }
+        }
+        
         Commands::Stats => {
             let stats = client.get_stats().await;
             
@@ -207,7 +392,29 @@ pub async fn run(args: CliArgs) -> Result<()> {
             for (agent_id, state) in statuses {
                 println!("  {}: {:?}", agent_id, state);
             }
+            
+            // Planned work is shown as a banner so it is not mistaken for an outage
+            match client.maintenance().await {
+                Ok(windows) => {
+                    let now = chrono::Utc::now();
+                    for window in windows {
+                        let target = match &window.agent_id {
+                            Some(agent_id) => format!("agent {}", agent_id),
+                            None => "the relay".to_string(),
+                        };
+                        let until = window.ends_at.map(|ends_at| format!(" until {}", ends_at)).unwrap_or_default();
+                        if window.is_active_at(now) {
+                            println!("MAINTENANCE IN PROGRESS on {}{}: {}", target, until, window.message);
+                        } else {
+                            println!("Maintenance scheduled on {} from {}{}: {}", target, window.starts_at, until, window.message);
+                        }
+                    }
+                }
+                Err(e) => println!("Maintenance status unavailable: {}", e),
+            }
         }
+        
+        Commands::Replay { .. } => unreachable!("replays are served before connecting"),
     }
     
     // Shutdown client
@@ -215,3 +422,26 @@ pub async fn run(args: CliArgs) -> Result<()> {
     
     Ok(())
 }
+
+/// Collect the directories and files under `local`, paired with their
+/// remote paths under `remote`; directories come before their contents
+fn collect_local_tree(
+    local: &Path,
+    remote: &str,
+    directories: &mut Vec<String>,
+    files: &mut Vec<(PathBuf, String, u64)>,
+) -> Result<()> {
+    let metadata = std::fs::metadata(local)?;
+    if !metadata.is_dir() {
+        files.push((local.to_path_buf(), remote.to_string(), metadata.len()));
+        return Ok(());
+    }
+    
+    directories.push(remote.to_string());
+    for entry in std::fs::read_dir(local)? {
+        let entry = entry?;
+        let remote_path = format!("{}/{}", remote.trim_end_matches('/'), entry.file_name().to_string_lossy());
+        collect_local_tree(&entry.path(), &remote_path, directories, files)?;
+    }
+    Ok(())
+}
@@ -1,13 +1,22 @@
-use crate::config::{ClientConfig, RetryStrategy};
-use crate::connection::{ConnectionPool, AgentConnection, ConnectionState};
+use crate::config::{AgentConfig, ClientConfig, RetryStrategy};
+use crate::discovery::discover_relay;
+use crate::connection::{ConnectionPool, AgentConnection, AgentNotice, Announcements, ConnectionState, ResponseStream};
 use crate::error::{ClientError, ClientResult};
+use crate::local::LocalFiles;
+use crate::recording::Recorder;
+use crate::rewrite::PathRewriter;
+use remotefs_common::checksum::Checksum;
+use remotefs_common::delta::{self, DeltaOp, FileSignature};
 use remotefs_common::protocol::{
-    Message, FileMetadata, DirEntry, generate_request_id
+    Message, ErrorCode, RequestId, ChecksumAlgorithm, FileLock, LockOwner, OpenFlags, ChangeKind, FileMetadata, DirEntry, SearchMatch, MetadataUpdate, XattrSetMode, CallerIdentity, ChangeSet, BackupEntry, TransactionOp, OutputStream, ExportInfo, SpaceInfo, AgentInfo, MaintenanceWindow, NewFile, BatchFailure, TreeProgress, MAX_BATCH_FILES, MAX_BATCH_BYTES, MAX_BATCH_OPERATIONS, MAX_STREAM_CHUNK, generate_request_id
 };
+use chrono::{DateTime, Utc};
+use std::ops::Range;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use tokio::sync::{Mutex, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::RwLock;
 use tokio::time::sleep;
 use tracing::{debug, info, warn};
 use bytes::Bytes;
@@ -18,10 +27,19 @@ pub struct RemoteFsClient {
     config: ClientConfig,
     
     /// Connection pool for managing agent connections
-    connection_pool: ConnectionPool,
+    connection_pool: Arc<ConnectionPool>,
     
     /// Client statistics
     stats: Arc<RwLock<ClientStats>>,
+    
+    /// Local user requests are made on behalf of, for shared mounts
+    caller: Option<CallerIdentity>,
+    
+    /// Socket of an agent on this host that reads are served through
+    local: Option<Arc<LocalFiles>>,
+    
+    /// The configured `path_rewrites`
+    rewriter: PathRewriter,
 }
 
 /// Client statistics
@@ -34,24 +52,63 @@ pub struct ClientStats {
     pub bytes_written: u64,
     pub avg_response_time_ms: f64,
     pub active_connections: u32,
+    /// Reads served from a descriptor passed by an agent on this host
+    pub local_reads: u64,
+    /// Requests downgraded to a legacy message because the agent lacked a feature
+    pub protocol_fallbacks: u64,
 }
 
+/// Entries per page when a directory too large for one listing is paged
+const LISTING_PAGE_SIZE: u32 = 1000;
+
+/// Chunks of an upload read and sent at once
+const WRITE_WINDOW: usize = 8;
+
 impl RemoteFsClient {
     /// Create a new RemoteFS client
     pub fn new(config: ClientConfig) -> ClientResult<Self> {
         config.validate()?;
         
-        let connection_pool = ConnectionPool::new(config.connection.clone());
+        let mut connection_pool = ConnectionPool::new(config.connection.clone());
+        let mut local = config.connection.local_socket.as_ref().map(|path| Arc::new(LocalFiles::new(path)));
+        if let Some(record_file) = &config.logging.record_file {
+            connection_pool = connection_pool.with_recorder(Recorder::create(record_file)?);
+            // Local reads bypass the connections, so they would be missing from the recording
+            local = None;
+        }
         
+        let rewriter = PathRewriter::new(&config.path_rewrites);
         let client = Self {
             config,
-            connection_pool,
+            connection_pool: Arc::new(connection_pool),
             stats: Arc::new(RwLock::new(ClientStats::default())),
+            caller: None,
+            local,
+            rewriter,
         };
         
         Ok(client)
     }
     
+    /// Client sharing this client's connections whose requests are made on
+    /// behalf of `caller`, so the agent applies that user's access rules
+    pub fn with_caller(&self, caller: CallerIdentity) -> Self {
+        Self {
+            config: self.config.clone(),
+            connection_pool: Arc::clone(&self.connection_pool),
+            stats: Arc::clone(&self.stats),
+            caller: Some(caller),
+            // The agent checks local opens for this process's user, not the caller
+            local: None,
+            rewriter: self.rewriter.clone(),
+        }
+    }
+    
+    /// Local user requests are made on behalf of, if any
+    pub fn caller(&self) -> Option<&CallerIdentity> {
+        self.caller.as_ref()
+    }
+    
     /// Initialize the client and connect to agents
     pub async fn initialize(&self) -> ClientResult<()> {
         info!("Initializing RemoteFS client with {} agents", self.config.agents.len());
@@ -61,20 +118,40 @@ impl RemoteFsClient {
             self.connection_pool.add_agent(agent_config.clone()).await;
         }
         
+        // Add the nearest relay from the discovery directory
+        if let Some(discovery) = &self.config.discovery {
+            match discover_relay(discovery).await {
+                Ok(relay) => {
+                    self.connection_pool.add_agent(AgentConfig {
+                        id: relay.id,
+                        url: relay.url,
+                        auth: self.config.auth.clone(),
+                        weight: 1,
+                        enabled: true,
+                    }).await;
+                }
+                Err(e) if !self.config.enabled_agents().is_empty() => {
+                    warn!("Relay discovery failed, using configured agents only: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        
         // Connect to all agents
+        let agent_ids = self.connection_pool.agent_ids().await;
         let results = self.connection_pool.connect_all().await;
         let mut successful_connections = 0;
         let mut failed_connections = 0;
         
-        for (i, result) in results.iter().enumerate() {
+        for (agent_id, result) in agent_ids.iter().zip(results.iter()) {
             match result {
                 Ok(()) => {
                     successful_connections += 1;
-                    info!("Successfully connected to agent {}", self.config.agents[i].id);
+                    info!("Successfully connected to agent {}", agent_id);
                 }
                 Err(e) => {
                     failed_connections += 1;
-                    warn!("Failed to connect to agent {}: {}", self.config.agents[i].id, e);
+                    warn!("Failed to connect to agent {}: {}", agent_id, e);
                 }
             }
         }
@@ -93,6 +170,32 @@ impl RemoteFsClient {
         Ok(())
     }
     
+    /// Re-establish all agent connections, e.g. after the host wakes from sleep
+    ///
+    /// Succeeds if at least one agent could be reached.
+    pub async fn reconnect(&self) -> ClientResult<()> {
+        info!("Reconnecting to all agents");
+        
+        let agent_ids = self.connection_pool.agent_ids().await;
+        let results = self.connection_pool.reconnect_all().await;
+        let mut successful_connections = 0;
+        for (agent_id, result) in agent_ids.iter().zip(results.iter()) {
+            match result {
+                Ok(()) => successful_connections += 1,
+                Err(e) => warn!("Failed to reconnect to agent {}: {}", agent_id, e),
+            }
+        }
+        
+        if successful_connections == 0 {
+            return Err(ClientError::Connection(
+                "Failed to reconnect to any agents".to_string()
+            ));
+        }
+        
+        info!("Reconnected to {}/{} agents", successful_connections, results.len());
+        Ok(())
+    }
+    
     /// Shutdown the client and disconnect from all agents
     pub async fn shutdown(&self) -> ClientResult<()> {
         info!("Shutting down RemoteFS client");
@@ -108,6 +211,20 @@ impl RemoteFsClient {
         Ok(())
     }
     
+    /// Receive the events agents broadcast from now on
+    ///
+    /// The relay only delivers an agent's events to clients that have sent
+    /// it requests. A subscriber that falls far behind misses the oldest
+    /// events it has not read.
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<AgentNotice> {
+        self.connection_pool.announcements().subscribe_events()
+    }
+    
+    /// Agent events and maintenance windows pushed to this client
+    pub fn announcements(&self) -> &Announcements {
+        self.connection_pool.announcements()
+    }
+    
     /// Read a file from the remote filesystem
     pub async fn read_file<P: AsRef<Path>>(&self, path: P) -> ClientResult<Bytes> {
         self.read_file_range(path, None, None).await
@@ -122,18 +239,66 @@ impl RemoteFsClient {
     ) -> ClientResult<Bytes> {
         let path_str = path.as_ref().to_string_lossy().to_string();
         
+        // Anything the local agent refuses gets its usual answer through the relay
+        if let Some(local) = &self.local {
+            let local_path = self.rewriter.rewrite(&path_str).unwrap_or_else(|| path_str.clone());
+            match local.read(&local_path, offset.unwrap_or(0), length).await {
+                Ok(data) => {
+                    let mut stats = self.stats.write().await;
+                    stats.bytes_read += data.len() as u64;
+                    stats.local_reads += 1;
+                    return Ok(Bytes::from(data));
+                }
+                Err(e) => debug!("Local read of {} failed, reading through the relay: {}", path_str, e),
+            }
+        }
+        
+        let offset = offset.unwrap_or(0);
+        match self.read_range_once(&path_str, offset, length).await {
+            // Reads too large for one response are made in ranges the agent allows
+            Err(e) => match e.cause() {
+                ClientError::LimitExceeded { limit, max, .. } if limit == "response_bytes" && *max > 0 => {
+                    debug!("Reading {} in ranges of {} bytes", path_str, max);
+                    self.read_in_ranges(&path_str, offset, length, *max).await
+                }
+                _ => Err(e),
+            },
+            result => result,
+        }
+    }
+    
+    /// Read up to `max` bytes at a time until `length` bytes, or the rest of
+    /// the file, have been read
+    async fn read_in_ranges(&self, path: &str, mut offset: u64, length: Option<u64>, max: u64) -> ClientResult<Bytes> {
+        let mut data = Vec::new();
+        let mut remaining = length.unwrap_or(u64::MAX);
+        while remaining > 0 {
+            let range = remaining.min(max);
+            let chunk = self.read_range_once(path, offset, Some(range)).await?;
+            data.extend_from_slice(&chunk);
+            if (chunk.len() as u64) < range {
+                break;
+            }
+            offset += range;
+            remaining -= range;
+        }
+        Ok(Bytes::from(data))
+    }
+    
+    /// Read a file range through the relay with a single request
+    async fn read_range_once(&self, path: &str, offset: u64, length: Option<u64>) -> ClientResult<Bytes> {
         let request = Message::ReadFile {
             request_id: generate_request_id(),
-            path: path_str.clone(),
-            offset: offset.unwrap_or(0),
+            path: path.to_string(),
+            offset,
             length: length.map(|l| l as u32).unwrap_or(u32::MAX),
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
                 match response {
@@ -159,6 +324,9 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Offline (archived) files and reads beyond the agent's
+                // limits are refused with an error code
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
                     "Unexpected response for read file request".to_string()
                 )),
@@ -166,6 +334,223 @@ impl RemoteFsClient {
             }
         }).await
     }
+
+    /// Stream `length` bytes of a file from `offset`, or the rest of it, in
+    /// chunks of up to `MAX_STREAM_CHUNK` bytes
+    ///
+    /// The agent only sends a few chunks ahead of those taken from the
+    /// stream, so a large file never has to fit in memory.
+    pub async fn read_file_stream<P: AsRef<Path>>(&self, path: P, offset: u64, length: Option<u64>) -> ClientResult<FileChunks> {
+        let request = Message::ReadFileStream {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            offset,
+            length,
+            chunk_size: MAX_STREAM_CHUNK,
+        };
+
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let responses = conn.send_streaming_request((*request).clone()).await?;
+                Ok(FileChunks { responses, acks: conn.message_sender(), next_sequence: 0 })
+            }
+        }).await
+    }
+
+    /// Copy a whole file into `writer` as it is streamed, returning the
+    /// bytes copied
+    ///
+    /// Agents that cannot stream files are read from in ranges instead.
+    pub async fn read_file_to<P, W>(&self, path: P, writer: &mut W) -> ClientResult<u64>
+    where
+        P: AsRef<Path>,
+        W: AsyncWrite + Unpin,
+    {
+        let mut chunks = self.read_file_stream(&path, 0, None).await?;
+        let mut copied = 0;
+        while let Some(chunk) = chunks.next_chunk().await {
+            let chunk = match chunk {
+                Err(e) if copied == 0 && matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                    warn!("Falling back to ranged reads of {}: {}", path.as_ref().display(), e);
+                    self.stats.write().await.protocol_fallbacks += 1;
+                    return self.read_ranges_to(&path, writer).await;
+                }
+                chunk => chunk?,
+            };
+            writer.write_all(&chunk).await?;
+            copied += chunk.len() as u64;
+        }
+        writer.flush().await?;
+
+        self.stats.write().await.bytes_read += copied;
+        Ok(copied)
+    }
+
+    /// Copy a whole file into `writer` one `MAX_STREAM_CHUNK` range at a time
+    async fn read_ranges_to<P, W>(&self, path: P, writer: &mut W) -> ClientResult<u64>
+    where
+        P: AsRef<Path>,
+        W: AsyncWrite + Unpin,
+    {
+        let range = MAX_STREAM_CHUNK as u64;
+        let mut copied = 0;
+        loop {
+            let chunk = self.read_file_range(&path, Some(copied), Some(range)).await?;
+            writer.write_all(&chunk).await?;
+            copied += chunk.len() as u64;
+            if (chunk.len() as u64) < range {
+                break;
+            }
+        }
+        writer.flush().await?;
+        Ok(copied)
+    }
+
+    /// Digest of a file, computed by the agent so the file is not transferred
+    pub async fn checksum<P: AsRef<Path>>(&self, path: P, algorithm: ChecksumAlgorithm) -> ClientResult<Checksum> {
+        self.checksum_range(path, algorithm, 0, None).await
+    }
+    
+    /// Digest of `length` bytes of a file from `offset`, or of the rest of
+    /// the file without a length
+    pub async fn checksum_range<P: AsRef<Path>>(
+        &self,
+        path: P,
+        algorithm: ChecksumAlgorithm,
+        offset: u64,
+        length: Option<u64>,
+    ) -> ClientResult<Checksum> {
+        let request = Message::ComputeChecksum {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            algorithm,
+            offset,
+            length,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::ChecksumResponse { success: true, digest: Some(digest), length, .. } => {
+                        Ok(Checksum { algorithm, digest, length })
+                    }
+                    Message::ChecksumResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    // Offline files, and agents that cannot compute checksums
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for checksum request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Take an advisory lock on a byte range of a file without waiting,
+    /// returning the lock in the way if another owner holds one
+    ///
+    /// A lock replaces the owner's locks on the same bytes, so a read lock
+    /// over a write lock downgrades it. Locks last until unlocked or until
+    /// this client disconnects from the relay.
+    pub async fn lock_file<P: AsRef<Path>>(&self, path: P, lock: FileLock) -> ClientResult<Option<FileLock>> {
+        let request = Message::LockFile {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            lock,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::LockFileResponse { success: true, conflict, .. } => Ok(conflict),
+                    Message::LockFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    // Agents that do not keep locks
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for lock request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Release the locks of `owner` on `length` bytes of a file from
+    /// `start`, or on the rest of the file if `length` is 0
+    pub async fn unlock_file<P: AsRef<Path>>(&self, path: P, owner: u64, start: u64, length: u64) -> ClientResult<()> {
+        let request = Message::UnlockFile {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            owner: LockOwner { session: String::new(), owner },
+            start,
+            length,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::UnlockFileResponse { success: true, .. } => Ok(()),
+                    Message::UnlockFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for unlock request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// A lock of another owner that would keep `lock` from being taken,
+    /// as `F_GETLK` reports
+    pub async fn test_lock<P: AsRef<Path>>(&self, path: P, lock: FileLock) -> ClientResult<Option<FileLock>> {
+        let request = Message::TestLock {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            lock,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::TestLockResponse { success: true, conflict, .. } => Ok(conflict),
+                    Message::TestLockResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for lock test".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
     
     /// Write data to a file on the remote filesystem
     pub async fn write_file<P: AsRef<Path>>(
@@ -176,6 +561,145 @@ impl RemoteFsClient {
         self.write_file_at(path, data, None, true).await
     }
     
+    /// Open a file and keep it open on the agent until `close_file`,
+    /// returning its handle and metadata
+    ///
+    /// Reads and writes through the handle reach the file even after it is
+    /// renamed or removed. Handles last until closed or until this client
+    /// disconnects from the relay.
+    pub async fn open_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        flags: OpenFlags,
+        mode: u32,
+    ) -> ClientResult<(RequestId, FileMetadata)> {
+        let request = Message::OpenFile {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            flags,
+            mode,
+            session: String::new(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::OpenFileResponse { request_id, success: true, metadata: Some(metadata), .. } => {
+                        Ok((request_id, metadata))
+                    }
+                    Message::OpenFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    // Missing files, and agents that do not keep files open
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for open request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Read up to `length` bytes from `offset` through an open handle;
+    /// fewer are returned at the end of the file
+    pub async fn read_handle(&self, handle: RequestId, offset: u64, length: u32) -> ClientResult<Bytes> {
+        let request = Arc::new(Message::ReadHandle {
+            request_id: generate_request_id(),
+            handle,
+            offset,
+            length,
+        });
+        
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::ReadFileResponse { success: true, data: Some(data), .. } => {
+                        self.stats.write().await.bytes_read += data.len() as u64;
+                        Ok(Bytes::from(data))
+                    }
+                    Message::ReadFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for handle read".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Write data at `offset` through an open handle, or at the end of the
+    /// file if it was opened to append
+    pub async fn write_handle(&self, handle: RequestId, offset: u64, data: Bytes, sync: bool) -> ClientResult<()> {
+        let data_len = data.len();
+        let request = Arc::new(Message::WriteHandle {
+            request_id: generate_request_id(),
+            handle,
+            offset,
+            data: data.to_vec(),
+            sync,
+        });
+        
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::WriteFileResponse { success: true, .. } => {
+                        self.stats.write().await.bytes_written += data_len as u64;
+                        Ok(())
+                    }
+                    Message::WriteFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for handle write".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Close a handle from `open_file`
+    pub async fn close_file(&self, handle: RequestId) -> ClientResult<()> {
+        let request = Arc::new(Message::CloseFile {
+            request_id: generate_request_id(),
+            handle,
+        });
+        
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::CloseFileResponse { success: true, .. } => Ok(()),
+                    Message::CloseFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for close request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
     /// Write data to a file at a specific offset
     pub async fn write_file_at<P: AsRef<Path>>(
         &self,
@@ -195,11 +719,11 @@ impl RemoteFsClient {
             sync,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
             match response {
@@ -224,6 +748,8 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Refused by the agent's access rules before it was handled
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
                     "Unexpected response for write file request".to_string()
                 )),
@@ -231,79 +757,591 @@ impl RemoteFsClient {
         }
         }).await
     }
-    
-    /// List directory contents
-    pub async fn list_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<Vec<DirEntry>> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
+
+    /// Replace a file with everything `reader` yields, returning the bytes
+    /// written
+    ///
+    /// The file is uploaded in chunks of up to `MAX_STREAM_CHUNK` bytes, a
+    /// window of them at a time while the next window is read, so a large
+    /// file never has to fit in memory. With `sync` the file is synced to
+    /// disk once its last chunk is written. Agents that cannot take chunked
+    /// uploads are written to range by range, which leaves the tail of a
+    /// longer existing file in place.
+    pub async fn write_file_from<P, R>(&self, path: P, reader: &mut R, sync: bool) -> ClientResult<u64>
+    where
+        P: AsRef<Path>,
+        R: AsyncRead + Unpin,
+    {
+        let path = path.as_ref().to_string_lossy().to_string();
+        let mut window = read_window(reader).await?;
+        let mut ended = window_ended(&window);
+
+        // The first chunk creates the file, or cuts it short, so it goes
+        // before any other
+        let first = if window.is_empty() { Bytes::new() } else { window.remove(0) };
+        let last = ended && window.is_empty();
+        match self.write_chunk(&path, 0, 0, first.clone(), last, sync).await {
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                warn!("Falling back to ranged writes of {}: {}", path, e);
+                self.stats.write().await.protocol_fallbacks += 1;
+                window.insert(0, first);
+                return self.write_ranges_from(&path, window, reader, sync).await;
+            }
+            result => result?,
+        }
+        if last {
+            return Ok(first.len() as u64);
+        }
+
+        let mut sequence = 1;
+        let mut offset = first.len() as u64;
+        loop {
+            if ended {
+                self.write_chunks(&path, sequence, offset, &window, true, sync).await?;
+                return Ok(offset + window.iter().map(|chunk| chunk.len() as u64).sum::<u64>());
+            }
+            let (sent, next) = tokio::join!(
+                self.write_chunks(&path, sequence, offset, &window, false, sync),
+                read_window(reader),
+            );
+            sent?;
+            sequence += window.len() as u32;
+            offset += window.iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+            window = next?;
+            ended = window_ended(&window);
+        }
+    }
+
+    /// Replace a file with `data`, sending only the parts that differ from
+    /// the file the agent has, and return how much was sent
+    ///
+    /// The agent sends a signature of each block of its copy, and the file is
+    /// rebuilt from those blocks and the data around them, as rsync does.
+    /// This suits large files with small edits, such as VM images and
+    /// databases. A missing file, one sharing nothing with `data`, one that
+    /// changes while the delta is sent, and agents without `DeltaTransfer`
+    /// get `data` in full instead. Rebuilding replaces the file by renaming,
+    /// so other hard links to it keep the old content.
+    pub async fn write_file_delta<P: AsRef<Path>>(&self, path: P, data: Bytes, sync: bool) -> ClientResult<DeltaWrite> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        let length = data.len() as u64;
+        let signature = match self.file_signature(&path, delta::block_size_for(length)).await {
+            Ok(signature) => Some(signature),
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                warn!("Falling back to a full write of {}: {}", path, e);
+                self.stats.write().await.protocol_fallbacks += 1;
+                None
+            }
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotFound(_))) => None,
+            Err(e) => return Err(e),
+        };
         
-        let request = Message::ListDirectory {
+        let ops = signature.as_ref().map(|signature| (signature.block_size, delta::diff(signature, &data)));
+        if let Some((block_size, ops)) = ops.filter(|(_, ops)| ops.iter().any(|op| matches!(op, DeltaOp::Copy { .. }))) {
+            let checksum = Checksum::of(ChecksumAlgorithm::Blake3, &data).digest;
+            match self.write_delta(&path, block_size, ops, checksum, sync).await {
+                // Changed since the signature was taken
+                Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(_))) => {
+                    debug!("Delta write of {} failed, writing it in full: {}", path, e);
+                }
+                result => return result.map(|sent| DeltaWrite { length, sent }),
+            }
+        }
+        
+        self.write_file_from(&path, &mut &data[..], sync).await?;
+        Ok(DeltaWrite { length, sent: length })
+    }
+    
+    /// Checksums of each `block_size` bytes of a file, for a delta write
+    pub async fn file_signature<P: AsRef<Path>>(&self, path: P, block_size: u32) -> ClientResult<FileSignature> {
+        let request = Message::GetFileSignature {
             request_id: generate_request_id(),
-            path: path_str.clone(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            block_size,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
-            
-            match response {
-                Message::ListDirectoryResponse { 
-                    success: true, 
-                    entries: Some(entries), 
-                    .. 
-                } => Ok(entries),
-                Message::ListDirectoryResponse { 
-                    success: false, 
-                    error: Some(error), 
-                    .. 
-                } => {
-                    Err(ClientError::RemoteFs(
+                
+                match response {
+                    Message::FileSignatureResponse { success: true, signature: Some(signature), .. } => Ok(signature),
+                    Message::FileSignatureResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
                         remotefs_common::error::RemoteFsError::FileSystem(error)
-                    ))
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for signature request".to_string()
+                    )),
                 }
-                _ => Err(ClientError::InvalidResponse(
-                    "Unexpected response for list directory request".to_string()
-                )),
             }
-        }
         }).await
     }
     
-    /// Get file or directory metadata
-    pub async fn get_metadata<P: AsRef<Path>>(&self, path: P) -> ClientResult<FileMetadata> {
-        self.get_metadata_with_options(path, true).await
-    }
-    
-    /// Get file or directory metadata with options
-    pub async fn get_metadata_with_options<P: AsRef<Path>>(
+    /// Send a delta computed against a signature of the file, returning the
+    /// bytes of literal data sent
+    async fn write_delta(
         &self,
-        path: P,
-        follow_symlinks: bool,
-    ) -> ClientResult<FileMetadata> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        
-        let request = Message::GetMetadata {
+        path: &str,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+        checksum: Vec<u8>,
+        sync: bool,
+    ) -> ClientResult<u64> {
+        let sent = delta::literal_len(&ops);
+        let request = Message::WriteDelta {
             request_id: generate_request_id(),
-            path: path_str.clone(),
-            follow_symlinks,
+            path: path.to_string(),
+            block_size,
+            ops,
+            checksum,
+            sync,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
-            
+                
                 match response {
-                Message::GetMetadataResponse { 
-                    success: true, 
-                    metadata: Some(metadata), 
-                    .. 
-                } => Ok(metadata),
-                Message::GetMetadataResponse { 
+                    Message::WriteFileResponse { success: true, .. } => {
+                        self.stats.write().await.bytes_written += sent;
+                        Ok(sent)
+                    }
+                    Message::WriteFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for delta write".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+
+    /// Write `chunks` of an upload at once, from chunk `sequence` at
+    /// `offset`; with `last` the final one, or an empty chunk if there are
+    /// none, ends the upload once the others are written
+    async fn write_chunks(
+        &self,
+        path: &str,
+        sequence: u32,
+        offset: u64,
+        chunks: &[Bytes],
+        last: bool,
+        sync: bool,
+    ) -> ClientResult<()> {
+        let together = if last { chunks.len().saturating_sub(1) } else { chunks.len() };
+        let mut writes = Vec::with_capacity(together);
+        let mut at = offset;
+        for (index, chunk) in chunks[..together].iter().enumerate() {
+            writes.push(self.write_chunk(path, sequence + index as u32, at, chunk.clone(), false, false));
+            at += chunk.len() as u64;
+        }
+        futures::future::try_join_all(writes).await?;
+
+        if last {
+            let chunk = chunks.get(together).cloned().unwrap_or_default();
+            self.write_chunk(path, sequence + together as u32, at, chunk, true, sync).await?;
+        }
+        Ok(())
+    }
+
+    /// Write one chunk of an upload
+    async fn write_chunk(
+        &self,
+        path: &str,
+        sequence: u32,
+        offset: u64,
+        data: Bytes,
+        last: bool,
+        sync: bool,
+    ) -> ClientResult<()> {
+        let data_len = data.len();
+        let request = Message::WriteFileChunk {
+            request_id: generate_request_id(),
+            path: path.to_string(),
+            sequence,
+            offset,
+            data: data.to_vec(),
+            last,
+            sync,
+        };
+
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                match conn.send_request((*request).clone()).await? {
+                    Message::WriteFileResponse { success: true, .. } => {
+                        self.stats.write().await.bytes_written += data_len as u64;
+                        Ok(())
+                    }
+                    Message::WriteFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for write file chunk".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+
+    /// Write `window` and the rest of `reader` to a file with a `WriteFile`
+    /// per chunk
+    async fn write_ranges_from<R: AsyncRead + Unpin>(
+        &self,
+        path: &str,
+        mut window: Vec<Bytes>,
+        reader: &mut R,
+        sync: bool,
+    ) -> ClientResult<u64> {
+        if window.is_empty() {
+            self.write_file_at(path, Bytes::new(), Some(0), sync).await?;
+        }
+        let mut offset = 0;
+        loop {
+            let ended = window_ended(&window);
+            for chunk in window {
+                let length = chunk.len() as u64;
+                self.write_file_at(path, chunk, Some(offset), sync).await?;
+                offset += length;
+            }
+            if ended {
+                return Ok(offset);
+            }
+            window = read_window(reader).await?;
+        }
+    }
+
+    /// List directory contents
+    ///
+    /// Directories with more entries than the agent returns at once are
+    /// listed in pages instead.
+    pub async fn list_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<Vec<DirEntry>> {
+        match self.list_directory_once(path.as_ref()).await {
+            Err(e) if matches!(e.cause(), ClientError::LimitExceeded { limit, .. } if limit == "listing_entries") => {
+                debug!("Listing {} in pages: {}", path.as_ref().display(), e);
+                let mut pages = self.list_directory_pages(path, LISTING_PAGE_SIZE).await?;
+                let mut entries = Vec::new();
+                while let Some(page) = pages.next_page().await {
+                    entries.extend(page?);
+                }
+                Ok(entries)
+            }
+            result => result,
+        }
+    }
+    
+    /// List directory contents with a single whole-buffer request
+    async fn list_directory_once(&self, path: &Path) -> ClientResult<Vec<DirEntry>> {
+        let path_str = path.to_string_lossy().to_string();
+        
+        let request = Message::ListDirectory {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+            match response {
+                Message::ListDirectoryResponse { 
+                    success: true, 
+                    entries: Some(entries), 
+                    .. 
+                } => Ok(entries),
+                Message::ListDirectoryResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for list directory request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// List directory contents page by page
+    ///
+    /// Pages arrive as the agent reads the directory, so neither side holds
+    /// a large directory in memory at once. Agents too old to page a listing
+    /// get a whole-buffer `ListDirectory` instead, split into pages here.
+    /// That listing is received whole before it is counted, and refused if
+    /// it holds more than `max_fallback_entries` entries. Pages larger than
+    /// the agent allows are asked for again at the largest size it does.
+    pub async fn list_directory_pages<P: AsRef<Path>>(&self, path: P, page_size: u32) -> ClientResult<DirectoryPages> {
+        self.list_directory_pages_after(path, page_size, None).await
+    }
+    
+    /// List directory contents page by page, starting behind the entry named
+    /// `after`
+    ///
+    /// Entries are sorted by the bytes of their names, so the name of the
+    /// last entry read is a cursor that stays valid while the directory
+    /// changes: entries present throughout are listed exactly once, and
+    /// entries created or removed in between are listed if they sort after
+    /// the cursor and skipped otherwise. `after` need not exist any more.
+    pub async fn list_directory_pages_after<P: AsRef<Path>>(
+        &self,
+        path: P,
+        page_size: u32,
+        after: Option<&str>,
+    ) -> ClientResult<DirectoryPages> {
+        self.paged_listing(path.as_ref(), page_size, after, None).await
+    }
+    
+    /// List at most `max_entries` entries of a directory, starting behind the
+    /// entry named `after`
+    ///
+    /// Returns the entries and, if the directory has more, the cursor to pass
+    /// as `after` for the next part. Only the entries asked for are read, so
+    /// a reader working through a huge directory a part at a time, such as
+    /// the NFS server answering readdir, never lists all of it at once.
+    pub async fn list_directory_from<P: AsRef<Path>>(
+        &self,
+        path: P,
+        after: Option<&str>,
+        max_entries: u32,
+    ) -> ClientResult<(Vec<DirEntry>, Option<String>)> {
+        let max_entries = max_entries.max(1);
+        let mut pages = self.paged_listing(path.as_ref(), max_entries, after, Some(max_entries)).await?;
+        let mut entries = Vec::new();
+        while let Some(page) = pages.next_page().await {
+            entries.extend(page?);
+        }
+        Ok((entries, pages.cursor))
+    }
+    
+    /// Start a paged listing, adapting to agents that cannot page it or
+    /// allow smaller pages
+    async fn paged_listing(
+        &self,
+        path: &Path,
+        page_size: u32,
+        after: Option<&str>,
+        max_entries: Option<u32>,
+    ) -> ClientResult<DirectoryPages> {
+        let (first, responses) = match self.start_paged_listing(path, page_size, after, max_entries).await? {
+            (Some(Ok(Message::Error { code: ErrorCode::InvalidMessage, message, details: Some(details), .. })), _)
+                if details.get("limit").is_some_and(|limit| limit == "page_entries") =>
+            {
+                let max = details.get("max").and_then(|max| max.parse().ok()).unwrap_or(1);
+                debug!("Asking for pages of {} entries instead: {}", max, message);
+                self.start_paged_listing(path, max, after, max_entries).await?
+            }
+            started => started,
+        };
+        
+        if let Some(Ok(Message::Error { code: ErrorCode::NotImplemented, message, .. })) = &first {
+            warn!("Falling back to a whole-buffer listing of {}: {}", path.display(), message);
+            self.stats.write().await.protocol_fallbacks += 1;
+            
+            // The whole listing is in memory by now; the limit only keeps
+            // callers from being handed more than they expect
+            let mut entries = self.list_directory_once(path).await?;
+            let limit = self.config.client.max_fallback_entries;
+            if entries.len() > limit {
+                return Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(format!(
+                    "Directory has {} entries, more than the {} accepted without paging", entries.len(), limit
+                ))));
+            }
+            // Same order and cursor as agents that page themselves
+            entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+            if let Some(after) = after {
+                entries.retain(|entry| entry.name.as_bytes() > after.as_bytes());
+            }
+            let mut cursor = None;
+            if let Some(max_entries) = max_entries.map(|max| max as usize).filter(|max| entries.len() > *max) {
+                entries.truncate(max_entries);
+                cursor = entries.last().map(|entry| entry.name.clone());
+            }
+            let mut pages = DirectoryPages::buffered(entries, page_size);
+            pages.cursor = cursor;
+            return Ok(pages);
+        }
+        
+        Ok(DirectoryPages::streamed(first, responses))
+    }
+    
+    /// Send a paged listing request, returning its first response and the
+    /// stream of the rest
+    async fn start_paged_listing(
+        &self,
+        path: &Path,
+        page_size: u32,
+        after: Option<&str>,
+        max_entries: Option<u32>,
+    ) -> ClientResult<(Option<ClientResult<Message>>, ResponseStream)> {
+        let request = Message::ListDirectoryPaged {
+            request_id: generate_request_id(),
+            path: path.to_string_lossy().to_string(),
+            page_size,
+            after: after.map(str::to_string),
+            max_entries,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let mut responses = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                conn.send_streaming_request((*request).clone()).await
+            }
+        }).await?;
+        
+        let first = responses.next().await;
+        Ok((first, responses))
+    }
+    
+    /// List everything below a directory, page by page
+    ///
+    /// Entry names are paths relative to `path`, and pages arrive as the
+    /// agent walks the tree, depth first. `max_depth` of 1 lists only `path`
+    /// itself; `None` walks the whole tree. Directories the agent does not
+    /// let this client read are listed but not walked into.
+    pub async fn walk_directory<P: AsRef<Path>>(
+        &self,
+        path: P,
+        max_depth: Option<u32>,
+        follow_symlinks: bool,
+    ) -> ClientResult<DirectoryPages> {
+        let request = Message::WalkDirectory {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            max_depth,
+            follow_symlinks,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let responses = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                conn.send_streaming_request((*request).clone()).await
+            }
+        }).await?;
+        
+        Ok(DirectoryPages::streamed(None, responses))
+    }
+    
+    /// Search the tree below `root` on the agent for files named like
+    /// `name_glob` and, with `content_regex`, for their lines matching it
+    ///
+    /// Matches arrive in pages as the agent finds them, without fetching
+    /// the files. A glob containing `/` is matched against paths relative
+    /// to `root` rather than file names.
+    pub async fn search_files<P: AsRef<Path>>(
+        &self,
+        root: P,
+        name_glob: Option<&str>,
+        content_regex: Option<&str>,
+        max_results: Option<u32>,
+    ) -> ClientResult<SearchMatches> {
+        let request = Message::SearchFiles {
+            request_id: generate_request_id(),
+            root: root.as_ref().to_string_lossy().to_string(),
+            name_glob: name_glob.map(str::to_string),
+            content_regex: content_regex.map(str::to_string),
+            max_results,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let responses = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                conn.send_streaming_request((*request).clone()).await
+            }
+        }).await?;
+        
+        Ok(SearchMatches { responses, truncated: false })
+    }
+    
+    /// Run a command from the agent's remote-exec whitelist, e.g. `git fetch`
+    /// in an exported repository
+    ///
+    /// Output arrives as the command produces it. `working_dir` is needed
+    /// unless the command has a configured directory, and `arguments` are
+    /// only accepted by commands that allow them.
+    pub async fn run_extended_operation<P: AsRef<Path>>(
+        &self,
+        name: &str,
+        arguments: Vec<String>,
+        working_dir: Option<P>,
+    ) -> ClientResult<CommandOutput> {
+        let request = Message::ExtendedOperation {
+            request_id: generate_request_id(),
+            name: name.to_string(),
+            arguments,
+            working_dir: working_dir.map(|dir| dir.as_ref().to_string_lossy().to_string()),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let responses = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                conn.send_streaming_request((*request).clone()).await
+            }
+        }).await?;
+        
+        Ok(CommandOutput { responses, exit_code: None })
+    }
+    
+    /// Get file or directory metadata
+    pub async fn get_metadata<P: AsRef<Path>>(&self, path: P) -> ClientResult<FileMetadata> {
+        self.get_metadata_with_options(path, true).await
+    }
+    
+    /// Get file or directory metadata with options
+    pub async fn get_metadata_with_options<P: AsRef<Path>>(
+        &self,
+        path: P,
+        follow_symlinks: bool,
+    ) -> ClientResult<FileMetadata> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::GetMetadata {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+            follow_symlinks,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::GetMetadataResponse { 
+                    success: true, 
+                    metadata: Some(metadata), 
+                    .. 
+                } => Ok(metadata),
+                Message::GetMetadataResponse { 
                     success: false, 
                     error: Some(error), 
                     .. 
@@ -312,6 +1350,7 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
                     "Unexpected response for get metadata request".to_string()
                 )),
@@ -320,38 +1359,1060 @@ impl RemoteFsClient {
         }).await
     }
     
-    /// Create a directory
-    pub async fn create_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
-        self.create_directory_with_mode(path, 0o755).await
+    /// Metadata of many paths at once, such as every entry of a directory,
+    /// with a result for each path in order
+    pub async fn get_metadata_batch<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        follow_symlinks: bool,
+    ) -> ClientResult<Vec<ClientResult<FileMetadata>>> {
+        let operations = paths.iter()
+            .map(|path| Message::GetMetadata {
+                request_id: generate_request_id(),
+                path: path.as_ref().to_string_lossy().to_string(),
+                follow_symlinks,
+            })
+            .collect();
+        
+        let responses = self.batch(operations).await?;
+        Ok(responses.into_iter()
+            .map(|response| match response {
+                Message::GetMetadataResponse { success: true, metadata: Some(metadata), .. } => Ok(metadata),
+                Message::GetMetadataResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                    remotefs_common::error::RemoteFsError::FileSystem(error)
+                )),
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for get metadata request".to_string()
+                )),
+            })
+            .collect())
     }
     
-    /// Create a directory with specific permissions
-    pub async fn create_directory_with_mode<P: AsRef<Path>>(
+    /// Send several independent requests in one round trip, returning the
+    /// response to each in order
+    ///
+    /// Each request must be [batchable](Message::is_batchable) and succeeds or
+    /// fails on its own, answered by its usual response or by an `Error`.
+    /// Requests past `MAX_BATCH_OPERATIONS` go in further batches, and agents
+    /// without batch support are sent the requests one by one.
+    pub async fn batch(&self, mut operations: Vec<Message>) -> ClientResult<Vec<Message>> {
+        if let Some(operation) = operations.iter().find(|operation| !operation.is_batchable()) {
+            return Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::Protocol(
+                format!("{} cannot be sent in a batch", operation.message_type())
+            )));
+        }
+        
+        let mut responses = Vec::with_capacity(operations.len());
+        while !operations.is_empty() {
+            let rest = operations.split_off(operations.len().min(MAX_BATCH_OPERATIONS));
+            let operations = std::mem::replace(&mut operations, rest);
+            let result = match self.send_batch(operations.clone()).await {
+                Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                    warn!("Falling back to sending {} requests one by one: {}", operations.len(), e);
+                    self.stats.write().await.protocol_fallbacks += 1;
+                    self.send_one_by_one(operations).await
+                }
+                result => result,
+            };
+            responses.extend(result?);
+        }
+        
+        Ok(responses)
+    }
+    
+    async fn send_batch(&self, operations: Vec<Message>) -> ClientResult<Vec<Message>> {
+        let request = Message::Batch {
+            request_id: generate_request_id(),
+            operations,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::BatchResponse { responses, .. } => Ok(responses),
+                    // Too many requests, or no agent that takes batches
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for batch request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    async fn send_one_by_one(&self, operations: Vec<Message>) -> ClientResult<Vec<Message>> {
+        let mut responses = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let request = Arc::new(self.as_caller(operation));
+            let response = self.execute_with_retry(request.request_id(), |connection| {
+                let request = request.clone();
+                async move {
+                    let conn = connection.read().await;
+                    conn.send_request((*request).clone()).await
+                }
+            }).await?;
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+    
+    /// Apply a partial metadata update; fields left as `None` are not changed
+    pub async fn set_metadata<P: AsRef<Path>>(
         &self,
         path: P,
-        mode: u32,
+        update: MetadataUpdate,
     ) -> ClientResult<()> {
         let path_str = path.as_ref().to_string_lossy().to_string();
         
-        let request = Message::CreateDirectory {
+        let request = Message::SetMetadata {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+            update,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::SetMetadataResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::SetMetadataResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for set metadata request".to_string()
+                )),
+                }
+            }
+        }).await
+    }
+    
+    /// Change permission bits (chmod)
+    pub async fn set_permissions<P: AsRef<Path>>(&self, path: P, mode: u32) -> ClientResult<()> {
+        self.set_metadata(path, MetadataUpdate {
+            permissions: Some(mode),
+            ..Default::default()
+        }).await
+    }
+    
+    /// Change owner and/or group (chown); `None` keeps the current value
+    pub async fn set_owner<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> ClientResult<()> {
+        self.set_metadata(path, MetadataUpdate {
+            uid,
+            gid,
+            ..Default::default()
+        }).await
+    }
+    
+    /// Change access and/or modification times (utimes); `None` keeps the current value
+    pub async fn set_times<P: AsRef<Path>>(
+        &self,
+        path: P,
+        accessed: Option<DateTime<Utc>>,
+        modified: Option<DateTime<Utc>>,
+    ) -> ClientResult<()> {
+        self.set_metadata(path, MetadataUpdate {
+            accessed,
+            modified,
+            ..Default::default()
+        }).await
+    }
+    
+    /// Apply a partial metadata update to `path` and everything below it,
+    /// as `chmod -R` or `chown -R` would, in one request
+    ///
+    /// The agent walks the tree itself and reports its progress about once
+    /// a second. Symbolic links below `path` are neither followed nor
+    /// changed, and entries the caller may not change are skipped and
+    /// counted as failed. Needs an agent announcing `MetadataTree`.
+    pub async fn set_metadata_tree<P: AsRef<Path>>(
+        &self,
+        path: P,
+        update: MetadataUpdate,
+    ) -> ClientResult<MetadataTreeChange> {
+        let request = Message::SetMetadataTree {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            update,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let responses = conn.send_streaming_request((*request).clone()).await?;
+                Ok(MetadataTreeChange { responses, cancel: conn.message_sender(), progress: TreeProgress::default() })
+            }
+        }).await
+    }
+    
+    /// Change the permission bits of a whole tree (chmod -R), returning how
+    /// many entries were changed and skipped
+    pub async fn set_permissions_recursive<P: AsRef<Path>>(&self, path: P, mode: u32) -> ClientResult<TreeProgress> {
+        self.set_metadata_tree(path, MetadataUpdate {
+            permissions: Some(mode),
+            ..Default::default()
+        }).await?.finish().await
+    }
+    
+    /// Change the owner and/or group of a whole tree (chown -R); `None`
+    /// keeps the current value
+    pub async fn set_owner_recursive<P: AsRef<Path>>(
+        &self,
+        path: P,
+        uid: Option<u32>,
+        gid: Option<u32>,
+    ) -> ClientResult<TreeProgress> {
+        self.set_metadata_tree(path, MetadataUpdate {
+            uid,
+            gid,
+            ..Default::default()
+        }).await?.finish().await
+    }
+    
+    /// Read an extended attribute, or `None` if the path has no such attribute
+    pub async fn get_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> ClientResult<Option<Vec<u8>>> {
+        let request = Message::GetXattr {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            name: name.to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::GetXattrResponse { 
+                    success: true, 
+                    value, 
+                    .. 
+                } => Ok(value),
+                Message::GetXattrResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for get xattr request".to_string()
+                )),
+                }
+            }
+        }).await
+    }
+    
+    /// Set an extended attribute
+    ///
+    /// Fails with `AlreadyExists` or `NotFound` when `mode` does not allow
+    /// creating or replacing the attribute.
+    pub async fn set_xattr<P: AsRef<Path>>(
+        &self,
+        path: P,
+        name: &str,
+        value: Vec<u8>,
+        mode: XattrSetMode,
+    ) -> ClientResult<()> {
+        let request = Message::SetXattr {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            name: name.to_string(),
+            value,
+            mode,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::SetXattrResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::SetXattrResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for set xattr request".to_string()
+                )),
+                }
+            }
+        }).await
+    }
+    
+    /// List the names of a path's extended attributes
+    pub async fn list_xattr<P: AsRef<Path>>(&self, path: P) -> ClientResult<Vec<String>> {
+        let request = Message::ListXattr {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::ListXattrResponse { 
+                    success: true, 
+                    names: Some(names), 
+                    .. 
+                } => Ok(names),
+                Message::ListXattrResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for list xattr request".to_string()
+                )),
+                }
+            }
+        }).await
+    }
+    
+    /// Remove an extended attribute, failing with `NotFound` if there is none
+    pub async fn remove_xattr<P: AsRef<Path>>(&self, path: P, name: &str) -> ClientResult<()> {
+        let request = Message::RemoveXattr {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            name: name.to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::RemoveXattrResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::RemoveXattrResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for remove xattr request".to_string()
+                )),
+                }
+            }
+        }).await
+    }
+    
+    /// Create an empty file with the permission bits `mode`, returning its
+    /// metadata
+    ///
+    /// An existing file is left as it is, or refused with `AlreadyExists` if
+    /// `exclusive`. Agents too old to create files get an empty write
+    /// instead, which truncates an existing file and leaves the mode to the
+    /// agent's umask.
+    pub async fn create_file<P: AsRef<Path>>(&self, path: P, mode: u32, exclusive: bool) -> ClientResult<FileMetadata> {
+        let request = Message::CreateFile {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            mode,
+            exclusive,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let result = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::CreateFileResponse { success: true, metadata: Some(metadata), .. } => Ok(metadata),
+                    Message::CreateFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    // Missing parents and existing files, and agents that cannot create files
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for create file request".to_string()
+                    )),
+                }
+            }
+        }).await;
+        
+        match result {
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                warn!("Falling back to an empty write to create {}: {}", path.as_ref().display(), e);
+                self.stats.write().await.protocol_fallbacks += 1;
+                self.write_file(&path, Bytes::new()).await?;
+                self.get_metadata(&path).await
+            }
+            result => result,
+        }
+    }
+    
+    /// Create a directory
+    pub async fn create_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
+        self.create_directory_with_mode(path, 0o755).await.map(|_| ())
+    }
+    
+    /// Create a directory with specific permissions, returning its metadata
+    ///
+    /// The agent may drop bits of `mode` it does not allow clients to set;
+    /// the metadata shows the mode the directory got.
+    pub async fn create_directory_with_mode<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: u32,
+    ) -> ClientResult<FileMetadata> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::CreateDirectory {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+            mode,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        let metadata = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::CreateDirectoryResponse { 
+                    success: true, 
+                    metadata,
+                    .. 
+                } => Ok(metadata),
+                Message::CreateDirectoryResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for create directory request".to_string()
+                )),
+            }
+        }
+        }).await?;
+        
+        // Agents from before directory metadata was returned
+        match metadata {
+            Some(metadata) => Ok(metadata),
+            None => self.get_metadata_with_options(&path, false).await,
+        }
+    }
+    
+    /// Delete a file
+    pub async fn delete_file<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::DeleteFile {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::DeleteFileResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::DeleteFileResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for delete file request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// Cut a file down, or extend it with zeros, to `size` bytes in place
+    pub async fn truncate_file<P: AsRef<Path>>(&self, path: P, size: u64) -> ClientResult<()> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::TruncateFile {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+            size,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::TruncateFileResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::TruncateFileResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for truncate file request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// Delete a directory
+    pub async fn delete_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::RemoveDirectory {
+            request_id: generate_request_id(),
+            path: path_str.clone(),
+            recursive: true,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::RemoveDirectoryResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::RemoveDirectoryResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for delete directory request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// Put back the entry most recently deleted from `path`, on an agent
+    /// that keeps deleted entries in a trash; `path` must not exist
+    pub async fn restore_from_trash<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
+        let request = Message::RestoreFromTrash {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::RestoreFromTrashResponse { success: true, .. } => Ok(()),
+                    Message::RestoreFromTrashResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for restore from trash request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Delete for good what the agent's trash holds of entries deleted from
+    /// `path` or below it, returning how many entries were purged
+    pub async fn purge_trash<P: AsRef<Path>>(&self, path: P) -> ClientResult<u32> {
+        let request = Message::PurgeTrash {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::PurgeTrashResponse { success: true, purged, .. } => Ok(purged),
+                    Message::PurgeTrashResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for purge trash request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Move/rename a file or directory
+    pub async fn move_path<P: AsRef<Path>>(&self, source: P, destination: P) -> ClientResult<()> {
+        self.move_path_with_options(source, destination, false).await
+    }
+    
+    /// Move/rename a file or directory; with `no_replace` an existing
+    /// `destination` is an `AlreadyExists` error instead of being replaced
+    ///
+    /// `no_replace` needs an agent announcing `RenameNoReplace`.
+    pub async fn move_path_with_options<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        no_replace: bool,
+    ) -> ClientResult<()> {
+        let source_str = source.as_ref().to_string_lossy().to_string();
+        let dest_str = destination.as_ref().to_string_lossy().to_string();
+        
+        let request = Message::Rename {
+            request_id: generate_request_id(),
+            from_path: source_str.clone(),
+            to_path: dest_str.clone(),
+            no_replace,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::RenameResponse { 
+                    success: true, 
+                    .. 
+                } => Ok(()),
+                Message::RenameResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for rename request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// Create a hard link at `link` to the file at `existing`
+    ///
+    /// Both paths must be on the same agent and filesystem. Needs an agent
+    /// announcing `HardLinks`.
+    pub async fn hard_link<P: AsRef<Path>>(&self, existing: P, link: P) -> ClientResult<()> {
+        let request = Message::CreateHardLink {
+            request_id: generate_request_id(),
+            existing_path: existing.as_ref().to_string_lossy().to_string(),
+            link_path: link.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::CreateHardLinkResponse { success: true, .. } => Ok(()),
+                    Message::CreateHardLinkResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for hard link request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Target stored in the symlink at `path`, as given when it was created
+    ///
+    /// Needs an agent announcing `ReadSymlink`; with older agents the target
+    /// is in the link's metadata.
+    pub async fn read_symlink<P: AsRef<Path>>(&self, path: P) -> ClientResult<String> {
+        let request = Message::ReadSymlink {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::ReadSymlinkResponse { success: true, target: Some(target), .. } => Ok(target),
+                    Message::ReadSymlinkResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for symlink read".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Changes recorded in the agent's change journal after `since`
+    ///
+    /// Start with a cursor of 0 and pass each result's `next_cursor` to the
+    /// next call. When `reset` is set the journal no longer covers the
+    /// cursor and the caller must rescan.
+    pub async fn get_changes(&self, since: u64, limit: Option<u32>) -> ClientResult<ChangeSet> {
+        let request = Message::GetChanges {
+            request_id: generate_request_id(),
+            since,
+            limit,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+            
+                match response {
+                Message::GetChangesResponse { 
+                    success: true, 
+                    changes: Some(changes), 
+                    .. 
+                } => Ok(changes),
+                Message::GetChangesResponse { 
+                    success: false, 
+                    error: Some(error), 
+                    .. 
+                } => {
+                    Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    ))
+                }
+                _ => Err(ClientError::InvalidResponse(
+                    "Unexpected response for get changes request".to_string()
+                )),
+            }
+        }
+        }).await
+    }
+    
+    /// Watch `path` for changes, made through RemoteFS or on the agent's host
+    ///
+    /// With `recursive` set, changes in subdirectories at any depth are
+    /// reported too. The watch runs until the returned stream is dropped or
+    /// the connection closes, after which the caller watches again and
+    /// re-reads anything it cached.
+    pub async fn watch<P: AsRef<Path>>(&self, path: P, recursive: bool) -> ClientResult<WatchStream> {
+        let request = Message::Watch {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+            recursive,
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let mut responses = conn.send_streaming_request((*request).clone()).await?;
+                
+                match responses.next().await {
+                    Some(Ok(Message::WatchResponse { success: true, .. })) => Ok(WatchStream {
+                        responses: responses.without_timeout(),
+                        unwatch: conn.message_sender(),
+                    }),
+                    Some(Ok(Message::WatchResponse { error, .. })) => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error.unwrap_or_default())
+                    )),
+                    Some(Ok(Message::Error { code, message, details, errno, .. })) => Err(error_response(code, message, details, errno)),
+                    Some(Err(e)) => Err(e),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for watch request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Agents connected to the relay, with what they support and the
+    /// readiness of their configured paths
+    pub async fn list_agents(&self) -> ClientResult<Vec<AgentInfo>> {
+        let request = Message::ListAgents {
+            request_id: generate_request_id(),
+        };
+        
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request(request).await?;
+                
+                match response {
+                    Message::ListAgentsResponse { agents, .. } => Ok(agents),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for list agents request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Ask the relay for the maintenance windows that concern this client:
+    /// the relay's own and those of the agents it has used
+    pub async fn maintenance(&self) -> ClientResult<Vec<MaintenanceWindow>> {
+        let request = Message::GetMaintenance {
+            request_id: generate_request_id(),
+        };
+        
+        let windows = self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request(request).await?;
+                
+                match response {
+                    Message::MaintenanceStatus { windows, .. } => Ok(windows),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for maintenance request".to_string()
+                    )),
+                }
+            }
+        }).await?;
+        
+        self.connection_pool.announcements().set_maintenance(windows.clone()).await;
+        Ok(windows)
+    }
+    
+    /// The maintenance window underway, as the relay last reported it
+    ///
+    /// Does not ask the relay, which reports changes as they are made.
+    pub async fn active_maintenance(&self) -> Option<MaintenanceWindow> {
+        let now = Utc::now();
+        self.connection_pool.announcements().maintenance().await
+            .into_iter()
+            .find(|window| window.is_active_at(now))
+    }
+    
+    /// The configured paths that this client may read, under the names the
+    /// agent gives them
+    ///
+    /// Asks `agent_id` when given, as listed by `list_agents`, and otherwise
+    /// whichever agent the relay picks.
+    pub async fn list_exports(&self, agent_id: Option<&str>) -> ClientResult<Vec<ExportInfo>> {
+        let request = Message::ListExports {
+            request_id: generate_request_id(),
+            agent_id: agent_id.map(str::to_string),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::ListExportsResponse { error: None, exports, .. } => Ok(exports),
+                    Message::ListExportsResponse { error: Some(error), .. } => {
+                        Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+                    }
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for list exports request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Size and free space of the filesystem holding `path` on the agent
+    pub async fn space_info<P: AsRef<Path>>(&self, path: P) -> ClientResult<SpaceInfo> {
+        let request = Message::GetSpaceInfo {
+            request_id: generate_request_id(),
+            path: path.as_ref().to_string_lossy().to_string(),
+        };
+        
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::GetSpaceInfoResponse {
+                        success: true,
+                        total_space: Some(total_space),
+                        available_space: Some(available_space),
+                        used_space,
+                        total_files,
+                        available_files,
+                        ..
+                    } => Ok(SpaceInfo {
+                        total_space,
+                        available_space,
+                        used_space: used_space.unwrap_or(total_space.saturating_sub(available_space)),
+                        total_files,
+                        available_files,
+                    }),
+                    Message::GetSpaceInfoResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    // Missing paths, and agents that cannot report space
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for space info request".to_string()
+                    )),
+                }
+            }
+        }).await
+    }
+    
+    /// Read a file as it was at `as_of`
+    ///
+    /// The agent keeps no old file contents, so this only succeeds when its
+    /// change journal shows the file unchanged since `as_of`.
+    pub async fn read_file_as_of<P: AsRef<Path>>(
+        &self,
+        path: P,
+        as_of: DateTime<Utc>,
+        offset: Option<u64>,
+        length: Option<u64>,
+    ) -> ClientResult<Bytes> {
+        let request = Message::ReadFileAsOf {
             request_id: generate_request_id(),
-            path: path_str.clone(),
-            mode,
+            path: path.as_ref().to_string_lossy().to_string(),
+            offset: offset.unwrap_or(0),
+            length: length.map(|l| l as u32).unwrap_or(u32::MAX),
+            as_of,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
                 match response {
-                Message::CreateDirectoryResponse { 
+                Message::ReadFileResponse { 
                     success: true, 
+                    data: Some(data), 
                     .. 
-                } => Ok(()),
-                Message::CreateDirectoryResponse { 
+                } => {
+                    {
+                        let mut stats = self.stats.write().await;
+                        stats.bytes_read += data.len() as u64;
+                    }
+                    
+                    Ok(Bytes::from(data))
+                }
+                Message::ReadFileResponse { 
                     success: false, 
                     error: Some(error), 
                     .. 
@@ -360,36 +2421,47 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Offline (archived) files are refused with an error code
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
-                    "Unexpected response for create directory request".to_string()
+                    "Unexpected response for read file as-of request".to_string()
                 )),
+                }
             }
-        }
         }).await
     }
     
-    /// Delete a file
-    pub async fn delete_file<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        
-        let request = Message::DeleteFile {
+    /// Read a file's content, full metadata (including the real owner) and
+    /// extended attributes in one round trip, for backups
+    ///
+    /// Symlinks are not followed; their target is in the metadata.
+    pub async fn read_backup_entry<P: AsRef<Path>>(&self, path: P) -> ClientResult<BackupEntry> {
+        let request = Message::ReadBackupEntry {
             request_id: generate_request_id(),
-            path: path_str.clone(),
+            path: path.as_ref().to_string_lossy().to_string(),
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
                 match response {
-                Message::DeleteFileResponse { 
+                Message::ReadBackupEntryResponse { 
                     success: true, 
+                    entry: Some(entry), 
                     .. 
-                } => Ok(()),
-                Message::DeleteFileResponse { 
+                } => {
+                    {
+                        let mut stats = self.stats.write().await;
+                        stats.bytes_read += entry.data.len() as u64;
+                    }
+                    
+                    Ok(entry)
+                }
+                Message::ReadBackupEntryResponse { 
                     success: false, 
                     error: Some(error), 
                     .. 
@@ -398,37 +2470,53 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Offline (archived) files are refused with an error code
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
-                    "Unexpected response for delete file request".to_string()
+                    "Unexpected response for read backup entry request".to_string()
                 )),
+                }
             }
-        }
         }).await
     }
     
-    /// Delete a directory
-    pub async fn delete_directory<P: AsRef<Path>>(&self, path: P) -> ClientResult<()> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        
-        let request = Message::RemoveDirectory {
+    /// Apply several mutating operations all-or-nothing, e.g. write a
+    /// temporary file, rename it over the original and delete a lock file
+    ///
+    /// If any operation fails the agent rolls back the ones before it and the
+    /// error names the failed operation's index.
+    pub async fn transaction(&self, operations: Vec<TransactionOp>) -> ClientResult<()> {
+        let bytes: u64 = operations.iter()
+            .map(|operation| match operation {
+                TransactionOp::WriteFile { data, .. } => data.len() as u64,
+                _ => 0,
+            })
+            .sum();
+        let request = Message::Transaction {
             request_id: generate_request_id(),
-            path: path_str.clone(),
-            recursive: true,
+            operations,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
                 match response {
-                Message::RemoveDirectoryResponse { 
+                Message::TransactionResponse { 
                     success: true, 
                     .. 
-                } => Ok(()),
-                Message::RemoveDirectoryResponse { 
+                } => {
+                    {
+                        let mut stats = self.stats.write().await;
+                        stats.bytes_written += bytes;
+                    }
+                    
+                    Ok(())
+                }
+                Message::TransactionResponse { 
                     success: false, 
                     error: Some(error), 
                     .. 
@@ -437,39 +2525,74 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Refused for the agent's resource limits
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
-                    "Unexpected response for delete directory request".to_string()
+                    "Unexpected response for transaction request".to_string()
                 )),
+                }
             }
-        }
         }).await
     }
     
-    /// Move/rename a file or directory
-    pub async fn move_path<P: AsRef<Path>>(&self, source: P, destination: P) -> ClientResult<()> {
-        let source_str = source.as_ref().to_string_lossy().to_string();
-        let dest_str = destination.as_ref().to_string_lossy().to_string();
+    /// Create many small files, e.g. a source tree, in as few requests as
+    /// possible
+    ///
+    /// Files are sent in batches the agent accepts and created with any
+    /// missing parent directories. Unlike a transaction, each file succeeds
+    /// or fails on its own: the failures returned name the index of the file
+    /// in `files`. Without `overwrite`, existing files are left as they are
+    /// and reported as failures. Agents too old to create a batch get one
+    /// write per file, which needs the parent directories to exist.
+    pub async fn batch_create_files(&self, mut files: Vec<NewFile>, overwrite: bool) -> ClientResult<Vec<BatchFailure>> {
+        let mut failures = Vec::new();
+        for range in batch_ranges(&files) {
+            let offset = range.start as u32;
+            let batch: Vec<NewFile> = files.drain(..range.len()).collect();
+            let result = match self.create_batch(batch.clone(), overwrite).await {
+                Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                    warn!("Falling back to writing {} files one by one: {}", batch.len(), e);
+                    self.stats.write().await.protocol_fallbacks += 1;
+                    Ok(self.create_files_one_by_one(batch, overwrite).await)
+                }
+                result => result,
+            };
+            
+            failures.extend(result?.into_iter().map(|failure| BatchFailure { index: failure.index + offset, ..failure }));
+        }
         
-        let request = Message::Rename {
+        Ok(failures)
+    }
+    
+    async fn create_batch(&self, files: Vec<NewFile>, overwrite: bool) -> ClientResult<Vec<BatchFailure>> {
+        let bytes: u64 = files.iter().map(|file| file.data.len() as u64).sum();
+        let request = Message::BatchCreateFiles {
             request_id: generate_request_id(),
-            from_path: source_str.clone(),
-            to_path: dest_str.clone(),
+            files,
+            overwrite,
         };
         
-        let request = Arc::new(request);
-        self.execute_with_retry(|connection| {
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
             let request = request.clone();
             async move {
-                let conn = connection.lock().await;
+                let conn = connection.read().await;
                 let response = conn.send_request((*request).clone()).await?;
             
                 match response {
-                Message::RenameResponse { 
-                    success: true, 
+                Message::BatchCreateFilesResponse { 
+                    error: None, 
+                    failures, 
                     .. 
-                } => Ok(()),
-                Message::RenameResponse { 
-                    success: false, 
+                } => {
+                    {
+                        let mut stats = self.stats.write().await;
+                        stats.bytes_written += bytes;
+                    }
+                    
+                    Ok(failures)
+                }
+                Message::BatchCreateFilesResponse { 
                     error: Some(error), 
                     .. 
                 } => {
@@ -477,23 +2600,100 @@ impl RemoteFsClient {
                         remotefs_common::error::RemoteFsError::FileSystem(error)
                     ))
                 }
+                // Refused for the agent's resource limits, or not supported
+                Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
                 _ => Err(ClientError::InvalidResponse(
-                    "Unexpected response for rename request".to_string()
+                    "Unexpected response for batch create request".to_string()
                 )),
+                }
             }
-        }
         }).await
     }
     
-    /// Copy a file (implemented as read + write)
+    async fn create_files_one_by_one(&self, files: Vec<NewFile>, overwrite: bool) -> Vec<BatchFailure> {
+        let mut failures = Vec::new();
+        for (index, file) in files.into_iter().enumerate() {
+            let result = async {
+                if !overwrite {
+                    match self.get_metadata_with_options(&file.path, false).await {
+                        Ok(_) => return Err(ClientError::RemoteFs(
+                            remotefs_common::error::RemoteFsError::AlreadyExists(file.path.clone())
+                        )),
+                        Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotFound(_))) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                self.write_file(&file.path, Bytes::from(file.data)).await?;
+                if let Some(mode) = file.mode {
+                    self.set_permissions(&file.path, mode).await?;
+                }
+                Ok(())
+            }.await;
+            
+            if let Err(e) = result {
+                failures.push(BatchFailure { index: index as u32, error: e.to_string() });
+            }
+        }
+        failures
+    }
+    
+    /// Copy a file, replacing `destination`
+    ///
+    /// Agents announcing `CopyFile` copy the data themselves; from older
+    /// agents the file is read and written back through this client.
     pub async fn copy_file<P: AsRef<Path>>(&self, source: P, destination: P) -> ClientResult<()> {
-        // Read the source file
-        let data = self.read_file(source).await?;
-        
-        // Write to the destination file
-        self.write_file(destination, data).await?;
+        match self.copy_file_range(&source, &destination, 0, None).await {
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(remotefs_common::error::RemoteFsError::NotImplemented(_))) => {
+                warn!("Falling back to reading and writing back {}: {}", source.as_ref().display(), e);
+                self.stats.write().await.protocol_fallbacks += 1;
+                let data = self.read_file(source).await?;
+                self.write_file(destination, data).await
+            }
+            result => result.map(|_| ()),
+        }
+    }
+    
+    /// Copy the range of `source` from `offset`, `length` bytes long or to
+    /// its end, into `destination` at the same offset, on the agent;
+    /// returns the number of bytes copied
+    ///
+    /// `destination` is created if missing. With `offset` 0 and no `length`
+    /// it is replaced by a copy of the whole source instead. Needs an agent
+    /// announcing `CopyFile`.
+    pub async fn copy_file_range<P: AsRef<Path>>(
+        &self,
+        source: P,
+        destination: P,
+        offset: u64,
+        length: Option<u64>,
+    ) -> ClientResult<u64> {
+        let request = Message::CopyFile {
+            request_id: generate_request_id(),
+            source: source.as_ref().to_string_lossy().to_string(),
+            destination: destination.as_ref().to_string_lossy().to_string(),
+            offset,
+            length,
+        };
         
-        Ok(())
+        let request = Arc::new(self.as_caller(request));
+        self.execute_with_retry(request.request_id(), |connection| {
+            let request = request.clone();
+            async move {
+                let conn = connection.read().await;
+                let response = conn.send_request((*request).clone()).await?;
+                
+                match response {
+                    Message::CopyFileResponse { success: true, copied, .. } => Ok(copied),
+                    Message::CopyFileResponse { success: false, error: Some(error), .. } => Err(ClientError::RemoteFs(
+                        remotefs_common::error::RemoteFsError::FileSystem(error)
+                    )),
+                    Message::Error { code, message, details, errno, .. } => Err(error_response(code, message, details, errno)),
+                    _ => Err(ClientError::InvalidResponse(
+                        "Unexpected response for copy".to_string()
+                    )),
+                }
+            }
+        }).await
     }
     
     /// Get client statistics
@@ -507,7 +2707,7 @@ impl RemoteFsClient {
         let mut statuses = Vec::new();
         
         for connection in connections {
-            let conn = connection.lock().await;
+            let conn = connection.read().await;
             let agent_id = conn.agent_config().id.clone();
             let state = conn.state().await;
             statuses.push((agent_id, state));
@@ -516,10 +2716,27 @@ impl RemoteFsClient {
         statuses
     }
     
+    /// Rewrite a request's paths into the agent's layout and wrap it for
+    /// the agent to check against the caller's rules
+    fn as_caller(&self, mut request: Message) -> Message {
+        self.rewriter.apply(&mut request);
+        match &self.caller {
+            Some(identity) => Message::AsUser {
+                identity: identity.clone(),
+                request: Box::new(request),
+            },
+            None => request,
+        }
+    }
+    
     /// Execute an operation with retry logic and load balancing
-    async fn execute_with_retry<F, Fut, T>(&self, operation: F) -> ClientResult<T>
+    ///
+    /// Every attempt resends the same request, so its ID ties the attempts
+    /// together in the client's, relay's and agent's logs. The error
+    /// returned carries the ID.
+    async fn execute_with_retry<F, Fut, T>(&self, request_id: Option<RequestId>, operation: F) -> ClientResult<T>
     where
-        F: Fn(Arc<Mutex<AgentConnection>>) -> Fut,
+        F: Fn(Arc<RwLock<AgentConnection>>) -> Fut,
         Fut: std::future::Future<Output = ClientResult<T>>,
     {
         let start_time = SystemTime::now();
@@ -548,6 +2765,7 @@ impl RemoteFsClient {
                             return Ok(result);
                         }
                         Err(e) if e.is_retryable() && attempt < self.config.client.max_retries => {
+                            let e = e.for_request(request_id);
                             warn!("Retryable error on attempt {}: {}", attempt + 1, e);
                             last_error = Some(e);
                             
@@ -580,7 +2798,7 @@ impl RemoteFsClient {
         
         Err(last_error.unwrap_or_else(|| ClientError::Internal(
             "Operation failed without specific error".to_string()
-        )))
+        )).for_request(request_id))
     }
     
     /// Calculate retry delay based on strategy
@@ -599,9 +2817,547 @@ impl RemoteFsClient {
     }
 }
 
+/// Pages of a directory listing or walk, in the order the agent reads them
+pub struct DirectoryPages {
+    source: PageSource,
+    /// Where to continue a listing stopped at its `max_entries`
+    cursor: Option<String>,
+}
+
+enum PageSource {
+    /// Pages streamed by the agent
+    Streamed {
+        /// Response already read while checking the agent could page
+        first: Option<Box<ClientResult<Message>>>,
+        responses: ResponseStream,
+    },
+    /// A whole-buffer listing from an older agent, split up locally
+    Buffered(std::collections::VecDeque<Vec<DirEntry>>),
+}
+
+impl DirectoryPages {
+    fn streamed(first: Option<ClientResult<Message>>, responses: ResponseStream) -> Self {
+        Self { source: PageSource::Streamed { first: first.map(Box::new), responses }, cursor: None }
+    }
+    
+    fn buffered(entries: Vec<DirEntry>, page_size: u32) -> Self {
+        let page_size = page_size.max(1) as usize;
+        let mut pages: std::collections::VecDeque<_> = entries.chunks(page_size).map(<[DirEntry]>::to_vec).collect();
+        if pages.is_empty() {
+            // An empty directory is still one (empty) page
+            pages.push_back(Vec::new());
+        }
+        Self { source: PageSource::Buffered(pages), cursor: None }
+    }
+    
+    /// Next page of entries, or `None` after the last page
+    pub async fn next_page(&mut self) -> Option<ClientResult<Vec<DirEntry>>> {
+        let (first, responses) = match &mut self.source {
+            PageSource::Streamed { first, responses } => (first, responses),
+            PageSource::Buffered(pages) => return pages.pop_front().map(Ok),
+        };
+        let response = match first.take() {
+            Some(response) => *response,
+            None => responses.next().await?,
+        };
+        let page = match response {
+            Ok(Message::DirectoryPage { error: Some(error), .. }) => {
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::DirectoryPage { entries, cursor, .. }) => {
+                self.cursor = cursor;
+                Ok(entries)
+            }
+            Ok(Message::Error { code, message, details, errno, .. }) => Err(error_response(code, message, details, errno)),
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for paged directory request".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(page.map_err(|e| e.for_request(Some(responses.request_id()))))
+    }
+}
+
+/// Matches of a search started with [`RemoteFsClient::search_files`]
+pub struct SearchMatches {
+    responses: ResponseStream,
+    truncated: bool,
+}
+
+impl SearchMatches {
+    /// Next page of matches, or `None` after the last page
+    pub async fn next_page(&mut self) -> Option<ClientResult<Vec<SearchMatch>>> {
+        let page = match self.responses.next().await? {
+            Ok(Message::SearchResults { error: Some(error), .. }) => {
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::SearchResults { matches, truncated, .. }) => {
+                self.truncated = truncated;
+                Ok(matches)
+            }
+            Ok(Message::Error { code, message, details, errno, .. }) => Err(error_response(code, message, details, errno)),
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for search request".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(page.map_err(|e| e.for_request(Some(self.responses.request_id()))))
+    }
+    
+    /// Whether the search stopped at its result limit, once
+    /// [`next_page`](Self::next_page) has returned the last page
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+/// Chunks of a file streamed with [`RemoteFsClient::read_file_stream`]
+///
+/// Each chunk taken from the stream lets the agent send another, so a
+/// reader that stops taking chunks holds the stream back, and ends it if
+/// it stops for long.
+pub struct FileChunks {
+    responses: ResponseStream,
+    /// Acknowledges chunks to the agent
+    acks: Option<tokio::sync::mpsc::UnboundedSender<Message>>,
+    next_sequence: u32,
+}
+
+impl FileChunks {
+    /// Next chunk of the file, or `None` after the last one
+    ///
+    /// A stream that fails partway, e.g. because the file could no longer
+    /// be read, ends with an error.
+    pub async fn next_chunk(&mut self) -> Option<ClientResult<Bytes>> {
+        let chunk = match self.responses.next().await? {
+            Ok(Message::ReadFileChunk { error: Some(error), .. }) => {
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::ReadFileChunk { sequence, .. }) if sequence != self.next_sequence => Err(ClientError::InvalidResponse(
+                format!("File chunk {} arrived when chunk {} was expected", sequence, self.next_sequence)
+            )),
+            Ok(Message::ReadFileChunk { sequence, data, last, .. }) => {
+                self.next_sequence += 1;
+                if !last {
+                    if let Some(acks) = &self.acks {
+                        let _ = acks.send(Message::ReadFileAck { stream_id: self.responses.request_id(), sequence });
+                    }
+                }
+                Ok(Bytes::from(data))
+            }
+            Ok(Message::Error { code, message, details, errno, .. }) => Err(error_response(code, message, details, errno)),
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for file stream".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(chunk.map_err(|e| e.for_request(Some(self.responses.request_id()))))
+    }
+}
+
+/// Output of a command run with [`RemoteFsClient::run_extended_operation`]
+pub struct CommandOutput {
+    responses: ResponseStream,
+    exit_code: Option<i32>,
+}
+
+impl CommandOutput {
+    /// Next chunk of stdout or stderr, or `None` once the command finished
+    ///
+    /// A command that could not be started, was killed at its timeout or
+    /// whose output was cut off at its limit ends with an error.
+    pub async fn next_chunk(&mut self) -> Option<ClientResult<(OutputStream, Vec<u8>)>> {
+        let chunk = match self.responses.next().await? {
+            Ok(Message::ExtendedOutput { error: Some(error), exit_code, .. }) => {
+                self.exit_code = exit_code;
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::ExtendedOutput { last: true, exit_code, .. }) => {
+                self.exit_code = exit_code;
+                return None;
+            }
+            Ok(Message::ExtendedOutput { stream, data, .. }) => Ok((stream, data)),
+            Ok(Message::Error { code, message, details, errno, .. }) => Err(error_response(code, message, details, errno)),
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for extended operation".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(chunk.map_err(|e| e.for_request(Some(self.responses.request_id()))))
+    }
+    
+    /// Exit code of the command, once [`next_chunk`](Self::next_chunk) has
+    /// returned `None`
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+}
+
+/// What [`RemoteFsClient::write_file_delta`] sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaWrite {
+    /// Length of the file written
+    pub length: u64,
+    /// Bytes of file data sent; the whole length if the file was written in
+    /// full
+    pub sent: u64,
+}
+
+/// A metadata change of a tree started with
+/// [`RemoteFsClient::set_metadata_tree`]
+///
+/// Dropping it before it has finished cancels the change; entries already
+/// changed keep their new metadata.
+pub struct MetadataTreeChange {
+    responses: ResponseStream,
+    /// Tells the agent to stop the change, until it has ended
+    cancel: Option<tokio::sync::mpsc::UnboundedSender<Message>>,
+    progress: TreeProgress,
+}
+
+impl MetadataTreeChange {
+    /// Progress so far, as the agent reports it about once a second, or
+    /// `None` after the last report
+    pub async fn next_progress(&mut self) -> Option<ClientResult<TreeProgress>> {
+        let progress = match self.responses.next().await? {
+            Ok(Message::SetMetadataTreeProgress { error: Some(error), .. }) => {
+                self.cancel = None;
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::SetMetadataTreeProgress { progress, last, .. }) => {
+                if last {
+                    self.cancel = None;
+                }
+                self.progress = progress.clone();
+                Ok(progress)
+            }
+            Ok(Message::Error { code, message, details, errno, .. }) => {
+                self.cancel = None;
+                Err(error_response(code, message, details, errno))
+            }
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for metadata tree request".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(progress.map_err(|e| e.for_request(Some(self.responses.request_id()))))
+    }
+    
+    /// Ask the agent to stop; the last report, marked `cancelled`, still
+    /// follows
+    pub fn cancel(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(Message::CancelSetMetadataTree { request_id: self.responses.request_id() });
+        }
+    }
+    
+    /// Wait for the change to end, returning the last report
+    pub async fn finish(mut self) -> ClientResult<TreeProgress> {
+        while let Some(progress) = self.next_progress().await {
+            progress?;
+        }
+        Ok(self.progress.clone())
+    }
+}
+
+impl Drop for MetadataTreeChange {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// A change reported by a watch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// A file or directory was created, modified, deleted or renamed
+    FileChanged { path: String, kind: ChangeKind, is_dir: bool },
+    /// Entries were added to, removed from or renamed in a directory, so a
+    /// cached listing of it is out of date
+    DirectoryChanged { path: String },
+}
+
+/// Changes under a path watched with [`RemoteFsClient::watch`]
+///
+/// Dropping the stream stops the watch.
+pub struct WatchStream {
+    responses: ResponseStream,
+    /// Tells the agent to stop the watch, until it has ended
+    unwatch: Option<tokio::sync::mpsc::UnboundedSender<Message>>,
+}
+
+impl WatchStream {
+    /// Next change, waiting as long as it takes, or `None` once the watch
+    /// was stopped
+    ///
+    /// A watch the agent had to end, e.g. because its path was removed or
+    /// changes were lost, ends with an error.
+    pub async fn next_event(&mut self) -> Option<ClientResult<WatchEvent>> {
+        let event = match self.responses.next().await? {
+            Ok(Message::FileChanged { path, kind, is_dir, .. }) => Ok(WatchEvent::FileChanged { path, kind, is_dir }),
+            Ok(Message::DirectoryChanged { path, .. }) => Ok(WatchEvent::DirectoryChanged { path }),
+            Ok(Message::WatchEnded { error: None, .. }) => {
+                self.unwatch = None;
+                return None;
+            }
+            Ok(Message::WatchEnded { error: Some(error), .. }) => {
+                self.unwatch = None;
+                Err(ClientError::RemoteFs(remotefs_common::error::RemoteFsError::FileSystem(error)))
+            }
+            Ok(Message::Error { code, message, details, errno, .. }) => Err(error_response(code, message, details, errno)),
+            Ok(_) => Err(ClientError::InvalidResponse(
+                "Unexpected response for watch".to_string()
+            )),
+            Err(e) => Err(e),
+        };
+        Some(event.map_err(|e| e.for_request(Some(self.responses.request_id()))))
+    }
+    
+    /// Stop the watch; changes still on their way are dropped
+    pub fn stop(self) {}
+}
+
+impl Drop for WatchStream {
+    fn drop(&mut self) {
+        if let Some(unwatch) = self.unwatch.take() {
+            let _ = unwatch.send(Message::Unwatch { request_id: self.responses.request_id() });
+        }
+    }
+}
+
 impl Drop for RemoteFsClient {
     fn drop(&mut self) {
         // Note: We can't call async methods in Drop, so we just clean up synchronously
         debug!("RemoteFsClient dropped");
     }
 }
+
+/// Client error for an `Error` response, keeping the limit a request
+/// exceeded when the agent names it, so the request can be adapted, and
+/// the error number of a failed filesystem call
+fn error_response(
+    code: ErrorCode,
+    message: String,
+    details: Option<std::collections::HashMap<String, String>>,
+    errno: Option<i32>,
+) -> ClientError {
+    let limit = details.as_ref().and_then(|details| {
+        Some((details.get("limit")?.clone(), details.get("max")?.parse().ok()?))
+    });
+    match (code, limit) {
+        (ErrorCode::MessageTooLarge | ErrorCode::InvalidMessage, Some((limit, max))) => {
+            ClientError::LimitExceeded { limit, max, message }
+        }
+        (code, _) => ClientError::RemoteFs(remotefs_common::error::RemoteFsError::from_error_response(code, errno, message)),
+    }
+}
+
+/// Read up to `WRITE_WINDOW` chunks of an upload, stopping after a chunk
+/// the end of `reader` cut short
+async fn read_window<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<Bytes>> {
+    let mut window = Vec::new();
+    while window.len() < WRITE_WINDOW {
+        let mut chunk = vec![0; MAX_STREAM_CHUNK as usize];
+        let mut filled = 0;
+        while filled < chunk.len() {
+            match reader.read(&mut chunk[filled..]).await? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        chunk.truncate(filled);
+        if !chunk.is_empty() {
+            window.push(Bytes::from(chunk));
+        }
+        if filled < MAX_STREAM_CHUNK as usize {
+            break;
+        }
+    }
+    Ok(window)
+}
+
+/// Whether a window read by `read_window` ends its upload
+fn window_ended(window: &[Bytes]) -> bool {
+    window.len() < WRITE_WINDOW || window.last().is_some_and(|chunk| chunk.len() < MAX_STREAM_CHUNK as usize)
+}
+
+/// Split `files` into consecutive batches within the agent's limits on file
+/// count and data size; a file larger than the size limit gets a batch of
+/// its own
+fn batch_ranges(files: &[NewFile]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+    for (index, file) in files.iter().enumerate() {
+        let full = index - start == MAX_BATCH_FILES || bytes + file.data.len() > MAX_BATCH_BYTES;
+        if full && index > start {
+            ranges.push(start..index);
+            start = index;
+            bytes = 0;
+        }
+        bytes += file.data.len();
+    }
+    if start < files.len() {
+        ranges.push(start..files.len());
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    fn file(len: usize) -> NewFile {
+        NewFile { path: "/data/file".to_string(), data: vec![0; len], mode: None }
+    }
+
+    #[test]
+    fn test_batch_ranges() {
+        assert!(batch_ranges(&[]).is_empty());
+
+        let files: Vec<NewFile> = (0..MAX_BATCH_FILES + 1).map(|_| file(1)).collect();
+        assert_eq!(batch_ranges(&files), [0..MAX_BATCH_FILES, MAX_BATCH_FILES..MAX_BATCH_FILES + 1]);
+
+        let half = MAX_BATCH_BYTES / 2;
+        let files = [file(half), file(half), file(1), file(MAX_BATCH_BYTES * 2), file(1)];
+        assert_eq!(batch_ranges(&files), [0..2, 2..3, 3..4, 4..5]);
+    }
+
+    #[test]
+    fn test_error_response_keeps_limits() {
+        let details = |limit: &str, max: &str| Some(std::collections::HashMap::from([
+            ("limit".to_string(), limit.to_string()),
+            ("max".to_string(), max.to_string()),
+        ]));
+
+        match error_response(ErrorCode::MessageTooLarge, "too large".to_string(), details("response_bytes", "1024"), None) {
+            ClientError::LimitExceeded { limit, max, .. } => {
+                assert_eq!(limit, "response_bytes");
+                assert_eq!(max, 1024);
+            }
+            other => panic!("Unexpected error: {:?}", other),
+        }
+
+        // Without usable details, or for other errors, the usual error
+        assert!(matches!(error_response(ErrorCode::MessageTooLarge, "too large".to_string(), None, None), ClientError::RemoteFs(_)));
+        assert!(matches!(error_response(ErrorCode::InvalidMessage, "bad".to_string(), details("path_depth", "many"), None), ClientError::RemoteFs(_)));
+        assert!(matches!(error_response(ErrorCode::ServiceUnavailable, "busy".to_string(), details("x", "1"), None), ClientError::RemoteFs(_)));
+    }
+
+    #[tokio::test]
+    async fn test_operations_report_limits() {
+        use crate::recording::{Direction, RecordedMessage, ReplayAgent};
+        
+        // An agent refusing each request's path as too deep
+        let refused = |request: Message| {
+            let request_id = request.request_id();
+            [
+                RecordedMessage { elapsed_ms: 0, agent_id: "agent".to_string(), direction: Direction::Sent, message: request },
+                RecordedMessage {
+                    elapsed_ms: 0,
+                    agent_id: "agent".to_string(),
+                    direction: Direction::Received,
+                    message: Message::Error {
+                        request_id,
+                        code: ErrorCode::InvalidMessage,
+                        message: "Path with 9 components exceeds the agent's limit of 8".to_string(),
+                        details: Some(std::collections::HashMap::from([
+                            ("limit".to_string(), "path_depth".to_string()),
+                            ("max".to_string(), "8".to_string()),
+                        ])),
+                        errno: None,
+                    },
+                },
+            ]
+        };
+        let path = "/a/b/c/d/e/f/g/h/i".to_string();
+        let agent = Arc::new(ReplayAgent::new([
+            refused(Message::GetMetadata { request_id: generate_request_id(), path: path.clone(), follow_symlinks: true }),
+            refused(Message::CreateDirectory { request_id: generate_request_id(), path: path.clone(), mode: 0o755 }),
+            refused(Message::DeleteFile { request_id: generate_request_id(), path: path.clone() }),
+            refused(Message::ReadSymlink { request_id: generate_request_id(), path: path.clone() }),
+        ].concat()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(Arc::clone(&agent).serve(listener));
+        
+        let mut config = ClientConfig {
+            agents: vec![AgentConfig { id: "agent".to_string(), url, auth: None, weight: 1, enabled: true }],
+            ..Default::default()
+        };
+        config.client.max_retries = 0;
+        let client = RemoteFsClient::new(config).unwrap();
+        client.initialize().await.unwrap();
+        
+        let errors = [
+            client.get_metadata(&path).await.unwrap_err(),
+            client.create_directory(&path).await.unwrap_err(),
+            client.delete_file(&path).await.unwrap_err(),
+            client.read_symlink(&path).await.unwrap_err(),
+        ];
+        for error in &errors {
+            assert!(matches!(error.cause(), ClientError::LimitExceeded { limit, max: 8, .. } if limit == "path_depth"), "{:?}", error);
+        }
+        assert_eq!(agent.remaining(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_read_window() {
+        let chunk = MAX_STREAM_CHUNK as usize;
+        
+        // A reader exactly a window long only ends with the next, empty window
+        let data = vec![7; chunk * WRITE_WINDOW];
+        let mut reader = &data[..];
+        let window = read_window(&mut reader).await.unwrap();
+        assert_eq!(window.len(), WRITE_WINDOW);
+        assert!(!window_ended(&window));
+        let window = read_window(&mut reader).await.unwrap();
+        assert!(window.is_empty() && window_ended(&window));
+        
+        let data = vec![7; chunk + 1];
+        let window = read_window(&mut &data[..]).await.unwrap();
+        assert_eq!(window.iter().map(Bytes::len).collect::<Vec<_>>(), [chunk, 1]);
+        assert!(window_ended(&window));
+    }
+
+    #[tokio::test]
+    async fn test_requests_share_a_connection() {
+        // An agent that answers two requests only once both arrived, the
+        // later one first
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws_stream = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut waiting = Vec::new();
+            while waiting.len() < 2 {
+                let Some(Ok(WsMessage::Binary(data))) = ws_stream.next().await else {
+                    return;
+                };
+                if let Ok(Message::ListExports { request_id, .. }) = bincode::deserialize(&data) {
+                    waiting.push(request_id);
+                }
+            }
+            for request_id in waiting.into_iter().rev() {
+                let reply = Message::ListExportsResponse { request_id, exports: Vec::new(), error: None };
+                ws_stream.send(WsMessage::Binary(bincode::serialize(&reply).unwrap())).await.unwrap();
+            }
+            while ws_stream.next().await.is_some() {}
+        });
+
+        let mut config = ClientConfig {
+            agents: vec![AgentConfig { id: "agent".to_string(), url, auth: None, weight: 1, enabled: true }],
+            ..Default::default()
+        };
+        config.client.max_retries = 0;
+        let client = RemoteFsClient::new(config).unwrap();
+        client.initialize().await.unwrap();
+
+        let both = futures::future::join(client.list_exports(None), client.list_exports(None));
+        let (first, second) = tokio::time::timeout(Duration::from_secs(5), both).await
+            .expect("requests on one connection were sent one at a time");
+        assert!(first.unwrap().is_empty());
+        assert!(second.unwrap().is_empty());
+    }
+}
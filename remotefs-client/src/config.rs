@@ -4,7 +4,7 @@ use std::time::Duration;
 use crate::error::{ClientError, ClientResult};
 
 /// Client configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ClientConfig {
     /// List of RemoteFS agents to connect to
     pub agents: Vec<AgentConfig>,
@@ -20,6 +20,40 @@ pub struct ClientConfig {
     
     /// Logging configuration
     pub logging: LoggingConfig,
+    
+    /// Relay discovery; the nearest relay is used in addition to `agents`
+    #[serde(default)]
+    pub discovery: Option<RelayDiscoveryConfig>,
+    
+    /// Rules mapping the paths requests are made with to the agent's
+    #[serde(default)]
+    pub path_rewrites: Vec<PathRewrite>,
+}
+
+/// A path prefix rewritten before requests are sent
+///
+/// Lets a client see an agent's tree under another layout, e.g. the agent's
+/// `/export/home` as `/home`, without changing the agent's configuration.
+/// Prefixes match whole path components, and the longest `from` that
+/// matches a path applies. Paths in responses are the agent's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathRewrite {
+    /// Absolute prefix of the paths requests are made with
+    pub from: String,
+    
+    /// Absolute prefix it stands for on the agent
+    pub to: String,
+}
+
+/// Relay discovery configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayDiscoveryConfig {
+    /// WebSocket URL of any relay serving the relay directory
+    pub url: String,
+    
+    /// How long to wait for each relay's answer (in milliseconds)
+    #[serde(default = "default_probe_timeout")]
+    pub probe_timeout_ms: u64,
 }
 
 /// Configuration for a single agent
@@ -73,6 +107,13 @@ pub struct ClientBehaviorConfig {
     /// Buffer size for write operations
     #[serde(default = "default_write_buffer_size")]
     pub write_buffer_size: usize,
+    
+    /// Most entries returned from a whole-buffer listing when an older agent
+    /// cannot page a directory; larger listings are refused. The listing
+    /// arrives in one response, so this limits what callers get, not the
+    /// memory the response takes
+    #[serde(default = "default_max_fallback_entries")]
+    pub max_fallback_entries: usize,
 }
 
 /// Connection configuration
@@ -90,12 +131,43 @@ pub struct ConnectionConfig {
     #[serde(default = "default_max_message_size")]
     pub max_message_size: usize,
     
-    /// Enable compression
+    /// Compress large messages sent to the relay; the relay must support
+    /// compression, and then compresses the messages it sends back
     #[serde(default)]
     pub enable_compression: bool,
     
     /// Reconnection settings
     pub reconnection: ReconnectionConfig,
+    
+    /// Local socket of an agent on this host; reads are served from file
+    /// descriptors it passes instead of through the relay
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+    
+    /// Slow every connection down to the given network conditions, for
+    /// development; never set this in production
+    #[serde(default)]
+    pub simulate: Option<NetworkSimulation>,
+}
+
+/// Network conditions imposed on agent connections (see `simulate`)
+///
+/// Each direction of a connection is a link of its own. Messages keep
+/// their order, as they would over the TCP connection underneath.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSimulation {
+    /// Delay added to every message, in milliseconds
+    pub latency_ms: u64,
+    
+    /// Further random delay of up to this much, in milliseconds
+    pub jitter_ms: u64,
+    
+    /// Bytes per second each direction carries; 0 for no limit
+    pub bandwidth_bytes_per_sec: u64,
+    
+    /// Share of messages lost, from 0.0 to 1.0
+    pub drop_rate: f64,
 }
 
 /// Reconnection configuration
@@ -166,9 +238,10 @@ pub enum RetryStrategy {
 }
 
 /// Load balancing strategies
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum LoadBalancingStrategy {
     #[serde(rename = "round_robin")]
+    #[default]
     RoundRobin,
     #[serde(rename = "weighted_round_robin")]
     WeightedRoundRobin,
@@ -199,18 +272,11 @@ pub struct LoggingConfig {
     /// Enable performance logging
     #[serde(default)]
     pub enable_performance_logs: bool,
-}
-
-impl Default for ClientConfig {
-    fn default() -> Self {
-        Self {
-            agents: vec![],
-            client: ClientBehaviorConfig::default(),
-            connection: ConnectionConfig::default(),
-            auth: None,
-            logging: LoggingConfig::default(),
-        }
-    }
+    
+    /// Record every message exchanged with agents to this file, for
+    /// replaying against a mock agent
+    #[serde(default)]
+    pub record_file: Option<PathBuf>,
 }
 
 impl Default for ClientBehaviorConfig {
@@ -223,6 +289,7 @@ impl Default for ClientBehaviorConfig {
             enable_failover: default_enabled(),
             read_buffer_size: default_read_buffer_size(),
             write_buffer_size: default_write_buffer_size(),
+            max_fallback_entries: default_max_fallback_entries(),
         }
     }
 }
@@ -235,6 +302,8 @@ impl Default for ConnectionConfig {
             max_message_size: default_max_message_size(),
             enable_compression: false,
             reconnection: ReconnectionConfig::default(),
+            local_socket: None,
+            simulate: None,
         }
     }
 }
@@ -260,12 +329,6 @@ impl Default for RetryStrategy {
     }
 }
 
-impl Default for LoadBalancingStrategy {
-    fn default() -> Self {
-        LoadBalancingStrategy::RoundRobin
-    }
-}
-
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
@@ -274,6 +337,7 @@ impl Default for LoggingConfig {
             file: None,
             enable_connection_logs: false,
             enable_performance_logs: false,
+            record_file: None,
         }
     }
 }
@@ -333,16 +397,30 @@ impl ClientConfig {
     
     /// Validate the configuration
     pub fn validate(&self) -> ClientResult<()> {
-        if self.agents.is_empty() {
+        if self.agents.is_empty() && self.discovery.is_none() {
             return Err(ClientError::Configuration(
-                "At least one agent must be configured".to_string()
+                "At least one agent or relay discovery must be configured".to_string()
             ));
         }
         
+        if let Some(discovery) = &self.discovery {
+            url::Url::parse(&discovery.url)
+                .map_err(|e| ClientError::Configuration(format!("Invalid discovery URL '{}': {}", discovery.url, e)))?;
+        }
+        
         for agent in &self.agents {
             agent.validate()?;
         }
         
+        for rewrite in &self.path_rewrites {
+            if !rewrite.from.starts_with('/') || !rewrite.to.starts_with('/') {
+                return Err(ClientError::Configuration(format!(
+                    "Path rewrite '{}' -> '{}' must map an absolute path to an absolute path",
+                    rewrite.from, rewrite.to
+                )));
+            }
+        }
+        
         Ok(())
     }
     
@@ -387,6 +465,13 @@ impl ConnectionConfig {
     }
 }
 
+impl RelayDiscoveryConfig {
+    /// Get probe timeout as Duration
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms)
+    }
+}
+
 impl AgentConfig {
     /// Validate agent configuration
     pub fn validate(&self) -> ClientResult<()> {
@@ -423,8 +508,10 @@ fn default_operation_timeout() -> u64 { 30000 }
 fn default_max_retries() -> u32 { 3 }
 fn default_read_buffer_size() -> usize { 8192 }
 fn default_write_buffer_size() -> usize { 8192 }
+fn default_max_fallback_entries() -> usize { 100_000 }
 fn default_connection_timeout() -> u64 { 10000 }
 fn default_heartbeat_interval() -> u64 { 30000 }
+fn default_probe_timeout() -> u64 { 2000 }
 fn default_max_message_size() -> usize { 64 * 1024 * 1024 } // 64MB
 fn default_max_reconnect_attempts() -> u32 { 5 }
 fn default_reconnect_delay() -> u64 { 1000 }
@@ -1,10 +1,16 @@
 use crate::config::{AgentConfig, ConnectionConfig};
 use crate::error::{ClientError, ClientResult};
-use remotefs_common::protocol::Message;
+use crate::recording::{Direction, Recorder};
+use crate::simulate::{self, SimulatedLink};
+use remotefs_common::{
+    compression::{self, AutoDisable, CompressionStats},
+    error::RemoteFsError,
+    protocol::{AgentEvent, ErrorCode, MaintenanceWindow, Message},
+};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::{mpsc, oneshot, RwLock, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
 use tokio::time::timeout;
 use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage, WebSocketStream};
 use futures::{SinkExt, StreamExt};
@@ -35,11 +41,129 @@ pub struct ConnectionStats {
     pub last_connected: Option<Instant>,
     pub last_disconnected: Option<Instant>,
     pub total_uptime: Duration,
+    /// Payload compression results for messages sent on this connection
+    pub compression: CompressionStats,
+}
+
+/// How many agent events a subscriber may fall behind before it misses some
+const EVENT_BACKLOG: usize = 64;
+
+/// An event an agent broadcast to its clients through the relay
+#[derive(Debug, Clone)]
+pub struct AgentNotice {
+    pub agent_id: String,
+    pub event: AgentEvent,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+/// What agents and the relay announce outside of any request
+///
+/// A pool's connections share one, so announcements arriving on any of them
+/// reach the same subscribers.
+#[derive(Debug, Clone)]
+pub struct Announcements {
+    events: broadcast::Sender<AgentNotice>,
+    /// Latest maintenance windows the relay reported
+    maintenance: Arc<RwLock<Vec<MaintenanceWindow>>>,
+}
+
+impl Announcements {
+    pub fn new() -> Self {
+        Self {
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            maintenance: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+    
+    /// Receive the events agents broadcast from now on
+    pub fn subscribe_events(&self) -> broadcast::Receiver<AgentNotice> {
+        self.events.subscribe()
+    }
+    
+    /// Maintenance windows the relay last reported
+    pub async fn maintenance(&self) -> Vec<MaintenanceWindow> {
+        self.maintenance.read().await.clone()
+    }
+    
+    /// Replace the reported maintenance windows
+    pub async fn set_maintenance(&self, windows: Vec<MaintenanceWindow>) {
+        *self.maintenance.write().await = windows;
+    }
+}
+
+impl Default for Announcements {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes a message takes on the wire, uncompressed
+fn wire_size(message: &Message) -> u64 {
+    bincode::serialized_size(message).unwrap_or(0)
 }
 
 /// Response waiter for request-response pattern
 type ResponseWaiter = oneshot::Sender<ClientResult<Message>>;
 
+/// Response waiter for requests answered by several messages
+type StreamWaiter = mpsc::UnboundedSender<ClientResult<Message>>;
+
+/// Responses to a streamed request, in the order they arrive
+pub struct ResponseStream {
+    request_id: Uuid,
+    responses: mpsc::UnboundedReceiver<ClientResult<Message>>,
+    pending_streams: Arc<DashMap<Uuid, StreamWaiter>>,
+    operation_timeout: Option<Duration>,
+    finished: bool,
+}
+
+impl ResponseStream {
+    /// ID of the request the responses answer
+    pub fn request_id(&self) -> Uuid {
+        self.request_id
+    }
+    
+    /// Wait for responses as long as it takes, for streams such as watches
+    /// that may be quiet for a long time
+    pub fn without_timeout(mut self) -> Self {
+        self.operation_timeout = None;
+        self
+    }
+    
+    /// Wait for the next response, or `None` once the last one was received
+    ///
+    /// Each response has the full operation timeout, so a long stream is
+    /// not cut short as long as it keeps making progress.
+    pub async fn next(&mut self) -> Option<ClientResult<Message>> {
+        if self.finished {
+            return None;
+        }
+        
+        let received = match self.operation_timeout {
+            Some(operation_timeout) => timeout(operation_timeout, self.responses.recv()).await
+                .map_err(|_| ClientError::Timeout { seconds: operation_timeout.as_secs() }),
+            None => Ok(self.responses.recv().await),
+        };
+        let response = match received {
+            Ok(Some(response)) => response,
+            Ok(None) => Err(ClientError::Connection("Connection closed".to_string())),
+            Err(e) => Err(e),
+        };
+        
+        self.finished = match &response {
+            Ok(message) => message.ends_request(),
+            Err(_) => true,
+        };
+        Some(response)
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        self.pending_streams.remove(&self.request_id);
+    }
+}
+
 /// WebSocket connection to a RemoteFS agent
 pub struct AgentConnection {
     /// Agent configuration
@@ -57,9 +181,18 @@ pub struct AgentConnection {
     /// Pending requests waiting for responses
     pending_requests: Arc<DashMap<Uuid, ResponseWaiter>>,
     
+    /// Pending streamed requests waiting for their remaining responses
+    pending_streams: Arc<DashMap<Uuid, StreamWaiter>>,
+    
     /// Channel for sending messages to the connection task
     message_sender: Option<mpsc::UnboundedSender<Message>>,
     
+    /// Agent events and maintenance windows
+    announcements: Announcements,
+    
+    /// Recording of the messages exchanged, if one was asked for
+    recorder: Option<Recorder>,
+    
     /// Shutdown signal
     shutdown_tx: Option<oneshot::Sender<()>>,
     
@@ -76,12 +209,32 @@ impl AgentConnection {
             state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
             stats: Arc::new(RwLock::new(ConnectionStats::default())),
             pending_requests: Arc::new(DashMap::new()),
+            pending_streams: Arc::new(DashMap::new()),
             message_sender: None,
+            announcements: Announcements::new(),
+            recorder: None,
             shutdown_tx: None,
             tasks: Vec::new(),
         }
     }
     
+    /// Publish announcements on `announcements` instead of its own
+    pub fn with_announcements(mut self, announcements: Announcements) -> Self {
+        self.announcements = announcements;
+        self
+    }
+    
+    /// Agent events and maintenance windows received on this connection
+    pub fn announcements(&self) -> &Announcements {
+        &self.announcements
+    }
+    
+    /// Record the messages exchanged with the agent on `recorder`
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+    
     /// Connect to the agent
     pub async fn connect(&mut self) -> ClientResult<()> {
         if self.is_connected().await {
@@ -190,6 +343,10 @@ impl AgentConnection {
         }
         
         match response {
+            // Load shed by the agent or relay; an error so the request is retried
+            Ok(Ok(Ok(Message::Error { code: ErrorCode::ServiceUnavailable, message, .. }))) => {
+                Err(ClientError::RemoteFs(RemoteFsError::ServiceUnavailable(message)))
+            }
             Ok(Ok(result)) => result,
             Ok(Err(e)) => Err(ClientError::Internal(format!("Response channel error: {}", e))),
             Err(_) => Err(ClientError::Timeout { 
@@ -198,6 +355,31 @@ impl AgentConnection {
         }
     }
     
+    /// Send a request answered by a stream of responses
+    pub async fn send_streaming_request(&self, message: Message) -> ClientResult<ResponseStream> {
+        let request_id = message.request_id()
+            .ok_or_else(|| ClientError::Internal("Streamed request without a request ID".to_string()))?;
+        
+        let (responses_tx, responses) = mpsc::unbounded_channel();
+        self.pending_streams.insert(request_id, responses_tx);
+        let stream = ResponseStream {
+            request_id,
+            responses,
+            pending_streams: Arc::clone(&self.pending_streams),
+            operation_timeout: Some(self.connection_config.operation_timeout()),
+            finished: false,
+        };
+        
+        self.send_message(message).await?;
+        Ok(stream)
+    }
+    
+    /// Channel queueing messages for the agent, for senders that cannot wait
+    /// for the connection's lock, e.g. in `Drop`
+    pub(crate) fn message_sender(&self) -> Option<mpsc::UnboundedSender<Message>> {
+        self.message_sender.clone()
+    }
+    
     /// Send a message without waiting for response
     pub async fn send_message(&self, message: Message) -> ClientResult<()> {
         let sender = self.message_sender.as_ref()
@@ -259,29 +441,61 @@ impl AgentConnection {
         .map_err(|_| ClientError::Timeout { 
             seconds: self.connection_config.connect_timeout_ms / 1000 
         })?
-        .map_err(|e| ClientError::Network(e))?;
+        .map_err(ClientError::Network)?;
         
         let (ws_sink, ws_stream) = ws_stream.split();
         
         // Create channels
-        let (message_tx, message_rx) = mpsc::unbounded_channel();
+        let (message_tx, mut message_rx) = mpsc::unbounded_channel();
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         
         // Start background tasks
         let mut tasks = Vec::new();
         
+        // Hold messages in both directions back as the simulated network would
+        let mut simulated_receiver = None;
+        if let Some(simulation) = &self.connection_config.simulate {
+            warn!("Simulating {:?} on the connection to agent {}", simulation, self.config.id);
+            message_rx = simulate::delay(SimulatedLink::new(simulation.clone()), message_rx, wire_size);
+            
+            let (received_tx, received_rx) = mpsc::unbounded_channel();
+            let mut delivered = simulate::delay(SimulatedLink::new(simulation.clone()), received_rx, wire_size);
+            let agent_id = self.config.id.clone();
+            let pending_requests = self.pending_requests.clone();
+            let pending_streams = self.pending_streams.clone();
+            let announcements = self.announcements.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Some(message) = delivered.recv().await {
+                    Self::handle_received_message_static(
+                        agent_id.clone(),
+                        pending_requests.clone(),
+                        pending_streams.clone(),
+                        &announcements,
+                        message
+                    ).await;
+                }
+            }));
+            simulated_receiver = Some(received_tx);
+        }
+        
         // Clone the necessary components for the tasks
         let agent_id = self.config.id.clone();
         let stats = self.stats.clone();
         let state = self.state.clone();
         let pending_requests = self.pending_requests.clone();
+        let pending_streams = self.pending_streams.clone();
+        let announcements = self.announcements.clone();
+        let recorder = self.recorder.clone();
         let heartbeat_interval_ms = self.connection_config.heartbeat_interval_ms;
+        let compress = self.connection_config.enable_compression;
         
         // Message sender task
         tasks.push(tokio::spawn(
             Self::message_sender_task(
                 agent_id.clone(),
                 stats.clone(),
+                recorder.clone(),
+                compress,
                 ws_sink,
                 message_rx,
                 shutdown_rx,
@@ -295,6 +509,10 @@ impl AgentConnection {
                 state,
                 stats,
                 pending_requests,
+                pending_streams,
+                announcements,
+                recorder,
+                simulated_receiver,
                 ws_stream,
             )
         ));
@@ -313,20 +531,34 @@ impl AgentConnection {
         Ok((message_tx, shutdown_tx, tasks))
     }
     
-    /// Task for sending messages to WebSocket
+    /// Task for sending messages to WebSocket, compressing large messages
+    /// when `compress` is set
     async fn message_sender_task(
         agent_id: String,
         stats: Arc<RwLock<ConnectionStats>>,
+        recorder: Option<Recorder>,
+        compress: bool,
         mut ws_sink: futures::stream::SplitSink<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>, WsMessage>,
         mut message_rx: mpsc::UnboundedReceiver<Message>,
         mut shutdown_rx: oneshot::Receiver<()>,
     ) {
+        let mut heuristic = AutoDisable::default();
         loop {
             tokio::select! {
                 message = message_rx.recv() => {
                     match message {
                         Some(msg) => {
-                            match bincode::serialize(&msg) {
+                            if let Some(recorder) = &recorder {
+                                recorder.record(&agent_id, Direction::Sent, &msg);
+                            }
+                            let encoded = if compress {
+                                let mut stats_guard = stats.write().await;
+                                compression::encode_message(&msg, &mut heuristic, &mut stats_guard.compression)
+                                    .map_err(|e| e.to_string())
+                            } else {
+                                bincode::serialize(&msg).map_err(|e| e.to_string())
+                            };
+                            match encoded {
                                 Ok(data) => {
                                     let data_len = data.len();
                                     let ws_msg = WsMessage::Binary(data);
@@ -360,28 +592,42 @@ impl AgentConnection {
     }
     
     /// Task for receiving messages from WebSocket
+    #[allow(clippy::too_many_arguments)]
     async fn message_receiver_task(
         agent_id: String,
         state: Arc<RwLock<ConnectionState>>,
         stats: Arc<RwLock<ConnectionStats>>,
         pending_requests: Arc<DashMap<Uuid, ResponseWaiter>>,
+        pending_streams: Arc<DashMap<Uuid, StreamWaiter>>,
+        announcements: Announcements,
+        recorder: Option<Recorder>,
+        simulated: Option<mpsc::UnboundedSender<Message>>,
         mut ws_stream: futures::stream::SplitStream<WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>>,
     ) {
         while let Some(ws_msg) = ws_stream.next().await {
             match ws_msg {
                 Ok(WsMessage::Binary(data)) => {
-                    match bincode::deserialize::<Message>(&data) {
-                        Ok(message) => {
+                    match compression::decode_message(&data) {
+                        Ok((message, _)) => {
                             // Update receive stats
                             {
                                 let mut stats_guard = stats.write().await;
                                 stats_guard.messages_received += 1;
                                 stats_guard.bytes_received += data.len() as u64;
                             }
+                            if let Some(recorder) = &recorder {
+                                recorder.record(&agent_id, Direction::Received, &message);
+                            }
                             
+                            if let Some(simulated) = &simulated {
+                                let _ = simulated.send(message);
+                                continue;
+                            }
                             Self::handle_received_message_static(
                                 agent_id.clone(),
                                 pending_requests.clone(),
+                                pending_streams.clone(),
+                                &announcements,
                                 message
                             ).await;
                         }
@@ -423,15 +669,57 @@ impl AgentConnection {
     async fn handle_received_message_static(
         agent_id: String,
         pending_requests: Arc<DashMap<Uuid, ResponseWaiter>>,
+        pending_streams: Arc<DashMap<Uuid, StreamWaiter>>,
+        announcements: &Announcements,
         message: Message,
     ) {
         let request_id = message.request_id();
         
         // Check if this is a response to a pending request
         if let Some(request_id) = request_id {
-            if let Some((_, response_tx)) = pending_requests.remove(&request_id) {
+            if let Some(responses_tx) = pending_streams.get(&request_id).map(|entry| entry.clone()) {
+                if message.ends_request() {
+                    pending_streams.remove(&request_id);
+                }
+                let _ = responses_tx.send(Ok(message));
+            } else if let Some((_, response_tx)) = pending_requests.remove(&request_id) {
                 let _ = response_tx.send(Ok(message));
             }
+        } else if let Message::MirrorStatus { primary, mirror, promoted, writes_allowed, .. } = &message {
+            if *promoted {
+                warn!(
+                    "Agent {} is unavailable; mirror {} now serves {}. It may not have replicated the most recent changes",
+                    primary, mirror, if *writes_allowed { "reads and writes" } else { "reads only" }
+                );
+            } else {
+                info!("Agent {} is back; mirror {} is read-only again", primary, mirror);
+            }
+        } else if let Message::Broadcast { agent_id, event, timestamp } = message {
+            match &event {
+                AgentEvent::Maintenance { message, starts_at: Some(starts_at) } => {
+                    warn!("Agent {} goes down for maintenance at {}: {}", agent_id, starts_at, message);
+                }
+                AgentEvent::Maintenance { message, starts_at: None } => {
+                    warn!("Agent {} is going down for maintenance: {}", agent_id, message);
+                }
+                AgentEvent::ShuttingDown => warn!("Agent {} is shutting down", agent_id),
+            }
+            // Nobody may be listening
+            let _ = announcements.events.send(AgentNotice { agent_id, event, timestamp });
+        } else if let Message::MaintenanceStatus { windows, .. } = message {
+            for window in &windows {
+                warn!(
+                    "Maintenance of {} from {}{}: {}",
+                    window.agent_id.as_deref().map_or_else(|| "the relay".to_string(), |agent_id| format!("agent {}", agent_id)),
+                    window.starts_at,
+                    window.ends_at.map(|ends_at| format!(" until {}", ends_at)).unwrap_or_default(),
+                    window.message
+                );
+            }
+            if windows.is_empty() {
+                info!("No maintenance scheduled");
+            }
+            announcements.set_maintenance(windows).await;
         } else {
             // This is an unsolicited message (notification, event, etc.)
             debug!("Received unsolicited message from agent {}: {:?}", agent_id, message);
@@ -449,17 +737,23 @@ impl AgentConnection {
         );
         
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                // Stop with the sender task instead of at the next tick, so
+                // disconnecting does not wait out the interval
+                _ = message_tx.closed() => break,
+            }
             
             let heartbeat = Message::Ping {
                 timestamp: chrono::Utc::now(),
             };
             
             if message_tx.send(heartbeat).is_err() {
-                debug!("Heartbeat channel closed for agent {}", agent_id);
                 break;
             }
         }
+        
+        debug!("Heartbeat channel closed for agent {}", agent_id);
     }
 }
 
@@ -474,9 +768,13 @@ impl Drop for AgentConnection {
 
 /// Connection pool for managing multiple agent connections
 pub struct ConnectionPool {
-    connections: Arc<RwLock<Vec<Arc<Mutex<AgentConnection>>>>>,
+    connections: Arc<RwLock<Vec<Arc<RwLock<AgentConnection>>>>>,
     connection_config: ConnectionConfig,
     load_balancer: Arc<AtomicU64>,
+    /// Announcements arriving on any of the connections
+    announcements: Announcements,
+    /// Recording shared by the connections, if one was asked for
+    recorder: Option<Recorder>,
 }
 
 impl ConnectionPool {
@@ -486,20 +784,31 @@ impl ConnectionPool {
             connections: Arc::new(RwLock::new(Vec::new())),
             connection_config,
             load_balancer: Arc::new(AtomicU64::new(0)),
+            announcements: Announcements::new(),
+            recorder: None,
         }
     }
     
+    /// Record the messages of every connection on `recorder`
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+    
     /// Add an agent to the pool
     pub async fn add_agent(&self, agent_config: AgentConfig) {
-        let connection = Arc::new(Mutex::new(
-            AgentConnection::new(agent_config, self.connection_config.clone())
-        ));
+        let mut connection = AgentConnection::new(agent_config, self.connection_config.clone())
+            .with_announcements(self.announcements.clone());
+        if let Some(recorder) = &self.recorder {
+            connection = connection.with_recorder(recorder.clone());
+        }
+        let connection = Arc::new(RwLock::new(connection));
         
         self.connections.write().await.push(connection);
     }
     
     /// Get the next available connection using load balancing
-    pub async fn get_connection(&self) -> ClientResult<Arc<Mutex<AgentConnection>>> {
+    pub async fn get_connection(&self) -> ClientResult<Arc<RwLock<AgentConnection>>> {
         let connections = self.connections.read().await;
         
         if connections.is_empty() {
@@ -512,29 +821,63 @@ impl ConnectionPool {
         let index = self.load_balancer.fetch_add(1, Ordering::Relaxed) as usize % connections.len();
         let connection = connections[index].clone();
         
-        // Check if connection is healthy, try to connect if not
-        {
-            let mut conn = connection.lock().await;
-            if !conn.is_connected().await {
-                conn.connect().await?;
-            }
+        // Check if connection is healthy, try to connect if not. Requests
+        // share the connection and only a reconnect has it to itself.
+        let connected = connection.read().await.is_connected().await;
+        if !connected {
+            connection.write().await.connect().await?;
         }
         
         Ok(connection)
     }
     
+    /// Agent events and maintenance windows, whichever connection they
+    /// arrive on
+    pub fn announcements(&self) -> &Announcements {
+        &self.announcements
+    }
+    
     /// Get all connections
-    pub async fn get_all_connections(&self) -> Vec<Arc<Mutex<AgentConnection>>> {
+    pub async fn get_all_connections(&self) -> Vec<Arc<RwLock<AgentConnection>>> {
         self.connections.read().await.clone()
     }
     
+    /// IDs of the pooled agents, in pool order
+    pub async fn agent_ids(&self) -> Vec<String> {
+        let connections = self.connections.read().await.clone();
+        let mut ids = Vec::with_capacity(connections.len());
+        for connection in connections {
+            ids.push(connection.read().await.agent_config().id.clone());
+        }
+        ids
+    }
+    
     /// Connect all agents
     pub async fn connect_all(&self) -> Vec<ClientResult<()>> {
         let connections = self.connections.read().await.clone();
         let mut results = Vec::new();
         
         for connection in connections {
-            let mut conn = connection.lock().await;
+            let mut conn = connection.write().await;
+            results.push(conn.connect().await);
+        }
+        
+        results
+    }
+    
+    /// Drop and re-establish every connection
+    ///
+    /// Connections can look healthy after the host sleeps or changes network
+    /// while their sockets are dead, so this does not check `is_connected`.
+    pub async fn reconnect_all(&self) -> Vec<ClientResult<()>> {
+        let connections = self.connections.read().await.clone();
+        let mut results = Vec::new();
+        
+        for connection in connections {
+            let mut conn = connection.write().await;
+            if let Err(e) = conn.disconnect().await {
+                debug!("Error dropping connection to agent {}: {}", conn.agent_config().id, e);
+            }
             results.push(conn.connect().await);
         }
         
@@ -547,7 +890,7 @@ impl ConnectionPool {
         let mut results = Vec::new();
         
         for connection in connections {
-            let mut conn = connection.lock().await;
+            let mut conn = connection.write().await;
             results.push(conn.disconnect().await);
         }
         
@@ -0,0 +1,135 @@
+//! Relay discovery for multi-region deployments
+//!
+//! Every relay serves a directory of the relays clients may use. The client
+//! fetches it from the configured discovery URL, measures the round-trip time
+//! of a ping to each listed relay and connects to the fastest one. Relays that
+//! do not answer within the probe timeout are skipped.
+
+use crate::config::RelayDiscoveryConfig;
+use crate::error::{ClientError, ClientResult};
+use futures::{future, SinkExt, StreamExt};
+use remotefs_common::protocol::{Message, RelayDirectory, RelayEndpoint};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tracing::{debug, info};
+
+/// Fetch the directory and pick the relay with the lowest latency
+pub async fn discover_relay(config: &RelayDiscoveryConfig) -> ClientResult<RelayEndpoint> {
+    let probe_timeout = config.probe_timeout();
+    let directory = fetch_directory(&config.url, probe_timeout).await?;
+    debug!("Relay directory lists {} relays", directory.relays.len());
+    nearest_relay(&directory, probe_timeout).await
+}
+
+/// Ask the relay at `url` for its relay directory
+pub async fn fetch_directory(url: &str, probe_timeout: Duration) -> ClientResult<RelayDirectory> {
+    match exchange(url, Message::GetRelayDirectory, probe_timeout).await?.0 {
+        Message::RelayDirectoryResponse { directory } => Ok(directory),
+        other => Err(ClientError::InvalidResponse(format!(
+            "Expected RelayDirectoryResponse, got {}",
+            other.message_type()
+        ))),
+    }
+}
+
+/// Round-trip time of a ping to the relay at `url`
+pub async fn probe_latency(url: &str, probe_timeout: Duration) -> ClientResult<Duration> {
+    let ping = Message::Ping { timestamp: chrono::Utc::now() };
+    match exchange(url, ping, probe_timeout).await? {
+        (Message::Pong { .. }, elapsed) => Ok(elapsed),
+        (other, _) => Err(ClientError::InvalidResponse(format!(
+            "Expected Pong, got {}",
+            other.message_type()
+        ))),
+    }
+}
+
+/// Probe every relay in `directory` concurrently and return the fastest
+pub async fn nearest_relay(directory: &RelayDirectory, probe_timeout: Duration) -> ClientResult<RelayEndpoint> {
+    let probes = directory.relays.iter().map(|relay| async move {
+        let latency = probe_latency(&relay.url, probe_timeout).await;
+        match &latency {
+            Ok(latency) => debug!("Relay {} answered in {:?}", relay.id, latency),
+            Err(e) => debug!("Relay {} did not answer: {}", relay.id, e),
+        }
+        latency.ok()
+    });
+    let latencies = future::join_all(probes).await;
+
+    let relay = fastest(&directory.relays, &latencies)
+        .cloned()
+        .ok_or_else(|| ClientError::Connection("No relay from the directory answered".to_string()))?;
+    info!(
+        "Selected relay {} ({})",
+        relay.id,
+        relay.region.as_deref().unwrap_or("unknown region")
+    );
+    Ok(relay)
+}
+
+/// Relay with the lowest measured latency; unreachable relays have `None`
+fn fastest<'a>(relays: &'a [RelayEndpoint], latencies: &[Option<Duration>]) -> Option<&'a RelayEndpoint> {
+    relays
+        .iter()
+        .zip(latencies)
+        .filter_map(|(relay, latency)| latency.map(|latency| (relay, latency)))
+        .min_by_key(|(_, latency)| *latency)
+        .map(|(relay, _)| relay)
+}
+
+/// Send one message on a fresh connection and wait for the first reply,
+/// returning it with the time between sending and receiving
+async fn exchange(url: &str, message: Message, probe_timeout: Duration) -> ClientResult<(Message, Duration)> {
+    let url = url::Url::parse(url)?;
+    let result = timeout(probe_timeout, async {
+        let (mut ws_stream, _) = connect_async(url).await?;
+        let started = Instant::now();
+        ws_stream.send(WsMessage::Binary(bincode::serialize(&message)?)).await?;
+
+        while let Some(ws_msg) = ws_stream.next().await {
+            if let WsMessage::Binary(data) = ws_msg? {
+                let elapsed = started.elapsed();
+                let _ = ws_stream.close(None).await;
+                return Ok((bincode::deserialize::<Message>(&data)?, elapsed));
+            }
+        }
+        Err(ClientError::Connection("Relay closed the connection".to_string()))
+    })
+    .await;
+
+    result.map_err(|_| ClientError::Timeout { seconds: probe_timeout.as_secs() })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay(id: &str) -> RelayEndpoint {
+        RelayEndpoint {
+            id: id.to_string(),
+            url: format!("ws://{}.example.com/ws", id),
+            region: None,
+        }
+    }
+
+    #[test]
+    fn test_fastest_relay() {
+        let relays = vec![relay("us"), relay("eu"), relay("ap")];
+        let latencies = [
+            Some(Duration::from_millis(80)),
+            None,
+            Some(Duration::from_millis(30)),
+        ];
+        assert_eq!(fastest(&relays, &latencies).map(|r| r.id.as_str()), Some("ap"));
+        assert!(fastest(&relays, &[None, None, None]).is_none());
+        assert!(fastest(&[], &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_relays() {
+        let directory = RelayDirectory { relays: vec![relay("unreachable")] };
+        let result = nearest_relay(&directory, Duration::from_millis(200)).await;
+        assert!(matches!(result, Err(ClientError::Connection(_))));
+    }
+}
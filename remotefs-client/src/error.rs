@@ -1,5 +1,5 @@
 use thiserror::Error;
-use remotefs_common::error::RemoteFsError;
+use remotefs_common::{error::RemoteFsError, protocol::RequestId};
 
 /// Client-specific error types
 #[derive(Error, Debug)]
@@ -28,6 +28,11 @@ pub enum ClientError {
     #[error("Invalid response: {0}")]
     InvalidResponse(String),
     
+    /// The agent refused a request beyond one of its limits, such as a read
+    /// too large for one response; `limit` names it and `max` is its value
+    #[error("{message}")]
+    LimitExceeded { limit: String, max: u64, message: String },
+    
     #[error("Remote filesystem error: {0}")]
     RemoteFs(#[from] RemoteFsError),
     
@@ -39,12 +44,45 @@ pub enum ClientError {
     
     #[error("Internal error: {0}")]
     Internal(String),
+    
+    /// An error answering a request, with the ID the relay and agent log it
+    /// under; retries resend the request with the same ID
+    #[error("{source} (request {request_id})")]
+    Request {
+        request_id: RequestId,
+        source: Box<ClientError>,
+    },
 }
 
 impl ClientError {
+    /// Record the request this error answers, unless it is already known
+    pub fn for_request(self, request_id: Option<RequestId>) -> Self {
+        match (self, request_id) {
+            (error @ ClientError::Request { .. }, _) | (error, None) => error,
+            (error, Some(request_id)) => ClientError::Request { request_id, source: Box::new(error) },
+        }
+    }
+    
+    /// ID of the request that failed, if known
+    pub fn request_id(&self) -> Option<RequestId> {
+        match self {
+            ClientError::Request { request_id, .. } => Some(*request_id),
+            _ => None,
+        }
+    }
+    
+    /// The error itself, without the request it answers; match on this
+    pub fn cause(&self) -> &ClientError {
+        match self {
+            ClientError::Request { source, .. } => source.cause(),
+            error => error,
+        }
+    }
+    
     /// Check if the error is retryable
     pub fn is_retryable(&self) -> bool {
         match self {
+            ClientError::Request { source, .. } => source.is_retryable(),
             ClientError::Network(_) => true,
             ClientError::Connection(_) => true,
             ClientError::Timeout { .. } => true,
@@ -57,6 +95,7 @@ impl ClientError {
     /// Check if the error is temporary
     pub fn is_temporary(&self) -> bool {
         match self {
+            ClientError::Request { source, .. } => source.is_temporary(),
             ClientError::Network(_) => true,
             ClientError::Connection(_) => true,
             ClientError::Timeout { .. } => true,
@@ -69,3 +108,27 @@ impl ClientError {
 
 /// Result type for client operations
 pub type ClientResult<T> = Result<T, ClientError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::protocol::generate_request_id;
+
+    #[test]
+    fn test_errors_name_their_request() {
+        let request_id = generate_request_id();
+        let error = ClientError::RemoteFs(RemoteFsError::NotFound("/data".to_string())).for_request(Some(request_id));
+
+        assert_eq!(error.request_id(), Some(request_id));
+        assert!(matches!(error.cause(), ClientError::RemoteFs(RemoteFsError::NotFound(_))));
+        assert!(error.to_string().ends_with(&format!("(request {})", request_id)));
+
+        // The first request recorded is kept
+        let again = error.for_request(Some(generate_request_id()));
+        assert_eq!(again.request_id(), Some(request_id));
+
+        let timeout = ClientError::Timeout { seconds: 30 }.for_request(Some(request_id));
+        assert!(timeout.is_retryable());
+        assert_eq!(ClientError::Internal("x".to_string()).for_request(None).request_id(), None);
+    }
+}
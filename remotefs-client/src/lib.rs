@@ -4,18 +4,31 @@
 //! Provides high-level filesystem operations over WebSocket connections with
 //! support for load balancing, retries, and connection pooling.
 
+// `ClientError` wraps tungstenite errors by value, which trips this lint everywhere
+#![allow(clippy::result_large_err)]
+
+pub mod cli;
 mod client;
 mod config;
 mod connection;
+mod discovery;
 mod error;
+mod local;
+mod recording;
+mod rewrite;
+mod simulate;
 
 pub use client::*;
 pub use config::*;
 pub use connection::*;
+pub use discovery::*;
 pub use error::*;
+pub use local::*;
+pub use recording::*;
+pub use rewrite::*;
 
 // Type alias for convenience
 pub type Client = RemoteFsClient;
 
 // Re-export common types for convenience
-pub use remotefs_common::{error::RemoteFsError, protocol::*};
+pub use remotefs_common::{checksum::Checksum, delta::{DeltaOp, FileSignature}, error::RemoteFsError, protocol::*};
@@ -0,0 +1,177 @@
+//! Reads through an agent on the same host
+//!
+//! An agent with a `local_socket` hands out file descriptors instead of
+//! file data. Reads through it skip the protocol and the relay entirely.
+
+use crate::error::{ClientError, ClientResult};
+use remotefs_common::{
+    error::RemoteFsError,
+    protocol::{LocalOpenRequest, LocalOpenResponse},
+};
+use std::{
+    fs::File,
+    io,
+    os::unix::{
+        fs::FileExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncWriteExt, Interest},
+    net::UnixStream,
+    sync::Mutex,
+};
+
+/// Longest response line accepted from the agent
+const MAX_RESPONSE_LINE: usize = 64 * 1024;
+
+/// Connection to an agent's local socket, opened on first use and again
+/// after it fails
+pub struct LocalFiles {
+    socket_path: PathBuf,
+    stream: Mutex<Option<UnixStream>>,
+}
+
+impl LocalFiles {
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// Open `path` on the agent for reading
+    pub async fn open(&self, path: &str) -> ClientResult<File> {
+        let mut stream = self.stream.lock().await;
+        let connection = match stream.as_mut() {
+            Some(connection) => connection,
+            None => stream.insert(UnixStream::connect(&self.socket_path).await?),
+        };
+
+        let result = request(connection, path).await;
+        if let Err(ClientError::Io(_) | ClientError::InvalidResponse(_)) = &result {
+            // Reconnect next time rather than reuse a stream in an unknown state
+            *stream = None;
+        }
+        result
+    }
+
+    /// Read up to `length` bytes of `path` from `offset`, or everything after
+    /// `offset` without a length
+    pub async fn read(&self, path: &str, offset: u64, length: Option<u64>) -> ClientResult<Vec<u8>> {
+        let file = self.open(path).await?;
+        tokio::task::spawn_blocking(move || read_range(&file, offset, length))
+            .await
+            .map_err(|e| ClientError::Internal(format!("Local read failed: {}", e)))?
+            .map_err(ClientError::Io)
+    }
+
+    /// Socket this connects to
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+}
+
+async fn request(stream: &mut UnixStream, path: &str) -> ClientResult<File> {
+    let mut line = serde_json::to_vec(&LocalOpenRequest { path: path.to_string() })
+        .map_err(|e| ClientError::Internal(format!("Failed to encode local request: {}", e)))?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+
+    let mut response = Vec::new();
+    let mut fd = None;
+    while !response.ends_with(b"\n") {
+        if response.len() > MAX_RESPONSE_LINE {
+            return Err(ClientError::InvalidResponse("Local response too long".to_string()));
+        }
+        stream.readable().await?;
+        match stream.try_io(Interest::READABLE, || recv_with_fd(stream.as_raw_fd(), &mut response)) {
+            Ok((0, _)) => return Err(ClientError::Io(io::ErrorKind::UnexpectedEof.into())),
+            Ok((_, received)) => fd = fd.or(received),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let response: LocalOpenResponse = serde_json::from_slice(&response)
+        .map_err(|e| ClientError::InvalidResponse(format!("Invalid local response: {}", e)))?;
+    match (response.error, fd) {
+        (Some((code, message)), _) => Err(ClientError::RemoteFs(RemoteFsError::from_error_code(code, message))),
+        (None, Some(fd)) => Ok(File::from(fd)),
+        (None, None) => Err(ClientError::InvalidResponse("Local response carried no file".to_string())),
+    }
+}
+
+/// Receive what is available on `socket` into `buffer`, along with a
+/// descriptor if one is attached
+fn recv_with_fd(socket: RawFd, buffer: &mut Vec<u8>) -> io::Result<(usize, Option<OwnedFd>)> {
+    let mut chunk = [0u8; 4096];
+    let mut iov = libc::iovec {
+        iov_base: chunk.as_mut_ptr().cast(),
+        iov_len: chunk.len(),
+    };
+    // u64 elements keep the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    // SAFETY: a zeroed msghdr is a valid empty message
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+
+    // SAFETY: `message` points at `chunk` and `control`, both alive for the call
+    let received = unsafe { libc::recvmsg(socket, &mut message, flags) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let received = received as usize;
+    buffer.extend_from_slice(&chunk[..received]);
+
+    let mut fd = None;
+    // SAFETY: the kernel filled in the control messages it reported in
+    // `message`; each SCM_RIGHTS message carries descriptors now owned by us
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                let count = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+                for index in 0..count {
+                    let received = OwnedFd::from_raw_fd(std::ptr::read_unaligned(data.add(index)));
+                    // Keep the first; any others close when dropped
+                    if fd.is_none() {
+                        fd = Some(received);
+                    }
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    Ok((received, fd))
+}
+
+fn read_range(file: &File, offset: u64, length: Option<u64>) -> io::Result<Vec<u8>> {
+    let size = file.metadata()?.len();
+    let remaining = size.saturating_sub(offset);
+    let to_read = length.map_or(remaining, |length| length.min(remaining)) as usize;
+
+    let mut data = vec![0; to_read];
+    let mut filled = 0;
+    while filled < to_read {
+        match file.read_at(&mut data[filled..], offset + filled as u64) {
+            // The file shrank since it was sized
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(filled);
+    Ok(data)
+}
@@ -1,29 +1,10 @@
-mod client;
-mod config;
-mod connection;
-mod error;
-mod cli;
-
-use anyhow::Result;
 use clap::Parser;
-use cli::CliArgs;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use remotefs_client::cli::{self, CliArgs};
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let args = CliArgs::parse();
-    
-    match cli::run(args).await {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
-        }
+async fn main() {
+    if let Err(e) = cli::run(CliArgs::parse()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,354 @@
+//! Recording sessions with agents and replaying them
+//!
+//! With `logging.record_file` set, the client writes every message it sends
+//! to or receives from its agents to that file, one JSON object per line.
+//! Heartbeats are left out. A recording attached to a bug report lets the
+//! bug be reproduced without the reporter's environment: [`ReplayAgent`]
+//! stands in for their agents and answers each request with the responses
+//! recorded for it.
+//!
+//! Replays are deterministic. A request gets the responses of the first
+//! unplayed recorded request equal to it, apart from the request ID, or
+//! failing that of the same type. Responses are sent at once, whatever the
+//! recorded timing, and carry the ID of the request being replayed.
+
+use crate::error::{ClientError, ClientResult};
+use futures::{SinkExt, StreamExt};
+use remotefs_common::protocol::{ErrorCode, Message, RequestId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
+use tracing::{debug, info, warn};
+
+/// Whether the client sent or received a recorded message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One line of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    /// Time since the recording started
+    pub elapsed_ms: u64,
+    /// Agent the message was exchanged with
+    pub agent_id: String,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+struct RecorderFile {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+/// Recordings started by this process, by path
+static RECORDERS: LazyLock<Mutex<HashMap<PathBuf, Recorder>>> = LazyLock::new(Default::default);
+
+/// Writes the messages of a client's connections to a recording
+///
+/// Clones share the file, so the messages of all connections are recorded
+/// in the order they were exchanged.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<RecorderFile>>,
+}
+
+impl Recorder {
+    /// Start a recording at `path`, replacing any file there
+    ///
+    /// Clients of one process recording to the same path share the
+    /// recording rather than replacing each other's.
+    pub fn create<P: AsRef<Path>>(path: P) -> ClientResult<Self> {
+        let mut recorders = RECORDERS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(recorder) = recorders.get(path.as_ref()) {
+            return Ok(recorder.clone());
+        }
+
+        let file = File::create(path.as_ref())?;
+        info!("Recording agent messages to {}", path.as_ref().display());
+        let recorder = Self {
+            file: Arc::new(Mutex::new(RecorderFile {
+                writer: BufWriter::new(file),
+                started: Instant::now(),
+            })),
+        };
+        recorders.insert(path.as_ref().to_path_buf(), recorder.clone());
+        Ok(recorder)
+    }
+
+    /// Append `message` to the recording
+    pub fn record(&self, agent_id: &str, direction: Direction, message: &Message) {
+        if matches!(message, Message::Ping { .. } | Message::Pong { .. }) {
+            return;
+        }
+
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let line = RecordedMessage {
+            elapsed_ms: file.started.elapsed().as_millis() as u64,
+            agent_id: agent_id.to_string(),
+            direction,
+            message: message.clone(),
+        };
+        // Flushed line by line, so a crash loses nothing it saw
+        let written = serde_json::to_writer(&mut file.writer, &line)
+            .map_err(std::io::Error::from)
+            .and_then(|()| file.writer.write_all(b"\n"))
+            .and_then(|()| file.writer.flush());
+        if let Err(e) = written {
+            warn!("Failed to record {} message: {}", message.message_type(), e);
+        }
+    }
+}
+
+/// Read the messages of a recording
+pub fn load_recording<P: AsRef<Path>>(path: P) -> ClientResult<Vec<RecordedMessage>> {
+    let reader = BufReader::new(File::open(path.as_ref())?);
+    let mut messages = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = serde_json::from_str(&line).map_err(|e| ClientError::InvalidResponse(format!(
+            "Line {} of recording {}: {}", number + 1, path.as_ref().display(), e
+        )))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// A recorded request and what the client received in answer to it
+#[derive(Debug)]
+struct Exchange {
+    request: Message,
+    /// The request as JSON without its ID, for finding equal requests
+    key: serde_json::Value,
+    replies: Vec<Message>,
+    played: bool,
+}
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    exchanges: Vec<Exchange>,
+    /// Types of requests no recorded request matched
+    unmatched: Vec<String>,
+}
+
+/// Mock agent answering requests from a recording
+#[derive(Debug)]
+pub struct ReplayAgent {
+    /// Messages received before the first request, sent on connecting
+    greeting: Vec<Message>,
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayAgent {
+    /// Replay the exchanges in `recording`, with all agents' exchanges served
+    /// as if by one agent
+    pub fn new(recording: Vec<RecordedMessage>) -> Self {
+        let mut greeting = Vec::new();
+        let mut exchanges: Vec<Exchange> = Vec::new();
+        let mut by_request: HashMap<RequestId, usize> = HashMap::new();
+
+        for recorded in recording {
+            let message = recorded.message;
+            match (recorded.direction, message.request_id()) {
+                (Direction::Sent, Some(request_id)) => {
+                    by_request.insert(request_id, exchanges.len());
+                    exchanges.push(Exchange { key: request_key(&message), request: message, replies: Vec::new(), played: false });
+                }
+                (Direction::Sent, None) => {}
+                (Direction::Received, Some(request_id)) => match by_request.get(&request_id) {
+                    Some(&index) => exchanges[index].replies.push(message),
+                    None => debug!("Recorded {} answers no recorded request", message.message_type()),
+                },
+                // Announcements are replayed after the request they followed
+                (Direction::Received, None) => match exchanges.last_mut() {
+                    Some(exchange) => exchange.replies.push(message),
+                    None => greeting.push(message),
+                },
+            }
+        }
+
+        Self {
+            greeting,
+            state: Mutex::new(ReplayState { exchanges, unmatched: Vec::new() }),
+        }
+    }
+
+    /// Replay the recording at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> ClientResult<Self> {
+        Ok(Self::new(load_recording(path)?))
+    }
+
+    /// Recorded requests that have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.lock().exchanges.iter().filter(|exchange| !exchange.played).count()
+    }
+
+    /// Types of the requests that had no recorded match, in arrival order
+    pub fn unmatched(&self) -> Vec<String> {
+        self.lock().unmatched.clone()
+    }
+
+    /// Accept client connections on `listener` until it fails
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> ClientResult<()> {
+        info!("Replaying {} recorded requests on {}", self.remaining(), listener.local_addr()?);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("Replay connection from {}", peer);
+            let agent = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = agent.serve_connection(stream).await {
+                    warn!("Replay connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(&self, stream: TcpStream) -> ClientResult<()> {
+        let mut ws_stream = accept_async(stream).await?;
+        for message in &self.greeting {
+            ws_stream.send(WsMessage::Binary(bincode::serialize(message)?)).await?;
+        }
+
+        while let Some(ws_message) = ws_stream.next().await {
+            let data = match ws_message? {
+                WsMessage::Binary(data) => data,
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+            let request: Message = bincode::deserialize(&data)?;
+            for reply in self.answer(&request) {
+                ws_stream.send(WsMessage::Binary(bincode::serialize(&reply)?)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Messages answering `request`
+    fn answer(&self, request: &Message) -> Vec<Message> {
+        if let Message::Ping { timestamp } = request {
+            return vec![Message::Pong { timestamp: chrono::Utc::now(), original_timestamp: *timestamp }];
+        }
+        let Some(request_id) = request.request_id() else {
+            return Vec::new();
+        };
+
+        let mut state = self.lock();
+        let key = request_key(request);
+        let unplayed = || state.exchanges.iter().position(|exchange| !exchange.played && exchange.key == key)
+            .or_else(|| state.exchanges.iter().position(|exchange| {
+                !exchange.played && exchange.request.message_type() == request.message_type()
+            }));
+        match unplayed() {
+            Some(index) => {
+                let exchange = &mut state.exchanges[index];
+                exchange.played = true;
+                exchange.replies.iter().map(|reply| with_request_id(reply, request_id)).collect()
+            }
+            None => {
+                warn!("No recorded {} left to replay", request.message_type());
+                state.unmatched.push(request.message_type().to_string());
+                vec![Message::Error {
+                    request_id: Some(request_id),
+                    code: ErrorCode::InternalError,
+                    message: format!("No recorded {} left to replay", request.message_type()),
+                    details: None,
+                    errno: None,
+                }]
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ReplayState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// `message` as JSON, without the request ID
+fn request_key(message: &Message) -> serde_json::Value {
+    let mut value = serde_json::to_value(message).unwrap_or_default();
+    if let Some(fields) = variant_fields(&mut value) {
+        fields.remove("request_id");
+    }
+    value
+}
+
+/// `message` answering `request_id` instead of the recorded request
+fn with_request_id(message: &Message, request_id: RequestId) -> Message {
+    let Ok(mut value) = serde_json::to_value(message) else {
+        return message.clone();
+    };
+    match variant_fields(&mut value).and_then(|fields| fields.get_mut("request_id")) {
+        Some(field) if !field.is_null() => *field = serde_json::Value::String(request_id.to_string()),
+        _ => return message.clone(),
+    }
+    serde_json::from_value(value).unwrap_or_else(|_| message.clone())
+}
+
+/// The fields of the variant a message serializes to
+fn variant_fields(value: &mut serde_json::Value) -> Option<&mut serde_json::Map<String, serde_json::Value>> {
+    value.as_object_mut()?.values_mut().next()?.as_object_mut()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AgentConfig, Client, ClientConfig};
+    use remotefs_common::protocol::generate_request_id;
+
+    fn recorded(direction: Direction, message: Message) -> RecordedMessage {
+        RecordedMessage { elapsed_ms: 0, agent_id: "agent".to_string(), direction, message }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let recorded_id = generate_request_id();
+        let agent = Arc::new(ReplayAgent::new(vec![
+            recorded(Direction::Sent, Message::ListDirectory { request_id: recorded_id, path: "/data".to_string() }),
+            recorded(Direction::Received, Message::ListDirectoryResponse {
+                request_id: recorded_id,
+                success: true,
+                entries: Some(Vec::new()),
+                error: None,
+            }),
+        ]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(Arc::clone(&agent).serve(listener));
+
+        let record_file = std::env::temp_dir().join(format!("remotefs-recording-{}.jsonl", generate_request_id()));
+        let mut config = ClientConfig {
+            agents: vec![AgentConfig { id: "agent".to_string(), url, auth: None, weight: 1, enabled: true }],
+            ..Default::default()
+        };
+        config.client.max_retries = 0;
+        config.logging.record_file = Some(record_file.clone());
+        let client = Client::new(config).unwrap();
+        client.initialize().await.unwrap();
+
+        assert!(client.list_directory("/data").await.unwrap().is_empty());
+        assert_eq!(agent.remaining(), 0);
+        // Nothing left to answer this with
+        assert!(client.list_directory("/data").await.is_err());
+        assert_eq!(agent.unmatched(), ["ListDirectory"]);
+
+        // The replayed session was itself recorded
+        let recording = load_recording(&record_file).unwrap();
+        std::fs::remove_file(&record_file).unwrap();
+        let directions: Vec<Direction> = recording.iter().map(|recorded| recorded.direction).collect();
+        assert_eq!(directions, [Direction::Sent, Direction::Received, Direction::Sent, Direction::Received]);
+        assert!(matches!(recording[1].message, Message::ListDirectoryResponse { success: true, .. }));
+        assert_eq!(recording[0].message.request_id(), recording[1].message.request_id());
+    }
+}
@@ -0,0 +1,112 @@
+//! Rewriting of request paths into the agent's layout
+//!
+//! The `path_rewrites` of the client configuration map prefixes of the
+//! paths requests are made with to prefixes on the agent, so that e.g. a
+//! mount shows the agent's `/export/home` as `/home`. Every path a request
+//! names is rewritten just before it is sent, including those of batched
+//! requests and absolute symlink targets.
+
+use crate::config::PathRewrite;
+use remotefs_common::protocol::Message;
+
+/// Compiled `path_rewrites`
+#[derive(Debug, Clone, Default)]
+pub struct PathRewriter {
+    /// `(from, to)` without trailing slashes, longest `from` first
+    rules: Vec<(String, String)>,
+}
+
+impl PathRewriter {
+    pub fn new(rewrites: &[PathRewrite]) -> Self {
+        let mut rules: Vec<_> = rewrites
+            .iter()
+            .map(|rewrite| (trim_slashes(&rewrite.from).to_string(), trim_slashes(&rewrite.to).to_string()))
+            .collect();
+        rules.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        Self { rules }
+    }
+
+    /// Whether any rule is configured
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// `path` on the agent; `None` if no rule applies
+    pub fn rewrite(&self, path: &str) -> Option<String> {
+        self.rules.iter().find_map(|(from, to)| {
+            let rest = path.strip_prefix(from.as_str())?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            let rest = if rest == "/" { "" } else { rest };
+            Some(match (to.is_empty(), rest.is_empty()) {
+                (true, true) => "/".to_string(),
+                _ => format!("{}{}", to, rest),
+            })
+        })
+    }
+
+    /// Rewrite every path `request` names
+    pub fn apply(&self, request: &mut Message) {
+        if self.is_empty() {
+            return;
+        }
+        for path in request.request_paths_mut() {
+            if let Some(rewritten) = self.rewrite(path) {
+                *path = rewritten;
+            }
+        }
+    }
+}
+
+/// `path` without trailing slashes; `/` becomes empty, so that it prefixes
+/// every absolute path
+fn trim_slashes(path: &str) -> &str {
+    path.trim_end_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::protocol::{generate_request_id, TransactionOp};
+
+    fn rewriter(rules: &[(&str, &str)]) -> PathRewriter {
+        PathRewriter::new(&rules
+            .iter()
+            .map(|(from, to)| PathRewrite { from: from.to_string(), to: to.to_string() })
+            .collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let rewriter = rewriter(&[("/home", "/export/home"), ("/home/shared/", "/srv/shared"), ("/", "/data")]);
+        assert_eq!(rewriter.rewrite("/home/alice/notes.txt").as_deref(), Some("/export/home/alice/notes.txt"));
+        assert_eq!(rewriter.rewrite("/home").as_deref(), Some("/export/home"));
+        // The longest prefix applies, on whole components only
+        assert_eq!(rewriter.rewrite("/home/shared/a").as_deref(), Some("/srv/shared/a"));
+        assert_eq!(rewriter.rewrite("/homework").as_deref(), Some("/data/homework"));
+        assert_eq!(rewriter.rewrite("/").as_deref(), Some("/data"));
+        assert_eq!(rewriter.rewrite("relative"), None);
+
+        // Onto the root
+        let rewriter = self::rewriter(&[("/mnt/remote", "/")]);
+        assert_eq!(rewriter.rewrite("/mnt/remote").as_deref(), Some("/"));
+        assert_eq!(rewriter.rewrite("/mnt/remote/a").as_deref(), Some("/a"));
+        assert_eq!(rewriter.rewrite("/mnt/other"), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let rewriter = rewriter(&[("/home", "/export/home")]);
+        let mut request = Message::Transaction {
+            request_id: generate_request_id(),
+            operations: vec![
+                TransactionOp::Rename { from_path: "/home/a".to_string(), to_path: "/tmp/b".to_string() },
+                TransactionOp::DeleteFile { path: "/home".to_string() },
+            ],
+        };
+        rewriter.apply(&mut request);
+        let paths: Vec<&str> = request.request_paths_mut().into_iter().map(|path| path.as_str()).collect();
+        assert_eq!(paths, vec!["/export/home/a", "/tmp/b", "/export/home"]);
+    }
+}
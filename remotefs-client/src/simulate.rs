@@ -0,0 +1,140 @@
+//! Simulated network conditions for development
+//!
+//! On a fast LAN the latency, narrow links and lost messages of the
+//! networks the client is used over never show. With
+//! `connection.simulate` set, each message to and from an agent is held
+//! back until it would have arrived over such a link, or dropped, so
+//! timeouts, retries and read-ahead can be tuned without an external
+//! network emulator.
+
+use crate::config::NetworkSimulation;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// One direction of a simulated connection
+pub(crate) struct SimulatedLink {
+    config: NetworkSimulation,
+    /// When the link has finished sending the messages before
+    busy_until: Option<Instant>,
+    /// When the last message arrives; later messages never overtake it
+    last_arrival: Option<Instant>,
+    rng: StdRng,
+}
+
+impl SimulatedLink {
+    pub(crate) fn new(config: NetworkSimulation) -> Self {
+        Self::with_rng(config, StdRng::from_entropy())
+    }
+
+    fn with_rng(config: NetworkSimulation, rng: StdRng) -> Self {
+        Self { config, busy_until: None, last_arrival: None, rng }
+    }
+
+    /// When a message of `len` bytes handed to the link at `now` arrives,
+    /// or `None` if it is lost
+    pub(crate) fn schedule(&mut self, len: u64, now: Instant) -> Option<Instant> {
+        if self.config.drop_rate > 0.0 && self.rng.gen::<f64>() < self.config.drop_rate {
+            return None;
+        }
+
+        let sent = match self.config.bandwidth_bytes_per_sec {
+            0 => now,
+            bandwidth => {
+                let start = self.busy_until.map_or(now, |busy_until| busy_until.max(now));
+                start + Duration::from_secs_f64(len as f64 / bandwidth as f64)
+            }
+        };
+        self.busy_until = Some(sent);
+
+        let jitter = match self.config.jitter_ms {
+            0 => 0,
+            jitter_ms => self.rng.gen_range(0..=jitter_ms),
+        };
+        let arrival = sent + Duration::from_millis(self.config.latency_ms + jitter);
+        let arrival = self.last_arrival.map_or(arrival, |last_arrival| last_arrival.max(arrival));
+        self.last_arrival = Some(arrival);
+        Some(arrival)
+    }
+}
+
+/// Messages from `input`, each passed on once it would have arrived over
+/// `link`; `size` gives the bytes a message takes on the wire
+pub(crate) fn delay<T: Send + 'static>(
+    mut link: SimulatedLink,
+    mut input: mpsc::UnboundedReceiver<T>,
+    size: fn(&T) -> u64,
+) -> mpsc::UnboundedReceiver<T> {
+    let (in_flight_tx, mut in_flight) = mpsc::unbounded_channel::<(Instant, T)>();
+    let (output, delivered) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Some(message) = input.recv().await {
+            if let Some(arrival) = link.schedule(size(&message), Instant::now()) {
+                if in_flight_tx.send((arrival, message)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some((arrival, message)) = in_flight.recv().await {
+            tokio::time::sleep_until(arrival).await;
+            if output.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(config: NetworkSimulation) -> SimulatedLink {
+        SimulatedLink::with_rng(config, StdRng::seed_from_u64(7))
+    }
+
+    #[tokio::test]
+    async fn test_schedule() {
+        let now = Instant::now();
+
+        let mut slow = link(NetworkSimulation { latency_ms: 100, bandwidth_bytes_per_sec: 1000, ..Default::default() });
+        assert_eq!(slow.schedule(500, now), Some(now + Duration::from_millis(600)));
+        // The second message waits for the link to finish sending the first
+        assert_eq!(slow.schedule(500, now), Some(now + Duration::from_millis(1100)));
+
+        // Jitter never reorders messages
+        let mut jittery = link(NetworkSimulation { latency_ms: 10, jitter_ms: 50, ..Default::default() });
+        let arrivals: Vec<_> = (0..100).map(|_| jittery.schedule(0, now).unwrap()).collect();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(arrivals.iter().all(|arrival| *arrival <= now + Duration::from_millis(60)));
+
+        let mut lossy = link(NetworkSimulation { drop_rate: 0.5, ..Default::default() });
+        let lost = (0..1000).filter(|_| lossy.schedule(0, now).is_none()).count();
+        assert!((400..600).contains(&lost), "{} lost", lost);
+
+        let mut clear = link(NetworkSimulation::default());
+        assert_eq!(clear.schedule(1 << 30, now), Some(now));
+    }
+
+    #[tokio::test]
+    async fn test_delay() {
+        let (input, received) = mpsc::unbounded_channel();
+        let mut delivered = delay(link(NetworkSimulation { latency_ms: 200, ..Default::default() }), received, |_: &u32| 0);
+
+        let start = Instant::now();
+        for n in 0..3 {
+            input.send(n).unwrap();
+        }
+        for n in 0..3 {
+            assert_eq!(delivered.recv().await, Some(n));
+        }
+        // Messages travel together rather than one round of latency each
+        assert!(start.elapsed() < Duration::from_millis(600));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,160 @@
+//! Listening sockets passed in by systemd socket activation
+//!
+//! With a `.socket` unit, systemd binds the relay's port or the agent's
+//! local socket itself and starts the daemon with the listening socket
+//! already open, as descriptor 3 onwards, counted by `LISTEN_FDS` and
+//! `LISTEN_PID`. The socket outlives the daemon, so connections made while
+//! it restarts wait in the socket's backlog instead of being refused, and
+//! the daemon needs no privileges to listen on a low port. Each daemon takes
+//! a passed socket of the kind it listens on, and binds its configured
+//! address only if there is none.
+
+use std::io;
+use std::ops::Range;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::net::TcpListener;
+use std::sync::{Mutex, PoisonError};
+
+/// First descriptor systemd passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Kind of listening socket a daemon serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    /// TCP over IPv4 or IPv6
+    Tcp,
+    Unix,
+}
+
+/// Passed sockets not taken yet; read from the environment on first use,
+/// so that each descriptor is owned once
+static PASSED: Mutex<Option<Vec<OwnedFd>>> = Mutex::new(None);
+
+/// Take the first passed listening TCP socket, ready for tokio
+pub fn take_tcp_listener() -> io::Result<Option<TcpListener>> {
+    let Some(fd) = take(SocketKind::Tcp) else {
+        return Ok(None);
+    };
+    let listener = TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+/// Take the first passed listening Unix socket, ready for tokio
+pub fn take_unix_listener() -> io::Result<Option<UnixListener>> {
+    let Some(fd) = take(SocketKind::Unix) else {
+        return Ok(None);
+    };
+    let listener = UnixListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(Some(listener))
+}
+
+fn take(kind: SocketKind) -> Option<OwnedFd> {
+    let mut passed = PASSED.lock().unwrap_or_else(PoisonError::into_inner);
+    let passed = passed.get_or_insert_with(|| {
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        listen_fds(std::process::id(), pid.as_deref(), count.as_deref())
+            .filter_map(|fd| {
+                // SAFETY: systemd opened these descriptors for this process,
+                // and they are read from the environment only once
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                // Not for the commands the agent runs
+                set_cloexec(&fd).ok()?;
+                Some(fd)
+            })
+            .collect()
+    });
+    let index = passed.iter().position(|fd| listening_kind(fd) == Some(kind))?;
+    Some(passed.remove(index))
+}
+
+/// Descriptors passed to process `pid`, given `LISTEN_PID` and `LISTEN_FDS`
+///
+/// Variables meant for another process, e.g. inherited from a parent that
+/// was socket activated, pass nothing.
+fn listen_fds(pid: u32, listen_pid: Option<&str>, listen_fds: Option<&str>) -> Range<RawFd> {
+    let for_us = listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) == Some(pid);
+    let count = listen_fds
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .filter(|_| for_us)
+        .unwrap_or(0)
+        .max(0);
+    LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)
+}
+
+/// Kind of `fd` if it is a listening stream socket
+fn listening_kind(fd: &OwnedFd) -> Option<SocketKind> {
+    let fd = fd.as_raw_fd();
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM || socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        return None;
+    }
+
+    // SAFETY: all zeroes is a valid sockaddr_storage, which has room for
+    // any address, as `length` says
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut length) };
+    if result != 0 {
+        return None;
+    }
+    match address.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => Some(SocketKind::Tcp),
+        libc::AF_UNIX => Some(SocketKind::Unix),
+        _ => None,
+    }
+}
+
+fn socket_option(fd: RawFd, option: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` is an int, and `length` says so
+    let result = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut _ as *mut libc::c_void, &mut length)
+    };
+    (result == 0).then_some(value)
+}
+
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: fcntl on a descriptor this process owns
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(42, Some("42"), Some("2")), 3..5);
+        // Meant for another process, or malformed
+        assert!(listen_fds(42, Some("41"), Some("2")).is_empty());
+        assert!(listen_fds(42, None, Some("2")).is_empty());
+        assert!(listen_fds(42, Some("42"), Some("-1")).is_empty());
+        assert!(listen_fds(42, Some("42"), Some("many")).is_empty());
+    }
+
+    #[test]
+    fn test_listening_kind() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(listening_kind(&OwnedFd::from(tcp)), Some(SocketKind::Tcp));
+
+        let path = std::env::temp_dir().join(format!("remotefs-activation-{}", crate::protocol::generate_request_id()));
+        let unix = UnixListener::bind(&path).unwrap();
+        assert_eq!(listening_kind(&OwnedFd::from(unix)), Some(SocketKind::Unix));
+        std::fs::remove_file(&path).unwrap();
+
+        // Connected sockets and other files are not listeners
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        assert_eq!(listening_kind(&OwnedFd::from(client)), None);
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert_eq!(listening_kind(&OwnedFd::from(file)), None);
+    }
+}
@@ -0,0 +1,96 @@
+//! File checksums for integrity checks
+//!
+//! Agents answer `ComputeChecksum` with a digest computed here, and clients
+//! compute the same digest of data they hold to compare against it, e.g. to
+//! verify a copy without reading it back.
+
+use crate::blake3;
+use crate::protocol::ChecksumAlgorithm;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Incremental hasher for any [`ChecksumAlgorithm`]
+pub struct Hasher(Inner);
+
+enum Inner {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self(match algorithm {
+            ChecksumAlgorithm::Sha256 => Inner::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Inner::Blake3(Box::new(blake3::Hasher::new())),
+        })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            Inner::Sha256(hasher) => hasher.update(data),
+            Inner::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self.0 {
+            Inner::Sha256(hasher) => hasher.finalize().to_vec(),
+            Inner::Blake3(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// Digest of some bytes of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Vec<u8>,
+    /// Number of bytes hashed
+    pub length: u64,
+}
+
+impl Checksum {
+    /// Checksum of `data`, for comparing with one computed by an agent
+    pub fn of(algorithm: ChecksumAlgorithm, data: &[u8]) -> Self {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        Self {
+            algorithm,
+            digest: hasher.finalize(),
+            length: data.len() as u64,
+        }
+    }
+
+    /// The digest as lowercase hex, as printed by `sha256sum` and `b3sum`
+    pub fn to_hex(&self) -> String {
+        self.digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_matches_one_shot() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3] {
+            let mut hasher = Hasher::new(algorithm);
+            for piece in data.chunks(333) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize(), Checksum::of(algorithm, &data).digest);
+        }
+
+        assert_eq!(
+            Checksum::of(ChecksumAlgorithm::Sha256, b"abc").to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Message compression, its statistics and auto-disable heuristic
+//!
+//! Nodes that announced the `Compression` capability are sent large messages
+//! wrapped in `Message::Compressed`, in binary frames; `encode_message` and
+//! `decode_message` do the wrapping and unwrapping.
+//!
+//! Compressing data that is already compressed (media, archives) costs CPU
+//! without shrinking it. `CompressionStats` records what compression achieved
+//! on a connection, and `AutoDisable` stops compressing once a window of
+//! payloads saved too little, trying again after a number of messages in case
+//! the data changed.
+
+use crate::error::{RemoteFsError, Result};
+use crate::protocol::{CompressionAlgorithm, Message};
+use std::time::{Duration, Instant};
+
+/// Payloads per window the heuristic judges at once
+const DEFAULT_WINDOW: u32 = 16;
+
+/// Fraction of bytes a window must save for compression to stay enabled
+const DEFAULT_MIN_SAVINGS: f64 = 0.1;
+
+/// Payloads sent uncompressed before compression is tried again
+const DEFAULT_RETRY_AFTER: u64 = 256;
+
+/// Encoded messages smaller than this are sent as they are
+pub const MIN_COMPRESSED_SIZE: usize = 512;
+
+/// Largest message a `Compressed` message may expand to
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+/// Compression results for one connection or session
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    /// Payloads sent compressed
+    pub payloads_compressed: u64,
+    /// Payloads sent uncompressed because compression was auto-disabled
+    pub payloads_skipped: u64,
+    /// Size of the compressed payloads before compression
+    pub bytes_in: u64,
+    /// Size of the compressed payloads after compression
+    pub bytes_out: u64,
+    /// Time spent compressing
+    pub cpu_time: Duration,
+    /// How often compression was auto-disabled
+    pub times_disabled: u64,
+}
+
+impl CompressionStats {
+    /// Record one compressed payload
+    pub fn record(&mut self, bytes_in: usize, bytes_out: usize, cpu_time: Duration) {
+        self.payloads_compressed += 1;
+        self.bytes_in += bytes_in as u64;
+        self.bytes_out += bytes_out as u64;
+        self.cpu_time += cpu_time;
+    }
+
+    /// Record a payload sent uncompressed while compression was disabled
+    pub fn record_skipped(&mut self) {
+        self.payloads_skipped += 1;
+    }
+
+    /// Compressed size as a fraction of the original, if anything was compressed
+    pub fn ratio(&self) -> Option<f64> {
+        (self.bytes_in > 0).then(|| self.bytes_out as f64 / self.bytes_in as f64)
+    }
+
+    /// Add another connection's results to these
+    pub fn merge(&mut self, other: &CompressionStats) {
+        self.payloads_compressed += other.payloads_compressed;
+        self.payloads_skipped += other.payloads_skipped;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.cpu_time += other.cpu_time;
+        self.times_disabled += other.times_disabled;
+    }
+}
+
+/// Decides whether the next payload is worth compressing
+#[derive(Debug, Clone)]
+pub struct AutoDisable {
+    window: u32,
+    min_savings: f64,
+    retry_after: u64,
+    samples: u32,
+    window_in: u64,
+    window_out: u64,
+    skip_remaining: u64,
+}
+
+impl Default for AutoDisable {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_MIN_SAVINGS, DEFAULT_RETRY_AFTER)
+    }
+}
+
+impl AutoDisable {
+    pub fn new(window: u32, min_savings: f64, retry_after: u64) -> Self {
+        Self {
+            window: window.max(1),
+            min_savings,
+            retry_after,
+            samples: 0,
+            window_in: 0,
+            window_out: 0,
+            skip_remaining: 0,
+        }
+    }
+
+    /// Whether to compress the next payload; counts down while disabled
+    pub fn should_compress(&mut self) -> bool {
+        if self.skip_remaining > 0 {
+            self.skip_remaining -= 1;
+            return false;
+        }
+        true
+    }
+
+    /// Feed the result of compressing a payload
+    ///
+    /// Returns `true` if this result disabled compression.
+    pub fn observe(&mut self, bytes_in: usize, bytes_out: usize) -> bool {
+        self.samples += 1;
+        self.window_in += bytes_in as u64;
+        self.window_out += bytes_out as u64;
+        if self.samples < self.window {
+            return false;
+        }
+
+        let savings = 1.0 - self.window_out as f64 / self.window_in.max(1) as f64;
+        self.samples = 0;
+        self.window_in = 0;
+        self.window_out = 0;
+
+        if savings < self.min_savings {
+            self.skip_remaining = self.retry_after;
+            return true;
+        }
+        false
+    }
+}
+
+/// Encode `message` for a binary frame, compressed if that is worthwhile
+///
+/// Small messages, messages that do not shrink, and every message while
+/// `heuristic` has compression disabled are encoded as they are.
+pub fn encode_message(message: &Message, heuristic: &mut AutoDisable, stats: &mut CompressionStats) -> Result<Vec<u8>> {
+    let encoded = bincode::serialize(message)?;
+    if encoded.len() < MIN_COMPRESSED_SIZE {
+        return Ok(encoded);
+    }
+    if !heuristic.should_compress() {
+        stats.record_skipped();
+        return Ok(encoded);
+    }
+
+    let started = Instant::now();
+    let mut data = Vec::with_capacity(encoded.len() / 2 + 4);
+    data.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    data.extend_from_slice(&lz4_flex::compress(&encoded));
+    stats.record(encoded.len(), data.len(), started.elapsed());
+    if heuristic.observe(encoded.len(), data.len()) {
+        stats.times_disabled += 1;
+    }
+
+    if data.len() >= encoded.len() {
+        return Ok(encoded);
+    }
+    Ok(bincode::serialize(&Message::Compressed { algorithm: CompressionAlgorithm::Lz4, data })?)
+}
+
+/// Decode a binary frame, expanding a `Compressed` message
+///
+/// Also returns whether the message came compressed, which shows the sender
+/// can read compressed messages as well.
+pub fn decode_message(frame: &[u8]) -> Result<(Message, bool)> {
+    let (algorithm, data) = match bincode::deserialize(frame)? {
+        Message::Compressed { algorithm, data } => (algorithm, data),
+        message => return Ok((message, false)),
+    };
+
+    let decompressed = match algorithm {
+        CompressionAlgorithm::Lz4 => {
+            let (size, compressed) = data.split_first_chunk::<4>()
+                .ok_or_else(|| RemoteFsError::Protocol("Compressed message is truncated".to_string()))?;
+            let size = u32::from_le_bytes(*size) as usize;
+            if size > MAX_DECOMPRESSED_SIZE {
+                return Err(RemoteFsError::Protocol(format!(
+                    "Compressed message expands to {} bytes; at most {} are allowed",
+                    size, MAX_DECOMPRESSED_SIZE
+                )));
+            }
+            lz4_flex::decompress(compressed, size)
+                .map_err(|e| RemoteFsError::Protocol(format!("Failed to decompress message: {}", e)))?
+        }
+    };
+
+    match bincode::deserialize(&decompressed)? {
+        Message::Compressed { .. } => Err(RemoteFsError::Protocol("Compressed message is compressed again".to_string())),
+        message => Ok((message, true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = CompressionStats::default();
+        assert_eq!(stats.ratio(), None);
+
+        stats.record(1000, 250, Duration::from_micros(40));
+        stats.record(1000, 750, Duration::from_micros(60));
+        stats.record_skipped();
+        assert_eq!(stats.ratio(), Some(0.5));
+        assert_eq!(stats.cpu_time, Duration::from_micros(100));
+
+        let mut total = CompressionStats::default();
+        total.merge(&stats);
+        total.merge(&stats);
+        assert_eq!(total.payloads_compressed, 4);
+        assert_eq!(total.payloads_skipped, 2);
+        assert_eq!(total.ratio(), Some(0.5));
+    }
+
+    #[test]
+    fn test_auto_disable() {
+        let mut heuristic = AutoDisable::new(2, 0.1, 3);
+
+        // Text shrinks well: compression stays on
+        assert!(!heuristic.observe(1000, 300));
+        assert!(!heuristic.observe(1000, 300));
+        assert!(heuristic.should_compress());
+
+        // Already-compressed media barely shrinks: disabled for three payloads
+        assert!(!heuristic.observe(1000, 990));
+        assert!(heuristic.observe(1000, 1000));
+        assert!(!heuristic.should_compress());
+        assert!(!heuristic.should_compress());
+        assert!(!heuristic.should_compress());
+        assert!(heuristic.should_compress());
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut heuristic = AutoDisable::default();
+        let mut stats = CompressionStats::default();
+        let text = Message::WriteFile {
+            request_id: crate::protocol::generate_request_id(),
+            path: "/project/src/main.rs".to_string(),
+            data: "fn main() {}\n".repeat(500).into_bytes(),
+            offset: 0,
+            sync: false,
+        };
+
+        let frame = encode_message(&text, &mut heuristic, &mut stats).unwrap();
+        assert!(frame.len() < 1000);
+        assert_eq!(stats.payloads_compressed, 1);
+        let (decoded, compressed) = decode_message(&frame).unwrap();
+        assert!(compressed);
+        assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&text).unwrap());
+
+        // Small messages are left alone
+        let ping = Message::Ping { timestamp: chrono::Utc::now() };
+        let frame = encode_message(&ping, &mut heuristic, &mut stats).unwrap();
+        assert_eq!(frame, bincode::serialize(&ping).unwrap());
+        assert!(matches!(decode_message(&frame).unwrap(), (Message::Ping { .. }, false)));
+
+        // A claimed size past the limit is refused before allocating it
+        let bomb = Message::Compressed {
+            algorithm: CompressionAlgorithm::Lz4,
+            data: u32::MAX.to_le_bytes().to_vec(),
+        };
+        assert!(decode_message(&bincode::serialize(&bomb).unwrap()).is_err());
+    }
+}
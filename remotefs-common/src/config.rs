@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::protocol::RelayEndpoint;
+
 /// Client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
@@ -35,20 +38,81 @@ pub struct AgentConfig {
     /// Relay server URL
     pub relay_url: String,
     
+    /// Further relays, tried in turn when the one in use cannot be reached,
+    /// or all connected at once with `relay_mode = "all"`
+    #[serde(default)]
+    pub relay_urls: Vec<String>,
+    
+    /// How the agent uses `relay_url` and `relay_urls`
+    #[serde(default)]
+    pub relay_mode: RelayMode,
+    
     /// Access control configuration
     pub access: AccessConfig,
     
     /// Security configuration
+    #[serde(default)]
     pub security: SecurityConfig,
     
     /// Network configuration
+    #[serde(default)]
     pub network: NetworkConfig,
     
     /// Logging configuration
+    #[serde(default)]
     pub logging: LoggingConfig,
     
     /// Performance tuning
+    #[serde(default)]
     pub performance: PerformanceConfig,
+    
+    /// Change journal for incremental sync
+    #[serde(default)]
+    pub journal: JournalConfig,
+    
+    /// Hooks for files offloaded to cold storage
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    
+    /// Trash that deleted files and directories are moved into
+    #[serde(default)]
+    pub trash: TrashConfig,
+    
+    /// Replication from a primary agent when this agent is its mirror
+    #[serde(default)]
+    pub mirror: MirrorConfig,
+    
+    /// Guardrails on memory and file descriptors used by requests
+    #[serde(default)]
+    pub limits: ResourceLimitsConfig,
+    
+    /// Request and bandwidth rates, per client and in total
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+    
+    /// Whitelisted commands clients may run next to the data
+    #[serde(default)]
+    pub remote_exec: RemoteExecConfig,
+    
+    /// Translation between the uids and gids of clients and of this host
+    #[serde(default)]
+    pub id_map: IdMapConfig,
+    
+    /// Unix socket on which clients on the same host open files for reading,
+    /// receiving a file descriptor instead of the data
+    #[serde(default)]
+    pub local_socket: Option<PathBuf>,
+}
+
+/// How an agent with several relays connects to them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayMode {
+    /// One relay at a time, moving on to the next when it is lost
+    #[default]
+    Failover,
+    /// Every relay at once, so clients of any of them reach the agent
+    All,
 }
 
 /// Relay server configuration
@@ -80,9 +144,86 @@ pub struct RelayConfig {
     
     /// Logging configuration
     pub logging: LoggingConfig,
+    
+    /// Primary/mirror agent pairs for failover
+    #[serde(default)]
+    pub mirrors: Vec<MirrorPair>,
+    
+    /// Identity of this relay and the other relays clients may choose from
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    
+    /// Caps on data queued for slow consumers
+    #[serde(default)]
+    pub buffers: BufferLimits,
+    
+    /// Timeouts and caps for connections that have not authenticated
+    #[serde(default)]
+    pub connections: ConnectionLimits,
+    
+    /// Hostnames limited to some of the relay's endpoints
+    #[serde(default)]
+    pub virtual_hosts: Vec<VirtualHost>,
+    
+    /// Agent paths readable by clients that never authenticate
+    #[serde(default)]
+    pub public_exports: Vec<PublicExport>,
+    
+    /// Bearer token required by the admin endpoints that change the relay's
+    /// state; they are refused while it is unset
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    
+    /// Confinement of the relay process once it is listening
+    #[serde(default)]
+    pub hardening: HardeningConfig,
 }
 
-/// Mount point configuration
+/// Relay discovery for multi-region deployments
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    /// Identifier of this relay
+    #[serde(default = "default_relay_id")]
+    pub relay_id: String,
+    
+    /// Region this relay runs in
+    #[serde(default)]
+    pub region: Option<String>,
+    
+    /// WebSocket URL clients reach this relay at; without it the relay
+    /// leaves itself out of the directory
+    #[serde(default)]
+    pub public_url: Option<String>,
+    
+    /// Relays in other regions
+    #[serde(default)]
+    pub peers: Vec<RelayEndpoint>,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            relay_id: default_relay_id(),
+            region: None,
+            public_url: None,
+            peers: Vec::new(),
+        }
+    }
+}
+
+/// A primary agent and the read-only mirror that replicates it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorPair {
+    /// Agent ID of the primary
+    pub primary: String,
+    
+    /// Agent ID of the mirror, which serves reads while the primary is gone
+    pub mirror: String,
+    
+    /// Also route writes to the promoted mirror
+    #[serde(default)]
+    pub promote_writes: bool,
+}
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MountPoint {
     /// Remote path on the agent
@@ -166,6 +307,11 @@ pub struct AccessConfig {
     #[serde(default = "default_true")]
     pub follow_symlinks: bool,
     
+    /// Refuse paths that symlinks lead out of the allowed or read-only path
+    /// they are under, even into another one
+    #[serde(default)]
+    pub deny_symlink_escapes: bool,
+    
     /// Allowed file extensions (empty = allow all)
     #[serde(default)]
     pub allowed_extensions: Vec<String>,
@@ -173,6 +319,135 @@ pub struct AccessConfig {
     /// Denied file extensions
     #[serde(default)]
     pub denied_extensions: Vec<String>,
+    
+    /// Per-user rules for requests forwarded from shared (`allow_other`)
+    /// mounts. Advisory: they only apply to requests the client wraps in
+    /// `AsUser`, with a uid and gid it asserts itself
+    #[serde(default)]
+    pub user_rules: Vec<UserAccessRule>,
+    
+    /// Policy for forwarded users that no rule matches; requests not wrapped
+    /// in `AsUser` are not subject to it
+    #[serde(default)]
+    pub unmatched_users: UnmatchedUserPolicy,
+    
+    /// Pattern rules applied on top of the path lists, to every client or
+    /// to the clients they name
+    #[serde(default)]
+    pub rules: Vec<AccessRule>,
+    
+    /// Effect for requests that no pattern rule matches
+    #[serde(default)]
+    pub unmatched_rules: RuleEffect,
+    
+    /// Permission bits clients may set on files and directories they create
+    /// or chmod; other bits of a requested mode are dropped, e.g. `0o777`
+    /// keeps clients from creating setuid, setgid or sticky entries
+    #[serde(default = "default_allowed_mode")]
+    pub allowed_mode: u32,
+    
+    /// Space the files under a directory may take up, enforced by the agent
+    #[serde(default)]
+    pub quotas: Vec<PathQuota>,
+}
+
+/// Limit on the file data under a directory
+///
+/// Counts the size of every file below `path`, whoever wrote it. Writes
+/// through the agent that would go over the limit fail with
+/// `QuotaExceeded`; quotas on nested directories all apply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathQuota {
+    /// Directory the quota covers
+    pub path: String,
+    
+    /// Bytes of file data allowed under it
+    pub max_bytes: u64,
+}
+
+/// Access rule for local users of a shared mount, applied on top of the
+/// agent-wide rules
+///
+/// A rule matches a caller whose uid is in `uids` or who belongs to one of
+/// `gids`; the first matching rule applies.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserAccessRule {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    
+    /// Paths the user may access (empty = everything the agent allows)
+    #[serde(default)]
+    pub allowed_paths: Vec<String>,
+    
+    /// Paths the user may only read
+    #[serde(default)]
+    pub read_only_paths: Vec<String>,
+    
+    /// Paths the user may not access
+    #[serde(default)]
+    pub denied_paths: Vec<String>,
+    
+    /// Deny every write for the user
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// How to treat forwarded users that no `UserAccessRule` matches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmatchedUserPolicy {
+    /// Only the agent-wide rules apply
+    #[default]
+    Allow,
+    /// Reads only
+    ReadOnly,
+    /// Reject every request
+    Deny,
+}
+
+/// Allow or deny rule over path patterns
+///
+/// A rule matches a request from one of `clients` (every client when empty)
+/// that needs one of `verbs` (every verb when empty) on a path matching one
+/// of `paths`. Patterns are globs, where `*` and `?` stay within a path
+/// component and `**` spans components, unless written `regex:<pattern>`;
+/// client IDs are matched the same way. When rules both allow and deny a
+/// request, deny wins.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccessRule {
+    pub effect: RuleEffect,
+    
+    #[serde(default)]
+    pub clients: Vec<String>,
+    
+    pub paths: Vec<String>,
+    
+    #[serde(default)]
+    pub verbs: Vec<AccessVerb>,
+}
+
+/// Whether a matching `AccessRule` lets a request through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleEffect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Kind of access an `AccessRule` covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessVerb {
+    /// Reading files, listing directories and reading metadata
+    Read,
+    /// Writing, creating and changing the metadata of files and directories
+    Write,
+    /// Removing files and directories, and renaming them away
+    Delete,
 }
 
 /// Security configuration
@@ -243,6 +518,14 @@ pub struct NetworkConfig {
     /// TCP keep-alive interval in seconds
     #[serde(default = "default_keepalive_interval")]
     pub keepalive_interval: u64,
+    
+    /// Compress large messages to nodes that read compressed messages
+    #[serde(default = "default_true")]
+    pub compression: bool,
+    
+    /// Seconds to wait on shutdown for requests in progress to finish
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
 }
 
 /// Message size limits
@@ -282,6 +565,11 @@ pub struct SessionConfig {
     
     /// Session storage path (for persistence)
     pub storage_path: Option<PathBuf>,
+    
+    /// How long a resumption ticket lets a node reconnect without
+    /// authenticating again, in seconds; 0 disables resumption
+    #[serde(default = "default_resumption_ticket_secs")]
+    pub resumption_ticket_secs: u64,
 }
 
 /// Storage configuration for relay server
@@ -335,6 +623,376 @@ pub struct PerformanceConfig {
     pub prefetch_window: usize,
 }
 
+/// Agent change journal configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalConfig {
+    /// Record creates, modifications, deletes and renames made through the agent
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Journal file (None = ~/.remotefs/journal.jsonl)
+    pub path: Option<PathBuf>,
+    
+    /// Number of most recent changes to retain
+    #[serde(default = "default_journal_max_entries")]
+    pub max_entries: usize,
+}
+
+/// Agent cold-storage hooks configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Report archived files as offline and recall them on read
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Suffix of the marker file an archiver creates next to a file it offloaded
+    #[serde(default = "default_archive_marker_suffix")]
+    pub marker_suffix: String,
+    
+    /// Command that recalls a file, run with the file's path appended
+    #[serde(default)]
+    pub recall_command: Vec<String>,
+    
+    /// Seconds to wait for the recall command to exit
+    #[serde(default = "default_recall_timeout")]
+    pub recall_timeout_secs: u64,
+}
+
+/// Agent trash configuration
+///
+/// With the trash enabled, deleted files and directories are moved into a
+/// trash directory at the top of the allowed path holding them, from which
+/// clients can restore them until they are purged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashConfig {
+    /// Move deleted entries into the trash instead of unlinking them
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Name of the trash directory in each allowed path
+    #[serde(default = "default_trash_directory")]
+    pub directory: String,
+    
+    /// Seconds an entry stays in the trash before it is purged
+    #[serde(default = "default_trash_retention")]
+    pub retention_secs: u64,
+    
+    /// Seconds between purges of expired entries
+    #[serde(default = "default_trash_purge_interval")]
+    pub purge_interval_secs: u64,
+}
+
+/// Translation between client and host user and group IDs
+///
+/// Ownership a client sets is translated to the host's IDs before the
+/// change is made, and ownership reported to clients is translated back.
+/// IDs without a mapping are passed through as they are. Each ID may appear
+/// once on either side, so that every mapping can be reversed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IdMapConfig {
+    /// User IDs of clients and the host users they stand for
+    #[serde(default)]
+    pub uids: Vec<IdMapping>,
+    
+    /// Group IDs of clients and the host groups they stand for
+    #[serde(default)]
+    pub gids: Vec<IdMapping>,
+}
+
+/// A client ID and the host ID it stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMapping {
+    /// ID on the clients
+    pub remote: u32,
+    /// ID on this host
+    pub local: u32,
+}
+
+/// Agent mirror configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    /// Replicate from `primary_url` and refuse writes unless promoted
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Agent ID of the primary
+    #[serde(default)]
+    pub primary_id: String,
+    
+    /// WebSocket URL of the primary, which must have its change journal enabled
+    #[serde(default)]
+    pub primary_url: String,
+    
+    /// Directory replicated from the primary, at the same path on both hosts
+    #[serde(default)]
+    pub root: PathBuf,
+    
+    /// Seconds between polls of the primary's change journal
+    #[serde(default = "default_mirror_poll_interval")]
+    pub poll_interval_secs: u64,
+}
+
+/// Agent resource guardrails
+///
+/// Requests that would exceed the memory or open file limits, or that find
+/// every slot for their kind of operation taken and the queue full, are
+/// refused with a retriable `ServiceUnavailable` error instead of risking the
+/// agent running out of memory or threads under a burst of requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceLimitsConfig {
+    /// Memory for file data held by in-flight requests, in MB
+    #[serde(default = "default_max_buffer_mb")]
+    pub max_buffer_mb: u64,
+    
+    /// Files open at once for in-flight requests
+    #[serde(default = "default_max_open_files")]
+    pub max_open_files: usize,
+    
+    /// Largest response a single read may produce, in MB; larger reads are
+    /// refused and must be made in ranges
+    #[serde(default = "default_max_response_mb")]
+    pub max_response_mb: u64,
+    
+    /// Entries a whole-directory listing may return; larger directories
+    /// must be listed in pages
+    #[serde(default = "default_max_listing_entries")]
+    pub max_listing_entries: usize,
+    
+    /// Entries per page a paged listing may ask for
+    #[serde(default = "default_max_page_entries")]
+    pub max_page_entries: u32,
+    
+    /// Longest path a request may name, in bytes
+    #[serde(default = "default_max_path_length")]
+    pub max_path_length: usize,
+    
+    /// Most components a path in a request may have
+    #[serde(default = "default_max_path_depth")]
+    pub max_path_depth: usize,
+    
+    /// Requests that only read handled at once
+    #[serde(default = "default_max_concurrent_reads")]
+    pub max_concurrent_reads: usize,
+    
+    /// Requests that change files handled at once
+    #[serde(default = "default_max_concurrent_writes")]
+    pub max_concurrent_writes: usize,
+    
+    /// Requests waiting for a read or write slot; requests beyond these are
+    /// refused until one is free
+    #[serde(default = "default_max_queued_operations")]
+    pub max_queued_operations: usize,
+}
+
+/// Agent request and bandwidth rates
+///
+/// Each rate is enforced with a token bucket holding one second's worth, so
+/// short bursts pass. A client over its rate, or any client while the agent
+/// is over its total rate, has its requests refused with a retriable
+/// `ServiceUnavailable` error naming the limit and when to retry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Rates across all clients together
+    #[serde(default)]
+    pub global: RateLimit,
+    
+    /// Rates each client gets on its own, unless listed in `clients`
+    #[serde(default)]
+    pub per_client: RateLimit,
+    
+    /// Rates of particular clients, by client ID
+    #[serde(default)]
+    pub clients: HashMap<String, RateLimit>,
+}
+
+/// A pair of rates; 0 means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// File data read and written per second, in bytes
+    #[serde(default)]
+    pub bytes_per_sec: u64,
+    
+    /// Requests per second
+    #[serde(default)]
+    pub ops_per_sec: u64,
+}
+
+/// Agent remote command execution
+///
+/// Clients can only run the commands listed here, by name, and only on
+/// agents built with the `remote-exec` feature. Commands run without a
+/// shell, so client-supplied arguments are never interpreted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RemoteExecConfig {
+    /// Accept `ExtendedOperation` requests
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Commands clients may run
+    #[serde(default)]
+    pub commands: Vec<ExecCommandConfig>,
+}
+
+/// A command clients may run on the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecCommandConfig {
+    /// Name clients ask for the command by
+    pub name: String,
+    
+    /// Program and its fixed arguments
+    pub command: Vec<String>,
+    
+    /// Directory to run in; when unset the client names one, which must
+    /// pass the agent's write access checks
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    
+    /// Let clients append arguments after the fixed ones
+    #[serde(default)]
+    pub allow_arguments: bool,
+    
+    /// Seconds before the command is killed
+    #[serde(default = "default_exec_timeout")]
+    pub timeout_secs: u64,
+    
+    /// Output sent back before the rest is dropped, in MB
+    #[serde(default = "default_exec_max_output_mb")]
+    pub max_output_mb: u64,
+}
+
+/// Relay buffer caps
+///
+/// Messages for a session wait in memory until its socket accepts them. A
+/// session whose queue passes the soft limit has its new requests refused
+/// with a retriable `ServiceUnavailable` error until it catches up; one that
+/// passes the hard limit is disconnected as a slow consumer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BufferLimits {
+    /// Queued bytes per session above which its new requests are refused, in MB
+    #[serde(default = "default_session_soft_limit_mb")]
+    pub session_soft_limit_mb: u64,
+    
+    /// Queued bytes per session above which it is disconnected, in MB
+    #[serde(default = "default_session_hard_limit_mb")]
+    pub session_hard_limit_mb: u64,
+    
+    /// Queued bytes across all sessions above which new requests are refused, in MB
+    #[serde(default = "default_total_buffer_limit_mb")]
+    pub total_limit_mb: u64,
+}
+
+/// Relay connection admission
+///
+/// A connection must finish its TLS handshake and send its HTTP request
+/// within the handshake timeout, then authenticate, or send its first
+/// request as a guest, within the auth timeout. Connections that have not
+/// authenticated yet are capped in total, and all connections per IP
+/// address; a limit of 0 means none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionLimits {
+    /// Seconds to finish the TLS handshake and send the HTTP request
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout_secs: u64,
+    
+    /// Seconds from the WebSocket upgrade to authentication
+    #[serde(default = "default_auth_timeout")]
+    pub auth_timeout_secs: u64,
+    
+    /// WebSocket connections that have not authenticated yet
+    #[serde(default = "default_max_unauthenticated")]
+    pub max_unauthenticated: usize,
+    
+    /// Open connections from one IP address
+    #[serde(default = "default_max_connections_per_ip")]
+    pub max_per_ip: usize,
+}
+
+/// Endpoints a relay hostname serves
+///
+/// The relay serves everything on one port. Requests for `server_name`,
+/// taken from TLS SNI or else the `Host` header, may only reach `services`;
+/// requests for other names reach every endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualHost {
+    /// Hostname, matched case-insensitively
+    pub server_name: String,
+    
+    /// Endpoints reachable under this name
+    pub services: Vec<RelayService>,
+}
+
+/// A group of relay endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayService {
+    /// `/ws`, where clients and agents connect
+    #[serde(rename = "websocket")]
+    WebSocket,
+    /// `/discovery`, the relay directory
+    Discovery,
+    /// `/health`, `/stats` and `/maintenance`
+    Admin,
+}
+
+/// Confinement of the relay, which handles untrusted input from the internet
+///
+/// Applied once the relay has read its TLS key and is listening, so that a
+/// relay started as root to bind a low port keeps nothing of root while it
+/// serves. Linux only.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HardeningConfig {
+    /// Account to switch to, with its primary group and no others
+    #[serde(default)]
+    pub user: Option<String>,
+    
+    /// Make `storage.temp_dir` the relay's root directory, so it cannot
+    /// reach any other file; needs the relay to start as root
+    #[serde(default)]
+    pub confine_to_storage: bool,
+    
+    /// Refuse system calls the relay never makes, such as running programs,
+    /// tracing processes, mounting or changing user, with `EPERM`
+    #[serde(default)]
+    pub seccomp: bool,
+}
+
+impl HardeningConfig {
+    /// Whether any confinement is configured
+    pub fn is_enabled(&self) -> bool {
+        self.user.is_some() || self.confine_to_storage || self.seccomp
+    }
+}
+
+/// An agent path served read-only to anonymous clients
+///
+/// Connections that send a request without authenticating become guests.
+/// Guests may read, list and stat files under `path` on `agent_id` and
+/// nothing else; all guests of an export share its rate limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicExport {
+    /// Agent ID serving the export
+    pub agent_id: String,
+    
+    /// Directory on the agent, including everything below it
+    pub path: String,
+    
+    /// Guest requests admitted per minute, across all guests
+    #[serde(default = "default_export_requests_per_minute")]
+    pub requests_per_minute: u32,
+    
+    /// Guest requests admitted at once after a quiet period
+    #[serde(default = "default_export_burst")]
+    pub burst: u32,
+}
+
+fn default_export_requests_per_minute() -> u32 {
+    600
+}
+
+fn default_export_burst() -> u32 {
+    50
+}
+
 /// Logging configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
@@ -363,18 +1021,51 @@ pub struct LoggingConfig {
     
     /// Access log file path
     pub access_log_file: Option<PathBuf>,
+    
+    /// Also send access log records to the relay, which logs them
+    #[serde(default)]
+    pub forward_access_log: bool,
+    
+    /// Reports written when the process panics
+    #[serde(default)]
+    pub crash: CrashConfig,
+}
+
+/// Crash report configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashConfig {
+    /// Write a report of the panic, the daemon's state and its last log
+    /// events whenever a thread panics
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    
+    /// Directory for reports (None = <data dir>/remotefs/crash)
+    pub directory: Option<PathBuf>,
+    
+    /// Number of most recent log events included in a report
+    #[serde(default = "default_crash_recent_events")]
+    pub recent_events: usize,
+    
+    /// Allow core dumps up to the hard limit and abort on the first panic so
+    /// the system writes one; otherwise a panic in a task only ends that task
+    #[serde(default)]
+    pub core_dumps: bool,
 }
 
 // Default value functions
 fn default_true() -> bool { true }
+fn default_relay_id() -> String { "relay-001".to_string() }
 fn default_cache_ttl() -> u64 { 3600 } // 1 hour
 fn default_max_cached_file_size() -> u64 { 100 * 1024 * 1024 } // 100MB
 fn default_max_file_size() -> u64 { 10 * 1024 * 1024 * 1024 } // 10GB
+fn default_allowed_mode() -> u32 { 0o7777 }
 fn default_session_timeout() -> u64 { 3600 } // 1 hour
+fn default_resumption_ticket_secs() -> u64 { 300 } // 5 minutes
 fn default_connection_timeout() -> u64 { 30 } // 30 seconds
 fn default_io_timeout() -> u64 { 60 } // 1 minute
 fn default_heartbeat_interval() -> u64 { 30 } // 30 seconds
 fn default_max_reconnect_attempts() -> u32 { 5 }
+fn default_shutdown_timeout() -> u64 { 30 }
 fn default_reconnect_backoff() -> u64 { 1 } // 1 second
 fn default_max_concurrent_connections() -> usize { 10 }
 fn default_keepalive_interval() -> u64 { 60 } // 1 minute
@@ -390,6 +1081,33 @@ fn default_worker_threads() -> usize { num_cpus::get() }
 fn default_io_buffer_size() -> usize { 64 * 1024 } // 64KB
 fn default_fs_cache_size() -> usize { 256 } // 256MB
 fn default_prefetch_window() -> usize { 8 }
+fn default_journal_max_entries() -> usize { 100_000 }
+fn default_crash_recent_events() -> usize { 200 }
+fn default_archive_marker_suffix() -> String { ".offline".to_string() }
+fn default_recall_timeout() -> u64 { 300 } // 5 minutes
+fn default_trash_directory() -> String { ".remotefs-trash".to_string() }
+fn default_trash_retention() -> u64 { 7 * 86400 } // 7 days
+fn default_trash_purge_interval() -> u64 { 3600 } // 1 hour
+fn default_mirror_poll_interval() -> u64 { 5 }
+fn default_max_buffer_mb() -> u64 { 512 }
+fn default_max_open_files() -> usize { 256 }
+fn default_max_response_mb() -> u64 { 64 } // Same as the relay's message limit
+fn default_max_listing_entries() -> usize { 100_000 }
+fn default_max_page_entries() -> u32 { 10_000 }
+fn default_max_path_length() -> usize { 4096 } // PATH_MAX on Linux
+fn default_max_path_depth() -> usize { 256 }
+fn default_max_concurrent_reads() -> usize { 64 }
+fn default_max_concurrent_writes() -> usize { 16 }
+fn default_max_queued_operations() -> usize { 128 }
+fn default_session_soft_limit_mb() -> u64 { 128 } // Two maximum-size messages
+fn default_session_hard_limit_mb() -> u64 { 512 }
+fn default_total_buffer_limit_mb() -> u64 { 2048 }
+fn default_handshake_timeout() -> u64 { 10 }
+fn default_auth_timeout() -> u64 { 30 }
+fn default_max_unauthenticated() -> usize { 1000 }
+fn default_max_connections_per_ip() -> usize { 100 }
+fn default_exec_timeout() -> u64 { 600 } // 10 minutes
+fn default_exec_max_output_mb() -> u64 { 16 }
 fn default_log_level() -> String { "info".to_string() }
 fn default_log_format() -> String { "plain".to_string() }
 fn default_log_file_size() -> usize { 100 } // 100MB
@@ -407,6 +1125,20 @@ impl Default for MountOptions {
     }
 }
 
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        Self {
+            key_file: crate::defaults::agent_key_path(),
+            cert_file: crate::defaults::agent_cert_path(),
+            enable_tls: true,
+            verify_certs: true,
+            session_timeout: default_session_timeout(),
+            enable_auth: true,
+            allowed_clients: vec![],
+        }
+    }
+}
+
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
@@ -419,6 +1151,8 @@ impl Default for NetworkConfig {
             max_concurrent_connections: default_max_concurrent_connections(),
             tcp_keepalive: true,
             keepalive_interval: default_keepalive_interval(),
+            compression: true,
+            shutdown_timeout: default_shutdown_timeout(),
         }
     }
 }
@@ -433,6 +1167,89 @@ impl Default for MessageLimits {
     }
 }
 
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: default_worker_threads(),
+            io_buffer_size: default_io_buffer_size(),
+            async_io: true,
+            fs_cache_size: default_fs_cache_size(),
+            enable_prefetch: true,
+            prefetch_window: default_prefetch_window(),
+        }
+    }
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_entries: default_journal_max_entries(),
+        }
+    }
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: default_trash_directory(),
+            retention_secs: default_trash_retention(),
+            purge_interval_secs: default_trash_purge_interval(),
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            marker_suffix: default_archive_marker_suffix(),
+            recall_command: Vec::new(),
+            recall_timeout_secs: default_recall_timeout(),
+        }
+    }
+}
+
+impl Default for ResourceLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_buffer_mb: default_max_buffer_mb(),
+            max_open_files: default_max_open_files(),
+            max_response_mb: default_max_response_mb(),
+            max_listing_entries: default_max_listing_entries(),
+            max_page_entries: default_max_page_entries(),
+            max_path_length: default_max_path_length(),
+            max_path_depth: default_max_path_depth(),
+            max_concurrent_reads: default_max_concurrent_reads(),
+            max_concurrent_writes: default_max_concurrent_writes(),
+            max_queued_operations: default_max_queued_operations(),
+        }
+    }
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self {
+            session_soft_limit_mb: default_session_soft_limit_mb(),
+            session_hard_limit_mb: default_session_hard_limit_mb(),
+            total_limit_mb: default_total_buffer_limit_mb(),
+        }
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_secs: default_handshake_timeout(),
+            auth_timeout_secs: default_auth_timeout(),
+            max_unauthenticated: default_max_unauthenticated(),
+            max_per_ip: default_max_connections_per_ip(),
+        }
+    }
+}
+
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
@@ -443,6 +1260,19 @@ impl Default for LoggingConfig {
             max_files: default_log_file_count(),
             enable_access_log: false,
             access_log_file: None,
+            forward_access_log: false,
+            crash: CrashConfig::default(),
+        }
+    }
+}
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            directory: None,
+            recent_events: default_crash_recent_events(),
+            core_dumps: false,
         }
     }
 }
@@ -0,0 +1,231 @@
+//! Crash reports for the daemons
+//!
+//! At startup a daemon installs a panic hook that writes a report before the
+//! panic carries on: the panic and its backtrace, a snapshot of the state
+//! the daemon registered (sessions, in-flight operations) and its last log
+//! events, which [`RecentEvents`] keeps in a ring. A panic in a spawned task
+//! only ends that task, so every panic gets a report, not just those that
+//! end the process.
+//!
+//! The hook runs on the panicking thread, which may be holding any lock.
+//! State snapshots must never block: they should `try_read` and say what
+//! they could not see.
+
+use crate::config::CrashConfig;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Write as _};
+use std::panic::PanicHookInfo;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use tracing::field::{Field, Visit};
+use tracing::{warn, Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+type Snapshot = Box<dyn Fn() -> String + Send + Sync>;
+
+/// Where reports go and whom they are from, set once by [`install`]
+struct Reporter {
+    component: String,
+    directory: PathBuf,
+}
+
+static REPORTER: OnceLock<Reporter> = OnceLock::new();
+static SNAPSHOTS: Mutex<Vec<(String, Snapshot)>> = Mutex::new(Vec::new());
+static EVENTS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+/// Events kept for reports; events logged before [`install`] are kept too
+static EVENT_CAPACITY: AtomicUsize = AtomicUsize::new(200);
+static ABORT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Write a report whenever a thread of `component` panics
+///
+/// The hook runs before the one already installed, so panics are still
+/// printed as usual. Installing a second time has no effect.
+pub fn install(component: &str, config: &CrashConfig) {
+    if !config.enabled {
+        EVENT_CAPACITY.store(0, Ordering::Relaxed);
+        lock(&EVENTS).clear();
+        return;
+    }
+
+    let reporter = Reporter {
+        component: component.to_string(),
+        directory: config.directory.clone().unwrap_or_else(|| crate::defaults::data_dir().join("crash")),
+    };
+    if REPORTER.set(reporter).is_err() {
+        return;
+    }
+    EVENT_CAPACITY.store(config.recent_events, Ordering::Relaxed);
+    if config.core_dumps {
+        enable_core_dumps();
+        ABORT_ON_PANIC.store(true, Ordering::Relaxed);
+    }
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(reporter) = REPORTER.get() {
+            match write_report(reporter, info) {
+                Ok(path) => eprintln!("Crash report written to {}", path.display()),
+                Err(e) => eprintln!("Failed to write crash report: {}", e),
+            }
+        }
+        previous(info);
+        if ABORT_ON_PANIC.load(Ordering::Relaxed) {
+            std::process::abort();
+        }
+    }));
+}
+
+/// Include the output of `snapshot` under `name` in crash reports
+///
+/// `snapshot` runs inside the panic hook and must not block.
+pub fn add_snapshot(name: &str, snapshot: impl Fn() -> String + Send + Sync + 'static) {
+    lock(&SNAPSHOTS).push((name.to_string(), Box::new(snapshot)));
+}
+
+/// Layer keeping the last log events for crash reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RecentEvents;
+
+impl<S: Subscriber> Layer<S> for RecentEvents {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let capacity = EVENT_CAPACITY.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+
+        let metadata = event.metadata();
+        let mut line = format!(
+            "{} {:>5} {}:",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            metadata.level(),
+            metadata.target()
+        );
+        event.record(&mut LineVisitor(&mut line));
+
+        let mut events = lock(&EVENTS);
+        while events.len() >= capacity {
+            events.pop_front();
+        }
+        events.push_back(line);
+    }
+}
+
+/// Appends an event's fields to its log line
+struct LineVisitor<'a>(&'a mut String);
+
+impl Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {:?}", value);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+fn write_report(reporter: &Reporter, info: &PanicHookInfo<'_>) -> std::io::Result<PathBuf> {
+    let payload = info.payload();
+    let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    let location = info.location().map(|location| location.to_string());
+
+    let now = Utc::now();
+    let report = render_report(&reporter.component, message, location.as_deref(), now);
+
+    std::fs::create_dir_all(&reporter.directory)?;
+    let path = reporter.directory.join(format!(
+        "{}-{}-{}.crash",
+        reporter.component,
+        now.format("%Y%m%dT%H%M%SZ"),
+        std::process::id()
+    ));
+    std::fs::write(&path, report)?;
+    Ok(path)
+}
+
+fn render_report(component: &str, message: &str, location: Option<&str>, now: DateTime<Utc>) -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "{} {} crashed at {}", component, crate::VERSION, now.to_rfc3339());
+    let _ = writeln!(report, "pid: {}", std::process::id());
+    let _ = writeln!(report, "thread: {}", std::thread::current().name().unwrap_or("<unnamed>"));
+    let _ = writeln!(report, "panic: {}", message);
+    let _ = writeln!(report, "location: {}", location.unwrap_or("unknown"));
+    let _ = writeln!(report, "\n== backtrace ==\n{}", Backtrace::force_capture());
+
+    // The panicking thread may hold these locks; report without them
+    match SNAPSHOTS.try_lock() {
+        Ok(snapshots) => {
+            for (name, snapshot) in snapshots.iter() {
+                let _ = writeln!(report, "== {} ==\n{}", name, snapshot());
+            }
+        }
+        Err(_) => report.push_str("== state ==\nunavailable, the registry is locked\n"),
+    }
+
+    report.push_str("\n== recent log events, oldest first ==\n");
+    match EVENTS.try_lock() {
+        Ok(events) => {
+            for event in events.iter() {
+                let _ = writeln!(report, "{}", event);
+            }
+        }
+        Err(_) => report.push_str("unavailable, the event ring is locked\n"),
+    }
+    report
+}
+
+#[cfg(unix)]
+fn enable_core_dumps() {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: `limit` is a valid rlimit for both calls
+    let raised = unsafe {
+        libc::getrlimit(libc::RLIMIT_CORE, &mut limit) == 0 && {
+            limit.rlim_cur = limit.rlim_max;
+            libc::setrlimit(libc::RLIMIT_CORE, &limit) == 0
+        }
+    };
+    if !raised {
+        warn!("Failed to raise the core dump limit: {}", std::io::Error::last_os_error());
+    } else if limit.rlim_cur == 0 {
+        warn!("Core dumps are enabled but the hard limit allows none");
+    }
+}
+
+#[cfg(not(unix))]
+fn enable_core_dumps() {
+    warn!("Core dumps are not supported on this platform");
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_report_includes_state_and_recent_events() {
+        add_snapshot("sessions", || "2 clients, 1 agent".to_string());
+        let subscriber = tracing_subscriber::registry().with(RecentEvents);
+        tracing::subscriber::with_default(subscriber, || {
+            for n in 0..250 {
+                tracing::info!(request_id = n, "Handling request");
+            }
+        });
+
+        let report = render_report("remotefs-relay", "index out of bounds", Some("src/routing.rs:10:5"), Utc::now());
+        assert!(report.contains("panic: index out of bounds"));
+        assert!(report.contains("location: src/routing.rs:10:5"));
+        assert!(report.contains("== sessions ==\n2 clients, 1 agent"));
+        assert!(report.contains("Handling request request_id=249"));
+        // Only the most recent events are kept
+        assert!(!report.contains("request_id=49\n"));
+        assert!(report.contains("request_id=50\n"));
+    }
+}
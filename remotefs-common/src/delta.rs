@@ -0,0 +1,345 @@
+//! Delta transfer of files that changed a little
+//!
+//! The agent splits its copy of a file into blocks and sends a signature of
+//! each: a weak rolling checksum and a truncated BLAKE3 digest. The client
+//! slides a window over its own version, looking the rolling checksum up at
+//! every offset, and describes the new version as blocks of the old one and
+//! literal data in between. Only the literal data is sent, as with rsync, so
+//! a small edit in a large VM image or database costs a few blocks rather
+//! than the whole file.
+
+use crate::checksum::Checksum;
+use crate::protocol::ChecksumAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Smallest block a signature may be asked for
+pub const MIN_BLOCK_SIZE: u32 = 512;
+
+/// Largest block a signature may be asked for
+pub const MAX_BLOCK_SIZE: u32 = 1024 * 1024;
+
+/// Bytes of each block's BLAKE3 digest kept in a signature
+const STRONG_LEN: usize = 16;
+
+/// Block size for a file of `length` bytes: about its square root, so that
+/// the signature and the data resent around each change grow together
+pub fn block_size_for(length: u64) -> u32 {
+    let root = (length as f64).sqrt() as u64;
+    root.next_power_of_two().clamp(MIN_BLOCK_SIZE as u64 * 4, MAX_BLOCK_SIZE as u64 / 8) as u32
+}
+
+/// rsync's weak checksum over a window that slides a byte at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    pub fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a: a & 0xffff, b: b & 0xffff, len }
+    }
+
+    /// Slide the window one byte, dropping `out` and taking in `next`
+    pub fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    pub fn value(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+/// Checksums of one block of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+impl BlockSignature {
+    pub fn of(block: &[u8]) -> Self {
+        Self {
+            weak: RollingChecksum::new(block).value(),
+            strong: strong_digest(block),
+        }
+    }
+}
+
+/// Checksums of every block of a file, as answered by `GetFileSignature`;
+/// the last block is shorter unless the length is a multiple of the block
+/// size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSignature {
+    pub block_size: u32,
+    pub length: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+impl FileSignature {
+    /// Signature of `data` in blocks of `block_size` bytes
+    pub fn of(data: &[u8], block_size: u32) -> Self {
+        Self {
+            block_size,
+            length: data.len() as u64,
+            blocks: data.chunks(block_size.max(1) as usize).map(BlockSignature::of).collect(),
+        }
+    }
+
+    /// Signature of everything `reader` yields, a block at a time
+    pub fn from_reader<R: Read>(reader: R, block_size: u32) -> io::Result<Self> {
+        let mut reader = reader;
+        let mut block = vec![0; block_size.max(1) as usize];
+        let mut signature = Self { block_size, length: 0, blocks: Vec::new() };
+        loop {
+            let read = read_full(&mut reader, &mut block)?;
+            if read == 0 {
+                return Ok(signature);
+            }
+            signature.length += read as u64;
+            signature.blocks.push(BlockSignature::of(&block[..read]));
+        }
+    }
+}
+
+/// One step of rebuilding a file from its old version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// `count` blocks of the old version from block `block` on
+    Copy { block: u64, count: u64 },
+    /// Bytes the old version does not have
+    Data(Vec<u8>),
+}
+
+/// Bytes of literal data in `ops`, which is what a delta costs to send
+pub fn literal_len(ops: &[DeltaOp]) -> u64 {
+    ops.iter()
+        .map(|op| match op {
+            DeltaOp::Data(data) => data.len() as u64,
+            DeltaOp::Copy { .. } => 0,
+        })
+        .sum()
+}
+
+/// Describe `data` as blocks of the file `signature` was taken of and the
+/// literal data between them
+///
+/// Only whole blocks are matched; a short last block of the old version is
+/// sent as data if it is still there.
+pub fn diff(signature: &FileSignature, data: &[u8]) -> Vec<DeltaOp> {
+    let block_size = signature.block_size.max(1) as usize;
+    let mut blocks: HashMap<u32, Vec<u64>> = HashMap::new();
+    for (index, block) in signature.blocks.iter().enumerate() {
+        let whole = (index as u64 + 1) * block_size as u64 <= signature.length;
+        if whole {
+            blocks.entry(block.weak).or_default().push(index as u64);
+        }
+    }
+
+    let mut ops = Vec::new();
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = (data.len() >= block_size).then(|| RollingChecksum::new(&data[..block_size]));
+    while let Some(checksum) = rolling.as_mut() {
+        let window = &data[pos..pos + block_size];
+        let found = blocks.get(&checksum.value()).and_then(|candidates| {
+            let strong = strong_digest(window);
+            // Prefer the block after the last one copied, so runs merge
+            let next = match ops.last() {
+                Some(DeltaOp::Copy { block, count }) if literal_start == pos => Some(block + count),
+                _ => None,
+            };
+            let matching = |index: &&u64| signature.blocks[**index as usize].strong == strong;
+            candidates.iter().filter(matching).find(|index| Some(**index) == next)
+                .or_else(|| candidates.iter().find(matching))
+                .copied()
+        });
+
+        if let Some(index) = found {
+            push_data(&mut ops, &data[literal_start..pos]);
+            push_copy(&mut ops, index);
+            pos += block_size;
+            literal_start = pos;
+            rolling = (data.len() >= pos + block_size).then(|| RollingChecksum::new(&data[pos..pos + block_size]));
+        } else if pos + block_size < data.len() {
+            checksum.roll(data[pos], data[pos + block_size]);
+            pos += 1;
+        } else {
+            rolling = None;
+        }
+    }
+    push_data(&mut ops, &data[literal_start..]);
+    ops
+}
+
+fn push_copy(ops: &mut Vec<DeltaOp>, index: u64) {
+    if let Some(DeltaOp::Copy { block, count }) = ops.last_mut() {
+        if *block + *count == index {
+            *count += 1;
+            return;
+        }
+    }
+    ops.push(DeltaOp::Copy { block: index, count: 1 });
+}
+
+fn push_data(ops: &mut Vec<DeltaOp>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    if let Some(DeltaOp::Data(last)) = ops.last_mut() {
+        last.extend_from_slice(data);
+        return;
+    }
+    ops.push(DeltaOp::Data(data.to_vec()));
+}
+
+/// Rebuild a file into `out` from `ops` and its old version `basis` of
+/// `basis_len` bytes, returning the bytes written
+///
+/// Fails with `InvalidData` if a copy refers to blocks past the end of the
+/// old version.
+pub fn apply<R, W>(basis: &mut R, basis_len: u64, block_size: u32, ops: &[DeltaOp], out: &mut W) -> io::Result<u64>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let block_size = block_size.max(1) as u64;
+    let mut written = 0;
+    for op in ops {
+        match op {
+            DeltaOp::Copy { block, count } => {
+                let start = block.checked_mul(block_size).filter(|start| *start < basis_len);
+                let Some(start) = start else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Block {} is past the end of the file", block),
+                    ));
+                };
+                let len = count.saturating_mul(block_size).min(basis_len - start);
+                basis.seek(SeekFrom::Start(start))?;
+                let copied = io::copy(&mut basis.take(len), out)?;
+                if copied < len {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrank while it was rebuilt"));
+                }
+                written += copied;
+            }
+            DeltaOp::Data(data) => {
+                out.write_all(data)?;
+                written += data.len() as u64;
+            }
+        }
+    }
+    Ok(written)
+}
+
+fn strong_digest(block: &[u8]) -> Vec<u8> {
+    let mut digest = Checksum::of(ChecksumAlgorithm::Blake3, block).digest;
+    digest.truncate(STRONG_LEN);
+    digest
+}
+
+/// Fill `buffer` unless the reader ends first, returning the bytes read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn pseudo_random(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    fn rebuild(old: &[u8], ops: &[DeltaOp], block_size: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        let written = apply(&mut Cursor::new(old), old.len() as u64, block_size, ops, &mut out).unwrap();
+        assert_eq!(written, out.len() as u64);
+        out
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh() {
+        let data = pseudo_random(4096, 1);
+        let mut rolling = RollingChecksum::new(&data[..1024]);
+        for start in 1..=data.len() - 1024 {
+            rolling.roll(data[start - 1], data[start + 1023]);
+            assert_eq!(rolling, RollingChecksum::new(&data[start..start + 1024]));
+        }
+    }
+
+    #[test]
+    fn test_small_edit_sends_little() {
+        let old = pseudo_random(1024 * 1024, 2);
+        let block_size = 4096;
+        let signature = FileSignature::from_reader(Cursor::new(&old), block_size).unwrap();
+        assert_eq!(signature, FileSignature::of(&old, block_size));
+
+        // Bytes inserted in the middle shift everything after them
+        let mut new = old[..300_000].to_vec();
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&old[300_000..]);
+
+        let ops = diff(&signature, &new);
+        assert!(literal_len(&ops) < 2 * block_size as u64, "sent {} bytes", literal_len(&ops));
+        assert_eq!(rebuild(&old, &ops, block_size), new);
+    }
+
+    #[test]
+    fn test_unrelated_and_empty_files() {
+        let old = pseudo_random(10_000, 3);
+        let new = pseudo_random(10_000, 4);
+        let signature = FileSignature::of(&old, 1024);
+        assert_eq!(diff(&signature, &new), vec![DeltaOp::Data(new.clone())]);
+
+        // A short last block is sent as data
+        let ops = diff(&signature, &old);
+        assert_eq!(ops[0], DeltaOp::Copy { block: 0, count: 9 });
+        assert_eq!(rebuild(&old, &ops, 1024), old);
+
+        let empty = FileSignature::of(&[], 1024);
+        assert!(empty.blocks.is_empty());
+        assert_eq!(diff(&empty, &new), vec![DeltaOp::Data(new.clone())]);
+        assert!(diff(&signature, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_copy_past_end_is_refused() {
+        let old = pseudo_random(2048, 5);
+        let ops = [DeltaOp::Copy { block: 2, count: 1 }];
+        let error = apply(&mut Cursor::new(&old), old.len() as u64, 1024, &ops, &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_block_size_for() {
+        assert_eq!(block_size_for(0), MIN_BLOCK_SIZE * 4);
+        assert_eq!(block_size_for(64 * 1024 * 1024), 8192);
+        assert_eq!(block_size_for(u64::MAX), MAX_BLOCK_SIZE / 8);
+    }
+}
@@ -25,6 +25,11 @@ pub enum RemoteFsError {
     #[error("File system error: {0}")]
     FileSystem(String),
     
+    /// A filesystem call that failed with an OS error, or an agent's report
+    /// of one; `errno` is the error number, when known
+    #[error("File system error: {message}")]
+    Os { code: ErrorCode, errno: Option<i32>, message: String },
+    
     #[error("Encryption error: {0}")]
     Encryption(#[from] anyhow::Error),
     
@@ -58,6 +63,9 @@ pub enum RemoteFsError {
     #[error("Service unavailable: {0}")]
     ServiceUnavailable(String),
     
+    #[error("File offline: {0}")]
+    Offline(String),
+    
     #[error("Not implemented: {0}")]
     NotImplemented(String),
     
@@ -82,11 +90,45 @@ impl RemoteFsError {
             RemoteFsError::Protocol(_) => ErrorCode::InvalidMessage,
             RemoteFsError::ServiceUnavailable(_) => ErrorCode::ServiceUnavailable,
             RemoteFsError::NotImplemented(_) => ErrorCode::NotImplemented,
+            RemoteFsError::Offline(_) => ErrorCode::FileOffline,
             RemoteFsError::Session(_) => ErrorCode::SessionExpired,
+            RemoteFsError::Os { code, .. } => code.clone(),
+            RemoteFsError::Io(e) => ErrorCode::from_io_error(e),
             _ => ErrorCode::InternalError,
         }
     }
     
+    /// OS error number behind the error, if it came from a failed call
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            RemoteFsError::Os { errno, .. } => *errno,
+            RemoteFsError::Io(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+    
+    /// POSIX error number to report for the error to local applications
+    ///
+    /// The code decides, unless it only says the failure was internal and
+    /// the agent sent an error number. Numbers up to `ERANGE` are the same
+    /// on every Unix, while later ones differ between the agent's system and
+    /// ours, so those fall back to `EIO`.
+    pub fn to_errno(&self) -> i32 {
+        match (self.to_error_code(), self.errno()) {
+            (ErrorCode::InternalError, Some(errno)) if (1..=libc::ERANGE).contains(&errno) => errno,
+            (code, _) => code.to_errno(),
+        }
+    }
+    
+    /// Error for a failed filesystem call, keeping its error number
+    pub fn io(context: &str, error: std::io::Error) -> Self {
+        RemoteFsError::Os {
+            code: ErrorCode::from_io_error(&error),
+            errno: error.raw_os_error(),
+            message: format!("{}: {}", context, error),
+        }
+    }
+    
     /// Create from protocol error code
     pub fn from_error_code(code: ErrorCode, message: String) -> Self {
         match code {
@@ -100,8 +142,14 @@ impl RemoteFsError {
             ErrorCode::DirectoryNotFound => RemoteFsError::NotFound(message),
             ErrorCode::PathAlreadyExists => RemoteFsError::AlreadyExists(message),
             ErrorCode::InvalidPath => RemoteFsError::InvalidPath(message),
-            ErrorCode::DiskFull => RemoteFsError::FileSystem(format!("Disk full: {}", message)),
-            ErrorCode::ReadOnlyFileSystem => RemoteFsError::FileSystem(format!("Read-only filesystem: {}", message)),
+            ErrorCode::FileOffline => RemoteFsError::Offline(message),
+            code @ (ErrorCode::DiskFull
+            | ErrorCode::ReadOnlyFileSystem
+            | ErrorCode::NotADirectory
+            | ErrorCode::IsADirectory
+            | ErrorCode::DirectoryNotEmpty
+            | ErrorCode::NameTooLong
+            | ErrorCode::QuotaExceeded) => RemoteFsError::Os { code, errno: None, message },
             ErrorCode::NetworkError => RemoteFsError::Network(message),
             ErrorCode::ConnectionTimeout => RemoteFsError::Timeout(message),
             ErrorCode::MessageTooLarge => RemoteFsError::Protocol(format!("Message too large: {}", message)),
@@ -112,6 +160,17 @@ impl RemoteFsError {
         }
     }
     
+    /// Create from an `Error` response, keeping the error number it carries
+    pub fn from_error_response(code: ErrorCode, errno: Option<i32>, message: String) -> Self {
+        match (RemoteFsError::from_error_code(code, message), errno) {
+            (RemoteFsError::Os { code, message, .. }, errno) => RemoteFsError::Os { code, errno, message },
+            (RemoteFsError::Internal(message), Some(errno)) => {
+                RemoteFsError::Os { code: ErrorCode::InternalError, errno: Some(errno), message }
+            }
+            (error, _) => error,
+        }
+    }
+    
     /// Check if error is retryable
     pub fn is_retryable(&self) -> bool {
         matches!(self,
@@ -129,11 +188,72 @@ impl RemoteFsError {
             RemoteFsError::Connection(_) |
             RemoteFsError::Timeout(_) |
             RemoteFsError::ServiceUnavailable(_) |
-            RemoteFsError::Session(_)
+            RemoteFsError::Session(_) |
+            RemoteFsError::Offline(_)
         )
     }
 }
 
+impl ErrorCode {
+    /// Code for an OS error number
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::ENOENT => ErrorCode::FileNotFound,
+            libc::EACCES | libc::EPERM => ErrorCode::InsufficientPermissions,
+            libc::EEXIST => ErrorCode::PathAlreadyExists,
+            libc::EINVAL => ErrorCode::InvalidPath,
+            libc::ENOSPC => ErrorCode::DiskFull,
+            libc::EROFS => ErrorCode::ReadOnlyFileSystem,
+            libc::ENOTDIR => ErrorCode::NotADirectory,
+            libc::EISDIR => ErrorCode::IsADirectory,
+            libc::ENOTEMPTY => ErrorCode::DirectoryNotEmpty,
+            libc::ENAMETOOLONG => ErrorCode::NameTooLong,
+            libc::EDQUOT => ErrorCode::QuotaExceeded,
+            libc::ETIMEDOUT => ErrorCode::ConnectionTimeout,
+            libc::ENOSYS | libc::ENOTSUP => ErrorCode::NotImplemented,
+            _ => ErrorCode::InternalError,
+        }
+    }
+    
+    /// Code for an I/O error, by its error number when it has one
+    pub fn from_io_error(error: &std::io::Error) -> Self {
+        if let Some(errno) = error.raw_os_error() {
+            return ErrorCode::from_errno(errno);
+        }
+        match error.kind() {
+            std::io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+            std::io::ErrorKind::PermissionDenied => ErrorCode::InsufficientPermissions,
+            std::io::ErrorKind::AlreadyExists => ErrorCode::PathAlreadyExists,
+            std::io::ErrorKind::InvalidInput => ErrorCode::InvalidPath,
+            std::io::ErrorKind::TimedOut => ErrorCode::ConnectionTimeout,
+            _ => ErrorCode::InternalError,
+        }
+    }
+    
+    /// POSIX error number a filesystem reports for the code
+    pub fn to_errno(&self) -> i32 {
+        match self {
+            ErrorCode::AuthenticationFailed | ErrorCode::InvalidCredentials | ErrorCode::SessionExpired => libc::EPERM,
+            ErrorCode::AccessDenied | ErrorCode::PathNotAllowed | ErrorCode::InsufficientPermissions => libc::EACCES,
+            ErrorCode::FileNotFound | ErrorCode::DirectoryNotFound => libc::ENOENT,
+            ErrorCode::PathAlreadyExists => libc::EEXIST,
+            ErrorCode::InvalidPath | ErrorCode::InvalidMessage => libc::EINVAL,
+            ErrorCode::DiskFull => libc::ENOSPC,
+            ErrorCode::ReadOnlyFileSystem => libc::EROFS,
+            ErrorCode::NotADirectory => libc::ENOTDIR,
+            ErrorCode::IsADirectory => libc::EISDIR,
+            ErrorCode::DirectoryNotEmpty => libc::ENOTEMPTY,
+            ErrorCode::NameTooLong => libc::ENAMETOOLONG,
+            ErrorCode::QuotaExceeded => libc::EDQUOT,
+            ErrorCode::FileOffline | ErrorCode::ServiceUnavailable => libc::EAGAIN,
+            ErrorCode::ConnectionTimeout => libc::ETIMEDOUT,
+            ErrorCode::MessageTooLarge => libc::EFBIG,
+            ErrorCode::NotImplemented => libc::ENOTSUP,
+            ErrorCode::NetworkError | ErrorCode::InternalError => libc::EIO,
+        }
+    }
+}
+
 /// Result type alias for RemoteFS operations
 pub type Result<T> = std::result::Result<T, RemoteFsError>;
 
@@ -154,3 +274,36 @@ impl From<std::io::Error> for RemoteFsError {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_errors_keep_their_number() {
+        let full = RemoteFsError::io("Failed to write file", std::io::Error::from_raw_os_error(libc::ENOSPC));
+        assert!(matches!(full.to_error_code(), ErrorCode::DiskFull));
+        assert_eq!(full.errno(), Some(libc::ENOSPC));
+        assert_eq!(full.to_errno(), libc::ENOSPC);
+        assert!(full.to_string().starts_with("File system error: Failed to write file"));
+
+        let denied = RemoteFsError::io("Failed to open file", std::io::Error::from_raw_os_error(libc::EACCES));
+        assert_eq!(denied.to_errno(), libc::EACCES);
+    }
+
+    #[test]
+    fn test_error_responses_map_to_posix_errors() {
+        let not_empty = RemoteFsError::from_error_response(ErrorCode::DirectoryNotEmpty, None, "/data".to_string());
+        assert_eq!(not_empty.to_errno(), libc::ENOTEMPTY);
+        let missing = RemoteFsError::from_error_response(ErrorCode::FileNotFound, Some(libc::ENOENT), "/data".to_string());
+        assert!(matches!(missing, RemoteFsError::NotFound(_)));
+
+        // Numbers the code does not name are kept while portable
+        let busy = RemoteFsError::from_error_response(ErrorCode::InternalError, Some(libc::ETXTBSY), "/bin".to_string());
+        assert_eq!(busy.errno(), Some(libc::ETXTBSY));
+        assert_eq!(busy.to_errno(), libc::ETXTBSY);
+        let looped = RemoteFsError::from_error_response(ErrorCode::InternalError, Some(libc::ELOOP), "/loop".to_string());
+        assert_eq!(looped.to_errno(), libc::EIO);
+        assert_eq!(RemoteFsError::Internal("x".to_string()).to_errno(), libc::EIO);
+    }
+}
@@ -2,21 +2,38 @@
 //! 
 //! This library contains shared functionality used by all RemoteFS components:
 //! - Protocol definitions for communication between client, agent, and relay
+//! - File metadata conversions between local filesystems and the protocol
 //! - Encryption and cryptography utilities 
+//! - Signed session tokens
 //! - Configuration structures and handling
 //! - Error types and conversions
+//! - Message compression and its statistics
+//! - Crash reports for the daemons
+//! - systemd units for the daemons, and sockets systemd passes them
+//! - File checksums
+//! - Delta transfer of changed files
 //! - Utility functions
 
 pub mod protocol;
+pub mod metadata;
 pub mod crypto;
+pub mod token;
 pub mod error;
 pub mod config;
 pub mod utils;
+pub mod compression;
+pub mod crash;
+pub mod service;
+pub mod checksum;
+pub mod delta;
+mod blake3;
+pub mod activation;
 
 // Re-export commonly used types
 pub use protocol::{
-    Message, NodeType, ErrorCode, RequestId, NodeId, SessionToken, FsPath,
-    FileMetadata, DirEntry, RelayInfo, generate_request_id,
+    Message, NodeType, Capability, ErrorCode, RequestId, NodeId, SessionToken, FsPath,
+    FileMetadata, DirEntry, SearchMatch, BackupEntry, XattrSetMode, ChecksumAlgorithm, CompressionAlgorithm, LockType, LockOwner, FileLock, TransactionOp, NewFile, BatchFailure, OutputStream, PathReadiness, ExportInfo, AgentInfo, AgentEvent, AuditRecord, MaintenanceWindow, LocalOpenRequest, LocalOpenResponse, RelayInfo, RelayEndpoint, RelayDirectory, CallerIdentity, ChangeKind, ChangeRecord, ChangeSet,
+    generate_request_id,
 };
 
 pub use crypto::{
@@ -27,11 +44,13 @@ pub use crypto::{
 
 pub use error::{RemoteFsError, Result};
 
+pub use compression::{CompressionStats, AutoDisable};
+
 pub use config::{
-    ClientConfig, AgentConfig, RelayConfig, MountPoint, MountOptions,
-    CacheConfig, AccessConfig, SecurityConfig, NetworkConfig, 
-    MessageLimits, SessionConfig, StorageConfig, PerformanceConfig,
-    LoggingConfig, load_config, save_config,
+    ClientConfig, AgentConfig, RelayMode, RelayConfig, MountPoint, MountOptions,
+    CacheConfig, AccessConfig, UserAccessRule, UnmatchedUserPolicy, AccessRule, RuleEffect, AccessVerb, PathQuota, SecurityConfig, NetworkConfig, 
+    MessageLimits, SessionConfig, StorageConfig, PerformanceConfig, JournalConfig, ArchiveConfig, TrashConfig, MirrorConfig, ResourceLimitsConfig, RateLimitConfig, RemoteExecConfig, ExecCommandConfig, IdMapConfig, IdMapping, MirrorPair, DiscoveryConfig, BufferLimits, HardeningConfig, ConnectionLimits, VirtualHost, RelayService, PublicExport,
+    LoggingConfig, CrashConfig, load_config, save_config,
     load_client_config, load_agent_config, load_relay_config,
 };
 
@@ -113,7 +132,6 @@ pub mod defaults {
 /// Utility functions for working with configurations
 pub mod config_utils {
     use crate::{config::*, defaults, Result};
-    use std::path::PathBuf;
     
     /// Create default client configuration
     pub fn create_default_client_config() -> ClientConfig {
@@ -147,14 +165,23 @@ pub mod config_utils {
         AgentConfig {
             agent_id: format!("agent-{}", uuid::Uuid::new_v4()),
             relay_url: "wss://localhost:8080/ws".to_string(),
+            relay_urls: vec![],
+            relay_mode: RelayMode::default(),
             access: AccessConfig {
                 allowed_paths: vec!["/tmp".to_string()],
                 read_only_paths: vec![],
                 denied_paths: vec!["/etc".to_string(), "/root".to_string()],
                 max_file_size: 10 * 1024 * 1024 * 1024, // 10GB
                 follow_symlinks: true,
+                deny_symlink_escapes: false,
                 allowed_extensions: vec![],
                 denied_extensions: vec![],
+                user_rules: vec![],
+                unmatched_users: UnmatchedUserPolicy::Allow,
+                rules: vec![],
+                unmatched_rules: RuleEffect::Allow,
+                allowed_mode: 0o7777,
+                quotas: Vec::new(),
             },
             security: SecurityConfig {
                 key_file: defaults::agent_key_path(),
@@ -175,6 +202,15 @@ pub mod config_utils {
                 enable_prefetch: true,
                 prefetch_window: 8,
             },
+            journal: JournalConfig::default(),
+            archive: ArchiveConfig::default(),
+            trash: TrashConfig::default(),
+            mirror: MirrorConfig::default(),
+            limits: ResourceLimitsConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            remote_exec: RemoteExecConfig::default(),
+            id_map: IdMapConfig::default(),
+            local_socket: None,
         }
     }
     
@@ -191,6 +227,7 @@ pub mod config_utils {
                 cleanup_interval: 300,
                 enable_persistence: false,
                 storage_path: None,
+                resumption_ticket_secs: 300,
             },
             storage: StorageConfig {
                 temp_dir: defaults::data_dir().join("relay").join("temp"),
@@ -210,6 +247,14 @@ pub mod config_utils {
             },
             network: NetworkConfig::default(),
             logging: LoggingConfig::default(),
+            mirrors: Vec::new(),
+            discovery: DiscoveryConfig::default(),
+            buffers: BufferLimits::default(),
+            connections: ConnectionLimits::default(),
+            virtual_hosts: Vec::new(),
+            public_exports: Vec::new(),
+            admin_token: None,
+            hardening: HardeningConfig::default(),
         }
     }
     
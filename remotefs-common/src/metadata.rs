@@ -0,0 +1,189 @@
+//! Conversions between local file metadata and the protocol's `FileMetadata`
+//!
+//! Agents build `FileMetadata` from what the local filesystem reports and
+//! mounts read it back into their own attribute formats, both through the
+//! helpers here, so every side agrees on what each field holds.
+
+use crate::protocol::{FileMetadata, FileType};
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// File type bits of a directory's mode
+const S_IFDIR: u32 = 0o040000;
+
+impl FileType {
+    /// The protocol's name for a local file type
+    pub fn from_fs(file_type: fs::FileType) -> Self {
+        if file_type.is_dir() {
+            FileType::Directory
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_fifo() {
+            FileType::Fifo
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else {
+            FileType::File
+        }
+    }
+}
+
+impl FileMetadata {
+    /// Metadata of the file at `path`, as `lstat` reported it in `metadata`
+    ///
+    /// Timestamps keep nanosecond precision. Where the platform cannot report
+    /// a birth time, the earliest of mtime and ctime stands in for it so
+    /// clients never see a creation date later than the file's contents.
+    pub fn from_fs(metadata: &fs::Metadata, path: &Path) -> Self {
+        let modified = metadata.modified().ok().map(utc).unwrap_or_default();
+        let accessed = metadata.accessed().ok().map(utc).unwrap_or_default();
+        let changed = DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32)
+            .unwrap_or(modified);
+        let created = metadata.created().ok().map(utc)
+            .unwrap_or_else(|| modified.min(changed));
+        let file_type = metadata.file_type();
+
+        FileMetadata {
+            size: metadata.len(),
+            modified,
+            created,
+            accessed,
+            changed,
+            permissions: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            is_dir: file_type.is_dir(),
+            is_file: file_type.is_file(),
+            is_symlink: file_type.is_symlink(),
+            hidden: is_hidden(metadata, path),
+            offline: false,
+            nlink: metadata.nlink(),
+            file_type: FileType::from_fs(file_type),
+            symlink_target: if file_type.is_symlink() {
+                path.read_link().ok().and_then(|target| target.to_str().map(str::to_string))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Metadata of a directory that exists only on the client, such as a
+    /// mount root joining several exports, with every timestamp at `time`
+    pub fn synthetic_directory(mode: u32, time: DateTime<Utc>) -> Self {
+        FileMetadata {
+            size: 0,
+            modified: time,
+            created: time,
+            accessed: time,
+            changed: time,
+            permissions: S_IFDIR | (mode & 0o7777),
+            uid: 0,
+            gid: 0,
+            is_dir: true,
+            is_file: false,
+            is_symlink: false,
+            hidden: false,
+            offline: false,
+            nlink: 2,
+            file_type: FileType::Directory,
+            symlink_target: None,
+        }
+    }
+
+    /// Permission bits, without the file type bits `permissions` may carry
+    pub fn mode(&self) -> u32 {
+        self.permissions & 0o7777
+    }
+
+    /// Inode change time; agents that predate `changed` leave it at the
+    /// epoch, and their mtime stands in for it
+    pub fn change_time(&self) -> DateTime<Utc> {
+        if self.changed == DateTime::<Utc>::default() {
+            self.modified
+        } else {
+            self.changed
+        }
+    }
+}
+
+fn utc(time: SystemTime) -> DateTime<Utc> {
+    DateTime::<Utc>::from(time)
+}
+
+/// Whether file browsers hide the file: a dotfile, or one flagged
+/// `UF_HIDDEN` on macOS
+fn is_hidden(metadata: &fs::Metadata, path: &Path) -> bool {
+    let dotfile = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'));
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::macos::fs::MetadataExt as _;
+        const UF_HIDDEN: u32 = 0x8000;
+        dotfile || metadata.st_flags() & UF_HIDDEN != 0
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = metadata;
+        dotfile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_from_fs() {
+        let dir = std::env::temp_dir().join(format!("remotefs-metadata-{}", crate::protocol::generate_request_id()));
+        fs::create_dir(&dir).unwrap();
+        let file = dir.join(".hidden");
+        fs::write(&file, b"hello").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+
+        let local = fs::metadata(&file).unwrap();
+        let metadata = FileMetadata::from_fs(&local, &file);
+        assert_eq!(metadata.size, 5);
+        assert_eq!(metadata.mode(), 0o640);
+        assert_eq!((metadata.uid, metadata.gid), (local.uid(), local.gid()));
+        assert!(metadata.is_file && metadata.hidden);
+        assert!(matches!(metadata.file_type, FileType::File));
+        assert!(metadata.created <= metadata.modified);
+        assert_eq!(metadata.change_time(), metadata.changed);
+
+        let metadata = FileMetadata::from_fs(&fs::symlink_metadata(&link).unwrap(), &link);
+        assert!(metadata.is_symlink && !metadata.hidden);
+        assert!(matches!(metadata.file_type, FileType::Symlink));
+        assert_eq!(metadata.symlink_target.as_deref(), file.to_str());
+
+        let metadata = FileMetadata::from_fs(&fs::metadata(&dir).unwrap(), &dir);
+        assert!(metadata.is_dir && matches!(metadata.file_type, FileType::Directory));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_synthetic_directory() {
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let root = FileMetadata::synthetic_directory(0o555, time);
+        assert_eq!(root.permissions, 0o40555);
+        assert_eq!(root.mode(), 0o555);
+        assert_eq!(root.change_time(), time);
+
+        // Agents that predate `changed` leave it unset
+        let legacy = FileMetadata { changed: DateTime::default(), ..FileMetadata::synthetic_directory(0o755, time) };
+        assert_eq!(legacy.change_time(), time);
+    }
+}
@@ -1,7 +1,8 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use crate::delta::{DeltaOp, FileSignature};
 
 /// Unique identifier for a request-response pair
 pub type RequestId = Uuid;
@@ -32,18 +33,83 @@ pub enum FileType {
 pub struct FileMetadata {
     pub size: u64,
     pub modified: DateTime<Utc>,
+    /// Birth time; agents that cannot report one substitute the earliest known timestamp
     pub created: DateTime<Utc>,
     pub accessed: DateTime<Utc>,
+    /// Inode change time (ctime)
+    #[serde(default)]
+    pub changed: DateTime<Utc>,
     pub permissions: u32,
     pub uid: u32,
     pub gid: u32,
     pub is_dir: bool,
     pub is_file: bool,
     pub is_symlink: bool,
+    /// Hidden from file browsers (dotfile, or `UF_HIDDEN` on macOS agents)
+    #[serde(default)]
+    pub hidden: bool,
+    /// Content has been archived to cold storage and must be recalled before reading
+    #[serde(default)]
+    pub offline: bool,
+    /// Number of hard links; 0 from agents that do not report it
+    #[serde(default)]
+    pub nlink: u64,
     pub file_type: FileType,
     pub symlink_target: Option<String>,
 }
 
+/// Kind of change recorded in an agent's change journal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+    /// Moved from `from` to the record's path
+    Renamed { from: FsPath },
+}
+
+/// A single change journal entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    /// Increases by one for every recorded change
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    pub kind: ChangeKind,
+    pub path: FsPath,
+    pub is_dir: bool,
+}
+
+/// Changes after a cursor, as returned by `GetChanges`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeSet {
+    pub changes: Vec<ChangeRecord>,
+    /// Cursor to pass as `since` for the next query
+    pub next_cursor: u64,
+    /// Changes after the cursor are no longer retained; the client must
+    /// rescan and continue from `next_cursor`
+    pub reset: bool,
+}
+
+/// Local user a request is made on behalf of
+///
+/// Sent by shared (`allow_other`) mounts so the agent can apply per-user
+/// access rules instead of granting every local user the mounting user's access.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallerIdentity {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary group IDs
+    #[serde(default)]
+    pub groups: Vec<u32>,
+}
+
+impl CallerIdentity {
+    /// Whether the caller's primary or supplementary groups include `gid`
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
 /// Directory entry information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirEntry {
@@ -51,6 +117,437 @@ pub struct DirEntry {
     pub metadata: FileMetadata,
 }
 
+/// A file found by `SearchFiles`, with the line that matched when the
+/// search looked at content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchMatch {
+    /// Path relative to the search root
+    pub path: String,
+    /// Number of the matching line, counted from 1
+    pub line_number: Option<u64>,
+    /// The matching line without its line ending, cut off at the agent's
+    /// limit
+    pub line: Option<String>,
+}
+
+/// Partial metadata update; only fields that are `Some` are applied
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetadataUpdate {
+    pub permissions: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub accessed: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl MetadataUpdate {
+    /// Check whether the update would change anything
+    pub fn is_empty(&self) -> bool {
+        self.permissions.is_none()
+            && self.uid.is_none()
+            && self.gid.is_none()
+            && self.accessed.is_none()
+            && self.modified.is_none()
+    }
+}
+
+/// How far a `SetMetadataTree` has got
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeProgress {
+    /// Entries changed so far
+    pub changed: u64,
+    /// Entries skipped because the caller may not change them or the
+    /// change failed
+    pub failed: u64,
+    /// Why the first skipped entry was skipped
+    pub first_failure: Option<String>,
+    /// Stopped by `CancelSetMetadataTree` before every entry was reached
+    pub cancelled: bool,
+}
+
+/// A file together with everything needed to restore it faithfully, as
+/// returned by `ReadBackupEntry`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupEntry {
+    pub path: FsPath,
+    /// Metadata of the entry itself (symlinks are not followed), with the
+    /// owner's real uid and gid
+    pub metadata: FileMetadata,
+    /// Extended attributes by name; empty where the filesystem has none
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// File content; empty for directories and symlinks
+    pub data: Vec<u8>,
+}
+
+/// How `SetXattr` treats an attribute that does or does not exist yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum XattrSetMode {
+    /// Create the attribute or replace its value
+    #[default]
+    Upsert,
+    /// Fail if the attribute exists
+    Create,
+    /// Fail if the attribute does not exist
+    Replace,
+}
+
+/// One step of a `Transaction`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionOp {
+    /// Replace a file's content, creating the file if needed
+    WriteFile { path: FsPath, data: Vec<u8> },
+    /// Rename a file or directory, replacing any file at `to_path`
+    Rename { from_path: FsPath, to_path: FsPath },
+    DeleteFile { path: FsPath },
+    CreateDirectory { path: FsPath },
+    /// Remove an empty directory
+    RemoveDirectory { path: FsPath },
+}
+
+impl TransactionOp {
+    /// Short name used in logs and errors
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionOp::WriteFile { .. } => "write",
+            TransactionOp::Rename { .. } => "rename",
+            TransactionOp::DeleteFile { .. } => "delete",
+            TransactionOp::CreateDirectory { .. } => "mkdir",
+            TransactionOp::RemoveDirectory { .. } => "rmdir",
+        }
+    }
+}
+
+/// Most files accepted in one `BatchCreateFiles` request
+pub const MAX_BATCH_FILES: usize = 1000;
+
+/// File data a client puts in one `BatchCreateFiles` request; a larger file
+/// goes in a batch of its own
+pub const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Most requests accepted in one `Batch`
+pub const MAX_BATCH_OPERATIONS: usize = 256;
+
+/// Largest chunk of a `ReadFileStream` or `WriteFileChunk` transfer
+pub const MAX_STREAM_CHUNK: u32 = 1024 * 1024;
+
+/// A file for `BatchCreateFiles`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewFile {
+    pub path: FsPath,
+    pub data: Vec<u8>,
+    /// Permission bits of the new file; the agent's umask applies otherwise
+    pub mode: Option<u32>,
+}
+
+/// A file of a `BatchCreateFiles` request that was not created
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchFailure {
+    /// Position of the file in the request
+    pub index: u32,
+    pub error: String,
+}
+
+/// Digest computed by `ComputeChecksum`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChecksumAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// Name used on the command line and in logs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+impl std::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Compression of a `Compressed` message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgorithm {
+    /// LZ4 block format, preceded by the decompressed size as a little-endian u32
+    Lz4,
+}
+
+impl std::str::FromStr for ChecksumAlgorithm {
+    type Err = String;
+    
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            _ => Err(format!("unknown checksum algorithm {}; expected sha256 or blake3", name)),
+        }
+    }
+}
+
+/// Kind of advisory lock, as with `fcntl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockType {
+    /// Shared with other read locks
+    Read,
+    /// Held by one owner only
+    Write,
+}
+
+/// Holder of advisory locks
+///
+/// Locks of different owners conflict, even within one client; a client
+/// picks an owner per process or open file as its platform's locks do.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LockOwner {
+    /// Client the owner belongs to, filled in by the relay; clients leave
+    /// it empty
+    #[serde(default)]
+    pub session: String,
+    pub owner: u64,
+}
+
+/// An advisory lock on a byte range of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileLock {
+    pub lock_type: LockType,
+    pub start: u64,
+    /// Bytes locked; 0 locks up to the end of the file, however it grows
+    pub length: u64,
+    pub owner: LockOwner,
+}
+
+impl FileLock {
+    /// Lock requested by a client for one of its owners
+    pub fn new(lock_type: LockType, start: u64, length: u64, owner: u64) -> Self {
+        Self {
+            lock_type,
+            start,
+            length,
+            owner: LockOwner { session: String::new(), owner },
+        }
+    }
+    
+    /// First byte past the lock, or `None` if it runs to the end of the file
+    pub fn end(&self) -> Option<u64> {
+        match self.length {
+            0 => None,
+            length => self.start.checked_add(length),
+        }
+    }
+    
+    /// Whether the lock covers any of the bytes from `start` up to `end`
+    pub fn overlaps(&self, start: u64, end: Option<u64>) -> bool {
+        self.end().is_none_or(|own_end| own_end > start) && end.is_none_or(|end| end > self.start)
+    }
+    
+    /// Whether this lock keeps `other` from being taken
+    pub fn conflicts_with(&self, other: &FileLock) -> bool {
+        self.owner != other.owner
+            && (self.lock_type == LockType::Write || other.lock_type == LockType::Write)
+            && self.overlaps(other.start, other.end())
+    }
+}
+
+/// How `OpenFile` opens a file, after the `open(2)` flags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    /// Every write goes to the end of the file, whatever its offset
+    pub append: bool,
+    /// Create the file if it does not exist
+    pub create: bool,
+    /// With `create`, fail if the file exists
+    pub exclusive: bool,
+    /// Empty the file once opened for writing
+    pub truncate: bool,
+}
+
+impl OpenFlags {
+    /// Flags opening a file for reading only
+    pub fn read_only() -> Self {
+        Self { read: true, ..Self::default() }
+    }
+    
+    /// Flags for the `open(2)` flags a local application passed
+    pub fn from_posix(flags: i32) -> Self {
+        let access = flags & libc::O_ACCMODE;
+        Self {
+            read: access == libc::O_RDONLY || access == libc::O_RDWR,
+            write: access == libc::O_WRONLY || access == libc::O_RDWR,
+            append: flags & libc::O_APPEND != 0,
+            create: flags & libc::O_CREAT != 0,
+            exclusive: flags & libc::O_EXCL != 0,
+            truncate: flags & libc::O_TRUNC != 0,
+        }
+    }
+    
+    /// Whether the file may be changed through the handle
+    pub fn writes(&self) -> bool {
+        self.write || self.append || self.truncate
+    }
+}
+
+/// Which output of a command a chunk came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Result of an agent probing one of its configured paths
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathReadiness {
+    pub path: FsPath,
+    /// Configured as read-only, so writes were not probed
+    pub read_only: bool,
+    pub readable: bool,
+    pub writable: bool,
+    /// Where the path points, if it is a symlink
+    pub symlink_target: Option<FsPath>,
+    /// Space left for unprivileged writes, in bytes
+    pub available_space: Option<u64>,
+    /// What stops the path from being served as configured
+    pub problems: Vec<String>,
+    /// Suspicious settings that do not stop the path from being served
+    pub warnings: Vec<String>,
+}
+
+impl PathReadiness {
+    /// Whether clients can use the path as configured
+    pub fn is_ready(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A configured path on an agent, presented to clients under a short name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportInfo {
+    /// Unique on the agent; the last component of `path` unless taken
+    pub name: String,
+    pub path: FsPath,
+    pub read_only: bool,
+    /// Size of the filesystem holding the export, in bytes
+    pub total_space: Option<u64>,
+    /// Space left for unprivileged writes, in bytes
+    pub available_space: Option<u64>,
+}
+
+/// Capacity of the filesystem holding a path on an agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct SpaceInfo {
+    /// Size of the filesystem, in bytes
+    pub total_space: u64,
+    /// Space left for unprivileged writes, in bytes
+    pub available_space: u64,
+    /// Space taken by files, in bytes
+    pub used_space: u64,
+    /// Files the filesystem can hold, when it has a fixed number
+    pub total_files: Option<u64>,
+    /// Files unprivileged users can still create
+    pub available_files: Option<u64>,
+}
+
+/// An agent connected to a relay, as the relay reports it to clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgentInfo {
+    pub agent_id: String,
+    /// What the agent announced it supports when it authenticated
+    pub capabilities: Vec<Capability>,
+    /// Latest probe of the agent's configured paths; empty until it reports one
+    pub paths: Vec<PathReadiness>,
+    /// When the agent connected, in seconds since the Unix epoch
+    pub connected_at: u64,
+}
+
+/// Something an agent announces to every client it serves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AgentEvent {
+    /// The agent will stop serving for maintenance
+    Maintenance {
+        message: String,
+        /// When the maintenance starts, if it is not starting now
+        starts_at: Option<DateTime<Utc>>,
+    },
+    /// The agent is shutting down
+    ShuttingDown,
+}
+
+/// A request an agent handled, as recorded in its audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When the request arrived
+    pub timestamp: DateTime<Utc>,
+    pub request_id: Option<RequestId>,
+    /// Client the relay named for the request
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// User a shared mount made the request for
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Request type, such as `WriteFile`
+    pub operation: String,
+    #[serde(default)]
+    pub paths: Vec<FsPath>,
+    /// File data read or written
+    pub bytes: u64,
+    pub success: bool,
+    /// Why the request failed, when the agent gave a code
+    #[serde(default)]
+    pub error: Option<ErrorCode>,
+    /// Time taken to answer, in microseconds
+    pub latency_us: u64,
+}
+
+/// Planned work on the relay or one of its agents, set through the relay's
+/// admin API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Agent under maintenance, or `None` for the relay itself
+    #[serde(default)]
+    pub agent_id: Option<NodeId>,
+    /// Shown to users while the window is announced
+    pub message: String,
+    #[serde(default = "Utc::now")]
+    pub starts_at: DateTime<Utc>,
+    /// Open-ended until cleared if not set
+    #[serde(default)]
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+impl MaintenanceWindow {
+    /// Whether the work is underway at `now`
+    pub fn is_active_at(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at <= now && !self.has_ended_at(now)
+    }
+    
+    /// Whether the window is over at `now`
+    pub fn has_ended_at(&self, now: DateTime<Utc>) -> bool {
+        self.ends_at.is_some_and(|ends_at| ends_at <= now)
+    }
+}
+
+/// Request on an agent's local socket to open a file for reading
+///
+/// Sent as one line of JSON by clients on the agent's host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalOpenRequest {
+    pub path: FsPath,
+}
+
+/// Answer to a `LocalOpenRequest`; a successful one arrives together with
+/// an `O_RDONLY` file descriptor passed with `SCM_RIGHTS`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalOpenResponse {
+    pub error: Option<(ErrorCode, String)>,
+}
+
 /// Connection information for relay server
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RelayInfo {
@@ -58,6 +555,25 @@ pub struct RelayInfo {
     pub capabilities: Vec<String>,
     pub max_message_size: u64,
     pub heartbeat_interval: u64,
+    /// Ed25519 key the relay signs session tokens with, for verifying them
+    /// offline with a `token::TokenVerifier`
+    #[serde(default)]
+    pub token_key: Option<Vec<u8>>,
+}
+
+/// A relay clients may connect to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayEndpoint {
+    pub id: String,
+    /// WebSocket URL of the relay
+    pub url: String,
+    pub region: Option<String>,
+}
+
+/// Relays of a multi-region deployment, served by each of them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RelayDirectory {
+    pub relays: Vec<RelayEndpoint>,
 }
 
 /// Main message types for communication between all components
@@ -70,15 +586,27 @@ pub enum Message {
         node_id: NodeId,
         node_type: NodeType,
         public_key: Vec<u8>,
-        capabilities: Vec<String>,
+        capabilities: Vec<Capability>,
     },
     
     /// Authentication response from relay
+    ///
+    /// A successful response carries a short-lived resumption ticket that
+    /// lets the node skip authentication when it reconnects.
     AuthResponse {
         success: bool,
         session_token: Option<SessionToken>,
         relay_info: Option<RelayInfo>,
         error: Option<String>,
+        #[serde(default)]
+        resumption_ticket: Option<Vec<u8>>,
+    },
+    
+    /// Resume a session with a ticket from an earlier `AuthResponse` instead
+    /// of authenticating again; answered with an `AuthResponse`
+    ResumeSession {
+        node_id: NodeId,
+        ticket: Vec<u8>,
     },
     
     /// Request to establish secure channel between client and agent
@@ -130,10 +658,61 @@ pub enum Message {
         error: Option<String>,
     },
     
-    /// Create a new file
+    /// Read `length` bytes from `offset`, or the rest of the file, as a
+    /// stream of `ReadFileChunk` messages of at most `chunk_size` bytes
+    ///
+    /// The agent only sends a few chunks ahead of the last one the reader
+    /// acknowledged with `ReadFileAck`, so neither end nor the relay holds
+    /// more than that of a file however large it is.
+    ReadFileStream {
+        request_id: RequestId,
+        path: FsPath,
+        offset: u64,
+        length: Option<u64>,
+        chunk_size: u32,
+    },
+    
+    /// Part of a file streamed for `ReadFileStream`, at `offset` in the
+    /// file; `last` marks the end of the stream and an error always ends it
+    ReadFileChunk {
+        request_id: RequestId,
+        sequence: u32,
+        offset: u64,
+        data: Vec<u8>,
+        last: bool,
+        error: Option<String>,
+    },
+    
+    /// The reader of `ReadFileStream` `stream_id` took chunk `sequence`,
+    /// making room for the agent to send another; not answered
+    ReadFileAck {
+        stream_id: RequestId,
+        sequence: u32,
+    },
+    
+    /// One chunk of a file uploaded in order, answered by a
+    /// `WriteFileResponse`
+    ///
+    /// The first chunk (`sequence` 0) creates the file, or cuts it off at
+    /// `offset`, so an upload replaces what was there. The `last` chunk is
+    /// synced to disk if `sync` is set. Chunks in between may be sent
+    /// without waiting for each other's responses.
+    WriteFileChunk {
+        request_id: RequestId,
+        path: FsPath,
+        sequence: u32,
+        offset: u64,
+        data: Vec<u8>,
+        last: bool,
+        sync: bool,
+    },
+    
+    /// Create an empty file; an existing file is left as it is unless
+    /// `exclusive`, which refuses it
     CreateFile {
         request_id: RequestId,
         path: FsPath,
+        /// Permission bits of a created file, applied as given
         mode: u32,
         exclusive: bool,
     },
@@ -173,6 +752,166 @@ pub enum Message {
         error: Option<String>,
     },
     
+    /// Compute a digest of a file, or of `length` bytes from `offset`
+    ComputeChecksum {
+        request_id: RequestId,
+        path: FsPath,
+        algorithm: ChecksumAlgorithm,
+        offset: u64,
+        /// Bytes to hash; up to the end of the file if unset
+        length: Option<u64>,
+    },
+    
+    /// Response to a checksum request
+    ChecksumResponse {
+        request_id: RequestId,
+        success: bool,
+        digest: Option<Vec<u8>>,
+        /// Bytes hashed, fewer than asked for if the file ends first
+        length: u64,
+        error: Option<String>,
+    },
+    
+    /// Checksums of each block of a file, for sending only what changed
+    /// with `WriteDelta`
+    GetFileSignature {
+        request_id: RequestId,
+        path: FsPath,
+        block_size: u32,
+    },
+    
+    /// Response to a signature request
+    FileSignatureResponse {
+        request_id: RequestId,
+        success: bool,
+        signature: Option<FileSignature>,
+        error: Option<String>,
+    },
+    
+    /// Replace a file with one rebuilt from blocks of its current content
+    /// and literal data, answered by a `WriteFileResponse` with the length
+    /// of the new file
+    ///
+    /// The file is left alone unless the rebuilt one has the BLAKE3 digest
+    /// `checksum`, e.g. because it changed since its signature was taken.
+    WriteDelta {
+        request_id: RequestId,
+        path: FsPath,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+        checksum: Vec<u8>,
+        sync: bool,
+    },
+    
+    /// Take an advisory lock without waiting for it, replacing the owner's
+    /// locks on the same bytes
+    LockFile {
+        request_id: RequestId,
+        path: FsPath,
+        lock: FileLock,
+    },
+    
+    /// Response to a lock request; a lock held by another owner is returned
+    /// in `conflict` instead of the lock being taken
+    LockFileResponse {
+        request_id: RequestId,
+        success: bool,
+        conflict: Option<FileLock>,
+        error: Option<String>,
+    },
+    
+    /// Release an owner's locks on a byte range; `length` 0 releases up to
+    /// the end of the file
+    UnlockFile {
+        request_id: RequestId,
+        path: FsPath,
+        owner: LockOwner,
+        start: u64,
+        length: u64,
+    },
+    
+    /// Response to an unlock request
+    UnlockFileResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Check whether a lock could be taken, as with `F_GETLK`
+    TestLock {
+        request_id: RequestId,
+        path: FsPath,
+        lock: FileLock,
+    },
+    
+    /// Response to a lock test, with a lock that would be in the way
+    TestLockResponse {
+        request_id: RequestId,
+        success: bool,
+        conflict: Option<FileLock>,
+        error: Option<String>,
+    },
+    
+    /// Sent by the relay when a client leaves, so agents drop its locks and
+    /// close the files it left open
+    ReleaseLocks {
+        session: String,
+    },
+    
+    /// Open a file and keep it open until `CloseFile`, under a handle that
+    /// is the request's id; reads and writes through the handle reach the
+    /// file even after it is renamed or removed
+    OpenFile {
+        request_id: RequestId,
+        path: FsPath,
+        flags: OpenFlags,
+        /// Permission bits of a created file, applied as given
+        mode: u32,
+        /// Client the handle belongs to, filled in by the relay; clients
+        /// leave it empty
+        #[serde(default)]
+        session: String,
+    },
+    
+    /// Response to an open request, with the metadata of the opened file
+    OpenFileResponse {
+        request_id: RequestId,
+        success: bool,
+        metadata: Option<FileMetadata>,
+        error: Option<String>,
+    },
+    
+    /// Read through an open handle, answered with `ReadFileResponse`
+    ReadHandle {
+        request_id: RequestId,
+        handle: RequestId,
+        offset: u64,
+        length: u32,
+    },
+    
+    /// Write through an open handle, answered with `WriteFileResponse`;
+    /// `offset` is ignored for handles opened to append
+    WriteHandle {
+        request_id: RequestId,
+        handle: RequestId,
+        offset: u64,
+        data: Vec<u8>,
+        sync: bool,
+    },
+    
+    /// Close an open handle
+    CloseFile {
+        request_id: RequestId,
+        handle: RequestId,
+    },
+    
+    /// Response to a close request
+    CloseFileResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
     // ===== Directory Operations =====
     
     /// List directory contents
@@ -189,10 +928,94 @@ pub enum Message {
         error: Option<String>,
     },
     
+    /// List directory contents in pages of at most `page_size` entries,
+    /// answered by a stream of `DirectoryPage` messages
+    ///
+    /// Entries come sorted by the bytes of their names. With `after` set the
+    /// listing resumes behind that name, which need not exist any more, so a
+    /// reader can continue where an earlier listing stopped: entries present
+    /// throughout are listed exactly once, and entries created or removed in
+    /// between are listed if they sort after `after` and skipped otherwise.
+    ///
+    /// With `max_entries` set the listing ends after that many entries, and
+    /// if entries remain its last page carries the `cursor` to pass as
+    /// `after` to continue.
+    ListDirectoryPaged {
+        request_id: RequestId,
+        path: FsPath,
+        page_size: u32,
+        #[serde(default)]
+        after: Option<String>,
+        #[serde(default)]
+        max_entries: Option<u32>,
+    },
+    
+    /// List everything below `path`, answered by a stream of `DirectoryPage`
+    /// messages whose entry names are paths relative to `path`
+    ///
+    /// `max_depth` of 1 lists only the entries of `path` itself; without it
+    /// the whole tree is walked, down to the agent's path depth limit.
+    /// Symbolic links to directories are descended into only with
+    /// `follow_symlinks`, and never into a directory the walk is already in.
+    WalkDirectory {
+        request_id: RequestId,
+        path: FsPath,
+        max_depth: Option<u32>,
+        follow_symlinks: bool,
+    },
+    
+    /// Search the tree below `root` for files whose name matches
+    /// `name_glob` and, with `content_regex`, for the lines of those files
+    /// matching it; answered by a stream of `SearchResults` messages
+    ///
+    /// A glob without a `/` is matched against file names, and one with a
+    /// `/` against paths relative to `root`. Without `name_glob` every file
+    /// is looked at, and without `content_regex` each file found is one
+    /// match. The search stops at `max_results` matches, or the agent's own
+    /// limit if that is lower.
+    SearchFiles {
+        request_id: RequestId,
+        root: FsPath,
+        name_glob: Option<String>,
+        content_regex: Option<String>,
+        max_results: Option<u32>,
+    },
+    
+    /// Matches of a search, in the order they were found; `last` marks the
+    /// end of the stream and an error always ends it
+    ///
+    /// `truncated` is only set on the last page of a search stopped at its
+    /// result limit.
+    SearchResults {
+        request_id: RequestId,
+        sequence: u32,
+        matches: Vec<SearchMatch>,
+        last: bool,
+        truncated: bool,
+        error: Option<String>,
+    },
+    
+    /// One page of a paged directory listing; `last` marks the end of the
+    /// stream and an error always ends it
+    ///
+    /// `cursor` is only set on the last page of a listing stopped at its
+    /// `max_entries` before the end of the directory.
+    DirectoryPage {
+        request_id: RequestId,
+        sequence: u32,
+        entries: Vec<DirEntry>,
+        last: bool,
+        error: Option<String>,
+        #[serde(default)]
+        cursor: Option<String>,
+    },
+    
     /// Create a directory
     CreateDirectory {
         request_id: RequestId,
         path: FsPath,
+        /// Permission bits of the directory if it is created, applied as
+        /// given; missing parents get the agent's defaults
         mode: u32,
     },
     
@@ -218,96 +1041,460 @@ pub enum Message {
         error: Option<String>,
     },
     
-    // ===== Metadata Operations =====
-    
-    /// Get file/directory metadata
-    GetMetadata {
+    // ===== Metadata Operations =====
+    
+    /// Get file/directory metadata
+    GetMetadata {
+        request_id: RequestId,
+        path: FsPath,
+        follow_symlinks: bool,
+    },
+    
+    /// Response to metadata request
+    GetMetadataResponse {
+        request_id: RequestId,
+        success: bool,
+        metadata: Option<FileMetadata>,
+        error: Option<String>,
+    },
+    
+    /// Set file/directory metadata, leaving unspecified fields untouched
+    SetMetadata {
+        request_id: RequestId,
+        path: FsPath,
+        update: MetadataUpdate,
+    },
+    
+    /// Response to set metadata
+    SetMetadataResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Apply `update` to `path` and everything below it, as `chmod -R` or
+    /// `chown -R` would; answered by a stream of `SetMetadataTreeProgress`
+    /// messages
+    ///
+    /// Symbolic links below `path` are neither followed nor changed. Entries
+    /// the caller may not change, or whose change fails, are skipped and
+    /// counted; a directory the caller may not change is not entered.
+    SetMetadataTree {
+        request_id: RequestId,
+        path: FsPath,
+        update: MetadataUpdate,
+    },
+    
+    /// Progress of a `SetMetadataTree`, sent about once a second; `last`
+    /// marks the end of the stream and an error always ends it
+    SetMetadataTreeProgress {
+        request_id: RequestId,
+        progress: TreeProgress,
+        last: bool,
+        error: Option<String>,
+    },
+    
+    /// Stop the `SetMetadataTree` request with this id; it ends with a last
+    /// progress message marked `cancelled`
+    CancelSetMetadataTree {
+        request_id: RequestId,
+    },
+    
+    /// Read an extended attribute; symlinks are not followed
+    GetXattr {
+        request_id: RequestId,
+        path: FsPath,
+        name: String,
+    },
+    
+    /// Response to get xattr; `value` is `None` if the attribute does not exist
+    GetXattrResponse {
+        request_id: RequestId,
+        success: bool,
+        value: Option<Vec<u8>>,
+        error: Option<String>,
+    },
+    
+    /// Set an extended attribute; refused with `PathAlreadyExists` or
+    /// `FileNotFound` when `mode` does not allow it
+    SetXattr {
+        request_id: RequestId,
+        path: FsPath,
+        name: String,
+        value: Vec<u8>,
+        mode: XattrSetMode,
+    },
+    
+    /// Response to set xattr
+    SetXattrResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// List the names of a path's extended attributes
+    ListXattr {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Response to list xattr
+    ListXattrResponse {
+        request_id: RequestId,
+        success: bool,
+        names: Option<Vec<String>>,
+        error: Option<String>,
+    },
+    
+    /// Remove an extended attribute; refused with `FileNotFound` if it does
+    /// not exist
+    RemoveXattr {
+        request_id: RequestId,
+        path: FsPath,
+        name: String,
+    },
+    
+    /// Response to remove xattr
+    RemoveXattrResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Rename/move a file or directory
+    ///
+    /// Whatever is at `to_path` is replaced, unless `no_replace` is set, in
+    /// which case the rename fails with `PathAlreadyExists` instead.
+    Rename {
+        request_id: RequestId,
+        from_path: FsPath,
+        to_path: FsPath,
+        #[serde(default)]
+        no_replace: bool,
+    },
+    
+    /// Response to rename operation
+    RenameResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Create a symbolic link
+    CreateSymlink {
+        request_id: RequestId,
+        link_path: FsPath,
+        target_path: FsPath,
+    },
+    
+    /// Response to symlink creation
+    CreateSymlinkResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Create a hard link at `link_path` to the file at `existing_path`
+    CreateHardLink {
+        request_id: RequestId,
+        existing_path: FsPath,
+        link_path: FsPath,
+    },
+    
+    /// Response to hard link creation
+    CreateHardLinkResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// Read the target stored in a symlink, without following it
+    ReadSymlink {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Response to a symlink read
+    ReadSymlinkResponse {
+        request_id: RequestId,
+        success: bool,
+        target: Option<FsPath>,
+        error: Option<String>,
+    },
+    
+    /// Copy `source` to `destination` on the agent, so the data does not
+    /// travel to the client and back
+    ///
+    /// With `offset` 0 and no `length` the destination is replaced by a copy
+    /// of the whole source. Otherwise the range of the source from `offset`
+    /// (to its end without `length`) is written into the destination at the
+    /// same offset, creating it if missing, as `copy_file_range` would.
+    CopyFile {
+        request_id: RequestId,
+        source: FsPath,
+        destination: FsPath,
+        offset: u64,
+        length: Option<u64>,
+    },
+    
+    /// Response to a copy; `copied` is the number of bytes copied, fewer
+    /// than asked for when the source ends first
+    CopyFileResponse {
+        request_id: RequestId,
+        success: bool,
+        copied: u64,
+        metadata: Option<FileMetadata>,
+        error: Option<String>,
+    },
+    
+    // ===== System Operations =====
+    
+    /// Check if path exists
+    PathExists {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Response to path existence check
+    PathExistsResponse {
+        request_id: RequestId,
+        exists: bool,
+        error: Option<String>,
+    },
+    
+    /// Get file system space information
+    GetSpaceInfo {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Response to space info request
+    GetSpaceInfoResponse {
+        request_id: RequestId,
+        success: bool,
+        total_space: Option<u64>,
+        available_space: Option<u64>,
+        used_space: Option<u64>,
+        #[serde(default)]
+        total_files: Option<u64>,
+        #[serde(default)]
+        available_files: Option<u64>,
+        error: Option<String>,
+    },
+    
+    /// List the agent's configured paths as named exports
+    ListExports {
+        request_id: RequestId,
+        /// Agent to ask; any agent that supports exports when not set
+        #[serde(default)]
+        agent_id: Option<String>,
+    },
+    
+    /// Exports the requester may read
+    ListExportsResponse {
+        request_id: RequestId,
+        exports: Vec<ExportInfo>,
+        error: Option<String>,
+    },
+    
+    /// Query the agent's change journal for changes after a cursor
+    GetChanges {
+        request_id: RequestId,
+        since: u64,
+        limit: Option<u32>,
+    },
+    
+    /// Response to change journal query
+    GetChangesResponse {
+        request_id: RequestId,
+        success: bool,
+        changes: Option<ChangeSet>,
+        error: Option<String>,
+    },
+    
+    /// Report changes under `path` until `Unwatch`; answered by a
+    /// `WatchResponse`, then `FileChanged` and `DirectoryChanged` messages
+    /// sharing the request id as changes happen, and a final `WatchEnded`
+    Watch {
+        request_id: RequestId,
+        path: FsPath,
+        /// Also report changes in subdirectories, at any depth
+        recursive: bool,
+    },
+    
+    /// Whether a watch was started; a failed one ends the request
+    WatchResponse {
+        request_id: RequestId,
+        success: bool,
+        error: Option<String>,
+    },
+    
+    /// A file or directory under a watched path changed
+    FileChanged {
+        request_id: RequestId,
+        path: FsPath,
+        kind: ChangeKind,
+        is_dir: bool,
+    },
+    
+    /// Entries were added to, removed from or renamed in a watched directory
+    DirectoryChanged {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Stop the watch started by the `Watch` request with this id
+    Unwatch {
+        request_id: RequestId,
+    },
+    
+    /// The watch ended, after `Unwatch` or because of `error`
+    WatchEnded {
+        request_id: RequestId,
+        error: Option<String>,
+    },
+    
+    /// Read a file as it was at `as_of`; answered with `ReadFileResponse`
+    ReadFileAsOf {
+        request_id: RequestId,
+        path: FsPath,
+        offset: u64,
+        length: u32,
+        as_of: DateTime<Utc>,
+    },
+    
+    /// Read a file's content, metadata and extended attributes at once
+    ReadBackupEntry {
+        request_id: RequestId,
+        path: FsPath,
+    },
+    
+    /// Response to backup entry read
+    ReadBackupEntryResponse {
+        request_id: RequestId,
+        success: bool,
+        entry: Option<BackupEntry>,
+        error: Option<String>,
+    },
+    
+    /// Apply several mutating operations in order, all or none of them
+    Transaction {
         request_id: RequestId,
-        path: FsPath,
-        follow_symlinks: bool,
+        operations: Vec<TransactionOp>,
     },
     
-    /// Response to metadata request
-    GetMetadataResponse {
+    /// Response to a transaction; on failure every applied operation was
+    /// rolled back
+    TransactionResponse {
         request_id: RequestId,
         success: bool,
-        metadata: Option<FileMetadata>,
+        /// Index of the operation that failed
+        failed_operation: Option<u32>,
         error: Option<String>,
     },
     
-    /// Set file/directory metadata
-    SetMetadata {
+    /// Create many small files, and any missing parent directories, in one
+    /// request; each file succeeds or fails on its own
+    BatchCreateFiles {
         request_id: RequestId,
-        path: FsPath,
-        metadata: FileMetadata,
+        files: Vec<NewFile>,
+        /// Replace files that exist instead of reporting them as failures
+        overwrite: bool,
     },
     
-    /// Response to set metadata
-    SetMetadataResponse {
+    /// Response to a batch create
+    BatchCreateFilesResponse {
         request_id: RequestId,
-        success: bool,
+        /// Number of files created or replaced
+        created: u32,
+        /// Files that were not created, by position in the request
+        failures: Vec<BatchFailure>,
+        /// Why the whole batch was refused
         error: Option<String>,
     },
     
-    /// Rename/move a file or directory
-    Rename {
+    /// Move the entry most recently deleted from `path` out of the agent's
+    /// trash and back to `path`, which must not exist
+    RestoreFromTrash {
         request_id: RequestId,
-        from_path: FsPath,
-        to_path: FsPath,
+        path: FsPath,
     },
     
-    /// Response to rename operation
-    RenameResponse {
+    /// Response to a restore
+    RestoreFromTrashResponse {
         request_id: RequestId,
         success: bool,
         error: Option<String>,
     },
     
-    /// Create a symbolic link
-    CreateSymlink {
+    /// Delete for good the trashed entries deleted from `path` or below it
+    PurgeTrash {
         request_id: RequestId,
-        link_path: FsPath,
-        target_path: FsPath,
+        path: FsPath,
     },
     
-    /// Response to symlink creation
-    CreateSymlinkResponse {
+    /// Response to a purge
+    PurgeTrashResponse {
         request_id: RequestId,
         success: bool,
+        /// Trashed entries deleted
+        purged: u32,
         error: Option<String>,
     },
     
-    // ===== System Operations =====
-    
-    /// Check if path exists
-    PathExists {
+    /// Several independent requests sent as one, such as the `GetMetadata`
+    /// of every entry of a directory; see [`Message::is_batchable`]
+    ///
+    /// The agent handles them in order, each succeeding or failing on its own.
+    Batch {
         request_id: RequestId,
-        path: FsPath,
+        operations: Vec<Message>,
     },
     
-    /// Response to path existence check
-    PathExistsResponse {
+    /// Response to a batch: the response to each request, in order
+    BatchResponse {
         request_id: RequestId,
-        exists: bool,
-        error: Option<String>,
+        responses: Vec<Message>,
     },
     
-    /// Get file system space information
-    GetSpaceInfo {
+    /// Run a command the agent whitelisted under `name`; answered with
+    /// `ExtendedOutput` messages, the last of which has `last` set
+    ExtendedOperation {
         request_id: RequestId,
-        path: FsPath,
+        name: String,
+        /// Appended to the command's fixed arguments, if it allows that
+        arguments: Vec<String>,
+        /// Directory to run in, for commands without a configured one
+        working_dir: Option<FsPath>,
     },
     
-    /// Response to space info request
-    GetSpaceInfoResponse {
+    /// A chunk of a command's output, or its outcome when `last` is set
+    ExtendedOutput {
         request_id: RequestId,
-        success: bool,
-        total_space: Option<u64>,
-        available_space: Option<u64>,
-        used_space: Option<u64>,
+        sequence: u32,
+        stream: OutputStream,
+        data: Vec<u8>,
+        last: bool,
+        /// Exit code, on the last message of a command that exited
+        exit_code: Option<i32>,
+        /// Why the command could not be run or did not finish
         error: Option<String>,
     },
     
+    /// File system request made on behalf of a local user of a shared mount
+    AsUser {
+        identity: CallerIdentity,
+        request: Box<Message>,
+    },
+    
+    /// Client request forwarded by the relay, naming the client that sent
+    /// it so the agent can apply that client's access rules; only the relay
+    /// sends it, and only to agents announcing `ClientRules`
+    FromClient {
+        client_id: String,
+        request: Box<Message>,
+    },
+    
     // ===== Connection Management =====
     
     /// Heartbeat/keepalive message
@@ -326,15 +1513,257 @@ pub enum Message {
         reason: String,
     },
     
+    /// Another message, encoded with bincode and compressed
+    ///
+    /// Only sent in binary frames, to a node that announced the
+    /// `Compression` capability; see [`crate::compression`].
+    Compressed {
+        algorithm: CompressionAlgorithm,
+        data: Vec<u8>,
+    },
+    
+    /// Sent by the relay when it promotes a mirror agent because its primary
+    /// disappeared, or demotes it when the primary returns
+    MirrorStatus {
+        primary: String,
+        mirror: String,
+        promoted: bool,
+        /// Whether the promoted mirror accepts writes
+        writes_allowed: bool,
+        timestamp: DateTime<Utc>,
+    },
+    
+    /// Sent by an agent after it authenticates and whenever it probes its
+    /// configured paths again
+    AgentHealth {
+        paths: Vec<PathReadiness>,
+        timestamp: DateTime<Utc>,
+    },
+    
+    /// Audit record of a request an agent handled, sent to the relay when
+    /// the agent forwards its access log
+    Audit {
+        record: AuditRecord,
+    },
+    
+    /// Event an agent publishes once; the relay delivers it to every client
+    /// that sent the agent requests, naming the agent it came from
+    Broadcast {
+        agent_id: NodeId,
+        event: AgentEvent,
+        timestamp: DateTime<Utc>,
+    },
+    
+    /// Ask a relay for the relays clients may choose from; answered before
+    /// authentication
+    GetRelayDirectory,
+    
+    /// Relays known to the relay that answered
+    RelayDirectoryResponse {
+        directory: RelayDirectory,
+    },
+    
+    /// Ask the relay which agents are connected; answered by the relay
+    ListAgents {
+        request_id: RequestId,
+    },
+    
+    /// Agents connected to the relay
+    ListAgentsResponse {
+        request_id: RequestId,
+        agents: Vec<AgentInfo>,
+    },
+    
+    /// Ask the relay for the maintenance windows that concern the sender
+    GetMaintenance {
+        request_id: RequestId,
+    },
+    
+    /// Maintenance windows that concern a client: the relay's own and those
+    /// of the agents it has used
+    ///
+    /// Answers `GetMaintenance`, and is also sent unprompted, without a
+    /// request id, whenever the windows change.
+    MaintenanceStatus {
+        request_id: Option<RequestId>,
+        windows: Vec<MaintenanceWindow>,
+    },
+    
     /// Generic error message
+    ///
+    /// `errno` is the OS error number behind a failed filesystem call on the
+    /// agent, for failures `code` only names in general terms.
     Error {
         request_id: Option<RequestId>,
         code: ErrorCode,
         message: String,
         details: Option<HashMap<String, String>>,
+        #[serde(default)]
+        errno: Option<i32>,
     },
 }
 
+/// A feature a node announces in its `AuthRequest`
+///
+/// On the wire a capability is its snake_case name, the same plain string
+/// older nodes send. Names this version does not know are kept as `Other`,
+/// so newer peers can announce features without breaking older ones.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Capability {
+    /// Serves filesystem requests; only agents may announce it
+    Filesystem,
+    Read,
+    Write,
+    /// Answers some requests with several messages, e.g. paged listings
+    Streaming,
+    /// Accepts `Compressed` messages, in binary frames
+    Compression,
+    /// Extended attributes, in backup entries and the xattr requests
+    Xattr,
+    /// Change notifications through `Watch`
+    Watch,
+    /// Advisory file locks
+    Locks,
+    /// All-or-nothing `Transaction` requests
+    Transactions,
+    /// Whitelisted commands through `ExtendedOperation`
+    RemoteExec,
+    /// Answers `ListExports`
+    Exports,
+    /// `BatchCreateFiles` requests
+    BatchCreate,
+    /// `Batch` requests
+    Batch,
+    /// Answers `ComputeChecksum`
+    Checksum,
+    /// Answers `CreateFile`, and applies the mode of created files and
+    /// directories as given instead of masking it with its own umask
+    CreateMode,
+    /// Answers `WalkDirectory`
+    Walk,
+    /// Sorts paged listings by name, resumes them behind `after` and stops
+    /// them at `max_entries`
+    ListingCursors,
+    /// Keeps files open between `OpenFile` and `CloseFile`
+    OpenFiles,
+    /// Answers `GetSpaceInfo`
+    SpaceInfo,
+    /// Applies access rules per client to requests wrapped in `FromClient`
+    ClientRules,
+    /// Moves deleted entries to a trash, and answers `RestoreFromTrash` and
+    /// `PurgeTrash`
+    Trash,
+    /// Answers `SearchFiles`
+    Search,
+    /// Answers `ReadFileStream` and `WriteFileChunk`
+    ChunkedTransfer,
+    /// Answers `CreateHardLink`
+    HardLinks,
+    /// Answers `SetMetadataTree`
+    MetadataTree,
+    /// Answers `GetFileSignature` and `WriteDelta`
+    DeltaTransfer,
+    /// Answers `ReadSymlink`
+    ReadSymlink,
+    /// Answers `CopyFile`
+    CopyFile,
+    /// Honours `no_replace` on `Rename`
+    RenameNoReplace,
+    /// A capability this version does not know
+    Other(String),
+}
+
+impl Capability {
+    /// The name sent on the wire
+    pub fn as_str(&self) -> &str {
+        match self {
+            Capability::Filesystem => "filesystem",
+            Capability::Read => "read",
+            Capability::Write => "write",
+            Capability::Streaming => "streaming",
+            Capability::Compression => "compression",
+            Capability::Xattr => "xattr",
+            Capability::Watch => "watch",
+            Capability::Locks => "locks",
+            Capability::Transactions => "transactions",
+            Capability::RemoteExec => "remote_exec",
+            Capability::Exports => "exports",
+            Capability::BatchCreate => "batch_create",
+            Capability::Batch => "batch",
+            Capability::Checksum => "checksum",
+            Capability::CreateMode => "create_mode",
+            Capability::Walk => "walk",
+            Capability::ListingCursors => "listing_cursors",
+            Capability::OpenFiles => "open_files",
+            Capability::SpaceInfo => "space_info",
+            Capability::ClientRules => "client_rules",
+            Capability::Trash => "trash",
+            Capability::Search => "search",
+            Capability::ChunkedTransfer => "chunked_transfer",
+            Capability::HardLinks => "hard_links",
+            Capability::MetadataTree => "metadata_tree",
+            Capability::DeltaTransfer => "delta_transfer",
+            Capability::ReadSymlink => "read_symlink",
+            Capability::CopyFile => "copy_file",
+            Capability::RenameNoReplace => "rename_no_replace",
+            Capability::Other(name) => name,
+        }
+    }
+}
+
+impl From<String> for Capability {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "filesystem" => Capability::Filesystem,
+            "read" => Capability::Read,
+            "write" => Capability::Write,
+            "streaming" => Capability::Streaming,
+            "compression" => Capability::Compression,
+            "xattr" => Capability::Xattr,
+            "watch" => Capability::Watch,
+            "locks" => Capability::Locks,
+            "transactions" => Capability::Transactions,
+            "remote_exec" => Capability::RemoteExec,
+            "exports" => Capability::Exports,
+            "batch_create" => Capability::BatchCreate,
+            "batch" => Capability::Batch,
+            "checksum" => Capability::Checksum,
+            "create_mode" => Capability::CreateMode,
+            "walk" => Capability::Walk,
+            "listing_cursors" => Capability::ListingCursors,
+            "open_files" => Capability::OpenFiles,
+            "space_info" => Capability::SpaceInfo,
+            "client_rules" => Capability::ClientRules,
+            "trash" => Capability::Trash,
+            "search" => Capability::Search,
+            "chunked_transfer" => Capability::ChunkedTransfer,
+            "hard_links" => Capability::HardLinks,
+            "metadata_tree" => Capability::MetadataTree,
+            "delta_transfer" => Capability::DeltaTransfer,
+            "read_symlink" => Capability::ReadSymlink,
+            "copy_file" => Capability::CopyFile,
+            "rename_no_replace" => Capability::RenameNoReplace,
+            _ => Capability::Other(name),
+        }
+    }
+}
+
+impl From<Capability> for String {
+    fn from(capability: Capability) -> Self {
+        match capability {
+            Capability::Other(name) => name,
+            known => known.as_str().to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 /// Type of node in the network
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NodeType {
@@ -363,6 +1792,13 @@ pub enum ErrorCode {
     InvalidPath,
     DiskFull,
     ReadOnlyFileSystem,
+    /// Content is in cold storage; retry once it has been recalled
+    FileOffline,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    NameTooLong,
+    QuotaExceeded,
     
     // Network/Communication errors
     NetworkError,
@@ -384,14 +1820,39 @@ impl Message {
             Message::ReadFileResponse { request_id, .. } => Some(*request_id),
             Message::WriteFile { request_id, .. } => Some(*request_id),
             Message::WriteFileResponse { request_id, .. } => Some(*request_id),
+            Message::ReadFileStream { request_id, .. } => Some(*request_id),
+            Message::ReadFileChunk { request_id, .. } => Some(*request_id),
+            Message::WriteFileChunk { request_id, .. } => Some(*request_id),
             Message::CreateFile { request_id, .. } => Some(*request_id),
             Message::CreateFileResponse { request_id, .. } => Some(*request_id),
             Message::DeleteFile { request_id, .. } => Some(*request_id),
             Message::DeleteFileResponse { request_id, .. } => Some(*request_id),
             Message::TruncateFile { request_id, .. } => Some(*request_id),
             Message::TruncateFileResponse { request_id, .. } => Some(*request_id),
+            Message::ComputeChecksum { request_id, .. } => Some(*request_id),
+            Message::ChecksumResponse { request_id, .. } => Some(*request_id),
+            Message::GetFileSignature { request_id, .. } => Some(*request_id),
+            Message::FileSignatureResponse { request_id, .. } => Some(*request_id),
+            Message::WriteDelta { request_id, .. } => Some(*request_id),
+            Message::LockFile { request_id, .. } => Some(*request_id),
+            Message::LockFileResponse { request_id, .. } => Some(*request_id),
+            Message::UnlockFile { request_id, .. } => Some(*request_id),
+            Message::UnlockFileResponse { request_id, .. } => Some(*request_id),
+            Message::TestLock { request_id, .. } => Some(*request_id),
+            Message::TestLockResponse { request_id, .. } => Some(*request_id),
+            Message::OpenFile { request_id, .. } => Some(*request_id),
+            Message::OpenFileResponse { request_id, .. } => Some(*request_id),
+            Message::ReadHandle { request_id, .. } => Some(*request_id),
+            Message::WriteHandle { request_id, .. } => Some(*request_id),
+            Message::CloseFile { request_id, .. } => Some(*request_id),
+            Message::CloseFileResponse { request_id, .. } => Some(*request_id),
             Message::ListDirectory { request_id, .. } => Some(*request_id),
             Message::ListDirectoryResponse { request_id, .. } => Some(*request_id),
+            Message::ListDirectoryPaged { request_id, .. } => Some(*request_id),
+            Message::WalkDirectory { request_id, .. } => Some(*request_id),
+            Message::SearchFiles { request_id, .. } => Some(*request_id),
+            Message::SearchResults { request_id, .. } => Some(*request_id),
+            Message::DirectoryPage { request_id, .. } => Some(*request_id),
             Message::CreateDirectory { request_id, .. } => Some(*request_id),
             Message::CreateDirectoryResponse { request_id, .. } => Some(*request_id),
             Message::RemoveDirectory { request_id, .. } => Some(*request_id),
@@ -400,14 +1861,62 @@ impl Message {
             Message::GetMetadataResponse { request_id, .. } => Some(*request_id),
             Message::SetMetadata { request_id, .. } => Some(*request_id),
             Message::SetMetadataResponse { request_id, .. } => Some(*request_id),
+            Message::SetMetadataTree { request_id, .. } => Some(*request_id),
+            Message::SetMetadataTreeProgress { request_id, .. } => Some(*request_id),
+            Message::CancelSetMetadataTree { request_id } => Some(*request_id),
+            Message::GetXattr { request_id, .. } => Some(*request_id),
+            Message::GetXattrResponse { request_id, .. } => Some(*request_id),
+            Message::SetXattr { request_id, .. } => Some(*request_id),
+            Message::SetXattrResponse { request_id, .. } => Some(*request_id),
+            Message::ListXattr { request_id, .. } => Some(*request_id),
+            Message::ListXattrResponse { request_id, .. } => Some(*request_id),
+            Message::RemoveXattr { request_id, .. } => Some(*request_id),
+            Message::RemoveXattrResponse { request_id, .. } => Some(*request_id),
             Message::Rename { request_id, .. } => Some(*request_id),
             Message::RenameResponse { request_id, .. } => Some(*request_id),
             Message::CreateSymlink { request_id, .. } => Some(*request_id),
             Message::CreateSymlinkResponse { request_id, .. } => Some(*request_id),
+            Message::CreateHardLink { request_id, .. } => Some(*request_id),
+            Message::CreateHardLinkResponse { request_id, .. } => Some(*request_id),
+            Message::ReadSymlink { request_id, .. } => Some(*request_id),
+            Message::ReadSymlinkResponse { request_id, .. } => Some(*request_id),
+            Message::CopyFile { request_id, .. } => Some(*request_id),
+            Message::CopyFileResponse { request_id, .. } => Some(*request_id),
             Message::PathExists { request_id, .. } => Some(*request_id),
             Message::PathExistsResponse { request_id, .. } => Some(*request_id),
             Message::GetSpaceInfo { request_id, .. } => Some(*request_id),
             Message::GetSpaceInfoResponse { request_id, .. } => Some(*request_id),
+            Message::ListExports { request_id, .. } => Some(*request_id),
+            Message::ListExportsResponse { request_id, .. } => Some(*request_id),
+            Message::GetChanges { request_id, .. } => Some(*request_id),
+            Message::GetChangesResponse { request_id, .. } => Some(*request_id),
+            Message::Watch { request_id, .. } => Some(*request_id),
+            Message::WatchResponse { request_id, .. } => Some(*request_id),
+            Message::FileChanged { request_id, .. } => Some(*request_id),
+            Message::DirectoryChanged { request_id, .. } => Some(*request_id),
+            Message::Unwatch { request_id } => Some(*request_id),
+            Message::WatchEnded { request_id, .. } => Some(*request_id),
+            Message::ReadFileAsOf { request_id, .. } => Some(*request_id),
+            Message::ReadBackupEntry { request_id, .. } => Some(*request_id),
+            Message::ReadBackupEntryResponse { request_id, .. } => Some(*request_id),
+            Message::Transaction { request_id, .. } => Some(*request_id),
+            Message::TransactionResponse { request_id, .. } => Some(*request_id),
+            Message::BatchCreateFiles { request_id, .. } => Some(*request_id),
+            Message::BatchCreateFilesResponse { request_id, .. } => Some(*request_id),
+            Message::RestoreFromTrash { request_id, .. } => Some(*request_id),
+            Message::RestoreFromTrashResponse { request_id, .. } => Some(*request_id),
+            Message::PurgeTrash { request_id, .. } => Some(*request_id),
+            Message::PurgeTrashResponse { request_id, .. } => Some(*request_id),
+            Message::Batch { request_id, .. } => Some(*request_id),
+            Message::BatchResponse { request_id, .. } => Some(*request_id),
+            Message::ExtendedOperation { request_id, .. } => Some(*request_id),
+            Message::ExtendedOutput { request_id, .. } => Some(*request_id),
+            Message::AsUser { request, .. } => request.request_id(),
+            Message::FromClient { request, .. } => request.request_id(),
+            Message::ListAgents { request_id } => Some(*request_id),
+            Message::GetMaintenance { request_id } => Some(*request_id),
+            Message::MaintenanceStatus { request_id, .. } => *request_id,
+            Message::ListAgentsResponse { request_id, .. } => Some(*request_id),
             Message::Error { request_id, .. } => *request_id,
             _ => None,
         }
@@ -420,42 +1929,380 @@ impl Message {
             Message::ChannelEstablished { .. } |
             Message::ReadFileResponse { .. } |
             Message::WriteFileResponse { .. } |
+            Message::ReadFileChunk { .. } |
             Message::CreateFileResponse { .. } |
             Message::DeleteFileResponse { .. } |
             Message::TruncateFileResponse { .. } |
+            Message::ChecksumResponse { .. } |
+            Message::FileSignatureResponse { .. } |
+            Message::LockFileResponse { .. } |
+            Message::UnlockFileResponse { .. } |
+            Message::TestLockResponse { .. } |
+            Message::OpenFileResponse { .. } |
+            Message::CloseFileResponse { .. } |
             Message::ListDirectoryResponse { .. } |
+            Message::DirectoryPage { .. } |
             Message::CreateDirectoryResponse { .. } |
             Message::RemoveDirectoryResponse { .. } |
             Message::GetMetadataResponse { .. } |
             Message::SetMetadataResponse { .. } |
+            Message::SetMetadataTreeProgress { .. } |
+            Message::GetXattrResponse { .. } |
+            Message::SetXattrResponse { .. } |
+            Message::ListXattrResponse { .. } |
+            Message::RemoveXattrResponse { .. } |
             Message::RenameResponse { .. } |
             Message::CreateSymlinkResponse { .. } |
+            Message::CreateHardLinkResponse { .. } |
+            Message::ReadSymlinkResponse { .. } |
+            Message::CopyFileResponse { .. } |
             Message::PathExistsResponse { .. } |
             Message::GetSpaceInfoResponse { .. } |
+            Message::ListExportsResponse { .. } |
+            Message::GetChangesResponse { .. } |
+            Message::WatchResponse { .. } |
+            Message::FileChanged { .. } |
+            Message::DirectoryChanged { .. } |
+            Message::WatchEnded { .. } |
+            Message::ReadBackupEntryResponse { .. } |
+            Message::TransactionResponse { .. } |
+            Message::BatchCreateFilesResponse { .. } |
+            Message::RestoreFromTrashResponse { .. } |
+            Message::PurgeTrashResponse { .. } |
+            Message::BatchResponse { .. } |
+            Message::ExtendedOutput { .. } |
+            Message::SearchResults { .. } |
             Message::Pong { .. } |
+            Message::RelayDirectoryResponse { .. } |
+            Message::ListAgentsResponse { .. } |
+            Message::MaintenanceStatus { .. } |
             Message::Error { .. }
         )
     }
     
+    /// Check if this is the last message answering its request
+    ///
+    /// Most requests get a single response; streamed responses such as
+    /// `DirectoryPage` span several messages sharing one request id.
+    pub fn ends_request(&self) -> bool {
+        match self {
+            Message::DirectoryPage { last, .. }
+            | Message::ExtendedOutput { last, .. }
+            | Message::SearchResults { last, .. }
+            | Message::SetMetadataTreeProgress { last, .. }
+            | Message::ReadFileChunk { last, .. } => *last,
+            Message::WatchResponse { success, .. } => !success,
+            Message::FileChanged { .. } | Message::DirectoryChanged { .. } => false,
+            message => message.is_response(),
+        }
+    }
+    
+    /// Capability an agent must have announced to serve this request
+    ///
+    /// Agents from before a feature existed do not announce it, so requests
+    /// needing one are only sent to agents that do.
+    pub fn required_capability(&self) -> Option<Capability> {
+        match self {
+            Message::ListDirectoryPaged { after: Some(_), .. }
+            | Message::ListDirectoryPaged { max_entries: Some(_), .. } => Some(Capability::ListingCursors),
+            Message::ListDirectoryPaged { .. } => Some(Capability::Streaming),
+            Message::WalkDirectory { .. } => Some(Capability::Walk),
+            Message::SearchFiles { .. } => Some(Capability::Search),
+            Message::GetXattr { .. }
+            | Message::SetXattr { .. }
+            | Message::ListXattr { .. }
+            | Message::RemoveXattr { .. } => Some(Capability::Xattr),
+            Message::ReadFileStream { .. } | Message::WriteFileChunk { .. } => Some(Capability::ChunkedTransfer),
+            Message::Transaction { .. } => Some(Capability::Transactions),
+            Message::BatchCreateFiles { .. } => Some(Capability::BatchCreate),
+            Message::Batch { .. } => Some(Capability::Batch),
+            Message::ComputeChecksum { .. } => Some(Capability::Checksum),
+            Message::CreateFile { .. } => Some(Capability::CreateMode),
+            Message::LockFile { .. } | Message::UnlockFile { .. } | Message::TestLock { .. } => Some(Capability::Locks),
+            Message::OpenFile { .. }
+            | Message::ReadHandle { .. }
+            | Message::WriteHandle { .. }
+            | Message::CloseFile { .. } => Some(Capability::OpenFiles),
+            Message::ExtendedOperation { .. } => Some(Capability::RemoteExec),
+            Message::ListExports { .. } => Some(Capability::Exports),
+            Message::GetSpaceInfo { .. } => Some(Capability::SpaceInfo),
+            Message::RestoreFromTrash { .. } | Message::PurgeTrash { .. } => Some(Capability::Trash),
+            Message::Watch { .. } => Some(Capability::Watch),
+            Message::CreateHardLink { .. } => Some(Capability::HardLinks),
+            Message::ReadSymlink { .. } => Some(Capability::ReadSymlink),
+            Message::CopyFile { .. } => Some(Capability::CopyFile),
+            Message::Rename { no_replace: true, .. } => Some(Capability::RenameNoReplace),
+            Message::SetMetadataTree { .. } => Some(Capability::MetadataTree),
+            Message::GetFileSignature { .. } | Message::WriteDelta { .. } => Some(Capability::DeltaTransfer),
+            Message::AsUser { request, .. } => request.required_capability(),
+            Message::FromClient { request, .. } => request.required_capability(),
+            _ => None,
+        }
+    }
+    
+    /// Whether this request may be sent in a `Batch`
+    ///
+    /// Only requests answered with a single response qualify. Lock requests
+    /// are left out, as the relay picks their agent by the file they lock.
+    pub fn is_batchable(&self) -> bool {
+        matches!(self,
+            Message::ReadFile { .. } |
+            Message::WriteFile { .. } |
+            Message::CreateFile { .. } |
+            Message::DeleteFile { .. } |
+            Message::ComputeChecksum { .. } |
+            Message::ListDirectory { .. } |
+            Message::CreateDirectory { .. } |
+            Message::RemoveDirectory { .. } |
+            Message::GetMetadata { .. } |
+            Message::SetMetadata { .. } |
+            Message::GetXattr { .. } |
+            Message::SetXattr { .. } |
+            Message::ListXattr { .. } |
+            Message::RemoveXattr { .. } |
+            Message::Rename { .. } |
+            Message::GetChanges { .. } |
+            Message::ReadFileAsOf { .. } |
+            Message::ReadBackupEntry { .. }
+        )
+    }
+
+    /// Whether this is a request a client sends an agent, the only kind
+    /// that may be made on behalf of a user with `AsUser`
+    ///
+    /// Responses, notices and messages only the relay sends are not, and
+    /// neither are the envelopes themselves.
+    pub fn is_client_request(&self) -> bool {
+        matches!(self,
+            Message::ReadFile { .. } |
+            Message::WriteFile { .. } |
+            Message::ReadFileStream { .. } |
+            Message::WriteFileChunk { .. } |
+            Message::CreateFile { .. } |
+            Message::DeleteFile { .. } |
+            Message::TruncateFile { .. } |
+            Message::ComputeChecksum { .. } |
+            Message::GetFileSignature { .. } |
+            Message::WriteDelta { .. } |
+            Message::LockFile { .. } |
+            Message::UnlockFile { .. } |
+            Message::TestLock { .. } |
+            Message::OpenFile { .. } |
+            Message::ReadHandle { .. } |
+            Message::WriteHandle { .. } |
+            Message::CloseFile { .. } |
+            Message::ListDirectory { .. } |
+            Message::ListDirectoryPaged { .. } |
+            Message::WalkDirectory { .. } |
+            Message::SearchFiles { .. } |
+            Message::CreateDirectory { .. } |
+            Message::RemoveDirectory { .. } |
+            Message::GetMetadata { .. } |
+            Message::SetMetadata { .. } |
+            Message::SetMetadataTree { .. } |
+            Message::GetXattr { .. } |
+            Message::SetXattr { .. } |
+            Message::ListXattr { .. } |
+            Message::RemoveXattr { .. } |
+            Message::Rename { .. } |
+            Message::CreateSymlink { .. } |
+            Message::CreateHardLink { .. } |
+            Message::ReadSymlink { .. } |
+            Message::CopyFile { .. } |
+            Message::PathExists { .. } |
+            Message::GetSpaceInfo { .. } |
+            Message::ListExports { .. } |
+            Message::GetChanges { .. } |
+            Message::ReadFileAsOf { .. } |
+            Message::ReadBackupEntry { .. } |
+            Message::Transaction { .. } |
+            Message::BatchCreateFiles { .. } |
+            Message::RestoreFromTrash { .. } |
+            Message::PurgeTrash { .. } |
+            Message::Batch { .. } |
+            Message::ExtendedOperation { .. } |
+            Message::Watch { .. }
+        )
+    }
+
+    /// Paths on the agent this request names
+    ///
+    /// The requests of a `Batch` are left out; each is looked at on its own.
+    pub fn request_paths(&self) -> Vec<&str> {
+        match self {
+            Message::ReadFile { path, .. }
+            | Message::WriteFile { path, .. }
+            | Message::ReadFileStream { path, .. }
+            | Message::WriteFileChunk { path, .. }
+            | Message::CreateFile { path, .. }
+            | Message::DeleteFile { path, .. }
+            | Message::TruncateFile { path, .. }
+            | Message::ComputeChecksum { path, .. }
+            | Message::GetFileSignature { path, .. }
+            | Message::WriteDelta { path, .. }
+            | Message::LockFile { path, .. }
+            | Message::UnlockFile { path, .. }
+            | Message::TestLock { path, .. }
+            | Message::OpenFile { path, .. }
+            | Message::ListDirectory { path, .. }
+            | Message::ListDirectoryPaged { path, .. }
+            | Message::WalkDirectory { path, .. }
+            | Message::CreateDirectory { path, .. }
+            | Message::RemoveDirectory { path, .. }
+            | Message::GetMetadata { path, .. }
+            | Message::SetMetadata { path, .. }
+            | Message::SetMetadataTree { path, .. }
+            | Message::GetXattr { path, .. }
+            | Message::SetXattr { path, .. }
+            | Message::ListXattr { path, .. }
+            | Message::RemoveXattr { path, .. }
+            | Message::ReadSymlink { path, .. }
+            | Message::PathExists { path, .. }
+            | Message::GetSpaceInfo { path, .. }
+            | Message::Watch { path, .. }
+            | Message::ReadFileAsOf { path, .. }
+            | Message::ReadBackupEntry { path, .. }
+            | Message::RestoreFromTrash { path, .. }
+            | Message::PurgeTrash { path, .. } => vec![path],
+            Message::SearchFiles { root, .. } => vec![root],
+            Message::Rename { from_path, to_path, .. } => vec![from_path, to_path],
+            Message::CreateSymlink { link_path, target_path, .. } => vec![link_path, target_path],
+            Message::CreateHardLink { existing_path, link_path, .. } => vec![existing_path, link_path],
+            Message::CopyFile { source, destination, .. } => vec![source, destination],
+            Message::Transaction { operations, .. } => operations.iter()
+                .flat_map(|operation| match operation {
+                    TransactionOp::WriteFile { path, .. }
+                    | TransactionOp::DeleteFile { path }
+                    | TransactionOp::CreateDirectory { path }
+                    | TransactionOp::RemoveDirectory { path } => vec![path.as_str()],
+                    TransactionOp::Rename { from_path, to_path } => vec![from_path.as_str(), to_path.as_str()],
+                })
+                .collect(),
+            Message::BatchCreateFiles { files, .. } => files.iter().map(|file| file.path.as_str()).collect(),
+            Message::ExtendedOperation { working_dir, .. } => working_dir.iter().map(String::as_str).collect(),
+            Message::AsUser { request, .. } => request.request_paths(),
+            Message::FromClient { request, .. } => request.request_paths(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Paths on the agent this request names, and those of batched
+    /// requests, for a client to rewrite before sending
+    pub fn request_paths_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Message::ReadFile { path, .. }
+            | Message::WriteFile { path, .. }
+            | Message::ReadFileStream { path, .. }
+            | Message::WriteFileChunk { path, .. }
+            | Message::CreateFile { path, .. }
+            | Message::DeleteFile { path, .. }
+            | Message::TruncateFile { path, .. }
+            | Message::ComputeChecksum { path, .. }
+            | Message::GetFileSignature { path, .. }
+            | Message::WriteDelta { path, .. }
+            | Message::LockFile { path, .. }
+            | Message::UnlockFile { path, .. }
+            | Message::TestLock { path, .. }
+            | Message::OpenFile { path, .. }
+            | Message::ListDirectory { path, .. }
+            | Message::ListDirectoryPaged { path, .. }
+            | Message::WalkDirectory { path, .. }
+            | Message::CreateDirectory { path, .. }
+            | Message::RemoveDirectory { path, .. }
+            | Message::GetMetadata { path, .. }
+            | Message::SetMetadata { path, .. }
+            | Message::SetMetadataTree { path, .. }
+            | Message::GetXattr { path, .. }
+            | Message::SetXattr { path, .. }
+            | Message::ListXattr { path, .. }
+            | Message::RemoveXattr { path, .. }
+            | Message::ReadSymlink { path, .. }
+            | Message::PathExists { path, .. }
+            | Message::GetSpaceInfo { path, .. }
+            | Message::Watch { path, .. }
+            | Message::ReadFileAsOf { path, .. }
+            | Message::ReadBackupEntry { path, .. }
+            | Message::RestoreFromTrash { path, .. }
+            | Message::PurgeTrash { path, .. } => vec![path],
+            Message::SearchFiles { root, .. } => vec![root],
+            Message::Rename { from_path, to_path, .. } => vec![from_path, to_path],
+            Message::CreateSymlink { link_path, target_path, .. } => vec![link_path, target_path],
+            Message::CreateHardLink { existing_path, link_path, .. } => vec![existing_path, link_path],
+            Message::CopyFile { source, destination, .. } => vec![source, destination],
+            Message::Transaction { operations, .. } => operations.iter_mut()
+                .flat_map(|operation| match operation {
+                    TransactionOp::WriteFile { path, .. }
+                    | TransactionOp::DeleteFile { path }
+                    | TransactionOp::CreateDirectory { path }
+                    | TransactionOp::RemoveDirectory { path } => vec![path],
+                    TransactionOp::Rename { from_path, to_path } => vec![from_path, to_path],
+                })
+                .collect(),
+            Message::BatchCreateFiles { files, .. } => files.iter_mut().map(|file| &mut file.path).collect(),
+            Message::ExtendedOperation { working_dir, .. } => working_dir.iter_mut().collect(),
+            Message::Batch { operations, .. } => operations.iter_mut().flat_map(Message::request_paths_mut).collect(),
+            Message::AsUser { request, .. } => request.request_paths_mut(),
+            Message::FromClient { request, .. } => request.request_paths_mut(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Agent the client addressed this request to, if it chose one
+    pub fn target_agent(&self) -> Option<&str> {
+        match self {
+            Message::ListExports { agent_id, .. } => agent_id.as_deref(),
+            Message::AsUser { request, .. } => request.target_agent(),
+            _ => None,
+        }
+    }
+
     /// Get message type name for logging
     pub fn message_type(&self) -> &'static str {
         match self {
             Message::AuthRequest { .. } => "AuthRequest",
             Message::AuthResponse { .. } => "AuthResponse", 
+            Message::ResumeSession { .. } => "ResumeSession",
             Message::EstablishChannel { .. } => "EstablishChannel",
             Message::ChannelEstablished { .. } => "ChannelEstablished",
             Message::ReadFile { .. } => "ReadFile",
             Message::ReadFileResponse { .. } => "ReadFileResponse",
             Message::WriteFile { .. } => "WriteFile",
             Message::WriteFileResponse { .. } => "WriteFileResponse",
+            Message::ReadFileStream { .. } => "ReadFileStream",
+            Message::ReadFileChunk { .. } => "ReadFileChunk",
+            Message::ReadFileAck { .. } => "ReadFileAck",
+            Message::WriteFileChunk { .. } => "WriteFileChunk",
             Message::CreateFile { .. } => "CreateFile",
             Message::CreateFileResponse { .. } => "CreateFileResponse",
             Message::DeleteFile { .. } => "DeleteFile",
             Message::DeleteFileResponse { .. } => "DeleteFileResponse",
             Message::TruncateFile { .. } => "TruncateFile",
             Message::TruncateFileResponse { .. } => "TruncateFileResponse",
+            Message::ComputeChecksum { .. } => "ComputeChecksum",
+            Message::ChecksumResponse { .. } => "ChecksumResponse",
+            Message::GetFileSignature { .. } => "GetFileSignature",
+            Message::FileSignatureResponse { .. } => "FileSignatureResponse",
+            Message::WriteDelta { .. } => "WriteDelta",
+            Message::LockFile { .. } => "LockFile",
+            Message::LockFileResponse { .. } => "LockFileResponse",
+            Message::UnlockFile { .. } => "UnlockFile",
+            Message::UnlockFileResponse { .. } => "UnlockFileResponse",
+            Message::TestLock { .. } => "TestLock",
+            Message::TestLockResponse { .. } => "TestLockResponse",
+            Message::ReleaseLocks { .. } => "ReleaseLocks",
+            Message::OpenFile { .. } => "OpenFile",
+            Message::OpenFileResponse { .. } => "OpenFileResponse",
+            Message::ReadHandle { .. } => "ReadHandle",
+            Message::WriteHandle { .. } => "WriteHandle",
+            Message::CloseFile { .. } => "CloseFile",
+            Message::CloseFileResponse { .. } => "CloseFileResponse",
             Message::ListDirectory { .. } => "ListDirectory",
             Message::ListDirectoryResponse { .. } => "ListDirectoryResponse",
+            Message::ListDirectoryPaged { .. } => "ListDirectoryPaged",
+            Message::WalkDirectory { .. } => "WalkDirectory",
+            Message::SearchFiles { .. } => "SearchFiles",
+            Message::SearchResults { .. } => "SearchResults",
+            Message::DirectoryPage { .. } => "DirectoryPage",
             Message::CreateDirectory { .. } => "CreateDirectory",
             Message::CreateDirectoryResponse { .. } => "CreateDirectoryResponse",
             Message::RemoveDirectory { .. } => "RemoveDirectory",
@@ -464,17 +2311,72 @@ impl Message {
             Message::GetMetadataResponse { .. } => "GetMetadataResponse",
             Message::SetMetadata { .. } => "SetMetadata",
             Message::SetMetadataResponse { .. } => "SetMetadataResponse",
+            Message::SetMetadataTree { .. } => "SetMetadataTree",
+            Message::SetMetadataTreeProgress { .. } => "SetMetadataTreeProgress",
+            Message::CancelSetMetadataTree { .. } => "CancelSetMetadataTree",
+            Message::GetXattr { .. } => "GetXattr",
+            Message::GetXattrResponse { .. } => "GetXattrResponse",
+            Message::SetXattr { .. } => "SetXattr",
+            Message::SetXattrResponse { .. } => "SetXattrResponse",
+            Message::ListXattr { .. } => "ListXattr",
+            Message::ListXattrResponse { .. } => "ListXattrResponse",
+            Message::RemoveXattr { .. } => "RemoveXattr",
+            Message::RemoveXattrResponse { .. } => "RemoveXattrResponse",
             Message::Rename { .. } => "Rename",
             Message::RenameResponse { .. } => "RenameResponse",
             Message::CreateSymlink { .. } => "CreateSymlink",
             Message::CreateSymlinkResponse { .. } => "CreateSymlinkResponse",
+            Message::CreateHardLink { .. } => "CreateHardLink",
+            Message::CreateHardLinkResponse { .. } => "CreateHardLinkResponse",
+            Message::ReadSymlink { .. } => "ReadSymlink",
+            Message::ReadSymlinkResponse { .. } => "ReadSymlinkResponse",
+            Message::CopyFile { .. } => "CopyFile",
+            Message::CopyFileResponse { .. } => "CopyFileResponse",
             Message::PathExists { .. } => "PathExists",
             Message::PathExistsResponse { .. } => "PathExistsResponse",
             Message::GetSpaceInfo { .. } => "GetSpaceInfo",
             Message::GetSpaceInfoResponse { .. } => "GetSpaceInfoResponse",
+            Message::ListExports { .. } => "ListExports",
+            Message::ListExportsResponse { .. } => "ListExportsResponse",
+            Message::GetChanges { .. } => "GetChanges",
+            Message::GetChangesResponse { .. } => "GetChangesResponse",
+            Message::Watch { .. } => "Watch",
+            Message::WatchResponse { .. } => "WatchResponse",
+            Message::FileChanged { .. } => "FileChanged",
+            Message::DirectoryChanged { .. } => "DirectoryChanged",
+            Message::Unwatch { .. } => "Unwatch",
+            Message::WatchEnded { .. } => "WatchEnded",
+            Message::ReadFileAsOf { .. } => "ReadFileAsOf",
+            Message::ReadBackupEntry { .. } => "ReadBackupEntry",
+            Message::ReadBackupEntryResponse { .. } => "ReadBackupEntryResponse",
+            Message::Transaction { .. } => "Transaction",
+            Message::TransactionResponse { .. } => "TransactionResponse",
+            Message::BatchCreateFiles { .. } => "BatchCreateFiles",
+            Message::BatchCreateFilesResponse { .. } => "BatchCreateFilesResponse",
+            Message::RestoreFromTrash { .. } => "RestoreFromTrash",
+            Message::RestoreFromTrashResponse { .. } => "RestoreFromTrashResponse",
+            Message::PurgeTrash { .. } => "PurgeTrash",
+            Message::PurgeTrashResponse { .. } => "PurgeTrashResponse",
+            Message::Batch { .. } => "Batch",
+            Message::BatchResponse { .. } => "BatchResponse",
+            Message::ExtendedOperation { .. } => "ExtendedOperation",
+            Message::ExtendedOutput { .. } => "ExtendedOutput",
+            Message::AsUser { .. } => "AsUser",
+            Message::FromClient { .. } => "FromClient",
             Message::Ping { .. } => "Ping",
             Message::Pong { .. } => "Pong",
             Message::ConnectionClose { .. } => "ConnectionClose",
+            Message::Compressed { .. } => "Compressed",
+            Message::MirrorStatus { .. } => "MirrorStatus",
+            Message::AgentHealth { .. } => "AgentHealth",
+            Message::Audit { .. } => "Audit",
+            Message::Broadcast { .. } => "Broadcast",
+            Message::GetRelayDirectory => "GetRelayDirectory",
+            Message::RelayDirectoryResponse { .. } => "RelayDirectoryResponse",
+            Message::ListAgents { .. } => "ListAgents",
+            Message::ListAgentsResponse { .. } => "ListAgentsResponse",
+            Message::GetMaintenance { .. } => "GetMaintenance",
+            Message::MaintenanceStatus { .. } => "MaintenanceStatus",
             Message::Error { .. } => "Error",
         }
     }
@@ -495,6 +2397,12 @@ impl std::fmt::Display for ErrorCode {
             ErrorCode::InvalidPath => "InvalidPath",
             ErrorCode::DiskFull => "DiskFull",
             ErrorCode::ReadOnlyFileSystem => "ReadOnlyFileSystem",
+            ErrorCode::FileOffline => "FileOffline",
+            ErrorCode::NotADirectory => "NotADirectory",
+            ErrorCode::IsADirectory => "IsADirectory",
+            ErrorCode::DirectoryNotEmpty => "DirectoryNotEmpty",
+            ErrorCode::NameTooLong => "NameTooLong",
+            ErrorCode::QuotaExceeded => "QuotaExceeded",
             ErrorCode::NetworkError => "NetworkError",
             ErrorCode::ConnectionTimeout => "ConnectionTimeout",
             ErrorCode::MessageTooLarge => "MessageTooLarge",
@@ -553,5 +2461,368 @@ mod tests {
         assert_eq!(request.request_id(), response.request_id());
         assert!(!request.is_response());
         assert!(response.is_response());
+        assert!(!request.ends_request());
+        assert!(response.ends_request());
+    }
+    
+    #[test]
+    fn test_streamed_response_end() {
+        let request_id = generate_request_id();
+        let page = |sequence, last| Message::DirectoryPage {
+            request_id,
+            sequence,
+            entries: Vec::new(),
+            last,
+            error: None,
+            cursor: None,
+        };
+        
+        assert!(page(0, false).is_response());
+        assert!(!page(0, false).ends_request());
+        assert!(page(1, true).ends_request());
+        assert_eq!(page(1, true).request_id(), Some(request_id));
+    }
+    
+    #[test]
+    fn test_watch_stream_end() {
+        let request_id = generate_request_id();
+        let started = Message::WatchResponse { request_id, success: true, error: None };
+        let refused = Message::WatchResponse { request_id, success: false, error: Some("denied".to_string()) };
+        let changed = Message::FileChanged {
+            request_id,
+            path: "/data/a.txt".to_string(),
+            kind: ChangeKind::Modified,
+            is_dir: false,
+        };
+        
+        assert!(!started.ends_request());
+        assert!(refused.ends_request());
+        assert!(changed.is_response());
+        assert!(!changed.ends_request());
+        assert!(!Message::DirectoryChanged { request_id, path: "/data".to_string() }.ends_request());
+        assert!(Message::WatchEnded { request_id, error: None }.ends_request());
+        assert!(!Message::Unwatch { request_id }.is_response());
+    }
+    
+    #[test]
+    fn test_metadata_tree_stream_end() {
+        let request_id = generate_request_id();
+        let tree = Message::SetMetadataTree {
+            request_id,
+            path: "/data".to_string(),
+            update: MetadataUpdate { permissions: Some(0o640), ..Default::default() },
+        };
+        let progress = |last| Message::SetMetadataTreeProgress { request_id, progress: TreeProgress::default(), last, error: None };
+        
+        assert_eq!(tree.required_capability(), Some(Capability::MetadataTree));
+        assert!(progress(false).is_response() && !progress(false).ends_request());
+        assert!(progress(true).ends_request());
+        assert!(!Message::CancelSetMetadataTree { request_id }.is_response());
+    }
+
+    #[test]
+    fn test_delta_requests() {
+        let request_id = generate_request_id();
+        let write = Message::WriteDelta {
+            request_id,
+            path: "/vm/disk.img".to_string(),
+            block_size: 4096,
+            ops: vec![DeltaOp::Copy { block: 0, count: 2 }, DeltaOp::Data(b"changed".to_vec())],
+            checksum: vec![0; 32],
+            sync: true,
+        };
+        assert_eq!(write.required_capability(), Some(Capability::DeltaTransfer));
+        assert_eq!(Capability::from("delta_transfer".to_string()), Capability::DeltaTransfer);
+
+        let bytes = bincode::serialize(&write).unwrap();
+        let decoded: Message = bincode::deserialize(&bytes).unwrap();
+        assert!(matches!(decoded, Message::WriteDelta { ops, .. } if ops.len() == 2));
+
+        let signature = Message::FileSignatureResponse { request_id, success: true, signature: None, error: None };
+        assert!(signature.is_response() && signature.ends_request());
+    }
+    
+    #[test]
+    fn test_partial_metadata_update() {
+        let msg = Message::SetMetadata {
+            request_id: generate_request_id(),
+            path: "/test/file.txt".to_string(),
+            update: MetadataUpdate {
+                modified: Some(Utc::now()),
+                ..Default::default()
+            },
+        };
+        
+        let serialized = bincode::serialize(&msg).expect("Serialization failed");
+        let deserialized: Message = bincode::deserialize(&serialized).expect("Deserialization failed");
+        
+        match deserialized {
+            Message::SetMetadata { update, .. } => {
+                assert!(!update.is_empty());
+                assert!(update.modified.is_some());
+                assert!(update.permissions.is_none());
+                assert!(update.uid.is_none());
+                assert!(update.gid.is_none());
+            }
+            _ => panic!("Expected SetMetadata"),
+        }
+        
+        assert!(MetadataUpdate::default().is_empty());
+    }
+    
+    #[test]
+    fn test_capabilities_are_plain_strings() {
+        let capabilities = vec![Capability::Read, Capability::RemoteExec, Capability::Other("future_thing".to_string())];
+        let names = vec!["read".to_string(), "remote_exec".to_string(), "future_thing".to_string()];
+        
+        // Both encodings match a list of names, as older nodes send
+        assert_eq!(serde_json::to_string(&capabilities).unwrap(), r#"["read","remote_exec","future_thing"]"#);
+        assert_eq!(bincode::serialize(&capabilities).unwrap(), bincode::serialize(&names).unwrap());
+        
+        let decoded: Vec<Capability> = bincode::deserialize(&bincode::serialize(&names).unwrap()).unwrap();
+        assert_eq!(decoded, capabilities);
+        assert_eq!(Capability::from("xattr".to_string()), Capability::Xattr);
+    }
+    
+    #[test]
+    fn test_required_capability() {
+        let request_id = generate_request_id();
+        let paged = Message::ListDirectoryPaged {
+            request_id,
+            path: "/data".to_string(),
+            page_size: 100,
+            after: None,
+            max_entries: None,
+        };
+        assert_eq!(paged.required_capability(), Some(Capability::Streaming));
+        
+        let resumed = Message::ListDirectoryPaged {
+            request_id,
+            path: "/data".to_string(),
+            page_size: 100,
+            after: Some("b.txt".to_string()),
+            max_entries: None,
+        };
+        assert_eq!(resumed.required_capability(), Some(Capability::ListingCursors));
+        
+        let bounded = Message::ListDirectoryPaged {
+            request_id,
+            path: "/data".to_string(),
+            page_size: 100,
+            after: None,
+            max_entries: Some(50),
+        };
+        assert_eq!(bounded.required_capability(), Some(Capability::ListingCursors));
+        
+        let legacy = Message::ListDirectory { request_id, path: "/data".to_string() };
+        assert_eq!(legacy.required_capability(), None);
+        
+        let wrapped = Message::AsUser {
+            identity: CallerIdentity { uid: 501, gid: 20, groups: vec![] },
+            request: Box::new(paged),
+        };
+        assert_eq!(wrapped.required_capability(), Some(Capability::Streaming));
+        
+        let exports = Message::ListExports { request_id, agent_id: None };
+        assert_eq!(exports.required_capability(), Some(Capability::Exports));
+        
+        let walk = Message::WalkDirectory { request_id, path: "/data".to_string(), max_depth: None, follow_symlinks: false };
+        assert_eq!(walk.required_capability(), Some(Capability::Walk));
+        
+        let search = Message::SearchFiles {
+            request_id,
+            root: "/data".to_string(),
+            name_glob: Some("*.rs".to_string()),
+            content_regex: None,
+            max_results: None,
+        };
+        assert_eq!(search.required_capability(), Some(Capability::Search));
+        
+        let stream = Message::ReadFileStream { request_id, path: "/data/a".to_string(), offset: 0, length: None, chunk_size: MAX_STREAM_CHUNK };
+        assert_eq!(stream.required_capability(), Some(Capability::ChunkedTransfer));
+        
+        // A stream ends with its last chunk, and acknowledgements answer nothing
+        let chunk = |last| Message::ReadFileChunk { request_id, sequence: 0, offset: 0, data: Vec::new(), last, error: None };
+        assert!(chunk(false).is_response() && !chunk(false).ends_request());
+        assert!(chunk(true).ends_request());
+        assert_eq!(Message::ReadFileAck { stream_id: request_id, sequence: 0 }.request_id(), None);
+        
+        let close = Message::CloseFile { request_id, handle: generate_request_id() };
+        assert_eq!(close.required_capability(), Some(Capability::OpenFiles));
+        
+        let space = Message::GetSpaceInfo { request_id, path: "/data".to_string() };
+        assert_eq!(space.required_capability(), Some(Capability::SpaceInfo));
+        
+        let restore = Message::RestoreFromTrash { request_id, path: "/data/a".to_string() };
+        assert_eq!(restore.required_capability(), Some(Capability::Trash));
+        assert_eq!(restore.request_paths(), vec!["/data/a"]);
+        
+        let link = Message::CreateHardLink { request_id, existing_path: "/data/a".to_string(), link_path: "/data/b".to_string() };
+        assert_eq!(link.required_capability(), Some(Capability::HardLinks));
+        let readlink = Message::ReadSymlink { request_id, path: "/data/link".to_string() };
+        assert_eq!(readlink.required_capability(), Some(Capability::ReadSymlink));
+        assert_eq!(readlink.request_paths(), vec!["/data/link"]);
+        let copy = Message::CopyFile {
+            request_id,
+            source: "/data/a".to_string(),
+            destination: "/data/b".to_string(),
+            offset: 0,
+            length: None,
+        };
+        assert_eq!(copy.required_capability(), Some(Capability::CopyFile));
+        assert_eq!(copy.request_paths(), vec!["/data/a", "/data/b"]);
+        
+        // Only renames that must not replace need agents that know the flag
+        let rename = |no_replace| Message::Rename {
+            request_id,
+            from_path: "/data/a".to_string(),
+            to_path: "/data/b".to_string(),
+            no_replace,
+        };
+        assert_eq!(rename(false).required_capability(), None);
+        assert_eq!(rename(true).required_capability(), Some(Capability::RenameNoReplace));
+    }
+    
+    #[test]
+    fn test_open_flags_from_posix() {
+        assert_eq!(OpenFlags::from_posix(libc::O_RDONLY), OpenFlags::read_only());
+        let flags = OpenFlags::from_posix(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_APPEND);
+        assert!(!flags.read && flags.write && flags.create && flags.exclusive && flags.append);
+        assert!(flags.writes());
+        let flags = OpenFlags::from_posix(libc::O_RDWR | libc::O_TRUNC);
+        assert!(flags.read && flags.write && flags.truncate && !flags.create);
+    }
+
+    #[test]
+    fn test_target_agent() {
+        let request_id = generate_request_id();
+        let any = Message::ListExports { request_id, agent_id: None };
+        assert_eq!(any.target_agent(), None);
+
+        let chosen = Message::ListExports { request_id, agent_id: Some("workstation".to_string()) };
+        assert_eq!(chosen.target_agent(), Some("workstation"));
+
+        let wrapped = Message::AsUser {
+            identity: CallerIdentity { uid: 501, gid: 20, groups: vec![] },
+            request: Box::new(chosen),
+        };
+        assert_eq!(wrapped.target_agent(), Some("workstation"));
+        assert_eq!(Message::ListAgents { request_id }.target_agent(), None);
+    }
+
+    #[test]
+    fn test_maintenance_window_activity() {
+        let now = Utc::now();
+        let window = MaintenanceWindow {
+            agent_id: None,
+            message: "Relay upgrade".to_string(),
+            starts_at: now,
+            ends_at: Some(now + chrono::Duration::hours(1)),
+        };
+        assert!(!window.is_active_at(now - chrono::Duration::minutes(1)));
+        assert!(window.is_active_at(now));
+        assert!(window.is_active_at(now + chrono::Duration::minutes(59)));
+        assert!(!window.is_active_at(now + chrono::Duration::hours(1)));
+        assert!(window.has_ended_at(now + chrono::Duration::hours(1)));
+
+        // Posted through the admin API without a start, it starts right away
+        let window: MaintenanceWindow = serde_json::from_str(r#"{"agent_id": "nas", "message": "Disk swap"}"#).unwrap();
+        assert!(window.is_active_at(Utc::now()));
+        assert!(!window.has_ended_at(now + chrono::Duration::days(365)));
+    }
+
+    #[test]
+    fn test_broadcast_roundtrip() {
+        let msg = Message::Broadcast {
+            agent_id: "workstation".to_string(),
+            event: AgentEvent::Maintenance {
+                message: "Disk replacement".to_string(),
+                starts_at: Some(Utc::now()),
+            },
+            timestamp: Utc::now(),
+        };
+        assert_eq!(msg.request_id(), None);
+        assert!(!msg.is_response());
+
+        let decoded: Message = bincode::deserialize(&bincode::serialize(&msg).unwrap()).unwrap();
+        let Message::Broadcast { agent_id, event, .. } = decoded else {
+            panic!("Expected a broadcast");
+        };
+        assert_eq!(agent_id, "workstation");
+        assert!(matches!(event, AgentEvent::Maintenance { message, .. } if message == "Disk replacement"));
+    }
+
+    #[test]
+    fn test_as_user_envelope() {
+        let request_id = generate_request_id();
+        let msg = Message::AsUser {
+            identity: CallerIdentity { uid: 501, gid: 20, groups: vec![12, 80] },
+            request: Box::new(Message::ListDirectory {
+                request_id,
+                path: "/shared".to_string(),
+            }),
+        };
+        
+        let serialized = bincode::serialize(&msg).expect("Serialization failed");
+        let deserialized: Message = bincode::deserialize(&serialized).expect("Deserialization failed");
+        
+        assert_eq!(deserialized.message_type(), "AsUser");
+        assert_eq!(deserialized.request_id(), Some(request_id));
+        assert!(!deserialized.is_response());
+        match deserialized {
+            Message::AsUser { identity, .. } => {
+                assert!(identity.in_group(20));
+                assert!(identity.in_group(80));
+                assert!(!identity.in_group(0));
+            }
+            _ => panic!("Expected AsUser"),
+        }
+        
+        // Only requests a client makes itself may name a user
+        assert!(Message::ListDirectory { request_id, path: "/shared".to_string() }.is_client_request());
+        assert!(!Message::ReleaseLocks { session: "laptop".to_string() }.is_client_request());
+        assert!(!Message::PathExistsResponse { request_id, exists: true, error: None }.is_client_request());
+        assert!(!msg.is_client_request());
+    }
+
+    #[test]
+    fn test_from_client_envelope() {
+        let request_id = generate_request_id();
+        let msg = Message::FromClient {
+            client_id: "laptop".to_string(),
+            request: Box::new(Message::GetSpaceInfo { request_id, path: "/shared".to_string() }),
+        };
+        
+        let deserialized: Message = bincode::deserialize(&bincode::serialize(&msg).unwrap()).unwrap();
+        assert_eq!(deserialized.message_type(), "FromClient");
+        assert_eq!(deserialized.request_id(), Some(request_id));
+        assert_eq!(deserialized.request_paths(), vec!["/shared"]);
+        assert_eq!(deserialized.required_capability(), Some(Capability::SpaceInfo));
+        assert!(!deserialized.is_response());
+        assert_eq!(Capability::from("client_rules".to_string()), Capability::ClientRules);
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let request_id = generate_request_id();
+        let stat = |name: &str| Message::GetMetadata {
+            request_id: generate_request_id(),
+            path: format!("/project/{}", name),
+            follow_symlinks: false,
+        };
+        let msg = Message::Batch { request_id, operations: vec![stat("Cargo.toml"), stat("src")] };
+        assert_eq!(msg.required_capability(), Some(Capability::Batch));
+        assert!(stat("src").is_batchable());
+        assert!(!msg.is_batchable());
+        
+        let serialized = bincode::serialize(&msg).expect("Serialization failed");
+        let Message::Batch { operations, .. } = bincode::deserialize(&serialized).expect("Deserialization failed") else {
+            panic!("Expected Batch");
+        };
+        assert_eq!(operations.len(), 2);
+        assert!(matches!(&operations[1], Message::GetMetadata { path, .. } if path == "/project/src"));
+        
+        let response = Message::BatchResponse { request_id, responses: vec![] };
+        assert!(response.is_response() && response.ends_request());
     }
 }
@@ -0,0 +1,153 @@
+//! systemd units running the agent and relay as system services
+//!
+//! The daemons' `install-service` subcommands write and enable a unit for
+//! the running binary; the deb and rpm packages ship the units generated for
+//! their install paths, so a packaged daemon starts the same way.
+
+use crate::error::{RemoteFsError, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Directory systemd reads administrator-installed units from
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// The binary bundling every component, which runs one as a subcommand
+pub const META_BINARY: &str = "remotefs";
+
+/// A daemon to run under systemd
+#[derive(Debug, Clone)]
+pub struct SystemdService {
+    /// Unit name, without `.service`
+    pub name: String,
+    pub description: String,
+    /// Program and arguments starting the daemon in the foreground
+    pub command: Vec<String>,
+    /// Account the daemon runs as; root if unset
+    pub user: Option<String>,
+    /// Whether the daemon reloads its configuration on SIGHUP, making
+    /// `systemctl reload` work
+    pub reloadable: bool,
+}
+
+impl SystemdService {
+    /// Service running `component` from `program` with `config`
+    ///
+    /// Run from the `remotefs` binary, the component is its subcommand.
+    pub fn new(component: &str, description: &str, program: &Path, config: &Path) -> Self {
+        let mut command = vec![program.display().to_string()];
+        if program.file_name().is_some_and(|name| name == META_BINARY) {
+            command.push(component.trim_start_matches("remotefs-").to_string());
+        }
+        command.extend(["--config".to_string(), config.display().to_string()]);
+
+        Self {
+            name: component.to_string(),
+            description: description.to_string(),
+            command,
+            user: None,
+            reloadable: false,
+        }
+    }
+
+    /// The unit file
+    pub fn unit(&self) -> String {
+        let exec_start: Vec<String> = self.command.iter().map(|arg| quote(arg)).collect();
+        let mut service = format!("Type=simple\nExecStart={}\n", exec_start.join(" "));
+        if self.reloadable {
+            service.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+        }
+        service.push_str("Restart=on-failure\nRestartSec=5\n");
+        if let Some(user) = &self.user {
+            service.push_str(&format!("User={}\nGroup={}\n", user, user));
+        }
+        service.push_str("NoNewPrivileges=yes\n");
+
+        format!(
+            "[Unit]\n\
+             Description={}\n\
+             After=network-online.target\n\
+             Wants=network-online.target\n\
+             \n\
+             [Service]\n\
+             {}\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            self.description, service
+        )
+    }
+
+    /// Where the unit is installed
+    pub fn unit_path(&self) -> PathBuf {
+        Path::new(UNIT_DIR).join(format!("{}.service", self.name))
+    }
+
+    /// Write the unit, then enable and start it
+    pub fn install(&self) -> Result<PathBuf> {
+        let path = self.unit_path();
+        std::fs::write(&path, self.unit()).map_err(|e| RemoteFsError::Configuration(format!(
+            "Failed to write {} (run as root): {}",
+            path.display(),
+            e
+        )))?;
+
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", &self.name])?;
+        Ok(path)
+    }
+}
+
+/// Quote an `ExecStart` argument systemd would otherwise split or expand
+fn quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\\' | '$' | '%')) {
+        let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "$$").replace('%', "%%");
+        format!("\"{}\"", escaped)
+    } else {
+        arg.to_string()
+    }
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("systemctl")
+        .args(args)
+        .output()
+        .map_err(|e| RemoteFsError::Internal(format!("Failed to execute systemctl: {}", e)))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(RemoteFsError::Internal(format!(
+            "systemctl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit() {
+        let mut service = SystemdService::new(
+            "remotefs-agent",
+            "RemoteFS Agent",
+            Path::new("/usr/bin/remotefs-agent"),
+            Path::new("/etc/remotefs/My Agent.toml"),
+        );
+        service.user = Some("remotefs".to_string());
+        service.reloadable = true;
+
+        let unit = service.unit();
+        assert!(unit.contains("ExecStart=/usr/bin/remotefs-agent --config \"/etc/remotefs/My Agent.toml\"\n"));
+        assert!(unit.contains("ExecReload=/bin/kill -HUP $MAINPID\n"));
+        assert!(unit.contains("User=remotefs\nGroup=remotefs\n"));
+        assert_eq!(service.unit_path(), Path::new("/etc/systemd/system/remotefs-agent.service"));
+
+        // The bundled binary runs the component as a subcommand
+        let service = SystemdService::new("remotefs-relay", "RemoteFS Relay", Path::new("/usr/local/bin/remotefs"), Path::new("/etc/remotefs/relay.toml"));
+        assert_eq!(service.command, ["/usr/local/bin/remotefs", "relay", "--config", "/etc/remotefs/relay.toml"]);
+        assert!(!service.unit().contains("ExecReload") && !service.unit().contains("User="));
+    }
+}
@@ -0,0 +1,266 @@
+//! Signed session tokens
+//!
+//! The relay signs the session tokens it issues with an Ed25519 key and
+//! publishes the public half in `RelayInfo::token_key`, so any node that
+//! authenticated with the relay can check a token offline, without asking
+//! the relay. A token reads `rfs1.<claims>.<signature>`, both parts base64url
+//! encoded, the claims as JSON.
+//!
+//! A token states how long it lives (`ttl_secs`) as well as when it was
+//! issued. Its holder times it from when it arrived with `HeldToken`, using
+//! the monotonic clock, so a holder whose clock is off does not throw away
+//! good tokens. Verifiers compare the issue time with their own clock and
+//! allow `MAX_CLOCK_SKEW_SECS` either way.
+
+use crate::error::{RemoteFsError, Result};
+use crate::protocol::{Capability, NodeId, NodeType, SessionToken};
+use anyhow::anyhow;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Prefix of tokens in this format
+const TOKEN_PREFIX: &str = "rfs1";
+
+/// Difference between the issuer's and the verifier's clocks tolerated when
+/// checking a token's lifetime
+pub const MAX_CLOCK_SKEW_SECS: u64 = 60;
+
+/// What a session token says about its holder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// Unique per token, for revocation lists
+    pub token_id: Uuid,
+    /// Relay that issued the token
+    pub relay_id: String,
+    pub node_id: NodeId,
+    pub node_type: NodeType,
+    /// Capabilities the holder authenticated with
+    pub scope: Vec<Capability>,
+    /// Seconds since the Unix epoch, by the issuer's clock
+    pub issued_at: u64,
+    /// Seconds the token is valid for after it was issued
+    pub ttl_secs: u64,
+}
+
+impl TokenClaims {
+    /// Seconds since the Unix epoch, by the issuer's clock, after which the
+    /// token is no longer valid
+    pub fn expires_at(&self) -> u64 {
+        self.issued_at.saturating_add(self.ttl_secs)
+    }
+
+    /// Whether the holder authenticated with `capability`
+    pub fn allows(&self, capability: &Capability) -> bool {
+        self.scope.contains(capability)
+    }
+}
+
+/// Signs session tokens; the relay holds one
+pub struct TokenSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl TokenSigner {
+    /// Create a signer with a fresh key
+    pub fn generate() -> Result<Self> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| RemoteFsError::Encryption(anyhow!("Failed to generate token signing key")))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|_| RemoteFsError::Encryption(anyhow!("Invalid token signing key")))?;
+        Ok(Self { key_pair })
+    }
+
+    /// Public key verifiers check tokens with
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key_pair.public_key().as_ref().to_vec()
+    }
+
+    /// Sign `claims` into a token
+    pub fn sign(&self, claims: &TokenClaims) -> Result<SessionToken> {
+        let claims = serde_json::to_vec(claims).map_err(|e| RemoteFsError::Protocol(e.to_string()))?;
+        let payload = format!("{}.{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(claims));
+        let signature = self.key_pair.sign(payload.as_bytes());
+        Ok(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(signature.as_ref())))
+    }
+}
+
+/// Checks tokens signed by one relay
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    public_key: Vec<u8>,
+}
+
+impl TokenVerifier {
+    /// Verifier for tokens signed with the key a relay published in
+    /// `RelayInfo::token_key`
+    pub fn new(public_key: Vec<u8>) -> Self {
+        Self { public_key }
+    }
+
+    /// Check the signature and lifetime of `token` and return its claims
+    pub fn verify(&self, token: &str) -> Result<TokenClaims> {
+        self.verify_at(token, unix_now())
+    }
+
+    /// `verify` as if the verifier's clock read `now`
+    pub fn verify_at(&self, token: &str, now: u64) -> Result<TokenClaims> {
+        let claims = self.verify_signature(token)?;
+        if claims.issued_at > now.saturating_add(MAX_CLOCK_SKEW_SECS) {
+            return Err(RemoteFsError::Authentication("Session token issued in the future".to_string()));
+        }
+        if claims.expires_at().saturating_add(MAX_CLOCK_SKEW_SECS) < now {
+            return Err(RemoteFsError::Authentication("Session token expired".to_string()));
+        }
+        Ok(claims)
+    }
+
+    /// Check the signature of `token` only and return its claims
+    pub fn verify_signature(&self, token: &str) -> Result<TokenClaims> {
+        let invalid = || RemoteFsError::Authentication("Invalid session token".to_string());
+
+        let (payload, signature) = token.rsplit_once('.').ok_or_else(invalid)?;
+        let (prefix, claims) = payload.split_once('.').ok_or_else(invalid)?;
+        if prefix != TOKEN_PREFIX {
+            return Err(invalid());
+        }
+
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        UnparsedPublicKey::new(&ED25519, &self.public_key)
+            .verify(payload.as_bytes(), &signature)
+            .map_err(|_| invalid())?;
+
+        let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| invalid())?;
+        serde_json::from_slice(&claims).map_err(|_| invalid())
+    }
+}
+
+/// A node's own token, timed from when it arrived
+#[derive(Debug, Clone)]
+pub struct HeldToken {
+    pub token: SessionToken,
+    /// Claims as read from the token; the holder cannot check the signature
+    /// unless it knows the issuer's key
+    pub claims: Option<TokenClaims>,
+    received_at: Instant,
+}
+
+impl HeldToken {
+    /// Hold `token`, received just now
+    pub fn new(token: SessionToken) -> Self {
+        let claims = read_claims(&token);
+        Self { token, claims, received_at: Instant::now() }
+    }
+
+    /// Time left before the token expires, or `None` for tokens that do not
+    /// say how long they live
+    pub fn remaining(&self) -> Option<Duration> {
+        let ttl = Duration::from_secs(self.claims.as_ref()?.ttl_secs);
+        Some(ttl.saturating_sub(self.received_at.elapsed()))
+    }
+
+    /// Whether the token has expired
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_some_and(|remaining| remaining.is_zero())
+    }
+}
+
+/// Claims of `token` without checking its signature
+fn read_claims(token: &str) -> Option<TokenClaims> {
+    let mut parts = token.split('.');
+    if parts.next()? != TOKEN_PREFIX {
+        return None;
+    }
+    let claims = URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+    serde_json::from_slice(&claims).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(issued_at: u64, ttl_secs: u64) -> TokenClaims {
+        TokenClaims {
+            token_id: Uuid::new_v4(),
+            relay_id: "relay-1".to_string(),
+            node_id: "client-001".to_string(),
+            node_type: NodeType::Client,
+            scope: vec![Capability::Read],
+            issued_at,
+            ttl_secs,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = TokenSigner::generate().unwrap();
+        let verifier = TokenVerifier::new(signer.public_key());
+        let issued = claims(1_000_000, 600);
+        let token = signer.sign(&issued).unwrap();
+
+        let verified = verifier.verify_at(&token, 1_000_300).unwrap();
+        assert_eq!(verified.token_id, issued.token_id);
+        assert_eq!(verified.node_id, issued.node_id);
+        assert_eq!(verified.expires_at(), 1_000_600);
+        assert!(verified.allows(&Capability::Read));
+        assert!(!verified.allows(&Capability::Write));
+
+        // Clocks may disagree by a little, not by a lot
+        assert!(verifier.verify_at(&token, 1_000_600 + MAX_CLOCK_SKEW_SECS).is_ok());
+        assert!(verifier.verify_at(&token, 1_000_601 + MAX_CLOCK_SKEW_SECS).is_err());
+        assert!(verifier.verify_at(&token, 1_000_000 - MAX_CLOCK_SKEW_SECS).is_ok());
+        assert!(verifier.verify_at(&token, 999_999 - MAX_CLOCK_SKEW_SECS).is_err());
+    }
+
+    #[test]
+    fn test_rejects_forged_tokens() {
+        let signer = TokenSigner::generate().unwrap();
+        let verifier = TokenVerifier::new(signer.public_key());
+        let token = signer.sign(&claims(1_000_000, 600)).unwrap();
+
+        // Another relay's tokens
+        let other = TokenSigner::generate().unwrap().sign(&claims(1_000_000, 600)).unwrap();
+        assert!(verifier.verify_signature(&other).is_err());
+
+        // Changed claims
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let mut forged = claims(1_000_000, 600);
+        forged.scope.push(Capability::Write);
+        let forged = format!("{}.{}.{}",
+            TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap()), signature);
+        assert!(verifier.verify_signature(&forged).is_err());
+
+        // Opaque tokens from older relays
+        assert!(verifier.verify_signature(&format!("{}_client-001", Uuid::new_v4())).is_err());
+        assert!(verifier.verify_signature("").is_err());
+    }
+
+    #[test]
+    fn test_held_token_lifetime() {
+        let signer = TokenSigner::generate().unwrap();
+
+        // Timed from arrival, whatever the issue time says
+        let held = HeldToken::new(signer.sign(&claims(0, 600)).unwrap());
+        assert!(!held.is_expired());
+        assert!(held.remaining().unwrap() > Duration::from_secs(590));
+
+        let held = HeldToken::new(signer.sign(&claims(unix_now(), 0)).unwrap());
+        assert!(held.is_expired());
+
+        // Opaque tokens have no known lifetime
+        let held = HeldToken::new("opaque".to_string());
+        assert!(held.claims.is_none());
+        assert!(held.remaining().is_none());
+        assert!(!held.is_expired());
+    }
+}
@@ -1,8 +1,12 @@
-use crate::{NfsConfig, RemoteNfsServer, Result};
-use clap::{Parser, Subcommand};
-use remotefs_client::{Client, ClientError, ClientConfig, AgentConfig, ClientBehaviorConfig, ConnectionConfig, ReconnectionConfig, AuthConfig, AuthMethod, AuthCredentials, LoggingConfig, RetryStrategy, LoadBalancingStrategy};
+use crate::{indexing, launchd, mount, MountProfile, NfsConfig, RemoteNfsServer, ResolvedExport, Result};
+use clap::{Args, Parser, Subcommand};
+use remotefs_client::{Client, ClientConfig, AgentConfig, ClientBehaviorConfig, ConnectionConfig, ReconnectionConfig, AuthConfig, AuthMethod, AuthCredentials, LoggingConfig, RetryStrategy, LoadBalancingStrategy};
+use remotefs_common::crash::{self, RecentEvents};
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Parser)]
 #[command(name = "remotefs-nfs")]
@@ -18,17 +22,22 @@ pub struct Cli {
     pub verbose: bool,
     
     /// Override the NFS server host
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub host: Option<String>,
     
     /// Override the NFS server port
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub port: Option<u16>,
     
     /// Override agent endpoints (comma-separated)
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub agents: Option<String>,
     
+    /// Record the messages exchanged with agents to this file, for
+    /// replaying with `remotefs-client replay`
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -37,11 +46,19 @@ pub struct Cli {
 pub enum Commands {
     /// Start the NFS server
     Start,
-    /// Generate example configuration file
+    /// Generate, validate or show the configuration
     Config {
         #[command(subcommand)]
         action: ConfigAction,
     },
+    /// Generate a configuration file (same as `config generate`)
+    GenerateConfig(GenerateArgs),
+    /// Validate a configuration file (same as `config validate`)
+    ValidateConfig {
+        /// Configuration file to validate
+        #[arg(value_name = "FILE")]
+        config_file: Option<PathBuf>,
+    },
     /// Mount operations
     Mount {
         #[command(subcommand)]
@@ -49,12 +66,27 @@ pub enum Commands {
     },
     /// Check server status
     Status,
+    /// Manage the launchd service (macOS)
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ServiceAction {
+    /// Print the launchd plist without installing it
+    Plist,
+    /// Install and load the launchd agent so the server starts at login
+    Install,
+    /// Unload and remove the launchd agent
+    Uninstall,
 }
 
 #[derive(Subcommand)]
 pub enum ConfigAction {
-    /// Generate example configuration
-    Generate,
+    /// Generate a configuration file
+    Generate(GenerateArgs),
     /// Validate configuration
     Validate {
         /// Path to configuration file
@@ -64,6 +96,47 @@ pub enum ConfigAction {
     Show,
 }
 
+/// Options of `config generate`
+///
+/// Without any setup option the example configuration is written. With one,
+/// including the global `--host`, `--port` and `--agents`, the file serves a
+/// single export instead.
+#[derive(Args)]
+pub struct GenerateArgs {
+    /// Output file path (defaults to the standard configuration location)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    
+    /// Force overwrite existing file
+    #[arg(short, long)]
+    pub force: bool,
+    
+    /// Directory on the agents to serve (defaults to the agent root)
+    #[arg(long, value_name = "PATH")]
+    pub export_path: Option<String>,
+    
+    /// Name clients mount the export by, as `host:/<name>` (defaults to "remotefs")
+    #[arg(long, value_name = "NAME")]
+    pub export_name: Option<String>,
+    
+    /// Token to authenticate to the agents with
+    #[arg(long)]
+    pub token: Option<String>,
+    
+    /// Caching profile the mounts are tuned for
+    #[arg(long, value_enum)]
+    pub profile: Option<MountProfile>,
+    
+    /// Print the commands and the /etc/fstab line that mount the exports here
+    #[arg(long, value_name = "DIR")]
+    pub mount_point: Option<String>,
+    
+    /// Install the launchd agent so the server starts at login with the
+    /// generated configuration (macOS)
+    #[arg(long)]
+    pub install_service: bool,
+}
+
 #[derive(Subcommand)]
 pub enum MountAction {
     /// Show mount command
@@ -71,12 +144,18 @@ pub enum MountAction {
         /// Mount point directory
         #[arg(default_value = "/mnt/remotefs")]
         mount_point: String,
+        /// Export name (defaults to the first configured export)
+        #[arg(long)]
+        export: Option<String>,
     },
     /// Mount the filesystem (requires sudo)
     Mount {
         /// Mount point directory
         #[arg(default_value = "/mnt/remotefs")]
         mount_point: String,
+        /// Export name (defaults to the first configured export)
+        #[arg(long)]
+        export: Option<String>,
     },
     /// Unmount the filesystem (requires sudo)
     Unmount {
@@ -94,8 +173,11 @@ impl Cli {
         match &self.command {
             Some(Commands::Start) => self.start_server().await,
             Some(Commands::Config { action }) => self.handle_config(action),
+            Some(Commands::GenerateConfig(args)) => self.generate_config(args),
+            Some(Commands::ValidateConfig { config_file }) => self.validate_config(config_file.as_ref()),
             Some(Commands::Mount { action }) => self.handle_mount(action).await,
             Some(Commands::Status) => self.check_status().await,
+            Some(Commands::Service { action }) => self.handle_service(action),
             None => self.start_server().await, // Default action
         }
     }
@@ -114,6 +196,8 @@ impl Cli {
                 tracing_subscriber::EnvFilter::try_from_default_env()
                     .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(filter))
             )
+            .finish()
+            .with(RecentEvents)
             .init();
     }
     
@@ -130,29 +214,48 @@ impl Cli {
         config.validate()?;
         
         info!("Configuration loaded and validated");
+        crash::install("remotefs-nfs", &config.crash);
+        
+        // Create and initialize NFS server, sharing one client between
+        // exports that use the same agents
+        let exports = config.resolved_exports();
+        let mut clients: HashMap<Vec<String>, Arc<Client>> = HashMap::new();
+        let mut server = RemoteNfsServer::new(config.clone());
         
-        // Create RemoteFS client
-        let client_config = self.create_client_config(&config)?;
+        for export in exports {
+            let client = match clients.get(&export.agents) {
+                Some(client) => Arc::clone(client),
+                None => {
+                    let client = Arc::new(self.connect_client(&config, &export.agents).await?);
+                    clients.insert(export.agents.clone(), Arc::clone(&client));
+                    client
+                }
+            };
+            server.add_export(export, client).await?;
+        }
+        
+        // Start server (monitoring is done internally)
+        info!("Starting NFS server");
+        server.start().await
+    }
+    
+    /// Create a RemoteFS client for the given agents and connect it
+    async fn connect_client(&self, config: &NfsConfig, agents: &[String]) -> Result<Client> {
+        let client_config = self.create_client_config(config, agents)?;
         let client = Client::new(client_config)
             .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
                 format!("Failed to create client: {}", e)
             ))?;
         
         // Initialize client (connects to agents)
-        info!("Connecting to RemoteFS agents...");
+        info!("Connecting to RemoteFS agents: {}", agents.join(", "));
         client.initialize().await
             .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
                 format!("Failed to connect to agents: {}", e)
             ))?;
         info!("Successfully connected to agents");
         
-        // Create and initialize NFS server
-        let mut server = RemoteNfsServer::new(config);
-        server.initialize(client).await?;
-        
-        // Start server (monitoring is done internally)
-        info!("Starting NFS server");
-        server.start().await
+        Ok(client)
     }
     
     fn load_config(&self) -> Result<NfsConfig> {
@@ -179,21 +282,20 @@ impl Cli {
         }
     }
     
-    fn create_client_config(&self, config: &NfsConfig) -> Result<ClientConfig> {
+    fn create_client_config(&self, config: &NfsConfig, agents: &[String]) -> Result<ClientConfig> {
         // Convert agent URLs to AgentConfig structs
-        let agents: Vec<AgentConfig> = config.agents.iter().enumerate().map(|(i, url)| {
+        let agents: Vec<AgentConfig> = agents.iter().enumerate().map(|(i, url)| {
             AgentConfig {
                 id: format!("agent-{}", i),
                 url: url.clone(),
-                auth: if config.auth.enabled && config.auth.token.is_some() {
-                    Some(AuthConfig {
+                auth: match (&config.auth.token, config.auth.enabled) {
+                    (Some(token), true) => Some(AuthConfig {
                         method: AuthMethod::Token,
                         credentials: AuthCredentials::Token {
-                            token: config.auth.token.as_ref().unwrap().clone(),
+                            token: token.clone(),
                         },
-                    })
-                } else {
-                    None
+                    }),
+                    _ => None,
                 },
                 weight: 1,
                 enabled: true,
@@ -213,6 +315,7 @@ impl Cli {
                 enable_failover: true,
                 read_buffer_size: config.performance.read_buffer_size,
                 write_buffer_size: config.performance.write_buffer_size,
+                max_fallback_entries: 100_000,
             },
             connection: ConnectionConfig {
                 connect_timeout_ms: config.connection_timeout * 1000,
@@ -226,6 +329,8 @@ impl Cli {
                     max_delay_ms: 30000,
                     backoff_multiplier: 2.0,
                 },
+                local_socket: None,
+                simulate: None,
             },
             auth: None, // Auth is handled per-agent
             logging: LoggingConfig {
@@ -234,7 +339,10 @@ impl Cli {
                 file: None,
                 enable_connection_logs: self.verbose,
                 enable_performance_logs: self.verbose,
+                record_file: self.record.clone(),
             },
+            discovery: None,
+            path_rewrites: Vec::new(),
         };
         
         Ok(client_config)
@@ -242,22 +350,8 @@ impl Cli {
     
     fn handle_config(&self, action: &ConfigAction) -> Result<()> {
         match action {
-            ConfigAction::Generate => {
-                NfsConfig::create_example_config()?;
-                println!("Example configuration file created");
-                Ok(())
-            }
-            ConfigAction::Validate { path } => {
-                let config_path = path.as_ref()
-                    .cloned()
-                    .unwrap_or_else(|| NfsConfig::default_config_path());
-                    
-                let config = NfsConfig::from_file(&config_path)?;
-                config.validate()?;
-                
-                println!("Configuration is valid: {}", config_path.display());
-                Ok(())
-            }
+            ConfigAction::Generate(args) => self.generate_config(args),
+            ConfigAction::Validate { path } => self.validate_config(path.as_ref()),
             ConfigAction::Show => {
                 let config = self.load_config()?;
                 let toml_str = config.to_toml()?;
@@ -267,30 +361,175 @@ impl Cli {
         }
     }
     
+    /// Write a configuration file and print how to start and mount it
+    fn generate_config(&self, args: &GenerateArgs) -> Result<()> {
+        let config_path = args.output.clone().unwrap_or_else(NfsConfig::default_config_path);
+        let config = self.generated_config(args);
+        config.validate()?;
+        config.create_file(&config_path, args.force)?;
+        
+        println!("Generated configuration file: {}", config_path.display());
+        println!();
+        println!("Please review the configuration before starting the server:");
+        println!("  - Check the agent endpoints and the authentication token");
+        println!("  - Check the exports and the ports they listen on");
+        println!();
+        println!("Validate it with:");
+        println!("   remotefs-nfs --config {} validate-config", config_path.display());
+        println!("Start the server with:");
+        println!("   remotefs-nfs --config {} start", config_path.display());
+        
+        if let Some(mount_point) = &args.mount_point {
+            let exports = config.resolved_exports();
+            for export in &exports {
+                // Several exports each get a directory under the mount point
+                let mount_point = if exports.len() > 1 {
+                    format!("{}/{}", mount_point.trim_end_matches('/'), export.name)
+                } else {
+                    mount_point.clone()
+                };
+                println!();
+                println!("To mount {}:", mount::mount_source(export));
+                println!("   sudo mkdir -p \"{}\"", mount_point);
+                println!("   sudo mount -t nfs -o '{}' {} \"{}\"", mount::mount_options(export), mount::mount_source(export), mount_point);
+                println!("To mount it at boot, add to /etc/fstab:");
+                println!("   {}", mount::fstab_entry(export, &mount_point));
+            }
+        }
+        
+        if args.install_service {
+            let program = std::env::current_exe()?;
+            let plist_path = launchd::install(&program, Some(&config_path.canonicalize()?))?;
+            println!();
+            println!("Installed launchd agent: {}", plist_path.display());
+            println!("Logs: {}", launchd::log_path().display());
+        }
+        
+        Ok(())
+    }
+    
+    /// The configuration `config generate` writes for the given options
+    fn generated_config(&self, args: &GenerateArgs) -> NfsConfig {
+        let guided = args.export_path.is_some()
+            || args.export_name.is_some()
+            || args.token.is_some()
+            || args.profile.is_some()
+            || self.host.is_some()
+            || self.port.is_some()
+            || self.agents.is_some();
+        if !guided {
+            return NfsConfig::example_config();
+        }
+        
+        let mut config = NfsConfig::single_export(
+            NfsConfig::default().agents,
+            args.export_name.as_deref().unwrap_or("remotefs"),
+            args.export_path.as_deref().unwrap_or("/"),
+        );
+        self.apply_overrides(&mut config);
+        if let Some(token) = &args.token {
+            config.auth.enabled = true;
+            config.auth.token = Some(token.clone());
+        }
+        if let Some(profile) = args.profile {
+            config.profile = profile;
+        }
+        config
+    }
+    
+    /// Check a configuration file and summarize what it serves
+    fn validate_config(&self, path: Option<&PathBuf>) -> Result<()> {
+        let config_path = path
+            .or(self.config.as_ref())
+            .cloned()
+            .unwrap_or_else(NfsConfig::default_config_path);
+        
+        if !config_path.exists() {
+            return Err(remotefs_common::error::RemoteFsError::Configuration(format!(
+                "Configuration file does not exist: {}",
+                config_path.display()
+            )));
+        }
+        
+        let config = NfsConfig::from_file(&config_path)?;
+        config.validate()?;
+        
+        println!("Configuration is valid: {}", config_path.display());
+        println!();
+        println!("Configuration summary:");
+        println!("  Agents: {}", config.agents.join(", "));
+        println!("  Authentication: {}", if config.auth.enabled { "token" } else { "disabled" });
+        println!("  Profile: {:?}", config.profile);
+        println!("  Exports:");
+        for export in config.resolved_exports() {
+            println!("    {} -> {} on {}", mount::mount_source(&export), export.remote_path, export.agents.join(", "));
+            println!("      listening on {}, mount options {}", export.listen_address(), mount::mount_options(&export));
+        }
+        Ok(())
+    }
+    
+    fn handle_service(&self, action: &ServiceAction) -> Result<()> {
+        match action {
+            ServiceAction::Plist => {
+                let (program, config) = self.service_paths()?;
+                print!("{}", launchd::generate_plist(&program, config.as_deref(), &launchd::log_path()));
+                Ok(())
+            }
+            ServiceAction::Install => {
+                let (program, config) = self.service_paths()?;
+                let plist_path = launchd::install(&program, config.as_deref())?;
+                println!("Installed launchd agent: {}", plist_path.display());
+                println!("Logs: {}", launchd::log_path().display());
+                Ok(())
+            }
+            ServiceAction::Uninstall => {
+                let plist_path = launchd::uninstall()?;
+                println!("Removed launchd agent: {}", plist_path.display());
+                Ok(())
+            }
+        }
+    }
+    
+    /// Absolute paths to this binary and the config file for the launchd job
+    fn service_paths(&self) -> Result<(PathBuf, Option<PathBuf>)> {
+        let program = std::env::current_exe()?;
+        let config = match &self.config {
+            Some(path) => Some(path.canonicalize()?),
+            None => None,
+        };
+        Ok((program, config))
+    }
+    
     async fn handle_mount(&self, action: &MountAction) -> Result<()> {
         let config = self.load_config()?;
         
         match action {
-            MountAction::Show { mount_point } => {
+            MountAction::Show { mount_point, export } => {
+                let export = Self::select_export(&config, export.as_deref())?;
                 println!("To mount RemoteFS using NFS:");
                 println!();
                 println!("1. Create mount point:");
                 println!("   sudo mkdir -p {}", mount_point);
                 println!();
                 println!("2. Mount with NFS:");
-                println!("   sudo mount -t nfs -o vers=3,tcp,port={},mountport={} {}:/ {}", 
-                         config.port, config.port, config.host, mount_point);
+                println!("   sudo mount -t nfs -o vers=3,tcp,port={},mountport={} {}:{} {}", 
+                         export.port, export.port, export.bind_address, export.mount_path(), mount_point);
                 println!();
                 println!("3. To unmount:");
                 println!("   sudo umount {}", mount_point);
                 println!();
                 println!("For better performance, add these options:");
                 println!("   -o vers=3,tcp,port={},mountport={},rsize=1048576,wsize=1048576,async", 
-                         config.port, config.port);
+                         export.port, export.port);
                 Ok(())
             }
-            MountAction::Mount { mount_point } => {
-                self.mount_filesystem(&config, mount_point).await
+            MountAction::Mount { mount_point, export } => {
+                let export = Self::select_export(&config, export.as_deref())?;
+                self.mount_filesystem(&export, mount_point).await?;
+                if config.indexing.exclude {
+                    indexing::exclude(std::path::Path::new(mount_point), true);
+                }
+                Ok(())
             }
             MountAction::Unmount { mount_point } => {
                 self.unmount_filesystem(mount_point).await
@@ -298,14 +537,27 @@ impl Cli {
         }
     }
     
-    async fn mount_filesystem(&self, config: &NfsConfig, mount_point: &str) -> Result<()> {
+    /// Pick an export by name, or the first one when no name is given
+    fn select_export(config: &NfsConfig, name: Option<&str>) -> Result<ResolvedExport> {
+        let mut exports = config.resolved_exports().into_iter();
+        let export = match name {
+            Some(name) => exports.find(|e| e.name == name.trim_matches('/')),
+            None => exports.next(),
+        };
+        
+        export.ok_or_else(|| remotefs_common::error::RemoteFsError::Internal(
+            format!("Unknown export: {}", name.unwrap_or_default())
+        ))
+    }
+    
+    async fn mount_filesystem(&self, export: &ResolvedExport, mount_point: &str) -> Result<()> {
         use std::process::Command;
         
         info!("Mounting RemoteFS at {}", mount_point);
         
         // Create mount point
         let mkdir_output = Command::new("sudo")
-            .args(&["mkdir", "-p", mount_point])
+            .args(["mkdir", "-p", mount_point])
             .output()
             .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
                 format!("Failed to create mount point: {}", e)
@@ -319,63 +571,26 @@ impl Cli {
         }
         
         // Mount filesystem
-        let mount_opts = format!("vers=3,tcp,port={},mountport={},rsize=1048576,wsize=1048576,async", 
-                          config.port, config.port);
-        let host_path = format!("{}:/", config.host);
-        
-        let mount_output = Command::new("sudo")
-            .args(&["mount", "-t", "nfs", "-o", &mount_opts, &host_path, mount_point])
-            .output()
-            .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
-                format!("Failed to execute mount command: {}", e)
-            ))?;
-            
-        if mount_output.status.success() {
-            println!("Successfully mounted RemoteFS at {}", mount_point);
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&mount_output.stderr);
-            Err(remotefs_common::error::RemoteFsError::Internal(
-                format!("Failed to mount: {}", stderr)
-            ))
-        }
+        mount::mount(export, mount_point, true)?;
+        println!("Successfully mounted RemoteFS at {}", mount_point);
+        Ok(())
     }
     
     async fn unmount_filesystem(&self, mount_point: &str) -> Result<()> {
-        use std::process::Command;
-        
         info!("Unmounting RemoteFS from {}", mount_point);
         
-        let output = Command::new("sudo")
-            .args(&["umount", mount_point])
-            .output()
-            .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
-                format!("Failed to execute umount command: {}", e)
-            ))?;
-            
-        if output.status.success() {
-            println!("Successfully unmounted RemoteFS from {}", mount_point);
-            Ok(())
-        } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // Try force unmount
-            warn!("Normal unmount failed, trying force unmount: {}", stderr);
-            
-            let force_output = Command::new("sudo")
-                .args(&["umount", "-f", mount_point])
-                .output()
-                .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
-                    format!("Failed to execute force umount command: {}", e)
-                ))?;
+        match mount::unmount(mount_point, false, true) {
+            Ok(()) => {
+                println!("Successfully unmounted RemoteFS from {}", mount_point);
+                Ok(())
+            }
+            Err(e) => {
+                // Try force unmount
+                warn!("Normal unmount failed, trying force unmount: {}", e);
                 
-            if force_output.status.success() {
+                mount::unmount(mount_point, true, true)?;
                 println!("Successfully force unmounted RemoteFS from {}", mount_point);
                 Ok(())
-            } else {
-                let force_stderr = String::from_utf8_lossy(&force_output.stderr);
-                Err(remotefs_common::error::RemoteFsError::Internal(
-                    format!("Failed to unmount: {}", force_stderr)
-                ))
             }
         }
     }
@@ -387,45 +602,36 @@ impl Cli {
         println!("================================");
         println!();
         
-        // Check if NFS port is listening
+        // Check if each export's NFS port is listening
         use std::net::{TcpStream, SocketAddr};
         use std::time::Duration;
         
-        let addr: SocketAddr = format!("{}:{}", config.host, config.port)
-            .parse()
-            .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
-                format!("Invalid address: {}", e)
-            ))?;
-            
-        match TcpStream::connect_timeout(&addr, Duration::from_secs(5)) {
-            Ok(_) => {
-                println!("✓ NFS server is running on {}:{}", config.host, config.port);
-            }
-            Err(_) => {
-                println!("✗ NFS server is not running on {}:{}", config.host, config.port);
+        let exports = config.resolved_exports();
+        for export in &exports {
+            let addr: SocketAddr = export.listen_address()
+                .parse()
+                .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid address: {}", e)
+                ))?;
+                
+            match TcpStream::connect_timeout(&addr, Duration::from_secs(5)) {
+                Ok(_) => {
+                    println!("✓ NFS export {} is being served on {}", export.mount_path(), addr);
+                }
+                Err(_) => {
+                    println!("✗ NFS export {} is not being served on {}", export.mount_path(), addr);
+                }
             }
         }
         
         // Check mount points
-        use std::process::Command;
-        let output = Command::new("mount")
-            .output()
-            .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
-                format!("Failed to check mounts: {}", e)
-            ))?;
-            
-        let mount_output = String::from_utf8_lossy(&output.stdout);
-        let remotefs_mounts: Vec<&str> = mount_output
-            .lines()
-            .filter(|line| line.contains(&format!("{}:{}", config.host, config.port)))
-            .collect();
-            
+        let remotefs_mounts = mount::active_mounts(&exports)?;
         if remotefs_mounts.is_empty() {
             println!("✗ No RemoteFS mounts found");
         } else {
             println!("✓ Active RemoteFS mounts:");
-            for mount in remotefs_mounts {
-                println!("  {}", mount);
+            for active in remotefs_mounts {
+                println!("  {} on {}", mount::mount_source(&active.export), active.mount_point);
             }
         }
         
@@ -434,6 +640,15 @@ impl Cli {
         println!("  Host: {}", config.host);
         println!("  Port: {}", config.port);
         println!("  Agents: {}", config.agents.join(", "));
+        println!("  NFS version: {:?}", config.nfs_version);
+        if config.control.enabled {
+            println!("  Control API: http://{}:{}", config.control.bind_address, config.control.port);
+        }
+        println!("  Exports:");
+        for export in &exports {
+            println!("    {} -> {} on {} (agents: {})",
+                     export.mount_path(), export.remote_path, export.listen_address(), export.agents.join(", "));
+        }
         
         Ok(())
     }
@@ -1,3 +1,4 @@
+use remotefs_common::config::CrashConfig;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -33,10 +34,508 @@ pub struct NfsConfig {
     
     /// Performance settings
     pub performance: PerformanceConfig,
+    
+    /// NFS protocol version to serve
+    #[serde(default)]
+    pub nfs_version: NfsVersion,
+    
+    /// SELinux context to mount exports with (`context=` mount option), e.g.
+    /// `system_u:object_r:nfs_t:s0`; NFSv3 cannot carry per-file labels
+    #[serde(default)]
+    pub selinux_context: Option<String>,
+    
+    /// Permission bits cleared from the mode of every file and directory
+    /// created through the mounts, on top of the creating process's umask,
+    /// e.g. `0o027` to keep new files from other users
+    #[serde(default)]
+    pub umask: Option<u32>,
+    
+    /// How symlinks on the agents are presented through the mounts
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    
+    /// Exports to serve; when empty a single `/` export is served on `host:port`
+    #[serde(default)]
+    pub exports: Vec<ExportConfig>,
+    
+    /// Local control API used by menu-bar and other desktop integrations
+    #[serde(default)]
+    pub control: ControlConfig,
+    
+    /// Finder presentation settings
+    #[serde(default)]
+    pub finder: FinderConfig,
+    
+    /// Recovery after sleep/wake and network changes
+    #[serde(default)]
+    pub recovery: RecoveryConfig,
+    
+    /// Settings for mounts shared between local users
+    #[serde(default)]
+    pub sharing: SharingConfig,
+    
+    /// Cached directory listings
+    #[serde(default)]
+    pub directory_cache: DirectoryCacheConfig,
+    
+    /// Prefetching for sequential readers
+    #[serde(default)]
+    pub read_ahead: ReadAheadConfig,
+    
+    /// File handles kept valid across server restarts
+    #[serde(default)]
+    pub handles: HandlesConfig,
+    
+    /// Keeping desktop search indexers off mounts
+    #[serde(default)]
+    pub indexing: IndexingConfig,
+    
+    /// Kernel write-back and transfer sizes of the mounts
+    #[serde(default)]
+    pub transfer: TransferConfig,
+    
+    /// Named caching profile applied on top of the settings above
+    #[serde(default)]
+    pub profile: MountProfile,
+    
+    /// Reports written when the server panics
+    #[serde(default)]
+    pub crash: CrashConfig,
+}
+
+/// How the kernel NFS client moves data for the mounts
+///
+/// The kernel uses the smaller of a mount's `rsize` and `wsize` and the
+/// largest transfers the server advertises, so the server advertises the
+/// same sizes the mount options ask for. Sizes left unset follow the agent
+/// chunk size, so one NFS read or write is one agent request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransferConfig {
+    /// Let the kernel cache writes and send them in the background (`async`)
+    /// instead of writing through (`sync`); on unless the media profile
+    /// is selected
+    pub write_back: Option<bool>,
+    
+    /// Largest file range agents send or accept in one request, in KB
+    pub chunk_size_kb: u32,
+    
+    /// Largest read the kernel sends (`rsize`), in KB
+    pub max_read_kb: Option<u32>,
+    
+    /// Largest write the kernel sends (`wsize`), in KB
+    pub max_write_kb: Option<u32>,
+    
+    /// Reads the kernel makes ahead of a sequential reader on macOS
+    /// (`readahead`), up to 128; Linux sizes its read-ahead from `rsize`.
+    /// 128 with the media profile
+    pub readahead: Option<u32>,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            write_back: None,
+            chunk_size_kb: 1024,
+            max_read_kb: None,
+            max_write_kb: None,
+            readahead: None,
+        }
+    }
+}
+
+/// Largest transfer the kernel NFS client makes, in KB
+const MAX_TRANSFER_KB: u32 = 1024;
+
+/// Largest `readahead` macOS accepts
+const MAX_READAHEAD: u32 = 128;
+
+/// Kernel write-back and transfer sizes of a mount, with defaults filled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountTransfer {
+    pub write_back: bool,
+    /// `rsize` and the largest read the server advertises, in bytes
+    pub max_read: u32,
+    /// `wsize` and the largest write the server advertises, in bytes
+    pub max_write: u32,
+    /// macOS `readahead`, if set
+    pub readahead: Option<u32>,
+}
+
+impl Default for MountTransfer {
+    fn default() -> Self {
+        Self {
+            write_back: true,
+            max_read: MAX_TRANSFER_KB * 1024,
+            max_write: MAX_TRANSFER_KB * 1024,
+            readahead: None,
+        }
+    }
+}
+
+/// Directories left out of preloading by the `ide` profile: build output
+/// and dependency trees that are large and rarely browsed
+const IDE_PRELOAD_EXCLUDE: &[&str] = &[
+    "node_modules", "target", "build", "dist", "out", ".gradle", "__pycache__", ".venv",
+];
+
+/// Named bundle of caching settings, so common workloads need one config key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MountProfile {
+    /// Settings exactly as configured
+    #[default]
+    Default,
+    /// Code workspaces: directory cache on, the top four levels preloaded
+    /// except build and dependency directories, and mounts that cache
+    /// attributes for 30 seconds, cache negative lookups and use READDIRPLUS
+    Ide,
+    /// Video playback: read-ahead on with windows of at least 32 MB fetched
+    /// over at least 8 parallel ranged reads, and mounts that write through
+    /// instead of caching writes
+    Media,
+}
+
+/// Recovery after sleep/wake and network changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryConfig {
+    /// Watch for wake-ups and network changes
+    pub enabled: bool,
+    
+    /// How often to check, in seconds
+    pub check_interval_secs: u64,
+    
+    /// Wall-clock time beyond the check interval that counts as a sleep, in seconds
+    pub sleep_threshold_secs: u64,
+    
+    /// Force-unmount and remount active mounts after a wake; needs
+    /// passwordless sudo for mount and umount
+    pub remount_after_wake: bool,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            check_interval_secs: 5,
+            sleep_threshold_secs: 30,
+            remount_after_wake: true,
+        }
+    }
+}
+
+/// Directory listing cache, kept current from the agent's change journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DirectoryCacheConfig {
+    /// Serve repeat readdirs, lookups and getattrs from cached listings;
+    /// has no effect unless the agent keeps a change journal
+    pub enabled: bool,
+    
+    /// How often to poll the journal for changes, in milliseconds; changes
+    /// made by others can take this long to show up
+    pub refresh_interval_ms: u64,
+    
+    /// Levels of each export to list in the background when the server
+    /// starts, so the first scan of the tree is served from the cache; 0
+    /// disables preloading
+    pub preload_depth: u32,
+    
+    /// Stop preloading after this many directories
+    pub preload_max_directories: usize,
+    
+    /// Directory names not to descend into while preloading
+    pub preload_exclude: Vec<String>,
+}
+
+impl Default for DirectoryCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            refresh_interval_ms: 1000,
+            preload_depth: 0,
+            preload_max_directories: 2000,
+            preload_exclude: Vec::new(),
+        }
+    }
+}
+
+/// Read-ahead for files that are read sequentially
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadAheadConfig {
+    /// Prefetch ahead of readers detected as sequential
+    pub enabled: bool,
+    
+    /// Consecutive sequential reads of a file before prefetching starts
+    pub trigger_reads: u32,
+    
+    /// Size of each prefetched window, in megabytes
+    pub window_mb: u64,
+    
+    /// Ranged reads each window is split into and fetched over concurrently
+    pub parallelism: u32,
+    
+    /// Files tracked at once; the least recently read is dropped first
+    pub max_streams: usize,
+    
+    /// Seconds between checks of prefetched windows against the agent's
+    /// hashes; 0 disables scrubbing
+    pub scrub_interval_secs: u64,
+    
+    /// Windows checked per scrub, oldest checked first
+    pub scrub_windows: usize,
+}
+
+impl Default for ReadAheadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trigger_reads: 2,
+            window_mb: 8,
+            parallelism: 4,
+            max_streams: 8,
+            scrub_interval_secs: 60,
+            scrub_windows: 4,
+        }
+    }
+}
+
+/// File handles that stay valid when the server restarts
+///
+/// NFS clients hold on to the handles of files they have looked up. Unless
+/// the server can tell which file a handle from before a restart names,
+/// every open file and working directory on a mount goes stale until it is
+/// remounted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandlesConfig {
+    /// Save each export's file ids and reload them on start
+    pub persist: bool,
+    
+    /// Directory the ids are saved in (None = <data dir>/remotefs/nfs-handles)
+    pub directory: Option<PathBuf>,
+    
+    /// Seconds between saves of ids given out since the last one; they are
+    /// also saved on shutdown
+    pub save_interval_secs: u64,
+}
+
+impl Default for HandlesConfig {
+    fn default() -> Self {
+        Self {
+            persist: true,
+            directory: None,
+            save_interval_secs: 5,
+        }
+    }
+}
+
+impl HandlesConfig {
+    /// Directory the ids are saved in
+    pub fn directory(&self) -> PathBuf {
+        self.directory.clone().unwrap_or_else(|| remotefs_common::defaults::data_dir().join("nfs-handles"))
+    }
+}
+
+/// Finder presentation settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FinderConfig {
+    /// Report each file's birth time as its ctime so Finder shows real
+    /// creation dates; NFSv3 has no dedicated birth time attribute
+    pub birthtime_as_ctime: bool,
+}
+
+/// Keeping Spotlight, Tracker and Baloo from crawling mounts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexingConfig {
+    /// Exclude each mount from the system's indexers when it is mounted
+    pub exclude: bool,
+}
+
+impl Default for IndexingConfig {
+    fn default() -> Self {
+        Self { exclude: true }
+    }
+}
+
+/// Settings for mounts shared between local users
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SharingConfig {
+    /// Forward each request's uid/gid to the agent so it applies that
+    /// user's access rules; without it every local user of the mount gets
+    /// the agent-wide access
+    pub forward_caller_identity: bool,
+}
+
+/// Local control API configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControlConfig {
+    /// Serve the control API alongside the NFS exports
+    pub enabled: bool,
+    
+    /// Bind address; keep this on loopback, the API is unauthenticated
+    pub bind_address: String,
+    
+    /// Control API port
+    pub port: u16,
+    
+    /// Directory to write each export's per-user I/O breakdown to, as
+    /// `<export>.json` (`root.json` for the default export)
+    #[serde(default)]
+    pub stats_dir: Option<PathBuf>,
+    
+    /// How often the statistics files are rewritten
+    #[serde(default = "default_stats_interval_secs")]
+    pub stats_interval_secs: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            bind_address: "127.0.0.1".to_string(),
+            port: 9049,
+            stats_dir: None,
+            stats_interval_secs: default_stats_interval_secs(),
+        }
+    }
+}
+
+fn default_stats_interval_secs() -> u64 { 10 }
+
+/// NFS protocol version
+///
+/// zerofs_nfsserve only implements NFSv3 (plus MOUNT and portmap), so `v4`
+/// is accepted in configuration but rejected by validation until the backend
+/// grows NFSv4.0 COMPOUND support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NfsVersion {
+    #[default]
+    V3,
+    V4,
+}
+
+/// A single NFS export backed by RemoteFS agents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportConfig {
+    /// Export name; clients mount `host:/<name>`
+    pub name: String,
+    
+    /// Agent endpoint for this export (defaults to the top-level agents)
+    #[serde(default)]
+    pub agent: Option<String>,
+    
+    /// Directory on the agent exposed as the export root
+    #[serde(default = "default_remote_path")]
+    pub remote_path: String,
+    
+    /// Listener port (defaults to the top-level port)
+    #[serde(default)]
+    pub port: Option<u16>,
+    
+    /// Listener bind address (defaults to the top-level host)
+    #[serde(default)]
+    pub bind_address: Option<String>,
+    
+    /// SELinux context for the mounted files (defaults to the top-level context)
+    #[serde(default)]
+    pub selinux_context: Option<String>,
+    
+    /// Present each path the agent exports as a directory of the export
+    /// root instead of serving `remote_path`
+    #[serde(default)]
+    pub agent_exports: bool,
+    
+    /// Umask of files created through this export (defaults to the top-level umask)
+    #[serde(default)]
+    pub umask: Option<u32>,
+    
+    /// Symlink presentation of this export (defaults to the top-level policy)
+    #[serde(default)]
+    pub symlinks: Option<SymlinkPolicy>,
+}
+
+/// How a mount presents symlinks on the agent
+///
+/// An absolute target names a path on the agent, which means nothing, or
+/// something else, on the machine that mounts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Links with their targets exactly as stored on the agent
+    #[default]
+    AsIs,
+    /// Links appear as the files and directories they point to, resolved
+    /// by the agent; links whose target is missing stay links
+    Follow,
+    /// Absolute targets inside the export become relative ones, so they
+    /// point to the same file wherever the export is mounted; other
+    /// targets are left as they are
+    Rewrite,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+    
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "as_is" => Ok(Self::AsIs),
+            "follow" => Ok(Self::Follow),
+            "rewrite" => Ok(Self::Rewrite),
+            _ => Err(format!("expected as_is, follow or rewrite, got '{}'", value)),
+        }
+    }
+}
+
+/// An export with all defaults filled in from the top-level configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedExport {
+    pub name: String,
+    pub agents: Vec<String>,
+    pub remote_path: String,
+    pub bind_address: String,
+    pub port: u16,
+    /// SELinux context the mount labels every file with
+    pub selinux_context: Option<String>,
+    /// Caching profile the mount options are tuned for
+    pub profile: MountProfile,
+    /// Whether the root lists the agent's exports by name
+    pub agent_exports: bool,
+    /// Permission bits cleared from created files and directories
+    pub umask: u32,
+    /// How symlinks on the agent are presented
+    pub symlinks: SymlinkPolicy,
+    /// Kernel write-back and transfer sizes the mount is tuned for
+    pub transfer: MountTransfer,
+}
+
+impl ResolvedExport {
+    /// Path clients pass to `mount`
+    pub fn mount_path(&self) -> String {
+        format!("/{}", self.name.trim_matches('/'))
+    }
+    
+    /// Address the listener binds to
+    pub fn listen_address(&self) -> String {
+        format!("{}:{}", self.bind_address, self.port)
+    }
+}
+
+fn default_remote_path() -> String { "/".to_string() }
+
+/// `user:role:type[:level]`, with nothing that would break the mount option
+fn is_valid_selinux_context(context: &str) -> bool {
+    let fields: Vec<&str> = context.splitn(4, ':').collect();
+    fields.len() >= 3
+        && fields.iter().all(|field| !field.is_empty())
+        && !context.contains(|c: char| c == '"' || c.is_whitespace())
 }
 
 /// Authentication configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Default)]
 pub struct AuthConfig {
     /// Enable authentication
     pub enabled: bool,
@@ -71,8 +570,15 @@ pub struct PerformanceConfig {
     
     /// Enable compression
     pub compression_enabled: bool,
+    
+    /// NFS operations each mount sends to the agents at once; further
+    /// operations wait for one to finish. 0 for no limit
+    #[serde(default = "default_max_concurrent_operations")]
+    pub max_concurrent_operations: usize,
 }
 
+fn default_max_concurrent_operations() -> usize { 64 }
+
 impl Default for NfsConfig {
     fn default() -> Self {
         Self {
@@ -86,20 +592,26 @@ impl Default for NfsConfig {
             cache_dir: None,
             auth: AuthConfig::default(),
             performance: PerformanceConfig::default(),
+            nfs_version: NfsVersion::default(),
+            selinux_context: None,
+            umask: None,
+            symlinks: SymlinkPolicy::default(),
+            exports: vec![],
+            control: ControlConfig::default(),
+            finder: FinderConfig::default(),
+            recovery: RecoveryConfig::default(),
+            sharing: SharingConfig::default(),
+            directory_cache: DirectoryCacheConfig::default(),
+            read_ahead: ReadAheadConfig::default(),
+            handles: HandlesConfig::default(),
+            indexing: IndexingConfig::default(),
+            transfer: TransferConfig::default(),
+            profile: MountProfile::default(),
+            crash: CrashConfig::default(),
         }
     }
 }
 
-impl Default for AuthConfig {
-    fn default() -> Self {
-        Self {
-            enabled: false,
-            token: None,
-            cert_file: None,
-            key_file: None,
-        }
-    }
-}
 
 impl Default for PerformanceConfig {
     fn default() -> Self {
@@ -110,6 +622,7 @@ impl Default for PerformanceConfig {
             write_buffer_size: 64 * 1024, // 64KB
             connection_pool_size: 10,
             compression_enabled: true,
+            max_concurrent_operations: default_max_concurrent_operations(),
         }
     }
 }
@@ -180,27 +693,48 @@ impl NfsConfig {
         }
     }
     
-    /// Create example configuration file
-    pub fn create_example_config() -> crate::Result<()> {
-        let config_path = Self::default_config_path();
+    /// Write the configuration to a new file, creating its directory;
+    /// an existing file is only replaced with `force`
+    pub fn create_file(&self, path: &PathBuf, force: bool) -> crate::Result<()> {
+        if path.exists() && !force {
+            return Err(remotefs_common::error::RemoteFsError::Configuration(format!(
+                "Configuration file already exists: {}. Use --force to overwrite.",
+                path.display()
+            )));
+        }
         
         // Create directory if it doesn't exist
-        if let Some(parent) = config_path.parent() {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
             std::fs::create_dir_all(parent)
                 .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
                     format!("Failed to create config directory: {}", e)
                 ))?;
         }
         
-        let example_config = Self::example_config();
-        example_config.save_to_file(&config_path)?;
-        
-        println!("Created example configuration at {}", config_path.display());
-        Ok(())
+        self.save_to_file(path)
     }
     
-    /// Get example configuration with comments
-    fn example_config() -> Self {
+    /// Configuration serving one directory of the agents as the export `name`
+    pub fn single_export(agents: Vec<String>, name: &str, remote_path: &str) -> Self {
+        Self {
+            agents,
+            exports: vec![ExportConfig {
+                name: name.trim_matches('/').to_string(),
+                agent: None,
+                remote_path: remote_path.to_string(),
+                port: None,
+                bind_address: None,
+                selinux_context: None,
+                agent_exports: false,
+                umask: None,
+                symlinks: None,
+            }],
+            ..Self::default()
+        }
+    }
+    
+    /// Example configuration showing most settings
+    pub fn example_config() -> Self {
         Self {
             host: "0.0.0.0".to_string(), // Listen on all interfaces
             port: 2049,
@@ -226,7 +760,122 @@ impl NfsConfig {
                 write_buffer_size: 128 * 1024, // 128KB
                 connection_pool_size: 20,
                 compression_enabled: true,
+                max_concurrent_operations: 128,
+            },
+            nfs_version: NfsVersion::V3,
+            selinux_context: None,
+            umask: None,
+            symlinks: SymlinkPolicy::default(),
+            exports: vec![
+                ExportConfig {
+                    name: "local".to_string(),
+                    agent: Some("ws://127.0.0.1:8080".to_string()),
+                    remote_path: "/".to_string(),
+                    port: Some(2049),
+                    bind_address: None,
+                    selinux_context: None,
+                    agent_exports: false,
+                    umask: None,
+                    symlinks: None,
+                },
+                ExportConfig {
+                    name: "projects".to_string(),
+                    agent: Some("ws://remote-agent:8080".to_string()),
+                    remote_path: "/home/user/projects".to_string(),
+                    port: Some(2050),
+                    bind_address: None,
+                    selinux_context: None,
+                    agent_exports: false,
+                    umask: None,
+                    symlinks: None,
+                },
+            ],
+            control: ControlConfig::default(),
+            finder: FinderConfig::default(),
+            recovery: RecoveryConfig::default(),
+            sharing: SharingConfig::default(),
+            directory_cache: DirectoryCacheConfig::default(),
+            read_ahead: ReadAheadConfig::default(),
+            handles: HandlesConfig::default(),
+            indexing: IndexingConfig::default(),
+            transfer: TransferConfig::default(),
+            profile: MountProfile::default(),
+            crash: CrashConfig::default(),
+        }
+    }
+    
+    /// Exports to serve, with defaults taken from the top-level settings
+    pub fn resolved_exports(&self) -> Vec<ResolvedExport> {
+        if self.exports.is_empty() {
+            return vec![ResolvedExport {
+                name: String::new(),
+                agents: self.agents.clone(),
+                remote_path: default_remote_path(),
+                bind_address: self.host.clone(),
+                port: self.port,
+                selinux_context: self.selinux_context.clone(),
+                profile: self.profile,
+                agent_exports: false,
+                umask: self.umask.unwrap_or(0),
+                symlinks: self.symlinks,
+                transfer: self.transfer(),
+            }];
+        }
+        
+        self.exports.iter().map(|export| ResolvedExport {
+            name: export.name.clone(),
+            agents: match &export.agent {
+                Some(agent) => vec![agent.clone()],
+                None => self.agents.clone(),
             },
+            remote_path: export.remote_path.clone(),
+            bind_address: export.bind_address.clone().unwrap_or_else(|| self.host.clone()),
+            port: export.port.unwrap_or(self.port),
+            selinux_context: export.selinux_context.clone().or_else(|| self.selinux_context.clone()),
+            profile: self.profile,
+            agent_exports: export.agent_exports,
+            umask: export.umask.or(self.umask).unwrap_or(0),
+            symlinks: export.symlinks.unwrap_or(self.symlinks),
+            transfer: self.transfer(),
+        }).collect()
+    }
+    
+    /// Directory cache settings with the profile applied
+    pub fn directory_cache(&self) -> DirectoryCacheConfig {
+        let mut cache = self.directory_cache.clone();
+        if self.profile == MountProfile::Ide {
+            cache.enabled = true;
+            cache.preload_depth = cache.preload_depth.max(4);
+            cache.preload_max_directories = cache.preload_max_directories.max(5000);
+            for dir in IDE_PRELOAD_EXCLUDE {
+                if !cache.preload_exclude.iter().any(|excluded| excluded == dir) {
+                    cache.preload_exclude.push(dir.to_string());
+                }
+            }
+        }
+        cache
+    }
+    
+    /// Read-ahead settings with the profile applied
+    pub fn read_ahead(&self) -> ReadAheadConfig {
+        let mut read_ahead = self.read_ahead.clone();
+        if self.profile == MountProfile::Media {
+            read_ahead.enabled = true;
+            read_ahead.window_mb = read_ahead.window_mb.max(32);
+            read_ahead.parallelism = read_ahead.parallelism.max(8);
+        }
+        read_ahead
+    }
+    
+    /// Transfer settings with the profile and chunk size applied
+    pub fn transfer(&self) -> MountTransfer {
+        let media = self.profile == MountProfile::Media;
+        let chunk_kb = self.transfer.chunk_size_kb.min(MAX_TRANSFER_KB);
+        MountTransfer {
+            write_back: self.transfer.write_back.unwrap_or(!media),
+            max_read: self.transfer.max_read_kb.unwrap_or(chunk_kb) * 1024,
+            max_write: self.transfer.max_write_kb.unwrap_or(chunk_kb) * 1024,
+            readahead: self.transfer.readahead.or(media.then_some(MAX_READAHEAD)),
         }
     }
     
@@ -251,7 +900,7 @@ impl NfsConfig {
         }
         
         // Validate agent URLs
-        for agent in &self.agents {
+        for agent in self.agents.iter().chain(self.exports.iter().filter_map(|e| e.agent.as_ref())) {
             if !agent.starts_with("ws://") && !agent.starts_with("wss://") {
                 return Err(remotefs_common::error::RemoteFsError::Internal(
                     format!("Invalid agent URL (must start with ws:// or wss://): {}", agent)
@@ -259,6 +908,115 @@ impl NfsConfig {
             }
         }
         
+        if self.control.enabled && self.control.port == 0 {
+            return Err(remotefs_common::error::RemoteFsError::Internal(
+                "Control API port must be greater than 0".to_string()
+            ));
+        }
+        
+        if self.recovery.enabled && self.recovery.check_interval_secs == 0 {
+            return Err(remotefs_common::error::RemoteFsError::Internal(
+                "Recovery check interval must be greater than 0".to_string()
+            ));
+        }
+        
+        self.validate_transfer()?;
+        
+        if self.nfs_version == NfsVersion::V4 {
+            return Err(remotefs_common::error::RemoteFsError::Internal(
+                "NFSv4 is not supported yet; the NFS backend only implements NFSv3".to_string()
+            ));
+        }
+        
+        self.validate_exports()
+    }
+    
+    /// Validate transfer sizes against what the kernel NFS client accepts
+    fn validate_transfer(&self) -> crate::Result<()> {
+        let sizes = [
+            ("chunk_size_kb", Some(self.transfer.chunk_size_kb)),
+            ("max_read_kb", self.transfer.max_read_kb),
+            ("max_write_kb", self.transfer.max_write_kb),
+        ];
+        for (name, size) in sizes {
+            if let Some(size) = size.filter(|size| *size == 0 || size % 4 != 0) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid transfer {} {}: must be a positive multiple of 4", name, size)
+                ));
+            }
+        }
+        for (name, size) in [("max_read_kb", self.transfer.max_read_kb), ("max_write_kb", self.transfer.max_write_kb)] {
+            if let Some(size) = size.filter(|size| *size > MAX_TRANSFER_KB) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid transfer {} {}: the kernel sends at most {} KB", name, size, MAX_TRANSFER_KB)
+                ));
+            }
+        }
+        if let Some(readahead) = self.transfer.readahead.filter(|readahead| *readahead > MAX_READAHEAD) {
+            return Err(remotefs_common::error::RemoteFsError::Internal(
+                format!("Invalid transfer readahead {}: at most {}", readahead, MAX_READAHEAD)
+            ));
+        }
+        Ok(())
+    }
+    
+    /// Validate export names and listener addresses
+    fn validate_exports(&self) -> crate::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        let mut addresses = std::collections::HashSet::new();
+        
+        for export in &self.exports {
+            let name = export.name.trim_matches('/');
+            if name.is_empty() || name.contains('/') {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid export name '{}': must be a single non-empty path component", export.name)
+                ));
+            }
+            
+            if !names.insert(name.to_string()) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Duplicate export name: {}", name)
+                ));
+            }
+            
+            if !export.remote_path.starts_with('/') {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Export '{}' remote_path must be absolute: {}", name, export.remote_path)
+                ));
+            }
+            
+            if export.port == Some(0) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Export '{}' port must be greater than 0", name)
+                ));
+            }
+        }
+        
+        for context in self.exports.iter().filter_map(|e| e.selinux_context.as_ref()).chain(&self.selinux_context) {
+            if !is_valid_selinux_context(context) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid SELinux context '{}': expected user:role:type[:level]", context)
+                ));
+            }
+        }
+        
+        for umask in self.exports.iter().filter_map(|e| e.umask).chain(self.umask) {
+            if umask > 0o7777 {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Invalid umask {:o}: only permission bits can be cleared", umask)
+                ));
+            }
+        }
+        
+        // Each listener serves exactly one export, so listen addresses must be distinct
+        for export in self.resolved_exports() {
+            if !addresses.insert(export.listen_address()) {
+                return Err(remotefs_common::error::RemoteFsError::Internal(
+                    format!("Export '{}' shares listen address {} with another export", export.name, export.listen_address())
+                ));
+            }
+        }
+        
         Ok(())
     }
 }
@@ -303,6 +1061,27 @@ mod tests {
         assert_eq!(config.port, loaded_config.port);
     }
     
+    #[test]
+    fn test_create_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("remotefs").join("macos.toml");
+        let config = NfsConfig::single_export(vec!["ws://files:8080".to_string()], "/projects/", "/srv/projects");
+        config.create_file(&path, false).unwrap();
+        
+        let loaded = NfsConfig::from_file(&path).unwrap();
+        assert!(loaded.validate().is_ok());
+        let exports = loaded.resolved_exports();
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].mount_path(), "/projects");
+        assert_eq!(exports[0].remote_path, "/srv/projects");
+        assert_eq!(exports[0].agents, vec!["ws://files:8080".to_string()]);
+        
+        // An existing file is only replaced when forced
+        assert!(NfsConfig::default().create_file(&path, false).is_err());
+        NfsConfig::default().create_file(&path, true).unwrap();
+        assert!(NfsConfig::from_file(&path).unwrap().exports.is_empty());
+    }
+    
     #[test]
     fn test_config_validation() {
         // Valid config
@@ -315,13 +1094,238 @@ mod tests {
         assert!(invalid_config.validate().is_err());
         
         // Invalid config - port 0
-        let mut invalid_config = NfsConfig::default();
-        invalid_config.port = 0;
+        let invalid_config = NfsConfig { port: 0, ..Default::default() };
         assert!(invalid_config.validate().is_err());
         
         // Invalid config - bad agent URL
-        let mut invalid_config = NfsConfig::default();
-        invalid_config.agents = vec!["http://invalid".to_string()];
+        let invalid_config = NfsConfig {
+            agents: vec!["http://invalid".to_string()],
+            ..Default::default()
+        };
         assert!(invalid_config.validate().is_err());
     }
+    
+    fn export(name: &str, port: Option<u16>) -> ExportConfig {
+        ExportConfig {
+            name: name.to_string(),
+            agent: None,
+            remote_path: "/".to_string(),
+            port,
+            bind_address: None,
+            selinux_context: None,
+            agent_exports: false,
+            umask: None,
+            symlinks: None,
+        }
+    }
+    
+    #[test]
+    fn test_selinux_context() {
+        let config = NfsConfig {
+            selinux_context: Some("system_u:object_r:nfs_t:s0".to_string()),
+            exports: vec![
+                export("home", None),
+                ExportConfig {
+                    selinux_context: Some("system_u:object_r:public_content_t:s0".to_string()),
+                    ..export("public", Some(2050))
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        
+        let exports = config.resolved_exports();
+        assert_eq!(exports[0].selinux_context.as_deref(), Some("system_u:object_r:nfs_t:s0"));
+        assert_eq!(exports[1].selinux_context.as_deref(), Some("system_u:object_r:public_content_t:s0"));
+        
+        for invalid in ["nfs_t", "system_u::nfs_t", "system_u:object_r:nfs_t:s0\",x"] {
+            let config = NfsConfig { selinux_context: Some(invalid.to_string()), ..Default::default() };
+            assert!(config.validate().is_err(), "{} should be rejected", invalid);
+        }
+    }
+    
+    #[test]
+    fn test_umask() {
+        let config = NfsConfig {
+            exports: vec![export("home", None), ExportConfig { umask: Some(0o077), ..export("private", Some(2050)) }],
+            ..Default::default()
+        };
+        let exports = config.resolved_exports();
+        assert_eq!((exports[0].umask, exports[1].umask), (0, 0o077));
+        
+        let config = NfsConfig { umask: Some(0o027), ..config };
+        assert!(config.validate().is_ok());
+        let exports = config.resolved_exports();
+        assert_eq!((exports[0].umask, exports[1].umask), (0o027, 0o077));
+        
+        let config = NfsConfig { umask: Some(0o10000), ..Default::default() };
+        assert!(config.validate().is_err());
+    }
+    
+    #[test]
+    fn test_symlinks() {
+        let config = NfsConfig {
+            symlinks: SymlinkPolicy::Rewrite,
+            exports: vec![export("home", None), ExportConfig { symlinks: Some(SymlinkPolicy::Follow), ..export("data", Some(2050)) }],
+            ..Default::default()
+        };
+        let exports = config.resolved_exports();
+        assert_eq!((exports[0].symlinks, exports[1].symlinks), (SymlinkPolicy::Rewrite, SymlinkPolicy::Follow));
+        
+        let config: NfsConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(config.symlinks, SymlinkPolicy::Rewrite);
+        assert_eq!("as_is".parse(), Ok(SymlinkPolicy::AsIs));
+        assert!("sometimes".parse::<SymlinkPolicy>().is_err());
+    }
+    
+    #[test]
+    fn test_ide_profile() {
+        let config = NfsConfig {
+            profile: MountProfile::Ide,
+            directory_cache: DirectoryCacheConfig {
+                preload_depth: 6,
+                preload_exclude: vec!["vendor".to_string()],
+                ..DirectoryCacheConfig::default()
+            },
+            ..NfsConfig::default()
+        };
+        let parsed = NfsConfig::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.profile, MountProfile::Ide);
+        assert_eq!(config.resolved_exports()[0].profile, MountProfile::Ide);
+
+        let cache = config.directory_cache();
+        assert!(cache.enabled);
+        assert_eq!(cache.preload_depth, 6);
+        assert_eq!(cache.preload_max_directories, 5000);
+        assert!(cache.preload_exclude.iter().any(|dir| dir == "vendor"));
+        assert!(cache.preload_exclude.iter().any(|dir| dir == "node_modules"));
+
+        // Without a profile the settings are used as configured
+        let cache = NfsConfig::default().directory_cache();
+        assert_eq!(cache.preload_depth, 0);
+        assert!(cache.preload_exclude.is_empty());
+    }
+
+    #[test]
+    fn test_media_profile() {
+        let config = NfsConfig {
+            profile: MountProfile::Media,
+            read_ahead: ReadAheadConfig { window_mb: 64, ..ReadAheadConfig::default() },
+            ..NfsConfig::default()
+        };
+        let parsed = NfsConfig::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.profile, MountProfile::Media);
+
+        let read_ahead = config.read_ahead();
+        assert!(read_ahead.enabled);
+        assert_eq!(read_ahead.window_mb, 64);
+        assert_eq!(read_ahead.parallelism, 8);
+
+        assert!(!NfsConfig::default().read_ahead().enabled);
+    }
+
+    #[test]
+    fn test_transfer() {
+        assert_eq!(NfsConfig::default().transfer(), MountTransfer::default());
+
+        let media = NfsConfig { profile: MountProfile::Media, ..NfsConfig::default() };
+        let transfer = media.transfer();
+        assert!(!transfer.write_back);
+        assert_eq!(transfer.readahead, Some(128));
+
+        // Unset sizes follow the chunk size, up to what the kernel sends
+        let mut config = NfsConfig::default();
+        config.transfer.chunk_size_kb = 256;
+        config.transfer.max_write_kb = Some(64);
+        config.transfer.write_back = Some(false);
+        let parsed = NfsConfig::from_toml(&config.to_toml().unwrap()).unwrap();
+        let transfer = parsed.transfer();
+        assert_eq!((transfer.max_read, transfer.max_write), (256 * 1024, 64 * 1024));
+        assert!(!transfer.write_back);
+        config.transfer.chunk_size_kb = 4096;
+        assert_eq!(config.transfer().max_read, 1024 * 1024);
+        assert!(config.validate().is_ok());
+
+        config.transfer.max_read_kb = Some(2048);
+        assert!(config.validate().is_err());
+        config.transfer.max_read_kb = Some(6);
+        assert!(config.validate().is_err());
+        config.transfer.max_read_kb = None;
+        config.transfer.readahead = Some(256);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_default_single_export() {
+        let config = NfsConfig::default();
+        let exports = config.resolved_exports();
+        
+        assert_eq!(exports.len(), 1);
+        assert_eq!(exports[0].mount_path(), "/");
+        assert_eq!(exports[0].listen_address(), "127.0.0.1:2049");
+        assert_eq!(exports[0].agents, config.agents);
+    }
+    
+    #[test]
+    fn test_multiple_exports() {
+        let config = NfsConfig {
+            host: "0.0.0.0".to_string(),
+            exports: vec![
+                export("home", None),
+                ExportConfig {
+                    agent: Some("ws://other:8080".to_string()),
+                    remote_path: "/srv/data".to_string(),
+                    bind_address: Some("127.0.0.1".to_string()),
+                    ..export("data", Some(2050))
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        
+        let exports = config.resolved_exports();
+        assert_eq!(exports[0].mount_path(), "/home");
+        assert_eq!(exports[0].listen_address(), "0.0.0.0:2049");
+        assert_eq!(exports[0].agents, config.agents);
+        assert_eq!(exports[1].listen_address(), "127.0.0.1:2050");
+        assert_eq!(exports[1].agents, vec!["ws://other:8080".to_string()]);
+        assert_eq!(exports[1].remote_path, "/srv/data");
+        
+        // Round-trips through TOML
+        let parsed = NfsConfig::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.resolved_exports(), exports);
+    }
+    
+    #[test]
+    fn test_export_validation() {
+        // Two exports on the same listener
+        let config = NfsConfig {
+            exports: vec![export("a", None), export("b", None)],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        
+        // Duplicate names
+        let config = NfsConfig {
+            exports: vec![export("a", Some(2050)), export("a", Some(2051))],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        
+        // Nested export names are not supported
+        let config = NfsConfig {
+            exports: vec![export("a/b", None)],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+    
+    #[test]
+    fn test_nfs_version() {
+        let config = NfsConfig::from_toml(&NfsConfig::default().to_toml().unwrap()).unwrap();
+        assert_eq!(config.nfs_version, NfsVersion::V3);
+        
+        let config = NfsConfig { nfs_version: NfsVersion::V4, ..Default::default() };
+        assert!(config.validate().is_err());
+    }
 }
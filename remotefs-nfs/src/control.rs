@@ -0,0 +1,409 @@
+//! Local control API for desktop integrations
+//!
+//! A menu-bar companion (or any other local tool) polls this JSON API for
+//! server status and recent errors, and uses it to enable or disable
+//! individual exports without restarting the server.
+
+use crate::io_stats::{CallerIo, IoAccounting};
+use crate::readahead::{ReadAhead, ScrubStats};
+use crate::{ControlConfig, NfsVersion, ResolvedExport, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use remotefs_client::Client;
+use remotefs_common::protocol::MaintenanceWindow;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::{watch, RwLock};
+use tracing::info;
+
+/// Number of errors kept for `/errors`
+pub const MAX_RECENT_ERRORS: usize = 50;
+
+/// Status of a single export as reported to control clients
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExportStatus {
+    pub name: String,
+    pub mount_path: String,
+    pub remote_path: String,
+    pub listen_address: String,
+    pub agents: Vec<String>,
+    /// Whether the export should be served
+    pub enabled: bool,
+    /// Whether the export's listener is currently accepting connections
+    pub serving: bool,
+    /// Maintenance underway on the export's agents, during which the export
+    /// is read-only and served from cache
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceWindow>,
+}
+
+/// An error surfaced to control clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub timestamp: DateTime<Utc>,
+    /// Mount path of the export the error relates to, if any
+    pub export: Option<String>,
+    pub message: String,
+}
+
+/// Response body for `/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub version: String,
+    pub nfs_version: NfsVersion,
+    pub started_at: DateTime<Utc>,
+    pub uptime_secs: i64,
+    pub exports: Vec<ExportStatus>,
+    pub recent_error_count: usize,
+}
+
+struct ExportEntry {
+    status: ExportStatus,
+    enabled_tx: watch::Sender<bool>,
+    io: Option<Arc<IoAccounting>>,
+    client: Option<Arc<Client>>,
+    read_ahead: Option<Arc<ReadAhead>>,
+}
+
+struct ControlInner {
+    nfs_version: NfsVersion,
+    started_at: DateTime<Utc>,
+    exports: RwLock<BTreeMap<String, ExportEntry>>,
+    errors: RwLock<VecDeque<ErrorRecord>>,
+}
+
+/// Shared state between the NFS listeners and the control API
+#[derive(Clone)]
+pub struct ControlState {
+    inner: Arc<ControlInner>,
+}
+
+impl ControlState {
+    pub fn new(nfs_version: NfsVersion) -> Self {
+        Self {
+            inner: Arc::new(ControlInner {
+                nfs_version,
+                started_at: Utc::now(),
+                exports: RwLock::new(BTreeMap::new()),
+                errors: RwLock::new(VecDeque::with_capacity(MAX_RECENT_ERRORS)),
+            }),
+        }
+    }
+
+    /// Track an export and return a receiver that follows its enabled flag
+    pub async fn register_export(&self, export: &ResolvedExport) -> watch::Receiver<bool> {
+        let (enabled_tx, enabled_rx) = watch::channel(true);
+        let status = ExportStatus {
+            name: export.name.clone(),
+            mount_path: export.mount_path(),
+            remote_path: export.remote_path.clone(),
+            listen_address: export.listen_address(),
+            agents: export.agents.clone(),
+            enabled: true,
+            serving: false,
+            maintenance: None,
+        };
+
+        let mut exports = self.inner.exports.write().await;
+        exports.insert(status.mount_path.clone(), ExportEntry { status, enabled_tx, io: None, client: None, read_ahead: None });
+        enabled_rx
+    }
+
+    /// Report an export's per-user I/O through `/exports/{name}/io`
+    pub async fn track_io(&self, export: &str, io: Arc<IoAccounting>) {
+        let mut exports = self.inner.exports.write().await;
+        if let Some(entry) = exports.get_mut(&mount_path(export)) {
+            entry.io = Some(io);
+        }
+    }
+
+    /// Report maintenance announced to an export's client in its status
+    pub async fn track_client(&self, export: &str, client: Arc<Client>) {
+        let mut exports = self.inner.exports.write().await;
+        if let Some(entry) = exports.get_mut(&mount_path(export)) {
+            entry.client = Some(client);
+        }
+    }
+
+    /// Report scrubbing of an export's read-ahead through `/exports/{name}/scrub`
+    pub async fn track_read_ahead(&self, export: &str, read_ahead: Arc<ReadAhead>) {
+        let mut exports = self.inner.exports.write().await;
+        if let Some(entry) = exports.get_mut(&mount_path(export)) {
+            entry.read_ahead = Some(read_ahead);
+        }
+    }
+
+    /// What the scrubber has done for an export's read-ahead
+    ///
+    /// Returns `None` if no such export exists; an export without read-ahead
+    /// reports nothing scrubbed.
+    pub async fn scrub_stats(&self, export: &str) -> Option<ScrubStats> {
+        let exports = self.inner.exports.read().await;
+        let entry = exports.get(&mount_path(export))?;
+        Some(entry.read_ahead.as_ref().map(|read_ahead| read_ahead.scrub_stats()).unwrap_or_default())
+    }
+
+    /// I/O of an export by local user, busiest first
+    ///
+    /// Returns `None` if no such export exists; an export whose I/O is not
+    /// tracked reports no users.
+    pub async fn io(&self, export: &str) -> Option<Vec<CallerIo>> {
+        let exports = self.inner.exports.read().await;
+        let entry = exports.get(&mount_path(export))?;
+        Some(entry.io.as_ref().map(|io| io.top()).unwrap_or_default())
+    }
+
+    /// Enable or disable an export by name or mount path
+    ///
+    /// Returns the updated status, or `None` if no such export exists.
+    pub async fn set_enabled(&self, export: &str, enabled: bool) -> Option<ExportStatus> {
+        let mut exports = self.inner.exports.write().await;
+        let entry = exports.get_mut(&mount_path(export))?;
+
+        entry.status.enabled = enabled;
+        entry.enabled_tx.send_replace(enabled);
+        info!(
+            "Export {} {} via control API",
+            entry.status.mount_path,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        Some(entry.status.clone())
+    }
+
+    /// Record whether an export's listener is accepting connections
+    pub async fn set_serving(&self, export: &str, serving: bool) {
+        let mut exports = self.inner.exports.write().await;
+        if let Some(entry) = exports.get_mut(&mount_path(export)) {
+            entry.status.serving = serving;
+        }
+    }
+
+    /// Remember an error, dropping the oldest once `MAX_RECENT_ERRORS` is reached
+    pub async fn record_error(&self, export: Option<&str>, message: impl Into<String>) {
+        let mut errors = self.inner.errors.write().await;
+        if errors.len() == MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(ErrorRecord {
+            timestamp: Utc::now(),
+            export: export.map(mount_path),
+            message: message.into(),
+        });
+    }
+
+    pub async fn exports(&self) -> Vec<ExportStatus> {
+        let exports = self.inner.exports.read().await;
+        let mut statuses = Vec::with_capacity(exports.len());
+        for entry in exports.values() {
+            let mut status = entry.status.clone();
+            if let Some(client) = &entry.client {
+                status.maintenance = client.active_maintenance().await;
+            }
+            statuses.push(status);
+        }
+        statuses
+    }
+
+    /// One line per export for crash reports, without waiting for locks
+    pub fn snapshot(&self) -> String {
+        let Ok(exports) = self.inner.exports.try_read() else {
+            return "unavailable, the export table is locked".to_string();
+        };
+        exports.values()
+            .map(|entry| {
+                let status = &entry.status;
+                format!(
+                    "{} -> {} on {} enabled={} serving={}",
+                    status.mount_path, status.remote_path, status.listen_address, status.enabled, status.serving
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Recent errors, newest first
+    pub async fn recent_errors(&self) -> Vec<ErrorRecord> {
+        let errors = self.inner.errors.read().await;
+        errors.iter().rev().cloned().collect()
+    }
+
+    pub async fn status(&self) -> ServerStatus {
+        let started_at = self.inner.started_at;
+        ServerStatus {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            nfs_version: self.inner.nfs_version,
+            started_at,
+            uptime_secs: (Utc::now() - started_at).num_seconds(),
+            exports: self.exports().await,
+            recent_error_count: self.inner.errors.read().await.len(),
+        }
+    }
+
+    /// Build the control API router
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/health", get(health_handler))
+            .route("/status", get(status_handler))
+            .route("/exports", get(exports_handler))
+            .route("/exports/:name/enable", post(enable_handler))
+            .route("/exports/:name/disable", post(disable_handler))
+            .route("/exports/:name/io", get(io_handler))
+            .route("/exports/:name/scrub", get(scrub_handler))
+            .route("/errors", get(errors_handler))
+            .with_state(self.clone())
+    }
+
+    /// Serve the control API until the task is cancelled
+    pub async fn serve(&self, config: &ControlConfig) -> Result<()> {
+        let addr = format!("{}:{}", config.bind_address, config.port);
+        let listener = tokio::net::TcpListener::bind(&addr).await.map_err(|e| {
+            remotefs_common::error::RemoteFsError::Network(
+                format!("Failed to bind control API on {}: {}", addr, e)
+            )
+        })?;
+
+        info!("Control API listening on http://{}", addr);
+        axum::serve(listener, self.router()).await.map_err(|e| {
+            remotefs_common::error::RemoteFsError::Network(format!("Control API error: {}", e))
+        })
+    }
+}
+
+/// Normalize an export name or mount path to the mount path key
+fn mount_path(export: &str) -> String {
+    format!("/{}", export.trim_matches('/'))
+}
+
+async fn health_handler() -> &'static str {
+    "OK"
+}
+
+async fn status_handler(State(state): State<ControlState>) -> Json<ServerStatus> {
+    Json(state.status().await)
+}
+
+async fn exports_handler(State(state): State<ControlState>) -> Json<Vec<ExportStatus>> {
+    Json(state.exports().await)
+}
+
+async fn errors_handler(State(state): State<ControlState>) -> Json<Vec<ErrorRecord>> {
+    Json(state.recent_errors().await)
+}
+
+async fn enable_handler(
+    State(state): State<ControlState>,
+    Path(name): Path<String>,
+) -> std::result::Result<Json<ExportStatus>, StatusCode> {
+    state.set_enabled(&name, true).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn disable_handler(
+    State(state): State<ControlState>,
+    Path(name): Path<String>,
+) -> std::result::Result<Json<ExportStatus>, StatusCode> {
+    state.set_enabled(&name, false).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn io_handler(
+    State(state): State<ControlState>,
+    Path(name): Path<String>,
+) -> std::result::Result<Json<Vec<CallerIo>>, StatusCode> {
+    state.io(&name).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn scrub_handler(
+    State(state): State<ControlState>,
+    Path(name): Path<String>,
+) -> std::result::Result<Json<ScrubStats>, StatusCode> {
+    state.scrub_stats(&name).await.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MountProfile, MountTransfer, SymlinkPolicy};
+
+    fn export(name: &str) -> ResolvedExport {
+        ResolvedExport {
+            name: name.to_string(),
+            agents: vec!["ws://127.0.0.1:8080".to_string()],
+            remote_path: "/".to_string(),
+            bind_address: "127.0.0.1".to_string(),
+            port: 2049,
+            selinux_context: None,
+            profile: MountProfile::Default,
+            agent_exports: false,
+            umask: 0,
+            symlinks: SymlinkPolicy::AsIs,
+            transfer: MountTransfer::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_toggle_export() {
+        let state = ControlState::new(NfsVersion::V3);
+        let mut enabled = state.register_export(&export("home")).await;
+        assert!(*enabled.borrow());
+
+        let status = state.set_enabled("home", false).await.unwrap();
+        assert!(!status.enabled);
+        assert!(enabled.has_changed().unwrap());
+        assert!(!*enabled.borrow_and_update());
+
+        // Mount paths are accepted as well as names
+        assert!(state.set_enabled("/home", true).await.unwrap().enabled);
+        assert!(*enabled.borrow_and_update());
+
+        assert!(state.set_enabled("missing", true).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_default_export_key() {
+        let state = ControlState::new(NfsVersion::V3);
+        state.register_export(&export("")).await;
+        state.set_serving("/", true).await;
+
+        let exports = state.exports().await;
+        assert_eq!(exports[0].mount_path, "/");
+        assert!(exports[0].serving);
+
+        // `/exports/%2F/disable` arrives as "/"
+        assert!(!state.set_enabled("/", false).await.unwrap().enabled);
+    }
+
+    #[tokio::test]
+    async fn test_export_io() {
+        let state = ControlState::new(NfsVersion::V3);
+        state.register_export(&export("home")).await;
+        assert_eq!(state.io("home").await, Some(Vec::new()));
+
+        let io = Arc::new(IoAccounting::new());
+        state.track_io("home", Arc::clone(&io)).await;
+        io.record_read(501, 4096);
+        io.record_write(502, 8192);
+
+        let top = state.io("/home").await.unwrap();
+        assert_eq!(top.iter().map(|caller| caller.uid).collect::<Vec<_>>(), vec![502, 501]);
+        assert!(state.io("missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recent_errors_are_bounded() {
+        let state = ControlState::new(NfsVersion::V3);
+        for i in 0..MAX_RECENT_ERRORS + 5 {
+            state.record_error(Some("home"), format!("error {}", i)).await;
+        }
+
+        let errors = state.recent_errors().await;
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].message, format!("error {}", MAX_RECENT_ERRORS + 4));
+        assert_eq!(errors[0].export.as_deref(), Some("/home"));
+        assert_eq!(state.status().await.recent_error_count, MAX_RECENT_ERRORS);
+    }
+}
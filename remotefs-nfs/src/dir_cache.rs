@@ -0,0 +1,357 @@
+//! Directory listing cache kept current from the agent's change journal
+//!
+//! Listings are cached by agent path together with the journal cursor they
+//! are valid at, which acts as the cache's version token. Before answering
+//! from the cache, the cache catches up with the journal (at most once per
+//! refresh interval) and applies each change to the affected listing: deleted
+//! entries are dropped, renamed ones moved and created or modified ones have
+//! their metadata fetched again. Repeat readdirs, and lookups and getattrs of
+//! entries in cached directories, then cost one journal poll per interval no
+//! matter how large the tree is.
+//!
+//! If the journal no longer covers the cursor, every listing is dropped. If
+//! the agent has no journal, the cache stays disabled and every request goes
+//! to the agent. While maintenance is underway the journal is not polled and
+//! cached listings are served as they are.
+
+use crate::config::DirectoryCacheConfig;
+use crate::nfs_filesystem::is_same_or_descendant;
+use remotefs_client::{Client, ClientError, ClientResult};
+use remotefs_common::{
+    error::RemoteFsError,
+    protocol::{ChangeKind, ChangeRecord, DirEntry, FileMetadata},
+};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info};
+
+/// Changes fetched per journal poll
+const CHANGES_PER_POLL: u32 = 500;
+
+#[derive(Debug, Default)]
+struct JournalState {
+    /// Journal position the cached listings reflect; `None` until first used
+    cursor: Option<u64>,
+    last_refresh: Option<Instant>,
+    /// The agent has no change journal
+    unavailable: bool,
+}
+
+/// Cached directory listings, keyed by agent path
+#[derive(Debug)]
+pub struct DirectoryCache {
+    listings: RwLock<HashMap<String, Vec<DirEntry>>>,
+    journal: Mutex<JournalState>,
+    refresh_interval: Duration,
+}
+
+impl DirectoryCache {
+    pub fn new(refresh_interval: Duration) -> Self {
+        Self {
+            listings: RwLock::new(HashMap::new()),
+            journal: Mutex::new(JournalState::default()),
+            refresh_interval,
+        }
+    }
+
+    /// List `path`, from the cache when possible
+    pub async fn list(&self, client: &Client, path: &str) -> ClientResult<Vec<DirEntry>> {
+        if !self.refresh(client).await {
+            return client.list_directory(path).await;
+        }
+
+        if let Some(entries) = self.listings.read().await.get(path) {
+            debug!("Directory cache hit: {}", path);
+            return Ok(entries.clone());
+        }
+
+        let entries = client.list_directory(path).await?;
+        self.listings.write().await.insert(path.to_string(), entries.clone());
+        Ok(entries)
+    }
+
+    /// List the tree under `root` down to `preload_depth` levels, breadth
+    /// first, skipping excluded directories and stopping after
+    /// `preload_max_directories` listings
+    ///
+    /// Returns the number of directories listed.
+    pub async fn preload(&self, client: &Client, root: &str, config: &DirectoryCacheConfig) -> usize {
+        if config.preload_depth == 0 || !self.refresh(client).await {
+            return 0;
+        }
+
+        let mut pending = VecDeque::from([(root.to_string(), 1)]);
+        let mut listed = 0;
+        while let Some((dir, level)) = pending.pop_front() {
+            if listed >= config.preload_max_directories {
+                debug!("Preload stopped after {} directories", listed);
+                break;
+            }
+
+            let entries = match self.list(client, &dir).await {
+                Ok(entries) => entries,
+                Err(e) => {
+                    debug!("Preload skipped {}: {}", dir, e);
+                    continue;
+                }
+            };
+            listed += 1;
+
+            if level < config.preload_depth {
+                let parent = Path::new(&dir);
+                pending.extend(entries.iter()
+                    .filter(|entry| entry.metadata.is_dir && !config.preload_exclude.contains(&entry.name))
+                    .filter_map(|entry| parent.join(&entry.name).to_str().map(|path| (path.to_string(), level + 1))));
+            }
+        }
+
+        listed
+    }
+
+    /// Look `path` up in its parent's cached listing
+    ///
+    /// Returns `None` if the parent is not cached, `Some(None)` if the parent
+    /// is cached and has no such entry.
+    pub async fn lookup(&self, client: &Client, path: &str) -> Option<Option<FileMetadata>> {
+        if !self.refresh(client).await {
+            return None;
+        }
+
+        let (parent, name) = split_path(path)?;
+        let listings = self.listings.read().await;
+        let entries = listings.get(parent)?;
+        Some(entries.iter().find(|entry| entry.name == name).map(|entry| entry.metadata.clone()))
+    }
+
+    /// Record the current metadata of `path` after a local change
+    pub async fn upsert(&self, path: &str, metadata: FileMetadata) {
+        let Some((parent, name)) = split_path(path) else {
+            return;
+        };
+
+        let mut listings = self.listings.write().await;
+        if let Some(entries) = listings.get_mut(parent) {
+            match entries.iter_mut().find(|entry| entry.name == name) {
+                Some(entry) => entry.metadata = metadata,
+                None => entries.push(DirEntry { name: name.to_string(), metadata }),
+            }
+        }
+    }
+
+    /// Drop `path` from its parent's listing, along with any listings beneath it
+    pub async fn remove(&self, path: &str) {
+        let mut listings = self.listings.write().await;
+        listings.retain(|dir, _| !is_same_or_descendant(dir, path));
+
+        if let Some((parent, name)) = split_path(path) {
+            if let Some(entries) = listings.get_mut(parent) {
+                entries.retain(|entry| entry.name != name);
+            }
+        }
+    }
+
+    /// Drop the cached listing of the directory containing `path`
+    pub async fn invalidate_parent(&self, path: &str) {
+        if let Some((parent, _)) = split_path(path) {
+            self.listings.write().await.remove(parent);
+        }
+    }
+
+    /// Catch up with the agent's journal if the refresh interval has passed
+    ///
+    /// Returns whether the cache may be used.
+    async fn refresh(&self, client: &Client) -> bool {
+        let mut journal = self.journal.lock().await;
+        if journal.unavailable {
+            return false;
+        }
+        if journal.last_refresh.is_some_and(|at| at.elapsed() < self.refresh_interval) {
+            return true;
+        }
+        // During maintenance the agent may be unreachable; serve what is cached
+        if journal.cursor.is_some() && client.active_maintenance().await.is_some() {
+            debug!("Maintenance underway, serving cached listings");
+            return true;
+        }
+
+        let result = match journal.cursor {
+            // A cursor past the end of the journal yields the latest position
+            None => client.get_changes(u64::MAX, Some(0)).await.map(|changes| changes.next_cursor),
+            Some(cursor) => self.catch_up(client, cursor).await,
+        };
+
+        match result {
+            Ok(cursor) => {
+                journal.cursor = Some(cursor);
+                journal.last_refresh = Some(Instant::now());
+                true
+            }
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(_)) && journal.cursor.is_none() => {
+                info!("Agent has no change journal, directory cache disabled: {}", e);
+                journal.unavailable = true;
+                false
+            }
+            Err(e) => {
+                // Listings may be stale; serve from the agent until the journal answers
+                debug!("Directory cache refresh failed: {}", e);
+                self.listings.write().await.clear();
+                journal.cursor = None;
+                false
+            }
+        }
+    }
+
+    /// Apply the journal's changes after `cursor`, returning the new cursor
+    async fn catch_up(&self, client: &Client, mut cursor: u64) -> ClientResult<u64> {
+        loop {
+            let changes = client.get_changes(cursor, Some(CHANGES_PER_POLL)).await?;
+            if changes.reset {
+                info!("Change journal no longer covers cursor {}, dropping cached listings", cursor);
+                self.listings.write().await.clear();
+                return Ok(changes.next_cursor);
+            }
+
+            for change in &changes.changes {
+                self.apply(client, change).await;
+            }
+            cursor = changes.next_cursor;
+
+            if changes.changes.len() < CHANGES_PER_POLL as usize {
+                return Ok(cursor);
+            }
+        }
+    }
+
+    async fn apply(&self, client: &Client, change: &ChangeRecord) {
+        if let ChangeKind::Renamed { from } = &change.kind {
+            self.remove(from).await;
+        }
+        if matches!(change.kind, ChangeKind::Deleted) {
+            self.remove(&change.path).await;
+            return;
+        }
+
+        // Only listings that are cached need the new metadata
+        let cached = match split_path(&change.path) {
+            Some((parent, _)) => self.listings.read().await.contains_key(parent),
+            None => false,
+        };
+        if !cached {
+            return;
+        }
+
+        match client.get_metadata_with_options(&change.path, false).await {
+            Ok(metadata) => self.upsert(&change.path, metadata).await,
+            // Removed again since; a later change in the journal says so too
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(RemoteFsError::NotFound(_))) => self.remove(&change.path).await,
+            Err(_) => self.invalidate_parent(&change.path).await,
+        }
+    }
+}
+
+/// Split an agent path into its parent directory and file name
+fn split_path(path: &str) -> Option<(&str, &str)> {
+    let path = Path::new(path);
+    let parent = path.parent()?.to_str()?;
+    let name = path.file_name()?.to_str()?;
+    Some((parent, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(size: u64) -> FileMetadata {
+        FileMetadata {
+            size,
+            modified: chrono::Utc::now(),
+            created: chrono::Utc::now(),
+            accessed: chrono::Utc::now(),
+            changed: chrono::Utc::now(),
+            permissions: 0o644,
+            uid: 0,
+            gid: 0,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+            hidden: false,
+            offline: false,
+            nlink: 1,
+            file_type: remotefs_common::protocol::FileType::File,
+            symlink_target: None,
+        }
+    }
+
+    async fn cache_with(dir: &str, names: &[&str]) -> DirectoryCache {
+        let cache = DirectoryCache::new(Duration::from_secs(1));
+        let entries = names
+            .iter()
+            .map(|name| DirEntry { name: name.to_string(), metadata: metadata(0) })
+            .collect();
+        cache.listings.write().await.insert(dir.to_string(), entries);
+        cache
+    }
+
+    async fn names(cache: &DirectoryCache, dir: &str) -> Option<Vec<String>> {
+        cache.listings.read().await.get(dir).map(|entries| entries.iter().map(|e| e.name.clone()).collect())
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(split_path("/srv/data/a.txt"), Some(("/srv/data", "a.txt")));
+        assert_eq!(split_path("/a.txt"), Some(("/", "a.txt")));
+        assert_eq!(split_path("/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_updates() {
+        let cache = cache_with("/data", &["a.txt", "b.txt"]).await;
+        cache.listings.write().await.insert("/data/sub".to_string(), Vec::new());
+
+        cache.upsert("/data/a.txt", metadata(42)).await;
+        cache.upsert("/data/c.txt", metadata(7)).await;
+        let listings = cache.listings.read().await;
+        let a = listings["/data"].iter().find(|e| e.name == "a.txt").unwrap();
+        assert_eq!(a.metadata.size, 42);
+        drop(listings);
+        assert_eq!(names(&cache, "/data").await.unwrap(), ["a.txt", "b.txt", "c.txt"]);
+
+        // Entries of uncached directories are not tracked
+        cache.upsert("/other/x.txt", metadata(1)).await;
+        assert!(names(&cache, "/other").await.is_none());
+
+        cache.remove("/data/b.txt").await;
+        assert_eq!(names(&cache, "/data").await.unwrap(), ["a.txt", "c.txt"]);
+
+        cache.remove("/data").await;
+        assert!(names(&cache, "/data/sub").await.is_none());
+
+        let cache = cache_with("/data", &["a.txt"]).await;
+        cache.invalidate_parent("/data/a.txt").await;
+        assert!(names(&cache, "/data").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_preload_without_agent() {
+        let client = Client::new(remotefs_client::ClientConfig {
+            agents: vec![remotefs_client::AgentConfig {
+                id: "test".to_string(),
+                url: "ws://127.0.0.1:1".to_string(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let cache = DirectoryCache::new(Duration::from_secs(1));
+        let mut config = DirectoryCacheConfig::default();
+        assert_eq!(cache.preload(&client, "/", &config).await, 0);
+        config.preload_depth = 3;
+        assert_eq!(cache.preload(&client, "/", &config).await, 0);
+        assert!(cache.listings.read().await.is_empty());
+    }
+}
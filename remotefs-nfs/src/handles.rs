@@ -0,0 +1,177 @@
+//! File handles that survive server restarts
+//!
+//! zerofs_nfsserve stamps every file handle with the time the server
+//! started and keeps nothing of a file id but the path we map it to in
+//! memory, so after a restart every handle a client holds is refused as
+//! stale. Instead each export stamps handles with a generation of its own,
+//! and its id map is saved now and then and loaded again on start, so the
+//! handles it gave out keep naming the same files.
+//!
+//! Ids are derived from paths, so an id given out after the last save is
+//! handed out again for the same path once the client looks it up anew.
+
+use crate::RemoteNfsFilesystem;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+use zerofs_nfsserve::nfs::{fileid3, nfs_fh3, nfsstat3};
+
+/// Saved id map of an export
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HandleSnapshot {
+    /// Generation the export's handles are stamped with
+    pub generation: u64,
+    /// Directory on the agent the ids are relative to
+    pub remote_root: String,
+    pub ids: Vec<(u64, String)>,
+}
+
+impl HandleSnapshot {
+    /// Load a snapshot; `None` if none was saved
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write_to(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec(self)?;
+        let partial = path.with_extension("json.tmp");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, path)
+    }
+}
+
+/// Generation for an export without saved handles
+pub fn new_generation() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Id a path is given unless another path already has it (FNV-1a, which
+/// unlike the standard hasher is the same in every build); never 0 or the
+/// root's 1
+pub fn stable_file_id(path: &str) -> u64 {
+    let hash = path.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash.max(2)
+}
+
+/// Handle for file `id`, stamped with `generation`
+pub fn encode(generation: u64, id: fileid3) -> nfs_fh3 {
+    let mut data = Vec::with_capacity(16);
+    data.extend_from_slice(&generation.to_le_bytes());
+    data.extend_from_slice(&id.to_le_bytes());
+    nfs_fh3 { data }
+}
+
+/// File id of a handle, which is stale unless stamped with `generation`
+pub fn decode(generation: u64, handle: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+    if handle.data.len() != 16 {
+        return Err(nfsstat3::NFS3ERR_BADHANDLE);
+    }
+    let (stamp, id) = handle.data.split_at(8);
+    if u64::from_le_bytes(stamp.try_into().unwrap()) != generation {
+        return Err(nfsstat3::NFS3ERR_STALE);
+    }
+    Ok(u64::from_le_bytes(id.try_into().unwrap()))
+}
+
+/// File an export's ids are saved in
+pub fn snapshot_path(directory: &Path, export_name: &str) -> PathBuf {
+    let stem = match export_name.trim_matches('/') {
+        "" => "root".to_string(),
+        name => name.replace('/', "_"),
+    };
+    directory.join(format!("{}.json", stem))
+}
+
+/// Give `filesystem` the ids saved in `path`, or keep its fresh ones if
+/// there are none or they were saved for another directory on the agent
+pub async fn restore(filesystem: &mut RemoteNfsFilesystem, path: &Path) {
+    match HandleSnapshot::load(path) {
+        Ok(Some(snapshot)) if snapshot.remote_root == filesystem.remote_root => {
+            info!("Restored {} file handles from {}", snapshot.ids.len(), path.display());
+            filesystem.restore_handles(snapshot).await;
+        }
+        Ok(Some(_)) => info!("Ignoring file handles in {} saved for another remote path", path.display()),
+        Ok(None) => debug!("No saved file handles in {}", path.display()),
+        Err(e) => warn!("Failed to load file handles from {}: {}", path.display(), e),
+    }
+}
+
+/// Save the ids of each export that gave out or moved any since it was
+/// last saved; `saved` holds the change count of each at its last save
+pub async fn save_changed(exports: &[(PathBuf, RemoteNfsFilesystem)], saved: &mut [u64]) {
+    for ((path, filesystem), saved) in exports.iter().zip(saved.iter_mut()) {
+        let changes = filesystem.id_changes.load(Ordering::Acquire);
+        if changes == *saved {
+            continue;
+        }
+        let snapshot = filesystem.handle_snapshot().await;
+        let target = path.clone();
+        match tokio::task::spawn_blocking(move || snapshot.write_to(&target)).await {
+            Ok(Ok(())) => *saved = changes,
+            Ok(Err(e)) => warn!("Failed to save file handles to {}: {}", path.display(), e),
+            Err(e) => warn!("Failed to save file handles to {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Save the exports' ids now, and then every `interval` while they change
+pub async fn save_periodically(exports: Vec<(PathBuf, RemoteNfsFilesystem)>, interval: Duration) {
+    let directories: std::collections::HashSet<_> = exports.iter().filter_map(|(path, _)| path.parent()).collect();
+    for directory in directories {
+        if let Err(e) = tokio::fs::create_dir_all(directory).await {
+            warn!("Failed to create file handle directory {}: {}", directory.display(), e);
+            return;
+        }
+    }
+
+    // The first save records the generation even before any id is given out
+    let mut saved = vec![u64::MAX; exports.len()];
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        save_changed(&exports, &mut saved).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handles() {
+        let handle = encode(7, 42);
+        assert!(matches!(decode(7, &handle), Ok(42)));
+        assert!(matches!(decode(8, &handle), Err(nfsstat3::NFS3ERR_STALE)));
+        assert!(matches!(decode(7, &nfs_fh3 { data: vec![0; 8] }), Err(nfsstat3::NFS3ERR_BADHANDLE)));
+
+        assert_eq!(stable_file_id("/docs/a.txt"), stable_file_id("/docs/a.txt"));
+        assert_ne!(stable_file_id("/docs/a.txt"), stable_file_id("/docs/b.txt"));
+        assert!(stable_file_id("") > 1);
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = snapshot_path(dir.path(), "/");
+        assert_eq!(path.file_name().unwrap(), "root.json");
+        assert!(HandleSnapshot::load(&path).unwrap().is_none());
+
+        let snapshot = HandleSnapshot {
+            generation: 3,
+            remote_root: "/srv".to_string(),
+            ids: vec![(1, "/".to_string()), (stable_file_id("/a"), "/a".to_string())],
+        };
+        snapshot.write_to(&path).unwrap();
+        let loaded = HandleSnapshot::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.generation, 3);
+        assert_eq!(loaded.ids, snapshot.ids);
+    }
+}
@@ -0,0 +1,153 @@
+//! Keeping desktop search indexers off RemoteFS mounts
+//!
+//! Spotlight, Tracker and Baloo treat a fresh mount like any other disk and
+//! crawl the whole tree, which turns into a full scan of the remote agent.
+//! After mounting, each indexer is told to leave the mount alone in the way
+//! it understands: a marker file in the mount's root for Spotlight and
+//! Tracker, `mdutil -i off` for Spotlight's per-volume setting, and an entry
+//! in the user's `baloofilerc` for Baloo. Every step is best effort; a
+//! read-only export or a missing indexer just skips that step.
+
+use crate::mount;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Marker files whose presence in a directory keeps an indexer out of it
+#[cfg(target_os = "macos")]
+const MARKER_FILES: &[&str] = &[".metadata_never_index"];
+
+#[cfg(not(target_os = "macos"))]
+const MARKER_FILES: &[&str] = &[".trackerignore"];
+
+/// Baloo's setting listing excluded folders
+const BALOO_EXCLUDE_KEY: &str = "exclude folders[$e]";
+
+/// Exclude a freshly mounted directory from the system's indexers
+///
+/// With `interactive` unset, sudo fails instead of prompting for a password.
+pub fn exclude(mount_point: &Path, interactive: bool) {
+    create_markers(mount_point);
+
+    if cfg!(target_os = "macos") {
+        let mount_point = mount_point.to_string_lossy();
+        match mount::sudo(&["mdutil", "-i", "off", &mount_point], interactive) {
+            Ok(()) => info!("Disabled Spotlight indexing for {}", mount_point),
+            Err(e) => warn!("Failed to disable Spotlight indexing for {}: {}", mount_point, e),
+        }
+    } else if let Some(config) = baloo_config_path().filter(|path| path.exists()) {
+        if let Err(e) = exclude_from_baloo(&config, mount_point) {
+            warn!("Failed to exclude {} in {}: {}", mount_point.display(), config.display(), e);
+        }
+    }
+}
+
+/// Create the marker files in the root of a mount, keeping existing ones
+fn create_markers(mount_point: &Path) {
+    for marker in MARKER_FILES {
+        let path = mount_point.join(marker);
+        match std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(&path) {
+            Ok(_) => debug!("Created indexer marker {}", path.display()),
+            Err(e) => warn!("Failed to create indexer marker {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// The current user's Baloo configuration, if they have a config directory
+fn baloo_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("baloofilerc"))
+}
+
+fn exclude_from_baloo(config: &Path, mount_point: &Path) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(config)?;
+    if let Some(updated) = add_baloo_exclusion(&contents, &mount_point.to_string_lossy()) {
+        std::fs::write(config, updated)?;
+        info!("Excluded {} from Baloo indexing", mount_point.display());
+    }
+    Ok(())
+}
+
+/// Add `folder` to the excluded folders in a `baloofilerc`
+///
+/// Returns `None` when it is already excluded. Baloo keeps folders with a
+/// trailing slash in a comma-separated list under `[General]`.
+pub fn add_baloo_exclusion(contents: &str, folder: &str) -> Option<String> {
+    let folder = format!("{}/", folder.trim_end_matches('/'));
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+
+    let general = lines.iter().position(|line| line.trim() == "[General]");
+    let section_end = |start: usize| {
+        lines[start + 1..].iter()
+            .position(|line| line.trim_start().starts_with('['))
+            .map_or(lines.len(), |offset| start + 1 + offset)
+    };
+
+    match general {
+        Some(start) => {
+            let end = section_end(start);
+            let existing = (start + 1..end).find(|&i| {
+                lines[i].split_once('=').is_some_and(|(key, _)| key.trim() == BALOO_EXCLUDE_KEY)
+            });
+            match existing {
+                Some(i) => {
+                    let (key, value) = lines[i].split_once('=').unwrap();
+                    if value.split(',').any(|excluded| excluded.trim() == folder) {
+                        return None;
+                    }
+                    let value = match value.trim() {
+                        "" => folder,
+                        value => format!("{},{}", value, folder),
+                    };
+                    lines[i] = format!("{}={}", key, value);
+                }
+                None => lines.insert(start + 1, format!("{}={}", BALOO_EXCLUDE_KEY, folder)),
+            }
+        }
+        None => {
+            if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push("[General]".to_string());
+            lines.push(format!("{}={}", BALOO_EXCLUDE_KEY, folder));
+        }
+    }
+
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    Some(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_add_baloo_exclusion() {
+        let contents = "[Basic Settings]\nIndexing-Enabled=true\n\n[General]\nexclude folders[$e]=$HOME/tmp/\nfolders[$e]=$HOME/\n";
+        let updated = add_baloo_exclusion(contents, "/mnt/projects").unwrap();
+        assert!(updated.contains("\nexclude folders[$e]=$HOME/tmp/,/mnt/projects/\n"));
+        assert!(updated.contains("Indexing-Enabled=true"));
+        assert!(add_baloo_exclusion(&updated, "/mnt/projects/").is_none());
+
+        // No exclusions yet
+        let updated = add_baloo_exclusion("[General]\nfolders[$e]=$HOME/\n", "/mnt/projects").unwrap();
+        assert_eq!(updated, "[General]\nexclude folders[$e]=/mnt/projects/\nfolders[$e]=$HOME/\n");
+
+        // No [General] section yet
+        let updated = add_baloo_exclusion("[Basic Settings]\nIndexing-Enabled=true\n", "/mnt/projects").unwrap();
+        assert_eq!(
+            updated,
+            "[Basic Settings]\nIndexing-Enabled=true\n\n[General]\nexclude folders[$e]=/mnt/projects/\n"
+        );
+    }
+
+    #[test]
+    fn test_create_markers() {
+        let temp_dir = TempDir::new().unwrap();
+        create_markers(temp_dir.path());
+        create_markers(temp_dir.path());
+        for marker in MARKER_FILES {
+            assert!(temp_dir.path().join(marker).exists());
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Per-mount I/O accounting by local user
+//!
+//! NFS requests carry the caller's uid and gids (AUTH_UNIX) but not its
+//! process, so I/O is attributed to the user that issued it. That is usually
+//! enough to spot a background indexer or backup tool hammering the remote,
+//! since those tend to run as their own user or as root.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+/// I/O issued by one local user on one mount
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CallerIo {
+    pub uid: u32,
+    /// Login name of `uid` on this machine, when it has one
+    pub user: Option<String>,
+    /// NFS requests of any kind
+    pub operations: u64,
+    pub reads: u64,
+    pub bytes_read: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl CallerIo {
+    fn new(uid: u32) -> Self {
+        Self {
+            uid,
+            user: user_name(uid),
+            operations: 0,
+            reads: 0,
+            bytes_read: 0,
+            writes: 0,
+            bytes_written: 0,
+            last_seen: Utc::now(),
+        }
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.bytes_read + self.bytes_written
+    }
+}
+
+/// Counters for every user that has used a mount
+#[derive(Debug, Default)]
+pub struct IoAccounting {
+    callers: Mutex<HashMap<u32, CallerIo>>,
+}
+
+impl IoAccounting {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a request of any kind from `uid`
+    pub fn record_operation(&self, uid: u32) {
+        self.update(uid, |caller| caller.operations += 1);
+    }
+
+    /// Count `bytes` read by `uid`
+    pub fn record_read(&self, uid: u32, bytes: u64) {
+        self.update(uid, |caller| {
+            caller.reads += 1;
+            caller.bytes_read += bytes;
+        });
+    }
+
+    /// Count `bytes` written by `uid`
+    pub fn record_write(&self, uid: u32, bytes: u64) {
+        self.update(uid, |caller| {
+            caller.writes += 1;
+            caller.bytes_written += bytes;
+        });
+    }
+
+    /// Users by bytes transferred, busiest first, like `top`
+    pub fn top(&self) -> Vec<CallerIo> {
+        let mut callers: Vec<CallerIo> = self.lock().values().cloned().collect();
+        callers.sort_by(|a, b| {
+            b.total_bytes().cmp(&a.total_bytes())
+                .then(b.operations.cmp(&a.operations))
+                .then(a.uid.cmp(&b.uid))
+        });
+        callers
+    }
+
+    /// Write the current breakdown to `path` as JSON, replacing it atomically
+    pub fn write_to(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_vec_pretty(&self.top())?;
+        let partial = path.with_extension("json.tmp");
+        std::fs::write(&partial, json)?;
+        std::fs::rename(&partial, path)
+    }
+
+    fn update(&self, uid: u32, update: impl FnOnce(&mut CallerIo)) {
+        let mut callers = self.lock();
+        let caller = callers.entry(uid).or_insert_with(|| CallerIo::new(uid));
+        update(caller);
+        caller.last_seen = Utc::now();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u32, CallerIo>> {
+        self.callers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Login name of a local user
+fn user_name(uid: u32) -> Option<String> {
+    let mut passwd = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut buf = vec![0 as libc::c_char; 1024];
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: every pointer is valid for the duration of the call and `buf`
+    // is large enough for typical entries; a short buffer fails with ERANGE
+    let status = unsafe {
+        libc::getpwuid_r(uid, passwd.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success `pw_name` points to a NUL-terminated string in `buf`
+    let name = unsafe { CStr::from_ptr((*result).pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_top_orders_by_bytes() {
+        let io = IoAccounting::new();
+        io.record_operation(501);
+        io.record_read(501, 100);
+        io.record_operation(0);
+        io.record_write(0, 4096);
+        io.record_read(0, 10);
+        io.record_operation(502);
+
+        let top = io.top();
+        assert_eq!(top.iter().map(|caller| caller.uid).collect::<Vec<_>>(), vec![0, 501, 502]);
+        assert_eq!(top[0].bytes_written, 4096);
+        assert_eq!(top[0].reads, 1);
+        assert_eq!(top[0].operations, 1);
+        assert_eq!(top[0].user.as_deref(), Some("root"));
+        assert_eq!(top[2].total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("home.json");
+        let io = IoAccounting::new();
+        io.record_read(501, 100);
+
+        io.write_to(&path).unwrap();
+        let written: Vec<CallerIo> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written, io.top());
+        assert!(!temp_dir.path().join("home.json.tmp").exists());
+    }
+}
@@ -0,0 +1,167 @@
+//! launchd integration for running the NFS server as a macOS login item
+
+use crate::Result;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+/// launchd job label
+pub const LAUNCHD_LABEL: &str = "com.remotefs.nfs";
+
+/// Per-user LaunchAgents plist location
+pub fn launch_agent_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("Library")
+        .join("LaunchAgents")
+        .join(format!("{}.plist", LAUNCHD_LABEL))
+}
+
+/// Log file the launchd job writes stdout and stderr to
+pub fn log_path() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("Library")
+        .join("Logs")
+        .join("remotefs-nfs.log")
+}
+
+/// Generate a LaunchAgent plist that starts the server at login and
+/// restarts it if it exits
+pub fn generate_plist(program: &Path, config: Option<&Path>, log_file: &Path) -> String {
+    let mut arguments = vec![program.display().to_string()];
+    if let Some(config) = config {
+        arguments.push("--config".to_string());
+        arguments.push(config.display().to_string());
+    }
+    arguments.push("start".to_string());
+
+    let arguments: String = arguments
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+        .collect();
+    let log_file = xml_escape(&log_file.display().to_string());
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>10</integer>
+    <key>StandardOutPath</key>
+    <string>{log_file}</string>
+    <key>StandardErrorPath</key>
+    <string>{log_file}</string>
+</dict>
+</plist>
+"#,
+        label = LAUNCHD_LABEL,
+    )
+}
+
+/// Write the LaunchAgent plist and load it with launchctl
+pub fn install(program: &Path, config: Option<&Path>) -> Result<PathBuf> {
+    let plist_path = launch_agent_path();
+    let log_file = log_path();
+
+    for dir in [plist_path.parent(), log_file.parent()].into_iter().flatten() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&plist_path, generate_plist(program, config, &log_file))?;
+    info!("Wrote launchd plist to {}", plist_path.display());
+
+    launchctl(&["load", "-w"], &plist_path)?;
+    Ok(plist_path)
+}
+
+/// Unload the LaunchAgent and remove its plist
+pub fn uninstall() -> Result<PathBuf> {
+    let plist_path = launch_agent_path();
+    if !plist_path.exists() {
+        return Err(remotefs_common::error::RemoteFsError::NotFound(
+            plist_path.display().to_string()
+        ));
+    }
+
+    launchctl(&["unload", "-w"], &plist_path)?;
+    std::fs::remove_file(&plist_path)?;
+    Ok(plist_path)
+}
+
+fn launchctl(args: &[&str], plist_path: &Path) -> Result<()> {
+    let output = Command::new("launchctl")
+        .args(args)
+        .arg(plist_path)
+        .output()
+        .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
+            format!("Failed to execute launchctl: {}", e)
+        ))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(remotefs_common::error::RemoteFsError::Internal(
+            format!("launchctl {} failed: {}", args.join(" "), stderr)
+        ))
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_plist() {
+        let plist = generate_plist(
+            Path::new("/usr/local/bin/remotefs-nfs"),
+            Some(Path::new("/Users/me/R&D/nfs.toml")),
+            Path::new("/Users/me/Library/Logs/remotefs-nfs.log"),
+        );
+
+        assert!(plist.contains("<string>com.remotefs.nfs</string>"));
+        assert!(plist.contains(
+            "        <string>/usr/local/bin/remotefs-nfs</string>\n\
+             \x20       <string>--config</string>\n\
+             \x20       <string>/Users/me/R&amp;D/nfs.toml</string>\n\
+             \x20       <string>start</string>\n"
+        ));
+        assert!(plist.contains("<key>RunAtLoad</key>"));
+        assert!(plist.contains("<string>/Users/me/Library/Logs/remotefs-nfs.log</string>"));
+    }
+
+    #[test]
+    fn test_generate_plist_without_config() {
+        let plist = generate_plist(Path::new("/bin/remotefs-nfs"), None, Path::new("/tmp/nfs.log"));
+        assert!(!plist.contains("--config"));
+        assert!(plist.contains("<string>start</string>"));
+    }
+
+    #[test]
+    fn test_launch_agent_path() {
+        let path = launch_agent_path();
+        assert!(path.ends_with("Library/LaunchAgents/com.remotefs.nfs.plist"));
+    }
+}
@@ -1,11 +1,25 @@
 pub mod nfs_filesystem;
+pub mod dir_cache;
+pub mod readahead;
+pub mod handles;
+pub mod io_stats;
 pub mod server;
 pub mod config;
 pub mod cli;
+pub mod control;
+pub mod indexing;
+pub mod launchd;
+pub mod mount;
+pub mod mount_helper;
+pub mod recovery;
 
 pub use nfs_filesystem::RemoteNfsFilesystem;
 pub use server::RemoteNfsServer;
-pub use config::NfsConfig;
+pub use control::ControlState;
+pub use config::{
+    ControlConfig, DirectoryCacheConfig, ExportConfig, FinderConfig, HandlesConfig, IndexingConfig, MountProfile, MountTransfer, NfsConfig, NfsVersion, ReadAheadConfig, RecoveryConfig,
+    ResolvedExport, SharingConfig, SymlinkPolicy, TransferConfig,
+};
 
 use remotefs_common::error::RemoteFsError;
 
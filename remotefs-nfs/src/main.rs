@@ -1,7 +1,21 @@
-use remotefs_nfs::cli;
+use remotefs_nfs::{cli, mount_helper};
+use std::path::Path;
 
 #[tokio::main]
 async fn main() {
+    // Symlinked to /sbin/mount.remotefs, this binary acts as the mount(8) helper
+    let invoked_as = std::env::args().next().unwrap_or_default();
+    if Path::new(&invoked_as).file_name().is_some_and(|name| name == mount_helper::HELPER_NAME) {
+        let result = mount_helper::MountRequest::parse(std::env::args().skip(1))
+            .and_then(|request| mount_helper::run(&request));
+        if let Err(e) = result {
+            eprintln!("{}: {}", mount_helper::HELPER_NAME, e);
+            // mount(8) reserves 32 for mount failures
+            std::process::exit(32);
+        }
+        return;
+    }
+
     if let Err(e) = cli::run().await {
         eprintln!("Error: {}", e);
         std::process::exit(1);
@@ -0,0 +1,227 @@
+//! Helpers for mounting and unmounting exports with the system NFS client
+
+use crate::{MountProfile, ResolvedExport, Result};
+use std::process::Command;
+
+/// Mount options tuned for the RemoteFS NFS server
+///
+/// Reads and writes are sized and cached as the export's transfer settings
+/// say. On Linux an export's SELinux context is applied with `context=`,
+/// which labels every file on the mount without the server storing labels.
+pub fn mount_options(export: &ResolvedExport) -> String {
+    let transfer = &export.transfer;
+    let mut options = format!(
+        "vers=3,tcp,port={},mountport={},rsize={},wsize={},{}",
+        export.port,
+        export.port,
+        transfer.max_read,
+        transfer.max_write,
+        if transfer.write_back { "async" } else { "sync" }
+    );
+    if export.profile == MountProfile::Ide {
+        // Linux uses READDIRPLUS by default; macOS needs `rdirplus`
+        options.push_str(",actimeo=30");
+        options.push_str(if cfg!(target_os = "linux") { ",lookupcache=all" } else { ",rdirplus" });
+    }
+    // Linux sizes kernel read-ahead from rsize; macOS takes a block count
+    if let Some(readahead) = transfer.readahead.filter(|_| cfg!(target_os = "macos")) {
+        options.push_str(&format!(",readahead={}", readahead));
+    }
+    if let Some(context) = export.selinux_context.as_ref().filter(|_| cfg!(target_os = "linux")) {
+        // Quoted because MLS levels may contain commas
+        options.push_str(&format!(",context=\"{}\"", context));
+    }
+    options
+}
+
+/// `host:/path` source the system NFS client uses for an export
+pub fn mount_source(export: &ResolvedExport) -> String {
+    format!("{}:{}", export.bind_address, export.mount_path())
+}
+
+/// `/etc/fstab` line mounting an export at `mount_point` at boot
+///
+/// Spaces in the mount point are escaped as fstab requires, and the mount
+/// waits for the network (`_netdev`) since the server runs on this host.
+pub fn fstab_entry(export: &ResolvedExport, mount_point: &str) -> String {
+    format!(
+        "{} {} nfs {},_netdev 0 0",
+        mount_source(export),
+        mount_point.replace(' ', "\\040"),
+        mount_options(export)
+    )
+}
+
+/// An export currently mounted on this host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveMount {
+    pub export: ResolvedExport,
+    pub mount_point: String,
+}
+
+/// Mount an export with `sudo mount`
+///
+/// With `interactive` unset, sudo fails instead of prompting for a password,
+/// which is what background tasks need.
+pub fn mount(export: &ResolvedExport, mount_point: &str, interactive: bool) -> Result<()> {
+    let options = mount_options(export);
+    let source = mount_source(export);
+    sudo(&["mount", "-t", "nfs", "-o", &options, &source, mount_point], interactive)
+        .map_err(|e| remotefs_common::error::RemoteFsError::Internal(format!("Failed to mount: {}", e)))
+}
+
+/// Unmount a mount point with `sudo umount`, optionally forcing it
+pub fn unmount(mount_point: &str, force: bool, interactive: bool) -> Result<()> {
+    let args: &[&str] = if force {
+        &["umount", "-f", mount_point]
+    } else {
+        &["umount", mount_point]
+    };
+    sudo(args, interactive)
+        .map_err(|e| remotefs_common::error::RemoteFsError::Internal(format!("Failed to unmount: {}", e)))
+}
+
+/// Exports that are currently mounted, according to `mount`
+pub fn active_mounts(exports: &[ResolvedExport]) -> Result<Vec<ActiveMount>> {
+    let output = Command::new("mount")
+        .output()
+        .map_err(|e| remotefs_common::error::RemoteFsError::Internal(
+            format!("Failed to check mounts: {}", e)
+        ))?;
+
+    Ok(parse_mounts(&String::from_utf8_lossy(&output.stdout), exports))
+}
+
+/// Parse `mount` output into the mounts belonging to `exports`
+///
+/// Handles both the macOS (`src on dst (nfs, ...)`) and Linux
+/// (`src on dst type nfs (...)`) formats.
+pub fn parse_mounts(output: &str, exports: &[ResolvedExport]) -> Vec<ActiveMount> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (source, rest) = line.split_once(" on ")?;
+            let end = [" type ", " ("]
+                .iter()
+                .filter_map(|sep| rest.find(sep))
+                .min()
+                .unwrap_or(rest.len());
+            let export = exports.iter().find(|e| mount_source(e) == source)?;
+
+            Some(ActiveMount {
+                export: export.clone(),
+                mount_point: rest[..end].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Run a command with sudo, returning its stderr on failure
+pub(crate) fn sudo(args: &[&str], interactive: bool) -> std::result::Result<(), String> {
+    let mut command = Command::new("sudo");
+    if !interactive {
+        command.arg("-n");
+    }
+
+    let output = command
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to execute {}: {}", args[0], e))?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MountTransfer, SymlinkPolicy};
+
+    fn export(name: &str, port: u16) -> ResolvedExport {
+        ResolvedExport {
+            name: name.to_string(),
+            agents: vec![],
+            remote_path: "/".to_string(),
+            bind_address: "127.0.0.1".to_string(),
+            port,
+            selinux_context: None,
+            profile: MountProfile::Default,
+            agent_exports: false,
+            umask: 0,
+            symlinks: SymlinkPolicy::AsIs,
+            transfer: MountTransfer::default(),
+        }
+    }
+
+    #[test]
+    fn test_mount_source_and_options() {
+        assert_eq!(mount_source(&export("", 2049)), "127.0.0.1:/");
+        assert_eq!(mount_source(&export("home", 2049)), "127.0.0.1:/home");
+        assert!(mount_options(&export("home", 2050)).starts_with("vers=3,tcp,port=2050,mountport=2050"));
+    }
+
+    #[test]
+    fn test_fstab_entry() {
+        assert_eq!(
+            fstab_entry(&export("home", 2049), "/mnt/Remote Home"),
+            format!("127.0.0.1:/home /mnt/Remote\\040Home nfs {},_netdev 0 0", mount_options(&export("home", 2049)))
+        );
+    }
+
+    #[test]
+    fn test_mount_options_ide_profile() {
+        assert!(!mount_options(&export("home", 2049)).contains("actimeo"));
+
+        let ide = ResolvedExport { profile: MountProfile::Ide, ..export("home", 2049) };
+        assert!(mount_options(&ide).contains(",async,actimeo=30,"));
+    }
+
+    #[test]
+    fn test_mount_options_media_profile() {
+        let transfer = MountTransfer { write_back: false, readahead: Some(128), ..MountTransfer::default() };
+        let media = ResolvedExport { profile: MountProfile::Media, transfer, ..export("home", 2049) };
+        let options = mount_options(&media);
+        assert!(options.contains(",wsize=1048576,sync"));
+        assert!(!options.contains("async"));
+    }
+
+    #[test]
+    fn test_mount_options_transfer() {
+        let transfer = MountTransfer { write_back: false, max_read: 262144, max_write: 65536, readahead: None };
+        let tuned = ResolvedExport { transfer, ..export("home", 2049) };
+        assert!(mount_options(&tuned).contains(",rsize=262144,wsize=65536,sync"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_mount_options_selinux_context() {
+        assert!(!mount_options(&export("home", 2049)).contains("context="));
+
+        let labeled = ResolvedExport {
+            selinux_context: Some("system_u:object_r:nfs_t:s0:c0,c1".to_string()),
+            ..export("home", 2049)
+        };
+        assert!(mount_options(&labeled).ends_with(",async,context=\"system_u:object_r:nfs_t:s0:c0,c1\""));
+    }
+
+    #[test]
+    fn test_parse_mounts() {
+        let exports = vec![export("home", 2049), export("data", 2050)];
+        let output = "\
+/dev/disk3s1s1 on / (apfs, sealed, local, read-only, journaled)
+127.0.0.1:/home on /Volumes/Remote Home (nfs, asynchronous, nodev, nosuid, mounted by me)
+127.0.0.1:/data on /mnt/data type nfs (rw,relatime,vers=3)
+127.0.0.1:/other on /mnt/other (nfs)
+";
+
+        let mounts = parse_mounts(output, &exports);
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].export.name, "home");
+        assert_eq!(mounts[0].mount_point, "/Volumes/Remote Home");
+        assert_eq!(mounts[1].export.name, "data");
+        assert_eq!(mounts[1].mount_point, "/mnt/data");
+    }
+}
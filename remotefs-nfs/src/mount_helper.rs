@@ -0,0 +1,481 @@
+//! `mount.remotefs` helper for /etc/fstab and systemd mount units
+//!
+//! mount(8) runs `mount.remotefs <source> <dir> [-sfnv] [-o options]` for
+//! filesystems of type `remotefs`. The source is an agent URL whose path is
+//! the remote directory, e.g. `ws://fileserver:8080/srv/projects`. The helper
+//! starts a `remotefs-nfs` server for that agent on localhost (unless one is
+//! already listening on the requested `port=`, so give each mount its own
+//! port) and mounts its `/remotefs` export with the kernel NFS client, so
+//! `x-systemd.automount` and `x-systemd.idle-timeout` work as for any other
+//! network filesystem.
+//!
+//! Install it by symlinking the `remotefs-nfs` or `remotefs` binary to
+//! `/sbin/mount.remotefs`.
+
+use crate::{indexing, mount, ExportConfig, NfsConfig, ResolvedExport, Result};
+use remotefs_common::error::RemoteFsError;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Name the binary is invoked as by mount(8)
+pub const HELPER_NAME: &str = "mount.remotefs";
+
+/// The binary bundling every component, which may stand in for the helper
+const META_BINARY: &str = "remotefs";
+
+/// Export the helper's server serves the remote directory as
+const EXPORT_NAME: &str = "remotefs";
+
+/// Directory for generated server configs and logs
+const RUNTIME_DIR: &str = "/run/remotefs";
+
+/// How long to wait for a freshly started server to accept connections
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options consumed by mount(8), systemd or fstab tooling; never passed on
+const IGNORED_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group",
+    "_netdev", "nofail",
+];
+
+/// Options handed through to the kernel NFS client
+const NFS_OPTIONS: &[&str] = &[
+    "ro", "rw", "sync", "async", "soft", "hard", "intr", "nointr",
+    "atime", "noatime", "diratime", "nodiratime", "relatime", "norelatime",
+    "exec", "noexec", "suid", "nosuid", "dev", "nodev", "ac", "noac",
+];
+
+/// NFS options that take a value
+const NFS_VALUE_OPTIONS: &[&str] = &[
+    "rsize", "wsize", "timeo", "retrans", "acregmin", "acregmax",
+    "acdirmin", "acdirmax", "actimeo", "fscontext", "defcontext", "rootcontext",
+];
+
+/// Arguments mount(8) passes to the helper
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MountRequest {
+    pub source: String,
+    pub mount_point: String,
+    pub options: Vec<String>,
+    /// `-f`: do everything except the actual mount
+    pub fake: bool,
+    /// `-n`: do not write /etc/mtab
+    pub no_mtab: bool,
+    /// `-s`: tolerate unknown options
+    pub sloppy: bool,
+    /// `-v`: verbose
+    pub verbose: bool,
+}
+
+impl MountRequest {
+    /// Parse helper arguments, excluding the program name
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut request = MountRequest::default();
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => {
+                    let options = args.next().ok_or_else(|| usage("-o requires an argument"))?;
+                    request.options.extend(split_options(&options));
+                }
+                // Filesystem type; always remotefs for this helper
+                "-t" => {
+                    args.next();
+                }
+                flags if flags.starts_with('-') && flags.len() > 1 => {
+                    for flag in flags[1..].chars() {
+                        match flag {
+                            'f' => request.fake = true,
+                            'n' => request.no_mtab = true,
+                            's' => request.sloppy = true,
+                            'v' => request.verbose = true,
+                            _ => return Err(usage(&format!("unknown flag -{}", flag))),
+                        }
+                    }
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        match <[String; 2]>::try_from(positional) {
+            Ok([source, mount_point]) => {
+                request.source = source;
+                request.mount_point = mount_point;
+                Ok(request)
+            }
+            Err(_) => Err(usage("expected <source> <mount point>")),
+        }
+    }
+}
+
+/// Server configuration and kernel mount options derived from a request
+#[derive(Debug, Clone)]
+pub struct HelperPlan {
+    pub config: NfsConfig,
+    pub export: ResolvedExport,
+    pub mount_options: String,
+}
+
+/// Split `ws://host:port/remote/path` into the agent URL and remote path
+pub fn parse_source(source: &str) -> Result<(String, String)> {
+    let (scheme, rest) = source
+        .split_once("://")
+        .filter(|(scheme, _)| matches!(*scheme, "ws" | "wss"))
+        .ok_or_else(|| usage(&format!("source must be a ws:// or wss:// agent URL: {}", source)))?;
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(usage(&format!("source is missing the agent host: {}", source)));
+    }
+
+    Ok((format!("{}://{}", scheme, authority), path.to_string()))
+}
+
+/// Translate a mount request into server configuration and NFS mount options
+///
+/// `config=PATH` loads a base `NfsConfig`; the agent, export and listener are
+/// always taken from the request. The control API is disabled unless
+/// `control_port=` is given, since every fstab mount runs its own server.
+pub fn plan(request: &MountRequest) -> Result<HelperPlan> {
+    let (agent, remote_path) = parse_source(&request.source)?;
+
+    let mut config = match option_value(&request.options, "config") {
+        Some(path) => NfsConfig::from_file(&PathBuf::from(path))?,
+        None => NfsConfig::default(),
+    };
+    config.host = "127.0.0.1".to_string();
+    config.agents = vec![agent];
+    config.control.enabled = false;
+
+    let mut nfs_options = Vec::new();
+    for option in &request.options {
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option.as_str(), None),
+        };
+
+        match (key, value) {
+            ("config", Some(_)) => {}
+            ("port", Some(value)) => config.port = parse_number(key, value)?,
+            ("token", Some(value)) => {
+                config.auth.enabled = true;
+                config.auth.token = Some(value.to_string());
+            }
+            ("control_port", Some(value)) => {
+                config.control.enabled = true;
+                config.control.port = parse_number(key, value)?;
+            }
+            ("request_timeout", Some(value)) => config.request_timeout = parse_number(key, value)?,
+            ("connect_timeout", Some(value)) => config.connection_timeout = parse_number(key, value)?,
+            ("cache_size", Some(value)) => config.performance.cache_size_mb = parse_number(key, value)?,
+            ("allow_other", None) => config.sharing.forward_caller_identity = true,
+            ("noindex", None) => config.indexing.exclude = true,
+            ("index", None) => config.indexing.exclude = false,
+            ("context", Some(value)) => config.selinux_context = Some(value.trim_matches('"').to_string()),
+            ("umask", Some(value)) => config.umask = Some(parse_umask(value)?),
+            ("symlinks", Some(value)) => config.symlinks = value.parse()
+                .map_err(|e| usage(&format!("invalid value for symlinks: {}", e)))?,
+            (key, None) if IGNORED_OPTIONS.contains(&key) => {}
+            (key, _) if key.starts_with("x-") || key == "comment" => {}
+            (key, None) if NFS_OPTIONS.contains(&key) => nfs_options.push(option.clone()),
+            (key, Some(_)) if NFS_VALUE_OPTIONS.contains(&key) => nfs_options.push(option.clone()),
+            _ if request.sloppy => {}
+            _ => return Err(usage(&format!("unknown option: {}", option))),
+        }
+    }
+
+    config.exports = vec![ExportConfig {
+        name: EXPORT_NAME.to_string(),
+        agent: None,
+        remote_path,
+        port: None,
+        bind_address: None,
+        selinux_context: None,
+        agent_exports: false,
+        umask: None,
+        symlinks: None,
+    }];
+    config.validate()?;
+    let export = config.resolved_exports().remove(0);
+
+    // The local server does not implement NLM, so locking stays client-side
+    let mut mount_options = format!("{},nolock", mount::mount_options(&export));
+    for option in nfs_options {
+        mount_options.push(',');
+        mount_options.push_str(&option);
+    }
+
+    Ok(HelperPlan { config, export, mount_options })
+}
+
+/// Carry out a mount request
+pub fn run(request: &MountRequest) -> Result<()> {
+    let plan = plan(request)?;
+    if request.verbose {
+        eprintln!(
+            "{}: {} -> {}:{} on {} ({})",
+            HELPER_NAME, request.source, plan.export.listen_address(),
+            plan.export.remote_path, request.mount_point, plan.mount_options
+        );
+    }
+    if request.fake {
+        return Ok(());
+    }
+
+    ensure_server(&plan, request.verbose)?;
+
+    let mut command = Command::new("mount");
+    command.args(["-t", "nfs", "-o", &plan.mount_options]);
+    if request.no_mtab {
+        command.arg("-n");
+    }
+    if request.verbose {
+        command.arg("-v");
+    }
+    let status = command
+        .arg(mount::mount_source(&plan.export))
+        .arg(&request.mount_point)
+        .status()
+        .map_err(|e| RemoteFsError::Internal(format!("Failed to execute mount: {}", e)))?;
+
+    if status.success() {
+        if plan.config.indexing.exclude {
+            indexing::exclude(Path::new(&request.mount_point), false);
+        }
+        Ok(())
+    } else {
+        Err(RemoteFsError::Internal(format!("mount -t nfs exited with {}", status)))
+    }
+}
+
+/// Start a server for the plan unless something already listens on its port
+fn ensure_server(plan: &HelperPlan, verbose: bool) -> Result<()> {
+    let addr = plan.export.listen_address();
+    if is_listening(&addr) {
+        if verbose {
+            eprintln!("{}: reusing server on {}", HELPER_NAME, addr);
+        }
+        return Ok(());
+    }
+
+    let runtime_dir = Path::new(RUNTIME_DIR);
+    std::fs::create_dir_all(runtime_dir)?;
+    let config_path = runtime_dir.join(format!("mount-{}.toml", plan.export.port));
+    let log_path = runtime_dir.join(format!("mount-{}.log", plan.export.port));
+    std::fs::write(&config_path, plan.config.to_toml()?)?;
+
+    spawn_server(&config_path, &log_path)?;
+
+    let deadline = Instant::now() + SERVER_START_TIMEOUT;
+    while Instant::now() < deadline {
+        if is_listening(&addr) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+
+    Err(RemoteFsError::Timeout(format!(
+        "remotefs-nfs did not start listening on {}; see {}",
+        addr, log_path.display()
+    )))
+}
+
+fn spawn_server(config_path: &Path, log_path: &Path) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let log = std::fs::OpenOptions::new().create(true).append(true).open(log_path)?;
+    server_command()
+        .arg("--config")
+        .arg(config_path)
+        .arg("start")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        // Detach from mount(8)'s process group so the server outlives it
+        .process_group(0)
+        .spawn()
+        .map_err(|e| RemoteFsError::Internal(format!("Failed to start remotefs-nfs: {}", e)))?;
+    Ok(())
+}
+
+/// The `remotefs-nfs` binary; the helper is normally a symlink to it, or
+/// to the `remotefs` binary, which runs the server as `remotefs macos`
+fn server_command() -> Command {
+    let exe = std::env::current_exe()
+        .ok()
+        .filter(|exe| exe.file_name().is_some_and(|name| name != HELPER_NAME));
+    match exe {
+        Some(exe) if exe.file_name().is_some_and(|name| name == META_BINARY) => {
+            let mut command = Command::new(exe);
+            command.arg("macos");
+            command
+        }
+        Some(exe) => Command::new(exe),
+        None => Command::new("remotefs-nfs"),
+    }
+}
+
+fn is_listening(addr: &str) -> bool {
+    addr.parse()
+        .map(|addr| TcpStream::connect_timeout(&addr, Duration::from_millis(500)).is_ok())
+        .unwrap_or(false)
+}
+
+/// Split a comma-separated option string, keeping commas inside double
+/// quotes (as in `context="system_u:object_r:nfs_t:s0:c0,c1"`)
+fn split_options(options: &str) -> impl Iterator<Item = String> + '_ {
+    let mut in_quotes = false;
+    options
+        .split(move |c| {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            c == ',' && !in_quotes
+        })
+        .filter(|o| !o.is_empty())
+        .map(str::to_string)
+}
+
+fn option_value<'a>(options: &'a [String], key: &str) -> Option<&'a str> {
+    options.iter().find_map(|option| {
+        option.split_once('=').filter(|(k, _)| *k == key).map(|(_, v)| v)
+    })
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| usage(&format!("invalid value for {}: {}", key, value)))
+}
+
+/// Octal permission bits, as for the `umask=` option of other filesystems
+fn parse_umask(value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|umask| *umask <= 0o7777)
+        .ok_or_else(|| usage(&format!("invalid value for umask: {}", value)))
+}
+
+fn usage(message: &str) -> RemoteFsError {
+    RemoteFsError::Configuration(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SymlinkPolicy;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = MountRequest::parse(args(
+            "ws://files:8080/srv/projects /mnt/projects -sv -o rw,port=2050 -o _netdev",
+        )).unwrap();
+
+        assert_eq!(request.source, "ws://files:8080/srv/projects");
+        assert_eq!(request.mount_point, "/mnt/projects");
+        assert_eq!(request.options, vec!["rw", "port=2050", "_netdev"]);
+        assert!(request.sloppy && request.verbose);
+        assert!(!request.fake && !request.no_mtab);
+
+        assert!(MountRequest::parse(args("ws://files:8080/srv")).is_err());
+        assert!(MountRequest::parse(args("ws://files:8080/srv /mnt -x")).is_err());
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            parse_source("wss://files:8443/srv/projects").unwrap(),
+            ("wss://files:8443".to_string(), "/srv/projects".to_string())
+        );
+        assert_eq!(
+            parse_source("ws://files:8080").unwrap(),
+            ("ws://files:8080".to_string(), "/".to_string())
+        );
+        assert!(parse_source("http://files/srv").is_err());
+        assert!(parse_source("ws:///srv").is_err());
+    }
+
+    #[test]
+    fn test_plan_translates_options() {
+        let request = MountRequest::parse(args(
+            "ws://files:8080/srv/projects /mnt/projects -o defaults,noauto,x-systemd.automount,x-systemd.idle-timeout=300,port=2050,token=secret,cache_size=512,allow_other,ro,rsize=65536",
+        )).unwrap();
+        let plan = plan(&request).unwrap();
+
+        assert_eq!(plan.config.agents, vec!["ws://files:8080".to_string()]);
+        assert_eq!(plan.config.auth.token.as_deref(), Some("secret"));
+        assert!(plan.config.auth.enabled);
+        assert_eq!(plan.config.performance.cache_size_mb, 512);
+        assert!(!plan.config.control.enabled);
+        assert!(plan.config.sharing.forward_caller_identity);
+        assert!(plan.config.indexing.exclude);
+
+        assert_eq!(plan.export.listen_address(), "127.0.0.1:2050");
+        assert_eq!(plan.export.remote_path, "/srv/projects");
+        assert_eq!(mount::mount_source(&plan.export), "127.0.0.1:/remotefs");
+        assert!(plan.mount_options.starts_with("vers=3,tcp,port=2050,mountport=2050"));
+        assert!(plan.mount_options.ends_with(",nolock,ro,rsize=65536"));
+        assert!(!plan.mount_options.contains("x-systemd"));
+    }
+
+    #[test]
+    fn test_plan_selinux_context() {
+        let request = MountRequest::parse(vec![
+            "ws://files:8080/srv".to_string(),
+            "/mnt/srv".to_string(),
+            "-o".to_string(),
+            "noauto,context=\"system_u:object_r:nfs_t:s0:c0,c1\",rootcontext=system_u:object_r:nfs_t:s0".to_string(),
+        ]).unwrap();
+        assert_eq!(request.options.len(), 3);
+
+        let plan = plan(&request).unwrap();
+        assert_eq!(plan.config.selinux_context.as_deref(), Some("system_u:object_r:nfs_t:s0:c0,c1"));
+        assert!(plan.mount_options.ends_with(",rootcontext=system_u:object_r:nfs_t:s0"));
+    }
+
+    #[test]
+    fn test_plan_umask_option() {
+        let request = MountRequest::parse(args("ws://files:8080/srv /mnt/srv -o umask=027")).unwrap();
+        let umask_plan = plan(&request).unwrap();
+        assert_eq!(umask_plan.export.umask, 0o027);
+        assert!(!umask_plan.mount_options.contains("umask"));
+
+        let request = MountRequest::parse(args("ws://files:8080/srv /mnt/srv -o umask=0899")).unwrap();
+        assert!(plan(&request).is_err());
+    }
+
+    #[test]
+    fn test_plan_symlinks_option() {
+        let request = MountRequest::parse(args("ws://files:8080/srv /mnt/srv -o symlinks=rewrite")).unwrap();
+        let symlinks_plan = plan(&request).unwrap();
+        assert_eq!(symlinks_plan.export.symlinks, SymlinkPolicy::Rewrite);
+        assert!(!symlinks_plan.mount_options.contains("symlinks"));
+
+        let request = MountRequest::parse(args("ws://files:8080/srv /mnt/srv -o symlinks=sometimes")).unwrap();
+        assert!(plan(&request).is_err());
+    }
+
+    #[test]
+    fn test_plan_index_option() {
+        let request = MountRequest::parse(args("ws://files:8080/srv /mnt/srv -o index")).unwrap();
+        assert!(!plan(&request).unwrap().config.indexing.exclude);
+    }
+
+    #[test]
+    fn test_plan_rejects_unknown_options_unless_sloppy() {
+        let mut request = MountRequest::parse(args("ws://files:8080/ /mnt -o bogus")).unwrap();
+        assert!(plan(&request).is_err());
+
+        request.sloppy = true;
+        assert!(!plan(&request).unwrap().mount_options.contains("bogus"));
+    }
+}
@@ -1,29 +1,204 @@
+use crate::config::{DirectoryCacheConfig, MountTransfer, ReadAheadConfig, SymlinkPolicy};
+use crate::dir_cache::DirectoryCache;
+use crate::handles::{self, HandleSnapshot};
+use crate::io_stats::IoAccounting;
+use crate::readahead::ReadAhead;
 use async_trait::async_trait;
-use remotefs_client::{Client, ClientError};
+use remotefs_client::{Client, ClientError, ClientResult};
 use remotefs_common::{
-    protocol::{FileMetadata, Message},
+    protocol::{CallerIdentity, ExportInfo, FileMetadata, FileType, MetadataUpdate, SpaceInfo},
     error::RemoteFsError,
 };
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
 use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
-use tokio::sync::RwLock;
-use tracing::{debug, warn};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use tracing::{debug, info, warn};
 use zerofs_nfsserve::{
-    nfs::{fattr3, fileid3, filename3, ftype3, nfsstat3, nfspath3, sattr3, set_atime, set_gid3, set_mode3, set_mtime, set_size3, set_uid3, nfstime3, specdata3},
+    nfs::{fattr3, fileid3, filename3, nfs_fh3, fsinfo3, fsstat3, FSF_CANSETTIME, FSF_HOMOGENEOUS, FSF_SYMLINK, ftype3, nfsstat3, nfspath3, post_op_attr, sattr3, set_atime, set_gid3, set_mode3, set_mtime, set_size3, set_uid3, nfstime3, specdata3},
     vfs::{VFSCapabilities, NFSFileSystem, AuthContext, ReadDirResult, DirEntry as NfsDirEntry},
 };
 
+/// Space reported for exports the agent gives no figures for
+const UNKNOWN_SPACE: u64 = 1024 * 1024 * 1024 * 1024;
+
+/// File count reported for filesystems without a fixed number of files
+const UNKNOWN_FILES: u64 = 1024 * 1024 * 1024;
+
+fn nfs_time(time: DateTime<Utc>) -> nfstime3 {
+    nfstime3 {
+        seconds: time.timestamp() as u32,
+        nseconds: time.timestamp_subsec_nanos(),
+    }
+}
+
+fn from_nfs_time(time: nfstime3) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(i64::from(time.seconds), time.nseconds)
+}
+
+/// The mode, owner and times `attr` asks to change, with times the NFS
+/// client leaves to the server taken as `now`
+fn metadata_update(attr: &sattr3, now: DateTime<Utc>) -> Result<MetadataUpdate, nfsstat3> {
+    let time = |how: Option<Option<nfstime3>>| match how {
+        None => Ok(None),
+        Some(None) => Ok(Some(now)),
+        Some(Some(time)) => from_nfs_time(time).map(Some).ok_or(nfsstat3::NFS3ERR_INVAL),
+    };
+    Ok(MetadataUpdate {
+        permissions: match attr.mode {
+            set_mode3::mode(mode) => Some(mode & 0o7777),
+            set_mode3::Void => None,
+        },
+        uid: match attr.uid {
+            set_uid3::uid(uid) => Some(uid),
+            set_uid3::Void => None,
+        },
+        gid: match attr.gid {
+            set_gid3::gid(gid) => Some(gid),
+            set_gid3::Void => None,
+        },
+        accessed: time(match attr.atime {
+            set_atime::DONT_CHANGE => None,
+            set_atime::SET_TO_SERVER_TIME => Some(None),
+            set_atime::SET_TO_CLIENT_TIME(time) => Some(Some(time)),
+        })?,
+        modified: time(match attr.mtime {
+            set_mtime::DONT_CHANGE => None,
+            set_mtime::SET_TO_SERVER_TIME => Some(None),
+            set_mtime::SET_TO_CLIENT_TIME(time) => Some(Some(time)),
+        })?,
+    })
+}
+
+/// Identity of the local user behind an NFS request (AUTH_UNIX credentials)
+fn caller_identity(auth: &AuthContext) -> CallerIdentity {
+    CallerIdentity {
+        uid: auth.uid,
+        gid: auth.gid,
+        groups: auth.gids.clone(),
+    }
+}
+
+/// NFS status for a failed request, from the POSIX error its code and
+/// error number stand for
+fn nfs_status(error: &ClientError) -> nfsstat3 {
+    let ClientError::RemoteFs(e) = error.cause() else {
+        return nfsstat3::NFS3ERR_IO;
+    };
+    match e.to_errno() {
+        libc::EPERM => nfsstat3::NFS3ERR_PERM,
+        libc::ENOENT => nfsstat3::NFS3ERR_NOENT,
+        libc::EACCES => nfsstat3::NFS3ERR_ACCES,
+        libc::EEXIST => nfsstat3::NFS3ERR_EXIST,
+        libc::EXDEV => nfsstat3::NFS3ERR_XDEV,
+        libc::ENOTDIR => nfsstat3::NFS3ERR_NOTDIR,
+        libc::EISDIR => nfsstat3::NFS3ERR_ISDIR,
+        libc::EINVAL => nfsstat3::NFS3ERR_INVAL,
+        libc::EFBIG => nfsstat3::NFS3ERR_FBIG,
+        libc::ENOSPC => nfsstat3::NFS3ERR_NOSPC,
+        libc::EROFS => nfsstat3::NFS3ERR_ROFS,
+        libc::EMLINK => nfsstat3::NFS3ERR_MLINK,
+        libc::ENAMETOOLONG => nfsstat3::NFS3ERR_NAMETOOLONG,
+        libc::ENOTEMPTY => nfsstat3::NFS3ERR_NOTEMPTY,
+        libc::EDQUOT => nfsstat3::NFS3ERR_DQUOT,
+        // Offline files and busy agents: the kernel retries later instead
+        // of hanging the request
+        libc::EAGAIN => nfsstat3::NFS3ERR_JUKEBOX,
+        libc::ENOTSUP => nfsstat3::NFS3ERR_NOTSUPP,
+        _ => nfsstat3::NFS3ERR_IO,
+    }
+}
+
+/// NFS status for a failed request; failures other than the expected ones,
+/// such as a missing file, are logged as warnings
+fn nfs_error(context: std::fmt::Arguments<'_>, error: &ClientError) -> nfsstat3 {
+    let status = nfs_status(error);
+    if matches!(status, nfsstat3::NFS3ERR_IO) {
+        warn!("{}: {}", context, error);
+    } else {
+        debug!("{}: {}", context, error);
+    }
+    status
+}
+
 /// NFS filesystem adapter that proxies requests to RemoteFS agents
 pub struct RemoteNfsFilesystem {
     pub client: Arc<Client>,
-    pub next_file_id: AtomicU64,
+    /// Stamp on this filesystem's file handles (see `handles`)
+    pub generation: u64,
+    /// Bumped whenever an id is given out, moved or dropped, so the saver
+    /// knows when the id map needs saving
+    pub id_changes: Arc<AtomicU64>,
     pub path_to_id_map: Arc<RwLock<HashMap<String, u64>>>,
     pub id_to_path_map: Arc<RwLock<HashMap<u64, String>>>,
     pub root_id: u64,
+    /// Directory on the agent that this filesystem's `/` maps to
+    pub remote_root: String,
+    /// Report birth time in the ctime slot (see `FinderConfig`)
+    pub birthtime_as_ctime: bool,
+    /// Send requests on behalf of the calling uid/gid (see `SharingConfig`)
+    pub forward_caller_identity: bool,
+    /// Cached directory listings (see `DirectoryCacheConfig`)
+    pub dir_cache: Option<Arc<DirectoryCache>>,
+    /// Prefetching for sequential readers (see `ReadAheadConfig`)
+    pub read_ahead: Option<Arc<ReadAhead>>,
+    /// Requests and bytes transferred per local user
+    pub io: Arc<IoAccounting>,
+    /// The agent's exports, when the root lists them (see
+    /// `ExportConfig::agent_exports`)
+    pub agent_exports: Option<Arc<std::sync::RwLock<AgentExports>>>,
+    /// Permission bits cleared from created files and directories (see
+    /// `ExportConfig::umask`)
+    pub umask: u32,
+    /// How symlinks on the agent are presented (see `SymlinkPolicy`)
+    pub symlinks: SymlinkPolicy,
+    /// Largest reads and writes offered to NFS clients (see `TransferConfig`)
+    pub transfer: MountTransfer,
+    /// Places for operations in progress, when they are limited (see
+    /// `PerformanceConfig::max_concurrent_operations`)
+    pub operations: Option<Arc<Semaphore>>,
+}
+
+/// Client for one NFS operation, holding its place among the operations
+/// the mount allows at once until dropped
+struct OperationClient {
+    client: Arc<Client>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl OperationClient {
+    /// The client, for work that may outlive the operation
+    fn shared(&self) -> &Arc<Client> {
+        &self.client
+    }
+}
+
+impl std::ops::Deref for OperationClient {
+    type Target = Client;
+    
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+/// Exports last listed by the agent
+#[derive(Debug, Clone, Default)]
+pub struct AgentExports {
+    pub exports: Vec<ExportInfo>,
+    /// When the list last changed, reported as the root's mtime
+    pub changed: DateTime<Utc>,
 }
 
 impl RemoteNfsFilesystem {
     pub async fn new(client: Client) -> crate::Result<Self> {
+        Self::with_root(Arc::new(client), "/").await
+    }
+    
+    /// Create a filesystem exposing `remote_root` on the agent as its root
+    ///
+    /// The client may be shared between several exports.
+    pub async fn with_root(client: Arc<Client>, remote_root: &str) -> crate::Result<Self> {
         let root_id = 1u64; // NFS root directory has ID 1
         let mut path_to_id_map = HashMap::new();
         let mut id_to_path_map = HashMap::new();
@@ -32,15 +207,304 @@ impl RemoteNfsFilesystem {
         path_to_id_map.insert("/".to_string(), root_id);
         id_to_path_map.insert(root_id, "/".to_string());
         
+        let remote_root = match remote_root.trim_end_matches('/') {
+            "" => "/".to_string(),
+            root => root.to_string(),
+        };
+        
         Ok(Self {
-            client: Arc::new(client),
-            next_file_id: AtomicU64::new(root_id + 1),
+            client,
+            generation: handles::new_generation(),
+            id_changes: Arc::new(AtomicU64::new(0)),
             path_to_id_map: Arc::new(RwLock::new(path_to_id_map)),
             id_to_path_map: Arc::new(RwLock::new(id_to_path_map)),
             root_id,
+            remote_root,
+            birthtime_as_ctime: false,
+            forward_caller_identity: false,
+            dir_cache: None,
+            read_ahead: None,
+            io: Arc::new(IoAccounting::new()),
+            agent_exports: None,
+            umask: 0,
+            symlinks: SymlinkPolicy::AsIs,
+            transfer: MountTransfer::default(),
+            operations: None,
         })
     }
     
+    /// Report each file's birth time as its ctime
+    pub fn with_birthtime_as_ctime(mut self, enabled: bool) -> Self {
+        self.birthtime_as_ctime = enabled;
+        self
+    }
+    
+    /// Make requests on behalf of each NFS caller's uid/gid
+    pub fn with_forward_caller_identity(mut self, enabled: bool) -> Self {
+        self.forward_caller_identity = enabled;
+        self
+    }
+    
+    /// Clear the bits of `umask` from the mode of created files and
+    /// directories
+    pub fn with_umask(mut self, umask: u32) -> Self {
+        self.umask = umask;
+        self
+    }
+    
+    /// Present symlinks on the agent as `policy` says
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+    
+    /// Send at most `limit` operations to the agents at once; 0 for no limit
+    pub fn with_max_concurrent_operations(mut self, limit: usize) -> Self {
+        self.operations = (limit > 0).then(|| Arc::new(Semaphore::new(limit)));
+        self
+    }
+    
+    /// Offer NFS clients reads and writes of the sizes in `transfer`
+    pub fn with_transfer(mut self, transfer: MountTransfer) -> Self {
+        self.transfer = transfer;
+        self
+    }
+    
+    /// Mode of a file or directory created with `attr`, or with `default`
+    /// if the NFS client set none
+    fn create_mode(&self, attr: &sattr3, default: u32) -> u32 {
+        let mode = match attr.mode {
+            set_mode3::mode(mode) => mode,
+            set_mode3::Void => default,
+        };
+        mode & 0o7777 & !self.umask
+    }
+    
+    /// Serve repeat listings and attribute lookups from a directory cache
+    pub fn with_directory_cache(mut self, config: &DirectoryCacheConfig) -> Self {
+        self.dir_cache = config.enabled.then(|| {
+            Arc::new(DirectoryCache::new(Duration::from_millis(config.refresh_interval_ms)))
+        });
+        self
+    }
+    
+    /// Prefetch ahead of sequential readers
+    pub fn with_read_ahead(mut self, config: &ReadAheadConfig) -> Self {
+        self.read_ahead = config.enabled.then(|| Arc::new(ReadAhead::new(config.clone())));
+        self
+    }
+    
+    /// Reuse another export's directory cache and read-ahead buffers, so
+    /// exports on the same agent cache each path once and see each other's
+    /// invalidations
+    pub fn with_caches_of(mut self, other: &RemoteNfsFilesystem) -> Self {
+        self.dir_cache = other.dir_cache.clone();
+        self.read_ahead = other.read_ahead.clone();
+        self
+    }
+    
+    /// List the agent's exports as the root's directories instead of
+    /// serving `remote_root`
+    pub fn with_agent_exports(mut self) -> Self {
+        self.agent_exports = Some(Arc::new(std::sync::RwLock::new(AgentExports::default())));
+        self
+    }
+    
+    /// Fetch the agent's current exports, as visible to `client`
+    pub async fn refresh_agent_exports(&self, client: &Client) -> ClientResult<()> {
+        let Some(agent_exports) = &self.agent_exports else {
+            return Ok(());
+        };
+        
+        let exports = client.list_exports(None).await?;
+        let mut agent_exports = agent_exports.write().unwrap();
+        if agent_exports.exports != exports {
+            agent_exports.exports = exports;
+            agent_exports.changed = Utc::now();
+        }
+        Ok(())
+    }
+    
+    /// Fill the directory cache with the top levels of the export
+    pub async fn preload(&self, config: &DirectoryCacheConfig) {
+        let Some(cache) = self.dir_cache() else {
+            return;
+        };
+        
+        let roots = match &self.agent_exports {
+            Some(agent_exports) => {
+                if let Err(e) = self.refresh_agent_exports(&self.client).await {
+                    warn!("Could not list the agent's exports to preload: {}", e);
+                }
+                agent_exports.read().unwrap().exports.iter().map(|export| export.path.clone()).collect()
+            }
+            None => vec![self.remote_root.clone()],
+        };
+        
+        for root in roots {
+            let started = std::time::Instant::now();
+            let listed = cache.preload(&self.client, &root, config).await;
+            if listed > 0 {
+                info!("Preloaded {} directories of {} in {:?}", listed, root, started.elapsed());
+            }
+        }
+    }
+    
+    /// Directory cache to use, if any
+    ///
+    /// Listings depend on the caller's access rules when requests are made on
+    /// behalf of each caller, so the cache is not used then.
+    fn dir_cache(&self) -> Option<&DirectoryCache> {
+        if self.forward_caller_identity {
+            None
+        } else {
+            self.dir_cache.as_deref()
+        }
+    }
+    
+    /// Read-ahead to use, if any
+    ///
+    /// Prefetched data would be served to callers that may not read the
+    /// file when requests are made on behalf of each caller, so it is not
+    /// used then.
+    fn read_ahead(&self) -> Option<&ReadAhead> {
+        if self.forward_caller_identity {
+            None
+        } else {
+            self.read_ahead.as_deref()
+        }
+    }
+    
+    /// Metadata of `path` without following symlinks, unless the mount
+    /// follows them, from the directory cache when its parent directory is
+    /// cached
+    async fn metadata(&self, client: &Client, path: &str) -> ClientResult<FileMetadata> {
+        if self.is_export_root(&self.normalize_path(path)) {
+            return Ok(self.export_root_metadata());
+        }
+        
+        let remote_path = self.remote_path(path);
+        if remote_path.is_empty() {
+            return Err(ClientError::RemoteFs(RemoteFsError::NotFound(path.to_string())));
+        }
+        let metadata = match self.dir_cache() {
+            Some(cache) => match cache.lookup(client, &remote_path).await {
+                Some(Some(metadata)) => Some(metadata),
+                Some(None) => return Err(ClientError::RemoteFs(RemoteFsError::NotFound(remote_path))),
+                None => None,
+            },
+            None => None,
+        };
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => client.get_metadata_with_options(&remote_path, false).await?,
+        };
+        Ok(self.follow(client, &remote_path, metadata).await)
+    }
+    
+    /// Metadata of the target of the symlink at `remote_path` instead of
+    /// the link's, when the mount follows symlinks; links whose target is
+    /// missing or out of reach stay links
+    async fn follow(&self, client: &Client, remote_path: &str, metadata: FileMetadata) -> FileMetadata {
+        if self.symlinks != SymlinkPolicy::Follow || !matches!(metadata.file_type, FileType::Symlink) {
+            return metadata;
+        }
+        match client.get_metadata_with_options(remote_path, true).await {
+            Ok(target) => target,
+            Err(e) => {
+                debug!("Presenting {} as a symlink: {}", remote_path, e);
+                metadata
+            }
+        }
+    }
+    
+    /// Target of the symlink at `path` as the mount presents it
+    ///
+    /// When the mount rewrites symlinks, an absolute target inside the
+    /// export becomes relative to the link's directory, so it points to
+    /// the same file wherever the export is mounted.
+    fn presented_target(&self, path: &str, target: String) -> String {
+        if self.symlinks != SymlinkPolicy::Rewrite || !target.starts_with('/') {
+            return target;
+        }
+        match self.local_path(&lexically_normal(&target)) {
+            Some(local) => {
+                let path = self.normalize_path(path);
+                let dir = match path.rsplit_once('/') {
+                    Some(("", _)) | None => "/",
+                    Some((dir, _)) => dir,
+                };
+                relative_path(dir, &local)
+            }
+            None => target,
+        }
+    }
+    
+    /// Client to use for a request from `auth`, once the mount's limit on
+    /// operations in progress lets it start
+    async fn client_for(&self, auth: &AuthContext) -> OperationClient {
+        self.io.record_operation(auth.uid);
+        let permit = match &self.operations {
+            // The semaphore is never closed
+            Some(operations) => Arc::clone(operations).acquire_owned().await.ok(),
+            None => None,
+        };
+        let client = if self.forward_caller_identity {
+            Arc::new(self.client.with_caller(caller_identity(auth)))
+        } else {
+            Arc::clone(&self.client)
+        };
+        OperationClient { client, _permit: permit }
+    }
+    
+    /// Refuse changes while maintenance is underway, so the mount reads as
+    /// read-only instead of failing writes partway through
+    async fn check_writable(&self) -> Result<(), nfsstat3> {
+        if let Some(window) = self.client.active_maintenance().await {
+            debug!("Refusing change during maintenance: {}", window.message);
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        Ok(())
+    }
+    
+    /// Create a file with `mode`, for `create` and `create_exclusive`
+    async fn create_file(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+        mode: u32,
+        exclusive: bool,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
+        debug!("NFS create: dirid={}, filename={:?}", dirid, String::from_utf8_lossy(filename));
+        
+        let dir_path = match self.get_path_for_id(dirid).await {
+            Some(path) => path,
+            None => return Err(nfsstat3::NFS3ERR_STALE),
+        };
+        if self.is_export_root(&dir_path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        
+        let filename_str = String::from_utf8_lossy(filename);
+        let full_path = self.join_path(&dir_path, &filename_str);
+        
+        match client.create_file(&self.remote_path(&full_path), mode, exclusive).await {
+            Ok(metadata) => {
+                let file_id = self.get_or_create_file_id(&full_path).await;
+                let fattr = self.file_metadata_to_fattr(&metadata, file_id);
+                if let Some(cache) = self.dir_cache() {
+                    cache.upsert(&self.remote_path(&full_path), metadata).await;
+                }
+                debug!("Create successful: {} -> {}", full_path, file_id);
+                Ok((file_id, fattr))
+            }
+            Err(e) => Err(nfs_error(format_args!("Create error for {}", full_path), &e)),
+        }
+    }
+    
     /// Get or create a file ID for the given path
     async fn get_or_create_file_id(&self, path: &str) -> u64 {
         let normalized_path = self.normalize_path(path);
@@ -53,27 +517,102 @@ impl RemoteNfsFilesystem {
             }
         }
         
-        // Create new ID
-        let new_id = self.next_file_id.fetch_add(1, Ordering::SeqCst);
-        
-        // Update both maps
-        {
-            let mut path_map = self.path_to_id_map.write().await;
-            let mut id_map = self.id_to_path_map.write().await;
-            
-            path_map.insert(normalized_path.clone(), new_id);
-            id_map.insert(new_id, normalized_path);
+        // Derive the ID from the path, so the path gets the same one after
+        // a restart even if it was given out after the last save
+        let mut path_map = self.path_to_id_map.write().await;
+        let mut id_map = self.id_to_path_map.write().await;
+        if let Some(&id) = path_map.get(&normalized_path) {
+            return id;
+        }
+        let mut new_id = handles::stable_file_id(&normalized_path);
+        while id_map.contains_key(&new_id) {
+            new_id = new_id.wrapping_add(1).max(2);
         }
+        path_map.insert(normalized_path.clone(), new_id);
+        id_map.insert(new_id, normalized_path);
+        self.id_changes.fetch_add(1, Ordering::Release);
         
         new_id
     }
     
+    /// The ID map, for saving
+    pub async fn handle_snapshot(&self) -> HandleSnapshot {
+        let id_map = self.id_to_path_map.read().await;
+        HandleSnapshot {
+            generation: self.generation,
+            remote_root: self.remote_root.clone(),
+            ids: id_map.iter().map(|(id, path)| (*id, path.clone())).collect(),
+        }
+    }
+    
+    /// Take over the handles of a saved ID map, so clients can keep using
+    /// the ones they got before a restart
+    pub async fn restore_handles(&mut self, snapshot: HandleSnapshot) {
+        let mut path_map = self.path_to_id_map.write().await;
+        let mut id_map = self.id_to_path_map.write().await;
+        for (id, path) in snapshot.ids {
+            if id == self.root_id || path == "/" {
+                continue;
+            }
+            path_map.insert(path.clone(), id);
+            id_map.insert(id, path);
+        }
+        self.generation = snapshot.generation;
+    }
+    
     /// Get the path for a file ID
     async fn get_path_for_id(&self, id: u64) -> Option<String> {
         let id_map = self.id_to_path_map.read().await;
         id_map.get(&id).cloned()
     }
     
+    /// Move the ID mappings for `from` and everything beneath it to `to`
+    ///
+    /// Any mappings already under `to` belong to an entry the rename has
+    /// replaced, so they are dropped first.
+    async fn remap_subtree(&self, from: &str, to: &str) {
+        let mut path_map = self.path_to_id_map.write().await;
+        let mut id_map = self.id_to_path_map.write().await;
+        
+        let replaced: Vec<String> = path_map.keys()
+            .filter(|p| is_same_or_descendant(p, to))
+            .cloned()
+            .collect();
+        for path in replaced {
+            if let Some(id) = path_map.remove(&path) {
+                id_map.remove(&id);
+            }
+        }
+        
+        let moved: Vec<(String, u64)> = path_map.iter()
+            .filter(|(p, _)| is_same_or_descendant(p, from))
+            .map(|(p, id)| (p.clone(), *id))
+            .collect();
+        for (old_path, id) in moved {
+            let new_path = format!("{}{}", to, &old_path[from.len()..]);
+            path_map.remove(&old_path);
+            path_map.insert(new_path.clone(), id);
+            id_map.insert(id, new_path);
+        }
+        self.id_changes.fetch_add(1, Ordering::Release);
+    }
+    
+    /// Drop the ID mappings for `path` and everything beneath it
+    async fn forget_subtree(&self, path: &str) {
+        let mut path_map = self.path_to_id_map.write().await;
+        let mut id_map = self.id_to_path_map.write().await;
+        
+        path_map.retain(|p, id| {
+            if is_same_or_descendant(p, path) {
+                id_map.remove(id);
+                false
+            } else {
+                true
+            }
+        });
+        self.id_changes.fetch_add(1, Ordering::Release);
+    }
+    
     /// Normalize a path for consistent handling
     fn normalize_path(&self, path: &str) -> String {
         if path.is_empty() || path == "/" {
@@ -88,6 +627,125 @@ impl RemoteNfsFilesystem {
         }
     }
     
+    /// Translate an export-relative path into a path on the agent
+    ///
+    /// When the root lists the agent's exports, the first component names
+    /// the export. The root itself and unknown names have no path on the
+    /// agent and give an empty path.
+    fn remote_path(&self, path: &str) -> String {
+        let path = self.normalize_path(path);
+        if let Some(agent_exports) = &self.agent_exports {
+            let relative = &path[1..];
+            let (name, rest) = relative.split_once('/').unwrap_or((relative, ""));
+            let agent_exports = agent_exports.read().unwrap();
+            return match agent_exports.exports.iter().find(|export| export.name == name) {
+                Some(export) if rest.is_empty() => export.path.clone(),
+                Some(export) => format!("{}/{}", export.path.trim_end_matches('/'), rest),
+                None => String::new(),
+            };
+        }
+        
+        if self.remote_root == "/" {
+            path
+        } else if path == "/" {
+            self.remote_root.clone()
+        } else {
+            format!("{}{}", self.remote_root, path)
+        }
+    }
+    
+    /// Export-relative path of `remote`, a path on the agent; `None` if it
+    /// lies outside the export
+    fn local_path(&self, remote: &str) -> Option<String> {
+        let (prefix, root) = match &self.agent_exports {
+            Some(agent_exports) => {
+                let agent_exports = agent_exports.read().unwrap();
+                let export = agent_exports.exports.iter()
+                    .filter(|export| is_same_or_descendant(remote, &self.normalize_path(&export.path)))
+                    .max_by_key(|export| export.path.len())?;
+                (format!("/{}", export.name), self.normalize_path(&export.path))
+            }
+            None if is_same_or_descendant(remote, &self.remote_root) => (String::new(), self.remote_root.clone()),
+            None => return None,
+        };
+        let rest = if root == "/" { remote } else { &remote[root.len()..] };
+        Some(self.normalize_path(&format!("{}{}", prefix, rest)))
+    }
+    
+    /// Whether `dir_path` is the root listing the agent's exports, in which
+    /// nothing can be created, removed or renamed
+    fn is_export_root(&self, dir_path: &str) -> bool {
+        self.agent_exports.is_some() && dir_path == "/"
+    }
+    
+    /// Attributes of the root listing the agent's exports
+    fn export_root_metadata(&self) -> FileMetadata {
+        let changed = self.agent_exports.as_ref()
+            .map(|agent_exports| agent_exports.read().unwrap().changed)
+            .unwrap_or_default();
+        FileMetadata::synthetic_directory(0o555, changed)
+    }
+    
+    /// Total and available bytes of the export holding `path`, when the
+    /// root lists the agent's exports and the agent reported them
+    fn export_space(&self, path: &str) -> Option<(u64, u64)> {
+        let agent_exports = self.agent_exports.as_ref()?.read().unwrap();
+        let path = self.normalize_path(path);
+        let name = path[1..].split('/').next()?;
+        let export = agent_exports.exports.iter().find(|export| export.name == name)?;
+        export.total_space.zip(export.available_space)
+    }
+    
+    /// List the agent's exports as the entries of the root
+    async fn readdir_export_root(
+        &self,
+        client: &Client,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        if let Err(e) = self.refresh_agent_exports(client).await {
+            warn!("Could not refresh the agent's exports: {}", e);
+        }
+        
+        let root = self.export_root_metadata();
+        let mut entries = vec![
+            (self.root_id, ".".to_string(), root.clone()),
+            (self.root_id, "..".to_string(), root),
+        ];
+        let names: Vec<String> = match &self.agent_exports {
+            Some(agent_exports) => agent_exports.read().unwrap().exports.iter().map(|export| export.name.clone()).collect(),
+            None => Vec::new(),
+        };
+        for name in names {
+            let path = self.join_path("/", &name);
+            match self.metadata(client, &path).await {
+                Ok(metadata) => entries.push((self.get_or_create_file_id(&path).await, name, metadata)),
+                Err(e) => debug!("Leaving export {} out of the listing: {:?}", name, e),
+            }
+        }
+        
+        // Resume after the entry the client saw last; `.` and `..` share an ID
+        let start = match start_after {
+            0 => 0,
+            id => entries.iter().rposition(|(entry_id, _, _)| *entry_id == id).map_or(entries.len(), |i| i + 1),
+        };
+        let total = entries.len();
+        let entries: Vec<NfsDirEntry> = entries.into_iter()
+            .skip(start)
+            .take(max_entries)
+            .map(|(fileid, name, metadata)| NfsDirEntry {
+                fileid,
+                name: zerofs_nfsserve::nfs::nfsstring(name.into_bytes()),
+                attr: self.file_metadata_to_fattr(&metadata, fileid),
+            })
+            .collect();
+        
+        Ok(ReadDirResult {
+            end: start + entries.len() >= total,
+            entries,
+        })
+    }
+    
     /// Join directory and filename to create full path
     fn join_path(&self, dir_path: &str, filename: &str) -> String {
         if dir_path == "/" {
@@ -97,18 +755,37 @@ impl RemoteNfsFilesystem {
         }
     }
     
+    /// Timestamp to report as ctime
+    ///
+    /// NFSv3 has no birth time attribute, so Finder's "Date Created" can only
+    /// be populated by reporting it here. That gives up ctime's change-tracking
+    /// meaning, hence it is opt-in. Agents that predate `changed` fall back
+    /// to mtime.
+    fn ctime(&self, metadata: &FileMetadata) -> DateTime<Utc> {
+        if self.birthtime_as_ctime {
+            metadata.created
+        } else {
+            metadata.change_time()
+        }
+    }
+    
     /// Convert FileMetadata to NFS file attributes
     fn file_metadata_to_fattr(&self, metadata: &FileMetadata, file_id: u64) -> fattr3 {
-        let file_type = if metadata.is_dir {
-            ftype3::NF3DIR
-        } else {
-            ftype3::NF3REG
+        let file_type = match metadata.file_type {
+            FileType::File => ftype3::NF3REG,
+            FileType::Directory => ftype3::NF3DIR,
+            FileType::Symlink => ftype3::NF3LNK,
+            FileType::BlockDevice => ftype3::NF3BLK,
+            FileType::CharDevice => ftype3::NF3CHR,
+            FileType::Fifo => ftype3::NF3FIFO,
+            FileType::Socket => ftype3::NF3SOCK,
         };
         
         fattr3 {
             ftype: file_type,
-            mode: metadata.permissions,
-            nlink: 1,
+            mode: metadata.mode(),
+            // Agents that do not report link counts send 0
+            nlink: metadata.nlink.max(1) as u32,
             uid: 1000, // Default UID
             gid: 1000, // Default GID
             size: metadata.size,
@@ -116,24 +793,23 @@ impl RemoteNfsFilesystem {
             rdev: specdata3 { specdata1: 0, specdata2: 0 },
             fsid: 1,
             fileid: file_id,
-            atime: nfstime3 {
-                seconds: metadata.accessed.timestamp() as u32,
-                nseconds: metadata.accessed.timestamp_subsec_nanos(),
-            },
-            mtime: nfstime3 {
-                seconds: metadata.modified.timestamp() as u32,
-                nseconds: metadata.modified.timestamp_subsec_nanos(),
-            },
-            ctime: nfstime3 {
-                seconds: metadata.created.timestamp() as u32,
-                nseconds: metadata.created.timestamp_subsec_nanos(),
-            },
+            atime: nfs_time(metadata.accessed),
+            mtime: nfs_time(metadata.modified),
+            ctime: nfs_time(self.ctime(metadata)),
         }
     }
 }
 
 #[async_trait]
 impl NFSFileSystem for RemoteNfsFilesystem {
+    fn id_to_fh(&self, id: fileid3) -> nfs_fh3 {
+        handles::encode(self.generation, id)
+    }
+    
+    fn fh_to_id(&self, handle: &nfs_fh3) -> Result<fileid3, nfsstat3> {
+        handles::decode(self.generation, handle)
+    }
+    
     fn root_dir(&self) -> fileid3 {
         self.root_id
     }
@@ -144,10 +820,11 @@ impl NFSFileSystem for RemoteNfsFilesystem {
 
     async fn lookup(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         dirid: fileid3,
         filename: &filename3,
     ) -> Result<fileid3, nfsstat3> {
+        let client = self.client_for(auth).await;
         debug!("NFS lookup: dirid={}, filename={:?}", dirid, String::from_utf8_lossy(filename));
         
         // Get directory path
@@ -155,7 +832,7 @@ impl NFSFileSystem for RemoteNfsFilesystem {
             Some(path) => path,
             None => {
                 debug!("Directory ID {} not found", dirid);
-                return Err(nfsstat3::NFS3ERR_NOENT);
+                return Err(nfsstat3::NFS3ERR_STALE);
             }
         };
         
@@ -164,382 +841,473 @@ impl NFSFileSystem for RemoteNfsFilesystem {
         
         debug!("Looking up full path: {}", full_path);
         
+        // The exports are listed lazily, so a name may be new to us
+        if self.is_export_root(&dir_path) && self.remote_path(&full_path).is_empty() {
+            if let Err(e) = self.refresh_agent_exports(&client).await {
+                warn!("Could not refresh the agent's exports: {}", e);
+            }
+        }
+        
         // Try to get metadata to verify file exists
-        match self.client.get_metadata_with_options(&full_path, false).await {
+        match self.metadata(&client, &full_path).await {
             Ok(_) => {
                 let file_id = self.get_or_create_file_id(&full_path).await;
                 debug!("Lookup successful: {} -> {}", full_path, file_id);
                 Ok(file_id)
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => {
-                debug!("File not found: {}", full_path);
-                Err(nfsstat3::NFS3ERR_NOENT)
-            }
-            Err(e) => {
-                warn!("Lookup error for {}: {:?}", full_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Lookup error for {}", full_path), &e)),
         }
     }
 
-    async fn getattr(&self, _auth: &AuthContext, id: fileid3) -> Result<fattr3, nfsstat3> {
+    async fn getattr(&self, auth: &AuthContext, id: fileid3) -> Result<fattr3, nfsstat3> {
+        let client = self.client_for(auth).await;
         debug!("NFS getattr: id={}", id);
         
         let path = match self.get_path_for_id(id).await {
             Some(path) => path,
             None => {
                 debug!("File ID {} not found in getattr", id);
-                return Err(nfsstat3::NFS3ERR_NOENT);
+                return Err(nfsstat3::NFS3ERR_STALE);
             }
         };
         
-        match self.client.get_metadata_with_options(&path, false).await {
+        match self.metadata(&client, &path).await {
             Ok(metadata) => {
                 let fattr = self.file_metadata_to_fattr(&metadata, id);
                 debug!("getattr successful for {}: {:?}", path, fattr);
                 Ok(fattr)
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(e) => {
-                warn!("getattr error for {}: {:?}", path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("getattr error for {}", path), &e)),
         }
     }
 
     async fn read(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         id: fileid3,
         offset: u64,
         count: u32,
     ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        let client = self.client_for(auth).await;
         debug!("NFS read: id={}, offset={}, count={}", id, offset, count);
         
         let path = match self.get_path_for_id(id).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
         
-        match self.client.read_file_range(&path, Some(offset), Some(count as u64)).await {
+        let remote_path = self.remote_path(&path);
+        if let Some(read_ahead) = self.read_ahead() {
+            if let Some((data, eof)) = read_ahead.read(client.shared(), &remote_path, offset, count).await {
+                debug!("Read {} bytes from {} from read-ahead, eof={}", data.len(), path, eof);
+                self.io.record_read(auth.uid, data.len() as u64);
+                return Ok((data.to_vec(), eof));
+            }
+        }
+        
+        match client.read_file_range(&remote_path, Some(offset), Some(count as u64)).await {
             Ok(data) => {
                 let eof = (data.len() as u32) < count;
                 debug!("Read {} bytes from {}, eof={}", data.len(), path, eof);
+                self.io.record_read(auth.uid, data.len() as u64);
                 Ok((data.to_vec(), eof))
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Read error for {}: {:?}", path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Read error for {}", path), &e)),
         }
     }
 
     async fn write(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         id: fileid3,
         offset: u64,
         data: &[u8],
     ) -> Result<fattr3, nfsstat3> {
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
         debug!("NFS write: id={}, offset={}, len={}", id, offset, data.len());
         
         let path = match self.get_path_for_id(id).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
         
-        match self.client.write_file_at(&path, bytes::Bytes::from(data.to_vec()), Some(offset), false).await {
+        match client.write_file_at(&self.remote_path(&path), bytes::Bytes::from(data.to_vec()), Some(offset), false).await {
             Ok(_) => {
+                self.io.record_write(auth.uid, data.len() as u64);
+                if let Some(read_ahead) = self.read_ahead() {
+                    read_ahead.invalidate(&self.remote_path(&path)).await;
+                }
                 // Get updated metadata
-                match self.client.get_metadata_with_options(&path, false).await {
+                match client.get_metadata_with_options(&self.remote_path(&path), false).await {
                     Ok(metadata) => {
                         let fattr = self.file_metadata_to_fattr(&metadata, id);
+                        if let Some(cache) = self.dir_cache() {
+                            cache.upsert(&self.remote_path(&path), metadata).await;
+                        }
                         debug!("Write successful for {}", path);
                         Ok(fattr)
                     }
                     Err(_) => Err(nfsstat3::NFS3ERR_IO),
                 }
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Write error for {}: {:?}", path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Write error for {}", path), &e)),
         }
     }
 
     async fn create(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         dirid: fileid3,
         filename: &filename3,
-        _attr: sattr3,
+        attr: sattr3,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
-        debug!("NFS create: dirid={}, filename={:?}", dirid, String::from_utf8_lossy(filename));
-        
-        let dir_path = match self.get_path_for_id(dirid).await {
-            Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
-        };
-        
-        let filename_str = String::from_utf8_lossy(filename);
-        let full_path = self.join_path(&dir_path, &filename_str);
-        
-        match self.client.write_file(&full_path, bytes::Bytes::new()).await {
-            Ok(_) => {
-                let file_id = self.get_or_create_file_id(&full_path).await;
-                
-                // Get file metadata
-                match self.client.get_metadata_with_options(&full_path, false).await {
-                    Ok(metadata) => {
-                        let fattr = self.file_metadata_to_fattr(&metadata, file_id);
-                        debug!("Create successful: {} -> {}", full_path, file_id);
-                        Ok((file_id, fattr))
-                    }
-                    Err(_) => Err(nfsstat3::NFS3ERR_IO),
-                }
-            }
-            Err(ClientError::RemoteFs(RemoteFsError::AlreadyExists(_))) => Err(nfsstat3::NFS3ERR_EXIST),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Create error for {}: {:?}", full_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
-        }
+        self.create_file(auth, dirid, filename, self.create_mode(&attr, 0o666), false).await
     }
 
     async fn mkdir(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         dirid: fileid3,
         dirname: &filename3,
-        _attr: &sattr3,
+        attr: &sattr3,
     ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
         debug!("NFS mkdir: dirid={}, dirname={:?}", dirid, String::from_utf8_lossy(dirname));
         
         let dir_path = match self.get_path_for_id(dirid).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
+        if self.is_export_root(&dir_path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
         
         let dirname_str = String::from_utf8_lossy(dirname);
         let full_path = self.join_path(&dir_path, &dirname_str);
         
-        match self.client.create_directory(&full_path).await {
-            Ok(_) => {
+        match client.create_directory_with_mode(&self.remote_path(&full_path), self.create_mode(attr, 0o777)).await {
+            Ok(metadata) => {
                 let dir_id = self.get_or_create_file_id(&full_path).await;
-                
-                // Get directory metadata
-                match self.client.get_metadata_with_options(&full_path, false).await {
-                    Ok(metadata) => {
-                        let fattr = self.file_metadata_to_fattr(&metadata, dir_id);
-                        debug!("Mkdir successful: {} -> {}", full_path, dir_id);
-                        Ok((dir_id, fattr))
-                    }
-                    Err(_) => Err(nfsstat3::NFS3ERR_IO),
+                let fattr = self.file_metadata_to_fattr(&metadata, dir_id);
+                if let Some(cache) = self.dir_cache() {
+                    cache.upsert(&self.remote_path(&full_path), metadata).await;
                 }
+                debug!("Mkdir successful: {} -> {}", full_path, dir_id);
+                Ok((dir_id, fattr))
             }
-            Err(ClientError::RemoteFs(RemoteFsError::AlreadyExists(_))) => Err(nfsstat3::NFS3ERR_EXIST),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Mkdir error for {}: {:?}", full_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Mkdir error for {}", full_path), &e)),
         }
     }
 
     async fn remove(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         dirid: fileid3,
         filename: &filename3,
     ) -> Result<(), nfsstat3> {
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
         debug!("NFS remove: dirid={}, filename={:?}", dirid, String::from_utf8_lossy(filename));
         
         let dir_path = match self.get_path_for_id(dirid).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
+        if self.is_export_root(&dir_path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
         
         let filename_str = String::from_utf8_lossy(filename);
         let full_path = self.join_path(&dir_path, &filename_str);
         
-        match self.client.delete_file(&full_path).await {
+        match client.delete_file(&self.remote_path(&full_path)).await {
             Ok(_) => {
                 // Remove from our mappings
-                {
-                    let mut path_map = self.path_to_id_map.write().await;
-                    let mut id_map = self.id_to_path_map.write().await;
-                    
-                    if let Some(id) = path_map.remove(&full_path) {
-                        id_map.remove(&id);
-                    }
+                self.forget_subtree(&full_path).await;
+                if let Some(cache) = self.dir_cache() {
+                    cache.remove(&self.remote_path(&full_path)).await;
+                }
+                if let Some(read_ahead) = self.read_ahead() {
+                    read_ahead.invalidate(&self.remote_path(&full_path)).await;
                 }
                 debug!("Remove successful: {}", full_path);
                 Ok(())
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Remove error for {}: {:?}", full_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Remove error for {}", full_path), &e)),
         }
     }
 
     async fn readdir(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         dirid: fileid3,
         start_after: fileid3,
         max_entries: usize,
     ) -> Result<ReadDirResult, nfsstat3> {
+        let client = self.client_for(auth).await;
         debug!("NFS readdir: dirid={}, start_after={}, max_entries={}", dirid, start_after, max_entries);
         
         let dir_path = match self.get_path_for_id(dirid).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
+        };
+        if self.is_export_root(&dir_path) {
+            return self.readdir_export_root(&client, start_after, max_entries).await;
+        }
+        
+        let parent_path = if dir_path == "/" {
+            "/".to_string()
+        } else {
+            let parent = std::path::Path::new(&dir_path).parent()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|| "/".to_string());
+            if parent.is_empty() { "/".to_string() } else { parent }
+        };
+        let parent_id = self.get_or_create_file_id(&parent_path).await;
+        
+        // Entries are resumed by name, not position, so a cookie stays valid
+        // while files are created and removed: entries sorting after the one
+        // the client saw last are listed, the rest skipped. The cookie points
+        // before `.`, between `.` and `..`, or behind a named entry; `.` and
+        // `..` share an ID in `/`.
+        let (with_dot, with_dotdot, after) = match start_after {
+            0 => (true, true, None),
+            id if id == dirid && id != parent_id => (false, true, None),
+            id if id == dirid || id == parent_id => (false, false, None),
+            id => match self.get_path_for_id(id).await {
+                Some(path) if self.join_path(&dir_path, entry_name(&path)) == path => {
+                    (false, false, Some(entry_name(&path).to_string()))
+                }
+                // Not an entry of this directory, e.g. renamed away
+                _ => return Err(nfsstat3::NFS3ERR_BAD_COOKIE),
+            },
+        };
+        
+        let mut dots = Vec::new();
+        if with_dot {
+            dots.push((dirid, ".", dir_path.as_str()));
+        }
+        if with_dotdot {
+            dots.push((parent_id, "..", parent_path.as_str()));
+        }
+        let dots_done = dots.len() <= max_entries;
+        let room = max_entries.saturating_sub(dots.len());
+        
+        // Only this reply's share of the directory is fetched, unless the
+        // cache holds all of it anyway
+        let listing = match self.dir_cache() {
+            Some(cache) => cache.list(&client, &self.remote_path(&dir_path)).await.map(|mut entries| {
+                entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
+                entries.retain(|entry| after.as_ref().is_none_or(|after| entry.name.as_bytes() > after.as_bytes()));
+                let more = entries.len() > room;
+                entries.truncate(room);
+                (entries, more)
+            }),
+            None if room == 0 => Ok((Vec::new(), true)),
+            None => client.list_directory_from(&self.remote_path(&dir_path), after.as_deref(), room as u32).await
+                .map(|(entries, cursor)| (entries, cursor.is_some())),
         };
         
-        match self.client.list_directory(&dir_path).await {
-            Ok(entries) => {
+        match listing {
+            Ok((entries, more)) => {
                 let mut nfs_entries = Vec::new();
-                let mut count = 0;
                 
                 // Add . and .. entries for NFS compatibility
-                if start_after == 0 {
-                    // Add . entry
-                    if count < max_entries {
-                        if let Ok(metadata) = self.client.get_metadata(&dir_path).await {
-                            let fattr = self.file_metadata_to_fattr(&metadata, dirid);
+                for (fileid, name, path) in dots.into_iter().take(max_entries) {
+                    if let Ok(metadata) = self.metadata(&client, path).await {
+                        let fattr = self.file_metadata_to_fattr(&metadata, fileid);
                         nfs_entries.push(NfsDirEntry {
-                            fileid: dirid,
-                            name: zerofs_nfsserve::nfs::nfsstring(b".".to_vec()),
+                            fileid,
+                            name: zerofs_nfsserve::nfs::nfsstring(name.as_bytes().to_vec()),
                             attr: fattr,
                         });
-                            count += 1;
-                        }
-                    }
-                    
-                    // Add .. entry (parent directory)
-                    if count < max_entries {
-                        let parent_path = if dir_path == "/" {
-                            "/".to_string()
-                        } else {
-                            let parent = std::path::Path::new(&dir_path).parent()
-                                .map(|p| p.to_string_lossy().to_string())
-                                .unwrap_or_else(|| "/".to_string());
-                            if parent.is_empty() { "/".to_string() } else { parent }
-                        };
-                        
-                        let parent_id = self.get_or_create_file_id(&parent_path).await;
-                        if let Ok(metadata) = self.client.get_metadata(&parent_path).await {
-                            let fattr = self.file_metadata_to_fattr(&metadata, parent_id);
-                            nfs_entries.push(NfsDirEntry {
-                                fileid: parent_id,
-                                name: zerofs_nfsserve::nfs::nfsstring(b"..".to_vec()),
-                                attr: fattr,
-                            });
-                            count += 1;
-                        }
                     }
                 }
                 
                 // Add directory entries
-                for entry in entries.into_iter().skip(if start_after == 0 { 0 } else { start_after as usize }) {
-                    if count >= max_entries {
-                        break;
-                    }
-                    
+                for entry in entries {
                     let entry_path = self.join_path(&dir_path, &entry.name);
                     let entry_id = self.get_or_create_file_id(&entry_path).await;
+                    let metadata = self.follow(&client, &self.remote_path(&entry_path), entry.metadata).await;
                     
-                    let fattr = self.file_metadata_to_fattr(&entry.metadata, entry_id);
+                    let fattr = self.file_metadata_to_fattr(&metadata, entry_id);
                     nfs_entries.push(NfsDirEntry {
                         fileid: entry_id,
                         name: zerofs_nfsserve::nfs::nfsstring(entry.name.into_bytes()),
                         attr: fattr,
                     });
-                    count += 1;
                 }
                 
                 debug!("Readdir successful: {} entries returned", nfs_entries.len());
                 Ok(ReadDirResult {
                     entries: nfs_entries,
-                    end: count < max_entries,
+                    end: dots_done && !more,
                 })
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Readdir error for {}: {:?}", dir_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Readdir error for {}", dir_path), &e)),
         }
     }
 
+    async fn fsinfo(&self, auth: &AuthContext, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        let obj_attributes = match self.getattr(auth, root_fileid).await {
+            Ok(attr) => post_op_attr::attributes(attr),
+            Err(_) => post_op_attr::Void,
+        };
+        
+        // Clients size their requests from these, never above the mount's
+        // rsize and wsize
+        let (read, write) = (self.transfer.max_read, self.transfer.max_write);
+        Ok(fsinfo3 {
+            obj_attributes,
+            rtmax: read,
+            rtpref: read,
+            rtmult: 4096,
+            wtmax: write,
+            wtpref: write,
+            wtmult: 4096,
+            dtpref: 1024 * 1024,
+            maxfilesize: 128 * 1024 * 1024 * 1024,
+            time_delta: nfstime3 { seconds: 0, nseconds: 1000000 },
+            properties: FSF_SYMLINK | FSF_HOMOGENEOUS | FSF_CANSETTIME,
+        })
+    }
+
+    async fn fsstat(&self, auth: &AuthContext, fileid: fileid3) -> Result<fsstat3, nfsstat3> {
+        let obj_attributes = match self.getattr(auth, fileid).await {
+            Ok(attr) => post_op_attr::attributes(attr),
+            Err(_) => post_op_attr::Void,
+        };
+        
+        let space = match self.get_path_for_id(fileid).await {
+            Some(path) if self.agent_exports.is_some() => self.export_space(&path)
+                .map(|(total_space, available_space)| SpaceInfo { total_space, available_space, ..SpaceInfo::default() }),
+            Some(path) => match self.client_for(auth).await.space_info(self.remote_path(&path)).await {
+                Ok(space) => Some(space),
+                Err(e) => {
+                    debug!("Space of {} unknown: {}", path, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (total, available) = space
+            .map(|space| (space.total_space, space.available_space))
+            .unwrap_or((UNKNOWN_SPACE, UNKNOWN_SPACE));
+        let (total_files, available_files) = space
+            .and_then(|space| space.total_files.zip(space.available_files))
+            .unwrap_or((UNKNOWN_FILES, UNKNOWN_FILES));
+        Ok(fsstat3 {
+            obj_attributes,
+            tbytes: total,
+            fbytes: available,
+            abytes: available,
+            tfiles: total_files,
+            ffiles: available_files,
+            afiles: available_files,
+            // Real figures change as files are written
+            invarsec: if space.is_some() { 0 } else { u32::MAX },
+        })
+    }
+
     // Implement additional NFS operations as needed
     async fn rename(
         &self,
-        _auth: &AuthContext,
+        auth: &AuthContext,
         from_dirid: fileid3,
         from_filename: &filename3,
         to_dirid: fileid3,
         to_filename: &filename3,
     ) -> Result<(), nfsstat3> {
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
         debug!("NFS rename: from_dirid={}, to_dirid={}", from_dirid, to_dirid);
         
         let from_dir_path = match self.get_path_for_id(from_dirid).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
         
         let to_dir_path = match self.get_path_for_id(to_dirid).await {
             Some(path) => path,
-            None => return Err(nfsstat3::NFS3ERR_NOENT),
+            None => return Err(nfsstat3::NFS3ERR_STALE),
         };
+        if self.is_export_root(&from_dir_path) || self.is_export_root(&to_dir_path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
         
         let from_filename_str = String::from_utf8_lossy(from_filename);
         let to_filename_str = String::from_utf8_lossy(to_filename);
         let from_path = self.join_path(&from_dir_path, &from_filename_str);
         let to_path = self.join_path(&to_dir_path, &to_filename_str);
         
-        match self.client.move_path(&from_path, &to_path).await {
+        match client.move_path(&self.remote_path(&from_path), &self.remote_path(&to_path)).await {
             Ok(_) => {
-                // Update our path mappings
-                {
-                    let mut path_map = self.path_to_id_map.write().await;
-                    let mut id_map = self.id_to_path_map.write().await;
-                    
-                    if let Some(id) = path_map.remove(&from_path) {
-                        path_map.insert(to_path.clone(), id);
-                        id_map.insert(id, to_path.clone());
-                    }
+                // Update our path mappings, including cached children of a renamed directory
+                self.remap_subtree(&from_path, &to_path).await;
+                if let Some(cache) = self.dir_cache() {
+                    cache.remove(&self.remote_path(&from_path)).await;
+                    cache.remove(&self.remote_path(&to_path)).await;
+                    cache.invalidate_parent(&self.remote_path(&to_path)).await;
+                }
+                if let Some(read_ahead) = self.read_ahead() {
+                    read_ahead.invalidate(&self.remote_path(&from_path)).await;
+                    read_ahead.invalidate(&self.remote_path(&to_path)).await;
                 }
                 debug!("Rename successful: {} -> {}", from_path, to_path);
                 Ok(())
             }
-            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_))) => Err(nfsstat3::NFS3ERR_NOENT),
-            Err(ClientError::RemoteFs(RemoteFsError::PermissionDenied(_))) => Err(nfsstat3::NFS3ERR_ACCES),
-            Err(e) => {
-                warn!("Rename error {} -> {}: {:?}", from_path, to_path, e);
-                Err(nfsstat3::NFS3ERR_IO)
-            }
+            Err(e) => Err(nfs_error(format_args!("Rename error {} -> {}", from_path, to_path), &e)),
         }
     }
 
     async fn setattr(
         &self,
-        _auth: &AuthContext,
-        _id: fileid3,
-        _setattr: sattr3,
+        auth: &AuthContext,
+        id: fileid3,
+        setattr: sattr3,
     ) -> Result<fattr3, nfsstat3> {
-        // For now, return the current attributes without making changes
-        // This could be extended to support permission changes, etc.
-        self.getattr(_auth, _id).await
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
+        debug!("NFS setattr: id={}, attr={:?}", id, setattr);
+        
+        let path = self.get_path_for_id(id).await.ok_or(nfsstat3::NFS3ERR_STALE)?;
+        let remote_path = self.remote_path(&path);
+        let update = metadata_update(&setattr, Utc::now())?;
+        
+        if let set_size3::size(size) = setattr.size {
+            let metadata = self.metadata(&client, &path).await
+                .map_err(|e| nfs_error(format_args!("setattr error for {}", path), &e))?;
+            if !matches!(metadata.file_type, FileType::File) {
+                return Err(nfsstat3::NFS3ERR_INVAL);
+            }
+            if size != metadata.size {
+                if let Err(e) = client.truncate_file(&remote_path, size).await {
+                    return Err(nfs_error(format_args!("setattr error for {}", path), &e));
+                }
+                if let Some(read_ahead) = self.read_ahead() {
+                    read_ahead.invalidate(&remote_path).await;
+                }
+            }
+        }
+        
+        if !update.is_empty() {
+            if let Err(e) = client.set_metadata(&remote_path, update).await {
+                return Err(nfs_error(format_args!("setattr error for {}", path), &e));
+            }
+        }
+        
+        match client.get_metadata_with_options(&remote_path, false).await {
+            Ok(metadata) => {
+                let fattr = self.file_metadata_to_fattr(&metadata, id);
+                if let Some(cache) = self.dir_cache() {
+                    cache.upsert(&remote_path, metadata).await;
+                }
+                Ok(fattr)
+            }
+            Err(e) => Err(nfs_error(format_args!("setattr error for {}", path), &e)),
+        }
     }
 
     // Stub implementations for less common operations
@@ -549,8 +1317,7 @@ impl NFSFileSystem for RemoteNfsFilesystem {
         dirid: fileid3,
         filename: &filename3,
     ) -> Result<fileid3, nfsstat3> {
-        // For exclusive create, just use regular create for now
-        match self.create(auth, dirid, filename, sattr3::default()).await {
+        match self.create_file(auth, dirid, filename, self.create_mode(&sattr3::default(), 0o666), true).await {
             Ok((fileid, _)) => Ok(fileid),
             Err(e) => Err(e),
         }
@@ -568,9 +1335,25 @@ impl NFSFileSystem for RemoteNfsFilesystem {
         Err(nfsstat3::NFS3ERR_NOTSUPP)
     }
 
-    async fn readlink(&self, _auth: &AuthContext, _id: fileid3) -> Result<nfspath3, nfsstat3> {
-        // Symlinks not supported for now
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+    async fn readlink(&self, auth: &AuthContext, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        let client = self.client_for(auth).await;
+        let path = self.get_path_for_id(id).await.ok_or(nfsstat3::NFS3ERR_STALE)?;
+        
+        let target = match client.read_symlink(&self.remote_path(&path)).await {
+            // Agents without `ReadSymlink` report the target in the link's metadata
+            Err(e) if matches!(e.cause(), ClientError::RemoteFs(RemoteFsError::NotImplemented(_))) => {
+                match self.metadata(&client, &path).await {
+                    Ok(FileMetadata { symlink_target: Some(target), .. }) => Ok(target),
+                    Ok(_) => return Err(nfsstat3::NFS3ERR_INVAL),
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        };
+        match target {
+            Ok(target) => Ok(self.presented_target(&path, target).into_bytes().into()),
+            Err(e) => Err(nfs_error(format_args!("readlink error for {}", path), &e)),
+        }
     }
 
     async fn mknod(
@@ -588,12 +1371,537 @@ impl NFSFileSystem for RemoteNfsFilesystem {
 
     async fn link(
         &self,
-        _auth: &AuthContext,
-        _id: fileid3,
-        _dirid: fileid3,
-        _filename: &filename3,
+        auth: &AuthContext,
+        id: fileid3,
+        dirid: fileid3,
+        filename: &filename3,
     ) -> Result<(), nfsstat3> {
-        // Hard links not supported for now
-        Err(nfsstat3::NFS3ERR_NOTSUPP)
+        self.check_writable().await?;
+        let client = self.client_for(auth).await;
+        debug!("NFS link: id={}, dirid={}, filename={:?}", id, dirid, String::from_utf8_lossy(filename));
+        
+        let existing_path = self.get_path_for_id(id).await.ok_or(nfsstat3::NFS3ERR_STALE)?;
+        let dir_path = self.get_path_for_id(dirid).await.ok_or(nfsstat3::NFS3ERR_STALE)?;
+        if self.is_export_root(&dir_path) {
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        
+        let filename_str = String::from_utf8_lossy(filename);
+        let link_path = self.join_path(&dir_path, &filename_str);
+        
+        match client.hard_link(&self.remote_path(&existing_path), &self.remote_path(&link_path)).await {
+            Ok(()) => {
+                // Both names now report another link
+                if let Some(cache) = self.dir_cache() {
+                    cache.invalidate_parent(&self.remote_path(&existing_path)).await;
+                    cache.invalidate_parent(&self.remote_path(&link_path)).await;
+                }
+                debug!("Link successful: {} -> {}", link_path, existing_path);
+                Ok(())
+            }
+            Err(e) => Err(nfs_error(format_args!("Link error {} -> {}", link_path, existing_path), &e)),
+        }
+    }
+}
+
+/// Check whether `path` is `root` itself or lies beneath it
+pub(crate) fn is_same_or_descendant(path: &str, root: &str) -> bool {
+    if root == "/" {
+        return true;
+    }
+    path == root || path.strip_prefix(root).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Last component of `path`
+fn entry_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Absolute `path` with `.` and `..` components resolved, without looking
+/// at the filesystem
+fn lexically_normal(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+/// Path leading from directory `from` to `to`, both absolute and normalized
+fn relative_path(from: &str, to: &str) -> String {
+    let from: Vec<&str> = from.split('/').filter(|component| !component.is_empty()).collect();
+    let to: Vec<&str> = to.split('/').filter(|component| !component.is_empty()).collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let components: Vec<&str> = std::iter::repeat_n("..", from.len() - common)
+        .chain(to[common..].iter().copied())
+        .collect();
+    if components.is_empty() {
+        ".".to_string()
+    } else {
+        components.join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_client::{AgentConfig, ClientConfig};
+
+    async fn create_test_filesystem() -> RemoteNfsFilesystem {
+        let client_config = ClientConfig {
+            agents: vec![AgentConfig {
+                id: "test".to_string(),
+                url: "ws://localhost:8080".to_string(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        };
+        RemoteNfsFilesystem::new(Client::new(client_config).unwrap()).await.unwrap()
+    }
+
+    #[test]
+    fn test_is_same_or_descendant() {
+        assert!(is_same_or_descendant("/a/b", "/a/b"));
+        assert!(is_same_or_descendant("/a/b/c", "/a/b"));
+        assert!(!is_same_or_descendant("/a/bc", "/a/b"));
+        assert!(!is_same_or_descendant("/a", "/a/b"));
+        assert!(is_same_or_descendant("/anything", "/"));
+    }
+
+    fn sample_metadata() -> FileMetadata {
+        FileMetadata {
+            size: 0,
+            modified: DateTime::from_timestamp(2_000, 0).unwrap(),
+            created: DateTime::from_timestamp(1_000, 0).unwrap(),
+            accessed: DateTime::from_timestamp(4_000, 0).unwrap(),
+            changed: DateTime::from_timestamp(3_000, 500).unwrap(),
+            permissions: 0o644,
+            uid: 0,
+            gid: 0,
+            is_dir: false,
+            is_file: true,
+            is_symlink: false,
+            hidden: false,
+            offline: false,
+            nlink: 1,
+            file_type: remotefs_common::protocol::FileType::File,
+            symlink_target: None,
+        }
+    }
+
+    #[test]
+    fn test_error_codes_map_to_nfs_status() {
+        use remotefs_common::protocol::{generate_request_id, ErrorCode};
+        let status = |code, errno| {
+            let error = RemoteFsError::from_error_response(code, errno, "/data".to_string());
+            nfs_status(&ClientError::RemoteFs(error).for_request(Some(generate_request_id())))
+        };
+        
+        assert!(matches!(status(ErrorCode::FileNotFound, None), nfsstat3::NFS3ERR_NOENT));
+        assert!(matches!(status(ErrorCode::DiskFull, Some(libc::ENOSPC)), nfsstat3::NFS3ERR_NOSPC));
+        assert!(matches!(status(ErrorCode::InsufficientPermissions, Some(libc::EACCES)), nfsstat3::NFS3ERR_ACCES));
+        assert!(matches!(status(ErrorCode::DirectoryNotEmpty, None), nfsstat3::NFS3ERR_NOTEMPTY));
+        assert!(matches!(status(ErrorCode::FileOffline, None), nfsstat3::NFS3ERR_JUKEBOX));
+        assert!(matches!(status(ErrorCode::InternalError, Some(libc::EXDEV)), nfsstat3::NFS3ERR_XDEV));
+        assert!(matches!(status(ErrorCode::InternalError, None), nfsstat3::NFS3ERR_IO));
+        assert!(matches!(nfs_status(&ClientError::Timeout { seconds: 30 }), nfsstat3::NFS3ERR_IO));
+    }
+
+    #[tokio::test]
+    async fn test_ctime_mapping() {
+        let fs = create_test_filesystem().await;
+        let metadata = sample_metadata();
+
+        let fattr = fs.file_metadata_to_fattr(&metadata, 2);
+        assert_eq!(fattr.ctime.seconds, 3_000);
+        assert_eq!(fattr.ctime.nseconds, 500);
+        assert_eq!(fattr.mtime.seconds, 2_000);
+        assert_eq!(fattr.atime.seconds, 4_000);
+
+        // Agents that do not report ctime
+        let legacy = FileMetadata { changed: DateTime::default(), ..sample_metadata() };
+        assert_eq!(fs.file_metadata_to_fattr(&legacy, 2).ctime.seconds, 2_000);
+
+        let fs = fs.with_birthtime_as_ctime(true);
+        assert_eq!(fs.file_metadata_to_fattr(&metadata, 2).ctime.seconds, 1_000);
+    }
+
+    #[tokio::test]
+    async fn test_file_types() {
+        let fs = create_test_filesystem().await;
+        let link = FileMetadata {
+            permissions: 0o120777,
+            is_file: false,
+            is_symlink: true,
+            file_type: FileType::Symlink,
+            symlink_target: Some("target.txt".to_string()),
+            ..sample_metadata()
+        };
+        let fattr = fs.file_metadata_to_fattr(&link, 2);
+        assert!(matches!(fattr.ftype, ftype3::NF3LNK));
+        assert_eq!(fattr.mode, 0o777);
+
+        let root = fs.file_metadata_to_fattr(&FileMetadata::synthetic_directory(0o555, Utc::now()), 1);
+        assert!(matches!(root.ftype, ftype3::NF3DIR));
+        assert_eq!(root.mode, 0o555);
+    }
+
+    #[tokio::test]
+    async fn test_create_mode() {
+        let fs = create_test_filesystem().await;
+        let attr = sattr3 { mode: set_mode3::mode(0o100640), ..sattr3::default() };
+        assert_eq!(fs.create_mode(&attr, 0o666), 0o640);
+        assert_eq!(fs.create_mode(&sattr3::default(), 0o777), 0o777);
+
+        let fs = fs.with_umask(0o027);
+        assert_eq!(fs.create_mode(&attr, 0o666), 0o640);
+        assert_eq!(fs.create_mode(&sattr3 { mode: set_mode3::mode(0o4777), ..sattr3::default() }, 0o666), 0o4750);
+        assert_eq!(fs.create_mode(&sattr3::default(), 0o666), 0o640);
+    }
+
+    #[test]
+    fn test_metadata_update() {
+        let now = DateTime::from_timestamp(5_000, 0).unwrap();
+        assert!(metadata_update(&sattr3::default(), now).unwrap().is_empty());
+
+        // chmod keeps setuid and sticky bits but not the file type
+        let attr = sattr3 { mode: set_mode3::mode(0o104755), ..sattr3::default() };
+        let update = metadata_update(&attr, now).unwrap();
+        assert_eq!(update.permissions, Some(0o4755));
+        assert!(update.uid.is_none() && update.accessed.is_none());
+
+        let attr = sattr3 { gid: set_gid3::gid(20), ..sattr3::default() };
+        let update = metadata_update(&attr, now).unwrap();
+        assert_eq!((update.uid, update.gid), (None, Some(20)));
+
+        // touch -t sets both times; plain touch leaves them to the server
+        let attr = sattr3 {
+            atime: set_atime::SET_TO_CLIENT_TIME(nfstime3 { seconds: 1_000, nseconds: 250 }),
+            mtime: set_mtime::SET_TO_SERVER_TIME,
+            ..sattr3::default()
+        };
+        let update = metadata_update(&attr, now).unwrap();
+        assert_eq!(update.accessed, DateTime::from_timestamp(1_000, 250));
+        assert_eq!(update.modified, Some(now));
+
+        let attr = sattr3 {
+            mtime: set_mtime::SET_TO_CLIENT_TIME(nfstime3 { seconds: 1, nseconds: 2_000_000_000 }),
+            ..sattr3::default()
+        };
+        assert!(matches!(metadata_update(&attr, now), Err(nfsstat3::NFS3ERR_INVAL)));
+    }
+
+    #[tokio::test]
+    async fn test_client_for_forwards_caller() {
+        let auth = AuthContext { uid: 501, gid: 20, gids: vec![12] };
+        
+        let fs = create_test_filesystem().await;
+        assert!(fs.client_for(&auth).await.caller().is_none());
+        
+        let fs = fs.with_forward_caller_identity(true);
+        let client = fs.client_for(&auth).await;
+        assert_eq!(
+            client.caller(),
+            Some(&CallerIdentity { uid: 501, gid: 20, groups: vec![12] })
+        );
+        // Clones keep the setting
+        assert!(fs.clone().client_for(&auth).await.caller().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_operations() {
+        let auth = AuthContext { uid: 501, gid: 20, gids: vec![] };
+        let fs = create_test_filesystem().await.with_max_concurrent_operations(2);
+        let wait = Duration::from_millis(50);
+        
+        let first = fs.client_for(&auth).await;
+        // Clones are the same mount and share its limit
+        let second = fs.clone().client_for(&auth).await;
+        assert!(tokio::time::timeout(wait, fs.client_for(&auth)).await.is_err());
+        
+        drop(first);
+        let third = tokio::time::timeout(wait, fs.client_for(&auth)).await;
+        assert!(third.is_ok());
+        drop(second);
+        
+        let unlimited = create_test_filesystem().await.with_max_concurrent_operations(0);
+        let held: Vec<_> = futures::future::join_all((0..100).map(|_| unlimited.client_for(&auth))).await;
+        assert_eq!(held.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_directory_cache_not_shared_between_callers() {
+        let fs = create_test_filesystem().await;
+        assert!(fs.dir_cache().is_none());
+        
+        let fs = fs.with_directory_cache(&DirectoryCacheConfig::default());
+        assert!(fs.dir_cache().is_some());
+        assert!(fs.clone().dir_cache().is_some());
+        
+        let fs = fs.with_forward_caller_identity(true);
+        assert!(fs.dir_cache().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_not_shared_between_callers() {
+        let fs = create_test_filesystem().await;
+        assert!(fs.with_read_ahead(&ReadAheadConfig::default()).read_ahead().is_none());
+        
+        let fs = create_test_filesystem().await;
+        let fs = fs.with_read_ahead(&ReadAheadConfig { enabled: true, ..ReadAheadConfig::default() });
+        assert!(fs.read_ahead().is_some());
+        assert!(fs.clone().read_ahead().is_some());
+        
+        let fs = fs.with_forward_caller_identity(true);
+        assert!(fs.read_ahead().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remote_path_with_export_root() {
+        let fs = create_test_filesystem().await;
+        assert_eq!(fs.remote_path("/a/b"), "/a/b");
+        
+        let fs = RemoteNfsFilesystem::with_root(Arc::clone(&fs.client), "/srv/data/").await.unwrap();
+        assert_eq!(fs.remote_root, "/srv/data");
+        assert_eq!(fs.remote_path("/"), "/srv/data");
+        assert_eq!(fs.remote_path("/a/b/"), "/srv/data/a/b");
+    }
+
+    #[tokio::test]
+    async fn test_remote_path_with_agent_exports() {
+        let fs = create_test_filesystem().await.with_agent_exports();
+        let export = |name: &str, path: &str, space: Option<(u64, u64)>| ExportInfo {
+            name: name.to_string(),
+            path: path.to_string(),
+            read_only: false,
+            total_space: space.map(|(total, _)| total),
+            available_space: space.map(|(_, available)| available),
+        };
+        fs.agent_exports.as_ref().unwrap().write().unwrap().exports = vec![
+            export("projects", "/home/alice/projects", Some((1000, 400))),
+            export("root", "/", None),
+        ];
+        
+        assert_eq!(fs.remote_path("/projects"), "/home/alice/projects");
+        assert_eq!(fs.remote_path("/projects/a/b/"), "/home/alice/projects/a/b");
+        assert_eq!(fs.remote_path("/root/etc"), "/etc");
+        assert_eq!(fs.remote_path("/"), "");
+        assert_eq!(fs.remote_path("/unknown/a"), "");
+        
+        assert!(fs.is_export_root("/"));
+        assert!(!fs.is_export_root("/projects"));
+        let root = fs.metadata(&fs.client, "/").await.unwrap();
+        assert!(root.is_dir);
+        assert_eq!(root.permissions & 0o222, 0);
+        assert!(matches!(
+            fs.metadata(&fs.client, "/unknown").await,
+            Err(ClientError::RemoteFs(RemoteFsError::NotFound(_)))
+        ));
+        
+        assert_eq!(fs.export_space("/projects/a"), Some((1000, 400)));
+        assert_eq!(fs.export_space("/root"), None);
+        assert_eq!(fs.export_space("/"), None);
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(lexically_normal("/srv/./data/../data//a/"), "/srv/data/a");
+        assert_eq!(lexically_normal("/../etc"), "/etc");
+        assert_eq!(relative_path("/a/b", "/a/c/d"), "../c/d");
+        assert_eq!(relative_path("/", "/a"), "a");
+        assert_eq!(relative_path("/a/b", "/"), "../..");
+        assert_eq!(relative_path("/a", "/a"), ".");
+    }
+
+    #[tokio::test]
+    async fn test_symlink_targets() {
+        let fs = create_test_filesystem().await;
+        let fs = RemoteNfsFilesystem::with_root(Arc::clone(&fs.client), "/srv/data").await.unwrap();
+        // Targets are presented as they are unless the mount rewrites them
+        assert_eq!(fs.presented_target("/a/link", "/srv/data/b".to_string()), "/srv/data/b");
+        
+        let fs = fs.with_symlinks(SymlinkPolicy::Rewrite);
+        assert_eq!(fs.presented_target("/a/link", "/srv/data/b/c".to_string()), "../b/c");
+        assert_eq!(fs.presented_target("/link", "/srv/data/b".to_string()), "b");
+        assert_eq!(fs.presented_target("/a/link", "/srv/data".to_string()), "..");
+        assert_eq!(fs.presented_target("/a/link", "/srv/data/x/../b".to_string()), "../b");
+        // Relative targets and targets outside the export are left alone
+        assert_eq!(fs.presented_target("/a/link", "../b".to_string()), "../b");
+        assert_eq!(fs.presented_target("/a/link", "/etc/passwd".to_string()), "/etc/passwd");
+        assert_eq!(fs.presented_target("/a/link", "/srv/database".to_string()), "/srv/database");
+        
+        // Between the agent's exports listed in the root
+        let fs = create_test_filesystem().await.with_agent_exports().with_symlinks(SymlinkPolicy::Rewrite);
+        fs.agent_exports.as_ref().unwrap().write().unwrap().exports = ["projects", "shared"].iter()
+            .map(|name| ExportInfo {
+                name: name.to_string(),
+                path: format!("/home/{}", name),
+                read_only: false,
+                total_space: None,
+                available_space: None,
+            })
+            .collect();
+        assert_eq!(fs.presented_target("/projects/app/link", "/home/shared/lib".to_string()), "../../shared/lib");
+        assert_eq!(fs.presented_target("/projects/link", "/home/other".to_string()), "/home/other");
+    }
+
+    #[tokio::test]
+    async fn test_deep_tree_rename_remaps_children() {
+        let fs = create_test_filesystem().await;
+        
+        let dir_id = fs.get_or_create_file_id("/projects/app").await;
+        let child_id = fs.get_or_create_file_id("/projects/app/src").await;
+        let deep_id = fs.get_or_create_file_id("/projects/app/src/lib/mod.rs").await;
+        let sibling_id = fs.get_or_create_file_id("/projects/application").await;
+        
+        fs.remap_subtree("/projects/app", "/archive/app-old").await;
+        
+        // IDs survive the move and resolve to the new paths
+        assert_eq!(fs.get_path_for_id(dir_id).await.as_deref(), Some("/archive/app-old"));
+        assert_eq!(fs.get_path_for_id(child_id).await.as_deref(), Some("/archive/app-old/src"));
+        assert_eq!(fs.get_path_for_id(deep_id).await.as_deref(), Some("/archive/app-old/src/lib/mod.rs"));
+        assert_eq!(fs.get_or_create_file_id("/archive/app-old/src/lib/mod.rs").await, deep_id);
+        
+        // Nothing is left behind under the old path
+        let path_map = fs.path_to_id_map.read().await;
+        assert!(!path_map.keys().any(|p| p.starts_with("/projects/app/") || p == "/projects/app"));
+        drop(path_map);
+        
+        // A sibling sharing the name prefix is untouched
+        assert_eq!(fs.get_path_for_id(sibling_id).await.as_deref(), Some("/projects/application"));
+    }
+
+    #[tokio::test]
+    async fn test_handles_survive_restart() {
+        let before = create_test_filesystem().await;
+        let dir_id = before.get_or_create_file_id("/projects").await;
+        let file_id = before.get_or_create_file_id("/projects/notes.txt").await;
+        before.remap_subtree("/projects/notes.txt", "/projects/todo.txt").await;
+        let handle = before.id_to_fh(file_id);
+        let snapshot = before.handle_snapshot().await;
+
+        // A restarted server refuses old handles until it has the saved ids
+        let mut after = create_test_filesystem().await;
+        after.generation = before.generation + 1;
+        assert!(matches!(after.fh_to_id(&handle), Err(nfsstat3::NFS3ERR_STALE)));
+        let auth = AuthContext { uid: 501, gid: 20, gids: vec![] };
+        assert!(matches!(after.getattr(&auth, file_id).await, Err(nfsstat3::NFS3ERR_STALE)));
+
+        after.restore_handles(snapshot).await;
+        assert!(matches!(after.fh_to_id(&handle), Ok(id) if id == file_id));
+        assert_eq!(after.get_path_for_id(file_id).await.as_deref(), Some("/projects/todo.txt"));
+        assert_eq!(after.get_or_create_file_id("/projects").await, dir_id);
+
+        // Paths given out after the last save get their old ids again
+        let fresh = create_test_filesystem().await;
+        assert_eq!(fresh.get_or_create_file_id("/projects").await, dir_id);
+    }
+
+    #[tokio::test]
+    async fn test_rename_over_existing_target_drops_its_mappings() {
+        let fs = create_test_filesystem().await;
+        
+        let source_id = fs.get_or_create_file_id("/a/dir").await;
+        let source_child_id = fs.get_or_create_file_id("/a/dir/file.txt").await;
+        let target_id = fs.get_or_create_file_id("/b/dir").await;
+        let target_child_id = fs.get_or_create_file_id("/b/dir/stale.txt").await;
+        
+        fs.remap_subtree("/a/dir", "/b/dir").await;
+        
+        assert_eq!(fs.get_path_for_id(source_id).await.as_deref(), Some("/b/dir"));
+        assert_eq!(fs.get_path_for_id(source_child_id).await.as_deref(), Some("/b/dir/file.txt"));
+        assert!(fs.get_path_for_id(target_id).await.is_none());
+        assert!(fs.get_path_for_id(target_child_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forget_subtree() {
+        let fs = create_test_filesystem().await;
+        
+        let dir_id = fs.get_or_create_file_id("/tmp/dir").await;
+        let child_id = fs.get_or_create_file_id("/tmp/dir/nested/file").await;
+        let other_id = fs.get_or_create_file_id("/tmp/dir2").await;
+        
+        fs.forget_subtree("/tmp/dir").await;
+        
+        assert!(fs.get_path_for_id(dir_id).await.is_none());
+        assert!(fs.get_path_for_id(child_id).await.is_none());
+        assert_eq!(fs.get_path_for_id(other_id).await.as_deref(), Some("/tmp/dir2"));
+        assert_eq!(fs.get_path_for_id(fs.root_id).await.as_deref(), Some("/"));
+    }
+
+    #[tokio::test]
+    async fn test_setattr_truncates_to_any_size() {
+        use remotefs_client::{Direction, RecordedMessage, ReplayAgent};
+        use remotefs_common::protocol::{generate_request_id, Message};
+        
+        let recorded = |direction, message| RecordedMessage { elapsed_ms: 0, agent_id: "test".to_string(), direction, message };
+        let metadata = |request_id, size| Message::GetMetadataResponse {
+            request_id,
+            success: true,
+            metadata: Some(FileMetadata { size, ..sample_metadata() }),
+            error: None,
+        };
+        let (before, truncate, after) = (generate_request_id(), generate_request_id(), generate_request_id());
+        let agent = Arc::new(ReplayAgent::new(vec![
+            recorded(Direction::Sent, Message::GetMetadata { request_id: before, path: "/file".to_string(), follow_symlinks: false }),
+            recorded(Direction::Received, metadata(before, 10)),
+            recorded(Direction::Sent, Message::TruncateFile { request_id: truncate, path: "/file".to_string(), size: 3 }),
+            recorded(Direction::Received, Message::TruncateFileResponse { request_id: truncate, success: true, error: None }),
+            recorded(Direction::Sent, Message::GetMetadata { request_id: after, path: "/file".to_string(), follow_symlinks: false }),
+            recorded(Direction::Received, metadata(after, 3)),
+        ]));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(Arc::clone(&agent).serve(listener));
+        
+        let mut client_config = ClientConfig {
+            agents: vec![AgentConfig { id: "test".to_string(), url, auth: None, weight: 1, enabled: true }],
+            ..Default::default()
+        };
+        client_config.client.max_retries = 0;
+        let client = Client::new(client_config).unwrap();
+        client.initialize().await.unwrap();
+        let fs = RemoteNfsFilesystem::new(client).await.unwrap();
+        
+        // Sizes other than zero are cut to on the agent as well
+        let auth = AuthContext { uid: 501, gid: 20, gids: vec![] };
+        let id = fs.get_or_create_file_id("/file").await;
+        let attr = sattr3 { size: set_size3::size(3), ..sattr3::default() };
+        let fattr = fs.setattr(&auth, id, attr).await.unwrap();
+        assert_eq!(fattr.size, 3);
+        assert_eq!(agent.remaining(), 0);
+        assert!(agent.unmatched().is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_changes_refused_during_maintenance() {
+        let auth = AuthContext { uid: 501, gid: 20, gids: vec![] };
+        let fs = create_test_filesystem().await;
+        assert!(fs.check_writable().await.is_ok());
+        
+        let now = Utc::now();
+        let window = |starts_at| remotefs_common::protocol::MaintenanceWindow {
+            agent_id: None,
+            message: "Disk replacement".to_string(),
+            starts_at,
+            ends_at: Some(now + chrono::Duration::hours(1)),
+        };
+        // Scheduled but not yet started
+        fs.client.announcements().set_maintenance(vec![window(now + chrono::Duration::minutes(30))]).await;
+        assert!(fs.check_writable().await.is_ok());
+        
+        fs.client.announcements().set_maintenance(vec![window(now - chrono::Duration::minutes(5))]).await;
+        assert!(matches!(fs.write(&auth, fs.root_id, 0, b"data").await, Err(nfsstat3::NFS3ERR_ROFS)));
+        assert!(matches!(fs.mkdir(&auth, fs.root_id, &b"dir".as_slice().into(), &sattr3::default()).await, Err(nfsstat3::NFS3ERR_ROFS)));
+        assert!(matches!(fs.remove(&auth, fs.root_id, &b"file".as_slice().into()).await, Err(nfsstat3::NFS3ERR_ROFS)));
     }
 }
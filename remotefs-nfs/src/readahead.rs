@@ -0,0 +1,380 @@
+//! Read-ahead for sequential readers such as media players
+//!
+//! Once a file has been read sequentially a few times in a row, the next
+//! window of it is fetched in the background, split into ranged reads that
+//! are issued concurrently, and later reads are answered from that window.
+//! Playback then waits on one round trip per window rather than one per NFS
+//! read. At most two windows are kept per file, and only for the most
+//! recently read files.
+//!
+//! A window may outlive changes made to its file by other clients of the
+//! agent, so a background scrubber re-hashes a few fetched windows at a time
+//! on the agent and drops any that no longer match.
+
+use crate::config::ReadAheadConfig;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture, FutureExt, Shared};
+use remotefs_client::Client;
+use remotefs_common::checksum::Checksum;
+use remotefs_common::protocol::ChecksumAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+/// A window being fetched or fetched already; `None` if the fetch failed
+type Window = Shared<BoxFuture<'static, Option<Bytes>>>;
+
+/// Windows kept per file: the one being read and the one after it
+const WINDOWS_PER_FILE: usize = 2;
+
+struct Prefetched {
+    start: u64,
+    window: Window,
+    /// When the scrubber last found the window to match the file
+    verified: Option<Instant>,
+}
+
+struct Stream {
+    /// Where the next read starts if the reader is sequential
+    next_offset: Option<u64>,
+    sequential_reads: u32,
+    windows: VecDeque<Prefetched>,
+    last_used: Instant,
+}
+
+/// What the scrubber has done so far
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubStats {
+    pub rounds: u64,
+    pub windows_checked: u64,
+    pub bytes_checked: u64,
+    /// Windows dropped because they no longer matched the file
+    pub evicted: u64,
+    /// Windows dropped because the agent could not hash them
+    pub errors: u64,
+    pub last_round: Option<DateTime<Utc>>,
+}
+
+/// Per-file sequential read detection and prefetching, keyed by agent path
+pub struct ReadAhead {
+    config: ReadAheadConfig,
+    streams: Mutex<HashMap<String, Stream>>,
+    scrub_stats: std::sync::Mutex<ScrubStats>,
+}
+
+impl ReadAhead {
+    pub fn new(config: ReadAheadConfig) -> Self {
+        Self {
+            config,
+            streams: Mutex::new(HashMap::new()),
+            scrub_stats: std::sync::Mutex::new(ScrubStats::default()),
+        }
+    }
+
+    fn window_len(&self) -> u64 {
+        self.config.window_mb.max(1) * 1024 * 1024
+    }
+
+    /// Answer a read of `count` bytes at `offset` from a prefetched window
+    ///
+    /// Returns the data and whether it ends at end of file, or `None` if no
+    /// window covers the read and it has to go to the agent.
+    pub async fn read(&self, client: &Arc<Client>, path: &str, offset: u64, count: u32) -> Option<(Bytes, bool)> {
+        let window = self.track(client, path, offset, count).await?;
+        let (start, window) = window;
+        let data = window.await?;
+
+        let relative = (offset - start) as usize;
+        let end = relative + count as usize;
+        let at_eof = (data.len() as u64) < self.window_len();
+        if end <= data.len() {
+            Some((data.slice(relative..end), at_eof && end == data.len()))
+        } else if at_eof && relative <= data.len() {
+            Some((data.slice(relative..), true))
+        } else {
+            // Straddles the end of the window
+            None
+        }
+    }
+
+    /// Forget what is known about `path`, e.g. after it was written
+    pub async fn invalidate(&self, path: &str) {
+        self.streams.lock().await.remove(path);
+    }
+
+    /// Record the read, start prefetching when the reader is sequential and
+    /// return the window covering the read, if any
+    async fn track(&self, client: &Arc<Client>, path: &str, offset: u64, count: u32) -> Option<(u64, Window)> {
+        let window_len = self.window_len();
+        let mut streams = self.streams.lock().await;
+        if !streams.contains_key(path) && streams.len() >= self.config.max_streams.max(1) {
+            let oldest = streams.iter().min_by_key(|(_, stream)| stream.last_used).map(|(path, _)| path.clone());
+            if let Some(oldest) = oldest {
+                streams.remove(&oldest);
+            }
+        }
+
+        let stream = streams.entry(path.to_string()).or_insert_with(|| Stream {
+            next_offset: None,
+            sequential_reads: 0,
+            windows: VecDeque::new(),
+            last_used: Instant::now(),
+        });
+        stream.last_used = Instant::now();
+        if stream.next_offset == Some(offset) {
+            stream.sequential_reads += 1;
+        } else {
+            stream.sequential_reads = 0;
+            stream.windows.retain(|prefetched| (prefetched.start..prefetched.start + window_len).contains(&offset));
+        }
+        let read_end = offset + count as u64;
+        stream.next_offset = Some(read_end);
+
+        // Keep a window ahead of the reader once it has proven sequential
+        if stream.sequential_reads >= self.config.trigger_reads {
+            let next_start = match stream.windows.back().map(|prefetched| prefetched.start) {
+                Some(start) if read_end + window_len / 2 < start + window_len => None,
+                Some(start) if (start..start + window_len).contains(&offset) => Some(start + window_len),
+                _ => Some(read_end),
+            };
+            if let Some(next_start) = next_start {
+                debug!("Prefetching {} bytes of {} at {}", window_len, path, next_start);
+                let window = self.fetch(client, path, next_start);
+                stream.windows.push_back(Prefetched { start: next_start, window, verified: None });
+                if stream.windows.len() > WINDOWS_PER_FILE {
+                    stream.windows.pop_front();
+                }
+            }
+        }
+
+        stream.windows.iter()
+            .find(|prefetched| (prefetched.start..prefetched.start + window_len).contains(&offset))
+            .map(|prefetched| (prefetched.start, prefetched.window.clone()))
+    }
+
+    /// What the scrubber has done so far
+    pub fn scrub_stats(&self) -> ScrubStats {
+        self.scrub_stats.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Scrub every `interval` until the task is cancelled
+    pub async fn run_scrubber(self: Arc<Self>, client: Arc<Client>, interval: Duration) {
+        info!("Scrubbing read-ahead windows every {:?}", interval);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately, when there is nothing to scrub yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.scrub(&client).await;
+        }
+    }
+
+    /// Check up to `scrub_windows` fetched windows against the agent's hash
+    /// of the same range, dropping those that differ
+    ///
+    /// Windows never checked go first, then those checked longest ago, and
+    /// among them those of the files read least recently, so readers are
+    /// disturbed as little as possible. Windows are checked one at a time.
+    pub async fn scrub(&self, client: &Client) {
+        let window_len = self.window_len();
+        let mut candidates = Vec::new();
+        for (path, stream) in self.streams.lock().await.iter() {
+            for prefetched in &stream.windows {
+                // Windows still being fetched or whose fetch failed hold nothing to check
+                if let Some(Some(data)) = prefetched.window.peek() {
+                    candidates.push((prefetched.verified, stream.last_used, path.clone(), prefetched.start, data.clone()));
+                }
+            }
+        }
+        candidates.sort_by_key(|(verified, last_used, ..)| (*verified, *last_used));
+        candidates.truncate(self.config.scrub_windows);
+
+        let mut round = ScrubStats { rounds: 1, ..ScrubStats::default() };
+        for (_, _, path, start, data) in candidates {
+            // Asking for a whole window also catches a file grown past a short last window
+            let expected = Checksum::of(ChecksumAlgorithm::Blake3, &data);
+            let matches = match client.checksum_range(&path, ChecksumAlgorithm::Blake3, start, Some(window_len)).await {
+                Ok(checksum) if checksum == expected => true,
+                Ok(_) => {
+                    round.evicted += 1;
+                    false
+                }
+                Err(e) => {
+                    debug!("Could not scrub {} at {}: {}", path, start, e);
+                    round.errors += 1;
+                    false
+                }
+            };
+            round.windows_checked += 1;
+            round.bytes_checked += data.len() as u64;
+
+            let mut streams = self.streams.lock().await;
+            let Some(stream) = streams.get_mut(&path) else { continue };
+            if matches {
+                if let Some(prefetched) = stream.windows.iter_mut().find(|prefetched| prefetched.start == start) {
+                    prefetched.verified = Some(Instant::now());
+                }
+            } else {
+                debug!("Dropping read-ahead window of {} at {}", path, start);
+                stream.windows.retain(|prefetched| prefetched.start != start);
+            }
+        }
+
+        let mut stats = self.scrub_stats.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.rounds += round.rounds;
+        stats.windows_checked += round.windows_checked;
+        stats.bytes_checked += round.bytes_checked;
+        stats.evicted += round.evicted;
+        stats.errors += round.errors;
+        stats.last_round = Some(Utc::now());
+    }
+
+    /// Start fetching a window as concurrent ranged reads
+    fn fetch(&self, client: &Arc<Client>, path: &str, start: u64) -> Window {
+        let parallelism = self.config.parallelism.max(1) as u64;
+        let chunk_len = self.window_len().div_ceil(parallelism);
+        let client = Arc::clone(client);
+        let path = path.to_string();
+
+        let fetch = async move {
+            let reads = (0..parallelism).map(|i| {
+                client.read_file_range(&path, Some(start + i * chunk_len), Some(chunk_len))
+            });
+            let chunks = future::join_all(reads).await;
+
+            let mut window = Vec::with_capacity((chunk_len * parallelism) as usize);
+            for chunk in chunks {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug!("Prefetch of {} failed: {}", path, e);
+                        return None;
+                    }
+                };
+                window.extend_from_slice(&chunk);
+                // End of file; later chunks are empty
+                if (chunk.len() as u64) < chunk_len {
+                    break;
+                }
+            }
+            Some(Bytes::from(window))
+        };
+
+        let window = fetch.boxed().shared();
+        tokio::spawn(window.clone());
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_client::{AgentConfig, ClientConfig};
+
+    fn unreachable_client() -> Arc<Client> {
+        Arc::new(Client::new(ClientConfig {
+            agents: vec![AgentConfig {
+                id: "test".to_string(),
+                url: "ws://127.0.0.1:1".to_string(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        }).unwrap())
+    }
+
+    fn read_ahead() -> ReadAhead {
+        ReadAhead::new(ReadAheadConfig {
+            enabled: true,
+            trigger_reads: 2,
+            window_mb: 1,
+            parallelism: 4,
+            max_streams: 2,
+            scrub_windows: 1,
+            ..ReadAheadConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_sequential_detection() {
+        let client = unreachable_client();
+        let read_ahead = read_ahead();
+
+        assert!(read_ahead.track(&client, "/movie.mkv", 0, 4096).await.is_none());
+        assert!(read_ahead.track(&client, "/movie.mkv", 4096, 4096).await.is_none());
+
+        // Random access does not prefetch
+        assert!(read_ahead.track(&client, "/random.db", 0, 4096).await.is_none());
+        assert!(read_ahead.track(&client, "/random.db", 65536, 4096).await.is_none());
+        assert!(read_ahead.streams.lock().await["/random.db"].windows.is_empty());
+
+        // The second read continuing the last starts a window where the next
+        // read will start
+        let window = read_ahead.track(&client, "/movie.mkv", 8192, 4096).await;
+        assert!(window.is_none());
+        let streams = read_ahead.streams.lock().await;
+        assert_eq!(streams["/movie.mkv"].windows.front().map(|prefetched| prefetched.start), Some(12288));
+        drop(streams);
+
+        let (start, window) = read_ahead.track(&client, "/movie.mkv", 12288, 4096).await.unwrap();
+        assert_eq!(start, 12288);
+        // The agent is unreachable, so the read falls back to it
+        assert!(window.await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_eviction_and_invalidation() {
+        let client = unreachable_client();
+        let read_ahead = read_ahead();
+
+        read_ahead.track(&client, "/a", 0, 1).await;
+        read_ahead.track(&client, "/b", 0, 1).await;
+        read_ahead.track(&client, "/c", 0, 1).await;
+        let streams = read_ahead.streams.lock().await;
+        assert_eq!(streams.len(), 2);
+        assert!(!streams.contains_key("/a"));
+        drop(streams);
+
+        read_ahead.invalidate("/b").await;
+        assert!(!read_ahead.streams.lock().await.contains_key("/b"));
+    }
+
+    #[tokio::test]
+    async fn test_scrub_drops_unverifiable_windows() {
+        let client = unreachable_client();
+        let read_ahead = read_ahead();
+
+        let fetched: Window = future::ready(Some(Bytes::from_static(b"frame"))).boxed().shared();
+        fetched.clone().await;
+        let pending: Window = future::pending().boxed().shared();
+        read_ahead.streams.lock().await.insert("/movie.mkv".to_string(), Stream {
+            next_offset: None,
+            sequential_reads: 0,
+            windows: VecDeque::from([
+                Prefetched { start: 0, window: pending, verified: None },
+                Prefetched { start: 1048576, window: fetched.clone(), verified: None },
+                Prefetched { start: 2097152, window: fetched, verified: Some(Instant::now()) },
+            ]),
+            last_used: Instant::now(),
+        });
+
+        // Only the fetched window never checked is scrubbed; the agent cannot
+        // vouch for it, so it is dropped
+        read_ahead.scrub(&client).await;
+        let starts: Vec<u64> = read_ahead.streams.lock().await["/movie.mkv"].windows.iter()
+            .map(|prefetched| prefetched.start)
+            .collect();
+        assert_eq!(starts, vec![0, 2097152]);
+
+        let stats = read_ahead.scrub_stats();
+        assert_eq!((stats.rounds, stats.windows_checked, stats.bytes_checked), (1, 1, 5));
+        assert_eq!((stats.evicted, stats.errors), (0, 1));
+        assert!(stats.last_round.is_some());
+    }
+}
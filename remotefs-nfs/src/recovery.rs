@@ -0,0 +1,219 @@
+//! Recovery after sleep/wake and network changes
+//!
+//! After a long sleep, or when the Mac moves between networks, agent
+//! connections can be left half-open and the kernel's NFS mount of the
+//! localhost server stops responding. Neither is reported to us, so we poll:
+//! a wake shows up as the wall clock jumping ahead of the monotonic clock
+//! (which stops while asleep), and a network change as a different source
+//! address on the default route.
+
+use crate::{mount, ControlState, RecoveryConfig, ResolvedExport};
+use remotefs_client::Client;
+use std::fmt;
+use std::net::{IpAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Documentation address (TEST-NET-1) used to look up the default route;
+/// connecting a UDP socket sends no packets
+const ROUTE_PROBE_ADDR: &str = "192.0.2.1:9";
+
+/// Something that invalidates agent connections or mounts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecoveryEvent {
+    Wake { slept: Duration },
+    NetworkChange { from: Option<IpAddr>, to: Option<IpAddr> },
+}
+
+impl fmt::Display for RecoveryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn addr(addr: &Option<IpAddr>) -> String {
+            addr.map(|a| a.to_string()).unwrap_or_else(|| "offline".to_string())
+        }
+
+        match self {
+            RecoveryEvent::Wake { slept } => write!(f, "woke after sleeping {}s", slept.as_secs()),
+            RecoveryEvent::NetworkChange { from, to } => {
+                write!(f, "network changed from {} to {}", addr(from), addr(to))
+            }
+        }
+    }
+}
+
+/// Detects wake-ups and network changes between successive observations
+pub struct WakeDetector {
+    threshold: Duration,
+    last_wall: SystemTime,
+    last_monotonic: Instant,
+    last_route: Option<IpAddr>,
+}
+
+impl WakeDetector {
+    pub fn new(threshold: Duration, route: Option<IpAddr>) -> Self {
+        Self {
+            threshold,
+            last_wall: SystemTime::now(),
+            last_monotonic: Instant::now(),
+            last_route: route,
+        }
+    }
+
+    /// Compare against the previous observation; a wake takes precedence over
+    /// a network change since recovering from it also reconnects
+    pub fn observe(&mut self, wall: SystemTime, monotonic: Instant, route: Option<IpAddr>) -> Option<RecoveryEvent> {
+        let wall_elapsed = wall.duration_since(self.last_wall).unwrap_or_default();
+        let monotonic_elapsed = monotonic.duration_since(self.last_monotonic);
+        let previous_route = std::mem::replace(&mut self.last_route, route);
+        self.last_wall = wall;
+        self.last_monotonic = monotonic;
+
+        let slept = wall_elapsed.saturating_sub(monotonic_elapsed);
+        if slept > self.threshold {
+            Some(RecoveryEvent::Wake { slept })
+        } else if previous_route != route {
+            Some(RecoveryEvent::NetworkChange { from: previous_route, to: route })
+        } else {
+            None
+        }
+    }
+}
+
+/// Source address the host would use for the default route, if it has one
+pub fn route_source_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(ROUTE_PROBE_ADDR).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// Watch for wake-ups and network changes until the task is cancelled
+pub async fn run(
+    config: RecoveryConfig,
+    clients: Vec<Arc<Client>>,
+    exports: Vec<ResolvedExport>,
+    control: ControlState,
+) {
+    let mut detector = WakeDetector::new(
+        Duration::from_secs(config.sleep_threshold_secs),
+        route_source_address(),
+    );
+    let mut interval = tokio::time::interval(Duration::from_secs(config.check_interval_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    info!("Watching for sleep/wake and network changes");
+    loop {
+        interval.tick().await;
+
+        let event = detector.observe(SystemTime::now(), Instant::now(), route_source_address());
+        if let Some(event) = event {
+            recover(&event, &config, &clients, &exports, &control).await;
+        }
+    }
+}
+
+async fn recover(
+    event: &RecoveryEvent,
+    config: &RecoveryConfig,
+    clients: &[Arc<Client>],
+    exports: &[ResolvedExport],
+    control: &ControlState,
+) {
+    info!("Host {}, reconnecting to agents", event);
+
+    for client in clients {
+        if let Err(e) = client.reconnect().await {
+            warn!("Reconnect after {} failed: {}", event, e);
+            control.record_error(None, format!("Reconnect after {} failed: {}", event, e)).await;
+        }
+    }
+
+    if matches!(event, RecoveryEvent::Wake { .. }) && config.remount_after_wake {
+        refresh_mounts(exports, control).await;
+    }
+}
+
+/// Force-unmount and remount every active mount of our exports
+async fn refresh_mounts(exports: &[ResolvedExport], control: &ControlState) {
+    let exports = exports.to_vec();
+    let results = tokio::task::spawn_blocking(move || {
+        let mounts = match mount::active_mounts(&exports) {
+            Ok(mounts) => mounts,
+            Err(e) => return vec![(None, Err(e))],
+        };
+
+        mounts
+            .into_iter()
+            .map(|active| {
+                debug!("Refreshing mount {}", active.mount_point);
+                let result = mount::unmount(&active.mount_point, true, false)
+                    .and_then(|_| mount::mount(&active.export, &active.mount_point, false));
+                (Some(active), result)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+    for (active, result) in results {
+        match (active, result) {
+            (Some(active), Ok(())) => info!("Remounted {} on {}", active.export.mount_path(), active.mount_point),
+            (active, Err(e)) => {
+                let export = active.as_ref().map(|a| a.export.mount_path());
+                warn!("Failed to refresh mount after wake: {}", e);
+                control.record_error(export.as_deref(), format!("Failed to refresh mount after wake: {}", e)).await;
+            }
+            (None, Ok(())) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_wake() {
+        let wall = SystemTime::now();
+        let monotonic = Instant::now();
+        let mut detector = WakeDetector::new(Duration::from_secs(30), None);
+        detector.last_wall = wall;
+        detector.last_monotonic = monotonic;
+
+        // A normal tick
+        let wall = wall + Duration::from_secs(5);
+        let monotonic = monotonic + Duration::from_secs(5);
+        assert_eq!(detector.observe(wall, monotonic, None), None);
+
+        // Wall clock ran on for an hour while the monotonic clock was stopped
+        let wall = wall + Duration::from_secs(3605);
+        let monotonic = monotonic + Duration::from_secs(5);
+        assert_eq!(
+            detector.observe(wall, monotonic, None),
+            Some(RecoveryEvent::Wake { slept: Duration::from_secs(3600) })
+        );
+    }
+
+    #[test]
+    fn test_detects_network_change() {
+        let home: IpAddr = "192.168.1.20".parse().unwrap();
+        let office: IpAddr = "10.0.0.7".parse().unwrap();
+        let mut detector = WakeDetector::new(Duration::from_secs(30), Some(home));
+        let (wall, monotonic) = (detector.last_wall, detector.last_monotonic);
+
+        assert_eq!(detector.observe(wall, monotonic, Some(home)), None);
+        assert_eq!(
+            detector.observe(wall, monotonic, Some(office)),
+            Some(RecoveryEvent::NetworkChange { from: Some(home), to: Some(office) })
+        );
+        assert_eq!(
+            detector.observe(wall, monotonic, None),
+            Some(RecoveryEvent::NetworkChange { from: Some(office), to: None })
+        );
+    }
+
+    #[test]
+    fn test_event_display() {
+        let event = RecoveryEvent::NetworkChange { from: None, to: Some("10.0.0.7".parse().unwrap()) };
+        assert_eq!(event.to_string(), "network changed from offline to 10.0.0.7");
+    }
+}
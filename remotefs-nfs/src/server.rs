@@ -1,85 +1,304 @@
-use crate::{RemoteNfsFilesystem, NfsConfig, Result};
+use crate::{handles, recovery, ControlState, RemoteNfsFilesystem, NfsConfig, ResolvedExport, Result};
 use remotefs_client::Client;
+use remotefs_common::crash;
+use crate::io_stats::IoAccounting;
+use crate::readahead::ReadAhead;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tracing::{info, error, warn};
 use zerofs_nfsserve::tcp::{NFSTcpListener, NFSTcp};
-use std::sync::atomic::{AtomicU64, Ordering};
 
 /// RemoteFS NFS server for cross-platform compatibility (Linux & macOS)
+///
+/// Each export gets its own listener, since zerofs_nfsserve serves a single
+/// filesystem per socket.
 pub struct RemoteNfsServer {
     config: NfsConfig,
-    filesystem: Option<RemoteNfsFilesystem>,
+    exports: Vec<(ResolvedExport, RemoteNfsFilesystem)>,
+    control: ControlState,
 }
 
 impl RemoteNfsServer {
     pub fn new(config: NfsConfig) -> Self {
         Self {
+            control: ControlState::new(config.nfs_version),
             config,
-            filesystem: None,
+            exports: Vec::new(),
         }
     }
 
-    /// Initialize the server with a RemoteFS client
+    /// Initialize every configured export with a single shared RemoteFS client
     pub async fn initialize(&mut self, client: Client) -> Result<()> {
         info!("Initializing RemoteFS NFS server");
         
-        let filesystem = RemoteNfsFilesystem::new(client).await?;
-        self.filesystem = Some(filesystem);
+        let client = Arc::new(client);
+        for export in self.config.resolved_exports() {
+            self.add_export(export, Arc::clone(&client)).await?;
+        }
         
         info!("RemoteFS NFS filesystem initialized");
         Ok(())
     }
 
+    /// Add a single export backed by the given client
+    pub async fn add_export(&mut self, export: ResolvedExport, client: Arc<Client>) -> Result<()> {
+        let mut filesystem = RemoteNfsFilesystem::with_root(client, &export.remote_path).await?
+            .with_birthtime_as_ctime(self.config.finder.birthtime_as_ctime)
+            .with_forward_caller_identity(self.config.sharing.forward_caller_identity)
+            .with_directory_cache(&self.config.directory_cache())
+            .with_read_ahead(&self.config.read_ahead())
+            .with_umask(export.umask)
+            .with_symlinks(export.symlinks)
+            .with_transfer(export.transfer)
+            .with_max_concurrent_operations(self.config.performance.max_concurrent_operations);
+        // Exports sharing a client talk to the same agents, so their caches hold the same paths
+        if let Some((_, shared)) = self.exports.iter().find(|(_, fs)| Arc::ptr_eq(&fs.client, &filesystem.client)) {
+            filesystem = filesystem.with_caches_of(shared);
+        }
+        if export.agent_exports {
+            filesystem = filesystem.with_agent_exports();
+        }
+        if self.config.handles.persist {
+            handles::restore(&mut filesystem, &handles::snapshot_path(&self.config.handles.directory(), &export.name)).await;
+        }
+        info!(
+            "Export {} -> {} on {}",
+            export.mount_path(),
+            if export.agent_exports { "the agent's exports" } else { &export.remote_path },
+            export.listen_address()
+        );
+        self.exports.push((export, filesystem));
+        Ok(())
+    }
+
+    /// Exports this server has been initialized with
+    pub fn exports(&self) -> impl Iterator<Item = &ResolvedExport> {
+        self.exports.iter().map(|(export, _)| export)
+    }
+
+    /// Control state shared with the local control API
+    pub fn control(&self) -> &ControlState {
+        &self.control
+    }
+
     /// Start the NFS server
     pub async fn start(&self) -> Result<()> {
-        let filesystem = match &self.filesystem {
-            Some(fs) => fs.clone(),
-            None => {
-                error!("Server not initialized. Call initialize() first.");
-                return Err(remotefs_common::error::RemoteFsError::Internal(
-                    "Server not initialized".to_string()
-                ));
+        if self.exports.is_empty() {
+            error!("Server not initialized. Call initialize() first.");
+            return Err(remotefs_common::error::RemoteFsError::Internal(
+                "Server not initialized".to_string()
+            ));
+        }
+
+        // Bind every listener up front so a bad address fails before anything is served
+        let mut listeners = Vec::with_capacity(self.exports.len());
+        for (export, filesystem) in &self.exports {
+            let listener = Self::bind_export(export, filesystem).await?;
+            info!("Mount with: sudo mount -t nfs -o vers=3,tcp,port={},mountport={} {}:{} /mnt/remotefs", 
+                  export.port, export.port, export.bind_address, export.mount_path());
+            listeners.push((export.clone(), filesystem.clone(), listener));
+        }
+
+        // Report the exports if the server panics
+        let control = self.control.clone();
+        crash::add_snapshot("exports", move || control.snapshot());
+        
+        let mut servers = JoinSet::new();
+        for (export, filesystem, listener) in listeners {
+            let enabled = self.control.register_export(&export).await;
+            self.control.track_io(&export.name, Arc::clone(&filesystem.io)).await;
+            self.control.track_client(&export.name, Arc::clone(&filesystem.client)).await;
+            if let Some(read_ahead) = &filesystem.read_ahead {
+                self.control.track_read_ahead(&export.name, Arc::clone(read_ahead)).await;
+            }
+            servers.spawn(Self::serve_export(export, filesystem, listener, enabled, self.control.clone()));
+        }
+
+        // The control API is a convenience; failing to serve it must not take the exports down
+        let control_api = self.config.control.enabled.then(|| {
+            let control = self.control.clone();
+            let config = self.config.control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.serve(&config).await {
+                    error!("{}", e);
+                    control.record_error(None, e.to_string()).await;
+                }
+            })
+        });
+
+        let recovery = self.config.recovery.enabled.then(|| {
+            let mut clients: Vec<Arc<Client>> = Vec::new();
+            for (_, filesystem) in &self.exports {
+                if !clients.iter().any(|client| Arc::ptr_eq(client, &filesystem.client)) {
+                    clients.push(Arc::clone(&filesystem.client));
+                }
+            }
+            let exports = self.exports.iter().map(|(export, _)| export.clone()).collect();
+            tokio::spawn(recovery::run(self.config.recovery.clone(), clients, exports, self.control.clone()))
+        });
+
+        let stats_writer = self.config.control.stats_dir.clone().map(|dir| {
+            let exports = self.exports.iter()
+                .map(|(export, filesystem)| (export.mount_path(), Arc::clone(&filesystem.io)))
+                .collect();
+            let interval = Duration::from_secs(self.config.control.stats_interval_secs.max(1));
+            tokio::spawn(write_io_stats(dir, exports, interval))
+        });
+
+        // Exports sharing a client share their read-ahead, which is scrubbed once
+        let read_ahead = self.config.read_ahead();
+        let mut scrubbers: Vec<(&Arc<ReadAhead>, &Arc<Client>)> = Vec::new();
+        for (_, filesystem) in &self.exports {
+            if let Some(shared) = &filesystem.read_ahead {
+                if read_ahead.scrub_interval_secs > 0 && !scrubbers.iter().any(|(seen, _)| Arc::ptr_eq(seen, shared)) {
+                    scrubbers.push((shared, &filesystem.client));
+                }
+            }
+        }
+        let interval = Duration::from_secs(read_ahead.scrub_interval_secs);
+        let scrubbers: Vec<_> = scrubbers.into_iter()
+            .map(|(shared, client)| tokio::spawn(Arc::clone(shared).run_scrubber(Arc::clone(client), interval)))
+            .collect();
+
+        // Keep the file handles given out valid across a restart
+        let handle_exports: Vec<_> = self.exports.iter()
+            .filter(|_| self.config.handles.persist)
+            .map(|(export, filesystem)| {
+                (handles::snapshot_path(&self.config.handles.directory(), &export.name), filesystem.clone())
+            })
+            .collect();
+        let handle_saver = (!handle_exports.is_empty()).then(|| {
+            let interval = Duration::from_secs(self.config.handles.save_interval_secs.max(1));
+            tokio::spawn(handles::save_periodically(handle_exports.clone(), interval))
+        });
+
+        // Warm the directory caches while the exports are already being served
+        let cache_config = self.config.directory_cache();
+        let preloads: Vec<_> = self.exports.iter()
+            .filter(|_| cache_config.preload_depth > 0)
+            .map(|(_, filesystem)| {
+                let filesystem = filesystem.clone();
+                let cache_config = cache_config.clone();
+                tokio::spawn(async move { filesystem.preload(&cache_config).await })
+            })
+            .collect();
+
+        // Handle graceful shutdown; any listener failing brings the server down
+        let result = tokio::select! {
+            Some(joined) = servers.join_next() => {
+                match joined {
+                    Ok((addr, Ok(_))) => {
+                        info!("NFS server on {} stopped normally", addr);
+                        Ok(())
+                    }
+                    Ok((addr, Err(e))) => {
+                        error!("NFS server error on {}: {}", addr, e);
+                        Err(e)
+                    }
+                    Err(e) => Err(remotefs_common::error::RemoteFsError::Internal(
+                        format!("NFS server task failed: {}", e)
+                    )),
+                }
+            }
+            _ = signal::ctrl_c() => {
+                info!("Received SIGINT, shutting down gracefully...");
+                Ok(())
             }
         };
 
-        info!(
-            "Starting RemoteFS NFS server on {}:{}",
-            self.config.host, self.config.port
-        );
+        servers.shutdown().await;
+        for task in [control_api, recovery, stats_writer, handle_saver].into_iter().flatten().chain(preloads).chain(scrubbers) {
+            task.abort();
+        }
+        handles::save_changed(&handle_exports, &mut vec![u64::MAX; handle_exports.len()]).await;
+        result
+    }
 
-        let addr = format!("{}:{}", self.config.host, self.config.port);
-        let listener = NFSTcpListener::bind(&addr, filesystem)
+    /// Bind the listener for a single export
+    async fn bind_export(
+        export: &ResolvedExport,
+        filesystem: &RemoteNfsFilesystem,
+    ) -> Result<NFSTcpListener<RemoteNfsFilesystem>> {
+        let addr = export.listen_address();
+        info!("Starting RemoteFS NFS server on {}", addr);
+
+        let mut listener = NFSTcpListener::bind(&addr, filesystem.clone())
             .await
             .map_err(|e| {
                 remotefs_common::error::RemoteFsError::Internal(
-                    format!("Failed to bind NFS server: {}", e)
+                    format!("Failed to bind NFS server on {}: {}", addr, e)
                 )
             })?;
+        if !export.name.is_empty() {
+            listener.with_export_name(&export.name);
+        }
 
         info!("NFS server listening on {}", addr);
-        info!("Mount with: sudo mount -t nfs -o vers=3,tcp,port={},mountport={} {}:/ /mnt/remotefs", 
-              self.config.port, self.config.port, self.config.host);
-
-        // Handle graceful shutdown
-        tokio::select! {
-            result = listener.handle_forever() => {
-                match result {
-                    Ok(_) => {
-                        info!("NFS server stopped normally");
-                        Ok(())
+        Ok(listener)
+    }
+
+    /// Serve an export, closing and rebinding its listener as it is toggled
+    /// through the control API
+    async fn serve_export(
+        export: ResolvedExport,
+        filesystem: RemoteNfsFilesystem,
+        listener: NFSTcpListener<RemoteNfsFilesystem>,
+        mut enabled: watch::Receiver<bool>,
+        control: ControlState,
+    ) -> (String, Result<()>) {
+        let addr = export.listen_address();
+        let mount_path = export.mount_path();
+        let mut listener = Some(listener);
+
+        loop {
+            let current = match listener.take() {
+                Some(listener) => listener,
+                None => {
+                    // Wait for the export to be re-enabled; a closed channel means
+                    // the export is no longer tracked
+                    if enabled.wait_for(|enabled| *enabled).await.is_err() {
+                        return (addr, Ok(()));
                     }
-                    Err(e) => {
-                        error!("NFS server error: {}", e);
-                        Err(remotefs_common::error::RemoteFsError::Internal(
-                            format!("NFS server error: {}", e)
-                        ))
+
+                    match Self::bind_export(&export, &filesystem).await {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            error!("Failed to re-enable export {}: {}", mount_path, e);
+                            control.record_error(Some(&mount_path), e.to_string()).await;
+                            control.set_enabled(&mount_path, false).await;
+                            continue;
+                        }
                     }
                 }
-            }
-            _ = signal::ctrl_c() => {
-                info!("Received SIGINT, shutting down gracefully...");
-                Ok(())
+            };
+
+            control.set_serving(&mount_path, true).await;
+            tokio::select! {
+                result = current.handle_forever() => {
+                    control.set_serving(&mount_path, false).await;
+                    let result = result.map_err(|e| {
+                        remotefs_common::error::RemoteFsError::Internal(
+                            format!("NFS server error on {}: {}", addr, e)
+                        )
+                    });
+                    if let Err(e) = &result {
+                        control.record_error(Some(&mount_path), e.to_string()).await;
+                    }
+                    return (addr, result);
+                }
+                closed = async { enabled.wait_for(|enabled| !*enabled).await.is_err() } => {
+                    control.set_serving(&mount_path, false).await;
+                    if closed {
+                        return (addr, Ok(()));
+                    }
+                    // Dropping the listener stops accepting new connections;
+                    // established ones drain on their own
+                    info!("Export {} disabled, closed listener on {}", mount_path, addr);
+                }
             }
         }
     }
@@ -121,14 +340,55 @@ impl RemoteNfsServer {
     }
 }
 
+/// Periodically write each export's per-user I/O to `<dir>/<export>.json`
+async fn write_io_stats(dir: PathBuf, exports: Vec<(String, Arc<IoAccounting>)>, interval: Duration) {
+    if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+        warn!("Failed to create statistics directory {}: {}", dir.display(), e);
+        return;
+    }
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for (mount_path, io) in &exports {
+            let path = dir.join(format!("{}.json", io_stats_file_stem(mount_path)));
+            let io = Arc::clone(io);
+            let written = tokio::task::spawn_blocking(move || io.write_to(&path).map_err(|e| (path, e))).await;
+            if let Ok(Err((path, e))) = written {
+                warn!("Failed to write I/O statistics to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Statistics file name for an export's mount path
+fn io_stats_file_stem(mount_path: &str) -> String {
+    match mount_path.trim_matches('/') {
+        "" => "root".to_string(),
+        name => name.replace('/', "_"),
+    }
+}
+
 impl Clone for RemoteNfsFilesystem {
     fn clone(&self) -> Self {
         Self {
             client: Arc::clone(&self.client),
-            next_file_id: AtomicU64::new(self.next_file_id.load(Ordering::SeqCst)),
+            generation: self.generation,
+            id_changes: Arc::clone(&self.id_changes),
             path_to_id_map: Arc::clone(&self.path_to_id_map),
             id_to_path_map: Arc::clone(&self.id_to_path_map),
             root_id: self.root_id,
+            remote_root: self.remote_root.clone(),
+            birthtime_as_ctime: self.birthtime_as_ctime,
+            forward_caller_identity: self.forward_caller_identity,
+            dir_cache: self.dir_cache.clone(),
+            read_ahead: self.read_ahead.clone(),
+            io: Arc::clone(&self.io),
+            agent_exports: self.agent_exports.clone(),
+            umask: self.umask,
+            symlinks: self.symlinks,
+            transfer: self.transfer,
+            operations: self.operations.clone(),
         }
     }
 }
@@ -137,16 +397,23 @@ impl Clone for RemoteNfsFilesystem {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::NfsConfig;
+    use crate::config::{DirectoryCacheConfig, ExportConfig, MountProfile, MountTransfer, NfsConfig, ReadAheadConfig, SymlinkPolicy};
     use remotefs_client::{ClientConfig, AgentConfig};
 
+    #[test]
+    fn test_io_stats_file_stem() {
+        assert_eq!(io_stats_file_stem("/"), "root");
+        assert_eq!(io_stats_file_stem("/home"), "home");
+        assert_eq!(io_stats_file_stem("/team/docs"), "team_docs");
+    }
+
     #[tokio::test]
     async fn test_server_creation() {
         let config = NfsConfig::default();
         let server = RemoteNfsServer::new(config);
         
         // Server should be created but not initialized
-        assert!(server.filesystem.is_none());
+        assert_eq!(server.exports().count(), 0);
     }
 
     #[tokio::test]
@@ -170,6 +437,155 @@ mod tests {
         // Initialize should work (even if connection fails in tests)
         let result = server.initialize(client).await;
         // Note: This might fail in tests due to no actual server, but the structure should be correct
-        assert!(server.filesystem.is_some() || result.is_err());
+        assert!(server.exports().count() == 1 || result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_initialization_with_exports() {
+        let config = NfsConfig {
+            exports: vec![
+                ExportConfig {
+                    name: "home".to_string(),
+                    agent: None,
+                    remote_path: "/home".to_string(),
+                    port: None,
+                    bind_address: None,
+                    selinux_context: None,
+                    agent_exports: false,
+                    umask: None,
+                    symlinks: None,
+                },
+                ExportConfig {
+                    name: "data".to_string(),
+                    agent: None,
+                    remote_path: "/srv/data".to_string(),
+                    port: Some(2050),
+                    bind_address: None,
+                    selinux_context: None,
+                    agent_exports: false,
+                    umask: None,
+                    symlinks: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let mut server = RemoteNfsServer::new(config);
+
+        let client_config = ClientConfig {
+            agents: vec![AgentConfig {
+                id: "test".to_string(),
+                url: "ws://localhost:8080".to_string(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        };
+        server.initialize(Client::new(client_config).unwrap()).await.unwrap();
+
+        let mounts: Vec<_> = server.exports().map(|e| e.mount_path()).collect();
+        assert_eq!(mounts, vec!["/home", "/data"]);
+        assert!(server.exports.iter().all(|(_, fs)| Arc::ptr_eq(&fs.client, &server.exports[0].1.client)));
+        assert_eq!(server.exports[1].1.remote_root, "/srv/data");
+    }
+
+    #[tokio::test]
+    async fn test_exports_on_the_same_agent_share_caches() {
+        let config = NfsConfig {
+            directory_cache: DirectoryCacheConfig { enabled: true, ..Default::default() },
+            read_ahead: ReadAheadConfig { enabled: true, ..Default::default() },
+            ..Default::default()
+        };
+        let mut server = RemoteNfsServer::new(config);
+        let client = |id: &str| {
+            let client_config = ClientConfig {
+                agents: vec![AgentConfig {
+                    id: id.to_string(),
+                    url: format!("ws://{}:8080", id),
+                    auth: None,
+                    weight: 1,
+                    enabled: true,
+                }],
+                ..Default::default()
+            };
+            Arc::new(Client::new(client_config).unwrap())
+        };
+        let export = |name: &str, agent: &str| ResolvedExport {
+            name: name.to_string(),
+            agents: vec![agent.to_string()],
+            remote_path: format!("/{}", name),
+            bind_address: "127.0.0.1".to_string(),
+            port: 0,
+            selinux_context: None,
+            profile: MountProfile::default(),
+            agent_exports: false,
+            umask: 0,
+            symlinks: SymlinkPolicy::AsIs,
+            transfer: MountTransfer::default(),
+        };
+
+        let first = client("first");
+        server.add_export(export("home", "first"), Arc::clone(&first)).await.unwrap();
+        server.add_export(export("data", "first"), first).await.unwrap();
+        server.add_export(export("logs", "second"), client("second")).await.unwrap();
+
+        let [home, data, logs] = [0, 1, 2].map(|i| &server.exports[i].1);
+        assert!(Arc::ptr_eq(home.dir_cache.as_ref().unwrap(), data.dir_cache.as_ref().unwrap()));
+        assert!(Arc::ptr_eq(home.read_ahead.as_ref().unwrap(), data.read_ahead.as_ref().unwrap()));
+        assert!(!Arc::ptr_eq(home.dir_cache.as_ref().unwrap(), logs.dir_cache.as_ref().unwrap()));
+        assert!(!Arc::ptr_eq(home.read_ahead.as_ref().unwrap(), logs.read_ahead.as_ref().unwrap()));
+    }
+
+    async fn wait_for_serving(control: &ControlState, serving: bool) {
+        for _ in 0..100 {
+            if control.exports().await[0].serving == serving {
+                return;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+        panic!("export never reached serving = {}", serving);
+    }
+
+    #[tokio::test]
+    async fn test_export_toggle_closes_and_rebinds_listener() {
+        let export = ResolvedExport {
+            name: "home".to_string(),
+            agents: vec!["ws://localhost:8080".to_string()],
+            remote_path: "/".to_string(),
+            bind_address: "127.0.0.1".to_string(),
+            port: 0,
+            selinux_context: None,
+            profile: MountProfile::Default,
+            agent_exports: false,
+            umask: 0,
+            symlinks: SymlinkPolicy::AsIs,
+            transfer: MountTransfer::default(),
+        };
+        let client_config = ClientConfig {
+            agents: vec![AgentConfig {
+                id: "test".to_string(),
+                url: "ws://localhost:8080".to_string(),
+                auth: None,
+                weight: 1,
+                enabled: true,
+            }],
+            ..Default::default()
+        };
+        let filesystem = RemoteNfsFilesystem::new(Client::new(client_config).unwrap()).await.unwrap();
+        let control = ControlState::new(crate::NfsVersion::V3);
+        let enabled = control.register_export(&export).await;
+
+        let listener = RemoteNfsServer::bind_export(&export, &filesystem).await.unwrap();
+        let task = tokio::spawn(RemoteNfsServer::serve_export(
+            export, filesystem, listener, enabled, control.clone(),
+        ));
+
+        wait_for_serving(&control, true).await;
+        control.set_enabled("home", false).await.unwrap();
+        wait_for_serving(&control, false).await;
+        control.set_enabled("home", true).await.unwrap();
+        wait_for_serving(&control, true).await;
+
+        task.abort();
     }
 }
@@ -84,6 +84,7 @@ async fn main() -> Result<()> {
 }
 
 /// Helper function to demonstrate configuration customization for development
+#[allow(dead_code)]
 fn customize_config_for_development(mut config: remotefs_common::config::RelayConfig) -> remotefs_common::config::RelayConfig {
     // Development-friendly settings
     config.bind_address = "127.0.0.1".to_string(); // Localhost only
@@ -104,6 +105,7 @@ fn customize_config_for_development(mut config: remotefs_common::config::RelayCo
 }
 
 /// Helper function to demonstrate configuration for production
+#[allow(dead_code)]
 fn customize_config_for_production(mut config: remotefs_common::config::RelayConfig) -> remotefs_common::config::RelayConfig {
     // Production settings
     config.bind_address = "0.0.0.0".to_string(); // All interfaces
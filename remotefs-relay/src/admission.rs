@@ -0,0 +1,195 @@
+//! Limits on connections that have not authenticated
+//!
+//! Opening a connection costs an attacker nothing, while every open one
+//! holds a socket, a task and buffers on the relay. Connections are
+//! therefore capped per IP address from the moment they are accepted, must
+//! finish their handshake and authenticate within configured timeouts, and
+//! only a limited number may be waiting to authenticate at once. Each
+//! refusal is counted so operators can see when the limits bite.
+
+use remotefs_common::config::ConnectionLimits;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Statistics for connection admission
+#[derive(Debug, Clone, Default)]
+pub struct AdmissionStats {
+    pub unauthenticated: usize,
+    pub rejected_per_ip: u64,
+    pub rejected_unauthenticated: u64,
+    pub handshake_timeouts: u64,
+    pub auth_timeouts: u64,
+}
+
+/// Connection counts and limits shared by the listener and the WebSocket
+/// handler
+#[derive(Debug)]
+pub struct ConnectionAdmission {
+    config: ConnectionLimits,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    unauthenticated: AtomicUsize,
+    rejected_per_ip: AtomicU64,
+    rejected_unauthenticated: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    auth_timeouts: AtomicU64,
+}
+
+/// An accepted connection, counted against its IP address until dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    admission: Arc<ConnectionAdmission>,
+    ip: IpAddr,
+}
+
+/// A WebSocket connection that has not authenticated, counted until dropped
+#[derive(Debug)]
+pub struct UnauthenticatedPermit {
+    admission: Arc<ConnectionAdmission>,
+}
+
+impl ConnectionAdmission {
+    pub fn new(config: &ConnectionLimits) -> Self {
+        Self {
+            config: config.clone(),
+            per_ip: Mutex::new(HashMap::new()),
+            unauthenticated: AtomicUsize::new(0),
+            rejected_per_ip: AtomicU64::new(0),
+            rejected_unauthenticated: AtomicU64::new(0),
+            handshake_timeouts: AtomicU64::new(0),
+            auth_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Time to finish the TLS handshake and send the HTTP request
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.handshake_timeout_secs)
+    }
+
+    /// Time from the WebSocket upgrade to authentication
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.auth_timeout_secs)
+    }
+
+    /// Count a new connection from `ip`, or `None` if that address already
+    /// has as many as it may
+    pub fn accept(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionPermit> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let open = per_ip.entry(ip).or_insert(0);
+        if self.config.max_per_ip > 0 && *open >= self.config.max_per_ip {
+            self.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        *open += 1;
+        Some(ConnectionPermit { admission: Arc::clone(self), ip })
+    }
+
+    /// Count a WebSocket connection waiting to authenticate, or `None` if
+    /// too many already are
+    pub fn start_unauthenticated(self: &Arc<Self>) -> Option<UnauthenticatedPermit> {
+        let max = self.config.max_unauthenticated;
+        let admitted = self.unauthenticated
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waiting| {
+                (max == 0 || waiting < max).then_some(waiting + 1)
+            })
+            .is_ok();
+        if !admitted {
+            self.rejected_unauthenticated.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(UnauthenticatedPermit { admission: Arc::clone(self) })
+    }
+
+    pub fn record_handshake_timeout(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_auth_timeout(&self) {
+        self.auth_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get_stats(&self) -> AdmissionStats {
+        AdmissionStats {
+            unauthenticated: self.unauthenticated.load(Ordering::Relaxed),
+            rejected_per_ip: self.rejected_per_ip.load(Ordering::Relaxed),
+            rejected_unauthenticated: self.rejected_unauthenticated.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            auth_timeouts: self.auth_timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut per_ip = self.admission.per_ip.lock().unwrap();
+        if let Some(open) = per_ip.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Drop for UnauthenticatedPermit {
+    fn drop(&mut self) {
+        self.admission.unauthenticated.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission(max_unauthenticated: usize, max_per_ip: usize) -> Arc<ConnectionAdmission> {
+        Arc::new(ConnectionAdmission::new(&ConnectionLimits {
+            max_unauthenticated,
+            max_per_ip,
+            ..ConnectionLimits::default()
+        }))
+    }
+
+    #[test]
+    fn test_per_ip_limit() {
+        let admission = admission(0, 2);
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let a = admission.accept(first).unwrap();
+        let _b = admission.accept(first).unwrap();
+        assert!(admission.accept(first).is_none());
+        assert!(admission.accept(second).is_some());
+
+        // A closed connection frees its slot
+        drop(a);
+        assert!(admission.accept(first).is_some());
+        assert_eq!(admission.get_stats().rejected_per_ip, 1);
+    }
+
+    #[test]
+    fn test_unauthenticated_limit() {
+        let admission = admission(2, 0);
+        let a = admission.start_unauthenticated().unwrap();
+        let _b = admission.start_unauthenticated().unwrap();
+        assert!(admission.start_unauthenticated().is_none());
+        assert_eq!(admission.get_stats().unauthenticated, 2);
+
+        drop(a);
+        assert!(admission.start_unauthenticated().is_some());
+        assert_eq!(admission.get_stats().rejected_unauthenticated, 1);
+
+        // 0 means no limit
+        let unlimited = self::admission(0, 0);
+        let permits: Vec<_> = (0..100).filter_map(|_| unlimited.start_unauthenticated()).collect();
+        assert_eq!(permits.len(), 100);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let permits: Vec<_> = (0..100).filter_map(|_| unlimited.accept(ip)).collect();
+        assert_eq!(permits.len(), 100);
+    }
+}
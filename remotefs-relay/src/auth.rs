@@ -1,9 +1,11 @@
 use remotefs_common::{
-    protocol::{NodeType, SessionToken},
+    protocol::{Capability, NodeType, SessionToken},
     config::RelayConfig,
     error::{RemoteFsError, Result},
-    crypto::{generate_key, EncryptionManager},
+    crypto::{generate_key, EncryptedData, EncryptionManager},
+    token::{TokenClaims, TokenSigner},
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -14,7 +16,13 @@ use uuid::Uuid;
 pub struct AuthManager {
     config: RelayConfig,
     active_tokens: Arc<RwLock<HashMap<String, AuthenticatedNode>>>,
+    /// Seals resumption tickets; the key only lives as long as the relay
     encryption_manager: Arc<EncryptionManager>,
+    /// Tickets already used to resume, with when they expire
+    used_tickets: Arc<RwLock<HashMap<Uuid, u64>>>,
+    /// Signs session tokens; like the ticket key, it only lives as long as
+    /// the relay
+    token_signer: TokenSigner,
 }
 
 /// Represents an authenticated node
@@ -24,30 +32,56 @@ pub struct AuthenticatedNode {
     pub node_type: NodeType,
     pub session_token: SessionToken,
     pub public_key: Vec<u8>,
-    pub capabilities: Vec<String>,
+    pub capabilities: Vec<Capability>,
     pub authenticated_at: u64,
 }
 
+/// What a node presented when it authenticated, carried in its resumption
+/// tickets so a resumed session gets the same identity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeCredentials {
+    pub node_id: String,
+    pub node_type: NodeType,
+    pub public_key: Vec<u8>,
+    pub capabilities: Vec<Capability>,
+}
+
+/// Contents of a resumption ticket before sealing
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumptionTicket {
+    id: Uuid,
+    credentials: NodeCredentials,
+    expires_at: u64,
+}
+
 impl AuthManager {
     /// Create a new authentication manager
     pub fn new(config: &RelayConfig) -> Self {
         let master_key = generate_key();
         let encryption_manager = Arc::new(EncryptionManager::new(master_key));
+        let token_signer = TokenSigner::generate().expect("Failed to generate token signing key");
         
         Self {
             config: config.clone(),
             active_tokens: Arc::new(RwLock::new(HashMap::new())),
             encryption_manager,
+            used_tickets: Arc::new(RwLock::new(HashMap::new())),
+            token_signer,
         }
     }
     
+    /// Public key of the session token signatures, published in `RelayInfo`
+    pub fn token_key(&self) -> Vec<u8> {
+        self.token_signer.public_key()
+    }
+    
     /// Authenticate a node (client or agent)
     pub async fn authenticate_node(
         &self,
         node_id: &str,
         node_type: &NodeType,
         public_key: &[u8],
-        capabilities: &[String],
+        capabilities: &[Capability],
     ) -> Result<SessionToken> {
         debug!("Authenticating node: {} ({:?})", node_id, node_type);
         
@@ -61,12 +95,19 @@ impl AuthManager {
         self.validate_public_key(public_key)?;
         
         // Validate capabilities
-        self.validate_capabilities(capabilities)?;
+        self.validate_capabilities(node_type, capabilities)?;
+        
+        let credentials = NodeCredentials {
+            node_id: node_id.to_string(),
+            node_type: node_type.clone(),
+            public_key: public_key.to_vec(),
+            capabilities: capabilities.to_vec(),
+        };
         
         // Check if authentication is enabled
         if !self.config.security.enable_auth {
             debug!("Authentication disabled, allowing node: {}", node_id);
-            return Ok(self.generate_session_token(node_id));
+            return self.generate_session_token(&credentials);
         }
         
         // In a real system, this would involve more sophisticated authentication:
@@ -79,24 +120,7 @@ impl AuthManager {
         let authenticated = self.perform_basic_authentication(node_id, node_type, public_key).await?;
         
         if authenticated {
-            // Generate session token
-            let session_token = self.generate_session_token(node_id);
-            
-            // Store authenticated node
-            let authenticated_node = AuthenticatedNode {
-                node_id: node_id.to_string(),
-                node_type: node_type.clone(),
-                session_token: session_token.clone(),
-                public_key: public_key.to_vec(),
-                capabilities: capabilities.to_vec(),
-                authenticated_at: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            };
-            
-            let mut tokens = self.active_tokens.write().await;
-            tokens.insert(session_token.clone(), authenticated_node);
+            let session_token = self.register_node(&credentials).await?;
             
             debug!("Node {} authenticated successfully", node_id);
             Ok(session_token)
@@ -107,6 +131,85 @@ impl AuthManager {
         }
     }
     
+    /// Seal a ticket that lets the node with `credentials` resume its session
+    /// without authenticating again, or `None` if resumption is disabled
+    pub fn issue_ticket(&self, credentials: &NodeCredentials) -> Result<Option<Vec<u8>>> {
+        let lifetime = self.config.session.resumption_ticket_secs;
+        if lifetime == 0 {
+            return Ok(None);
+        }
+        
+        let ticket = ResumptionTicket {
+            id: Uuid::new_v4(),
+            credentials: credentials.clone(),
+            expires_at: unix_now() + lifetime,
+        };
+        let sealed = self.encryption_manager.encrypt(&bincode::serialize(&ticket)?, false)?;
+        Ok(Some(sealed.to_bytes()))
+    }
+    
+    /// Start a new session for `node_id` from a ticket issued by this relay
+    ///
+    /// Each ticket can be used once; the new session comes with a new ticket.
+    pub async fn resume_session(&self, node_id: &str, ticket: &[u8]) -> Result<(SessionToken, NodeCredentials)> {
+        let invalid = || RemoteFsError::Authentication("Invalid resumption ticket".to_string());
+        
+        if self.config.session.resumption_ticket_secs == 0 {
+            return Err(RemoteFsError::Authentication("Session resumption is disabled".to_string()));
+        }
+        
+        // Sealing authenticates the ticket, so one that opens was issued here
+        let sealed = EncryptedData::from_bytes(ticket).map_err(|_| invalid())?;
+        let opened = self.encryption_manager.decrypt(&sealed).map_err(|_| invalid())?;
+        let ticket: ResumptionTicket = bincode::deserialize(&opened).map_err(|_| invalid())?;
+        
+        if ticket.credentials.node_id != node_id {
+            warn!("Node {} presented a resumption ticket for {}", node_id, ticket.credentials.node_id);
+            return Err(invalid());
+        }
+        
+        let now = unix_now();
+        if now > ticket.expires_at {
+            return Err(RemoteFsError::Authentication("Resumption ticket expired".to_string()));
+        }
+        
+        {
+            let mut used_tickets = self.used_tickets.write().await;
+            used_tickets.retain(|_, expires_at| *expires_at >= now);
+            if used_tickets.insert(ticket.id, ticket.expires_at).is_some() {
+                warn!("Node {} reused a resumption ticket", node_id);
+                return Err(RemoteFsError::Authentication("Resumption ticket already used".to_string()));
+            }
+        }
+        
+        let session_token = if self.config.security.enable_auth {
+            self.register_node(&ticket.credentials).await?
+        } else {
+            self.generate_session_token(&ticket.credentials)?
+        };
+        
+        debug!("Node {} resumed its session", node_id);
+        Ok((session_token, ticket.credentials))
+    }
+    
+    /// Issue and remember a session token for an authenticated node
+    async fn register_node(&self, credentials: &NodeCredentials) -> Result<SessionToken> {
+        let session_token = self.generate_session_token(credentials)?;
+        
+        let authenticated_node = AuthenticatedNode {
+            node_id: credentials.node_id.clone(),
+            node_type: credentials.node_type.clone(),
+            session_token: session_token.clone(),
+            public_key: credentials.public_key.clone(),
+            capabilities: credentials.capabilities.clone(),
+            authenticated_at: unix_now(),
+        };
+        
+        let mut tokens = self.active_tokens.write().await;
+        tokens.insert(session_token.clone(), authenticated_node);
+        Ok(session_token)
+    }
+    
     /// Validate a session token
     pub async fn validate_token(&self, session_token: &str) -> Result<AuthenticatedNode> {
         let tokens = self.active_tokens.read().await;
@@ -233,8 +336,11 @@ impl AuthManager {
     }
     
     /// Validate capabilities
-    fn validate_capabilities(&self, capabilities: &[String]) -> Result<()> {
-        const MAX_CAPABILITIES: usize = 20;
+    ///
+    /// Unknown capabilities are accepted so newer nodes can announce
+    /// features this relay does not know, but their names must be sane.
+    fn validate_capabilities(&self, node_type: &NodeType, capabilities: &[Capability]) -> Result<()> {
+        const MAX_CAPABILITIES: usize = 64;
         const MAX_CAPABILITY_LENGTH: usize = 64;
         
         if capabilities.len() > MAX_CAPABILITIES {
@@ -243,22 +349,40 @@ impl AuthManager {
             ));
         }
         
-        for capability in capabilities {
-            if capability.is_empty() {
-                return Err(RemoteFsError::Authentication("Empty capability not allowed".to_string()));
-            }
-            
-            if capability.len() > MAX_CAPABILITY_LENGTH {
+        for (index, capability) in capabilities.iter().enumerate() {
+            if capabilities[..index].contains(capability) {
                 return Err(RemoteFsError::Authentication(
-                    format!("Capability too long: max {} characters", MAX_CAPABILITY_LENGTH)
+                    format!("Capability '{}' announced more than once", capability)
                 ));
             }
             
-            // Only allow alphanumeric characters, hyphens, underscores, and dots
-            if !capability.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
-                return Err(RemoteFsError::Authentication(
-                    format!("Invalid capability '{}': contains invalid characters", capability)
-                ));
+            match capability {
+                Capability::Filesystem if !matches!(node_type, NodeType::Agent) => {
+                    return Err(RemoteFsError::Authentication(
+                        "Only agents can announce the filesystem capability".to_string()
+                    ));
+                }
+                Capability::Other(name) => {
+                    if name.is_empty() {
+                        return Err(RemoteFsError::Authentication("Empty capability not allowed".to_string()));
+                    }
+                    
+                    if name.len() > MAX_CAPABILITY_LENGTH {
+                        return Err(RemoteFsError::Authentication(
+                            format!("Capability too long: max {} characters", MAX_CAPABILITY_LENGTH)
+                        ));
+                    }
+                    
+                    // Only allow alphanumeric characters, hyphens, underscores, and dots
+                    if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.') {
+                        return Err(RemoteFsError::Authentication(
+                            format!("Invalid capability '{}': contains invalid characters", name)
+                        ));
+                    }
+                    
+                    debug!("Accepting unknown capability '{}'", name);
+                }
+                _ => {}
             }
         }
         
@@ -317,12 +441,27 @@ impl AuthManager {
         }
     }
     
-    /// Generate a session token
-    fn generate_session_token(&self, node_id: &str) -> SessionToken {
-        format!("{}_{}", Uuid::new_v4(), node_id)
+    /// Generate a session token, signed so nodes can check it offline
+    fn generate_session_token(&self, credentials: &NodeCredentials) -> Result<SessionToken> {
+        self.token_signer.sign(&TokenClaims {
+            token_id: Uuid::new_v4(),
+            relay_id: self.config.discovery.relay_id.clone(),
+            node_id: credentials.node_id.clone(),
+            node_type: credentials.node_type.clone(),
+            scope: credentials.capabilities.clone(),
+            issued_at: unix_now(),
+            ttl_secs: self.config.security.session_timeout,
+        })
     }
 }
 
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 /// Authentication statistics
 #[derive(Debug, Clone)]
 pub struct AuthStats {
@@ -335,6 +474,7 @@ pub struct AuthStats {
 mod tests {
     use super::*;
     use remotefs_common::config_utils;
+    use remotefs_common::token::TokenVerifier;
     
     #[tokio::test]
     async fn test_authentication_flow() {
@@ -344,7 +484,7 @@ mod tests {
         let node_id = "client-test-001";
         let node_type = NodeType::Client;
         let public_key = vec![0u8; 32]; // Mock 32-byte public key
-        let capabilities = vec!["read".to_string(), "write".to_string()];
+        let capabilities = vec![Capability::Read, Capability::Write];
         
         // Test successful authentication
         let token = auth_manager
@@ -374,6 +514,28 @@ mod tests {
         assert!(validation_result.is_err());
     }
     
+    #[tokio::test]
+    async fn test_tokens_verify_offline() {
+        let config = config_utils::create_default_relay_config();
+        let auth_manager = AuthManager::new(&config);
+        let verifier = TokenVerifier::new(auth_manager.token_key());
+        
+        let token = auth_manager
+            .authenticate_node("client-offline", &NodeType::Client, &[0u8; 32], &[Capability::Read])
+            .await
+            .expect("Authentication should succeed");
+        
+        let claims = verifier.verify(&token).expect("Token should verify with the published key");
+        assert_eq!(claims.node_id, "client-offline");
+        assert_eq!(claims.relay_id, config.discovery.relay_id);
+        assert_eq!(claims.ttl_secs, config.security.session_timeout);
+        assert!(claims.allows(&Capability::Read));
+        assert!(!claims.allows(&Capability::Write));
+        
+        // Another relay's key does not verify it
+        assert!(TokenVerifier::new(AuthManager::new(&config).token_key()).verify(&token).is_err());
+    }
+    
     #[tokio::test]
     async fn test_validation_failures() {
         let config = config_utils::create_default_relay_config();
@@ -381,22 +543,43 @@ mod tests {
         
         // Test empty node ID
         let result = auth_manager
-            .authenticate_node("", &NodeType::Client, &vec![0u8; 32], &vec![])
+            .authenticate_node("", &NodeType::Client, &[0u8; 32], &[])
             .await;
         assert!(result.is_err());
         
         // Test invalid public key length
         let result = auth_manager
-            .authenticate_node("client-test", &NodeType::Client, &vec![0u8; 16], &vec![])
+            .authenticate_node("client-test", &NodeType::Client, &[0u8; 16], &[])
             .await;
         assert!(result.is_err());
         
         // Test too many capabilities
-        let many_caps: Vec<String> = (0..25).map(|i| format!("cap-{}", i)).collect();
+        let many_caps: Vec<Capability> = (0..65).map(|i| Capability::from(format!("cap-{}", i))).collect();
         let result = auth_manager
-            .authenticate_node("client-test", &NodeType::Client, &vec![0u8; 32], &many_caps)
+            .authenticate_node("client-test", &NodeType::Client, &[0u8; 32], &many_caps)
             .await;
         assert!(result.is_err());
+        
+        // Test repeated, malformed and misplaced capabilities
+        for capabilities in [
+            vec![Capability::Read, Capability::Read],
+            vec![Capability::Other("bad cap".to_string())],
+            vec![Capability::Filesystem],
+        ] {
+            let result = auth_manager
+                .authenticate_node("client-test", &NodeType::Client, &[0u8; 32], &capabilities)
+                .await;
+            assert!(result.is_err());
+        }
+        
+        // Unknown but well-formed capabilities are fine
+        let result = auth_manager
+            .authenticate_node("agent-test", &NodeType::Agent, &[0u8; 32], &[
+                Capability::Filesystem,
+                Capability::Other("future.feature".to_string()),
+            ])
+            .await;
+        assert!(result.is_ok());
     }
     
     #[tokio::test]
@@ -411,8 +594,8 @@ mod tests {
             .authenticate_node(
                 "client-expire-test",
                 &NodeType::Client,
-                &vec![0u8; 32],
-                &vec![],
+                &[0u8; 32],
+                &[],
             )
             .await
             .expect("Authentication should succeed");
@@ -433,6 +616,57 @@ mod tests {
         assert_eq!(removed, 1);
     }
     
+    #[tokio::test]
+    async fn test_session_resumption() {
+        let config = config_utils::create_default_relay_config();
+        let auth_manager = AuthManager::new(&config);
+        let credentials = NodeCredentials {
+            node_id: "agent-resume".to_string(),
+            node_type: NodeType::Agent,
+            public_key: vec![7u8; 32],
+            capabilities: vec![Capability::Filesystem],
+        };
+        
+        let ticket = auth_manager.issue_ticket(&credentials).unwrap().expect("Resumption is on by default");
+        
+        // Tickets are bound to their node
+        assert!(auth_manager.resume_session("agent-other", &ticket).await.is_err());
+        
+        let (token, resumed) = auth_manager
+            .resume_session("agent-resume", &ticket)
+            .await
+            .expect("Resumption should succeed");
+        assert_eq!(resumed.public_key, credentials.public_key);
+        assert_eq!(auth_manager.validate_token(&token).await.unwrap().capabilities, credentials.capabilities);
+        
+        // Each ticket works once, and tampered tickets not at all
+        assert!(auth_manager.resume_session("agent-resume", &ticket).await.is_err());
+        let mut tampered = auth_manager.issue_ticket(&credentials).unwrap().unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(auth_manager.resume_session("agent-resume", &tampered).await.is_err());
+        
+        // Another relay cannot open this relay's tickets
+        let fresh = auth_manager.issue_ticket(&credentials).unwrap().unwrap();
+        assert!(AuthManager::new(&config).resume_session("agent-resume", &fresh).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_resumption_disabled() {
+        let mut config = config_utils::create_default_relay_config();
+        config.session.resumption_ticket_secs = 0;
+        let auth_manager = AuthManager::new(&config);
+        let credentials = NodeCredentials {
+            node_id: "client-001".to_string(),
+            node_type: NodeType::Client,
+            public_key: vec![0u8; 32],
+            capabilities: vec![],
+        };
+        
+        assert!(auth_manager.issue_ticket(&credentials).unwrap().is_none());
+        assert!(auth_manager.resume_session("client-001", &[0u8; 64]).await.is_err());
+    }
+    
     #[tokio::test]
     async fn test_auth_stats() {
         let config = config_utils::create_default_relay_config();
@@ -446,12 +680,12 @@ mod tests {
         
         // Authenticate some nodes
         let _client_token = auth_manager
-            .authenticate_node("client-001", &NodeType::Client, &vec![0u8; 32], &vec![])
+            .authenticate_node("client-001", &NodeType::Client, &[0u8; 32], &[])
             .await
             .expect("Client authentication should succeed");
             
         let _agent_token = auth_manager
-            .authenticate_node("agent-001", &NodeType::Agent, &vec![0u8; 32], &vec![])
+            .authenticate_node("agent-001", &NodeType::Agent, &[0u8; 32], &[])
             .await
             .expect("Agent authentication should succeed");
         
@@ -0,0 +1,307 @@
+//! Accounting for messages queued to slow consumers
+//!
+//! Every message the relay sends to a session waits in that session's
+//! outgoing queue until its socket accepts it. A client that stops reading
+//! while its agent keeps answering would otherwise grow the queue until the
+//! relay runs out of memory, so queued bytes are counted per session and
+//! across the relay: past the soft limit a session's new requests are
+//! refused until it catches up, and past the hard limit it is disconnected.
+
+use axum::extract::ws::Message as WsMessage;
+use remotefs_common::{
+    config::BufferLimits,
+    error::{RemoteFsError, Result},
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, watch};
+
+/// Statistics for buffered outgoing messages
+#[derive(Debug, Clone, Default)]
+pub struct BufferStats {
+    pub buffered_bytes: u64,
+    pub throttled_requests: u64,
+    pub slow_consumers_disconnected: u64,
+}
+
+/// Why a request was refused instead of being routed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// The requesting session has too much data waiting for it
+    Session { queued: u64 },
+    /// The relay as a whole has too much data waiting
+    Relay { queued: u64 },
+}
+
+impl Backpressure {
+    /// Short name used in error details
+    pub fn resource(&self) -> &'static str {
+        match self {
+            Backpressure::Session { .. } => "session_buffer",
+            Backpressure::Relay { .. } => "relay_buffer",
+        }
+    }
+
+    /// Bytes queued when the request was refused
+    pub fn queued(&self) -> u64 {
+        match self {
+            Backpressure::Session { queued } | Backpressure::Relay { queued } => *queued,
+        }
+    }
+}
+
+/// Relay-wide buffer limits and counters shared by all sessions
+#[derive(Debug)]
+pub struct BufferAccounting {
+    soft_limit: u64,
+    hard_limit: u64,
+    total_limit: u64,
+    total: AtomicU64,
+    throttled_requests: AtomicU64,
+    slow_consumers_disconnected: AtomicU64,
+}
+
+impl BufferAccounting {
+    pub fn new(limits: &BufferLimits) -> Self {
+        let mb = |value: u64| value.saturating_mul(1024 * 1024);
+        Self {
+            soft_limit: mb(limits.session_soft_limit_mb),
+            hard_limit: mb(limits.session_hard_limit_mb.max(limits.session_soft_limit_mb)),
+            total_limit: mb(limits.total_limit_mb),
+            total: AtomicU64::new(0),
+            throttled_requests: AtomicU64::new(0),
+            slow_consumers_disconnected: AtomicU64::new(0),
+        }
+    }
+
+    /// Current usage and enforcement counts
+    pub fn statistics(&self) -> BufferStats {
+        BufferStats {
+            buffered_bytes: self.total.load(Ordering::Relaxed),
+            throttled_requests: self.throttled_requests.load(Ordering::Relaxed),
+            slow_consumers_disconnected: self.slow_consumers_disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Create the outgoing queue for one connection
+pub fn outbound_channel(accounting: &Arc<BufferAccounting>) -> (OutboundSender, OutboundReceiver) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (overflow, _) = watch::channel(None);
+    let buffer = Arc::new(SessionBuffer {
+        accounting: Arc::clone(accounting),
+        queued: AtomicU64::new(0),
+        overflow,
+    });
+
+    (
+        OutboundSender { tx, buffer: Arc::clone(&buffer) },
+        OutboundReceiver { rx, buffer },
+    )
+}
+
+/// Bytes a message holds while it waits in a queue
+pub fn message_size(message: &WsMessage) -> u64 {
+    let len = match message {
+        WsMessage::Text(text) => text.len(),
+        WsMessage::Binary(data) | WsMessage::Ping(data) | WsMessage::Pong(data) => data.len(),
+        WsMessage::Close(_) => 0,
+    };
+    len as u64
+}
+
+/// Queued bytes of one connection
+#[derive(Debug)]
+struct SessionBuffer {
+    accounting: Arc<BufferAccounting>,
+    queued: AtomicU64,
+    /// Set to the queued bytes once the hard limit was passed
+    overflow: watch::Sender<Option<u64>>,
+}
+
+impl SessionBuffer {
+    fn reserve(&self, bytes: u64) -> std::result::Result<(), u64> {
+        if let Some(queued) = *self.overflow.borrow() {
+            return Err(queued);
+        }
+
+        let queued = self.queued.fetch_add(bytes, Ordering::AcqRel) + bytes;
+        if queued > self.accounting.hard_limit {
+            self.queued.fetch_sub(bytes, Ordering::AcqRel);
+            let first = self.overflow.send_if_modified(|overflow| {
+                let first = overflow.is_none();
+                overflow.get_or_insert(queued);
+                first
+            });
+            if first {
+                self.accounting.slow_consumers_disconnected.fetch_add(1, Ordering::Relaxed);
+            }
+            return Err(queued);
+        }
+
+        self.accounting.total.fetch_add(bytes, Ordering::AcqRel);
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        self.queued.fetch_sub(bytes, Ordering::AcqRel);
+        self.accounting.total.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+impl Drop for SessionBuffer {
+    fn drop(&mut self) {
+        // Whatever was still queued is dropped with the channel
+        let queued = self.queued.load(Ordering::Acquire);
+        self.accounting.total.fetch_sub(queued, Ordering::AcqRel);
+    }
+}
+
+/// Sending half of a connection's outgoing queue
+#[derive(Debug, Clone)]
+pub struct OutboundSender {
+    tx: mpsc::UnboundedSender<WsMessage>,
+    buffer: Arc<SessionBuffer>,
+}
+
+impl OutboundSender {
+    /// Queue a message, failing if the connection is gone or was
+    /// disconnected for falling too far behind
+    pub fn send(&self, message: WsMessage) -> Result<()> {
+        let size = message_size(&message);
+        self.buffer.reserve(size).map_err(|queued| {
+            RemoteFsError::Network(format!("Session disconnected as a slow consumer ({} bytes buffered)", queued))
+        })?;
+
+        self.tx.send(message).map_err(|_| {
+            self.buffer.release(size);
+            RemoteFsError::Network("Failed to send message to session".to_string())
+        })
+    }
+
+    /// Check whether the connection may make new requests; counts refusals
+    pub fn admit(&self) -> std::result::Result<(), Backpressure> {
+        let accounting = &self.buffer.accounting;
+        let queued = self.queued();
+        let refusal = if queued > accounting.soft_limit {
+            Backpressure::Session { queued }
+        } else {
+            let total = accounting.total.load(Ordering::Acquire);
+            if total <= accounting.total_limit {
+                return Ok(());
+            }
+            Backpressure::Relay { queued: total }
+        };
+
+        accounting.throttled_requests.fetch_add(1, Ordering::Relaxed);
+        Err(refusal)
+    }
+
+    /// Bytes waiting to be written to the connection
+    pub fn queued(&self) -> u64 {
+        self.buffer.queued.load(Ordering::Acquire)
+    }
+
+    /// Wait until the connection passes the hard limit, returning the
+    /// bytes it had queued
+    pub async fn overflowed(&self) -> u64 {
+        let mut overflow = self.buffer.overflow.subscribe();
+        loop {
+            if let Some(queued) = *overflow.borrow_and_update() {
+                return queued;
+            }
+            if overflow.changed().await.is_err() {
+                // The sender lives in the buffer we hold, so this never happens
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Receiving half of a connection's outgoing queue
+#[derive(Debug)]
+pub struct OutboundReceiver {
+    rx: mpsc::UnboundedReceiver<WsMessage>,
+    buffer: Arc<SessionBuffer>,
+}
+
+impl OutboundReceiver {
+    /// Next message to write; its bytes stay counted until [`release`](Self::release)
+    pub async fn recv(&mut self) -> Option<WsMessage> {
+        self.rx.recv().await
+    }
+
+    /// Stop counting a message once it was written
+    pub fn release(&self, bytes: u64) {
+        self.buffer.release(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounting(soft_mb: u64, hard_mb: u64, total_mb: u64) -> Arc<BufferAccounting> {
+        Arc::new(BufferAccounting::new(&BufferLimits {
+            session_soft_limit_mb: soft_mb,
+            session_hard_limit_mb: hard_mb,
+            total_limit_mb: total_mb,
+        }))
+    }
+
+    fn message(bytes: usize) -> WsMessage {
+        WsMessage::Binary(vec![0; bytes])
+    }
+
+    #[tokio::test]
+    async fn test_soft_limit_throttles_until_drained() {
+        let mb = 1024 * 1024;
+        let accounting = accounting(1, 4, 64);
+        let (tx, mut rx) = outbound_channel(&accounting);
+
+        tx.send(message(mb as usize)).unwrap();
+        assert!(tx.admit().is_ok());
+        tx.send(message(1)).unwrap();
+        assert_eq!(tx.admit(), Err(Backpressure::Session { queued: mb + 1 }));
+        assert_eq!(accounting.statistics().buffered_bytes, mb + 1);
+
+        let first = rx.recv().await.unwrap();
+        rx.release(message_size(&first));
+        assert!(tx.admit().is_ok());
+        assert_eq!(accounting.statistics().throttled_requests, 1);
+    }
+
+    #[tokio::test]
+    async fn test_hard_limit_disconnects() {
+        let mb = 1024 * 1024;
+        let accounting = accounting(1, 2, 64);
+        let (tx, rx) = outbound_channel(&accounting);
+
+        tx.send(message(2 * mb as usize)).unwrap();
+        assert!(tx.send(message(1)).is_err());
+        assert_eq!(tx.overflowed().await, 2 * mb + 1);
+        // Once disconnected, nothing more is queued
+        assert!(tx.send(message(0)).is_err());
+        assert_eq!(accounting.statistics().slow_consumers_disconnected, 1);
+
+        // Dropping the queue returns its bytes to the relay budget
+        drop(tx);
+        drop(rx);
+        assert_eq!(accounting.statistics().buffered_bytes, 0);
+    }
+
+    #[test]
+    fn test_relay_limit() {
+        let mb = 1024 * 1024;
+        let accounting = accounting(8, 8, 1);
+        let (stalled, _stalled_rx) = outbound_channel(&accounting);
+        let (other, _other_rx) = outbound_channel(&accounting);
+
+        stalled.send(message(mb as usize + 1)).unwrap();
+        assert!(stalled.admit().is_err());
+        assert_eq!(other.admit(), Err(Backpressure::Relay { queued: mb + 1 }));
+        assert_eq!(other.admit().unwrap_err().resource(), "relay_buffer");
+    }
+}
@@ -0,0 +1,199 @@
+//! Command line interface of the relay, run by `remotefs-relay` and by
+//! `remotefs relay`
+
+use remotefs_common::{
+    load_relay_config,
+    crash::{self, RecentEvents},
+    error::Result,
+    service::SystemdService,
+};
+use clap::{Parser, Subcommand};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::signal;
+use tracing::{error, info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{AuthManager, RelayServer};
+
+/// RemoteFS Relay - Connects clients to the agents serving their files
+#[derive(Parser)]
+#[command(name = "remotefs-relay")]
+#[command(about = "Relay server connecting RemoteFS clients to agents")]
+#[command(version)]
+pub struct Cli {
+    /// Configuration file path (defaults to $REMOTEFS_RELAY_CONFIG, then
+    /// relay-config.toml)
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+pub enum Commands {
+    /// Run the relay as a systemd service, started at boot
+    InstallService {
+        /// Print the unit instead of installing it
+        #[arg(long)]
+        print: bool,
+
+        /// Account to run the relay as (default: root)
+        #[arg(long, value_name = "USER")]
+        user: Option<String>,
+
+        /// Binary the unit starts (default: this one)
+        #[arg(long, value_name = "PATH")]
+        program: Option<PathBuf>,
+    },
+}
+
+/// Run the relay until interrupted
+pub async fn run(cli: Cli) -> Result<()> {
+    if let Some(Commands::InstallService { print, user, program }) = cli.command {
+        return install_service(cli.config, print, user, program);
+    }
+
+    // Initialize tracing
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
+        ))
+        .with(tracing_subscriber::fmt::layer())
+        .with(RecentEvents)
+        .init();
+
+    info!("Starting RemoteFS Relay Server...");
+
+    // Load configuration
+    let config_path = config_path(cli.config);
+    let config = match load_relay_config(&config_path) {
+        Ok(cfg) => {
+            info!("Loaded configuration from: {}", config_path.display());
+            cfg
+        }
+        Err(e) => {
+            warn!("Failed to load config from {}: {}. Using default configuration.", config_path.display(), e);
+            remotefs_common::config_utils::create_default_relay_config()
+        }
+    };
+    crash::install("remotefs-relay", &config.logging.crash);
+
+    // Create authentication manager
+    let auth_manager = Arc::new(AuthManager::new(&config));
+    info!("Authentication manager initialized (auth enabled: {})", config.security.enable_auth);
+
+    // Create and start the relay server
+    let server = RelayServer::new(config.clone(), auth_manager.clone())?;
+    
+    // Set up graceful shutdown
+    let server_handle = tokio::spawn(async move {
+        if let Err(e) = server.run().await {
+            error!("Server error: {}", e);
+        }
+    });
+
+    // Set up periodic cleanup tasks
+    let cleanup_auth_manager = auth_manager.clone();
+    let cleanup_handle = tokio::spawn(async move {
+        let mut cleanup_interval = tokio::time::interval(tokio::time::Duration::from_secs(300)); // 5 minutes
+        loop {
+            cleanup_interval.tick().await;
+            let removed = cleanup_auth_manager.cleanup_expired_tokens().await;
+            if removed > 0 {
+                info!("Cleaned up {} expired authentication tokens", removed);
+            }
+        }
+    });
+
+    // Set up stats reporting
+    let stats_auth_manager = auth_manager.clone();
+    let stats_handle = tokio::spawn(async move {
+        let mut stats_interval = tokio::time::interval(tokio::time::Duration::from_secs(600)); // 10 minutes
+        loop {
+            stats_interval.tick().await;
+            let auth_stats = stats_auth_manager.get_auth_stats().await;
+            info!(
+                "Server stats - Active sessions: {} (clients: {}, agents: {})",
+                auth_stats.total_authenticated,
+                auth_stats.authenticated_clients,
+                auth_stats.authenticated_agents
+            );
+        }
+    });
+
+    info!(
+        "RemoteFS Relay Server started on {}:{}",
+        config.bind_address, config.port
+    );
+
+    // Wait for shutdown signal
+    match signal::ctrl_c().await {
+        Ok(_) => {
+            info!("Received shutdown signal, gracefully shutting down...");
+        }
+        Err(err) => {
+            error!("Unable to listen for shutdown signal: {}", err);
+        }
+    }
+
+    // Cancel background tasks
+    cleanup_handle.abort();
+    stats_handle.abort();
+    server_handle.abort();
+
+    info!("RemoteFS Relay Server shutdown complete");
+    Ok(())
+}
+
+fn config_path(cli_config: Option<PathBuf>) -> PathBuf {
+    cli_config
+        .or_else(|| env::var("REMOTEFS_RELAY_CONFIG").ok().map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("relay-config.toml"))
+}
+
+/// The systemd service running the relay from `program` with `config`
+pub fn relay_service(program: &Path, config: &Path, user: Option<String>) -> SystemdService {
+    let mut service = SystemdService::new("remotefs-relay", "RemoteFS Relay", program, config);
+    service.user = user;
+    service
+}
+
+/// Install, or with `print` show, a systemd unit running this relay with its
+/// configuration file
+fn install_service(cli_config: Option<PathBuf>, print: bool, user: Option<String>, program: Option<PathBuf>) -> Result<()> {
+    let program = match program {
+        Some(program) => program,
+        None => env::current_exe()?,
+    };
+    let config = env::current_dir()?.join(config_path(cli_config));
+    let service = relay_service(&program, &config, user);
+
+    if print {
+        print!("{}", service.unit());
+        return Ok(());
+    }
+    let path = service.install()?;
+    println!("Installed and started {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packaged_service() {
+        // Packages ship what `install-service` generates for their paths
+        let service = relay_service(
+            Path::new("/usr/bin/remotefs-relay"),
+            Path::new("/etc/remotefs/relay.toml"),
+            Some("remotefs".to_string()),
+        );
+        assert_eq!(service.unit(), include_str!("../../packaging/systemd/remotefs-relay.service"));
+
+        load_relay_config(concat!(env!("CARGO_MANIFEST_DIR"), "/../packaging/config/relay.toml")).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Promotion of mirror agents when their primary disappears
+//!
+//! A mirror replicates its primary asynchronously and refuses writes. While
+//! the primary is connected the mirror receives no client requests; once the
+//! primary is gone the relay promotes the mirror, routes reads (and writes,
+//! if the pair allows it) to it, and tells the mirror and every client. When
+//! the primary returns the mirror is demoted again.
+//!
+//! Promotion does not make the mirror consistent with the primary: changes
+//! the mirror had not replicated yet are missing from it, and writes made to
+//! a promoted mirror are not copied back to the primary.
+
+use crate::session::SessionManager;
+use chrono::Utc;
+use remotefs_common::{
+    config::MirrorPair,
+    protocol::{Message, NodeType},
+};
+use std::collections::HashSet;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Tracks which mirrors are promoted
+pub struct MirrorManager {
+    pairs: Vec<MirrorPair>,
+    promoted: RwLock<HashSet<String>>,
+}
+
+impl MirrorManager {
+    pub fn new(pairs: Vec<MirrorPair>) -> Self {
+        Self {
+            pairs,
+            promoted: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Promote or demote mirrors according to which agents are connected
+    ///
+    /// Returns a `MirrorStatus` event for every mirror whose state changed.
+    pub async fn update(&self, connected: &[String]) -> Vec<Message> {
+        let mut promoted = self.promoted.write().await;
+        let mut events = Vec::new();
+
+        for pair in &self.pairs {
+            let should_promote = !connected.contains(&pair.primary) && connected.contains(&pair.mirror);
+            // A mirror that disconnected stays promoted until the primary returns
+            let demote = connected.contains(&pair.primary);
+
+            let changed = if should_promote {
+                promoted.insert(pair.mirror.clone())
+            } else if demote {
+                promoted.remove(&pair.mirror)
+            } else {
+                false
+            };
+
+            if changed {
+                let is_promoted = promoted.contains(&pair.mirror);
+                if is_promoted {
+                    warn!(
+                        "Primary agent {} is gone, promoting mirror {} ({})",
+                        pair.primary,
+                        pair.mirror,
+                        if pair.promote_writes { "reads and writes" } else { "read-only" }
+                    );
+                } else {
+                    info!("Primary agent {} is back, demoting mirror {}", pair.primary, pair.mirror);
+                }
+                events.push(status_message(pair, is_promoted));
+            }
+        }
+
+        events
+    }
+
+    /// Current status for `mirror`, if it belongs to a pair
+    pub async fn status_for(&self, mirror: &str) -> Option<Message> {
+        let pair = self.pairs.iter().find(|pair| pair.mirror == mirror)?;
+        let promoted = self.promoted.read().await.contains(mirror);
+        Some(status_message(pair, promoted))
+    }
+
+    /// Agents that may serve a request, in the order they were given
+    ///
+    /// Mirrors are left out unless promoted, and promoted mirrors only take
+    /// writes when their pair allows it.
+    pub async fn routable_agents(&self, agents: Vec<String>, is_write: bool) -> Vec<String> {
+        let promoted = self.promoted.read().await;
+        agents
+            .into_iter()
+            .filter(|agent| match self.pairs.iter().find(|pair| &pair.mirror == agent) {
+                Some(pair) => promoted.contains(agent) && (!is_write || pair.promote_writes),
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Re-evaluate promotions and deliver the resulting events to the
+    /// affected mirrors and to all clients
+    pub async fn refresh(&self, session_manager: &SessionManager) {
+        if self.pairs.is_empty() {
+            return;
+        }
+
+        let connected = session_manager.get_active_nodes(NodeType::Agent).await;
+        for event in self.update(&connected).await {
+            let mut recipients = session_manager.get_sessions_by_type(NodeType::Client).await;
+            if let Message::MirrorStatus { mirror, .. } = &event {
+                recipients.extend(session_manager.get_session_by_node(mirror).await);
+            }
+
+            for session in recipients {
+                let sent = match session.message_format.encode(&event) {
+                    Ok(ws_message) => session.send_message(ws_message).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    warn!("Failed to send mirror status to {}: {}", session.node_id, e);
+                }
+            }
+        }
+    }
+}
+
+fn status_message(pair: &MirrorPair, promoted: bool) -> Message {
+    Message::MirrorStatus {
+        primary: pair.primary.clone(),
+        mirror: pair.mirror.clone(),
+        promoted,
+        writes_allowed: promoted && pair.promote_writes,
+        timestamp: Utc::now(),
+    }
+}
+
+/// Whether a request modifies the agent's filesystem
+///
+/// Lock requests count as writes: locks are only seen by the agent holding
+/// them, so they stay with the primary like the writes they guard. A batch
+/// is a write if any of its requests is.
+pub fn is_write_request(message: &Message) -> bool {
+    match message {
+        Message::WriteFile { .. }
+        | Message::WriteFileChunk { .. }
+        | Message::WriteDelta { .. }
+        | Message::CreateFile { .. }
+        | Message::DeleteFile { .. }
+        | Message::TruncateFile { .. }
+        | Message::CreateDirectory { .. }
+        | Message::RemoveDirectory { .. }
+        | Message::SetMetadata { .. }
+        | Message::SetMetadataTree { .. }
+        | Message::SetXattr { .. }
+        | Message::RemoveXattr { .. }
+        | Message::Rename { .. }
+        | Message::CreateSymlink { .. }
+        | Message::CreateHardLink { .. }
+        | Message::CopyFile { .. }
+        | Message::Transaction { .. }
+        | Message::BatchCreateFiles { .. }
+        | Message::RestoreFromTrash { .. }
+        | Message::PurgeTrash { .. }
+        | Message::LockFile { .. }
+        | Message::UnlockFile { .. }
+        | Message::TestLock { .. } => true,
+        Message::OpenFile { flags, .. } => flags.writes() || flags.create,
+        Message::Batch { operations, .. } => operations.iter().any(is_write_request),
+        Message::AsUser { request, .. } => is_write_request(request),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(promote_writes: bool) -> MirrorPair {
+        MirrorPair {
+            primary: "primary".to_string(),
+            mirror: "mirror".to_string(),
+            promote_writes,
+        }
+    }
+
+    fn agents(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_promotion_and_demotion() {
+        let manager = MirrorManager::new(vec![pair(false)]);
+        assert!(manager.update(&agents(&["primary", "mirror"])).await.is_empty());
+        assert_eq!(manager.routable_agents(agents(&["primary", "mirror"]), false).await, agents(&["primary"]));
+
+        let events = manager.update(&agents(&["mirror"])).await;
+        assert!(matches!(
+            events.as_slice(),
+            [Message::MirrorStatus { promoted: true, writes_allowed: false, .. }]
+        ));
+        assert_eq!(manager.routable_agents(agents(&["mirror"]), false).await, agents(&["mirror"]));
+        assert!(manager.routable_agents(agents(&["mirror"]), true).await.is_empty());
+
+        // No repeated events while nothing changes
+        assert!(manager.update(&agents(&["mirror"])).await.is_empty());
+
+        let events = manager.update(&agents(&["primary", "mirror"])).await;
+        assert!(matches!(events.as_slice(), [Message::MirrorStatus { promoted: false, .. }]));
+        assert!(matches!(manager.status_for("mirror").await, Some(Message::MirrorStatus { promoted: false, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_promoted_writes() {
+        let manager = MirrorManager::new(vec![pair(true)]);
+        manager.update(&agents(&["mirror", "other"])).await;
+        assert_eq!(
+            manager.routable_agents(agents(&["mirror", "other"]), true).await,
+            agents(&["mirror", "other"])
+        );
+    }
+
+    #[test]
+    fn test_is_write_request() {
+        let write = Message::DeleteFile { request_id: uuid::Uuid::new_v4(), path: "/a".to_string() };
+        let read = Message::PathExists { request_id: uuid::Uuid::new_v4(), path: "/a".to_string() };
+        assert!(is_write_request(&write));
+        assert!(!is_write_request(&read));
+        assert!(!is_write_request(&Message::Batch {
+            request_id: uuid::Uuid::new_v4(),
+            operations: vec![read.clone()],
+        }));
+        assert!(is_write_request(&Message::Batch {
+            request_id: uuid::Uuid::new_v4(),
+            operations: vec![read, write.clone()],
+        }));
+        assert!(is_write_request(&Message::AsUser {
+            identity: remotefs_common::protocol::CallerIdentity { uid: 1, gid: 1, groups: vec![] },
+            request: Box::new(write),
+        }));
+    }
+}
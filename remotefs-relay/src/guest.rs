@@ -0,0 +1,230 @@
+//! Anonymous read-only access to public exports
+//!
+//! A connection that sends a request without authenticating is treated as a
+//! guest when the relay has public exports. Guests can only read, list and
+//! stat paths inside an export, each request goes to the agent serving that
+//! export, and every export has its own rate limit shared by all its guests,
+//! apart from the limits that apply to authenticated clients. Anything else
+//! a guest sends is refused without reaching an agent.
+
+use remotefs_common::{
+    config::PublicExport,
+    error::{RemoteFsError, Result},
+    protocol::Message,
+};
+use std::{
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Statistics for guest requests
+#[derive(Debug, Clone, Default)]
+pub struct GuestStats {
+    pub admitted: u64,
+    pub refused: u64,
+    pub rate_limited: u64,
+}
+
+/// The relay's public exports and their rate limits
+#[derive(Debug)]
+pub struct GuestAccess {
+    exports: Vec<Export>,
+    admitted: AtomicU64,
+    refused: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Export {
+    config: PublicExport,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl GuestAccess {
+    pub fn new(exports: &[PublicExport]) -> Self {
+        let now = Instant::now();
+        Self {
+            exports: exports.iter()
+                .map(|export| Export {
+                    config: export.clone(),
+                    bucket: Mutex::new(TokenBucket::full(export.burst, now)),
+                })
+                .collect(),
+            admitted: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether unauthenticated connections may become guests
+    pub fn is_enabled(&self) -> bool {
+        !self.exports.is_empty()
+    }
+
+    /// Check a request from a guest, returning the agent to route it to
+    pub fn admit(&self, message: &Message) -> Result<String> {
+        self.admit_at(message, Instant::now())
+    }
+
+    fn admit_at(&self, message: &Message, now: Instant) -> Result<String> {
+        let Some(path) = guest_read_path(message) else {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(RemoteFsError::AccessDenied(format!(
+                "Anonymous clients cannot send {}", message.message_type()
+            )));
+        };
+
+        let Some(export) = self.exports.iter().find(|export| is_within(path, &export.config.path)) else {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(RemoteFsError::AccessDenied(format!("{} is not publicly exported", path)));
+        };
+
+        let per_second = f64::from(export.config.requests_per_minute) / 60.0;
+        if !export.bucket.lock().unwrap().take(per_second, export.config.burst, now) {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return Err(RemoteFsError::ServiceUnavailable(format!(
+                "Public export {} is busy, retry later", export.config.path
+            )));
+        }
+
+        self.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(export.config.agent_id.clone())
+    }
+
+    pub fn get_stats(&self) -> GuestStats {
+        GuestStats {
+            admitted: self.admitted.load(Ordering::Relaxed),
+            refused: self.refused.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Path a guest may read with `message`, or `None` if guests may not send it
+///
+/// Only requests that cannot change anything on the agent qualify.
+pub fn guest_read_path(message: &Message) -> Option<&str> {
+    match message {
+        Message::ReadFile { path, .. }
+        | Message::ListDirectory { path, .. }
+        | Message::ListDirectoryPaged { path, .. }
+        | Message::WalkDirectory { path, .. }
+        | Message::SearchFiles { root: path, .. }
+        | Message::GetMetadata { path, .. }
+        | Message::PathExists { path, .. } => Some(path),
+        _ => None,
+    }
+}
+
+/// Whether `path` names `root` or something below it
+///
+/// Paths are compared by component, and relative paths or paths that climb
+/// with `..` never match.
+fn is_within(path: &str, root: &str) -> bool {
+    let path = Path::new(path);
+    path.is_absolute()
+        && !path.components().any(|component| matches!(component, Component::ParentDir))
+        && path.starts_with(root)
+}
+
+/// Requests admitted at a steady rate with bursts up to a capacity
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(capacity: u32, now: Instant) -> Self {
+        Self { tokens: f64::from(capacity), updated: now }
+    }
+
+    /// Take a token if one is available after refilling at `per_second`
+    fn take(&mut self, per_second: f64, capacity: u32, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(f64::from(capacity));
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remotefs_common::protocol::generate_request_id;
+    use std::time::Duration;
+
+    fn access(requests_per_minute: u32, burst: u32) -> GuestAccess {
+        GuestAccess::new(&[PublicExport {
+            agent_id: "datasets".to_string(),
+            path: "/srv/public".to_string(),
+            requests_per_minute,
+            burst,
+        }])
+    }
+
+    fn read(path: &str) -> Message {
+        Message::ReadFile { request_id: generate_request_id(), path: path.to_string(), offset: 0, length: 4096 }
+    }
+
+    #[test]
+    fn test_reads_inside_exports_only() {
+        let guests = access(600, 50);
+        assert_eq!(guests.admit(&read("/srv/public/data.csv")).unwrap(), "datasets");
+        assert!(guests.admit(&read("/srv/public")).is_ok());
+
+        for path in ["/srv/publicity/data.csv", "/srv/public/../private/key", "srv/public/data.csv", "/etc/passwd"] {
+            assert!(matches!(guests.admit(&read(path)), Err(RemoteFsError::AccessDenied(_))), "{}", path);
+        }
+
+        let stats = guests.get_stats();
+        assert_eq!((stats.admitted, stats.refused), (2, 4));
+    }
+
+    #[test]
+    fn test_mutating_requests_are_refused() {
+        let guests = access(600, 50);
+        let write = Message::WriteFile {
+            request_id: generate_request_id(),
+            path: "/srv/public/data.csv".to_string(),
+            offset: 0,
+            data: b"overwritten".to_vec(),
+            sync: false,
+        };
+        let delete = Message::DeleteFile {
+            request_id: generate_request_id(),
+            path: "/srv/public/data.csv".to_string(),
+        };
+
+        assert!(matches!(guests.admit(&write), Err(RemoteFsError::AccessDenied(_))));
+        assert!(matches!(guests.admit(&delete), Err(RemoteFsError::AccessDenied(_))));
+        assert!(!GuestAccess::new(&[]).is_enabled());
+    }
+
+    #[test]
+    fn test_rate_limit_refills() {
+        let guests = access(60, 2);
+        let start = Instant::now();
+
+        assert!(guests.admit_at(&read("/srv/public/a"), start).is_ok());
+        assert!(guests.admit_at(&read("/srv/public/b"), start).is_ok());
+        assert!(matches!(
+            guests.admit_at(&read("/srv/public/c"), start),
+            Err(RemoteFsError::ServiceUnavailable(_))
+        ));
+
+        // One request per second at 60 a minute
+        assert!(guests.admit_at(&read("/srv/public/c"), start + Duration::from_secs(1)).is_ok());
+        assert_eq!(guests.get_stats().rate_limited, 1);
+    }
+}
@@ -0,0 +1,356 @@
+//! Confinement of the relay process
+//!
+//! The relay parses input from anyone who can reach its port, but once it
+//! is listening it needs little of the system: it never opens files other
+//! than its crash reports, runs programs or changes user. `[hardening]`
+//! takes away what it does not need, in the order it must be given up:
+//!
+//! 1. `confine_to_storage` makes `storage.temp_dir` the root directory;
+//! 2. `user` switches to an unprivileged account, which also drops every
+//!    capability a relay started as root had;
+//! 3. `seccomp` installs a filter on every thread that refuses system calls
+//!    outside an allowlist with `EPERM`.
+//!
+//! A relay that cannot apply what is configured does not start.
+
+use remotefs_common::{
+    config::HardeningConfig,
+    error::{RemoteFsError, Result},
+};
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use tracing::info;
+
+/// Confine the relay as `config` says, after it has read its TLS key and
+/// bound its port
+#[cfg(target_os = "linux")]
+pub fn apply(config: &HardeningConfig, storage_dir: &Path) -> Result<()> {
+    // Looked up before the account database goes out of reach
+    let account = config.user.as_deref().map(lookup_user).transpose()?;
+
+    if config.confine_to_storage {
+        std::fs::create_dir_all(storage_dir)
+            .and_then(|_| std::os::unix::fs::chroot(storage_dir))
+            .and_then(|_| std::env::set_current_dir("/"))
+            .map_err(|e| RemoteFsError::Configuration(format!(
+                "Failed to confine the relay to {}: {}", storage_dir.display(), e
+            )))?;
+        info!("Relay confined to {}", storage_dir.display());
+    }
+
+    if let (Some(name), Some(account)) = (config.user.as_deref(), account) {
+        switch_user(account).map_err(|e| RemoteFsError::Configuration(format!(
+            "Failed to switch to user '{}': {}", name, e
+        )))?;
+        info!("Relay running as user '{}'", name);
+    }
+
+    if config.seccomp {
+        install_filter(&filter(&allowed_syscalls())).map_err(|e| RemoteFsError::Configuration(format!(
+            "Failed to install the system call filter: {}", e
+        )))?;
+        info!("Relay system calls filtered");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(config: &HardeningConfig, _storage_dir: &Path) -> Result<()> {
+    if config.is_enabled() {
+        return Err(RemoteFsError::Configuration(
+            "[hardening] is only supported on Linux".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use remotefs_common::error::{RemoteFsError, Result};
+    use std::ffi::CString;
+    use std::io;
+
+    /// Ids of an account
+    #[derive(Debug, Clone, Copy)]
+    pub struct Account {
+        pub uid: libc::uid_t,
+        pub gid: libc::gid_t,
+    }
+
+    /// Largest buffer offered to `getpwnam_r`
+    const MAX_PASSWD_BUFFER: usize = 1024 * 1024;
+
+    pub fn lookup_user(name: &str) -> Result<Account> {
+        let c_name = CString::new(name)
+            .map_err(|_| RemoteFsError::Configuration(format!("Invalid user name '{}'", name)))?;
+        let mut buffer = vec![0u8; 16 * 1024];
+        loop {
+            // SAFETY: all zeroes is a valid passwd, which getpwnam_r fills in
+            // with pointers into `buffer`, as long as `buffer.len()` says
+            let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+            let mut found: *mut libc::passwd = std::ptr::null_mut();
+            let result = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    &mut entry,
+                    buffer.as_mut_ptr() as *mut libc::c_char,
+                    buffer.len(),
+                    &mut found,
+                )
+            };
+            if result == libc::ERANGE && buffer.len() < MAX_PASSWD_BUFFER {
+                buffer.resize(buffer.len() * 2, 0);
+                continue;
+            }
+            if result != 0 {
+                return Err(RemoteFsError::Configuration(format!(
+                    "Failed to look up user '{}': {}", name, io::Error::from_raw_os_error(result)
+                )));
+            }
+            if found.is_null() {
+                return Err(RemoteFsError::Configuration(format!("No such user '{}'", name)));
+            }
+            return Ok(Account { uid: entry.pw_uid, gid: entry.pw_gid });
+        }
+    }
+
+    /// Switch every thread to `account`, with its primary group only
+    ///
+    /// glibc applies each id change to all threads of the process, so the
+    /// runtime's workers are switched too.
+    pub fn switch_user(account: Account) -> io::Result<()> {
+        // SAFETY: plain system calls on this process's credentials
+        unsafe {
+            if libc::geteuid() == account.uid && libc::getegid() == account.gid {
+                return Ok(());
+            }
+            if libc::setgroups(1, &account.gid) != 0
+                || libc::setresgid(account.gid, account.gid, account.gid) != 0
+                || libc::setresuid(account.uid, account.uid, account.uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            // Root must be out of reach for good
+            if account.uid != 0 && libc::setuid(0) == 0 {
+                return Err(io::Error::other("root privileges could be regained"));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Offsets of the fields of `seccomp_data` a filter reads
+    const SYSCALL_NR_OFFSET: u32 = 0;
+    const SYSCALL_ARCH_OFFSET: u32 = 4;
+
+    /// Numbers at and above this are x32 system calls on x86_64
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// System calls the relay makes while it serves: those of tokio, hyper
+    /// and rustls on glibc, and of writing a crash report
+    pub fn allowed_syscalls() -> Vec<libc::c_long> {
+        let mut allowed = vec![
+            // Files and descriptors
+            libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev,
+            libc::SYS_pread64, libc::SYS_pwrite64, libc::SYS_close, libc::SYS_lseek,
+            libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx, libc::SYS_statfs, libc::SYS_fstatfs,
+            libc::SYS_openat, libc::SYS_mkdirat, libc::SYS_unlinkat, libc::SYS_renameat2,
+            libc::SYS_readlinkat, libc::SYS_getdents64, libc::SYS_faccessat, libc::SYS_faccessat2,
+            libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_dup, libc::SYS_dup3, libc::SYS_pipe2,
+            libc::SYS_ftruncate, libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_getcwd,
+            // Memory
+            libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_mremap,
+            libc::SYS_madvise, libc::SYS_brk,
+            // Signals, including those of a panic that aborts
+            libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn,
+            libc::SYS_sigaltstack, libc::SYS_tgkill,
+            // Sockets
+            libc::SYS_socket, libc::SYS_socketpair, libc::SYS_bind, libc::SYS_listen,
+            libc::SYS_accept, libc::SYS_accept4, libc::SYS_connect, libc::SYS_getsockname,
+            libc::SYS_getpeername, libc::SYS_setsockopt, libc::SYS_getsockopt,
+            libc::SYS_sendto, libc::SYS_recvfrom, libc::SYS_sendmsg, libc::SYS_recvmsg,
+            libc::SYS_sendmmsg, libc::SYS_recvmmsg, libc::SYS_shutdown,
+            // Event loop and timers
+            libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_epoll_pwait2,
+            libc::SYS_eventfd2, libc::SYS_ppoll, libc::SYS_pselect6,
+            libc::SYS_timerfd_create, libc::SYS_timerfd_settime,
+            libc::SYS_clock_gettime, libc::SYS_clock_getres, libc::SYS_clock_nanosleep,
+            libc::SYS_nanosleep, libc::SYS_gettimeofday,
+            // Threads and the process
+            libc::SYS_clone, libc::SYS_clone3, libc::SYS_exit, libc::SYS_exit_group,
+            libc::SYS_set_robust_list, libc::SYS_rseq, libc::SYS_set_tid_address,
+            libc::SYS_futex, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+            libc::SYS_getpid, libc::SYS_gettid, libc::SYS_getppid,
+            libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+            libc::SYS_getresuid, libc::SYS_getresgid, libc::SYS_uname, libc::SYS_sysinfo,
+            libc::SYS_prctl, libc::SYS_prlimit64, libc::SYS_getrandom, libc::SYS_restart_syscall,
+        ];
+        // Older calls glibc still makes on x86_64
+        #[cfg(target_arch = "x86_64")]
+        allowed.extend([
+            libc::SYS_open, libc::SYS_stat, libc::SYS_lstat, libc::SYS_access,
+            libc::SYS_readlink, libc::SYS_mkdir, libc::SYS_unlink, libc::SYS_rename, libc::SYS_renameat,
+            libc::SYS_getdents, libc::SYS_pipe, libc::SYS_dup2, libc::SYS_poll, libc::SYS_select,
+            libc::SYS_epoll_create, libc::SYS_epoll_wait, libc::SYS_arch_prctl, libc::SYS_getrlimit,
+        ]);
+        allowed
+    }
+
+    /// Classic BPF filter allowing `allowed` and refusing every other system
+    /// call with `EPERM`; system calls of another architecture kill the
+    /// process, as their numbers mean something else
+    pub fn filter(allowed: &[libc::c_long]) -> Vec<libc::sock_filter> {
+        let load = |offset| statement(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+        let mut program = vec![
+            load(SYSCALL_ARCH_OFFSET),
+            jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            load(SYSCALL_NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1),
+            statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ]);
+        for &nr in allowed {
+            program.push(jump(libc::BPF_JEQ, nr as u32, 0, 1));
+            program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+        }
+        program.push(statement(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        program
+    }
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+
+    fn jump(condition: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code: (libc::BPF_JMP | condition | libc::BPF_K) as u16, jt, jf, k }
+    }
+
+    /// Install `program` on every thread of the process
+    pub fn install_filter(program: &[libc::sock_filter]) -> io::Result<()> {
+        let fprog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: `fprog` points at `program`, which outlives the call; the
+        // kernel copies the filter. Without no_new_privs an unprivileged
+        // process may not install one, and TSYNC sets it on all threads.
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let result = libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &fprog as *const libc::sock_fprog,
+            );
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // A positive result is a thread that could not be synchronized
+            if result > 0 {
+                return Err(io::Error::other(format!("thread {} could not be filtered", result)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+use linux::{allowed_syscalls, filter, install_filter, lookup_user, switch_user};
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::process::Command;
+
+    /// Set in the copy of the test binary that installs the filter
+    const FILTERED_CHILD: &str = "REMOTEFS_HARDENING_FILTERED_CHILD";
+
+    #[test]
+    fn test_filter() {
+        let allowed = allowed_syscalls();
+        let program = filter(&allowed);
+        let checks = if cfg!(target_arch = "x86_64") { 6 } else { 4 };
+        assert_eq!(program.len(), checks + 2 * allowed.len() + 1);
+
+        // Nothing that leaves the process's confinement is allowed
+        for denied in [libc::SYS_execve, libc::SYS_ptrace, libc::SYS_mount, libc::SYS_chroot, libc::SYS_setuid, libc::SYS_capset] {
+            assert!(!allowed.contains(&denied));
+        }
+    }
+
+    #[test]
+    fn test_lookup_user() {
+        assert_eq!(lookup_user("root").unwrap().uid, 0);
+        assert!(lookup_user("no-such-user-remotefs").is_err());
+    }
+
+    /// The filter is installed in a copy of this test binary, as it cannot
+    /// be removed again
+    #[test]
+    fn test_filtered_runtime() {
+        if std::env::var_os(FILTERED_CHILD).is_some() {
+            return filtered_child();
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "hardening::tests::test_filtered_runtime", "--nocapture", "--test-threads=1"])
+            .env(FILTERED_CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("filtered runtime served"), "{}", stdout);
+    }
+
+    fn filtered_child() {
+        let config = HardeningConfig { seccomp: true, ..Default::default() };
+        apply(&config, Path::new("/")).unwrap();
+
+        // A multi-threaded runtime still starts and serves TCP
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        runtime.block_on(async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4];
+                stream.read_exact(&mut buffer).await.unwrap();
+                stream.write_all(&buffer).await.unwrap();
+            });
+            let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            let mut buffer = [0u8; 4];
+            client.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"ping");
+            server.await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            tokio::task::spawn_blocking(|| std::fs::metadata("/").unwrap()).await.unwrap();
+        });
+        drop(runtime);
+
+        // Calls outside the allowlist are refused
+        // SAFETY: umask only changes this process's file mode mask
+        let result = unsafe { libc::syscall(libc::SYS_umask, 0o022) };
+        assert_eq!(result, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+        let program = std::ffi::CString::new("/bin/true").unwrap();
+        let argv = [program.as_ptr(), std::ptr::null()];
+        // SAFETY: `argv` is a null terminated array of strings; had execv
+        // succeeded, the marker below would be missing
+        unsafe { libc::execv(program.as_ptr(), argv.as_ptr()) };
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+
+        println!("filtered runtime served");
+    }
+}
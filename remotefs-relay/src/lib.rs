@@ -0,0 +1,21 @@
+//! RemoteFS Relay Library
+//!
+//! This crate provides the relay server for the RemoteFS system, routing
+//! messages between authenticated clients and agents.
+
+pub mod admission;
+pub mod auth;
+pub mod buffers;
+pub mod cli;
+pub mod failover;
+pub mod guest;
+pub mod hardening;
+pub mod listener;
+pub mod maintenance;
+pub mod routing;
+pub mod server;
+pub mod session;
+
+// Re-export commonly used types
+pub use auth::AuthManager;
+pub use server::RelayServer;
@@ -0,0 +1,304 @@
+//! TLS termination and hostname-based routing for the relay's single port
+//!
+//! The WebSocket endpoint, the directory and the admin endpoints share one
+//! port. With TLS enabled the relay terminates it itself, so self-hosters
+//! need neither a proxy nor a second open port. Virtual hosts narrow down
+//! which endpoints a hostname reaches, using the TLS SNI name when there is
+//! one and the `Host` header otherwise.
+//!
+//! Every connection is counted against its IP address from the moment it is
+//! accepted, and must finish its TLS handshake and send its request headers
+//! within the handshake timeout.
+
+use crate::admission::ConnectionAdmission;
+use axum::{
+    extract::{Request, State},
+    http::{header::HOST, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use remotefs_common::{
+    config::{RelayService, SecurityConfig, VirtualHost},
+    error::{RemoteFsError, Result},
+};
+use std::{fs::File, io::BufReader, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+/// SNI name the client asked for, attached to each request on a TLS connection
+#[derive(Debug, Clone)]
+pub struct ServerName(pub Option<String>);
+
+/// Acceptor for the relay's certificate and key from `security`
+pub fn tls_acceptor(security: &SecurityConfig) -> Result<TlsAcceptor> {
+    let certs = load_certs(&security.cert_file)?;
+    let key = load_key(&security.key_file)?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| RemoteFsError::Configuration(format!("Invalid TLS certificate or key: {}", e)))?;
+    // WebSocket upgrades need HTTP/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).map_err(|e| RemoteFsError::Configuration(
+        format!("Failed to open TLS certificate {}: {}", path.display(), e)
+    ))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| RemoteFsError::Configuration(format!("Invalid TLS certificate {}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(RemoteFsError::Configuration(format!("No certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).map_err(|e| RemoteFsError::Configuration(
+        format!("Failed to open TLS key {}: {}", path.display(), e)
+    ))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| RemoteFsError::Configuration(format!("Invalid TLS key {}: {}", path.display(), e)))?
+        .ok_or_else(|| RemoteFsError::Configuration(format!("No private key in {}", path.display())))
+}
+
+/// Serve `app` on `listener` until the listener fails, over TLS when there
+/// is an acceptor
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    app: Router,
+    admission: Arc<ConnectionAdmission>,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually out of file descriptors; back off instead of spinning
+                warn!("Failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let Some(permit) = admission.accept(peer.ip()) else {
+            debug!("Refusing connection from {}: too many from its address", peer);
+            continue;
+        };
+
+        let tls = tls.clone();
+        let app = app.clone();
+        let admission = Arc::clone(&admission);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let timeout = admission.handshake_timeout();
+            let Some(acceptor) = tls else {
+                return serve_connection(stream, ServerName(None), app, peer, &admission).await;
+            };
+
+            let stream = match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    admission.record_handshake_timeout();
+                    return;
+                }
+            };
+
+            let server_name = ServerName(stream.get_ref().1.server_name().map(str::to_string));
+            serve_connection(stream, server_name, app, peer, &admission).await;
+        });
+    }
+}
+
+/// Serve the requests on one connection, closing it if request headers do
+/// not arrive within the handshake timeout
+async fn serve_connection<S>(stream: S, server_name: ServerName, app: Router, peer: SocketAddr, admission: &ConnectionAdmission)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = hyper::service::service_fn(move |mut request: Request<hyper::body::Incoming>| {
+        request.extensions_mut().insert(server_name.clone());
+        app.clone().oneshot(request)
+    });
+
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(admission.handshake_timeout());
+    if let Err(e) = builder.serve_connection_with_upgrades(TokioIo::new(stream), service).await {
+        if e.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout) {
+            debug!("Connection from {} sent no request in time", peer);
+            admission.record_handshake_timeout();
+        } else {
+            debug!("Connection from {} ended with error: {}", peer, e);
+        }
+    }
+}
+
+/// Limit the hostnames in `hosts` to their configured endpoints
+pub fn with_virtual_hosts(app: Router, hosts: &[VirtualHost]) -> Router {
+    if hosts.is_empty() {
+        return app;
+    }
+    let hosts: Arc<[VirtualHost]> = hosts.into();
+    app.layer(middleware::from_fn_with_state(hosts, check_virtual_host))
+}
+
+async fn check_virtual_host(State(hosts): State<Arc<[VirtualHost]>>, request: Request, next: Next) -> Response {
+    let host = request_host(&request);
+    let allowed = match hosts.iter().find(|vhost| host.is_some_and(|host| vhost.server_name.eq_ignore_ascii_case(host))) {
+        None => true,
+        Some(vhost) => service_for_path(request.uri().path()).is_some_and(|service| vhost.services.contains(&service)),
+    };
+
+    if allowed {
+        next.run(request).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Hostname a request is for: the SNI name on TLS connections that sent one,
+/// otherwise the `Host` header without its port
+fn request_host(request: &Request) -> Option<&str> {
+    if let Some(ServerName(Some(name))) = request.extensions().get::<ServerName>() {
+        return Some(name);
+    }
+    let host = request.headers().get(HOST)?.to_str().ok()?;
+    Some(match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    })
+}
+
+/// The endpoint group a path belongs to
+pub fn service_for_path(path: &str) -> Option<RelayService> {
+    match path {
+        "/ws" => Some(RelayService::WebSocket),
+        "/discovery" => Some(RelayService::Discovery),
+        "/health" | "/stats" | "/maintenance" => Some(RelayService::Admin),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{pki_types, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    fn app() -> Router {
+        let hosts = vec![
+            VirtualHost { server_name: "admin.localhost".to_string(), services: vec![RelayService::Admin] },
+            VirtualHost { server_name: "relay.localhost".to_string(), services: vec![RelayService::WebSocket, RelayService::Discovery] },
+        ];
+        let app = Router::new()
+            .route("/ws", get(|| async { "ws" }))
+            .route("/discovery", get(|| async { "discovery" }))
+            .route("/health", get(|| async { "OK" }));
+        with_virtual_hosts(app, &hosts)
+    }
+
+    async fn status(host: &str, path: &str) -> StatusCode {
+        let request = Request::builder().uri(path).header(HOST, host).body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_virtual_hosts() {
+        assert_eq!(status("admin.localhost", "/health").await, StatusCode::OK);
+        assert_eq!(status("ADMIN.localhost:8443", "/health").await, StatusCode::OK);
+        assert_eq!(status("admin.localhost", "/ws").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("relay.localhost", "/health").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("relay.localhost", "/discovery").await, StatusCode::OK);
+
+        // Other names reach everything
+        assert_eq!(status("127.0.0.1:8080", "/health").await, StatusCode::OK);
+        assert_eq!(status("other.localhost", "/ws").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_tls_routes_by_sni() {
+        let mut security = remotefs_common::config_utils::create_default_relay_config().security;
+        security.cert_file = fixture("relay.crt");
+        security.key_file = fixture("relay.key");
+        let acceptor = tls_acceptor(&security).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let admission = Arc::new(ConnectionAdmission::new(&Default::default()));
+        let server = tokio::spawn(serve(listener, Some(acceptor), app(), admission));
+
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(load_certs(&fixture("relay.crt")).unwrap());
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+
+        // The SNI name decides, whatever the Host header says
+        let get = |sni: &'static str, path: &'static str| {
+            let connector = connector.clone();
+            async move {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let name = pki_types::ServerName::try_from(sni).unwrap();
+                let mut stream = connector.connect(name, stream).await.unwrap();
+                let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path);
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+
+        assert!(get("admin.localhost", "/health").await.starts_with("HTTP/1.1 200"));
+        assert!(get("admin.localhost", "/ws").await.starts_with("HTTP/1.1 404"));
+        assert!(get("localhost", "/ws").await.starts_with("HTTP/1.1 200"));
+
+        server.abort();
+    }
+
+    #[test]
+    fn test_missing_certificate() {
+        let mut security = remotefs_common::config_utils::create_default_relay_config().security;
+        security.cert_file = fixture("missing.crt");
+        security.key_file = fixture("relay.key");
+        assert!(matches!(tls_acceptor(&security), Err(RemoteFsError::Configuration(_))));
+    }
+}
@@ -0,0 +1,144 @@
+//! Scheduled maintenance of the relay and its agents
+//!
+//! Operators set and clear windows through the admin API. Each client is
+//! told about the windows that concern it: the relay's own, and those of the
+//! agents it has sent requests to. Clients learn about a window as soon as it
+//! is set, so they can stop writing before it starts instead of running into
+//! errors once the work is underway. The relay keeps routing as usual; a
+//! window only informs clients.
+
+use crate::routing::MessageRouter;
+use crate::session::SessionManager;
+use chrono::Utc;
+use remotefs_common::protocol::{MaintenanceWindow, Message, NodeType};
+use std::collections::BTreeMap;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+/// Maintenance windows by agent, `None` being the relay itself
+pub struct MaintenanceSchedule {
+    windows: RwLock<BTreeMap<Option<String>, MaintenanceWindow>>,
+}
+
+impl MaintenanceSchedule {
+    pub fn new() -> Self {
+        Self {
+            windows: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Set the window of the relay or an agent, replacing any earlier one
+    pub async fn set(&self, window: MaintenanceWindow) {
+        info!(
+            "Maintenance of {} from {}{}: {}",
+            window.agent_id.as_deref().map_or_else(|| "the relay".to_string(), |agent_id| format!("agent {}", agent_id)),
+            window.starts_at,
+            window.ends_at.map(|ends_at| format!(" until {}", ends_at)).unwrap_or_default(),
+            window.message
+        );
+        self.windows.write().await.insert(window.agent_id.clone(), window);
+    }
+
+    /// Clear the window of the relay (`None`) or an agent, returning whether
+    /// there was one
+    pub async fn clear(&self, agent_id: Option<&str>) -> bool {
+        let cleared = self.windows.write().await.remove(&agent_id.map(str::to_string)).is_some();
+        if cleared {
+            info!("Maintenance of {} cleared", agent_id.map_or_else(|| "the relay".to_string(), |agent_id| format!("agent {}", agent_id)));
+        }
+        cleared
+    }
+
+    /// Windows that have not ended, the relay's first
+    pub async fn windows(&self) -> Vec<MaintenanceWindow> {
+        let now = Utc::now();
+        let mut windows = self.windows.write().await;
+        windows.retain(|_, window| !window.has_ended_at(now));
+        windows.values().cloned().collect()
+    }
+
+    /// Windows of the relay and of `agents`
+    pub async fn windows_for(&self, agents: &[String]) -> Vec<MaintenanceWindow> {
+        self.windows().await
+            .into_iter()
+            .filter(|window| window.agent_id.as_ref().is_none_or(|agent_id| agents.contains(agent_id)))
+            .collect()
+    }
+
+    /// Whether `agent_id` has a window that has not ended
+    pub async fn has_window(&self, agent_id: &str) -> bool {
+        let now = Utc::now();
+        self.windows.read().await
+            .get(&Some(agent_id.to_string()))
+            .is_some_and(|window| !window.has_ended_at(now))
+    }
+
+    /// Tell clients the windows that concern them: the one client `only`
+    /// names, or every client
+    pub async fn announce(&self, session_manager: &SessionManager, router: &MessageRouter, only: Option<&str>) {
+        let recipients = match only {
+            Some(node_id) => session_manager.get_session_by_node(node_id).await.into_iter().collect(),
+            None => session_manager.get_sessions_by_type(NodeType::Client).await,
+        };
+
+        for session in recipients {
+            let windows = self.windows_for(&router.agents_used_by(&session.node_id)).await;
+            let status = Message::MaintenanceStatus { request_id: None, windows };
+            let sent = match session.message_format.encode(&status) {
+                Ok(ws_message) => session.send_message(ws_message).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                warn!("Failed to send maintenance status to {}: {}", session.node_id, e);
+            }
+        }
+    }
+}
+
+impl Default for MaintenanceSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(agent_id: Option<&str>, hours: i64) -> MaintenanceWindow {
+        let now = Utc::now();
+        MaintenanceWindow {
+            agent_id: agent_id.map(str::to_string),
+            message: "Planned work".to_string(),
+            starts_at: now - chrono::Duration::hours(2),
+            ends_at: Some(now + chrono::Duration::hours(hours)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_windows_concern_the_relay_and_used_agents() {
+        let schedule = MaintenanceSchedule::new();
+        schedule.set(window(None, 1)).await;
+        schedule.set(window(Some("nas"), 1)).await;
+        schedule.set(window(Some("backup"), 1)).await;
+
+        let agents = |windows: Vec<MaintenanceWindow>| -> Vec<Option<String>> {
+            windows.into_iter().map(|window| window.agent_id).collect()
+        };
+        assert_eq!(agents(schedule.windows_for(&[]).await), vec![None]);
+        assert_eq!(agents(schedule.windows_for(&["nas".to_string()]).await), vec![None, Some("nas".to_string())]);
+        assert!(schedule.has_window("backup").await);
+
+        assert!(schedule.clear(None).await);
+        assert!(!schedule.clear(None).await);
+        assert_eq!(agents(schedule.windows_for(&["nas".to_string()]).await), vec![Some("nas".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_ended_windows_are_dropped() {
+        let schedule = MaintenanceSchedule::new();
+        schedule.set(window(Some("nas"), -1)).await;
+        assert!(!schedule.has_window("nas").await);
+        assert!(schedule.windows().await.is_empty());
+    }
+}
@@ -1,25 +1,50 @@
-use crate::session::Session;
+use crate::session::{MessageFormat, Session};
 use crate::server::AppState;
-use axum::extract::ws::Message as WsMessage;
+use crate::failover::is_write_request;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
 use remotefs_common::{
-    protocol::{Message, NodeType},
+    protocol::{AgentEvent, Capability, Message, NodeType, RequestId},
     error::{RemoteFsError, Result},
 };
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tracing::{debug, warn};
 
+/// Requests in flight listed in a crash report
+const MAX_SNAPSHOT_REQUESTS: usize = 100;
+
 /// Statistics for message routing
 #[derive(Debug, Clone)]
 pub struct RoutingStats {
     pub messages_routed: u64,
     pub failed_routes: u64,
+    pub requests_in_flight: usize,
 }
 
 /// Handles routing of messages between clients and agents
+///
+/// Each message is forwarded as soon as it arrives. Responses find their way
+/// back through the client that sent the request, and streamed responses keep
+/// that route until their last message, so a large listing is relayed page by
+/// page instead of being collected first.
 pub struct MessageRouter {
     messages_routed: Arc<AtomicU64>,
     failed_routes: Arc<AtomicU64>,
+    /// Node that sent each request still waiting for its last response
+    in_flight: DashMap<RequestId, String>,
+    /// Clients that have sent requests to each agent
+    clients_of: DashMap<String, HashSet<String>>,
+    /// Client and agent of each watch that has not ended
+    watches: DashMap<RequestId, (String, String)>,
+    /// Client and agent of each open file handle
+    handles: DashMap<RequestId, (String, String)>,
+    /// Client and agent of each file being streamed, or tree having its
+    /// metadata changed, that has not ended
+    streams: DashMap<RequestId, (String, String)>,
 }
 
 impl MessageRouter {
@@ -28,6 +53,11 @@ impl MessageRouter {
         Self {
             messages_routed: Arc::new(AtomicU64::new(0)),
             failed_routes: Arc::new(AtomicU64::new(0)),
+            in_flight: DashMap::new(),
+            clients_of: DashMap::new(),
+            watches: DashMap::new(),
+            handles: DashMap::new(),
+            streams: DashMap::new(),
         }
     }
     
@@ -49,12 +79,21 @@ impl MessageRouter {
             }
         );
         
+        // An agent's event goes to every client using it
+        if let Message::Broadcast { event, timestamp, .. } = message {
+            let delivered = self.broadcast(event, timestamp, sender_session, state).await?;
+            debug!("Broadcast from {} reached {} clients", sender_session.node_id, delivered);
+            return Ok(());
+        }
+        
+        // A client that named the agent it wants does not get another one
+        if let (NodeType::Client, Some(agent_id)) = (&sender_session.node_type, message.target_agent()) {
+            let agent_id = agent_id.to_string();
+            return self.route_to_agent(message, sender_session, &agent_id, state).await;
+        }
+        
         match self.determine_target(&message, sender_session, state).await {
-            Ok(target_node_id) => {
-                self.send_to_target(message, &target_node_id, state).await?;
-                self.messages_routed.fetch_add(1, Ordering::Relaxed);
-                Ok(())
-            }
+            Ok(target_node_id) => self.forward(message, &target_node_id, sender_session, state).await,
             Err(e) => {
                 warn!("Failed to route message: {}", e);
                 self.failed_routes.fetch_add(1, Ordering::Relaxed);
@@ -63,6 +102,123 @@ impl MessageRouter {
         }
     }
     
+    /// Route a client request to a chosen agent instead of any available one
+    pub async fn route_to_agent(
+        &self,
+        message: Message,
+        sender_session: &Session,
+        agent_id: &str,
+        state: &AppState,
+    ) -> Result<()> {
+        debug!(
+            "Routing message {} from {} to agent {}",
+            message.message_type(),
+            sender_session.node_id,
+            agent_id
+        );
+        
+        let connected = state.session_manager.get_active_nodes(NodeType::Agent).await
+            .iter()
+            .any(|node_id| node_id == agent_id);
+        if !connected {
+            self.failed_routes.fetch_add(1, Ordering::Relaxed);
+            return Err(RemoteFsError::ServiceUnavailable(format!("Agent {} is not connected", agent_id)));
+        }
+        
+        if let Some(capability) = message.required_capability() {
+            let supported = state.session_manager.nodes_supporting(vec![agent_id.to_string()], &capability).await;
+            if supported.is_empty() {
+                return Err(RemoteFsError::NotImplemented(
+                    format!("Agent {} does not support {} ({})", agent_id, message.message_type(), capability)
+                ));
+            }
+        }
+        
+        self.forward(message, agent_id, sender_session, state).await
+    }
+    
+    /// Send a message to its target, keeping track of the request it
+    /// belongs to until its last response
+    async fn forward(
+        &self,
+        mut message: Message,
+        target_node_id: &str,
+        sender_session: &Session,
+        state: &AppState,
+    ) -> Result<()> {
+        // Locks and open files are owned per client, whatever owner the
+        // client claims
+        if matches!(sender_session.node_type, NodeType::Client) {
+            if let Some(session) = client_session(&mut message) {
+                *session = sender_session.node_id.clone();
+            }
+        }
+        
+        let tracked = self.track_request(&message, sender_session);
+        let ends_request = message.ends_request();
+        let request_id = message.request_id();
+        let watch = is_watch(&message);
+        let open = is_open(&message);
+        let opened = matches!(message, Message::OpenFileResponse { success: true, .. });
+        let closed = closed_handle(&message).filter(|_| tracked.is_some());
+        let stream = is_steered_stream(&message);
+        
+        // Agents with rules per client are told which client a request is from
+        if tracked.is_some() {
+            let supported = state.session_manager
+                .nodes_supporting(vec![target_node_id.to_string()], &Capability::ClientRules).await;
+            if !supported.is_empty() {
+                message = Message::FromClient {
+                    client_id: sender_session.node_id.clone(),
+                    request: Box::new(message),
+                };
+            }
+        }
+        
+        if let Err(e) = self.send_to_target(message, target_node_id, state).await {
+            if let Some(request_id) = tracked {
+                self.in_flight.remove(&request_id);
+            }
+            return Err(e);
+        }
+        
+        if let (Some(request_id), true) = (tracked, watch) {
+            self.watches.insert(request_id, (sender_session.node_id.clone(), target_node_id.to_string()));
+        }
+        if let (Some(request_id), true) = (tracked, open) {
+            self.handles.insert(request_id, (sender_session.node_id.clone(), target_node_id.to_string()));
+        }
+        if let Some(handle) = closed {
+            self.handles.remove(&handle);
+        }
+        if let (Some(request_id), true) = (tracked, stream) {
+            self.streams.insert(request_id, (sender_session.node_id.clone(), target_node_id.to_string()));
+        }
+        if tracked.is_some() {
+            let first_use = self.clients_of.entry(target_node_id.to_string())
+                .or_default()
+                .insert(sender_session.node_id.clone());
+            // A client new to an agent under maintenance is told right away
+            if first_use && state.maintenance.has_window(target_node_id).await {
+                state.maintenance.announce(&state.session_manager, self, Some(&sender_session.node_id)).await;
+            }
+        }
+        
+        if ends_request {
+            if let Some(request_id) = request_id {
+                self.in_flight.remove(&request_id);
+                self.watches.remove(&request_id);
+                // A handle whose open failed is not kept
+                if !opened {
+                    self.handles.remove(&request_id);
+                }
+                self.streams.remove(&request_id);
+            }
+        }
+        self.messages_routed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+    
     /// Route a message to a specific node
     pub async fn route_to_node(&self, message: Message, target_node_id: &str) -> Result<()> {
         debug!(
@@ -77,6 +233,145 @@ impl MessageRouter {
         Ok(())
     }
     
+    /// Remember who sent a client request so its responses can be routed
+    /// back, returning the request id if it was recorded
+    fn track_request(&self, message: &Message, sender_session: &Session) -> Option<RequestId> {
+        if !matches!(sender_session.node_type, NodeType::Client) || message.is_response() {
+            return None;
+        }
+        let request_id = message.request_id()?;
+        self.in_flight.insert(request_id, sender_session.node_id.clone());
+        Some(request_id)
+    }
+    
+    /// Forget the requests of a node that disconnected
+    pub fn forget_node(&self, node_id: &str) {
+        self.in_flight.retain(|_, requester| requester != node_id);
+        self.streams.retain(|_, (client, agent)| client != node_id && agent != node_id);
+        self.clients_of.remove(node_id);
+        for mut clients in self.clients_of.iter_mut() {
+            clients.remove(node_id);
+        }
+    }
+    
+    /// Forget a node that disconnected, ending its watches and releasing
+    /// its locks and open files first
+    pub async fn end_node(&self, node_id: &str, state: &AppState) {
+        self.end_watches(node_id, state).await;
+        self.release_locks(node_id, state).await;
+        self.handles.retain(|_, (client, agent)| client != node_id && agent != node_id);
+        self.forget_node(node_id);
+    }
+    
+    /// End the watches of a node that disconnected
+    ///
+    /// The agents of a client's watches are told to stop them and the
+    /// clients of an agent's watches that they ended.
+    pub async fn end_watches(&self, node_id: &str, state: &AppState) {
+        let mut ended = Vec::new();
+        self.watches.retain(|request_id, (client, agent)| {
+            if client == node_id {
+                ended.push((Message::Unwatch { request_id: *request_id }, agent.clone()));
+                false
+            } else if agent == node_id {
+                let error = Some(format!("Agent {} disconnected", agent));
+                ended.push((Message::WatchEnded { request_id: *request_id, error }, client.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        
+        for (message, target_node_id) in ended {
+            if let Err(e) = self.send_to_target(message, &target_node_id, state).await {
+                debug!("Failed to end watch of {} at {}: {}", node_id, target_node_id, e);
+            }
+        }
+    }
+    
+    /// Tell the agents a client that disconnected has used to release its
+    /// locks and close its files
+    pub async fn release_locks(&self, client_id: &str, state: &AppState) {
+        let used = self.agents_used_by(client_id);
+        let mut agents = state.session_manager.nodes_supporting(used.clone(), &Capability::Locks).await;
+        for agent_id in state.session_manager.nodes_supporting(used, &Capability::OpenFiles).await {
+            if !agents.contains(&agent_id) {
+                agents.push(agent_id);
+            }
+        }
+        for agent_id in agents {
+            let message = Message::ReleaseLocks { session: client_id.to_string() };
+            if let Err(e) = self.send_to_target(message, &agent_id, state).await {
+                debug!("Failed to release locks of {} at {}: {}", client_id, agent_id, e);
+            }
+        }
+    }
+    
+    /// Agents `client_id` has sent requests to
+    pub fn agents_used_by(&self, client_id: &str) -> Vec<String> {
+        self.clients_of.iter()
+            .filter(|clients| clients.contains(client_id))
+            .map(|clients| clients.key().clone())
+            .collect()
+    }
+    
+    /// Deliver an agent's event to every client that has sent it requests,
+    /// returning how many clients it reached
+    ///
+    /// The agent sends the event once. It is encoded at most once per
+    /// message format, whatever the number of clients.
+    pub async fn broadcast(
+        &self,
+        event: AgentEvent,
+        timestamp: DateTime<Utc>,
+        sender_session: &Session,
+        state: &AppState,
+    ) -> Result<usize> {
+        if !matches!(sender_session.node_type, NodeType::Agent) {
+            return Err(RemoteFsError::Protocol("Only agents broadcast events".to_string()));
+        }
+        
+        // The relay names the agent, so an agent cannot speak for another
+        let message = Message::Broadcast {
+            agent_id: sender_session.node_id.clone(),
+            event,
+            timestamp,
+        };
+        let clients: Vec<String> = self.clients_of.get(&sender_session.node_id)
+            .map(|clients| clients.iter().cloned().collect())
+            .unwrap_or_default();
+        
+        let mut json = None;
+        let mut binary = None;
+        let mut delivered = 0;
+        for client_id in clients {
+            let Some(session) = state.session_manager.get_session_by_node(&client_id).await else {
+                continue;
+            };
+            let encoded = match session.message_format {
+                MessageFormat::Json => &mut json,
+                MessageFormat::Binary => &mut binary,
+            };
+            let ws_message = match encoded {
+                Some(ws_message) => ws_message,
+                None => encoded.insert(session.message_format.encode(&message)?),
+            };
+            match session.send_message(ws_message.clone()).await {
+                Ok(()) => delivered += 1,
+                Err(e) => warn!("Failed to deliver broadcast from {} to {}: {}", sender_session.node_id, client_id, e),
+            }
+        }
+        
+        self.messages_routed.fetch_add(delivered as u64, Ordering::Relaxed);
+        Ok(delivered)
+    }
+    
+    /// Node waiting for responses to a request, if it is still in flight
+    fn requester(&self, message: &Message) -> Option<String> {
+        let request_id = message.request_id()?;
+        self.in_flight.get(&request_id).map(|requester| requester.clone())
+    }
+    
     /// Determine the target node for a message
     async fn determine_target(
         &self,
@@ -85,26 +380,70 @@ impl MessageRouter {
         state: &AppState,
     ) -> Result<String> {
         match message {
+            // Only what a client could send itself is made on a user's behalf
+            Message::AsUser { request, .. } if !request.is_client_request() => {
+                Err(RemoteFsError::Protocol(
+                    format!("Message {} cannot be sent on behalf of a user", request.message_type())
+                ))
+            }
+            
             // File system operations need to be routed to agents
             Message::ReadFile { .. }
             | Message::WriteFile { .. }
+            | Message::ReadFileStream { .. }
+            | Message::WriteFileChunk { .. }
             | Message::CreateFile { .. }
             | Message::DeleteFile { .. }
             | Message::TruncateFile { .. }
+            | Message::ComputeChecksum { .. }
+            | Message::GetFileSignature { .. }
+            | Message::WriteDelta { .. }
+            | Message::LockFile { .. }
+            | Message::UnlockFile { .. }
+            | Message::TestLock { .. }
+            | Message::OpenFile { .. }
+            | Message::ReadHandle { .. }
+            | Message::WriteHandle { .. }
+            | Message::CloseFile { .. }
             | Message::ListDirectory { .. }
+            | Message::ListDirectoryPaged { .. }
+            | Message::WalkDirectory { .. }
+            | Message::SearchFiles { .. }
             | Message::CreateDirectory { .. }
             | Message::RemoveDirectory { .. }
             | Message::GetMetadata { .. }
             | Message::SetMetadata { .. }
+            | Message::SetMetadataTree { .. }
+            | Message::GetXattr { .. }
+            | Message::SetXattr { .. }
+            | Message::ListXattr { .. }
+            | Message::RemoveXattr { .. }
             | Message::Rename { .. }
             | Message::CreateSymlink { .. }
+            | Message::CreateHardLink { .. }
+            | Message::ReadSymlink { .. }
+            | Message::CopyFile { .. }
             | Message::PathExists { .. }
-            | Message::GetSpaceInfo { .. } => {
+            | Message::GetSpaceInfo { .. }
+            | Message::ListExports { .. }
+            | Message::GetChanges { .. }
+            | Message::ReadFileAsOf { .. }
+            | Message::ReadBackupEntry { .. }
+            | Message::Transaction { .. }
+            | Message::BatchCreateFiles { .. }
+            | Message::RestoreFromTrash { .. }
+            | Message::PurgeTrash { .. }
+            | Message::Batch { .. }
+            | Message::ExtendedOperation { .. }
+            | Message::Watch { .. }
+            | Message::AsUser { .. } => {
                 match sender_session.node_type {
-                    NodeType::Client => {
+                    NodeType::Client => match used_handle(message) {
+                        // A handle is used at the agent that opened it
+                        Some(handle) => self.handle_agent(handle, sender_session),
                         // Client sending to agent - find available agent
-                        self.find_available_agent(state).await
-                    }
+                        None => self.find_available_agent(message, state).await,
+                    },
                     NodeType::Agent => {
                         // Agent responding to client - need to track request context
                         self.find_target_client_for_response(message, state).await
@@ -121,15 +460,43 @@ impl MessageRouter {
             | Message::CreateFileResponse { .. }
             | Message::DeleteFileResponse { .. }
             | Message::TruncateFileResponse { .. }
+            | Message::ChecksumResponse { .. }
+            | Message::FileSignatureResponse { .. }
+            | Message::LockFileResponse { .. }
+            | Message::UnlockFileResponse { .. }
+            | Message::TestLockResponse { .. }
+            | Message::OpenFileResponse { .. }
+            | Message::CloseFileResponse { .. }
             | Message::ListDirectoryResponse { .. }
+            | Message::DirectoryPage { .. }
+            | Message::SearchResults { .. }
+            | Message::ReadFileChunk { .. }
             | Message::CreateDirectoryResponse { .. }
             | Message::RemoveDirectoryResponse { .. }
             | Message::GetMetadataResponse { .. }
             | Message::SetMetadataResponse { .. }
+            | Message::SetMetadataTreeProgress { .. }
+            | Message::GetXattrResponse { .. }
+            | Message::SetXattrResponse { .. }
+            | Message::ListXattrResponse { .. }
+            | Message::RemoveXattrResponse { .. }
             | Message::RenameResponse { .. }
             | Message::CreateSymlinkResponse { .. }
+            | Message::CreateHardLinkResponse { .. }
+            | Message::ReadSymlinkResponse { .. }
+            | Message::CopyFileResponse { .. }
             | Message::PathExistsResponse { .. }
-            | Message::GetSpaceInfoResponse { .. } => {
+            | Message::GetSpaceInfoResponse { .. }
+            | Message::ListExportsResponse { .. }
+            | Message::GetChangesResponse { .. }
+            | Message::ReadBackupEntryResponse { .. }
+            | Message::TransactionResponse { .. }
+            | Message::BatchCreateFilesResponse { .. }
+            | Message::RestoreFromTrashResponse { .. }
+            | Message::PurgeTrashResponse { .. }
+            | Message::BatchResponse { .. }
+            | Message::ExtendedOutput { .. }
+            | Message::WatchResponse { .. } => {
                 match sender_session.node_type {
                     NodeType::Agent => {
                         // Agent responding to client
@@ -144,6 +511,41 @@ impl MessageRouter {
                 }
             }
             
+            // A watch nobody is waiting for any more is not sent to another client
+            Message::FileChanged { request_id, .. }
+            | Message::DirectoryChanged { request_id, .. }
+            | Message::WatchEnded { request_id, .. } => {
+                match sender_session.node_type {
+                    NodeType::Agent => self.requester(message)
+                        .ok_or_else(|| RemoteFsError::NotFound(format!("No client is watching {}", request_id))),
+                    _ => Err(RemoteFsError::Protocol("Only agents send change notifications".to_string())),
+                }
+            }
+            
+            // A watch is stopped by the agent that runs it
+            Message::Unwatch { request_id } => {
+                self.watches.get(request_id)
+                    .filter(|watch| watch.0 == sender_session.node_id)
+                    .map(|watch| watch.1.clone())
+                    .ok_or_else(|| RemoteFsError::NotFound(format!("No watch {}", request_id)))
+            }
+            
+            // A streamed file is paced by the agent sending it
+            Message::ReadFileAck { stream_id, .. } => {
+                self.streams.get(stream_id)
+                    .filter(|stream| stream.0 == sender_session.node_id)
+                    .map(|stream| stream.1.clone())
+                    .ok_or_else(|| RemoteFsError::NotFound(format!("No file stream {}", stream_id)))
+            }
+            
+            // A metadata change is stopped by the agent making it
+            Message::CancelSetMetadataTree { request_id } => {
+                self.streams.get(request_id)
+                    .filter(|stream| stream.0 == sender_session.node_id)
+                    .map(|stream| stream.1.clone())
+                    .ok_or_else(|| RemoteFsError::NotFound(format!("No metadata change {}", request_id)))
+            }
+            
             // Channel establishment can be bidirectional
             Message::EstablishChannel { target_node, .. } => {
                 Ok(target_node.clone())
@@ -162,9 +564,23 @@ impl MessageRouter {
             // These shouldn't be routed through this function
             Message::AuthRequest { .. }
             | Message::AuthResponse { .. }
+            | Message::ResumeSession { .. }
             | Message::Ping { .. }
             | Message::Pong { .. }
-            | Message::ConnectionClose { .. } => {
+            | Message::ConnectionClose { .. }
+            | Message::Compressed { .. }
+            | Message::MirrorStatus { .. }
+            | Message::AgentHealth { .. }
+            | Message::Audit { .. }
+            | Message::Broadcast { .. }
+            | Message::GetRelayDirectory
+            | Message::RelayDirectoryResponse { .. }
+            | Message::ListAgents { .. }
+            | Message::ListAgentsResponse { .. }
+            | Message::GetMaintenance { .. }
+            | Message::MaintenanceStatus { .. }
+            | Message::ReleaseLocks { .. }
+            | Message::FromClient { .. } => {
                 Err(RemoteFsError::Protocol(
                     format!("Message {} should not be routed", message.message_type())
                 ))
@@ -172,14 +588,50 @@ impl MessageRouter {
         }
     }
     
+    /// Agent holding an open handle of the client
+    fn handle_agent(&self, handle: &RequestId, sender_session: &Session) -> Result<String> {
+        self.handles.get(handle)
+            .filter(|open| open.0 == sender_session.node_id)
+            .map(|open| open.1.clone())
+            .ok_or_else(|| RemoteFsError::NotFound(format!("No open file handle {}", handle)))
+    }
+    
     /// Find an available agent to handle client requests
-    async fn find_available_agent(&self, state: &AppState) -> Result<String> {
+    ///
+    /// Mirror agents are only chosen once promoted.
+    async fn find_available_agent(&self, message: &Message, state: &AppState) -> Result<String> {
         let agents = state.session_manager.get_active_nodes(NodeType::Agent).await;
+        let agents = state.mirrors.routable_agents(agents, is_write_request(message)).await;
         
         if agents.is_empty() {
             return Err(RemoteFsError::ServiceUnavailable("No agents available".to_string()));
         }
         
+        // Older agents do not announce newer features; the client falls
+        // back to a legacy request where one exists
+        let agents = match message.required_capability() {
+            Some(capability) => {
+                let capable = state.session_manager.nodes_supporting(agents, &capability).await;
+                if capable.is_empty() {
+                    return Err(RemoteFsError::NotImplemented(format!(
+                        "No available agent supports {} ({})", message.message_type(), capability
+                    )));
+                }
+                capable
+            }
+            None => agents,
+        };
+        
+        // Every lock on a file must be kept by the same agent to conflict
+        if let Some(path) = locked_path(message) {
+            let mut agents = agents;
+            agents.sort();
+            let mut hasher = DefaultHasher::new();
+            path.hash(&mut hasher);
+            let index = (hasher.finish() % agents.len() as u64) as usize;
+            return Ok(agents[index].clone());
+        }
+
         // Simple round-robin selection - in a real system this could be more sophisticated
         // based on load, capability, or geographic proximity
         let index = (self.messages_routed.load(Ordering::Relaxed) as usize) % agents.len();
@@ -192,9 +644,11 @@ impl MessageRouter {
         message: &Message,
         state: &AppState,
     ) -> Result<String> {
-        // In a real implementation, this would maintain a mapping of request IDs
-        // to the originating client sessions. For now, we'll use a simplified approach.
+        if let Some(requester) = self.requester(message) {
+            return Ok(requester);
+        }
         
+        // Requests sent before the relay restarted are not tracked
         if let Some(_request_id) = message.request_id() {
             // Look up the client session that initiated this request
             // This would require maintaining a request tracking table
@@ -236,6 +690,12 @@ impl MessageRouter {
         state: &AppState,
     ) -> Result<String> {
         if let Message::Error { request_id, .. } = message {
+            if matches!(sender_session.node_type, NodeType::Agent) {
+                if let Some(requester) = self.requester(message) {
+                    return Ok(requester);
+                }
+            }
+            
             if let Some(_request_id) = request_id {
                 // Find the session that originated the request
                 // This would require request tracking
@@ -279,19 +739,9 @@ impl MessageRouter {
             .await
             .ok_or_else(|| RemoteFsError::NotFound(format!("Target node not found: {}", target_node_id)))?;
         
-        // Serialize message based on the target session's preferred format
-        let ws_message = match target_session.message_format {
-            crate::session::MessageFormat::Json => {
-                let json = serde_json::to_string(&message)
-                    .map_err(|e| RemoteFsError::Protocol(format!("JSON serialization error: {}", e)))?;
-                WsMessage::Text(json)
-            }
-            crate::session::MessageFormat::Binary => {
-                let binary = bincode::serialize(&message)
-                    .map_err(|e| RemoteFsError::Protocol(format!("Binary serialization error: {}", e)))?;
-                WsMessage::Binary(binary)
-            }
-        };
+        // Serialize message based on the target session's preferred format,
+        // or compressed if the target reads compressed messages
+        let ws_message = target_session.encode(&message).await?;
         
         // Send the message
         target_session.send_message(ws_message).await?;
@@ -305,9 +755,27 @@ impl MessageRouter {
         RoutingStats {
             messages_routed: self.messages_routed.load(Ordering::Relaxed),
             failed_routes: self.failed_routes.load(Ordering::Relaxed),
+            requests_in_flight: self.in_flight.len(),
         }
     }
     
+    /// Counters and requests in flight for crash reports
+    ///
+    /// The map's shard locks are only held for single inserts and removals,
+    /// which cannot panic, so a panicking thread never holds one here.
+    pub fn snapshot(&self) -> String {
+        let mut lines = vec![format!(
+            "{} messages routed, {} failed, {} requests in flight",
+            self.messages_routed.load(Ordering::Relaxed),
+            self.failed_routes.load(Ordering::Relaxed),
+            self.in_flight.len()
+        )];
+        lines.extend(self.in_flight.iter()
+            .take(MAX_SNAPSHOT_REQUESTS)
+            .map(|request| format!("{} from {}", request.key(), request.value())));
+        lines.join("\n")
+    }
+    
     /// Reset statistics (useful for testing)
     pub fn reset_stats(&self) {
         self.messages_routed.store(0, Ordering::Relaxed);
@@ -321,6 +789,79 @@ impl Default for MessageRouter {
     }
 }
 
+/// Check if a client request starts a watch
+fn is_watch(message: &Message) -> bool {
+    match message {
+        Message::Watch { .. } => true,
+        Message::AsUser { request, .. } => is_watch(request),
+        _ => false,
+    }
+}
+
+/// Check if a client request opens a file handle
+fn is_open(message: &Message) -> bool {
+    match message {
+        Message::OpenFile { .. } => true,
+        Message::AsUser { request, .. } => is_open(request),
+        _ => false,
+    }
+}
+
+/// Handle a client request reads, writes or closes
+fn used_handle(message: &Message) -> Option<&RequestId> {
+    match message {
+        Message::ReadHandle { handle, .. }
+        | Message::WriteHandle { handle, .. }
+        | Message::CloseFile { handle, .. } => Some(handle),
+        Message::AsUser { request, .. } => used_handle(request),
+        _ => None,
+    }
+}
+
+/// Handle a client request closes
+fn closed_handle(message: &Message) -> Option<RequestId> {
+    match message {
+        Message::CloseFile { handle, .. } => Some(*handle),
+        Message::AsUser { request, .. } => closed_handle(request),
+        _ => None,
+    }
+}
+
+/// Path of a client lock request
+fn locked_path(message: &Message) -> Option<&str> {
+    match message {
+        Message::LockFile { path, .. }
+        | Message::UnlockFile { path, .. }
+        | Message::TestLock { path, .. } => Some(path),
+        Message::AsUser { request, .. } => locked_path(request),
+        _ => None,
+    }
+}
+
+/// Client session named by a client lock or open request
+///
+/// `ReleaseLocks` names one too but is not stamped: only the relay sends
+/// it, and a client's is refused, wrapped or not, before it gets here.
+fn client_session(message: &mut Message) -> Option<&mut String> {
+    match message {
+        Message::LockFile { lock, .. } | Message::TestLock { lock, .. } => Some(&mut lock.owner.session),
+        Message::UnlockFile { owner, .. } => Some(&mut owner.session),
+        Message::OpenFile { session, .. } => Some(session),
+        Message::AsUser { request, .. } => client_session(request),
+        _ => None,
+    }
+}
+
+/// Check if a client request is answered by a stream the client steers
+/// while it runs, by acknowledging chunks or cancelling it
+fn is_steered_stream(message: &Message) -> bool {
+    match message {
+        Message::ReadFileStream { .. } | Message::SetMetadataTree { .. } => true,
+        Message::AsUser { request, .. } => is_steered_stream(request),
+        _ => false,
+    }
+}
+
 /// Request tracking entry for mapping responses back to originators
 #[derive(Debug, Clone)]
 pub struct RequestTrackingEntry {
@@ -334,6 +875,7 @@ pub struct RequestTrackingEntry {
 /// Enhanced message router with proper request tracking
 /// This would be used in a production system
 pub struct EnhancedMessageRouter {
+    #[allow(dead_code)]
     basic_router: MessageRouter,
     request_tracking: Arc<tokio::sync::RwLock<std::collections::HashMap<uuid::Uuid, RequestTrackingEntry>>>,
 }
@@ -387,7 +929,7 @@ impl EnhancedMessageRouter {
         let initial_count = tracking.len();
         
         tracking.retain(|_, entry| {
-            now.saturating_sub(entry.created_at) <= max_age_seconds
+            now.saturating_sub(entry.created_at) < max_age_seconds
         });
         
         initial_count - tracking.len()
@@ -417,9 +959,12 @@ impl Default for EnhancedMessageRouter {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use remotefs_common::config_utils;
-    use crate::session::Session;
-    use tokio::sync::mpsc;
+    use crate::buffers::{outbound_channel, BufferAccounting};
+    use crate::session::MessageFormat;
+    use remotefs_common::config::BufferLimits;
+    
+    
+    
     
     #[tokio::test]
     async fn test_routing_stats() {
@@ -445,6 +990,49 @@ mod tests {
         assert_eq!(stats.failed_routes, 0);
     }
     
+    #[test]
+    fn test_streamed_responses_keep_their_route() {
+        let router = MessageRouter::new();
+        let accounting = Arc::new(BufferAccounting::new(&BufferLimits::default()));
+        let (tx, _rx) = outbound_channel(&accounting);
+        let client = Session::new(
+            "session".to_string(),
+            "client-001".to_string(),
+            NodeType::Client,
+            uuid::Uuid::new_v4(),
+            tx,
+            MessageFormat::Binary,
+        );
+        
+        let request_id = uuid::Uuid::new_v4();
+        let request = Message::ListDirectoryPaged {
+            request_id,
+            path: "/".to_string(),
+            page_size: 100,
+            after: None,
+            max_entries: None,
+        };
+        assert_eq!(router.track_request(&request, &client), Some(request_id));
+        
+        let page = |last| Message::DirectoryPage {
+            request_id,
+            sequence: 0,
+            entries: Vec::new(),
+            last,
+            error: None,
+            cursor: None,
+        };
+        assert_eq!(router.requester(&page(false)), Some("client-001".to_string()));
+        assert!(!page(false).ends_request());
+        assert_eq!(router.requester(&page(true)), Some("client-001".to_string()));
+        
+        // Responses are never tracked as requests
+        assert_eq!(router.track_request(&page(true), &client), None);
+        
+        router.forget_node("client-001");
+        assert_eq!(router.requester(&page(true)), None);
+    }
+    
     #[tokio::test]
     async fn test_enhanced_router_tracking() {
         let router = EnhancedMessageRouter::new();
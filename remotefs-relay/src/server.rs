@@ -1,24 +1,38 @@
+use crate::admission::{ConnectionAdmission, UnauthenticatedPermit};
 use crate::session::{Session, SessionManager};
 use crate::routing::MessageRouter;
-use crate::auth::AuthManager;
+use crate::auth::{AuthManager, NodeCredentials};
+use crate::failover::MirrorManager;
+use crate::guest::GuestAccess;
+use crate::hardening;
+use crate::listener;
+use crate::maintenance::MaintenanceSchedule;
+use crate::buffers::{self, Backpressure, BufferAccounting, OutboundReceiver, OutboundSender};
 use axum::{
     extract::{
-        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query, State,
     },
-    response::Response,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
 use remotefs_common::{
-    protocol::{Message, NodeType, generate_request_id},
+    activation,
+    compression,
+    crash,
+    protocol::{Capability, ErrorCode, MaintenanceWindow, Message, NodeType, RelayDirectory, RelayInfo, SessionToken, generate_request_id},
     error::{RemoteFsError, Result},
     config::RelayConfig,
 };
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use tracing::{info, warn, error, debug};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot};
+use tracing::{field, info, instrument, warn, error, debug, Span};
 use uuid::Uuid;
 
 /// Main relay server that handles client and agent connections
@@ -27,6 +41,11 @@ pub struct RelayServer {
     session_manager: Arc<SessionManager>,
     message_router: Arc<MessageRouter>,
     auth_manager: Arc<AuthManager>,
+    mirrors: Arc<MirrorManager>,
+    buffers: Arc<BufferAccounting>,
+    guests: Arc<GuestAccess>,
+    maintenance: Arc<MaintenanceSchedule>,
+    admission: Arc<ConnectionAdmission>,
     shutdown_tx: broadcast::Sender<()>,
     shutdown_rx: broadcast::Receiver<()>,
 }
@@ -40,6 +59,11 @@ impl RelayServer {
             session_manager: Arc::new(SessionManager::new(&config)),
             message_router: Arc::new(MessageRouter::new()),
             auth_manager,
+            mirrors: Arc::new(MirrorManager::new(config.mirrors.clone())),
+            buffers: Arc::new(BufferAccounting::new(&config.buffers)),
+            guests: Arc::new(GuestAccess::new(&config.public_exports)),
+            maintenance: Arc::new(MaintenanceSchedule::new()),
+            admission: Arc::new(ConnectionAdmission::new(&config.connections)),
             config,
             shutdown_tx,
             shutdown_rx,
@@ -61,6 +85,11 @@ impl RelayServer {
             session_manager: Arc::clone(&self.session_manager),
             message_router: Arc::clone(&self.message_router),
             auth_manager: Arc::clone(&self.auth_manager),
+            mirrors: Arc::clone(&self.mirrors),
+            buffers: Arc::clone(&self.buffers),
+            guests: Arc::clone(&self.guests),
+            maintenance: Arc::clone(&self.maintenance),
+            admission: Arc::clone(&self.admission),
             config: self.config.clone(),
         };
         
@@ -69,20 +98,49 @@ impl RelayServer {
             .route("/ws", get(websocket_handler))
             .route("/health", get(health_handler))
             .route("/stats", get(stats_handler))
-            .with_state(app_state);
+            .route("/discovery", get(discovery_handler))
+            .route("/maintenance", get(maintenance_handler).put(set_maintenance_handler).delete(clear_maintenance_handler))
+            .with_state(app_state.clone());
+        let app = listener::with_virtual_hosts(app, &self.config.virtual_hosts);
         
-        // Start the server
-        let listener = tokio::net::TcpListener::bind(addr).await
-            .map_err(|e| RemoteFsError::Network(format!("Failed to bind to {}: {}", addr, e)))?;
+        // Load the certificate before binding so a bad one fails fast
+        let tls = if self.config.security.enable_tls {
+            Some(listener::tls_acceptor(&self.config.security)?)
+        } else {
+            None
+        };
+        
+        // Start the server, on the socket systemd bound if it was socket activated
+        let passed = activation::take_tcp_listener()
+            .and_then(|listener| listener.map(tokio::net::TcpListener::from_std).transpose())
+            .map_err(|e| RemoteFsError::Network(format!("Failed to use the socket passed by systemd: {}", e)))?;
+        let listener = match passed {
+            Some(listener) => {
+                info!("Using the socket passed by systemd instead of binding to {}", addr);
+                listener
+            }
+            None => tokio::net::TcpListener::bind(addr).await
+                .map_err(|e| RemoteFsError::Network(format!("Failed to bind to {}: {}", addr, e)))?,
+        };
+        let local_addr = listener.local_addr().unwrap_or(addr);
             
-        info!("Relay server listening on {}", addr);
+        info!("Relay server listening on {} ({})", local_addr, if tls.is_some() { "TLS" } else { "plain text" });
+        
+        // Give up what serving does not need, now that the key is read and the port bound
+        hardening::apply(&self.config.hardening, &self.config.storage.temp_dir)?;
+        
+        // Report sessions and requests in flight if the relay panics
+        let session_manager = Arc::clone(&self.session_manager);
+        crash::add_snapshot("sessions", move || session_manager.snapshot());
+        let message_router = Arc::clone(&self.message_router);
+        crash::add_snapshot("routing", move || message_router.snapshot());
         
         // Start background tasks
-        let session_cleanup = self.start_session_cleanup();
+        let session_cleanup = self.start_session_cleanup(app_state.clone());
         let stats_reporter = self.start_stats_reporter();
         
         // Run the server
-        let server = axum::serve(listener, app);
+        let server = listener::serve(listener, tls, app, Arc::clone(&self.admission));
         
         tokio::select! {
             result = server => {
@@ -109,8 +167,7 @@ impl RelayServer {
     }
     
     /// Start the session cleanup background task
-    fn start_session_cleanup(&self) -> tokio::task::JoinHandle<()> {
-        let session_manager = Arc::clone(&self.session_manager);
+    fn start_session_cleanup(&self, state: AppState) -> tokio::task::JoinHandle<()> {
         let cleanup_interval = self.config.session.cleanup_interval;
         let mut shutdown_rx = self.shutdown_rx.resubscribe();
         
@@ -122,7 +179,7 @@ impl RelayServer {
             loop {
                 tokio::select! {
                     _ = interval.tick() => {
-                        let expired = session_manager.cleanup_expired_sessions().await;
+                        let expired = state.expire_sessions().await;
                         if expired > 0 {
                             debug!("Cleaned up {} expired sessions", expired);
                         }
@@ -176,15 +233,53 @@ pub struct AppState {
     pub session_manager: Arc<SessionManager>,
     pub message_router: Arc<MessageRouter>,
     pub auth_manager: Arc<AuthManager>,
+    pub mirrors: Arc<MirrorManager>,
+    pub buffers: Arc<BufferAccounting>,
+    pub guests: Arc<GuestAccess>,
+    pub maintenance: Arc<MaintenanceSchedule>,
+    pub admission: Arc<ConnectionAdmission>,
     pub config: RelayConfig,
 }
 
+impl AppState {
+    /// Forget a node whose connection ended, end its watches, release its
+    /// locks, and promote its mirror if it was a primary agent
+    pub async fn end_session(&self, session: &Session) {
+        self.session_manager.remove_session(&session.id).await;
+        self.message_router.end_node(&session.node_id, self).await;
+        
+        if matches!(session.node_type, NodeType::Agent) {
+            self.mirrors.refresh(&self.session_manager).await;
+        }
+    }
+    
+    /// End the sessions that have been idle past the session timeout,
+    /// returning how many there were
+    pub async fn expire_sessions(&self) -> usize {
+        let expired = self.session_manager.remove_expired_sessions().await;
+        for session in &expired {
+            self.message_router.end_node(&session.node_id, self).await;
+        }
+        if !expired.is_empty() {
+            self.mirrors.refresh(&self.session_manager).await;
+        }
+        expired.len()
+    }
+}
+
 /// WebSocket upgrade handler
+///
+/// Upgrades are refused while too many connections are still waiting to
+/// authenticate.
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_websocket(socket, state))
+    let Some(unauthenticated) = state.admission.start_unauthenticated() else {
+        debug!("Refusing WebSocket upgrade: too many connections waiting to authenticate");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    ws.on_upgrade(|socket| handle_websocket(socket, state, unauthenticated))
 }
 
 /// Health check handler
@@ -196,6 +291,14 @@ pub async fn health_handler() -> &'static str {
 pub async fn stats_handler(State(state): State<AppState>) -> String {
     let session_stats = state.session_manager.get_stats().await;
     let routing_stats = state.message_router.get_stats().await;
+    let buffer_stats = state.buffers.statistics();
+    let guest_stats = state.guests.get_stats();
+    let admission_stats = state.admission.get_stats();
+    
+    let compression = &session_stats.compression;
+    let compression_ratio = compression.ratio()
+        .map(|ratio| format!("{:.2}", ratio))
+        .unwrap_or_else(|| "N/A".to_string());
     
     format!(
         "RemoteFS Relay Server Stats\n\
@@ -204,38 +307,163 @@ pub async fn stats_handler(State(state): State<AppState>) -> String {
          Total Agents: {}\n\
          Messages Routed: {}\n\
          Failed Routes: {}\n\
+         Requests In Flight: {}\n\
+         Compression Ratio: {}\n\
+         Payloads Compressed: {}\n\
+         Payloads Sent Uncompressed: {}\n\
+         Compression CPU Time: {} ms\n\
+         Compression Auto-Disabled: {} times\n\
+         Buffered Bytes: {}\n\
+         Throttled Requests: {}\n\
+         Slow Consumers Disconnected: {}\n\
+         Agent Paths Not Ready: {}\n\
+         Guest Requests Admitted: {}\n\
+         Guest Requests Refused: {}\n\
+         Guest Requests Rate Limited: {}\n\
+         Connections Awaiting Authentication: {}\n\
+         Connections Refused Per IP: {}\n\
+         Connections Refused Awaiting Authentication: {}\n\
+         Handshake Timeouts: {}\n\
+         Authentication Timeouts: {}\n\
          Uptime: {}",
         session_stats.active_sessions,
         session_stats.total_clients,
         session_stats.total_agents,
         routing_stats.messages_routed,
         routing_stats.failed_routes,
+        routing_stats.requests_in_flight,
+        compression_ratio,
+        compression.payloads_compressed,
+        compression.payloads_skipped,
+        compression.cpu_time.as_millis(),
+        compression.times_disabled,
+        buffer_stats.buffered_bytes,
+        buffer_stats.throttled_requests,
+        buffer_stats.slow_consumers_disconnected,
+        session_stats.unready_agent_paths,
+        guest_stats.admitted,
+        guest_stats.refused,
+        guest_stats.rate_limited,
+        admission_stats.unauthenticated,
+        admission_stats.rejected_per_ip,
+        admission_stats.rejected_unauthenticated,
+        admission_stats.handshake_timeouts,
+        admission_stats.auth_timeouts,
         "N/A" // TODO: Add uptime tracking
     )
 }
 
+/// Relay directory handler, for clients that discover relays over HTTP
+pub async fn discovery_handler(State(state): State<AppState>) -> Json<RelayDirectory> {
+    Json(state.session_manager.get_relay_directory())
+}
+
+/// Maintenance windows that have not ended
+pub async fn maintenance_handler(State(state): State<AppState>) -> Json<Vec<MaintenanceWindow>> {
+    Json(state.maintenance.windows().await)
+}
+
+/// Set the maintenance window of the relay or an agent and tell the clients
+/// it concerns
+pub async fn set_maintenance_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(window): Json<MaintenanceWindow>,
+) -> StatusCode {
+    if let Err(status) = authorize_admin(&state.config, &headers) {
+        return status;
+    }
+    state.maintenance.set(window).await;
+    state.maintenance.announce(&state.session_manager, &state.message_router, None).await;
+    StatusCode::NO_CONTENT
+}
+
+/// Which window `DELETE /maintenance` clears: an agent's, or the relay's if
+/// none is named
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceTarget {
+    pub agent_id: Option<String>,
+}
+
+/// End the maintenance window of the relay or an agent
+pub async fn clear_maintenance_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(target): Query<MaintenanceTarget>,
+) -> StatusCode {
+    if let Err(status) = authorize_admin(&state.config, &headers) {
+        return status;
+    }
+    if !state.maintenance.clear(target.agent_id.as_deref()).await {
+        return StatusCode::NOT_FOUND;
+    }
+    state.maintenance.announce(&state.session_manager, &state.message_router, None).await;
+    StatusCode::NO_CONTENT
+}
+
+/// Check the bearer token of a request to an admin endpoint that changes
+/// the relay's state
+fn authorize_admin(config: &RelayConfig, headers: &HeaderMap) -> std::result::Result<(), StatusCode> {
+    let Some(expected) = &config.admin_token else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+    let presented = headers.get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        // Compare without stopping at the first difference
+        Some(token) if token.len() == expected.len()
+            && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0 => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
 /// Handle individual WebSocket connections
-async fn handle_websocket(socket: WebSocket, state: AppState) {
+///
+/// A connection that has not authenticated, or become a guest, by the auth
+/// timeout is closed; `unauthenticated` counts it until then.
+async fn handle_websocket(socket: WebSocket, state: AppState, unauthenticated: UnauthenticatedPermit) {
     let connection_id = Uuid::new_v4();
     debug!("New WebSocket connection: {}", connection_id);
     
-    let (mut sender, mut receiver) = socket.split();
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (sender, mut receiver) = socket.split();
+    let (tx, rx) = buffers::outbound_channel(&state.buffers);
     
     // Spawn task to handle outgoing messages
-    let sender_task = tokio::spawn(async move {
-        let mut rx = tokio_stream::wrappers::UnboundedReceiverStream::new(rx);
-        while let Some(msg) = futures::stream::StreamExt::next(&mut rx).await {
-            if sender.send(msg).await.is_err() {
-                break;
-            }
-        }
-    });
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let sender_task = tokio::spawn(write_outgoing(sender, rx, stop_rx));
     
     // Handle incoming messages
     let mut session: Option<Session> = None;
+    let mut unauthenticated = Some(unauthenticated);
+    let auth_deadline = tokio::time::Instant::now() + state.admission.auth_timeout();
+    let mut close = None;
     
-    while let Some(msg) = receiver.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            queued = tx.overflowed() => {
+                warn!("Disconnecting slow consumer {} with {} bytes buffered", connection_id, queued);
+                close = Some(CloseFrame {
+                    code: close_code::AGAIN,
+                    reason: format!("Slow consumer: {} bytes buffered", queued).into(),
+                });
+                break;
+            }
+            _ = tokio::time::sleep_until(auth_deadline), if unauthenticated.is_some() => {
+                debug!("Connection {} did not authenticate in time", connection_id);
+                state.admission.record_auth_timeout();
+                close = Some(CloseFrame {
+                    code: close_code::POLICY,
+                    reason: "Authentication timed out".into(),
+                });
+                break;
+            }
+        };
+        
         match msg {
             Ok(WsMessage::Text(text)) => {
                 match handle_text_message(&text, &mut session, &state, &tx, connection_id).await {
@@ -276,24 +504,69 @@ async fn handle_websocket(socket: WebSocket, state: AppState) {
                 break;
             }
         }
+        
+        if session.is_some() {
+            unauthenticated = None;
+        }
     }
     
     // Clean up session if it exists
     if let Some(session) = session {
-        state.session_manager.remove_session(&session.id).await;
+        state.end_session(&session).await;
         debug!("Removed session: {}", session.id);
     }
     
-    sender_task.abort();
+    let _ = stop_tx.send(());
+    if let Some(close) = close {
+        // The backlog is dropped so the reason is the next thing written
+        if let Ok(mut sender) = sender_task.await {
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, sender.send(WsMessage::Close(Some(close)))).await;
+        }
+    } else {
+        sender_task.abort();
+    }
     debug!("WebSocket connection ended: {}", connection_id);
 }
 
+/// How long a closed connection gets to accept the close frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Write queued messages to the socket until the queue closes, the socket
+/// fails or the connection is told to stop, handing back the socket
+async fn write_outgoing(
+    mut sender: SplitSink<WebSocket, WsMessage>,
+    mut rx: OutboundReceiver,
+    mut stop_rx: oneshot::Receiver<()>,
+) -> SplitSink<WebSocket, WsMessage> {
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = &mut stop_rx => break,
+        };
+        
+        // A stalled socket blocks here, so stopping must interrupt the write
+        let size = buffers::message_size(&msg);
+        let sent = tokio::select! {
+            sent = sender.send(msg) => sent.is_ok(),
+            _ = &mut stop_rx => break,
+        };
+        rx.release(size);
+        if !sent {
+            break;
+        }
+    }
+    sender
+}
+
 /// Handle text messages (JSON)
 async fn handle_text_message(
     text: &str,
     session: &mut Option<Session>,
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     connection_id: Uuid,
 ) -> Result<()> {
     let message: Message = serde_json::from_str(text)
@@ -307,13 +580,19 @@ async fn handle_binary_message(
     data: &[u8],
     session: &mut Option<Session>,
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     connection_id: Uuid,
 ) -> Result<()> {
-    let message: Message = bincode::deserialize(data)
+    let (message, compressed) = compression::decode_message(data)
         .map_err(|e| RemoteFsError::Protocol(format!("Invalid binary message: {}", e)))?;
     
-    handle_message(message, session, state, tx, connection_id, MessageFormat::Binary).await
+    let result = handle_message(message, session, state, tx, connection_id, MessageFormat::Binary).await;
+    
+    // A node that sends compressed messages reads them as well
+    if let (true, Some(session)) = (compressed && state.config.network.compression, session.as_ref()) {
+        session.accept_compression();
+    }
+    result
 }
 
 /// Message format for responses
@@ -324,14 +603,21 @@ pub enum MessageFormat {
 }
 
 /// Handle a parsed message
+///
+/// Everything logged while handling or routing a request or its responses
+/// names the request, as the client and agent logs do.
+#[instrument(name = "request", skip_all, fields(id = field::Empty))]
 async fn handle_message(
     message: Message,
     session: &mut Option<Session>,
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     connection_id: Uuid,
     format: MessageFormat,
 ) -> Result<()> {
+    if let Some(request_id) = message.request_id() {
+        Span::current().record("id", field::display(request_id));
+    }
     debug!("Handling message: {} from connection: {}", message.message_type(), connection_id);
     
     match message {
@@ -342,6 +628,12 @@ async fn handle_message(
             ).await
         }
         
+        Message::ResumeSession { node_id, ticket } => {
+            debug!("Session resumption from {}", node_id);
+            let result = state.auth_manager.resume_session(&node_id, &ticket).await;
+            start_session(node_id, result, session, state, tx, connection_id, format).await
+        }
+        
         Message::EstablishChannel { target_node, encrypted_key_exchange } => {
             handle_establish_channel(
                 target_node, encrypted_key_exchange,
@@ -353,10 +645,122 @@ async fn handle_message(
             handle_ping(timestamp, tx, format).await
         }
         
+        Message::AgentHealth { paths, .. } => {
+            match session {
+                Some(session) if matches!(session.node_type, NodeType::Agent) => {
+                    for path in paths.iter().filter(|path| !path.is_ready()) {
+                        warn!("Agent {} cannot serve {}: {}", session.node_id, path.path, path.problems.join("; "));
+                    }
+                    *session.path_readiness.write().await = paths;
+                    Ok(())
+                }
+                Some(_) => Err(RemoteFsError::Protocol("Only agents report path health".to_string())),
+                None => Err(RemoteFsError::Authentication("No active session".to_string())),
+            }
+        }
+        
+        Message::Audit { record } => {
+            match session {
+                Some(session) if matches!(session.node_type, NodeType::Agent) => {
+                    match serde_json::to_string(&record) {
+                        Ok(line) => info!(target: "remotefs_relay::audit", agent = %session.node_id, "{}", line),
+                        Err(e) => warn!("Unreadable audit record from {}: {}", session.node_id, e),
+                    }
+                    Ok(())
+                }
+                Some(_) => Err(RemoteFsError::Protocol("Only agents send audit records".to_string())),
+                None => Err(RemoteFsError::Authentication("No active session".to_string())),
+            }
+        }
+        
+        Message::ConnectionClose { reason } => {
+            // The node closes the socket next, which ends its session
+            if let Some(session) = session {
+                info!("{:?} {} is disconnecting: {}", session.node_type, session.node_id, reason);
+            }
+            Ok(())
+        }
+        
+        Message::GetRelayDirectory => {
+            let directory = state.session_manager.get_relay_directory();
+            send_message(Message::RelayDirectoryResponse { directory }, tx, format).await
+        }
+        
+        Message::GetMaintenance { request_id } => {
+            // Without a session only the relay's own window concerns the sender
+            let agents = session.as_ref()
+                .map(|session| state.message_router.agents_used_by(&session.node_id))
+                .unwrap_or_default();
+            let windows = state.maintenance.windows_for(&agents).await;
+            send_message(Message::MaintenanceStatus { request_id: Some(request_id), windows }, tx, format).await
+        }
+        
+        Message::ListAgents { request_id } => {
+            match session {
+                Some(session) if !session.guest => {
+                    let agents = state.session_manager.list_agents().await;
+                    send_message(Message::ListAgentsResponse { request_id, agents }, tx, format).await
+                }
+                Some(_) => {
+                    let error = RemoteFsError::AccessDenied("Anonymous clients cannot list agents".to_string());
+                    send_message(create_error_message(Some(request_id), error), tx, format).await
+                }
+                None => Err(RemoteFsError::Authentication("No active session".to_string())),
+            }
+        }
+        
         // All other messages are routed between clients and agents
         _ => {
-            if let Some(session) = session {
-                state.message_router.route_message(message, session, state).await?;
+            if session.is_none() && state.guests.is_enabled() {
+                let guest = Session::new(
+                    generate_request_id().to_string(),
+                    format!("guest-{}", connection_id),
+                    NodeType::Client,
+                    connection_id,
+                    tx.clone(),
+                    format.into(),
+                ).as_guest();
+                debug!("Connection {} is a guest", connection_id);
+                state.session_manager.add_session(guest.clone()).await;
+                *session = Some(guest);
+            }
+            
+            if let Some(session) = session.as_ref().filter(|session| session.guest) {
+                let request_id = message.request_id();
+                let agent_id = match state.guests.admit(&message) {
+                    Ok(agent_id) => agent_id,
+                    Err(e) => {
+                        debug!("Refusing {} from {}: {}", message.message_type(), session.node_id, e);
+                        return send_message(create_error_message(request_id, e), tx, format).await;
+                    }
+                };
+                if let Err(pressure) = tx.admit() {
+                    return send_message(throttled_response(request_id, pressure), tx, format).await;
+                }
+                if let Err(e) = state.message_router.route_to_agent(message, session, &agent_id, state).await {
+                    // The guest is told instead of being disconnected
+                    return send_message(create_error_message(request_id, e), tx, format).await;
+                }
+            } else if let Some(session) = session {
+                // Hold back new requests from a client that is not reading
+                // its responses, or while the relay is short of memory;
+                // acknowledgements of streamed chunks are what lets those go
+                let request = !message.is_response() && !matches!(message, Message::ReadFileAck { .. });
+                if matches!(session.node_type, NodeType::Client) && request {
+                    if let Err(pressure) = tx.admit() {
+                        debug!("Throttling {} from {}: {:?}", message.message_type(), session.node_id, pressure);
+                        return send_message(throttled_response(message.request_id(), pressure), tx, format).await;
+                    }
+                }
+                let request_id = message.request_id();
+                match state.message_router.route_message(message, session, state).await {
+                    // Answer the request itself so the client can downgrade it,
+                    // or learn that the agent it chose is not there
+                    Err(e @ (RemoteFsError::NotImplemented(_) | RemoteFsError::ServiceUnavailable(_))) => {
+                        return send_message(create_error_message(request_id, e), tx, format).await;
+                    }
+                    result => result?,
+                }
             } else {
                 return Err(RemoteFsError::Authentication("No active session".to_string()));
             }
@@ -366,14 +770,15 @@ async fn handle_message(
 }
 
 /// Handle authentication requests
+#[allow(clippy::too_many_arguments)]
 async fn handle_auth_request(
     node_id: String,
     node_type: NodeType,
     public_key: Vec<u8>,
-    capabilities: Vec<String>,
+    capabilities: Vec<Capability>,
     session: &mut Option<Session>,
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     connection_id: Uuid,
     format: MessageFormat,
 ) -> Result<()> {
@@ -384,21 +789,54 @@ async fn handle_auth_request(
     });
     
     // Authenticate the node
-    let auth_result = state.auth_manager.authenticate_node(
+    let result = state.auth_manager.authenticate_node(
         &node_id, &node_type, &public_key, &capabilities
-    ).await;
+    ).await.map(|session_token| {
+        let credentials = NodeCredentials { node_id: node_id.clone(), node_type, public_key, capabilities };
+        (session_token, credentials)
+    });
     
+    start_session(node_id, result, session, state, tx, connection_id, format).await
+}
+
+/// Open a session for a node that authenticated or presented a resumption
+/// ticket, and answer with a fresh ticket
+async fn start_session(
+    node_id: String,
+    auth_result: Result<(SessionToken, NodeCredentials)>,
+    session: &mut Option<Session>,
+    state: &AppState,
+    tx: &OutboundSender,
+    connection_id: Uuid,
+    format: MessageFormat,
+) -> Result<()> {
+    let mut is_agent = false;
     let response = match auth_result {
-        Ok(session_token) => {
+        Ok((session_token, credentials)) => {
+            let resumption_ticket = state.auth_manager.issue_ticket(&credentials).unwrap_or_else(|e| {
+                warn!("Failed to issue resumption ticket for {}: {}", node_id, e);
+                None
+            });
+            is_agent = matches!(credentials.node_type, NodeType::Agent);
+            
             // Create session
             let new_session = Session::new(
                 generate_request_id().to_string(),
                 node_id.clone(),
-                node_type,
+                credentials.node_type,
                 connection_id,
                 tx.clone(),
                 format.into(),
-            );
+            ).with_capabilities(credentials.capabilities);
+            if state.config.network.compression && new_session.supports(&Capability::Compression) {
+                new_session.accept_compression();
+            }
+            
+            // A guest that authenticates leaves guest mode
+            if let Some(guest) = session.as_ref().filter(|session| session.guest) {
+                state.session_manager.remove_session(&guest.id).await;
+                state.message_router.end_node(&guest.node_id, state).await;
+            }
             
             // Store session
             state.session_manager.add_session(new_session.clone()).await;
@@ -407,8 +845,12 @@ async fn handle_auth_request(
             Message::AuthResponse {
                 success: true,
                 session_token: Some(session_token),
-                relay_info: Some(state.session_manager.get_relay_info()),
+                relay_info: Some(RelayInfo {
+                    token_key: Some(state.auth_manager.token_key()),
+                    ..state.session_manager.get_relay_info()
+                }),
                 error: None,
+                resumption_ticket,
             }
         }
         Err(e) => {
@@ -418,11 +860,31 @@ async fn handle_auth_request(
                 session_token: None,
                 relay_info: None,
                 error: Some(e.to_string()),
+                resumption_ticket: None,
             }
         }
     };
     
-    send_message(response, tx, format).await
+    let authenticated = matches!(response, Message::AuthResponse { success: true, .. });
+    send_message(response, tx, format).await?;
+    
+    if authenticated && is_agent {
+        // A reconnecting mirror learns whether it is currently promoted
+        state.mirrors.refresh(&state.session_manager).await;
+        if let Some(status) = state.mirrors.status_for(&node_id).await {
+            send_message(status, tx, format).await?;
+        }
+    }
+    
+    // A client that connects during planned work learns about it right away
+    if authenticated && !is_agent {
+        let windows = state.maintenance.windows_for(&state.message_router.agents_used_by(&node_id)).await;
+        if !windows.is_empty() {
+            send_message(Message::MaintenanceStatus { request_id: None, windows }, tx, format).await?;
+        }
+    }
+    
+    Ok(())
 }
 
 /// Handle channel establishment requests
@@ -431,7 +893,7 @@ async fn handle_establish_channel(
     encrypted_key_exchange: Vec<u8>,
     session: &mut Option<Session>,
     state: &AppState,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     format: MessageFormat,
 ) -> Result<()> {
     if let Some(_session) = session {
@@ -465,7 +927,7 @@ async fn handle_establish_channel(
 /// Handle ping messages
 async fn handle_ping(
     original_timestamp: chrono::DateTime<chrono::Utc>,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     format: MessageFormat,
 ) -> Result<()> {
     let response = Message::Pong {
@@ -479,7 +941,7 @@ async fn handle_ping(
 /// Send a message through the WebSocket
 async fn send_message(
     message: Message,
-    tx: &tokio::sync::mpsc::UnboundedSender<WsMessage>,
+    tx: &OutboundSender,
     format: MessageFormat,
 ) -> Result<()> {
     let ws_message = match format {
@@ -496,9 +958,21 @@ async fn send_message(
     };
     
     tx.send(ws_message)
-        .map_err(|_| RemoteFsError::Network("Failed to send message".to_string()))?;
+}
+
+/// Refuse a request until its session's or the relay's buffers drain
+fn throttled_response(request_id: Option<uuid::Uuid>, pressure: Backpressure) -> Message {
+    let mut details = HashMap::new();
+    details.insert("resource".to_string(), pressure.resource().to_string());
+    details.insert("buffered_bytes".to_string(), pressure.queued().to_string());
     
-    Ok(())
+    Message::Error {
+        request_id,
+        code: ErrorCode::ServiceUnavailable,
+        message: "Relay is buffering too much data, retry later".to_string(),
+        details: Some(details),
+        errno: None,
+    }
 }
 
 /// Create an error message
@@ -508,8 +982,9 @@ fn create_error_message(request_id: Option<uuid::Uuid>, error: RemoteFsError) ->
         code: error.to_error_code(),
         message: error.to_string(),
         details: None,
+        errno: None,
     }
 }
 
-use futures::stream::StreamExt;
+use futures::stream::{SplitSink, StreamExt};
 use futures::SinkExt;
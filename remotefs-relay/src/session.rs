@@ -1,13 +1,16 @@
+use crate::buffers::OutboundSender;
 use axum::extract::ws::Message as WsMessage;
 use remotefs_common::{
-    protocol::{NodeType, RelayInfo},
+    compression::{self, AutoDisable, CompressionStats},
+    protocol::{AgentInfo, Capability, Message, NodeType, PathReadiness, RelayDirectory, RelayEndpoint, RelayInfo},
     config::RelayConfig,
     error::{RemoteFsError, Result},
 };
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::RwLock;
 use tracing::{debug, warn};
 use uuid::Uuid;
 
@@ -20,8 +23,20 @@ pub struct Session {
     pub connection_id: Uuid,
     pub created_at: u64,
     pub last_activity: Arc<RwLock<u64>>,
-    pub sender: mpsc::UnboundedSender<WsMessage>,
+    pub sender: OutboundSender,
     pub message_format: MessageFormat,
+    /// Payload compression results for messages on this session
+    pub compression: Arc<RwLock<CompressionStats>>,
+    /// Whether the node reads `Compressed` messages
+    accepts_compression: Arc<AtomicBool>,
+    /// Whether messages to the node are still worth compressing
+    compression_heuristic: Arc<Mutex<AutoDisable>>,
+    /// What the node announced it supports when it authenticated
+    pub capabilities: Arc<[Capability]>,
+    /// Latest probe of an agent's configured paths
+    pub path_readiness: Arc<RwLock<Vec<PathReadiness>>>,
+    /// Anonymous client limited to the relay's public exports
+    pub guest: bool,
 }
 
 /// Message format preference for the session
@@ -31,6 +46,20 @@ pub enum MessageFormat {
     Binary,
 }
 
+impl MessageFormat {
+    /// Encode a protocol message in this format
+    pub fn encode(&self, message: &Message) -> Result<WsMessage> {
+        match self {
+            MessageFormat::Json => serde_json::to_string(message)
+                .map(WsMessage::Text)
+                .map_err(|e| RemoteFsError::Protocol(format!("JSON serialization error: {}", e))),
+            MessageFormat::Binary => bincode::serialize(message)
+                .map(WsMessage::Binary)
+                .map_err(|e| RemoteFsError::Protocol(format!("Binary serialization error: {}", e))),
+        }
+    }
+}
+
 impl Session {
     /// Create a new session
     pub fn new(
@@ -38,7 +67,7 @@ impl Session {
         node_id: String,
         node_type: NodeType,
         connection_id: Uuid,
-        sender: mpsc::UnboundedSender<WsMessage>,
+        sender: OutboundSender,
         message_format: MessageFormat,
     ) -> Self {
         let now = SystemTime::now()
@@ -55,7 +84,53 @@ impl Session {
             last_activity: Arc::new(RwLock::new(now)),
             sender,
             message_format,
+            compression: Arc::new(RwLock::new(CompressionStats::default())),
+            accepts_compression: Arc::new(AtomicBool::new(false)),
+            compression_heuristic: Arc::new(Mutex::new(AutoDisable::default())),
+            capabilities: Arc::new([]),
+            path_readiness: Arc::new(RwLock::new(Vec::new())),
+            guest: false,
+        }
+    }
+    
+    /// Record what the node announced it supports
+    pub fn with_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities.into();
+        self
+    }
+    
+    /// Send the node compressed messages from now on, as it announced it
+    /// reads them or sent one itself
+    pub fn accept_compression(&self) {
+        self.accepts_compression.store(true, Ordering::Relaxed);
+    }
+    
+    /// Whether the node is sent compressed messages
+    pub fn accepts_compression(&self) -> bool {
+        self.accepts_compression.load(Ordering::Relaxed)
+    }
+    
+    /// Encode a message for this node, compressed if it reads compressed
+    /// messages and compressing is worthwhile
+    pub async fn encode(&self, message: &Message) -> Result<WsMessage> {
+        if !self.accepts_compression() {
+            return self.message_format.encode(message);
         }
+        
+        let mut stats = self.compression.write().await;
+        let mut heuristic = self.compression_heuristic.lock().unwrap_or_else(PoisonError::into_inner);
+        compression::encode_message(message, &mut heuristic, &mut stats).map(WsMessage::Binary)
+    }
+    
+    /// Mark an unauthenticated client as a guest
+    pub fn as_guest(mut self) -> Self {
+        self.guest = true;
+        self
+    }
+    
+    /// Whether the node announced `capability`
+    pub fn supports(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
     }
     
     /// Update the last activity timestamp
@@ -81,8 +156,7 @@ impl Session {
     
     /// Send a message to this session
     pub async fn send_message(&self, message: WsMessage) -> Result<()> {
-        self.sender.send(message)
-            .map_err(|_| RemoteFsError::Network("Failed to send message to session".to_string()))?;
+        self.sender.send(message)?;
         
         self.update_activity().await;
         Ok(())
@@ -95,6 +169,9 @@ pub struct SessionStats {
     pub active_sessions: usize,
     pub total_clients: usize,
     pub total_agents: usize,
+    pub compression: CompressionStats,
+    /// Configured agent paths that failed their agent's self-test
+    pub unready_agent_paths: usize,
 }
 
 /// Manages all active sessions
@@ -162,6 +239,12 @@ impl SessionManager {
     
     /// Clean up expired sessions
     pub async fn cleanup_expired_sessions(&self) -> usize {
+        self.remove_expired_sessions().await.len()
+    }
+    
+    /// Remove expired sessions, returning them so their nodes can be
+    /// cleaned up elsewhere
+    pub async fn remove_expired_sessions(&self) -> Vec<Session> {
         let timeout = self.config.session.timeout;
         let mut sessions_to_remove = Vec::new();
         
@@ -177,15 +260,13 @@ impl SessionManager {
         }
         
         // Then remove them
-        let expired_count = sessions_to_remove.len();
-        if expired_count > 0 {
-            let mut sessions = self.sessions.write().await;
-            for session_id in sessions_to_remove {
-                sessions.remove(&session_id);
-            }
+        if sessions_to_remove.is_empty() {
+            return Vec::new();
         }
-        
-        expired_count
+        let mut sessions = self.sessions.write().await;
+        sessions_to_remove.iter()
+            .filter_map(|session_id| sessions.remove(session_id))
+            .collect()
     }
     
     /// Get session statistics
@@ -195,6 +276,8 @@ impl SessionManager {
         
         let mut total_clients = 0;
         let mut total_agents = 0;
+        let mut compression = CompressionStats::default();
+        let mut unready_agent_paths = 0;
         
         for session in sessions.values() {
             match session.node_type {
@@ -202,26 +285,85 @@ impl SessionManager {
                 NodeType::Agent => total_agents += 1,
                 NodeType::Relay => {} // Relays don't connect to other relays in this design
             }
+            compression.merge(&*session.compression.read().await);
+            unready_agent_paths += session.path_readiness.read().await.iter().filter(|path| !path.is_ready()).count();
         }
         
         SessionStats {
             active_sessions,
             total_clients,
             total_agents,
+            compression,
+            unready_agent_paths,
         }
     }
-    
-    /// Get relay information for auth responses
+
+    /// One line per session for crash reports, without waiting for locks
+    pub fn snapshot(&self) -> String {
+        let Ok(sessions) = self.sessions.try_read() else {
+            return "unavailable, the session table is locked".to_string();
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let mut lines: Vec<String> = sessions.values()
+            .map(|session| {
+                let idle = session.last_activity.try_read()
+                    .map(|last_activity| format!("{}s", now.saturating_sub(*last_activity)))
+                    .unwrap_or_else(|_| "?".to_string());
+                format!(
+                    "{:?} {} session={} age={}s idle={}{}",
+                    session.node_type,
+                    session.node_id,
+                    session.id,
+                    now.saturating_sub(session.created_at),
+                    idle,
+                    if session.guest { " guest" } else { "" }
+                )
+            })
+            .collect();
+        lines.sort();
+        lines.insert(0, format!("{} sessions", sessions.len()));
+        lines.join("\n")
+    }
+
+    /// Get relay information for auth responses, without the token key,
+    /// which the auth manager holds
     pub fn get_relay_info(&self) -> RelayInfo {
+        let mut capabilities = vec![
+            "routing".to_string(),
+            "authentication".to_string(),
+            "session_management".to_string(),
+        ];
+        if self.config.network.compression {
+            capabilities.push(Capability::Compression.to_string());
+        }
+        
         RelayInfo {
-            relay_id: "relay-001".to_string(), // TODO: Make configurable
-            capabilities: vec![
-                "routing".to_string(),
-                "authentication".to_string(),
-                "session_management".to_string(),
-            ],
+            relay_id: self.config.discovery.relay_id.clone(),
+            capabilities,
             max_message_size: self.config.message_limits.max_message_size as u64,
             heartbeat_interval: self.config.network.heartbeat_interval,
+            token_key: None,
+        }
+    }
+    
+    /// Get the relays clients may choose from, this one first
+    pub fn get_relay_directory(&self) -> RelayDirectory {
+        let discovery = &self.config.discovery;
+        let this_relay = discovery.public_url.as_ref().map(|url| RelayEndpoint {
+            id: discovery.relay_id.clone(),
+            url: url.clone(),
+            region: discovery.region.clone(),
+        });
+        
+        RelayDirectory {
+            relays: this_relay
+                .into_iter()
+                .chain(discovery.peers.iter().filter(|peer| peer.id != discovery.relay_id).cloned())
+                .collect(),
         }
     }
     
@@ -262,12 +404,38 @@ impl SessionManager {
         failed_sessions
     }
     
-    /// Get all active node IDs by type
+    /// Get all active node IDs by type, sorted so that round-robin
+    /// selection over them is stable
     pub async fn get_active_nodes(&self, node_type: NodeType) -> Vec<String> {
         let sessions = self.sessions.read().await;
-        sessions.values()
+        let mut nodes: Vec<String> = sessions.values()
             .filter(|session| std::mem::discriminant(&session.node_type) == std::mem::discriminant(&node_type))
             .map(|session| session.node_id.clone())
+            .collect();
+        nodes.sort();
+        nodes
+    }
+
+    /// Connected agents as clients see them, sorted by ID
+    pub async fn list_agents(&self) -> Vec<AgentInfo> {
+        let mut agents = Vec::new();
+        for session in self.get_sessions_by_type(NodeType::Agent).await {
+            agents.push(AgentInfo {
+                agent_id: session.node_id.clone(),
+                capabilities: session.capabilities.to_vec(),
+                paths: session.path_readiness.read().await.clone(),
+                connected_at: session.created_at,
+            });
+        }
+        agents.sort_by(|a, b| a.agent_id.cmp(&b.agent_id));
+        agents
+    }
+    
+    /// Keep the nodes among `node_ids` that announced `capability`
+    pub async fn nodes_supporting(&self, node_ids: Vec<String>, capability: &Capability) -> Vec<String> {
+        let sessions = self.sessions.read().await;
+        node_ids.into_iter()
+            .filter(|node_id| sessions.values().any(|session| session.node_id == *node_id && session.supports(capability)))
             .collect()
     }
     
@@ -342,6 +510,7 @@ impl From<MessageFormat> for crate::server::MessageFormat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::buffers::{outbound_channel, BufferAccounting};
     use remotefs_common::config_utils;
     
     #[tokio::test]
@@ -350,7 +519,8 @@ mod tests {
         let manager = SessionManager::new(&config);
         
         // Create a test session
-        let (tx, _rx) = mpsc::unbounded_channel();
+        let accounting = Arc::new(BufferAccounting::new(&config.buffers));
+        let (tx, _rx) = outbound_channel(&accounting);
         let session = Session::new(
             "test-session".to_string(),
             "test-node".to_string(),
@@ -358,7 +528,9 @@ mod tests {
             Uuid::new_v4(),
             tx,
             MessageFormat::Json,
-        );
+        ).with_capabilities(vec![Capability::Read, Capability::Streaming]);
+        assert!(session.supports(&Capability::Streaming));
+        assert!(!session.supports(&Capability::Write));
         
         // Test adding session
         manager.add_session(session.clone()).await;
@@ -372,6 +544,10 @@ mod tests {
         let by_node = manager.get_session_by_node("test-node").await;
         assert!(by_node.is_some());
         
+        let nodes = vec!["test-node".to_string(), "other-node".to_string()];
+        assert_eq!(manager.nodes_supporting(nodes.clone(), &Capability::Streaming).await, vec!["test-node".to_string()]);
+        assert!(manager.nodes_supporting(nodes, &Capability::Transactions).await.is_empty());
+        
         // Test stats
         let stats = manager.get_stats().await;
         assert_eq!(stats.active_sessions, 1);
@@ -392,7 +568,8 @@ mod tests {
         config.session.timeout = 1; // 1 second timeout
         
         let manager = SessionManager::new(&config);
-        let (tx, _rx) = mpsc::unbounded_channel();
+        let accounting = Arc::new(BufferAccounting::new(&config.buffers));
+        let (tx, _rx) = outbound_channel(&accounting);
         
         let session = Session::new(
             "expire-test".to_string(),
@@ -419,4 +596,60 @@ mod tests {
         let stats = manager.get_stats().await;
         assert_eq!(stats.active_sessions, 0);
     }
+    
+    #[tokio::test]
+    async fn test_compressed_encoding() {
+        let config = config_utils::create_default_relay_config();
+        let accounting = Arc::new(BufferAccounting::new(&config.buffers));
+        let (tx, _rx) = outbound_channel(&accounting);
+        let session = Session::new(
+            "compress-test".to_string(),
+            "compress-node".to_string(),
+            NodeType::Agent,
+            Uuid::new_v4(),
+            tx,
+            MessageFormat::Json,
+        );
+        let message = Message::WriteFile {
+            request_id: Uuid::new_v4(),
+            path: "/large.txt".to_string(),
+            offset: 0,
+            data: vec![b'a'; 64 * 1024],
+            sync: false,
+        };
+        
+        // Until the node reads compressed messages, its own format is used
+        assert!(matches!(session.encode(&message).await.unwrap(), WsMessage::Text(_)));
+        
+        session.accept_compression();
+        let WsMessage::Binary(frame) = session.encode(&message).await.unwrap() else {
+            panic!("compressed messages are sent in binary frames");
+        };
+        assert!(frame.len() < 64 * 1024);
+        let (decoded, compressed) = compression::decode_message(&frame).unwrap();
+        assert!(compressed);
+        assert!(matches!(decoded, Message::WriteFile { data, .. } if data.len() == 64 * 1024));
+    }
+    
+    #[test]
+    fn test_relay_directory() {
+        let mut config = config_utils::create_default_relay_config();
+        let peer = |id: &str| RelayEndpoint {
+            id: id.to_string(),
+            url: format!("wss://{}.example.com/ws", id),
+            region: Some("eu-west".to_string()),
+        };
+        config.discovery.peers = vec![peer("relay-001"), peer("relay-eu")];
+        
+        // Without a public URL only the peers are listed
+        let directory = SessionManager::new(&config).get_relay_directory();
+        assert_eq!(directory.relays, vec![peer("relay-eu")]);
+        
+        config.discovery.public_url = Some("wss://relay-001.example.com/ws".to_string());
+        config.discovery.region = Some("us-east".to_string());
+        let directory = SessionManager::new(&config).get_relay_directory();
+        assert_eq!(directory.relays.len(), 2);
+        assert_eq!(directory.relays[0].id, "relay-001");
+        assert_eq!(directory.relays[0].region.as_deref(), Some("us-east"));
+    }
 }
@@ -0,0 +1,401 @@
+// Shared helpers; not every test crate uses all of them
+#![allow(dead_code)]
+
+//! Deterministic simulation of the relay's routing layer
+//!
+//! Nodes are in-memory sessions whose outgoing queues the simulation drains
+//! itself, so no sockets are involved. Connects, disconnects, idle periods
+//! and messages are events on a virtual clock. Every message takes a
+//! latency drawn from a seeded RNG to reach the relay or its recipient,
+//! messages on one link never overtake each other, and simulated agents
+//! answer the requests they receive. The same seed always produces the
+//! same trace.
+
+use axum::extract::ws::Message as WsMessage;
+use futures::FutureExt;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use remotefs_common::{
+    config::RelayConfig,
+    config_utils,
+    protocol::{Capability, ChangeKind, ErrorCode, ExportInfo, Message, NodeType, RequestId},
+};
+use remotefs_relay::{
+    admission::ConnectionAdmission,
+    auth::AuthManager,
+    buffers::{self, BufferAccounting, OutboundReceiver},
+    failover::MirrorManager,
+    guest::GuestAccess,
+    maintenance::MaintenanceSchedule,
+    routing::MessageRouter,
+    server::AppState,
+    session::{MessageFormat, Session, SessionManager},
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// Something that happens at a point in virtual time
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connect { node_id: String, node_type: NodeType, capabilities: Vec<Capability> },
+    Disconnect { node_id: String },
+    /// A node's message reaches the relay
+    Send { from: String, message: Message },
+    /// A message from the relay reaches a node
+    Deliver { to: String, message: Message },
+    /// A node stops sending for `secs` seconds
+    Idle { node_id: String, secs: u64 },
+    /// The relay's session cleanup runs
+    Expire,
+}
+
+/// A message delivered to a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub at: u64,
+    pub to: String,
+    pub message_type: String,
+    pub request_id: Option<RequestId>,
+}
+
+/// A message the relay could not route
+#[derive(Debug, Clone)]
+pub struct RouteFailure {
+    pub at: u64,
+    pub from: String,
+    pub message_type: String,
+    pub error: String,
+}
+
+struct SimNode {
+    session: Session,
+    outbound: OutboundReceiver,
+    received: Vec<Message>,
+}
+
+pub struct Simulation {
+    pub state: AppState,
+    rng: StdRng,
+    /// Virtual time in milliseconds
+    now: u64,
+    sequence: u64,
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    events: HashMap<u64, Event>,
+    nodes: BTreeMap<String, SimNode>,
+    /// Earliest time the next message on each link may arrive
+    links: HashMap<(String, bool), u64>,
+    latency: RangeInclusive<u64>,
+    /// Time an agent takes to answer a request
+    service_time: RangeInclusive<u64>,
+    trace: Vec<Delivery>,
+    failures: Vec<RouteFailure>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Self::with_config(seed, config_utils::create_default_relay_config())
+    }
+
+    pub fn with_config(seed: u64, config: RelayConfig) -> Self {
+        let state = AppState {
+            session_manager: Arc::new(SessionManager::new(&config)),
+            message_router: Arc::new(MessageRouter::new()),
+            auth_manager: Arc::new(AuthManager::new(&config)),
+            mirrors: Arc::new(MirrorManager::new(config.mirrors.clone())),
+            buffers: Arc::new(BufferAccounting::new(&config.buffers)),
+            guests: Arc::new(GuestAccess::new(&config.public_exports)),
+            maintenance: Arc::new(MaintenanceSchedule::new()),
+            admission: Arc::new(ConnectionAdmission::new(&config.connections)),
+            config,
+        };
+
+        Self {
+            state,
+            rng: StdRng::seed_from_u64(seed),
+            now: 0,
+            sequence: 0,
+            queue: BinaryHeap::new(),
+            events: HashMap::new(),
+            nodes: BTreeMap::new(),
+            links: HashMap::new(),
+            latency: 1..=50,
+            service_time: 0..=20,
+            trace: Vec::new(),
+            failures: Vec::new(),
+        }
+    }
+
+    /// Milliseconds a message spends on a link
+    pub fn with_latency(mut self, latency: RangeInclusive<u64>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// A request ID drawn from the simulation's RNG
+    pub fn request_id(&mut self) -> RequestId {
+        Uuid::from_u128(self.rng.gen())
+    }
+
+    /// A time drawn from the simulation's RNG
+    pub fn random_time(&mut self, range: RangeInclusive<u64>) -> u64 {
+        self.rng.gen_range(range)
+    }
+
+    pub fn schedule(&mut self, at: u64, event: Event) {
+        self.sequence += 1;
+        self.queue.push(Reverse((at, self.sequence)));
+        self.events.insert(self.sequence, event);
+    }
+
+    pub fn connect_agent(&mut self, at: u64, node_id: &str, capabilities: Vec<Capability>) {
+        self.schedule(at, Event::Connect {
+            node_id: node_id.to_string(),
+            node_type: NodeType::Agent,
+            capabilities,
+        });
+    }
+
+    pub fn connect_client(&mut self, at: u64, node_id: &str) {
+        self.schedule(at, Event::Connect {
+            node_id: node_id.to_string(),
+            node_type: NodeType::Client,
+            capabilities: Vec::new(),
+        });
+    }
+
+    pub fn disconnect(&mut self, at: u64, node_id: &str) {
+        self.schedule(at, Event::Disconnect { node_id: node_id.to_string() });
+    }
+
+    /// `from` sends `message`, which reaches the relay after a latency
+    pub fn send(&mut self, at: u64, from: &str, message: Message) {
+        let arrival = self.link_arrival(from, true, at);
+        self.schedule(arrival, Event::Send { from: from.to_string(), message });
+    }
+
+    pub fn idle(&mut self, at: u64, node_id: &str, secs: u64) {
+        self.schedule(at, Event::Idle { node_id: node_id.to_string(), secs });
+    }
+
+    pub fn expire(&mut self, at: u64) {
+        self.schedule(at, Event::Expire);
+    }
+
+    /// Process events until none are left
+    pub async fn run(&mut self) {
+        while let Some(Reverse((at, sequence))) = self.queue.pop() {
+            self.now = at;
+            let event = self.events.remove(&sequence).unwrap();
+            self.process(event).await;
+        }
+    }
+
+    async fn process(&mut self, event: Event) {
+        match event {
+            Event::Connect { node_id, node_type, capabilities } => {
+                let is_agent = matches!(node_type, NodeType::Agent);
+                let (tx, outbound) = buffers::outbound_channel(&self.state.buffers);
+                let session = Session::new(
+                    format!("session-{}", node_id),
+                    node_id.clone(),
+                    node_type,
+                    Uuid::from_u128(self.rng.gen()),
+                    tx,
+                    MessageFormat::Binary,
+                ).with_capabilities(capabilities);
+
+                self.state.session_manager.add_session(session.clone()).await;
+                self.nodes.insert(node_id, SimNode { session, outbound, received: Vec::new() });
+                if is_agent {
+                    self.state.mirrors.refresh(&self.state.session_manager).await;
+                }
+            }
+            Event::Disconnect { node_id } => {
+                if let Some(node) = self.nodes.remove(&node_id) {
+                    self.state.end_session(&node.session).await;
+                }
+            }
+            Event::Send { from, message } => {
+                let Some(node) = self.nodes.get(&from) else { return };
+                let session = node.session.clone();
+                let message_type = message.message_type().to_string();
+                if let Err(e) = self.state.message_router.route_message(message, &session, &self.state).await {
+                    self.failures.push(RouteFailure { at: self.now, from, message_type, error: e.to_string() });
+                }
+            }
+            Event::Deliver { to, message } => {
+                let Some(node) = self.nodes.get_mut(&to) else { return };
+                self.trace.push(Delivery {
+                    at: self.now,
+                    to: to.clone(),
+                    message_type: message.message_type().to_string(),
+                    request_id: message.request_id(),
+                });
+                let answers = matches!(node.session.node_type, NodeType::Agent) && !message.is_response();
+                node.received.push(message.clone());
+                if answers {
+                    // The link keeps several answers in order
+                    let at = self.now + self.rng.gen_range(self.service_time.clone());
+                    for answer in answer(&to, &message) {
+                        self.send(at, &to, answer);
+                    }
+                }
+            }
+            Event::Idle { node_id, secs } => {
+                if let Some(node) = self.nodes.get(&node_id) {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    *node.session.last_activity.write().await = now.saturating_sub(secs);
+                }
+            }
+            Event::Expire => {
+                self.state.expire_sessions().await;
+                let sessions = &self.state.session_manager;
+                let mut expired = Vec::new();
+                for node_id in self.nodes.keys() {
+                    if !sessions.is_node_connected(node_id).await {
+                        expired.push(node_id.clone());
+                    }
+                }
+                for node_id in expired {
+                    self.nodes.remove(&node_id);
+                }
+            }
+        }
+        self.collect_outbound();
+    }
+
+    /// Turn whatever the relay queued for each node into deliveries
+    fn collect_outbound(&mut self) {
+        let mut deliveries = Vec::new();
+        for (node_id, node) in &mut self.nodes {
+            // Unconstrained, so tokio's cooperative budget cannot hide a queued message
+            while let Some(Some(ws_message)) = tokio::task::unconstrained(node.outbound.recv()).now_or_never() {
+                node.outbound.release(buffers::message_size(&ws_message));
+                deliveries.push((node_id.clone(), decode(&ws_message)));
+            }
+        }
+        for (to, message) in deliveries {
+            let arrival = self.link_arrival(&to, false, self.now);
+            self.schedule(arrival, Event::Deliver { to, message });
+        }
+    }
+
+    /// When a message sent at `at` arrives, keeping each direction of a
+    /// node's link in order
+    fn link_arrival(&mut self, node_id: &str, to_relay: bool, at: u64) -> u64 {
+        let latency = self.rng.gen_range(self.latency.clone());
+        let earliest = self.links.entry((node_id.to_string(), to_relay)).or_insert(0);
+        let arrival = (at + latency).max(*earliest);
+        *earliest = arrival;
+        arrival
+    }
+
+    /// Messages delivered to `node_id` while it was connected
+    pub fn received(&self, node_id: &str) -> &[Message] {
+        self.nodes.get(node_id).map(|node| node.received.as_slice()).unwrap_or(&[])
+    }
+
+    /// Every message the relay delivered, in order
+    pub fn trace(&self) -> &[Delivery] {
+        &self.trace
+    }
+
+    pub fn failures(&self) -> &[RouteFailure] {
+        &self.failures
+    }
+
+    pub async fn requests_in_flight(&self) -> usize {
+        self.state.message_router.get_stats().await.requests_in_flight
+    }
+}
+
+/// What a simulated agent sends back for `request`
+///
+/// Reads return the agent's ID so tests can tell which agent served them,
+/// as do export listings, and paged listings come in three pages. A watch
+/// reports one change right away and runs until it is stopped. Locks are
+/// always granted.
+fn answer(agent_id: &str, request: &Message) -> Vec<Message> {
+    let Some(request_id) = request.request_id() else { return Vec::new() };
+    match request {
+        Message::ReadFile { .. } | Message::ReadHandle { .. } => vec![Message::ReadFileResponse {
+            request_id,
+            success: true,
+            data: Some(agent_id.as_bytes().to_vec()),
+            bytes_read: agent_id.len() as u64,
+            error: None,
+        }],
+        Message::PathExists { .. } => vec![Message::PathExistsResponse { request_id, exists: true, error: None }],
+        Message::ListExports { .. } => vec![Message::ListExportsResponse {
+            request_id,
+            exports: vec![ExportInfo {
+                name: agent_id.to_string(),
+                path: "/data".to_string(),
+                read_only: false,
+                total_space: None,
+                available_space: None,
+            }],
+            error: None,
+        }],
+        Message::ListDirectoryPaged { .. } => (0..3)
+            .map(|sequence| Message::DirectoryPage {
+                request_id,
+                sequence,
+                entries: Vec::new(),
+                last: sequence == 2,
+                error: None,
+                cursor: None,
+            })
+            .collect(),
+        Message::Watch { path, .. } => vec![
+            Message::WatchResponse { request_id, success: true, error: None },
+            Message::FileChanged {
+                request_id,
+                path: format!("{}/{}", path, agent_id),
+                kind: ChangeKind::Created,
+                is_dir: false,
+            },
+            Message::DirectoryChanged { request_id, path: path.clone() },
+        ],
+        Message::Unwatch { .. } => vec![Message::WatchEnded { request_id, error: None }],
+        Message::LockFile { .. } => vec![Message::LockFileResponse { request_id, success: true, conflict: None, error: None }],
+        Message::UnlockFile { .. } => vec![Message::UnlockFileResponse { request_id, success: true, error: None }],
+        Message::TestLock { .. } => vec![Message::TestLockResponse { request_id, success: true, conflict: None, error: None }],
+        Message::OpenFile { .. } => vec![Message::OpenFileResponse { request_id, success: true, metadata: None, error: None }],
+        Message::CloseFile { .. } => vec![Message::CloseFileResponse { request_id, success: true, error: None }],
+        _ => vec![Message::Error {
+            request_id: Some(request_id),
+            code: ErrorCode::NotImplemented,
+            message: format!("Simulated agent cannot answer {}", request.message_type()),
+            details: None,
+            errno: None,
+        }],
+    }
+}
+
+fn decode(message: &WsMessage) -> Message {
+    match message {
+        WsMessage::Binary(data) => bincode::deserialize(data).unwrap(),
+        WsMessage::Text(text) => serde_json::from_str(text).unwrap(),
+        other => panic!("Unexpected message from the relay: {:?}", other),
+    }
+}
+
+/// A read of `path`
+pub fn read(request_id: RequestId, path: &str) -> Message {
+    Message::ReadFile { request_id, path: path.to_string(), offset: 0, length: 4096 }
+}
+
+/// The agent that served a read or export listing, from its response
+pub fn served_by(response: &Message) -> Option<String> {
+    match response {
+        Message::ReadFileResponse { data: Some(data), .. } => Some(String::from_utf8(data.clone()).unwrap()),
+        Message::ListExportsResponse { exports, .. } => exports.first().map(|export| export.name.clone()),
+        _ => None,
+    }
+}
@@ -0,0 +1,562 @@
+mod common;
+use common::*;
+use remotefs_common::{
+    config::MirrorPair,
+    config_utils,
+    protocol::{AgentEvent, CallerIdentity, Capability, FileLock, LockOwner, LockType, MaintenanceWindow, Message, OpenFlags, RequestId},
+};
+use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+
+/// Clients that each send reads at random times to two agents
+async fn busy_relay(seed: u64) -> (Simulation, HashMap<String, HashSet<RequestId>>) {
+    let mut sim = Simulation::new(seed);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem]);
+    sim.connect_agent(0, "agent-b", vec![Capability::Filesystem]);
+
+    let mut sent = HashMap::new();
+    for client in ["client-1", "client-2", "client-3"] {
+        sim.connect_client(0, client);
+        let mut ids = HashSet::new();
+        for _ in 0..10 {
+            let at = sim.random_time(10..=500);
+            let request_id = sim.request_id();
+            sim.send(at, client, read(request_id, "/data/file"));
+            ids.insert(request_id);
+        }
+        sent.insert(client.to_string(), ids);
+    }
+
+    sim.run().await;
+    (sim, sent)
+}
+
+#[tokio::test]
+async fn test_responses_return_to_their_requester() {
+    for seed in 0..20 {
+        let (sim, sent) = busy_relay(seed).await;
+        assert!(sim.failures().is_empty(), "seed {}: {:?}", seed, sim.failures());
+
+        for (client, ids) in &sent {
+            let received: HashSet<RequestId> = sim.received(client).iter()
+                .filter_map(Message::request_id)
+                .collect();
+            assert_eq!(&received, ids, "seed {}: {} got someone else's responses", seed, client);
+        }
+
+        // Both agents took a share of the reads
+        assert!(!sim.received("agent-a").is_empty() && !sim.received("agent-b").is_empty(), "seed {}", seed);
+        assert_eq!(sim.requests_in_flight().await, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_same_seed_same_trace() {
+    let (first, _) = busy_relay(7).await;
+    let (second, _) = busy_relay(7).await;
+    let (other, _) = busy_relay(8).await;
+
+    assert_eq!(first.trace(), second.trace());
+    assert_ne!(first.trace(), other.trace());
+}
+
+#[tokio::test]
+async fn test_paged_listings_keep_their_route_when_interleaved() {
+    for seed in 0..20 {
+        let mut sim = Simulation::new(seed);
+        sim.connect_agent(0, "agent", vec![Capability::Filesystem, Capability::Streaming]);
+        sim.connect_client(0, "client-1");
+        sim.connect_client(0, "client-2");
+
+        let mut listings = Vec::new();
+        for client in ["client-1", "client-2"] {
+            for _ in 0..3 {
+                let request_id = sim.request_id();
+                let at = sim.random_time(10..=100);
+                sim.send(at, client, Message::ListDirectoryPaged { request_id, path: "/data".to_string(), page_size: 100, after: None, max_entries: None });
+                listings.push((client, request_id));
+            }
+        }
+        sim.run().await;
+
+        for (client, request_id) in listings {
+            let pages: Vec<u32> = sim.received(client).iter()
+                .filter_map(|message| match message {
+                    Message::DirectoryPage { request_id: id, sequence, .. } if *id == request_id => Some(*sequence),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(pages, vec![0, 1, 2], "seed {}: pages of {} out of order or lost", seed, client);
+        }
+        assert_eq!(sim.requests_in_flight().await, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_disconnected_requester_is_forgotten() {
+    let mut sim = Simulation::new(1).with_latency(10..=10);
+    sim.connect_agent(0, "agent", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client");
+    let request_id = sim.request_id();
+    sim.send(100, "client", read(request_id, "/data/file"));
+    // The request reaches the agent at 120; the client leaves before the
+    // answer gets back to the relay
+    sim.disconnect(125, "client");
+    sim.run().await;
+
+    assert_eq!(sim.failures().len(), 1, "{:?}", sim.failures());
+    assert_eq!(sim.failures()[0].from, "agent");
+    assert_eq!(sim.requests_in_flight().await, 0);
+}
+
+#[tokio::test]
+async fn test_expired_requester_is_forgotten() {
+    let mut sim = Simulation::new(1).with_latency(10..=10);
+    sim.connect_agent(0, "agent", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client");
+    let request_id = sim.request_id();
+    sim.send(100, "client", read(request_id, "/data/file"));
+    sim.idle(115, "client", sim.state.config.session.timeout + 1);
+    sim.expire(116);
+    sim.run().await;
+
+    assert!(!sim.state.session_manager.is_node_connected("client").await);
+    assert!(sim.state.session_manager.is_node_connected("agent").await);
+    assert_eq!(sim.failures().len(), 1, "{:?}", sim.failures());
+    assert_eq!(sim.requests_in_flight().await, 0);
+}
+
+#[tokio::test]
+async fn test_mirror_takes_over_while_primary_is_gone() {
+    let mut config = config_utils::create_default_relay_config();
+    config.mirrors = vec![MirrorPair {
+        primary: "primary".to_string(),
+        mirror: "mirror".to_string(),
+        promote_writes: false,
+    }];
+    let mut sim = Simulation::with_config(3, config).with_latency(5..=5);
+    sim.connect_agent(0, "primary", vec![Capability::Filesystem]);
+    sim.connect_agent(0, "mirror", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client");
+
+    let before = sim.request_id();
+    sim.send(100, "client", read(before, "/data/file"));
+    sim.disconnect(200, "primary");
+    let during = sim.request_id();
+    sim.send(300, "client", read(during, "/data/file"));
+    let write = sim.request_id();
+    sim.send(310, "client", Message::WriteFile {
+        request_id: write,
+        path: "/data/file".to_string(),
+        offset: 0,
+        data: b"new".to_vec(),
+        sync: false,
+    });
+    sim.connect_agent(400, "primary", vec![Capability::Filesystem]);
+    let after = sim.request_id();
+    sim.send(500, "client", read(after, "/data/file"));
+    sim.run().await;
+
+    let served: HashMap<RequestId, String> = sim.received("client").iter()
+        .filter_map(|message| Some((message.request_id()?, served_by(message)?)))
+        .collect();
+    assert_eq!(served[&before], "primary");
+    assert_eq!(served[&during], "mirror");
+    assert_eq!(served[&after], "primary");
+
+    // The read-only mirror never saw the write
+    assert_eq!(sim.failures().len(), 1, "{:?}", sim.failures());
+    assert_eq!(sim.failures()[0].message_type, "WriteFile");
+
+    let promotions: Vec<bool> = sim.received("client").iter()
+        .filter_map(|message| match message {
+            Message::MirrorStatus { promoted, .. } => Some(*promoted),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(promotions, vec![true, false]);
+}
+
+#[tokio::test]
+async fn test_clients_cannot_send_mirror_status_as_a_user() {
+    let mut sim = Simulation::new(4);
+    sim.connect_agent(0, "mirror", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client");
+    sim.send(10, "client", Message::AsUser {
+        identity: CallerIdentity { uid: 1000, gid: 1000, groups: vec![] },
+        request: Box::new(Message::MirrorStatus {
+            primary: "primary".to_string(),
+            mirror: "mirror".to_string(),
+            promoted: true,
+            writes_allowed: true,
+            timestamp: Utc::now(),
+        }),
+    });
+    sim.run().await;
+
+    assert_eq!(sim.failures().len(), 1, "{:?}", sim.failures());
+    assert!(sim.received("mirror").is_empty());
+}
+
+#[tokio::test]
+async fn test_feature_gated_requests_skip_older_agents() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        sim.connect_agent(0, "old-agent", vec![Capability::Filesystem]);
+        sim.connect_agent(0, "new-agent", vec![Capability::Filesystem, Capability::Streaming]);
+        sim.connect_client(0, "client");
+        for _ in 0..5 {
+            let request_id = sim.request_id();
+            let at = sim.random_time(10..=100);
+            sim.send(at, "client", Message::ListDirectoryPaged { request_id, path: "/data".to_string(), page_size: 10, after: None, max_entries: None });
+        }
+        sim.run().await;
+
+        assert!(sim.failures().is_empty(), "seed {}: {:?}", seed, sim.failures());
+        assert!(sim.received("old-agent").is_empty(), "seed {}", seed);
+        assert_eq!(sim.received("new-agent").len(), 5);
+    }
+}
+
+#[tokio::test]
+async fn test_export_listings_reach_the_chosen_agent() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        sim.connect_agent(0, "agent-a", vec![Capability::Filesystem, Capability::Exports]);
+        sim.connect_agent(0, "agent-b", vec![Capability::Filesystem, Capability::Exports]);
+        sim.connect_agent(0, "agent-c", vec![Capability::Filesystem]);
+        sim.connect_client(0, "client");
+
+        let mut chosen = HashMap::new();
+        for agent_id in ["agent-a", "agent-b", "agent-b", "agent-a"] {
+            let request_id = sim.request_id();
+            let at = sim.random_time(10..=100);
+            sim.send(at, "client", Message::ListExports { request_id, agent_id: Some(agent_id.to_string()) });
+            chosen.insert(request_id, agent_id);
+        }
+        // Gone, and too old to list exports
+        for agent_id in ["agent-d", "agent-c"] {
+            let request_id = sim.request_id();
+            sim.send(200, "client", Message::ListExports { request_id, agent_id: Some(agent_id.to_string()) });
+        }
+        sim.run().await;
+
+        for message in sim.received("client") {
+            let request_id = message.request_id().unwrap();
+            assert_eq!(served_by(message).as_deref(), Some(chosen[&request_id]), "seed {}", seed);
+        }
+        assert_eq!(sim.received("client").len(), 4, "seed {}", seed);
+        let errors: Vec<&str> = sim.failures().iter().map(|failure| failure.error.as_str()).collect();
+        assert_eq!(errors.len(), 2, "seed {}: {:?}", seed, errors);
+        assert!(errors.iter().any(|error| error.contains("agent-d is not connected")), "{:?}", errors);
+        assert!(errors.iter().any(|error| error.contains("does not support")), "{:?}", errors);
+    }
+}
+
+#[tokio::test]
+async fn test_agents_are_listed_with_their_capabilities() {
+    let mut sim = Simulation::new(1);
+    sim.connect_agent(0, "agent-b", vec![Capability::Filesystem, Capability::Exports]);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem]);
+    sim.run().await;
+
+    let agents = sim.state.session_manager.list_agents().await;
+    let listed: Vec<(&str, usize)> = agents.iter()
+        .map(|agent| (agent.agent_id.as_str(), agent.capabilities.len()))
+        .collect();
+    assert_eq!(listed, vec![("agent-a", 1), ("agent-b", 2)]);
+    assert!(agents.iter().all(|agent| agent.paths.is_empty()));
+}
+
+#[tokio::test]
+async fn test_broadcasts_reach_the_agents_clients_once() {
+    let mut sim = Simulation::new(5);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem]);
+    for client in ["client-1", "client-2", "client-3", "client-4"] {
+        sim.connect_client(0, client);
+    }
+    for client in ["client-1", "client-2", "client-4"] {
+        for _ in 0..3 {
+            let request_id = sim.request_id();
+            sim.send(10, client, read(request_id, "/data/file"));
+        }
+    }
+    sim.disconnect(500, "client-4");
+    // The relay names the sender, whoever the agent claims to be
+    sim.send(1000, "agent-a", Message::Broadcast {
+        agent_id: "agent-b".to_string(),
+        event: AgentEvent::ShuttingDown,
+        timestamp: Utc::now(),
+    });
+    sim.run().await;
+    assert!(sim.failures().is_empty(), "{:?}", sim.failures());
+
+    let broadcasts = |client: &str| -> Vec<String> {
+        sim.received(client).iter()
+            .filter_map(|message| match message {
+                Message::Broadcast { agent_id, event: AgentEvent::ShuttingDown, .. } => Some(agent_id.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(broadcasts("client-1"), vec!["agent-a"]);
+    assert_eq!(broadcasts("client-2"), vec!["agent-a"]);
+    // Never used the agent
+    assert!(broadcasts("client-3").is_empty());
+    assert!(broadcasts("client-4").is_empty());
+}
+
+#[tokio::test]
+async fn test_clients_cannot_broadcast() {
+    let mut sim = Simulation::new(6);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client-1");
+    sim.connect_client(0, "client-2");
+    let request_id = sim.request_id();
+    sim.send(10, "client-2", read(request_id, "/data/file"));
+    sim.send(500, "client-1", Message::Broadcast {
+        agent_id: "agent-a".to_string(),
+        event: AgentEvent::ShuttingDown,
+        timestamp: Utc::now(),
+    });
+    sim.run().await;
+
+    assert_eq!(sim.failures().len(), 1);
+    assert!(!sim.received("client-2").iter().any(|message| matches!(message, Message::Broadcast { .. })));
+}
+
+#[tokio::test]
+async fn test_clients_learn_of_maintenance_on_the_agents_they_use() {
+    let mut sim = Simulation::new(9);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem]);
+    sim.connect_client(0, "client-1");
+    sim.connect_client(0, "client-2");
+    sim.state.maintenance.set(MaintenanceWindow {
+        agent_id: Some("agent-a".to_string()),
+        message: "Disk replacement".to_string(),
+        starts_at: Utc::now(),
+        ends_at: None,
+    }).await;
+    for _ in 0..3 {
+        let request_id = sim.request_id();
+        sim.send(10, "client-1", read(request_id, "/data/file"));
+    }
+    sim.run().await;
+    assert!(sim.failures().is_empty(), "{:?}", sim.failures());
+
+    let statuses = |client: &str| -> Vec<Vec<MaintenanceWindow>> {
+        sim.received(client).iter()
+            .filter_map(|message| match message {
+                Message::MaintenanceStatus { request_id: None, windows } => Some(windows.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+    // Told once, when it first used the agent
+    let told = statuses("client-1");
+    assert_eq!(told.len(), 1);
+    assert_eq!(told[0][0].agent_id.as_deref(), Some("agent-a"));
+    assert!(statuses("client-2").is_empty());
+}
+
+#[tokio::test]
+async fn test_watches_run_on_one_agent_until_stopped() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        sim.connect_agent(0, "old-agent", vec![Capability::Filesystem]);
+        sim.connect_agent(0, "agent-a", vec![Capability::Filesystem, Capability::Watch]);
+        sim.connect_agent(0, "agent-b", vec![Capability::Filesystem, Capability::Watch]);
+        sim.connect_client(0, "client");
+        let request_id = sim.request_id();
+        sim.send(10, "client", Message::Watch { request_id, path: "/data".to_string(), recursive: true });
+        sim.send(500, "client", Message::Unwatch { request_id });
+        sim.run().await;
+
+        assert!(sim.failures().is_empty(), "seed {}: {:?}", seed, sim.failures());
+        assert!(sim.received("old-agent").is_empty());
+        let watched_by: Vec<&str> = ["agent-a", "agent-b"].into_iter()
+            .filter(|agent| !sim.received(agent).is_empty())
+            .collect();
+        assert_eq!(watched_by.len(), 1, "seed {}: Unwatch went to another agent", seed);
+        assert_eq!(sim.received(watched_by[0]).len(), 2);
+
+        let types: Vec<&str> = sim.received("client").iter().map(Message::message_type).collect();
+        assert_eq!(types, ["WatchResponse", "FileChanged", "DirectoryChanged", "WatchEnded"]);
+        assert_eq!(sim.requests_in_flight().await, 0);
+    }
+}
+
+#[tokio::test]
+async fn test_watches_end_when_either_side_leaves() {
+    let mut sim = Simulation::new(1).with_latency(10..=10);
+    sim.connect_agent(0, "agent", vec![Capability::Filesystem, Capability::Watch]);
+    sim.connect_client(0, "client-1");
+    sim.connect_client(0, "client-2");
+    let first = sim.request_id();
+    let second = sim.request_id();
+    sim.send(10, "client-1", Message::Watch { request_id: first, path: "/data".to_string(), recursive: false });
+    sim.send(10, "client-2", Message::Watch { request_id: second, path: "/data".to_string(), recursive: false });
+    sim.disconnect(100, "client-1");
+    sim.run().await;
+
+    // The agent is told to stop the watch of the client that left
+    let stopped: Vec<_> = sim.received("agent").iter()
+        .filter_map(|message| match message {
+            Message::Unwatch { request_id } => Some(*request_id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(stopped, [first]);
+    assert!(sim.received("client-2").iter().all(|message| message.request_id() == Some(second)));
+
+    // and the client of a watch whose agent left is told it ended
+    sim.disconnect(200, "agent");
+    sim.run().await;
+    let ended = sim.received("client-2").last().unwrap();
+    assert!(matches!(ended, Message::WatchEnded { request_id, error: Some(_) } if *request_id == second));
+}
+
+#[tokio::test]
+async fn test_locks_stay_with_one_agent_per_file() {
+    let mut sim = Simulation::new(5).with_latency(10..=10);
+    for agent in ["agent-a", "agent-b", "agent-c"] {
+        sim.connect_agent(0, agent, vec![Capability::Filesystem, Capability::Locks]);
+    }
+    sim.connect_client(0, "client-1");
+    sim.connect_client(0, "client-2");
+
+    // A client cannot claim another client's locks
+    let mut forged = FileLock::new(LockType::Write, 0, 0, 7);
+    forged.owner.session = "client-2".to_string();
+    for at in 10..20 {
+        let request_id = sim.request_id();
+        let client = if at % 2 == 0 { "client-1" } else { "client-2" };
+        sim.send(at, client, Message::LockFile {
+            request_id,
+            path: "/data/db.sqlite".to_string(),
+            lock: forged.clone(),
+        });
+    }
+    let request_id = sim.request_id();
+    sim.send(20, "client-2", Message::TestLock {
+        request_id,
+        path: "/data/db.sqlite".to_string(),
+        lock: FileLock::new(LockType::Read, 0, 1, 1),
+    });
+    let request_id = sim.request_id();
+    sim.send(20, "client-1", Message::UnlockFile {
+        request_id,
+        path: "/data/db.sqlite".to_string(),
+        owner: LockOwner { session: String::new(), owner: 7 },
+        start: 0,
+        length: 0,
+    });
+    sim.run().await;
+    assert!(sim.failures().is_empty(), "{:?}", sim.failures());
+
+    let holders: Vec<_> = ["agent-a", "agent-b", "agent-c"].into_iter()
+        .filter(|agent| !sim.received(agent).is_empty())
+        .collect();
+    assert_eq!(holders.len(), 1, "locks went to {:?}", holders);
+    let holder = holders[0];
+    let mut sessions: Vec<_> = sim.received(holder).iter()
+        .filter_map(|message| match message {
+            Message::LockFile { lock, .. } | Message::TestLock { lock, .. } => Some(lock.owner.session.clone()),
+            Message::UnlockFile { owner, .. } => Some(owner.session.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sessions.len(), 12);
+    sessions.sort();
+    sessions.dedup();
+    assert_eq!(sessions, ["client-1", "client-2"]);
+
+    // Only the agent that has the client's locks is told to release them
+    sim.disconnect(100, "client-1");
+    sim.run().await;
+    let released: Vec<_> = ["agent-a", "agent-b", "agent-c"].into_iter()
+        .flat_map(|agent| sim.received(agent).iter().map(move |message| (agent, message)))
+        .filter_map(|(agent, message)| match message {
+            Message::ReleaseLocks { session } => Some((agent, session.as_str())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(released, [(holder, "client-1")]);
+}
+
+#[tokio::test]
+async fn test_clients_cannot_release_locks() {
+    let mut sim = Simulation::new(8);
+    sim.connect_agent(0, "agent-a", vec![Capability::Filesystem, Capability::Locks]);
+    sim.connect_client(0, "client-1");
+    let release = Message::ReleaseLocks { session: "client-2".to_string() };
+    sim.send(10, "client-1", release.clone());
+    sim.send(20, "client-1", Message::AsUser {
+        identity: CallerIdentity { uid: 1000, gid: 1000, groups: vec![] },
+        request: Box::new(release),
+    });
+    sim.run().await;
+
+    // Only the relay releases a client's locks, once it has gone
+    assert_eq!(sim.failures().len(), 2, "{:?}", sim.failures());
+    assert!(sim.received("agent-a").is_empty());
+}
+
+#[tokio::test]
+async fn test_handles_stay_with_the_agent_that_opened_them() {
+    let mut sim = Simulation::new(6).with_latency(10..=10);
+    for agent in ["agent-a", "agent-b", "agent-c"] {
+        sim.connect_agent(0, agent, vec![Capability::Filesystem, Capability::OpenFiles]);
+    }
+    sim.connect_client(0, "client-1");
+    sim.connect_client(0, "client-2");
+
+    let open = |request_id| Message::OpenFile {
+        request_id,
+        path: "/data/log".to_string(),
+        flags: OpenFlags::read_only(),
+        mode: 0,
+        session: "client-2".to_string(),
+    };
+    let handle = sim.request_id();
+    sim.send(10, "client-1", open(handle));
+    for at in 100..110 {
+        let request_id = sim.request_id();
+        sim.send(at, "client-1", Message::ReadHandle { request_id, handle, offset: 0, length: 10 });
+    }
+
+    // Another client cannot use the handle, nor anyone once it is closed
+    let request_id = sim.request_id();
+    sim.send(100, "client-2", Message::ReadHandle { request_id, handle, offset: 0, length: 10 });
+    let request_id = sim.request_id();
+    sim.send(200, "client-1", Message::CloseFile { request_id, handle });
+    let request_id = sim.request_id();
+    sim.send(300, "client-1", Message::ReadHandle { request_id, handle, offset: 0, length: 10 });
+    sim.run().await;
+
+    let failed: Vec<_> = sim.failures().iter().map(|failure| failure.from.as_str()).collect();
+    assert_eq!(failed, ["client-2", "client-1"]);
+    let served: HashSet<_> = sim.received("client-1").iter().filter_map(served_by).collect();
+    assert_eq!(served.len(), 1);
+    let holder = served.into_iter().next().unwrap();
+    let sessions: Vec<_> = sim.received(&holder).iter()
+        .filter_map(|message| match message {
+            Message::OpenFile { session, .. } => Some(session.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sessions, ["client-1"]);
+
+    // The agent holding a client's open files closes them when it leaves
+    let second = sim.request_id();
+    sim.send(400, "client-1", open(second));
+    sim.disconnect(500, "client-1");
+    sim.run().await;
+    let opened_by = ["agent-a", "agent-b", "agent-c"].into_iter()
+        .find(|agent| sim.received(agent).iter().any(|message| message.request_id() == Some(second)))
+        .unwrap();
+    assert!(sim.received(opened_by).iter().any(|message| matches!(
+        message,
+        Message::ReleaseLocks { session } if session == "client-1"
+    )));
+}
@@ -0,0 +1,36 @@
+//! {{project-name}}: serves a directory to RemoteFS clients through a relay
+//!
+//! Usage: `{{project-name}} [directory] [relay-url]`, exporting the current
+//! directory to {{relay_url}} by default.
+
+use remotefs_agent::AgentServer;
+use remotefs_common::config_utils::create_default_agent_config;
+use remotefs_common::error::Result;
+use std::path::PathBuf;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let directory = match args.next() {
+        Some(directory) => PathBuf::from(directory),
+        None => std::env::current_dir()?,
+    };
+    let relay_url = args.next().unwrap_or_else(|| "{{relay_url}}".to_string());
+
+    let mut config = create_default_agent_config();
+    config.agent_id = "{{project-name}}".to_string();
+    config.relay_url = relay_url;
+    config.access.allowed_paths = vec![directory.canonicalize()?.to_string_lossy().to_string()];
+    // A ws:// relay URL means the relay runs without TLS
+    config.security.enable_tls = config.relay_url.starts_with("wss://");
+
+    // Adjust the rest of the agent's configuration here, e.g. `config.access`
+    // for read-only paths and per-user rules, or `config.limits`
+
+    AgentServer::new(config)?.run().await
+}